@@ -0,0 +1,142 @@
+//! Benchmarks of expression building and of the shaders built from them, on the kind of expressions procedurally
+//! generated shaders end up with.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use shades::{
+  lit,
+  writer::glsl::{write_shader_to_str, write_shader_to_str_with_options, WriteOptions},
+  Expr, Scope, Shader, ShaderBuilder, Trigonometry as _,
+};
+
+/// Build an expression reusing its previous level twice, `depth` times — as material graphs do with node outputs.
+fn reused(depth: usize) -> Expr<f32> {
+  let mut e = lit!(1.);
+
+  for _ in 0..depth {
+    e = e.clone() * e + lit!(0.5);
+  }
+
+  e
+}
+
+/// Build a linear chain of `len` additions.
+fn chain(len: usize) -> Expr<f32> {
+  let mut e = lit!(1.);
+
+  for _ in 0..len {
+    e = e + lit!(0.5);
+  }
+
+  e
+}
+
+/// Build a layer of `depth` operations over an expression.
+fn layer(x: Expr<f32>, depth: usize) -> Expr<f32> {
+  let mut e = x;
+
+  for _ in 0..depth {
+    e = (e * lit!(1.5) + lit!(0.5)).sin();
+  }
+
+  e
+}
+
+/// Build a shader declaring `nodes` variables computed from the same layer, rebuilt for each of them — as material
+/// graphs do when they don’t cache the outputs of their nodes.
+fn material(nodes: usize) -> Shader {
+  ShaderBuilder::new_fragment_shader(|s, _| {
+    s.main_fun(|s: &mut Scope<()>| {
+      let x = s.var(lit!(1.));
+
+      for i in 0..nodes {
+        let _ = s.var(layer(x.to_expr(), 16) + lit!(i as f32));
+      }
+    })
+  })
+}
+
+fn build(c: &mut Criterion) {
+  let mut group = c.benchmark_group("build reused");
+
+  for depth in [4, 8, 12, 16].iter() {
+    group.bench_with_input(BenchmarkId::from_parameter(depth), depth, |b, &depth| {
+      b.iter(|| reused(black_box(depth)))
+    });
+  }
+
+  group.finish();
+
+  let mut group = c.benchmark_group("build chain");
+
+  for len in [100, 1000, 10000].iter() {
+    group.bench_with_input(BenchmarkId::from_parameter(len), len, |b, &len| {
+      b.iter(|| chain(black_box(len)))
+    });
+  }
+
+  group.finish();
+}
+
+fn clone(c: &mut Criterion) {
+  let mut group = c.benchmark_group("clone chain");
+
+  for len in [100, 1000, 10000].iter() {
+    let e = chain(*len);
+    group.bench_with_input(BenchmarkId::from_parameter(len), &e, |b, e| {
+      b.iter(|| e.clone())
+    });
+  }
+
+  group.finish();
+}
+
+fn shader(c: &mut Criterion) {
+  let mut group = c.benchmark_group("build material");
+
+  for nodes in [10, 100, 1000].iter() {
+    group.bench_with_input(BenchmarkId::from_parameter(nodes), nodes, |b, &nodes| {
+      b.iter(|| material(black_box(nodes)))
+    });
+  }
+
+  group.finish();
+
+  let mut group = c.benchmark_group("fingerprint material");
+
+  for nodes in [10, 100, 1000].iter() {
+    let shader = material(*nodes);
+    group.bench_with_input(BenchmarkId::from_parameter(nodes), &shader, |b, shader| {
+      b.iter(|| shader.fingerprint())
+    });
+  }
+
+  group.finish();
+
+  let mut group = c.benchmark_group("write material");
+
+  for nodes in [10, 100, 1000].iter() {
+    let shader = material(*nodes);
+    group.bench_with_input(BenchmarkId::from_parameter(nodes), &shader, |b, shader| {
+      b.iter(|| write_shader_to_str(shader))
+    });
+  }
+
+  group.finish();
+
+  let mut group = c.benchmark_group("write optimized material");
+  let options = WriteOptions::new()
+    .with_constant_folding(true)
+    .with_common_subexpression_elimination(true);
+
+  for nodes in [10, 100, 1000].iter() {
+    let shader = material(*nodes);
+    group.bench_with_input(BenchmarkId::from_parameter(nodes), &shader, |b, shader| {
+      b.iter(|| write_shader_to_str_with_options(shader, &options))
+    });
+  }
+
+  group.finish();
+}
+
+criterion_group!(benches, build, clone, shader);
+criterion_main!(benches);
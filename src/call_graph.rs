@@ -0,0 +1,418 @@
+//! Call graph of the functions of a shader.
+//!
+//! Functions are identified by handles, so nothing prevents a function from calling a function declared after it —
+//! shaders built from the IR or imported from GLSL prototypes do — or from calling itself. GLSL forbids recursion and
+//! requires functions to be declared before being called; this module finds recursive functions and orders
+//! declarations so that functions come before their callers.
+
+use crate::{
+  optimizer::{fun_exprs, walk_expr},
+  ErasedExpr, ErasedFun, ErasedFunHandle, ScopedHandle, ShaderDecl,
+};
+use std::collections::{HashMap, HashSet};
+
+/// Functions of a shader, along with the functions they call.
+pub(crate) struct CallGraph<'a> {
+  decls: &'a [ShaderDecl],
+  funs: HashMap<u16, Node<'a>>,
+}
+
+struct Node<'a> {
+  decl: &'a ShaderDecl,
+  fun: &'a ErasedFun,
+  // functions called, in order of first call
+  callees: Vec<u16>,
+  // constants and interface variables used
+  globals: Vec<ScopedHandle>,
+}
+
+/// Declaration of a shader, in the order returned by [`CallGraph::ordered_decls`].
+pub(crate) enum OrderedDecl<'a> {
+  Decl(&'a ShaderDecl),
+
+  /// Prototype of a function called before its definition.
+  Prototype(u16, &'a ErasedFun),
+}
+
+impl<'a> CallGraph<'a> {
+  pub(crate) fn new(decls: &'a [ShaderDecl]) -> Self {
+    let funs = decls
+      .iter()
+      .filter_map(|decl| match decl {
+        ShaderDecl::FunDef(handle, fun) => {
+          let exprs = fun_exprs(fun);
+          let node = Node {
+            decl,
+            fun,
+            callees: callees(exprs.iter().copied()),
+            globals: globals(exprs),
+          };
+
+          Some((*handle, node))
+        }
+
+        _ => None,
+      })
+      .collect();
+
+    Self { decls, funs }
+  }
+
+  fn callees(&self, handle: u16) -> &[u16] {
+    self
+      .funs
+      .get(&handle)
+      .map(|node| node.callees.as_slice())
+      .unwrap_or_default()
+  }
+
+  /// Functions calling themselves, directly or through other functions, in declaration order.
+  pub(crate) fn recursive_funs(&self) -> Vec<u16> {
+    self
+      .decls
+      .iter()
+      .filter_map(|decl| match decl {
+        ShaderDecl::FunDef(handle, _) if self.calls(*handle, *handle) => Some(*handle),
+        _ => None,
+      })
+      .collect()
+  }
+
+  // Whether `caller` calls `callee`, directly or through other functions.
+  fn calls(&self, caller: u16, callee: u16) -> bool {
+    let mut visited = HashSet::new();
+    let mut pending = self.callees(caller).to_vec();
+
+    while let Some(handle) = pending.pop() {
+      if handle == callee {
+        return true;
+      }
+
+      if visited.insert(handle) {
+        pending.extend_from_slice(self.callees(handle));
+      }
+    }
+
+    false
+  }
+
+  /// Handles of the functions, each after the functions it calls and otherwise in declaration order.
+  ///
+  /// Recursive functions come after the functions they call, except for the ones leading back to them.
+  pub(crate) fn callees_first(&self) -> Vec<u16> {
+    fn visit(graph: &CallGraph, handle: u16, visited: &mut HashSet<u16>, order: &mut Vec<u16>) {
+      if !graph.funs.contains_key(&handle) || !visited.insert(handle) {
+        return;
+      }
+
+      for &callee in graph.callees(handle) {
+        visit(graph, callee, visited, order);
+      }
+
+      order.push(handle);
+    }
+
+    let mut visited = HashSet::new();
+    let mut order = Vec::new();
+
+    for decl in self.decls {
+      if let ShaderDecl::FunDef(handle, _) = decl {
+        visit(self, *handle, &mut visited, &mut order);
+      }
+    }
+
+    order
+  }
+
+  /// Declarations in an order where functions are declared before being called.
+  ///
+  /// A function called before its definition is moved right before its first caller if the constants and interface
+  /// variables it uses are declared by then; otherwise, its prototype is declared there. Other declarations keep their
+  /// order.
+  pub(crate) fn ordered_decls(&self) -> Vec<OrderedDecl<'a>> {
+    let mut order = Order {
+      graph: self,
+      decls: Vec::new(),
+      defined: HashSet::new(),
+      prototyped: HashSet::new(),
+      globals: HashSet::new(),
+      calling: Vec::new(),
+    };
+
+    for decl in self.decls {
+      match decl {
+        // already moved before a caller
+        ShaderDecl::FunDef(handle, _) if order.defined.contains(handle) => continue,
+
+        ShaderDecl::FunDef(handle, _) => {
+          order.calling.push(*handle);
+          order.declare(self.callees(*handle));
+          order.calling.pop();
+          order.defined.insert(*handle);
+        }
+
+        ShaderDecl::Main(fun) => order.declare(&callees(fun_exprs(fun))),
+
+        ShaderDecl::Const(handle, _, expr, _) => {
+          order.declare(&callees(Some(expr)));
+          order.globals.insert(ScopedHandle::Global(*handle));
+        }
+
+        ShaderDecl::In(name, _) => {
+          order.globals.insert(ScopedHandle::Input(name.clone()));
+        }
+
+        ShaderDecl::Out(name, _, _) => {
+          order.globals.insert(ScopedHandle::Output(name.clone()));
+        }
+
+        ShaderDecl::Uniform(name, _) => {
+          order.globals.insert(ScopedHandle::Uniform(name.clone()));
+        }
+      }
+
+      order.decls.push(OrderedDecl::Decl(decl));
+    }
+
+    order.decls
+  }
+}
+
+// State of CallGraph::ordered_decls.
+struct Order<'g, 'a> {
+  graph: &'g CallGraph<'a>,
+  decls: Vec<OrderedDecl<'a>>,
+  defined: HashSet<u16>,
+  prototyped: HashSet<u16>,
+  // constants and interface variables declared so far
+  globals: HashSet<ScopedHandle>,
+  // functions being defined, outermost first
+  calling: Vec<u16>,
+}
+
+impl<'g, 'a> Order<'g, 'a> {
+  // Declare the functions called by the declaration about to be added.
+  fn declare(&mut self, callees: &[u16]) {
+    let graph = self.graph;
+
+    for &callee in callees {
+      if self.defined.contains(&callee) || self.prototyped.contains(&callee) {
+        continue;
+      }
+
+      let node = match graph.funs.get(&callee) {
+        Some(node) => node,
+        None => continue,
+      };
+
+      // a recursive function cannot be defined before itself
+      let movable = !self.calling.contains(&callee)
+        && node
+          .globals
+          .iter()
+          .all(|global| self.globals.contains(global));
+
+      if movable {
+        self.calling.push(callee);
+        self.declare(&node.callees);
+        self.calling.pop();
+
+        self.defined.insert(callee);
+        self.decls.push(OrderedDecl::Decl(node.decl));
+      } else {
+        self.prototyped.insert(callee);
+        self.decls.push(OrderedDecl::Prototype(callee, node.fun));
+      }
+    }
+  }
+}
+
+// User-defined functions called in some expressions, in order of first call.
+fn callees<'e>(exprs: impl IntoIterator<Item = &'e ErasedExpr>) -> Vec<u16> {
+  let mut callees = Vec::new();
+
+  for expr in exprs {
+    walk_expr(expr, &mut |expr| {
+      if let ErasedExpr::FunCall(ErasedFunHandle::UserDefined(handle), _) = expr {
+        if !callees.contains(handle) {
+          callees.push(*handle);
+        }
+      }
+    });
+  }
+
+  callees
+}
+
+// Constants and interface variables used in some expressions.
+fn globals(exprs: Vec<&ErasedExpr>) -> Vec<ScopedHandle> {
+  let mut globals = Vec::new();
+
+  for expr in exprs {
+    walk_expr(expr, &mut |expr| match expr {
+      ErasedExpr::Var(
+        handle @ (ScopedHandle::Global(_)
+        | ScopedHandle::Input(_)
+        | ScopedHandle::Output(_)
+        | ScopedHandle::Uniform(_)),
+      ) if !globals.contains(handle) => globals.push(handle.clone()),
+      _ => (),
+    });
+  }
+
+  globals
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{ErasedReturn, ErasedScope, ScopeInstr, ToType as _};
+
+  // Function calling some functions and using some constants, one per local variable.
+  fn fun(callees: &[u16], constants: &[u16]) -> ErasedFun {
+    let mut scope = ErasedScope::new(0);
+    let exprs = callees
+      .iter()
+      .map(|&callee| ErasedExpr::FunCall(ErasedFunHandle::UserDefined(callee), Vec::new()))
+      .chain(
+        constants
+          .iter()
+          .map(|&constant| ErasedExpr::Var(ScopedHandle::global(constant))),
+      );
+
+    for (handle, init_value) in (0..).zip(exprs) {
+      scope.push(ScopeInstr::VarDecl {
+        ty: f32::ty(),
+        handle: ScopedHandle::fun_var(0, handle),
+        init_value,
+      });
+    }
+
+    ErasedFun::new(Vec::new(), scope, ErasedReturn::Void)
+  }
+
+  #[derive(Debug, PartialEq)]
+  enum Item {
+    // declaration, by index in the declarations
+    Decl(usize),
+    Prototype(u16),
+  }
+
+  fn ordered_decls(decls: &[ShaderDecl]) -> Vec<Item> {
+    CallGraph::new(decls)
+      .ordered_decls()
+      .into_iter()
+      .map(|decl| match decl {
+        OrderedDecl::Decl(decl) => {
+          Item::Decl(decls.iter().position(|d| std::ptr::eq(d, decl)).unwrap())
+        }
+        OrderedDecl::Prototype(handle, _) => Item::Prototype(handle),
+      })
+      .collect()
+  }
+
+  #[test]
+  fn self_recursion() {
+    let decls = [
+      ShaderDecl::FunDef(0, fun(&[], &[])),
+      ShaderDecl::FunDef(1, fun(&[1, 0], &[])),
+      ShaderDecl::Main(fun(&[1], &[])),
+    ];
+    let graph = CallGraph::new(&decls);
+
+    assert_eq!(graph.recursive_funs(), vec![1]);
+    assert_eq!(graph.callees_first(), vec![0, 1]);
+
+    // a recursive function cannot be defined before itself
+    assert_eq!(
+      ordered_decls(&decls),
+      vec![
+        Item::Decl(0),
+        Item::Prototype(1),
+        Item::Decl(1),
+        Item::Decl(2)
+      ]
+    );
+  }
+
+  #[test]
+  fn mutual_recursion() {
+    let decls = [
+      ShaderDecl::FunDef(0, fun(&[1], &[])),
+      ShaderDecl::FunDef(1, fun(&[0], &[])),
+      ShaderDecl::FunDef(2, fun(&[], &[])),
+      ShaderDecl::Main(fun(&[0, 2], &[])),
+    ];
+    let graph = CallGraph::new(&decls);
+
+    assert_eq!(graph.recursive_funs(), vec![0, 1]);
+    assert_eq!(graph.callees_first(), vec![1, 0, 2]);
+
+    // the callee is moved before its caller, and calls the caller through its prototype
+    assert_eq!(
+      ordered_decls(&decls),
+      vec![
+        Item::Prototype(0),
+        Item::Decl(1),
+        Item::Decl(0),
+        Item::Decl(2),
+        Item::Decl(3)
+      ]
+    );
+  }
+
+  #[test]
+  fn diamond() {
+    let decls = [
+      ShaderDecl::Main(fun(&[0], &[])),
+      ShaderDecl::FunDef(0, fun(&[1, 2], &[])),
+      ShaderDecl::FunDef(1, fun(&[3], &[])),
+      ShaderDecl::FunDef(2, fun(&[3], &[])),
+      ShaderDecl::FunDef(3, fun(&[], &[])),
+    ];
+    let graph = CallGraph::new(&decls);
+
+    assert!(graph.recursive_funs().is_empty());
+    assert_eq!(graph.callees_first(), vec![3, 1, 2, 0]);
+
+    // the function called twice is only defined once, before its first caller
+    assert_eq!(
+      ordered_decls(&decls),
+      vec![
+        Item::Decl(4),
+        Item::Decl(2),
+        Item::Decl(3),
+        Item::Decl(1),
+        Item::Decl(0)
+      ]
+    );
+  }
+
+  #[test]
+  fn prototypes() {
+    let decls = [
+      ShaderDecl::FunDef(0, fun(&[1, 2], &[])),
+      ShaderDecl::Const(0, f32::ty(), ErasedExpr::LitFloat(1.), None),
+      ShaderDecl::FunDef(1, fun(&[], &[])),
+      ShaderDecl::FunDef(2, fun(&[], &[0])),
+      ShaderDecl::Main(fun(&[0], &[])),
+    ];
+    let graph = CallGraph::new(&decls);
+
+    assert!(graph.recursive_funs().is_empty());
+    assert_eq!(graph.callees_first(), vec![1, 2, 0]);
+
+    // the function using the constant cannot be moved before it, unlike the other one
+    assert_eq!(
+      ordered_decls(&decls),
+      vec![
+        Item::Decl(2),
+        Item::Prototype(2),
+        Item::Decl(0),
+        Item::Decl(1),
+        Item::Decl(3),
+        Item::Decl(4)
+      ]
+    );
+  }
+}
@@ -0,0 +1,877 @@
+//! Fingerprints of shaders.
+//!
+//! A fingerprint hashes a fixed encoding of the content of a shader, which only depends on this crate: unlike derived
+//! [`Hash`](std::hash::Hash) implementations, it depends neither on the compiler nor on the platform. Integers are
+//! encoded in little-endian, floating-point numbers by their bits — as they are compared — and enum variants by a
+//! fixed tag, so that renaming a variant doesn’t change fingerprints.
+//!
+//! Expressions are encoded as the digest of their content, computed once per shared sub-expression.
+
+use crate::{
+  ArgQualifier, BuiltIn, ColorAttachment, Dim, ErasedExpr, ErasedFun, ErasedFunHandle,
+  ErasedReturn, ErasedScope, FragmentBuiltIn, GeometryBuiltIn, MatrixDim, PrimType, ScopeInstr,
+  ScopedHandle, Shader, ShaderDecl, ShaderStage, Swizzle, SwizzleSelector, TessCtrlBuiltIn,
+  TessEvalBuiltIn, Type, VertexBuiltIn,
+};
+use std::{
+  collections::HashMap,
+  hash::{BuildHasherDefault, Hasher},
+  sync::Arc,
+};
+
+/// 64-bit FNV-1a hasher.
+struct StableHasher(u64);
+
+impl StableHasher {
+  const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+  const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+  fn new() -> Self {
+    StableHasher(Self::OFFSET_BASIS)
+  }
+
+  fn write(&mut self, bytes: &[u8]) {
+    for &byte in bytes {
+      self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(Self::PRIME);
+    }
+  }
+
+  fn finish(&self) -> u64 {
+    self.0
+  }
+}
+
+/// Hasher of addresses and digests, mixing them with a single multiplication.
+///
+/// Digests are computed once per shared sub-expression, looking a few of them up by address each time: those lookups
+/// don’t need the protection of the default hasher against collision attacks.
+#[derive(Default)]
+pub(crate) struct AddressHasher(u64);
+
+impl Hasher for AddressHasher {
+  fn finish(&self) -> u64 {
+    self.0
+  }
+
+  fn write(&mut self, bytes: &[u8]) {
+    for &byte in bytes {
+      self.write_u64(u64::from(byte));
+    }
+  }
+
+  fn write_u64(&mut self, x: u64) {
+    self.0 = (self.0.rotate_left(5) ^ x).wrapping_mul(0x517c_c1b7_2722_0a95);
+  }
+
+  fn write_usize(&mut self, x: usize) {
+    self.write_u64(x as u64);
+  }
+}
+
+/// Digests of expressions, by address.
+pub(crate) type Digests = HashMap<usize, u64, BuildHasherDefault<AddressHasher>>;
+
+/// Fingerprint of a shader.
+pub(crate) fn fingerprint(shader: &Shader) -> u64 {
+  let mut digests = Digests::default();
+  let mut encoder = Encoder::new(&mut digests);
+
+  encoder.stage(shader.stage);
+  encoder.len(shader.decls.len());
+  for decl in &shader.decls {
+    encoder.decl(decl);
+  }
+
+  encoder.hasher.finish()
+}
+
+/// Digest of the content of an expression, consistent with its [`PartialEq`] implementation.
+pub(crate) fn digest(expr: &ErasedExpr) -> u64 {
+  Encoder::new(&mut Digests::default()).digest(expr)
+}
+
+/// Digest of the content of an expression, given the digests of sub-expressions already computed, by address.
+///
+/// The digests of the sub-expressions computed along are added, but not the one of the expression.
+pub(crate) fn digest_with(expr: &ErasedExpr, digests: &mut Digests) -> u64 {
+  let mut encoder = Encoder::new(digests);
+  encoder.expr_content(expr);
+  encoder.hasher.finish()
+}
+
+struct Encoder<'a> {
+  hasher: StableHasher,
+  // digests of the expressions encoded so far, by address
+  digests: &'a mut Digests,
+}
+
+impl<'a> Encoder<'a> {
+  fn new(digests: &'a mut Digests) -> Self {
+    Self {
+      hasher: StableHasher::new(),
+      digests,
+    }
+  }
+
+  fn tag(&mut self, tag: u8) {
+    self.hasher.write(&[tag]);
+  }
+
+  fn u16(&mut self, x: u16) {
+    self.hasher.write(&x.to_le_bytes());
+  }
+
+  fn u64(&mut self, x: u64) {
+    self.hasher.write(&x.to_le_bytes());
+  }
+
+  fn u32s(&mut self, xs: &[u32]) {
+    for x in xs {
+      self.hasher.write(&x.to_le_bytes());
+    }
+  }
+
+  fn i32s(&mut self, xs: &[i32]) {
+    for x in xs {
+      self.hasher.write(&x.to_le_bytes());
+    }
+  }
+
+  fn f32s(&mut self, xs: &[f32]) {
+    for x in xs {
+      self.hasher.write(&x.to_bits().to_le_bytes());
+    }
+  }
+
+  fn bools(&mut self, xs: &[bool]) {
+    for &x in xs {
+      self.tag(x as u8);
+    }
+  }
+
+  fn len(&mut self, len: usize) {
+    self.u64(len as u64);
+  }
+
+  fn str(&mut self, s: &str) {
+    self.len(s.len());
+    self.hasher.write(s.as_bytes());
+  }
+
+  fn name(&mut self, name: &Option<String>) {
+    match name {
+      None => self.tag(0),
+      Some(name) => {
+        self.tag(1);
+        self.str(name);
+      }
+    }
+  }
+
+  fn stage(&mut self, stage: ShaderStage) {
+    self.tag(match stage {
+      ShaderStage::Vertex => 0,
+      ShaderStage::TessCtrl => 1,
+      ShaderStage::TessEval => 2,
+      ShaderStage::Geometry => 3,
+      ShaderStage::Fragment => 4,
+    });
+  }
+
+  fn ty(&mut self, ty: &Type) {
+    let dim = |dim: &Dim| match dim {
+      Dim::Scalar => 0,
+      Dim::D2 => 1,
+      Dim::D3 => 2,
+      Dim::D4 => 3,
+    };
+
+    match &ty.prim_ty {
+      PrimType::Int(d) => self.hasher.write(&[0, dim(d)]),
+      PrimType::UInt(d) => self.hasher.write(&[1, dim(d)]),
+      PrimType::Float(d) => self.hasher.write(&[2, dim(d)]),
+      PrimType::Bool(d) => self.hasher.write(&[3, dim(d)]),
+      PrimType::Matrix(d) => self.hasher.write(&[
+        4,
+        match d {
+          MatrixDim::D22 => 0,
+          MatrixDim::D23 => 1,
+          MatrixDim::D24 => 2,
+          MatrixDim::D32 => 3,
+          MatrixDim::D33 => 4,
+          MatrixDim::D34 => 5,
+          MatrixDim::D42 => 6,
+          MatrixDim::D43 => 7,
+          MatrixDim::D44 => 8,
+        },
+      ]),
+    }
+
+    self.len(ty.array_dims.len());
+    for &dim in &ty.array_dims {
+      self.len(dim);
+    }
+  }
+
+  fn types(&mut self, types: &[Type]) {
+    self.len(types.len());
+    for ty in types {
+      self.ty(ty);
+    }
+  }
+
+  fn arg_qualifiers(&mut self, qualifiers: &[ArgQualifier]) {
+    self.len(qualifiers.len());
+    for qualifier in qualifiers {
+      self.tag(match qualifier {
+        ArgQualifier::In => 0,
+        ArgQualifier::Out => 1,
+        ArgQualifier::InOut => 2,
+      });
+    }
+  }
+
+  fn color_attachment(&mut self, color_attachment: &Option<ColorAttachment>) {
+    match color_attachment {
+      None => self.tag(0),
+      Some(color_attachment) => {
+        self.tag(1);
+        self.u32s(&[color_attachment.location(), color_attachment.index()]);
+      }
+    }
+  }
+
+  // The stage of the built-in comes first, then the built-in among the ones of the stage.
+  fn builtin(&mut self, builtin: &BuiltIn) {
+    let tags = match builtin {
+      BuiltIn::Vertex(builtin) => [
+        0,
+        match builtin {
+          VertexBuiltIn::VertexID => 0,
+          VertexBuiltIn::InstanceID => 1,
+          VertexBuiltIn::BaseVertex => 2,
+          VertexBuiltIn::BaseInstance => 3,
+          VertexBuiltIn::Position => 4,
+          VertexBuiltIn::PointSize => 5,
+          VertexBuiltIn::ClipDistance => 6,
+        },
+      ],
+
+      BuiltIn::TessCtrl(builtin) => [
+        1,
+        match builtin {
+          TessCtrlBuiltIn::MaxPatchVerticesIn => 0,
+          TessCtrlBuiltIn::PatchVerticesIn => 1,
+          TessCtrlBuiltIn::PrimitiveID => 2,
+          TessCtrlBuiltIn::InvocationID => 3,
+          TessCtrlBuiltIn::TessellationLevelOuter => 4,
+          TessCtrlBuiltIn::TessellationLevelInner => 5,
+          TessCtrlBuiltIn::In => 6,
+          TessCtrlBuiltIn::Out => 7,
+          TessCtrlBuiltIn::Position => 8,
+          TessCtrlBuiltIn::PointSize => 9,
+          TessCtrlBuiltIn::ClipDistance => 10,
+          TessCtrlBuiltIn::CullDistance => 11,
+        },
+      ],
+
+      BuiltIn::TessEval(builtin) => [
+        2,
+        match builtin {
+          TessEvalBuiltIn::TessCoord => 0,
+          TessEvalBuiltIn::MaxPatchVerticesIn => 1,
+          TessEvalBuiltIn::PatchVerticesIn => 2,
+          TessEvalBuiltIn::PrimitiveID => 3,
+          TessEvalBuiltIn::TessellationLevelOuter => 4,
+          TessEvalBuiltIn::TessellationLevelInner => 5,
+          TessEvalBuiltIn::In => 6,
+          TessEvalBuiltIn::Out => 7,
+          TessEvalBuiltIn::Position => 8,
+          TessEvalBuiltIn::PointSize => 9,
+          TessEvalBuiltIn::ClipDistance => 10,
+          TessEvalBuiltIn::CullDistance => 11,
+        },
+      ],
+
+      BuiltIn::Geometry(builtin) => [
+        3,
+        match builtin {
+          GeometryBuiltIn::In => 0,
+          GeometryBuiltIn::Out => 1,
+          GeometryBuiltIn::Position => 2,
+          GeometryBuiltIn::PointSize => 3,
+          GeometryBuiltIn::ClipDistance => 4,
+          GeometryBuiltIn::CullDistance => 5,
+          GeometryBuiltIn::PrimitiveID => 6,
+          GeometryBuiltIn::PrimitiveIDIn => 7,
+          GeometryBuiltIn::InvocationID => 8,
+          GeometryBuiltIn::Layer => 9,
+          GeometryBuiltIn::ViewportIndex => 10,
+        },
+      ],
+
+      BuiltIn::Fragment(builtin) => [
+        4,
+        match builtin {
+          FragmentBuiltIn::FragCoord => 0,
+          FragmentBuiltIn::FrontFacing => 1,
+          FragmentBuiltIn::PointCoord => 2,
+          FragmentBuiltIn::SampleID => 3,
+          FragmentBuiltIn::SamplePosition => 4,
+          FragmentBuiltIn::SampleMaskIn => 5,
+          FragmentBuiltIn::ClipDistance => 6,
+          FragmentBuiltIn::CullDistance => 7,
+          FragmentBuiltIn::PrimitiveID => 8,
+          FragmentBuiltIn::Layer => 9,
+          FragmentBuiltIn::ViewportIndex => 10,
+          FragmentBuiltIn::FragDepth => 11,
+          FragmentBuiltIn::SampleMask => 12,
+          FragmentBuiltIn::HelperInvocation => 13,
+        },
+      ],
+    };
+
+    self.hasher.write(&tags);
+  }
+
+  // Swizzles are encoded by their number of selectors, then their selectors.
+  fn swizzle(&mut self, swizzle: &Swizzle) {
+    let selector = |sel: &SwizzleSelector| match sel {
+      SwizzleSelector::X => 0,
+      SwizzleSelector::Y => 1,
+      SwizzleSelector::Z => 2,
+      SwizzleSelector::W => 3,
+    };
+
+    match swizzle {
+      Swizzle::D1(a) => self.hasher.write(&[1, selector(a)]),
+      Swizzle::D2(a, b) => self.hasher.write(&[2, selector(a), selector(b)]),
+      Swizzle::D3(a, b, c) => self
+        .hasher
+        .write(&[3, selector(a), selector(b), selector(c)]),
+      Swizzle::D4(a, b, c, d) => {
+        self
+          .hasher
+          .write(&[4, selector(a), selector(b), selector(c), selector(d)])
+      }
+    }
+  }
+
+  fn fun_handle(&mut self, fun: &ErasedFunHandle) {
+    match fun {
+      ErasedFunHandle::Vec2 => self.tag(0),
+      ErasedFunHandle::Vec3 => self.tag(1),
+      ErasedFunHandle::Vec4 => self.tag(2),
+      ErasedFunHandle::Radians => self.tag(3),
+      ErasedFunHandle::Degrees => self.tag(4),
+      ErasedFunHandle::Sin => self.tag(5),
+      ErasedFunHandle::Cos => self.tag(6),
+      ErasedFunHandle::Tan => self.tag(7),
+      ErasedFunHandle::ASin => self.tag(8),
+      ErasedFunHandle::ACos => self.tag(9),
+      ErasedFunHandle::ATan => self.tag(10),
+      ErasedFunHandle::SinH => self.tag(11),
+      ErasedFunHandle::CosH => self.tag(12),
+      ErasedFunHandle::TanH => self.tag(13),
+      ErasedFunHandle::ASinH => self.tag(14),
+      ErasedFunHandle::ACosH => self.tag(15),
+      ErasedFunHandle::ATanH => self.tag(16),
+      ErasedFunHandle::Pow => self.tag(17),
+      ErasedFunHandle::Exp => self.tag(18),
+      ErasedFunHandle::Exp2 => self.tag(19),
+      ErasedFunHandle::Log => self.tag(20),
+      ErasedFunHandle::Log2 => self.tag(21),
+      ErasedFunHandle::Sqrt => self.tag(22),
+      ErasedFunHandle::InverseSqrt => self.tag(23),
+      ErasedFunHandle::Abs => self.tag(24),
+      ErasedFunHandle::Sign => self.tag(25),
+      ErasedFunHandle::Floor => self.tag(26),
+      ErasedFunHandle::Trunc => self.tag(27),
+      ErasedFunHandle::Round => self.tag(28),
+      ErasedFunHandle::RoundEven => self.tag(29),
+      ErasedFunHandle::Ceil => self.tag(30),
+      ErasedFunHandle::Fract => self.tag(31),
+      ErasedFunHandle::Min => self.tag(32),
+      ErasedFunHandle::Max => self.tag(33),
+      ErasedFunHandle::Clamp => self.tag(34),
+      ErasedFunHandle::Mix => self.tag(35),
+      ErasedFunHandle::Step => self.tag(36),
+      ErasedFunHandle::SmoothStep => self.tag(37),
+      ErasedFunHandle::IsNan => self.tag(38),
+      ErasedFunHandle::IsInf => self.tag(39),
+      ErasedFunHandle::FloatBitsToInt => self.tag(40),
+      ErasedFunHandle::IntBitsToFloat => self.tag(41),
+      ErasedFunHandle::UIntBitsToFloat => self.tag(42),
+      ErasedFunHandle::FMA => self.tag(43),
+      ErasedFunHandle::Frexp => self.tag(44),
+      ErasedFunHandle::Ldexp => self.tag(45),
+      ErasedFunHandle::PackUnorm2x16 => self.tag(46),
+      ErasedFunHandle::PackSnorm2x16 => self.tag(47),
+      ErasedFunHandle::PackUnorm4x8 => self.tag(48),
+      ErasedFunHandle::PackSnorm4x8 => self.tag(49),
+      ErasedFunHandle::UnpackUnorm2x16 => self.tag(50),
+      ErasedFunHandle::UnpackSnorm2x16 => self.tag(51),
+      ErasedFunHandle::UnpackUnorm4x8 => self.tag(52),
+      ErasedFunHandle::UnpackSnorm4x8 => self.tag(53),
+      ErasedFunHandle::PackHalf2x16 => self.tag(54),
+      ErasedFunHandle::UnpackHalf2x16 => self.tag(55),
+      ErasedFunHandle::Length => self.tag(56),
+      ErasedFunHandle::Distance => self.tag(57),
+      ErasedFunHandle::Dot => self.tag(58),
+      ErasedFunHandle::Cross => self.tag(59),
+      ErasedFunHandle::Normalize => self.tag(60),
+      ErasedFunHandle::FaceForward => self.tag(61),
+      ErasedFunHandle::Reflect => self.tag(62),
+      ErasedFunHandle::Refract => self.tag(63),
+      ErasedFunHandle::VLt => self.tag(64),
+      ErasedFunHandle::VLte => self.tag(65),
+      ErasedFunHandle::VGt => self.tag(66),
+      ErasedFunHandle::VGte => self.tag(67),
+      ErasedFunHandle::VEq => self.tag(68),
+      ErasedFunHandle::VNeq => self.tag(69),
+      ErasedFunHandle::VAny => self.tag(70),
+      ErasedFunHandle::VAll => self.tag(71),
+      ErasedFunHandle::VNot => self.tag(72),
+      ErasedFunHandle::UAddCarry => self.tag(73),
+      ErasedFunHandle::USubBorrow => self.tag(74),
+      ErasedFunHandle::UMulExtended => self.tag(75),
+      ErasedFunHandle::IMulExtended => self.tag(76),
+      ErasedFunHandle::BitfieldExtract => self.tag(77),
+      ErasedFunHandle::BitfieldInsert => self.tag(78),
+      ErasedFunHandle::BitfieldReverse => self.tag(79),
+      ErasedFunHandle::BitCount => self.tag(80),
+      ErasedFunHandle::FindLSB => self.tag(81),
+      ErasedFunHandle::FindMSB => self.tag(82),
+      ErasedFunHandle::EmitStreamVertex => self.tag(83),
+      ErasedFunHandle::EndStreamPrimitive => self.tag(84),
+      ErasedFunHandle::EmitVertex => self.tag(85),
+      ErasedFunHandle::EndPrimitive => self.tag(86),
+      ErasedFunHandle::DFDX => self.tag(87),
+      ErasedFunHandle::DFDY => self.tag(88),
+      ErasedFunHandle::DFDXFine => self.tag(89),
+      ErasedFunHandle::DFDYFine => self.tag(90),
+      ErasedFunHandle::DFDXCoarse => self.tag(91),
+      ErasedFunHandle::DFDYCoarse => self.tag(92),
+      ErasedFunHandle::FWidth => self.tag(93),
+      ErasedFunHandle::FWidthFine => self.tag(94),
+      ErasedFunHandle::FWidthCoarse => self.tag(95),
+      ErasedFunHandle::InterpolateAtCentroid => self.tag(96),
+      ErasedFunHandle::InterpolateAtSample => self.tag(97),
+      ErasedFunHandle::InterpolateAtOffset => self.tag(98),
+      ErasedFunHandle::Barrier => self.tag(99),
+      ErasedFunHandle::MemoryBarrier => self.tag(100),
+      ErasedFunHandle::MemoryBarrierAtomic => self.tag(101),
+      ErasedFunHandle::MemoryBarrierBuffer => self.tag(102),
+      ErasedFunHandle::MemoryBarrierShared => self.tag(103),
+      ErasedFunHandle::MemoryBarrierImage => self.tag(104),
+      ErasedFunHandle::GroupMemoryBarrier => self.tag(105),
+      ErasedFunHandle::AnyInvocation => self.tag(106),
+      ErasedFunHandle::AllInvocations => self.tag(107),
+      ErasedFunHandle::AllInvocationsEqual => self.tag(108),
+      ErasedFunHandle::UserDefined(handle) => {
+        self.tag(109);
+        self.u16(*handle);
+      }
+      ErasedFunHandle::Library(library, handle) => {
+        self.tag(110);
+        self.u64(*library as u64);
+        self.u16(*handle);
+      }
+    }
+  }
+
+  fn decl(&mut self, decl: &ShaderDecl) {
+    match decl {
+      ShaderDecl::Main(fun) => {
+        self.tag(0);
+        self.fun(fun);
+      }
+
+      ShaderDecl::FunDef(handle, fun) => {
+        self.tag(1);
+        self.u16(*handle);
+        self.fun(fun);
+      }
+
+      ShaderDecl::Const(handle, ty, expr, name) => {
+        self.tag(2);
+        self.u16(*handle);
+        self.ty(ty);
+        self.expr(expr);
+        self.name(name);
+      }
+
+      ShaderDecl::In(name, ty) => {
+        self.tag(3);
+        self.str(name);
+        self.ty(ty);
+      }
+
+      ShaderDecl::Out(name, ty, color_attachment) => {
+        self.tag(4);
+        self.str(name);
+        self.ty(ty);
+        self.color_attachment(color_attachment);
+      }
+
+      ShaderDecl::Uniform(name, ty) => {
+        self.tag(5);
+        self.str(name);
+        self.ty(ty);
+      }
+    }
+  }
+
+  fn fun(&mut self, fun: &ErasedFun) {
+    self.types(&fun.args);
+    self.arg_qualifiers(&fun.arg_qualifiers);
+    self.scope(&fun.scope);
+    self.ret(&fun.ret);
+    self.name(&fun.name);
+
+    self.len(fun.arg_names.len());
+    for name in &fun.arg_names {
+      self.str(name);
+    }
+  }
+
+  fn scope(&mut self, scope: &ErasedScope) {
+    self.u16(scope.id);
+    self.u16(scope.next_var);
+
+    self.len(scope.names.len());
+    for (handle, name) in &scope.names {
+      self.u16(*handle);
+      self.str(name);
+    }
+
+    self.len(scope.instructions.len());
+    for instr in &scope.instructions {
+      self.instr(instr);
+    }
+  }
+
+  fn ret(&mut self, ret: &ErasedReturn) {
+    match ret {
+      ErasedReturn::Void => self.tag(0),
+      ErasedReturn::Expr(ty, expr) => {
+        self.tag(1);
+        self.ty(ty);
+        self.expr(expr);
+      }
+    }
+  }
+
+  fn instr(&mut self, instr: &ScopeInstr) {
+    match instr {
+      ScopeInstr::VarDecl {
+        ty,
+        handle,
+        init_value,
+      } => {
+        self.tag(0);
+        self.ty(ty);
+        self.handle(handle);
+        self.expr(init_value);
+      }
+
+      ScopeInstr::Return(ret) => {
+        self.tag(1);
+        self.ret(ret);
+      }
+
+      ScopeInstr::Continue => self.tag(2),
+
+      ScopeInstr::Break => self.tag(3),
+
+      ScopeInstr::If { condition, scope } => {
+        self.tag(4);
+        self.expr(condition);
+        self.scope(scope);
+      }
+
+      ScopeInstr::ElseIf { condition, scope } => {
+        self.tag(5);
+        self.expr(condition);
+        self.scope(scope);
+      }
+
+      ScopeInstr::Else { scope } => {
+        self.tag(6);
+        self.scope(scope);
+      }
+
+      ScopeInstr::For {
+        init_ty,
+        init_handle,
+        init_expr,
+        condition,
+        post_expr,
+        scope,
+      } => {
+        self.tag(7);
+        self.ty(init_ty);
+        self.handle(init_handle);
+        self.expr(init_expr);
+        self.expr(condition);
+        self.expr(post_expr);
+        self.scope(scope);
+      }
+
+      ScopeInstr::While { condition, scope } => {
+        self.tag(8);
+        self.expr(condition);
+        self.scope(scope);
+      }
+
+      ScopeInstr::MutateVar { var, expr } => {
+        self.tag(9);
+        self.expr(var);
+        self.expr(expr);
+      }
+
+      ScopeInstr::Discard => self.tag(10),
+    }
+  }
+
+  fn handle(&mut self, handle: &ScopedHandle) {
+    match handle {
+      ScopedHandle::BuiltIn(builtin) => {
+        self.tag(0);
+        self.builtin(builtin);
+      }
+
+      ScopedHandle::Global(handle) => {
+        self.tag(1);
+        self.u16(*handle);
+      }
+
+      ScopedHandle::FunArg(handle) => {
+        self.tag(2);
+        self.u16(*handle);
+      }
+
+      ScopedHandle::FunVar { subscope, handle } => {
+        self.tag(3);
+        self.u16(*subscope);
+        self.u16(*handle);
+      }
+
+      ScopedHandle::Input(name) => {
+        self.tag(4);
+        self.str(name);
+      }
+
+      ScopedHandle::Output(name) => {
+        self.tag(5);
+        self.str(name);
+      }
+
+      ScopedHandle::Uniform(name) => {
+        self.tag(6);
+        self.str(name);
+      }
+
+      ScopedHandle::LibraryGlobal(library, handle) => {
+        self.tag(7);
+        self.u64(*library as u64);
+        self.u16(*handle);
+      }
+    }
+  }
+
+  fn expr(&mut self, expr: &ErasedExpr) {
+    let digest = self.digest(expr);
+    self.hasher.write(&digest.to_le_bytes());
+  }
+
+  fn exprs(&mut self, exprs: &[Arc<ErasedExpr>]) {
+    self.len(exprs.len());
+    for expr in exprs {
+      self.expr(expr);
+    }
+  }
+
+  fn unary(&mut self, tag: u8, a: &ErasedExpr) {
+    self.tag(tag);
+    self.expr(a);
+  }
+
+  fn binary(&mut self, tag: u8, a: &ErasedExpr, b: &ErasedExpr) {
+    self.tag(tag);
+    self.expr(a);
+    self.expr(b);
+  }
+
+  // Sub-expressions shared by several parents are encoded once.
+  fn digest(&mut self, expr: &ErasedExpr) -> u64 {
+    let address = expr as *const ErasedExpr as usize;
+
+    if let Some(&digest) = self.digests.get(&address) {
+      return digest;
+    }
+
+    let mut encoder = Encoder::new(&mut *self.digests);
+    encoder.expr_content(expr);
+    let digest = encoder.hasher.finish();
+
+    self.digests.insert(address, digest);
+    digest
+  }
+
+  fn expr_content(&mut self, expr: &ErasedExpr) {
+    match expr {
+      ErasedExpr::LitInt(x) => {
+        self.tag(0);
+        self.i32s(&[*x]);
+      }
+      ErasedExpr::LitUInt(x) => {
+        self.tag(1);
+        self.u32s(&[*x]);
+      }
+      ErasedExpr::LitFloat(x) => {
+        self.tag(2);
+        self.f32s(&[*x]);
+      }
+      ErasedExpr::LitBool(x) => {
+        self.tag(3);
+        self.bools(&[*x]);
+      }
+      ErasedExpr::LitInt2(x) => {
+        self.tag(4);
+        self.i32s(x);
+      }
+      ErasedExpr::LitUInt2(x) => {
+        self.tag(5);
+        self.u32s(x);
+      }
+      ErasedExpr::LitFloat2(x) => {
+        self.tag(6);
+        self.f32s(x);
+      }
+      ErasedExpr::LitBool2(x) => {
+        self.tag(7);
+        self.bools(x);
+      }
+      ErasedExpr::LitInt3(x) => {
+        self.tag(8);
+        self.i32s(x);
+      }
+      ErasedExpr::LitUInt3(x) => {
+        self.tag(9);
+        self.u32s(x);
+      }
+      ErasedExpr::LitFloat3(x) => {
+        self.tag(10);
+        self.f32s(x);
+      }
+      ErasedExpr::LitBool3(x) => {
+        self.tag(11);
+        self.bools(x);
+      }
+      ErasedExpr::LitInt4(x) => {
+        self.tag(12);
+        self.i32s(x);
+      }
+      ErasedExpr::LitUInt4(x) => {
+        self.tag(13);
+        self.u32s(x);
+      }
+      ErasedExpr::LitFloat4(x) => {
+        self.tag(14);
+        self.f32s(x);
+      }
+      ErasedExpr::LitBool4(x) => {
+        self.tag(15);
+        self.bools(x);
+      }
+      ErasedExpr::LitM22(m) => {
+        self.tag(16);
+        for column in &m.0 {
+          self.f32s(column);
+        }
+      }
+      ErasedExpr::LitM33(m) => {
+        self.tag(17);
+        for column in &m.0 {
+          self.f32s(column);
+        }
+      }
+      ErasedExpr::LitM44(m) => {
+        self.tag(18);
+        for column in &m.0 {
+          self.f32s(column);
+        }
+      }
+      ErasedExpr::Array(ty, items) => {
+        self.tag(19);
+        self.ty(ty);
+        self.exprs(items);
+      }
+      ErasedExpr::Var(handle) => {
+        self.tag(20);
+        self.handle(handle);
+      }
+      ErasedExpr::Not(a) => self.unary(21, a),
+      ErasedExpr::And(a, b) => self.binary(22, a, b),
+      ErasedExpr::Or(a, b) => self.binary(23, a, b),
+      ErasedExpr::Xor(a, b) => self.binary(24, a, b),
+      ErasedExpr::BitOr(a, b) => self.binary(25, a, b),
+      ErasedExpr::BitAnd(a, b) => self.binary(26, a, b),
+      ErasedExpr::BitXor(a, b) => self.binary(27, a, b),
+      ErasedExpr::Neg(a) => self.unary(28, a),
+      ErasedExpr::Add(a, b) => self.binary(29, a, b),
+      ErasedExpr::Sub(a, b) => self.binary(30, a, b),
+      ErasedExpr::Mul(a, b) => self.binary(31, a, b),
+      ErasedExpr::Div(a, b) => self.binary(32, a, b),
+      ErasedExpr::Rem(a, b) => self.binary(33, a, b),
+      ErasedExpr::Shl(a, b) => self.binary(34, a, b),
+      ErasedExpr::Shr(a, b) => self.binary(35, a, b),
+      ErasedExpr::Eq(a, b) => self.binary(36, a, b),
+      ErasedExpr::Neq(a, b) => self.binary(37, a, b),
+      ErasedExpr::Lt(a, b) => self.binary(38, a, b),
+      ErasedExpr::Lte(a, b) => self.binary(39, a, b),
+      ErasedExpr::Gt(a, b) => self.binary(40, a, b),
+      ErasedExpr::Gte(a, b) => self.binary(41, a, b),
+      ErasedExpr::FunCall(fun, args) => {
+        self.tag(42);
+        self.fun_handle(fun);
+        self.exprs(args);
+      }
+      ErasedExpr::Swizzle(a, swizzle) => {
+        self.unary(43, a);
+        self.swizzle(swizzle);
+      }
+      ErasedExpr::Field { object, field } => self.binary(44, object, field),
+      ErasedExpr::ArrayLookup { object, index } => self.binary(45, object, index),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{lit, vec4, Scope, ShaderBuilder};
+
+  #[test]
+  fn stable_hasher() {
+    // reference FNV-1a test vectors
+    let mut hasher = StableHasher::new();
+    assert_eq!(hasher.finish(), 0xcbf29ce484222325);
+    hasher.write(b"a");
+    assert_eq!(hasher.finish(), 0xaf63dc4c8601ec8c);
+  }
+
+  #[test]
+  fn stable_fingerprint() {
+    let shader = ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+      let c = s.constant_named("c", lit!(-0.));
+      s.main_fun(|s: &mut Scope<()>| {
+        s.set(vertex.position, vec4!(c.clone(), 0., 0., 1.));
+      })
+    });
+
+    // the fingerprint only depends on the encoding, which is the same on all platforms
+    assert_eq!(shader.fingerprint(), 0x13bd9c4e0e6c05e5);
+  }
+}
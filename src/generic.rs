@@ -0,0 +1,419 @@
+//! Generic functions, instantiated for each type they are called with.
+//!
+//! [`ShaderBuilder::fun`] declares a function for a single set of types: a helper working on `f32`, `V3<f32>` and
+//! `V4<f32>` would have to be declared three times. A [`GenericFun`] describes the body of a function for any types
+//! it supports, and [`ShaderBuilder::generic_fun`] declares it in a shader. The returned [`GenericFunHandle`] can be
+//! called with any of those types; the function is instantiated the first time it is called with a set of types, and
+//! further calls with the same types share that instance.
+//!
+//! Instances are declared right before the function calling them first. Instances of a named generic function all have
+//! the same name: the GLSL writer outputs them as overloads, and writers for languages without overloading suffix
+//! their identifiers with the types of their arguments.
+//!
+//! # Examples
+//!
+//! ```
+//! use shades::{
+//!   generic::GenericFun, lit, vec4, writer::glsl, Bounded as _, Expr, FunDef, Scope, ShaderBuilder, ToFun, ToType,
+//!   V3,
+//! };
+//!
+//! // a function returning the largest of its arguments, for any type supported by max
+//! struct Largest;
+//!
+//! impl<T> GenericFun<Expr<T>, (Expr<T>, Expr<T>)> for Largest
+//! where
+//!   T: ToType,
+//!   Expr<T>: shades::Bounded,
+//! {
+//!   fn instantiate(&self) -> FunDef<Expr<T>, (Expr<T>, Expr<T>)> {
+//!     (|_: &mut Scope<Expr<T>>, a: Expr<T>, b: Expr<T>| a.max(b)).build_fn()
+//!   }
+//! }
+//!
+//! let shader = ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+//!   let largest = s.generic_fun_named("largest", &["a", "b"], Largest);
+//!
+//!   s.main_fun(|s: &mut Scope<()>| {
+//!     let x = s.var(largest.call((lit!(1.), lit!(2.))));
+//!     let v = s.var(largest.call((lit!(1., 0., 0.), lit!(0., 1., 0.))));
+//!     let y = s.var(largest.call((x.clone().into(), lit!(3.))));
+//!     s.set(&vertex.position, vec4!(v, y));
+//!   })
+//! });
+//!
+//! let output = glsl::write_shader_to_str(&shader).unwrap();
+//! assert!(output.contains("float largest(float a, float b) {"));
+//! assert!(output.contains("vec3 largest(vec3 a, vec3 b) {"));
+//! assert_eq!(output.matches("largest(").count(), 5);
+//! ```
+
+use crate::{
+  ErasedExpr, ErasedFun, ErasedFunHandle, Expr, FunDef, ShaderBuilder, ShaderDecl, ToType, Type,
+};
+use std::{
+  collections::HashMap,
+  mem,
+  panic::Location,
+  sync::{Arc, Mutex},
+};
+
+/// Body of a generic function, for some types of arguments and return value.
+///
+/// `R` is the return type and `A` the arguments, as in [`FunHandle<R, A>`](crate::FunHandle). Implement this trait
+/// generically over the types the function supports, building the definition of the function from a closure with
+/// [`ToFun::build_fn`](crate::ToFun::build_fn). See the [module documentation](crate::generic) for an example.
+pub trait GenericFun<R, A> {
+  /// Build the definition of the function for these types.
+  fn instantiate(&self) -> FunDef<R, A>;
+}
+
+/// Instances of the generic functions of a shader builder, shared with their handles.
+#[derive(Debug, Default)]
+pub(crate) struct Instances {
+  // next handle of the shader builder, allocated to instances by handles
+  pub(crate) next_fun_handle: u16,
+  // instances not yet added to the shader, in instantiation order
+  pub(crate) pending: Vec<ShaderDecl>,
+  // handles of the instances, by generic function and types of their arguments and return value
+  handles: HashMap<(usize, Vec<Type>, Type), u16>,
+  next_generic: usize,
+}
+
+/// Handle of a generic function.
+///
+/// Its `call` method is available for any types the [`GenericFun`] is implemented for, and returns an expression
+/// calling the instance for those types.
+#[derive(Debug)]
+pub struct GenericFunHandle<G> {
+  id: usize,
+  body: Arc<G>,
+  name: Option<String>,
+  arg_names: Vec<String>,
+  location: &'static Location<'static>,
+  instances: Arc<Mutex<Instances>>,
+}
+
+impl<G> Clone for GenericFunHandle<G> {
+  fn clone(&self) -> Self {
+    Self {
+      id: self.id,
+      body: self.body.clone(),
+      name: self.name.clone(),
+      arg_names: self.arg_names.clone(),
+      location: self.location,
+      instances: self.instances.clone(),
+    }
+  }
+}
+
+impl<G> GenericFunHandle<G> {
+  /// Handle of the instance for some types, instantiating it if needed.
+  fn instance(
+    &self,
+    args: Vec<Type>,
+    ret: Type,
+    instantiate: impl FnOnce() -> ErasedFun,
+  ) -> ErasedFunHandle {
+    let key = (self.id, args, ret);
+
+    let handle = {
+      let mut instances = self.instances.lock().unwrap();
+
+      if let Some(&handle) = instances.handles.get(&key) {
+        return ErasedFunHandle::UserDefined(handle);
+      }
+
+      // the handle is allocated before the body is built, so that instances calling themselves find it
+      let handle = instances.next_fun_handle;
+      instances.next_fun_handle = instances.next_fun_handle.wrapping_add(1);
+      instances.handles.insert(key, handle);
+      handle
+    };
+
+    let mut fun = instantiate();
+    fun.name = self.name.clone();
+    fun.arg_names = self.arg_names.clone();
+    fun.location = Some(self.location);
+
+    self
+      .instances
+      .lock()
+      .unwrap()
+      .pending
+      .push(ShaderDecl::FunDef(handle, fun));
+
+    ErasedFunHandle::UserDefined(handle)
+  }
+}
+
+impl<G> GenericFunHandle<G> {
+  /// Create an expression representing a call to the instance of this function for the types of the arguments.
+  ///
+  /// A function taking a single argument is called with that argument, and a function taking several arguments with a
+  /// tuple of them. The return type selects the instance as well.
+  pub fn call<R, A>(&self, args: A) -> Expr<R>
+  where
+    G: GenericFun<Expr<R>, A>,
+    R: ToType,
+    A: CallArgs,
+  {
+    let handle = self.instance(A::types(), R::ty(), || self.body.instantiate().erased);
+    Expr::new(ErasedExpr::FunCall(handle, sealed::Erase::erase(args).0))
+  }
+}
+
+/// Arguments of a call to a generic function: `()`, an [`Expr<T>`] or a tuple of [`Expr<T>`].
+///
+/// This trait is sealed: it cannot be implemented outside of this crate.
+pub trait CallArgs: sealed::Erase {
+  /// Types of the arguments.
+  fn types() -> Vec<Type>;
+}
+
+mod sealed {
+  use crate::ErasedExpr;
+  use std::sync::Arc;
+
+  /// Erased arguments of a call.
+  pub struct Args(pub(crate) Vec<Arc<ErasedExpr>>);
+
+  pub trait Erase {
+    fn erase(self) -> Args;
+  }
+}
+
+impl CallArgs for () {
+  fn types() -> Vec<Type> {
+    Vec::new()
+  }
+}
+
+impl sealed::Erase for () {
+  fn erase(self) -> sealed::Args {
+    sealed::Args(Vec::new())
+  }
+}
+
+impl<A> CallArgs for Expr<A>
+where
+  A: ToType,
+{
+  fn types() -> Vec<Type> {
+    vec![A::ty()]
+  }
+}
+
+impl<A> sealed::Erase for Expr<A> {
+  fn erase(self) -> sealed::Args {
+    sealed::Args(vec![Arc::new(self.erased)])
+  }
+}
+
+macro_rules! impl_CallArgs {
+  ( $( ( $arg_name:ident, $arg_ty:ident ) ),* ) => {
+    impl<$($arg_ty),*> CallArgs for ($(Expr<$arg_ty>),*)
+    where
+      $($arg_ty: ToType),*
+    {
+      fn types() -> Vec<Type> {
+        vec![$($arg_ty::ty()),*]
+      }
+    }
+
+    impl<$($arg_ty),*> sealed::Erase for ($(Expr<$arg_ty>),*) {
+      fn erase(self) -> sealed::Args {
+        let ($($arg_name),*) = self;
+        sealed::Args(vec![$(Arc::new($arg_name.erased)),*])
+      }
+    }
+  };
+}
+
+macro_rules! impl_CallArgs_rec {
+  ( ( $a:ident, $b:ident ) , ( $x:ident, $y:ident ) ) => {
+    impl_CallArgs!(($a, $b), ($x, $y));
+  };
+
+  ( ( $a:ident, $b:ident ) , ( $x:ident, $y:ident ) , $($r:tt)* ) => {
+    impl_CallArgs_rec!(($a, $b), $($r)*);
+    impl_CallArgs!(($a, $b), ($x, $y), $($r)*);
+  };
+}
+
+impl_CallArgs_rec!(
+  (a, A),
+  (b, B),
+  (c, C),
+  (d, D),
+  (e, E),
+  (f, F),
+  (g, G),
+  (h, H),
+  (i, I),
+  (j, J),
+  (k, K),
+  (l, L),
+  (m, M),
+  (n, N),
+  (o, O),
+  (p, P)
+);
+
+impl ShaderBuilder {
+  /// Declare a new generic function in the shader and get its handle for future use.
+  ///
+  /// Nothing is added to the shader until the function is called: each call with new types instantiates the function
+  /// for those types. See the [`generic`](crate::generic) module for an example.
+  #[track_caller]
+  pub fn generic_fun<G>(&mut self, body: G) -> GenericFunHandle<G> {
+    let instances = self.instances.get_or_insert_with(Default::default).clone();
+    let id = {
+      let mut instances = instances.lock().unwrap();
+      instances.next_fun_handle = self.next_fun_handle;
+
+      let id = instances.next_generic;
+      instances.next_generic += 1;
+      id
+    };
+
+    GenericFunHandle {
+      id,
+      body: Arc::new(body),
+      name: None,
+      arg_names: Vec::new(),
+      location: Location::caller(),
+      instances,
+    }
+  }
+
+  /// Declare a new generic function with a name, and names for its arguments.
+  ///
+  /// This method is similar to [`ShaderBuilder::generic_fun`]; names are used as with [`ShaderBuilder::fun_named`].
+  #[track_caller]
+  pub fn generic_fun_named<G>(
+    &mut self,
+    name: impl Into<String>,
+    arg_names: &[&str],
+    body: G,
+  ) -> GenericFunHandle<G> {
+    let mut handle = self.generic_fun(body);
+    handle.name = Some(name.into());
+    handle.arg_names = arg_names.iter().map(|&name| name.to_owned()).collect();
+    handle
+  }
+
+  /// Add the pending instances of generic functions to the shader, and catch up with the handles they use.
+  pub(crate) fn add_instances(&mut self) {
+    let pending = match &self.instances {
+      Some(instances) => {
+        let mut instances = instances.lock().unwrap();
+        self.next_fun_handle = instances.next_fun_handle;
+        mem::take(&mut instances.pending)
+      }
+
+      None => return,
+    };
+
+    for mut decl in pending {
+      if let ShaderDecl::FunDef(_, fun) = &mut decl {
+        self.renumber_scopes(fun);
+      }
+
+      self.push_decl(decl);
+    }
+  }
+
+  /// Allocate the handle of a new function, after the pending instances of generic functions.
+  pub(crate) fn next_fun_handle(&mut self) -> u16 {
+    self.add_instances();
+
+    let handle = self.next_fun_handle;
+    self.next_fun_handle = self.next_fun_handle.wrapping_add(1);
+
+    if let Some(instances) = &self.instances {
+      instances.lock().unwrap().next_fun_handle = self.next_fun_handle;
+    }
+
+    handle
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{lit, writer::glsl::write_shader_to_str, Scope, ToFun as _, V4};
+  use std::ops::Add;
+
+  struct Double;
+
+  impl<T> GenericFun<Expr<T>, Expr<T>> for Double
+  where
+    T: ToType,
+    Expr<T>: Add<Output = Expr<T>>,
+  {
+    fn instantiate(&self) -> FunDef<Expr<T>, Expr<T>> {
+      (|_: &mut Scope<Expr<T>>, x: Expr<T>| x.clone() + x).build_fn()
+    }
+  }
+
+  #[test]
+  fn instances() {
+    let generic = ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+      let double = s.generic_fun(Double);
+      let quadruple = s.fun(|_: &mut Scope<Expr<f32>>, x: Expr<f32>| double.call(double.call(x)));
+
+      s.main_fun(|s: &mut Scope<()>| {
+        let v = double.call(lit!(0., 0., 0., 1.));
+        s.set(&vertex.position, v * quadruple.call(double.call(lit!(1.))));
+      })
+    });
+
+    let expected = ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+      let double = s.fun(|_: &mut Scope<Expr<f32>>, x: Expr<f32>| x.clone() + x);
+      let quadruple = s.fun(|_: &mut Scope<Expr<f32>>, x: Expr<f32>| double.call(double.call(x)));
+      let double_v4 = s.fun(|_: &mut Scope<Expr<V4<f32>>>, x: Expr<V4<f32>>| x.clone() + x);
+
+      s.main_fun(|s: &mut Scope<()>| {
+        let v = double_v4.call(lit!(0., 0., 0., 1.));
+        s.set(&vertex.position, v * quadruple.call(double.call(lit!(1.))));
+      })
+    });
+
+    // instances are declared once, before the first function calling them; their scopes are numbered when they are
+    // added to the shader, so the shaders only differ by the identifiers of their scopes
+    assert_eq!(
+      write_shader_to_str(&generic),
+      write_shader_to_str(&expected)
+    );
+    assert!(generic.validate().is_empty());
+  }
+
+  #[test]
+  fn named_instances() {
+    let shader = ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+      let double = s.generic_fun_named("double", &["x"], Double);
+      let other = s.generic_fun(Double);
+
+      s.main_fun(|s: &mut Scope<()>| {
+        let x = other.call(double.call(lit!(1.)));
+        s.set(&vertex.position, double.call(lit!(0., 0., 0., 1.)) * x);
+      })
+    });
+
+    let names: Vec<_> = shader
+      .decls
+      .iter()
+      .filter_map(|decl| match decl {
+        ShaderDecl::FunDef(handle, fun) => Some((*handle, fun.name.as_deref(), fun.args.len())),
+        _ => None,
+      })
+      .collect();
+
+    // generic functions don’t share their instances, even for the same body and types
+    assert_eq!(
+      names,
+      [(0, Some("double"), 1), (1, None, 1), (2, Some("double"), 1)]
+    );
+  }
+}
@@ -0,0 +1,94 @@
+//! All available _lang -> shades_ importers.
+//!
+//! Importers parse shaders written in a shading language into [`Shader`](crate::Shader)s, so that existing shaders can
+//! go through the passes and writers of this crate. They are available with the `glsl-import` feature.
+pub mod glsl;
+
+use crate::validation::Diagnostic;
+use std::fmt;
+
+/// Errors that can occur when importing a shader.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum ImportError {
+  /// The source is malformed, or refers to something it doesn’t declare.
+  Syntax {
+    /// Line of the error, starting from 1.
+    line: usize,
+
+    /// Column of the error, starting from 1.
+    column: usize,
+
+    /// Description of the error.
+    message: String,
+  },
+
+  /// The source uses constructs that shaders built with this crate cannot represent.
+  ///
+  /// All the unsupported constructs of the source are reported, in order. They are reported instead of the syntax errors
+  /// they might cause, such as the use of a macro whose `#define` is unsupported.
+  Unsupported(Vec<Unsupported>),
+
+  /// The imported shader is invalid.
+  ///
+  /// Those are the diagnostics reported by [`Shader::validate`](crate::Shader::validate).
+  InvalidShader(Vec<Diagnostic>),
+}
+
+impl fmt::Display for ImportError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ImportError::Syntax {
+        line,
+        column,
+        message,
+      } => write!(f, "{}:{}: {}", line, column, message),
+
+      ImportError::Unsupported(constructs) => {
+        f.write_str("unsupported constructs")?;
+
+        for construct in constructs {
+          write!(f, "\n  {}", construct)?;
+        }
+
+        Ok(())
+      }
+
+      ImportError::InvalidShader(diagnostics) => {
+        f.write_str("invalid shader")?;
+
+        for diagnostic in diagnostics {
+          write!(f, "\n  {}", diagnostic)?;
+        }
+
+        Ok(())
+      }
+    }
+  }
+}
+
+impl std::error::Error for ImportError {}
+
+/// A construct of the source that shaders built with this crate cannot represent.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub struct Unsupported {
+  /// Line of the construct, starting from 1.
+  pub line: usize,
+
+  /// Column of the construct, starting from 1.
+  pub column: usize,
+
+  /// Description of the construct, such as “`switch` statement”.
+  pub construct: String,
+}
+
+impl fmt::Display for Unsupported {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "{}:{}: {} is not supported",
+      self.line, self.column, self.construct
+    )
+  }
+}
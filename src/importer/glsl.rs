@@ -0,0 +1,2521 @@
+//! GLSL importer.
+//!
+//! [`import_shader`] parses the GLSL source of a shader stage into a [`Shader`]. Only the subset of GLSL that shaders
+//! built with this crate can represent is supported:
+//!
+//! - Inputs, outputs and uniforms of primitive types and arrays, along with the `location` and `index` layout
+//!   qualifiers of fragment shader outputs and the `flat` qualifier of integer inputs and outputs.
+//! - Constants, functions, function prototypes and the `main` function. Non-`void` functions must end with a `return`
+//!   statement.
+//! - Variable declarations, assignments (including compound assignments and increments), `if`, `for` and `while`
+//!   statements, `return`, `break`, `continue` and `discard`.
+//! - Operators, swizzles, array indexing, calls to functions and built-in functions, and built-in variables of the
+//!   stage. Integer literals are converted to floating-point or unsigned literals where GLSL implicitly converts them.
+//!
+//! Other constructs, such as structures, samplers, `switch` statements, the `?:` operator or most preprocessor
+//! directives, are reported as [`Unsupported`], all at once, so that a shader can be ported piece by piece. The
+//! `#version`, `#extension` and `#pragma` directives and precision qualifiers are ignored.
+//!
+//! The parser only recognizes that subset, rather than building a full GLSL syntax tree with the [glsl] crate: it keeps
+//! the crate free of mandatory dependencies, and anything it doesn’t recognize is reported with its position instead
+//! of being parsed only to be rejected afterwards.
+//!
+//! [glsl]: https://crates.io/crates/glsl
+//!
+//! # Examples
+//!
+//! ```
+//! use shades::{ShaderStage, importer::glsl::import_shader, writer::glsl::write_shader_to_str};
+//!
+//! let shader = import_shader(
+//!   r#"
+//!   #version 330 core
+//!
+//!   in vec3 position;
+//!   uniform float scale;
+//!
+//!   void main() {
+//!     gl_Position = vec4(position * scale, 1);
+//!   }
+//!   "#,
+//!   ShaderStage::Vertex,
+//! )
+//! .unwrap();
+//!
+//! let output = write_shader_to_str(&shader).unwrap();
+//! assert!(output.contains("gl_Position = vec4((position * scale), 1.);"));
+//! ```
+
+use crate::{
+  importer::{ImportError, Unsupported},
+  typing::TypeEnv,
+  BuiltIn, ColorAttachment, Dim, ErasedExpr, ErasedFun, ErasedFunHandle, ErasedReturn, ErasedScope,
+  FragmentBuiltIn, GeometryBuiltIn, Matrix, MatrixDim, PrimType, ScopeInstr, ScopedHandle, Shader,
+  ShaderBuilder, ShaderDecl, ShaderStage, Swizzle, SwizzleSelector, TessCtrlBuiltIn,
+  TessEvalBuiltIn, Type, VertexBuiltIn,
+};
+use std::{
+  collections::{HashMap, HashSet},
+  sync::Arc,
+};
+
+/// Import the GLSL source of a shader stage.
+///
+/// # Errors
+///
+/// [`ImportError::Unsupported`] is returned for all the constructs that cannot be imported, and
+/// [`ImportError::Syntax`] for the first syntax error of a source without any. The imported shader is validated, and [`ImportError::InvalidShader`] is
+/// returned if it is invalid.
+pub fn import_shader(source: &str, stage: ShaderStage) -> Result<Shader, ImportError> {
+  let mut unsupported = Vec::new();
+  let tokens = match lex(source, &mut unsupported) {
+    Ok(tokens) => tokens,
+    Err(_) if !unsupported.is_empty() => return Err(ImportError::Unsupported(unsupported)),
+    Err(e) => return Err(e),
+  };
+
+  let mut parser = Parser {
+    tokens,
+    pos: 0,
+    stage,
+    builder: ShaderBuilder::new(stage),
+    globals: HashMap::new(),
+    prototypes: HashSet::new(),
+    unsupported,
+    fun: FunContext::default(),
+  };
+  let parsed = parser.parse_translation_unit();
+
+  // syntax errors following unsupported constructs are most likely caused by them, as with an unsupported macro
+  if !parser.unsupported.is_empty() {
+    return Err(ImportError::Unsupported(parser.unsupported));
+  }
+  parsed?;
+
+  if !matches!(parser.globals.get("main"), Some(Global::Main)) {
+    return Err(parser.error("missing `main` function"));
+  }
+
+  let shader = parser.builder.into_shader();
+  let diagnostics = shader.validate();
+
+  if diagnostics.is_empty() {
+    Ok(shader)
+  } else {
+    Err(ImportError::InvalidShader(diagnostics))
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+  Ident(String),
+  Int(u32),
+  UInt(u32),
+  Float(f32),
+  Punct(&'static str),
+  Eof,
+}
+
+impl Token {
+  fn describe(&self) -> String {
+    match self {
+      Token::Ident(ident) => format!("`{}`", ident),
+      Token::Int(x) | Token::UInt(x) => format!("`{}`", x),
+      Token::Float(x) => format!("`{}`", x),
+      Token::Punct(punct) => format!("`{}`", punct),
+      Token::Eof => "the end of the source".to_owned(),
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+struct Spanned {
+  token: Token,
+  line: usize,
+  column: usize,
+}
+
+// longest first, so that the first match is the right one
+const PUNCTUATION: &[&str] = &[
+  "<<=", ">>=", "++", "--", "<=", ">=", "==", "!=", "&&", "||", "^^", "+=", "-=", "*=", "/=", "%=",
+  "&=", "|=", "^=", "<<", ">>", "+", "-", "*", "/", "%", "<", ">", "=", "!", "&", "|", "^", "~",
+  "?", ":", ";", ",", ".", "(", ")", "{", "}", "[", "]",
+];
+
+const ASSIGNMENT_OPS: &[&str] = &[
+  "=", "+=", "-=", "*=", "/=", "%=", "&=", "|=", "^=", "<<=", ">>=",
+];
+
+// binary operators, from the lowest precedence to the highest
+const BINARY_OPS: &[&[&str]] = &[
+  &["||"],
+  &["^^"],
+  &["&&"],
+  &["|"],
+  &["^"],
+  &["&"],
+  &["==", "!="],
+  &["<", ">", "<=", ">="],
+  &["<<", ">>"],
+  &["+", "-"],
+  &["*", "/", "%"],
+];
+
+fn syntax_error(line: usize, column: usize, message: impl Into<String>) -> ImportError {
+  ImportError::Syntax {
+    line,
+    column,
+    message: message.into(),
+  }
+}
+
+fn lex(source: &str, unsupported: &mut Vec<Unsupported>) -> Result<Vec<Spanned>, ImportError> {
+  let bytes = source.as_bytes();
+  let mut tokens = Vec::new();
+  let mut i = 0;
+  let mut line = 1;
+  let mut column = 1;
+  // whether only whitespace was found since the start of the line, for preprocessor directives
+  let mut line_start = true;
+
+  while i < bytes.len() {
+    let c = bytes[i];
+
+    if c == b'\n' {
+      i += 1;
+      line += 1;
+      column = 1;
+      line_start = true;
+      continue;
+    }
+
+    if c.is_ascii_whitespace() {
+      i += 1;
+      column += 1;
+      continue;
+    }
+
+    if bytes[i..].starts_with(b"//") {
+      while i < bytes.len() && bytes[i] != b'\n' {
+        i += 1;
+      }
+
+      continue;
+    }
+
+    if bytes[i..].starts_with(b"/*") {
+      let (start_line, start_column) = (line, column);
+      i += 2;
+      column += 2;
+
+      loop {
+        if i >= bytes.len() {
+          return Err(syntax_error(
+            start_line,
+            start_column,
+            "unterminated comment",
+          ));
+        }
+
+        if bytes[i..].starts_with(b"*/") {
+          i += 2;
+          column += 2;
+          break;
+        }
+
+        if bytes[i] == b'\n' {
+          line += 1;
+          column = 1;
+        } else {
+          column += 1;
+        }
+
+        i += 1;
+      }
+
+      continue;
+    }
+
+    if c == b'#' && line_start {
+      let start = i;
+      while i < bytes.len() && bytes[i] != b'\n' {
+        i += 1;
+      }
+
+      let directive = source[start + 1..i]
+        .trim_start()
+        .split(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .next()
+        .unwrap_or_default();
+
+      if !matches!(directive, "" | "version" | "extension" | "pragma") {
+        unsupported.push(Unsupported {
+          line,
+          column,
+          construct: format!("preprocessor directive `#{}`", directive),
+        });
+      }
+
+      continue;
+    }
+
+    line_start = false;
+    let start = i;
+
+    let token = if c.is_ascii_alphabetic() || c == b'_' {
+      while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+        i += 1;
+      }
+
+      Token::Ident(source[start..i].to_owned())
+    } else if c.is_ascii_digit()
+      || (c == b'.' && bytes.get(i + 1).map(u8::is_ascii_digit) == Some(true))
+    {
+      let (token, len) = lex_number(&bytes[i..])
+        .ok_or_else(|| syntax_error(line, column, "invalid number literal"))?;
+      i += len;
+      token
+    } else {
+      let punct = PUNCTUATION
+        .iter()
+        .find(|punct| bytes[i..].starts_with(punct.as_bytes()))
+        .ok_or_else(|| {
+          let c = source[i..].chars().next().unwrap_or_default();
+          syntax_error(line, column, format!("unexpected character `{}`", c))
+        })?;
+      i += punct.len();
+      Token::Punct(punct)
+    };
+
+    tokens.push(Spanned {
+      token,
+      line,
+      column,
+    });
+    column += i - start;
+  }
+
+  tokens.push(Spanned {
+    token: Token::Eof,
+    line,
+    column,
+  });
+
+  Ok(tokens)
+}
+
+// Lex a number literal, returning it along with its length.
+fn lex_number(bytes: &[u8]) -> Option<(Token, usize)> {
+  let digits = |from: usize, radix: u32| {
+    bytes[from..]
+      .iter()
+      .take_while(|c| (**c as char).is_digit(radix))
+      .count()
+  };
+  let text = |len: usize| std::str::from_utf8(&bytes[..len]).ok();
+  let is_suffix =
+    |len: usize, suffixes: &[u8]| bytes.get(len).map(|c| suffixes.contains(c)) == Some(true);
+
+  // hexadecimal integers
+  if bytes.starts_with(b"0x") || bytes.starts_with(b"0X") {
+    let len = 2 + digits(2, 16);
+    let x = u32::from_str_radix(text(len)?.get(2..)?, 16).ok()?;
+
+    return if is_suffix(len, b"uU") {
+      Some((Token::UInt(x), len + 1))
+    } else {
+      Some((Token::Int(x), len))
+    };
+  }
+
+  let mut len = digits(0, 10);
+  let mut is_float = false;
+
+  if bytes.get(len) == Some(&b'.') {
+    is_float = true;
+    len += 1 + digits(len + 1, 10);
+  }
+
+  if is_suffix(len, b"eE") {
+    let sign = usize::from(is_suffix(len + 1, b"+-"));
+    let exponent = digits(len + 1 + sign, 10);
+
+    if exponent > 0 {
+      is_float = true;
+      len += 1 + sign + exponent;
+    }
+  }
+
+  if is_float {
+    let x = text(len)?.parse().ok()?;
+    let suffix = usize::from(is_suffix(len, b"fF"));
+    return Some((Token::Float(x), len + suffix));
+  }
+
+  let digits = text(len)?;
+  let x = if digits.len() > 1 && digits.starts_with('0') {
+    u32::from_str_radix(&digits[1..], 8).ok()?
+  } else {
+    digits.parse().ok()?
+  };
+
+  if is_suffix(len, b"uU") {
+    Some((Token::UInt(x), len + 1))
+  } else {
+    Some((Token::Int(x), len))
+  }
+}
+
+/// Something declared at the top level of the source.
+#[derive(Clone, Debug)]
+enum Global {
+  Main,
+  Fun { handle: u16, args: Vec<Type> },
+  Const(u16),
+  Interface(ScopedHandle),
+}
+
+/// Storage qualifier of a global declaration.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Storage {
+  In,
+  Out,
+  Uniform,
+  Attribute,
+  Varying,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Qualifiers {
+  storage: Option<Storage>,
+  constant: bool,
+  flat: bool,
+  location: Option<u32>,
+  index: Option<u32>,
+}
+
+/// Context of the function being parsed.
+#[derive(Debug, Default)]
+struct FunContext {
+  args: Vec<String>,
+  // variables declared by the enclosing blocks, innermost last
+  frames: Vec<HashMap<String, ScopedHandle>>,
+  ret: Option<Type>,
+  env: TypeEnv,
+}
+
+struct Parser {
+  tokens: Vec<Spanned>,
+  pos: usize,
+  stage: ShaderStage,
+  builder: ShaderBuilder,
+  globals: HashMap<String, Global>,
+  // functions declared by a prototype but not defined yet
+  prototypes: HashSet<String>,
+  unsupported: Vec<Unsupported>,
+  fun: FunContext,
+}
+
+impl Parser {
+  fn peek(&self) -> &Token {
+    self.peek_at(0)
+  }
+
+  fn peek_at(&self, offset: usize) -> &Token {
+    let i = (self.pos + offset).min(self.tokens.len() - 1);
+    &self.tokens[i].token
+  }
+
+  fn peek_ident(&self) -> Option<&str> {
+    match self.peek() {
+      Token::Ident(ident) => Some(ident),
+      _ => None,
+    }
+  }
+
+  fn position(&self) -> (usize, usize) {
+    let spanned = &self.tokens[self.pos];
+    (spanned.line, spanned.column)
+  }
+
+  fn next(&mut self) -> Token {
+    let token = self.peek().clone();
+
+    if self.pos < self.tokens.len() - 1 {
+      self.pos += 1;
+    }
+
+    token
+  }
+
+  fn accept(&mut self, punct: &str) -> bool {
+    if matches!(self.peek(), Token::Punct(p) if *p == punct) {
+      self.next();
+      true
+    } else {
+      false
+    }
+  }
+
+  fn accept_any(&mut self, puncts: &[&'static str]) -> Option<&'static str> {
+    let punct = puncts
+      .iter()
+      .copied()
+      .find(|punct| matches!(self.peek(), Token::Punct(p) if p == punct))?;
+    self.next();
+    Some(punct)
+  }
+
+  fn accept_keyword(&mut self, keyword: &str) -> bool {
+    if self.peek_ident() == Some(keyword) {
+      self.next();
+      true
+    } else {
+      false
+    }
+  }
+
+  fn expect(&mut self, punct: &str) -> Result<(), ImportError> {
+    if self.accept(punct) {
+      Ok(())
+    } else {
+      Err(self.error(format!(
+        "expected `{}`, found {}",
+        punct,
+        self.peek().describe()
+      )))
+    }
+  }
+
+  fn expect_keyword(&mut self, keyword: &str) -> Result<(), ImportError> {
+    if self.accept_keyword(keyword) {
+      Ok(())
+    } else {
+      Err(self.error(format!(
+        "expected `{}`, found {}",
+        keyword,
+        self.peek().describe()
+      )))
+    }
+  }
+
+  fn expect_ident(&mut self) -> Result<String, ImportError> {
+    match self.next() {
+      Token::Ident(ident) => Ok(ident),
+      token => {
+        self.pos -= 1;
+        Err(self.error(format!(
+          "expected an identifier, found {}",
+          token.describe()
+        )))
+      }
+    }
+  }
+
+  fn expect_int(&mut self) -> Result<u32, ImportError> {
+    match self.next() {
+      Token::Int(x) | Token::UInt(x) => Ok(x),
+      token => {
+        self.pos -= 1;
+        Err(self.error(format!(
+          "expected an integer literal, found {}",
+          token.describe()
+        )))
+      }
+    }
+  }
+
+  fn error(&self, message: impl Into<String>) -> ImportError {
+    self.error_at(self.position(), message)
+  }
+
+  fn error_at(&self, (line, column): (usize, usize), message: impl Into<String>) -> ImportError {
+    syntax_error(line, column, message)
+  }
+
+  fn unsupported(&mut self, (line, column): (usize, usize), construct: impl Into<String>) {
+    self.unsupported.push(Unsupported {
+      line,
+      column,
+      construct: construct.into(),
+    });
+  }
+
+  // Skip tokens up to the end of the current declaration or statement, including braced blocks.
+  fn skip_declaration(&mut self) -> Result<(), ImportError> {
+    let mut depth = 0usize;
+
+    loop {
+      match self.next() {
+        Token::Punct("{") => depth += 1,
+        Token::Punct("}") => depth = depth.saturating_sub(1),
+        Token::Punct(";") if depth == 0 => return Ok(()),
+        Token::Eof => return Err(self.error("expected `;`")),
+        _ => (),
+      }
+    }
+  }
+
+  fn parse_translation_unit(&mut self) -> Result<(), ImportError> {
+    while *self.peek() != Token::Eof {
+      if !self.accept(";") {
+        self.parse_external_declaration()?;
+      }
+    }
+
+    Ok(())
+  }
+
+  fn parse_external_declaration(&mut self) -> Result<(), ImportError> {
+    let pos = self.position();
+
+    self.fun = FunContext {
+      env: TypeEnv::new(&self.builder.decls),
+      ..FunContext::default()
+    };
+
+    // precision statements don’t change the meaning of a shader
+    if self.accept_keyword("precision") {
+      return self.skip_declaration();
+    }
+
+    let qualifiers = self.parse_qualifiers()?;
+
+    if self.accept_keyword("struct") {
+      self.unsupported(pos, "structure");
+      return self.skip_declaration();
+    }
+
+    if let Some(ident) = self.peek_ident() {
+      if *self.peek_at(1) == Token::Punct("{") && !is_type_name(ident) {
+        self.unsupported(pos, "interface block");
+        return self.skip_declaration();
+      }
+    }
+
+    let ty_pos = self.position();
+    let ty = self.parse_type()?;
+    let mut name_pos = self.position();
+    let mut name = self.expect_ident()?;
+
+    if *self.peek() == Token::Punct("(") {
+      if qualifiers.storage.is_some() || qualifiers.constant {
+        self.unsupported(pos, "qualified function");
+      }
+
+      return self.parse_function(name_pos, ty, name);
+    }
+
+    let ty = ty.ok_or_else(|| self.error_at(ty_pos, "variables cannot be `void`"))?;
+
+    loop {
+      self.parse_global(&qualifiers, ty.clone(), name_pos, name)?;
+
+      if !self.accept(",") {
+        break;
+      }
+
+      name_pos = self.position();
+      name = self.expect_ident()?;
+    }
+
+    self.expect(";")
+  }
+
+  fn parse_qualifiers(&mut self) -> Result<Qualifiers, ImportError> {
+    let mut qualifiers = Qualifiers::default();
+
+    loop {
+      let pos = self.position();
+      let storage = match self.peek_ident() {
+        Some("const") => {
+          qualifiers.constant = true;
+          None
+        }
+
+        // integers are never interpolated, and the GLSL writer qualifies integer inputs and outputs `flat`
+        Some("flat") => {
+          qualifiers.flat = true;
+          None
+        }
+
+        Some("in") => Some(Storage::In),
+        Some("out") => Some(Storage::Out),
+        Some("uniform") => Some(Storage::Uniform),
+        Some("attribute") => Some(Storage::Attribute),
+        Some("varying") => Some(Storage::Varying),
+
+        // precision qualifiers don’t change the meaning of a shader
+        Some("highp") | Some("mediump") | Some("lowp") => None,
+
+        Some("layout") => {
+          self.next();
+          self.parse_layout(&mut qualifiers)?;
+          continue;
+        }
+
+        Some(
+          qualifier @ ("inout" | "buffer" | "shared" | "patch" | "sample" | "centroid" | "smooth"
+          | "noperspective" | "invariant" | "precise" | "coherent" | "volatile"
+          | "restrict" | "readonly" | "writeonly"),
+        ) => {
+          let construct = format!("qualifier `{}`", qualifier);
+          self.unsupported(pos, construct);
+          None
+        }
+
+        _ => return Ok(qualifiers),
+      };
+
+      self.next();
+
+      if storage.is_some() {
+        qualifiers.storage = storage;
+      }
+    }
+  }
+
+  fn parse_layout(&mut self, qualifiers: &mut Qualifiers) -> Result<(), ImportError> {
+    self.expect("(")?;
+
+    loop {
+      let pos = self.position();
+      let key = self.expect_ident()?;
+      let value = if self.accept("=") {
+        Some(self.expect_int()?)
+      } else {
+        None
+      };
+
+      match (key.as_str(), value) {
+        ("location", Some(location)) => qualifiers.location = Some(location),
+        ("index", Some(index)) => qualifiers.index = Some(index),
+        _ => self.unsupported(pos, format!("layout qualifier `{}`", key)),
+      }
+
+      if !self.accept(",") {
+        break;
+      }
+    }
+
+    self.expect(")")
+  }
+
+  // Parse a type, or `void`.
+  fn parse_type(&mut self) -> Result<Option<Type>, ImportError> {
+    let pos = self.position();
+    let name = self.expect_ident()?;
+
+    let prim_ty = match prim_type(&name) {
+      Some(prim_ty) => prim_ty,
+      None if name == "void" => return Ok(None),
+      None if is_unsupported_type(&name) => {
+        self.unsupported(pos, format!("type `{}`", name));
+        PrimType::Float(Dim::Scalar)
+      }
+      None => return Err(self.error_at(pos, format!("unknown type `{}`", name))),
+    };
+
+    Ok(Some(Type {
+      prim_ty,
+      array_dims: self.parse_array_dims()?,
+    }))
+  }
+
+  fn parse_array_dims(&mut self) -> Result<Vec<usize>, ImportError> {
+    let mut dims = Vec::new();
+
+    while self.accept("[") {
+      if self.accept("]") {
+        dims.push(0);
+        continue;
+      }
+
+      dims.push(self.expect_int()? as usize);
+      self.expect("]")?;
+    }
+
+    Ok(dims)
+  }
+
+  // Array dimensions following the name of a declaration are the outermost ones: `float[2] a[3]` is an array of three
+  // arrays of two floats.
+  fn parse_declarator_dims(&mut self, ty: &mut Type) -> Result<(), ImportError> {
+    let dims = self.parse_array_dims()?;
+    ty.array_dims.splice(0..0, dims);
+    Ok(())
+  }
+
+  fn parse_global(
+    &mut self,
+    qualifiers: &Qualifiers,
+    mut ty: Type,
+    pos: (usize, usize),
+    name: String,
+  ) -> Result<(), ImportError> {
+    self.parse_declarator_dims(&mut ty)?;
+
+    let init_pos = self.position();
+    let init = if self.accept("=") {
+      Some(coerce(self.parse_expr()?, &ty))
+    } else {
+      None
+    };
+
+    if self.globals.contains_key(&name) {
+      return Err(self.error_at(pos, format!("`{}` is already declared", name)));
+    }
+
+    let is_integer = matches!(ty.prim_ty, PrimType::Int(_) | PrimType::UInt(_));
+    if qualifiers.flat && (qualifiers.storage.is_none() || !is_integer) {
+      self.unsupported(pos, "qualifier `flat`");
+    }
+
+    let storage = match qualifiers.storage {
+      Some(storage) => storage,
+
+      None => {
+        let handle = self.builder.next_global_handle;
+        self.builder.next_global_handle += 1;
+
+        match init {
+          Some(value) if qualifiers.constant => {
+            let decl = ShaderDecl::Const(handle, ty, value, Some(name.clone()));
+            self.builder.push_decl(decl);
+          }
+
+          Some(_) => self.unsupported(pos, "global variable"),
+
+          None => {
+            return Err(self.error_at(pos, format!("constant `{}` must be initialized", name)))
+          }
+        }
+
+        self.globals.insert(name, Global::Const(handle));
+        return Ok(());
+      }
+    };
+
+    if init.is_some() {
+      self.unsupported(init_pos, "initializer of an interface variable");
+    }
+
+    let decl = match (storage, self.stage) {
+      (Storage::In, _)
+      | (Storage::Attribute, ShaderStage::Vertex)
+      | (Storage::Varying, ShaderStage::Fragment) => {
+        if qualifiers.location.is_some() {
+          self.unsupported(pos, "input location");
+        }
+
+        ShaderDecl::In(name.clone(), ty)
+      }
+
+      (Storage::Out, _) | (Storage::Varying, ShaderStage::Vertex) => {
+        let attachment = match (qualifiers.location, qualifiers.index) {
+          (Some(location), index) if self.stage == ShaderStage::Fragment => {
+            Some(ColorAttachment::new(location).with_index(index.unwrap_or(0)))
+          }
+
+          (None, None) => None,
+
+          _ => {
+            self.unsupported(pos, "output location");
+            None
+          }
+        };
+
+        ShaderDecl::Out(name.clone(), ty, attachment)
+      }
+
+      (Storage::Uniform, _) => {
+        if qualifiers.location.is_some() {
+          self.unsupported(pos, "uniform location");
+        }
+
+        ShaderDecl::Uniform(name.clone(), ty)
+      }
+
+      (storage, _) => {
+        let construct = format!("{:?} qualifier in a {} shader", storage, self.stage);
+        self.unsupported(pos, construct.to_lowercase());
+        return Ok(());
+      }
+    };
+
+    let handle = match &decl {
+      ShaderDecl::In(..) => ScopedHandle::Input(name.clone()),
+      ShaderDecl::Out(..) => ScopedHandle::Output(name.clone()),
+      _ => ScopedHandle::Uniform(name.clone()),
+    };
+
+    self.builder.decls.push(decl);
+    self.globals.insert(name, Global::Interface(handle));
+    Ok(())
+  }
+
+  fn parse_function(
+    &mut self,
+    pos: (usize, usize),
+    ret: Option<Type>,
+    name: String,
+  ) -> Result<(), ImportError> {
+    self.expect("(")?;
+
+    let mut args = Vec::new();
+    let mut arg_names = Vec::new();
+
+    if self.peek_ident() == Some("void") && *self.peek_at(1) == Token::Punct(")") {
+      self.next();
+    }
+
+    if !self.accept(")") {
+      loop {
+        loop {
+          let pos = self.position();
+
+          match self.peek_ident() {
+            Some("in") | Some("const") | Some("highp") | Some("mediump") | Some("lowp") => (),
+
+            Some(qualifier @ ("out" | "inout")) => {
+              let construct = format!("`{}` parameter", qualifier);
+              self.unsupported(pos, construct);
+            }
+
+            _ => break,
+          }
+
+          self.next();
+        }
+
+        let ty_pos = self.position();
+        let mut ty = self
+          .parse_type()?
+          .ok_or_else(|| self.error_at(ty_pos, "parameters cannot be `void`"))?;
+
+        // prototypes can omit the names of their parameters
+        if let Token::Ident(_) = self.peek() {
+          arg_names.push(self.expect_ident()?);
+        }
+
+        self.parse_declarator_dims(&mut ty)?;
+        args.push(ty);
+
+        if !self.accept(",") {
+          break;
+        }
+      }
+
+      self.expect(")")?;
+    }
+
+    if self.accept(";") {
+      // calls before the definition refer to the handle reserved by the prototype
+      if name != "main" && !self.globals.contains_key(&name) {
+        let handle = self.builder.next_fun_handle;
+        self.builder.next_fun_handle += 1;
+        self.prototypes.insert(name.clone());
+        self.globals.insert(name, Global::Fun { handle, args });
+      }
+
+      return Ok(());
+    }
+
+    if arg_names.len() != args.len() {
+      return Err(self.error_at(pos, format!("unnamed parameter of `{}`", name)));
+    }
+
+    let prototype = match self.globals.get(&name) {
+      Some(Global::Fun { handle, args: tys })
+        if self.prototypes.contains(&name) && *tys == args =>
+      {
+        Some(*handle)
+      }
+      Some(Global::Fun { .. }) => {
+        self.unsupported(pos, format!("overloaded function `{}`", name));
+        None
+      }
+      Some(_) => return Err(self.error_at(pos, format!("`{}` is already declared", name))),
+      None => None,
+    };
+
+    // the function is declared before its body, so that recursive calls are found by the validation
+    let handle = if name == "main" {
+      None
+    } else {
+      let handle = prototype.unwrap_or_else(|| {
+        let handle = self.builder.next_fun_handle;
+        self.builder.next_fun_handle += 1;
+        handle
+      });
+
+      self.prototypes.remove(&name);
+      let global = Global::Fun {
+        handle,
+        args: args.clone(),
+      };
+      self.globals.insert(name.clone(), global);
+      Some(handle)
+    };
+
+    self.fun.args = arg_names.clone();
+    self.fun.frames = vec![HashMap::new()];
+    self.fun.ret = ret.clone();
+    self.fun.env = self.fun.env.with_args(&args);
+
+    let mut scope = self.new_scope();
+    self.expect("{")?;
+    self.parse_statements(&mut scope)?;
+
+    let fun_ret = match ret {
+      None => {
+        if let Some(ScopeInstr::Return(ErasedReturn::Void)) = scope.instructions.last() {
+          scope.instructions.pop();
+        }
+
+        ErasedReturn::Void
+      }
+
+      Some(ty) => match scope.instructions.pop() {
+        Some(ScopeInstr::Return(ret @ ErasedReturn::Expr(..))) => ret,
+
+        last => {
+          scope.instructions.extend(last);
+          let construct = format!("function `{}` not ending with a `return` statement", name);
+          self.unsupported(pos, construct);
+          ErasedReturn::Expr(ty, placeholder())
+        }
+      },
+    };
+
+    let mut fun = ErasedFun::new(args.clone(), scope, fun_ret);
+
+    match handle {
+      None => {
+        if !args.is_empty() || !matches!(fun.ret, ErasedReturn::Void) {
+          return Err(self.error_at(pos, "`main` must take no argument and return nothing"));
+        }
+
+        self.builder.push_decl(ShaderDecl::Main(fun));
+        self.globals.insert(name, Global::Main);
+      }
+
+      Some(handle) => {
+        fun.name = Some(name);
+        fun.arg_names = arg_names;
+        self.builder.push_decl(ShaderDecl::FunDef(handle, fun));
+      }
+    }
+
+    Ok(())
+  }
+
+  // Parse statements up to the closing brace of the current block.
+  fn parse_statements(&mut self, scope: &mut ErasedScope) -> Result<(), ImportError> {
+    while !self.accept("}") {
+      if *self.peek() == Token::Eof {
+        return Err(self.error("expected `}`"));
+      }
+
+      self.parse_statement(scope)?;
+    }
+
+    Ok(())
+  }
+
+  // Every scope gets its own identifier from the builder, as with the EDSL.
+  fn new_scope(&mut self) -> ErasedScope {
+    let id = self.builder.next_scope;
+    self.builder.next_scope = id.wrapping_add(1);
+    ErasedScope::new(id)
+  }
+
+  // Parse the body of a conditional or a loop into its own scope.
+  fn parse_body(
+    &mut self,
+    mut scope: ErasedScope,
+    frame: HashMap<String, ScopedHandle>,
+  ) -> Result<ErasedScope, ImportError> {
+    self.fun.frames.push(frame);
+
+    if self.accept("{") {
+      self.parse_statements(&mut scope)?;
+    } else {
+      self.parse_statement(&mut scope)?;
+    }
+
+    self.fun.frames.pop();
+    Ok(scope)
+  }
+
+  fn parse_statement(&mut self, scope: &mut ErasedScope) -> Result<(), ImportError> {
+    let pos = self.position();
+
+    if self.accept(";") {
+      return Ok(());
+    }
+
+    // blocks don’t have a scope of their own: their variables are declared in the enclosing scope
+    if self.accept("{") {
+      self.fun.frames.push(HashMap::new());
+      self.parse_statements(scope)?;
+      self.fun.frames.pop();
+      return Ok(());
+    }
+
+    let keyword = self.peek_ident().unwrap_or_default().to_owned();
+
+    match keyword.as_str() {
+      "if" => {
+        self.next();
+        let condition = self.parse_condition()?;
+        let body = self.new_scope();
+        let body = self.parse_body(body, HashMap::new())?;
+        scope.instructions.push(ScopeInstr::If {
+          condition,
+          scope: body,
+        });
+
+        while self.accept_keyword("else") {
+          if self.accept_keyword("if") {
+            let condition = self.parse_condition()?;
+            let body = self.new_scope();
+            let body = self.parse_body(body, HashMap::new())?;
+            scope.instructions.push(ScopeInstr::ElseIf {
+              condition,
+              scope: body,
+            });
+          } else {
+            let body = self.new_scope();
+            let body = self.parse_body(body, HashMap::new())?;
+            scope.instructions.push(ScopeInstr::Else { scope: body });
+            break;
+          }
+        }
+      }
+
+      "for" => {
+        self.next();
+        self.parse_for(scope)?;
+      }
+
+      "while" => {
+        self.next();
+        let condition = self.parse_condition()?;
+        let body = self.new_scope();
+        let body = self.parse_body(body, HashMap::new())?;
+        scope.instructions.push(ScopeInstr::While {
+          condition,
+          scope: body,
+        });
+      }
+
+      "do" => {
+        self.next();
+        self.unsupported(pos, "`do`-`while` loop");
+        let body = self.new_scope();
+        self.parse_body(body, HashMap::new())?;
+        self.expect_keyword("while")?;
+        self.parse_condition()?;
+        self.expect(";")?;
+      }
+
+      "switch" => {
+        self.next();
+        self.unsupported(pos, "`switch` statement");
+        self.parse_condition()?;
+        self.expect("{")?;
+
+        let mut depth = 1;
+        while depth > 0 {
+          match self.next() {
+            Token::Punct("{") => depth += 1,
+            Token::Punct("}") => depth -= 1,
+            Token::Eof => return Err(self.error("expected `}`")),
+            _ => (),
+          }
+        }
+      }
+
+      "return" => {
+        self.next();
+
+        let ret = if self.accept(";") {
+          ErasedReturn::Void
+        } else {
+          let value = self.parse_expr()?;
+          self.expect(";")?;
+
+          match &self.fun.ret {
+            Some(ty) => ErasedReturn::Expr(ty.clone(), coerce(value, ty)),
+            None => return Err(self.error_at(pos, "`void` function returning a value")),
+          }
+        };
+
+        if matches!(ret, ErasedReturn::Void) && self.fun.ret.is_some() {
+          return Err(self.error_at(pos, "missing return value"));
+        }
+
+        scope.instructions.push(ScopeInstr::Return(ret));
+      }
+
+      "break" => {
+        self.next();
+        self.expect(";")?;
+        scope.instructions.push(ScopeInstr::Break);
+      }
+
+      "continue" => {
+        self.next();
+        self.expect(";")?;
+        scope.instructions.push(ScopeInstr::Continue);
+      }
+
+      "discard" => {
+        self.next();
+        self.expect(";")?;
+
+        if self.stage != ShaderStage::Fragment {
+          self.unsupported(pos, "`discard` outside of a fragment shader");
+        }
+
+        scope.instructions.push(ScopeInstr::Discard);
+      }
+
+      _ if self.is_declaration() => self.parse_declaration(scope)?,
+
+      _ => self.parse_expression_statement(scope)?,
+    }
+
+    Ok(())
+  }
+
+  fn parse_condition(&mut self) -> Result<ErasedExpr, ImportError> {
+    self.expect("(")?;
+    let condition = self.parse_expr()?;
+    self.expect(")")?;
+    Ok(condition)
+  }
+
+  // Whether a statement is a variable declaration.
+  fn is_declaration(&self) -> bool {
+    match self.peek_ident() {
+      Some("const") | Some("highp") | Some("mediump") | Some("lowp") => true,
+      Some(ident) if is_type_name(ident) => {
+        matches!(self.peek_at(1), Token::Ident(_) | Token::Punct("["))
+          && !matches!(self.peek_at(1), Token::Punct("[") if *self.peek_at(2) == Token::Punct("]"))
+      }
+      _ => false,
+    }
+  }
+
+  fn parse_declaration(&mut self, scope: &mut ErasedScope) -> Result<(), ImportError> {
+    // constness and precision don’t change the meaning of local variables
+    while let Some("const") | Some("highp") | Some("mediump") | Some("lowp") = self.peek_ident() {
+      self.next();
+    }
+
+    let ty_pos = self.position();
+    let ty = self
+      .parse_type()?
+      .ok_or_else(|| self.error_at(ty_pos, "variables cannot be `void`"))?;
+
+    loop {
+      let pos = self.position();
+      let name = self.expect_ident()?;
+      let mut ty = ty.clone();
+      self.parse_declarator_dims(&mut ty)?;
+
+      // variables always have a value in shaders built with this crate; making one up would change the meaning of
+      // the source
+      let init_value = if self.accept("=") {
+        coerce(self.parse_expr()?, &ty)
+      } else {
+        self.unsupported(pos, format!("uninitialized variable `{}`", name));
+        placeholder()
+      };
+
+      let handle = ScopedHandle::fun_var(scope.id, scope.next_var);
+      scope.names.insert(scope.next_var, name.clone());
+      scope.next_var += 1;
+      scope.instructions.push(ScopeInstr::VarDecl {
+        ty: ty.clone(),
+        handle: handle.clone(),
+        init_value,
+      });
+
+      self.fun.env.declare(&handle, &ty);
+      self.declare(name, handle);
+
+      if !self.accept(",") {
+        break;
+      }
+    }
+
+    self.expect(";")
+  }
+
+  fn declare(&mut self, name: String, handle: ScopedHandle) {
+    if let Some(frame) = self.fun.frames.last_mut() {
+      frame.insert(name, handle);
+    }
+  }
+
+  fn parse_for(&mut self, scope: &mut ErasedScope) -> Result<(), ImportError> {
+    let pos = self.position();
+    self.expect("(")?;
+
+    // the loop variable is declared in the scope of the body, before the body
+    let mut body = self.new_scope();
+    let init_handle = ScopedHandle::fun_var(body.id, body.next_var);
+    body.next_var += 1;
+
+    if !self.is_declaration() {
+      self.unsupported(pos, "`for` loop not declaring a variable");
+
+      let mut depth = 1;
+      while depth > 0 {
+        match self.next() {
+          Token::Punct("(") => depth += 1,
+          Token::Punct(")") => depth -= 1,
+          Token::Eof => return Err(self.error("expected `)`")),
+          _ => (),
+        }
+      }
+
+      self.parse_body(body, HashMap::new())?;
+      return Ok(());
+    }
+
+    while let Some("const") | Some("highp") | Some("mediump") | Some("lowp") = self.peek_ident() {
+      self.next();
+    }
+
+    let ty_pos = self.position();
+    let init_ty = self
+      .parse_type()?
+      .ok_or_else(|| self.error_at(ty_pos, "variables cannot be `void`"))?;
+    let name = self.expect_ident()?;
+    self.expect("=")?;
+    let init_expr = coerce(self.parse_expr()?, &init_ty);
+    self.expect(";")?;
+
+    self.fun.env.declare(&init_handle, &init_ty);
+    let mut frame = HashMap::new();
+    frame.insert(name, init_handle.clone());
+    self.fun.frames.push(frame);
+
+    let condition = if *self.peek() == Token::Punct(";") {
+      ErasedExpr::LitBool(true)
+    } else {
+      self.parse_expr()?
+    };
+    self.expect(";")?;
+
+    let post_expr = self.parse_for_step(&init_handle, &init_ty)?;
+    self.expect(")")?;
+
+    let frame = self.fun.frames.pop().unwrap_or_default();
+    let body = self.parse_body(body, frame)?;
+
+    scope.instructions.push(ScopeInstr::For {
+      init_ty,
+      init_handle,
+      init_expr,
+      condition,
+      post_expr,
+      scope: body,
+    });
+
+    Ok(())
+  }
+
+  // Parse the step of a for loop, as the next value of its variable.
+  fn parse_for_step(
+    &mut self,
+    init_handle: &ScopedHandle,
+    ty: &Type,
+  ) -> Result<ErasedExpr, ImportError> {
+    let pos = self.position();
+    let var = ErasedExpr::Var(init_handle.clone());
+
+    if *self.peek() == Token::Punct(")") {
+      return Ok(var);
+    }
+
+    let (target, next) = self.parse_update()?;
+
+    if target != var {
+      self.unsupported(pos, "`for` loop step not updating the loop variable");
+    }
+
+    Ok(coerce(next, ty))
+  }
+
+  // Parse an assignment, increment or decrement, returning the updated expression and its new value.
+  fn parse_update(&mut self) -> Result<(ErasedExpr, ErasedExpr), ImportError> {
+    let pos = self.position();
+
+    if let Some(op) = self.accept_any(&["++", "--"]) {
+      let target = self.parse_unary()?;
+      let next = self.increment(pos, op, target.clone());
+      return Ok((target, next));
+    }
+
+    let target = self.parse_conditional()?;
+    self.parse_update_of(pos, target)
+  }
+
+  // Parse the rest of an assignment or postfix increment or decrement of an already parsed expression.
+  fn parse_update_of(
+    &mut self,
+    pos: (usize, usize),
+    target: ErasedExpr,
+  ) -> Result<(ErasedExpr, ErasedExpr), ImportError> {
+    if let Some(op) = self.accept_any(&["++", "--"]) {
+      let next = self.increment(pos, op, target.clone());
+      return Ok((target, next));
+    }
+
+    let op = self.accept_any(ASSIGNMENT_OPS).ok_or_else(|| {
+      self.error(format!(
+        "expected an assignment, found {}",
+        self.peek().describe()
+      ))
+    })?;
+
+    let value = self.parse_expr()?;
+    let value = match self.fun.env.type_of(&target) {
+      Some(ty) => coerce(value, &ty),
+      None => value,
+    };
+
+    let next = match op {
+      "=" => value,
+      op => self.binary(&op[..op.len() - 1], target.clone(), value),
+    };
+
+    Ok((target, next))
+  }
+
+  fn increment(&mut self, pos: (usize, usize), op: &str, target: ErasedExpr) -> ErasedExpr {
+    let one = match self.fun.env.type_of(&target).map(|ty| ty.prim_ty) {
+      Some(PrimType::Int(Dim::Scalar)) => ErasedExpr::LitInt(1),
+      Some(PrimType::UInt(Dim::Scalar)) => ErasedExpr::LitUInt(1),
+      Some(PrimType::Float(Dim::Scalar)) => ErasedExpr::LitFloat(1.),
+      _ => {
+        self.unsupported(pos, format!("`{}` on a non-scalar", op));
+        placeholder()
+      }
+    };
+
+    self.binary(&op[..1], target, one)
+  }
+
+  fn parse_expression_statement(&mut self, scope: &mut ErasedScope) -> Result<(), ImportError> {
+    let pos = self.position();
+
+    let (var, expr) = if matches!(self.peek(), Token::Punct("++" | "--")) {
+      self.parse_update()?
+    } else {
+      let target = self.parse_conditional()?;
+
+      if self.accept(";") {
+        self.unsupported(pos, "expression statement");
+        return Ok(());
+      }
+
+      self.parse_update_of(pos, target)?
+    };
+    self.expect(";")?;
+
+    if !is_assignable(&var) {
+      return Err(self.error_at(pos, "cannot assign to this expression"));
+    }
+
+    scope.instructions.push(ScopeInstr::MutateVar { var, expr });
+    Ok(())
+  }
+
+  fn parse_expr(&mut self) -> Result<ErasedExpr, ImportError> {
+    let expr = self.parse_conditional()?;
+
+    // postfix increments and decrements are only parsed as statements
+    let pos = self.position();
+    match self.accept_any(&["++", "--"]) {
+      Some("++") => self.unsupported(pos, "postfix increment in expression"),
+      Some(_) => self.unsupported(pos, "postfix decrement in expression"),
+      None => (),
+    }
+
+    Ok(expr)
+  }
+
+  fn parse_conditional(&mut self) -> Result<ErasedExpr, ImportError> {
+    let pos = self.position();
+    let condition = self.parse_binary(0)?;
+
+    if self.accept("?") {
+      self.unsupported(pos, "conditional operator `?:`");
+      self.parse_expr()?;
+      self.expect(":")?;
+      self.parse_conditional()?;
+    }
+
+    Ok(condition)
+  }
+
+  fn parse_binary(&mut self, level: usize) -> Result<ErasedExpr, ImportError> {
+    let ops = match BINARY_OPS.get(level) {
+      Some(ops) => ops,
+      None => return self.parse_unary(),
+    };
+
+    let mut a = self.parse_binary(level + 1)?;
+
+    while let Some(op) = self.accept_any(ops) {
+      let b = self.parse_binary(level + 1)?;
+      a = self.binary(op, a, b);
+    }
+
+    Ok(a)
+  }
+
+  fn binary(&self, op: &str, a: ErasedExpr, b: ErasedExpr) -> ErasedExpr {
+    // integer literals are converted to the type of the other operand
+    let (a, b) = match (self.fun.env.type_of(&a), self.fun.env.type_of(&b)) {
+      (Some(ty), _) if is_int_literal(&b) => (a, coerce(b, &ty)),
+      (_, Some(ty)) if is_int_literal(&a) => (coerce(a, &ty), b),
+      _ => (a, b),
+    };
+    let (a, b) = (Arc::new(a), Arc::new(b));
+
+    match op {
+      "||" => ErasedExpr::Or(a, b),
+      "^^" => ErasedExpr::Xor(a, b),
+      "&&" => ErasedExpr::And(a, b),
+      "|" => ErasedExpr::BitOr(a, b),
+      "^" => ErasedExpr::BitXor(a, b),
+      "&" => ErasedExpr::BitAnd(a, b),
+      "==" => ErasedExpr::Eq(a, b),
+      "!=" => ErasedExpr::Neq(a, b),
+      "<" => ErasedExpr::Lt(a, b),
+      ">" => ErasedExpr::Gt(a, b),
+      "<=" => ErasedExpr::Lte(a, b),
+      ">=" => ErasedExpr::Gte(a, b),
+      "<<" => ErasedExpr::Shl(a, b),
+      ">>" => ErasedExpr::Shr(a, b),
+      "+" => ErasedExpr::Add(a, b),
+      "-" => ErasedExpr::Sub(a, b),
+      "*" => ErasedExpr::Mul(a, b),
+      "/" => ErasedExpr::Div(a, b),
+      _ => ErasedExpr::Rem(a, b),
+    }
+  }
+
+  fn parse_unary(&mut self) -> Result<ErasedExpr, ImportError> {
+    let pos = self.position();
+
+    if self.accept("-") {
+      return Ok(match self.parse_unary()? {
+        ErasedExpr::LitInt(x) => ErasedExpr::LitInt(x.wrapping_neg()),
+        ErasedExpr::LitFloat(x) => ErasedExpr::LitFloat(-x),
+        a => ErasedExpr::Neg(Arc::new(a)),
+      });
+    }
+
+    if self.accept("+") {
+      return self.parse_unary();
+    }
+
+    if self.accept("!") {
+      return Ok(ErasedExpr::Not(Arc::new(self.parse_unary()?)));
+    }
+
+    if self.accept("~") {
+      self.unsupported(pos, "bitwise complement `~`");
+      return self.parse_unary();
+    }
+
+    if self.accept_any(&["++", "--"]).is_some() {
+      self.unsupported(pos, "increment or decrement in an expression");
+      return self.parse_unary();
+    }
+
+    self.parse_postfix()
+  }
+
+  fn parse_postfix(&mut self) -> Result<ErasedExpr, ImportError> {
+    let mut expr = self.parse_primary()?;
+
+    loop {
+      let pos = self.position();
+
+      if self.accept("[") {
+        let index = self.parse_expr()?;
+        self.expect("]")?;
+        expr = ErasedExpr::ArrayLookup {
+          object: Arc::new(expr),
+          index: Arc::new(index),
+        };
+      } else if self.accept(".") {
+        let field = self.expect_ident()?;
+
+        if field.starts_with("gl_") {
+          // fields of per-vertex blocks, such as gl_in[i].gl_Position
+          let builtin = builtin(self.stage, &field)
+            .ok_or_else(|| self.error_at(pos, format!("unknown field `{}`", field)))?;
+          expr = ErasedExpr::Field {
+            object: Arc::new(expr),
+            field: Arc::new(ErasedExpr::new_builtin(builtin)),
+          };
+        } else if *self.peek() == Token::Punct("(") {
+          self.unsupported(pos, format!("method `{}`", field));
+          self.expect("(")?;
+          self.expect(")")?;
+        } else {
+          let swizzle = swizzle(&field)
+            .ok_or_else(|| self.error_at(pos, format!("invalid swizzle `{}`", field)))?;
+          expr = ErasedExpr::Swizzle(Arc::new(expr), swizzle);
+        }
+      } else {
+        return Ok(expr);
+      }
+    }
+  }
+
+  fn parse_primary(&mut self) -> Result<ErasedExpr, ImportError> {
+    let pos = self.position();
+
+    match self.next() {
+      Token::Int(x) => Ok(ErasedExpr::LitInt(x as i32)),
+      Token::UInt(x) => Ok(ErasedExpr::LitUInt(x)),
+      Token::Float(x) => Ok(ErasedExpr::LitFloat(x)),
+
+      Token::Punct("(") => {
+        let expr = self.parse_expr()?;
+        self.expect(")")?;
+        Ok(expr)
+      }
+
+      Token::Ident(ident) => match ident.as_str() {
+        "true" => Ok(ErasedExpr::LitBool(true)),
+        "false" => Ok(ErasedExpr::LitBool(false)),
+        _ if *self.peek() == Token::Punct("[") && prim_type(&ident).is_some() => {
+          self.pos -= 1;
+          self.parse_array(pos)
+        }
+        _ if *self.peek() == Token::Punct("(") => self.parse_call(pos, ident),
+        _ => self.resolve(pos, &ident),
+      },
+
+      token => Err(self.error_at(
+        pos,
+        format!("expected an expression, found {}", token.describe()),
+      )),
+    }
+  }
+
+  fn resolve(&self, pos: (usize, usize), name: &str) -> Result<ErasedExpr, ImportError> {
+    let local = self
+      .fun
+      .frames
+      .iter()
+      .rev()
+      .find_map(|frame| frame.get(name));
+
+    if let Some(handle) = local {
+      return Ok(ErasedExpr::Var(handle.clone()));
+    }
+
+    if let Some(arg) = self.fun.args.iter().position(|arg| arg == name) {
+      return Ok(ErasedExpr::Var(ScopedHandle::fun_arg(arg as u16)));
+    }
+
+    match self.globals.get(name) {
+      Some(Global::Const(handle)) => Ok(ErasedExpr::Var(ScopedHandle::global(*handle))),
+      Some(Global::Interface(handle)) => Ok(ErasedExpr::Var(handle.clone())),
+      Some(_) => Err(self.error_at(pos, format!("`{}` is a function", name))),
+      None => match builtin(self.stage, name) {
+        Some(builtin) => Ok(ErasedExpr::new_builtin(builtin)),
+        None => Err(self.error_at(pos, format!("undeclared identifier `{}`", name))),
+      },
+    }
+  }
+
+  fn parse_args(&mut self) -> Result<Vec<ErasedExpr>, ImportError> {
+    self.expect("(")?;
+    let mut args = Vec::new();
+
+    if self.peek_ident() == Some("void") && *self.peek_at(1) == Token::Punct(")") {
+      self.next();
+    }
+
+    if !self.accept(")") {
+      loop {
+        args.push(self.parse_expr()?);
+
+        if !self.accept(",") {
+          break;
+        }
+      }
+
+      self.expect(")")?;
+    }
+
+    Ok(args)
+  }
+
+  fn parse_array(&mut self, pos: (usize, usize)) -> Result<ErasedExpr, ImportError> {
+    let mut ty = self.parse_type()?.unwrap_or_else(|| unreachable!());
+    let items = self.parse_args()?;
+
+    let item_ty = Type {
+      prim_ty: ty.prim_ty.clone(),
+      array_dims: ty.array_dims[1..].to_vec(),
+    };
+    let items: Vec<_> = items
+      .into_iter()
+      .map(|item| Arc::new(coerce(item, &item_ty)))
+      .collect();
+
+    match ty.array_dims[0] {
+      0 => ty.array_dims[0] = items.len(),
+      len if len != items.len() => {
+        return Err(self.error_at(pos, format!("expected {} array items", len)))
+      }
+      _ => (),
+    }
+
+    Ok(ErasedExpr::Array(ty, items))
+  }
+
+  fn parse_call(&mut self, pos: (usize, usize), name: String) -> Result<ErasedExpr, ImportError> {
+    let args = self.parse_args()?;
+
+    if let Some(prim_ty) = prim_type(&name) {
+      return Ok(self.construct(pos, &name, &prim_ty, args));
+    }
+
+    if is_unsupported_type(&name) {
+      self.unsupported(pos, format!("type `{}`", name));
+      return Ok(placeholder());
+    }
+
+    if let Some(Global::Fun { handle, args: tys }) = self.globals.get(&name) {
+      if tys.len() != args.len() {
+        return Err(self.error_at(
+          pos,
+          format!(
+            "`{}` takes {} arguments, not {}",
+            name,
+            tys.len(),
+            args.len()
+          ),
+        ));
+      }
+
+      let args = args
+        .into_iter()
+        .zip(tys)
+        .map(|(arg, ty)| Arc::new(coerce(arg, ty)))
+        .collect();
+      return Ok(ErasedExpr::FunCall(
+        ErasedFunHandle::UserDefined(*handle),
+        args,
+      ));
+    }
+
+    if let Some(fun) = builtin_fun(&name) {
+      // integer literals are converted when mixed with floating-point arguments, except for the integer arguments of
+      // a few functions
+      let float = args
+        .iter()
+        .filter_map(|arg| self.fun.env.type_of(arg))
+        .find(|ty| matches!(ty.prim_ty, PrimType::Float(_)));
+      let has_int_args = matches!(
+        fun,
+        ErasedFunHandle::Ldexp
+          | ErasedFunHandle::BitfieldExtract
+          | ErasedFunHandle::BitfieldInsert
+          | ErasedFunHandle::InterpolateAtSample
+      );
+
+      let args = match float {
+        Some(ty) if !has_int_args => args
+          .into_iter()
+          .map(|arg| Arc::new(coerce(arg, &ty)))
+          .collect(),
+        _ => args.into_iter().map(Arc::new).collect(),
+      };
+
+      return Ok(ErasedExpr::FunCall(fun, args));
+    }
+
+    Err(self.error_at(pos, format!("undeclared function `{}`", name)))
+  }
+
+  // Constructors and conversions.
+  fn construct(
+    &mut self,
+    pos: (usize, usize),
+    name: &str,
+    prim_ty: &PrimType,
+    args: Vec<ErasedExpr>,
+  ) -> ErasedExpr {
+    if let PrimType::Float(dim @ (Dim::D2 | Dim::D3 | Dim::D4)) = prim_ty {
+      let fun = match dim {
+        Dim::D2 => ErasedFunHandle::Vec2,
+        Dim::D3 => ErasedFunHandle::Vec3,
+        _ => ErasedFunHandle::Vec4,
+      };
+      let float = Type {
+        prim_ty: PrimType::Float(Dim::Scalar),
+        array_dims: Vec::new(),
+      };
+      let args = args
+        .into_iter()
+        .map(|arg| Arc::new(coerce(arg, &float)))
+        .collect();
+
+      return ErasedExpr::FunCall(fun, args);
+    }
+
+    literal(prim_ty, &args).unwrap_or_else(|| {
+      self.unsupported(
+        pos,
+        format!("`{}` constructor with non-literal arguments", name),
+      );
+      placeholder()
+    })
+  }
+}
+
+// Expression standing for an unsupported construct; shaders with unsupported constructs are rejected anyway.
+fn placeholder() -> ErasedExpr {
+  ErasedExpr::LitBool(false)
+}
+
+fn is_int_literal(expr: &ErasedExpr) -> bool {
+  matches!(expr, ErasedExpr::LitInt(_) | ErasedExpr::LitUInt(_))
+}
+
+// Implicitly convert an integer literal to the kind of a type, as GLSL does.
+fn coerce(expr: ErasedExpr, ty: &Type) -> ErasedExpr {
+  if !ty.array_dims.is_empty() {
+    return expr;
+  }
+
+  match (expr, &ty.prim_ty) {
+    (ErasedExpr::LitInt(x), PrimType::Float(_) | PrimType::Matrix(_)) => {
+      ErasedExpr::LitFloat(x as f32)
+    }
+    (ErasedExpr::LitUInt(x), PrimType::Float(_) | PrimType::Matrix(_)) => {
+      ErasedExpr::LitFloat(x as f32)
+    }
+    (ErasedExpr::LitInt(x), PrimType::UInt(_)) if x >= 0 => ErasedExpr::LitUInt(x as u32),
+    (expr, _) => expr,
+  }
+}
+
+fn is_assignable(expr: &ErasedExpr) -> bool {
+  match expr {
+    ErasedExpr::Var(_) => true,
+    ErasedExpr::Swizzle(object, _)
+    | ErasedExpr::Field { object, .. }
+    | ErasedExpr::ArrayLookup { object, .. } => is_assignable(object),
+    _ => false,
+  }
+}
+
+// Literal built by a constructor whose arguments are all literals.
+fn literal(prim_ty: &PrimType, args: &[ErasedExpr]) -> Option<ErasedExpr> {
+  fn scalar(expr: &ErasedExpr) -> Option<f64> {
+    match expr {
+      ErasedExpr::LitInt(x) => Some(f64::from(*x)),
+      ErasedExpr::LitUInt(x) => Some(f64::from(*x)),
+      ErasedExpr::LitFloat(x) => Some(f64::from(*x)),
+      ErasedExpr::LitBool(x) => Some(f64::from(u8::from(*x))),
+      _ => None,
+    }
+  }
+
+  // a single argument is used for all the components
+  fn components<T, const N: usize>(xs: &[f64], f: impl Fn(f64) -> T) -> Option<[T; N]>
+  where
+    T: Copy + Default,
+  {
+    let mut components = [T::default(); N];
+
+    match xs.len() {
+      1 => components.iter_mut().for_each(|c| *c = f(xs[0])),
+      len if len == N => components.iter_mut().zip(xs).for_each(|(c, x)| *c = f(*x)),
+      _ => return None,
+    }
+
+    Some(components)
+  }
+
+  // a single argument is used for the diagonal
+  fn matrix<const N: usize>(xs: &[f64]) -> Option<[[f32; N]; N]> {
+    let mut m = [[0.; N]; N];
+
+    match xs.len() {
+      1 => (0..N).for_each(|i| m[i][i] = xs[0] as f32),
+      len if len == N * N => {
+        for (i, x) in xs.iter().enumerate() {
+          m[i / N][i % N] = *x as f32;
+        }
+      }
+      _ => return None,
+    }
+
+    Some(m)
+  }
+
+  let xs = args.iter().map(scalar).collect::<Option<Vec<_>>>()?;
+  let int = |x: f64| x as i32;
+  let uint = |x: f64| x as u32;
+  let float = |x: f64| x as f32;
+  let bool = |x: f64| x != 0.;
+
+  let expr = match prim_ty {
+    PrimType::Int(Dim::Scalar) => ErasedExpr::LitInt(components::<_, 1>(&xs, int)?[0]),
+    PrimType::Int(Dim::D2) => ErasedExpr::LitInt2(components(&xs, int)?),
+    PrimType::Int(Dim::D3) => ErasedExpr::LitInt3(components(&xs, int)?),
+    PrimType::Int(Dim::D4) => ErasedExpr::LitInt4(components(&xs, int)?),
+    PrimType::UInt(Dim::Scalar) => ErasedExpr::LitUInt(components::<_, 1>(&xs, uint)?[0]),
+    PrimType::UInt(Dim::D2) => ErasedExpr::LitUInt2(components(&xs, uint)?),
+    PrimType::UInt(Dim::D3) => ErasedExpr::LitUInt3(components(&xs, uint)?),
+    PrimType::UInt(Dim::D4) => ErasedExpr::LitUInt4(components(&xs, uint)?),
+    PrimType::Float(Dim::Scalar) => ErasedExpr::LitFloat(components::<_, 1>(&xs, float)?[0]),
+    PrimType::Float(Dim::D2) => ErasedExpr::LitFloat2(components(&xs, float)?),
+    PrimType::Float(Dim::D3) => ErasedExpr::LitFloat3(components(&xs, float)?),
+    PrimType::Float(Dim::D4) => ErasedExpr::LitFloat4(components(&xs, float)?),
+    PrimType::Bool(Dim::Scalar) => ErasedExpr::LitBool(components::<_, 1>(&xs, bool)?[0]),
+    PrimType::Bool(Dim::D2) => ErasedExpr::LitBool2(components(&xs, bool)?),
+    PrimType::Bool(Dim::D3) => ErasedExpr::LitBool3(components(&xs, bool)?),
+    PrimType::Bool(Dim::D4) => ErasedExpr::LitBool4(components(&xs, bool)?),
+    PrimType::Matrix(MatrixDim::D22) => ErasedExpr::LitM22(Matrix(matrix(&xs)?)),
+    PrimType::Matrix(MatrixDim::D33) => ErasedExpr::LitM33(Matrix(matrix(&xs)?)),
+    PrimType::Matrix(MatrixDim::D44) => ErasedExpr::LitM44(Matrix(matrix(&xs)?)),
+    PrimType::Matrix(_) => return None,
+  };
+
+  Some(expr)
+}
+
+fn prim_type(name: &str) -> Option<PrimType> {
+  let prim_ty = match name {
+    "int" => PrimType::Int(Dim::Scalar),
+    "ivec2" => PrimType::Int(Dim::D2),
+    "ivec3" => PrimType::Int(Dim::D3),
+    "ivec4" => PrimType::Int(Dim::D4),
+    "uint" => PrimType::UInt(Dim::Scalar),
+    "uvec2" => PrimType::UInt(Dim::D2),
+    "uvec3" => PrimType::UInt(Dim::D3),
+    "uvec4" => PrimType::UInt(Dim::D4),
+    "float" => PrimType::Float(Dim::Scalar),
+    "vec2" => PrimType::Float(Dim::D2),
+    "vec3" => PrimType::Float(Dim::D3),
+    "vec4" => PrimType::Float(Dim::D4),
+    "bool" => PrimType::Bool(Dim::Scalar),
+    "bvec2" => PrimType::Bool(Dim::D2),
+    "bvec3" => PrimType::Bool(Dim::D3),
+    "bvec4" => PrimType::Bool(Dim::D4),
+    "mat2" | "mat2x2" => PrimType::Matrix(MatrixDim::D22),
+    "mat2x3" => PrimType::Matrix(MatrixDim::D23),
+    "mat2x4" => PrimType::Matrix(MatrixDim::D24),
+    "mat3x2" => PrimType::Matrix(MatrixDim::D32),
+    "mat3" | "mat3x3" => PrimType::Matrix(MatrixDim::D33),
+    "mat3x4" => PrimType::Matrix(MatrixDim::D34),
+    "mat4x2" => PrimType::Matrix(MatrixDim::D42),
+    "mat4x3" => PrimType::Matrix(MatrixDim::D43),
+    "mat4" | "mat4x4" => PrimType::Matrix(MatrixDim::D44),
+    _ => return None,
+  };
+
+  Some(prim_ty)
+}
+
+// Types of GLSL that have no representation.
+fn is_unsupported_type(name: &str) -> bool {
+  const PREFIXES: &[&str] = &[
+    "sampler",
+    "isampler",
+    "usampler",
+    "image",
+    "iimage",
+    "uimage",
+    "texture",
+    "itexture",
+    "utexture",
+    "subpassInput",
+    "dvec",
+    "dmat",
+  ];
+
+  matches!(name, "double" | "atomic_uint" | "sampler")
+    || PREFIXES.iter().any(|prefix| name.starts_with(prefix))
+}
+
+fn is_type_name(name: &str) -> bool {
+  name == "void" || prim_type(name).is_some() || is_unsupported_type(name)
+}
+
+fn swizzle(field: &str) -> Option<Swizzle> {
+  let selectors = field
+    .chars()
+    .map(|c| match c {
+      'x' | 'r' | 's' => Some(SwizzleSelector::X),
+      'y' | 'g' | 't' => Some(SwizzleSelector::Y),
+      'z' | 'b' | 'p' => Some(SwizzleSelector::Z),
+      'w' | 'a' | 'q' => Some(SwizzleSelector::W),
+      _ => None,
+    })
+    .collect::<Option<Vec<_>>>()?;
+
+  match selectors[..] {
+    [a] => Some(Swizzle::D1(a)),
+    [a, b] => Some(Swizzle::D2(a, b)),
+    [a, b, c] => Some(Swizzle::D3(a, b, c)),
+    [a, b, c, d] => Some(Swizzle::D4(a, b, c, d)),
+    _ => None,
+  }
+}
+
+// Built-in variables of a shader stage, by GLSL name.
+fn builtin(stage: ShaderStage, name: &str) -> Option<BuiltIn> {
+  let builtin = match stage {
+    ShaderStage::Vertex => BuiltIn::Vertex(match name {
+      "gl_VertexID" => VertexBuiltIn::VertexID,
+      "gl_InstanceID" => VertexBuiltIn::InstanceID,
+      "gl_BaseVertex" => VertexBuiltIn::BaseVertex,
+      "gl_BaseInstance" => VertexBuiltIn::BaseInstance,
+      "gl_Position" => VertexBuiltIn::Position,
+      "gl_PointSize" => VertexBuiltIn::PointSize,
+      "gl_ClipDistance" => VertexBuiltIn::ClipDistance,
+      _ => return None,
+    }),
+
+    ShaderStage::TessCtrl => BuiltIn::TessCtrl(match name {
+      "gl_MaxPatchVerticesIn" => TessCtrlBuiltIn::MaxPatchVerticesIn,
+      "gl_PatchVerticesIn" => TessCtrlBuiltIn::PatchVerticesIn,
+      "gl_PrimitiveID" => TessCtrlBuiltIn::PrimitiveID,
+      "gl_InvocationID" => TessCtrlBuiltIn::InvocationID,
+      "gl_TessLevelOuter" => TessCtrlBuiltIn::TessellationLevelOuter,
+      "gl_TessLevelInner" => TessCtrlBuiltIn::TessellationLevelInner,
+      "gl_in" => TessCtrlBuiltIn::In,
+      "gl_out" => TessCtrlBuiltIn::Out,
+      "gl_Position" => TessCtrlBuiltIn::Position,
+      "gl_PointSize" => TessCtrlBuiltIn::PointSize,
+      "gl_ClipDistance" => TessCtrlBuiltIn::ClipDistance,
+      "gl_CullDistance" => TessCtrlBuiltIn::CullDistance,
+      _ => return None,
+    }),
+
+    ShaderStage::TessEval => BuiltIn::TessEval(match name {
+      "gl_TessCoord" => TessEvalBuiltIn::TessCoord,
+      "gl_MaxPatchVerticesIn" => TessEvalBuiltIn::MaxPatchVerticesIn,
+      "gl_PatchVerticesIn" => TessEvalBuiltIn::PatchVerticesIn,
+      "gl_PrimitiveID" => TessEvalBuiltIn::PrimitiveID,
+      "gl_TessLevelOuter" => TessEvalBuiltIn::TessellationLevelOuter,
+      "gl_TessLevelInner" => TessEvalBuiltIn::TessellationLevelInner,
+      "gl_in" => TessEvalBuiltIn::In,
+      "gl_out" => TessEvalBuiltIn::Out,
+      "gl_Position" => TessEvalBuiltIn::Position,
+      "gl_PointSize" => TessEvalBuiltIn::PointSize,
+      "gl_ClipDistance" => TessEvalBuiltIn::ClipDistance,
+      "gl_CullDistance" => TessEvalBuiltIn::CullDistance,
+      _ => return None,
+    }),
+
+    ShaderStage::Geometry => BuiltIn::Geometry(match name {
+      "gl_in" => GeometryBuiltIn::In,
+      "gl_out" => GeometryBuiltIn::Out,
+      "gl_Position" => GeometryBuiltIn::Position,
+      "gl_PointSize" => GeometryBuiltIn::PointSize,
+      "gl_ClipDistance" => GeometryBuiltIn::ClipDistance,
+      "gl_CullDistance" => GeometryBuiltIn::CullDistance,
+      "gl_PrimitiveID" => GeometryBuiltIn::PrimitiveID,
+      "gl_PrimitiveIDIn" => GeometryBuiltIn::PrimitiveIDIn,
+      "gl_InvocationID" => GeometryBuiltIn::InvocationID,
+      "gl_Layer" => GeometryBuiltIn::Layer,
+      "gl_ViewportIndex" => GeometryBuiltIn::ViewportIndex,
+      _ => return None,
+    }),
+
+    ShaderStage::Fragment => BuiltIn::Fragment(match name {
+      "gl_FragCoord" => FragmentBuiltIn::FragCoord,
+      "gl_FrontFacing" => FragmentBuiltIn::FrontFacing,
+      "gl_PointCoord" => FragmentBuiltIn::PointCoord,
+      "gl_SampleID" => FragmentBuiltIn::SampleID,
+      "gl_SamplePosition" => FragmentBuiltIn::SamplePosition,
+      "gl_SampleMaskIn" => FragmentBuiltIn::SampleMaskIn,
+      "gl_ClipDistance" => FragmentBuiltIn::ClipDistance,
+      "gl_CullDistance" => FragmentBuiltIn::CullDistance,
+      "gl_PrimitiveID" => FragmentBuiltIn::PrimitiveID,
+      "gl_Layer" => FragmentBuiltIn::Layer,
+      "gl_ViewportIndex" => FragmentBuiltIn::ViewportIndex,
+      "gl_FragDepth" => FragmentBuiltIn::FragDepth,
+      "gl_SampleMask" => FragmentBuiltIn::SampleMask,
+      "gl_HelperInvocation" => FragmentBuiltIn::HelperInvocation,
+      _ => return None,
+    }),
+  };
+
+  Some(builtin)
+}
+
+// Built-in functions, by GLSL name.
+fn builtin_fun(name: &str) -> Option<ErasedFunHandle> {
+  let fun = match name {
+    "radians" => ErasedFunHandle::Radians,
+    "degrees" => ErasedFunHandle::Degrees,
+    "sin" => ErasedFunHandle::Sin,
+    "cos" => ErasedFunHandle::Cos,
+    "tan" => ErasedFunHandle::Tan,
+    "asin" => ErasedFunHandle::ASin,
+    "acos" => ErasedFunHandle::ACos,
+    "atan" => ErasedFunHandle::ATan,
+    "sinh" => ErasedFunHandle::SinH,
+    "cosh" => ErasedFunHandle::CosH,
+    "tanh" => ErasedFunHandle::TanH,
+    "asinh" => ErasedFunHandle::ASinH,
+    "acosh" => ErasedFunHandle::ACosH,
+    "atanh" => ErasedFunHandle::ATanH,
+    "pow" => ErasedFunHandle::Pow,
+    "exp" => ErasedFunHandle::Exp,
+    "exp2" => ErasedFunHandle::Exp2,
+    "log" => ErasedFunHandle::Log,
+    "log2" => ErasedFunHandle::Log2,
+    "sqrt" => ErasedFunHandle::Sqrt,
+    "inversesqrt" => ErasedFunHandle::InverseSqrt,
+    "abs" => ErasedFunHandle::Abs,
+    "sign" => ErasedFunHandle::Sign,
+    "floor" => ErasedFunHandle::Floor,
+    "trunc" => ErasedFunHandle::Trunc,
+    "round" => ErasedFunHandle::Round,
+    "roundEven" => ErasedFunHandle::RoundEven,
+    "ceil" => ErasedFunHandle::Ceil,
+    "fract" => ErasedFunHandle::Fract,
+    "min" => ErasedFunHandle::Min,
+    "max" => ErasedFunHandle::Max,
+    "clamp" => ErasedFunHandle::Clamp,
+    "mix" => ErasedFunHandle::Mix,
+    "step" => ErasedFunHandle::Step,
+    "smoothstep" => ErasedFunHandle::SmoothStep,
+    "isnan" => ErasedFunHandle::IsNan,
+    "isinf" => ErasedFunHandle::IsInf,
+    "floatBitsToInt" => ErasedFunHandle::FloatBitsToInt,
+    "intBitsToFloat" => ErasedFunHandle::IntBitsToFloat,
+    "uintBitsToFloat" => ErasedFunHandle::UIntBitsToFloat,
+    "fma" => ErasedFunHandle::FMA,
+    "frexp" => ErasedFunHandle::Frexp,
+    "ldexp" => ErasedFunHandle::Ldexp,
+    "packUnorm2x16" => ErasedFunHandle::PackUnorm2x16,
+    "packSnorm2x16" => ErasedFunHandle::PackSnorm2x16,
+    "packUnorm4x8" => ErasedFunHandle::PackUnorm4x8,
+    "packSnorm4x8" => ErasedFunHandle::PackSnorm4x8,
+    "unpackUnorm2x16" => ErasedFunHandle::UnpackUnorm2x16,
+    "unpackSnorm2x16" => ErasedFunHandle::UnpackSnorm2x16,
+    "unpackUnorm4x8" => ErasedFunHandle::UnpackUnorm4x8,
+    "unpackSnorm4x8" => ErasedFunHandle::UnpackSnorm4x8,
+    "packHalf2x16" => ErasedFunHandle::PackHalf2x16,
+    "unpackHalf2x16" => ErasedFunHandle::UnpackHalf2x16,
+    "length" => ErasedFunHandle::Length,
+    "distance" => ErasedFunHandle::Distance,
+    "dot" => ErasedFunHandle::Dot,
+    "cross" => ErasedFunHandle::Cross,
+    "normalize" => ErasedFunHandle::Normalize,
+    "faceforward" => ErasedFunHandle::FaceForward,
+    "reflect" => ErasedFunHandle::Reflect,
+    "refract" => ErasedFunHandle::Refract,
+    "lessThan" => ErasedFunHandle::VLt,
+    "lessThanEqual" => ErasedFunHandle::VLte,
+    "greaterThan" => ErasedFunHandle::VGt,
+    "greaterThanEqual" => ErasedFunHandle::VGte,
+    "equal" => ErasedFunHandle::VEq,
+    "notEqual" => ErasedFunHandle::VNeq,
+    "any" => ErasedFunHandle::VAny,
+    "all" => ErasedFunHandle::VAll,
+    "not" => ErasedFunHandle::VNot,
+    "uaddCarry" => ErasedFunHandle::UAddCarry,
+    "usubBorrow" => ErasedFunHandle::USubBorrow,
+    "umulExtended" => ErasedFunHandle::UMulExtended,
+    "imulExtended" => ErasedFunHandle::IMulExtended,
+    "bitfieldExtract" => ErasedFunHandle::BitfieldExtract,
+    "bitfieldInsert" => ErasedFunHandle::BitfieldInsert,
+    "bitfieldReverse" => ErasedFunHandle::BitfieldReverse,
+    "bitCount" => ErasedFunHandle::BitCount,
+    "findLSB" => ErasedFunHandle::FindLSB,
+    "findMSB" => ErasedFunHandle::FindMSB,
+    "EmitStreamVertex" => ErasedFunHandle::EmitStreamVertex,
+    "EndStreamPrimitive" => ErasedFunHandle::EndStreamPrimitive,
+    "EmitVertex" => ErasedFunHandle::EmitVertex,
+    "EndPrimitive" => ErasedFunHandle::EndPrimitive,
+    "dFdx" => ErasedFunHandle::DFDX,
+    "dFdy" => ErasedFunHandle::DFDY,
+    "dFdxFine" => ErasedFunHandle::DFDXFine,
+    "dFdyFine" => ErasedFunHandle::DFDYFine,
+    "dFdxCoarse" => ErasedFunHandle::DFDXCoarse,
+    "dFdyCoarse" => ErasedFunHandle::DFDYCoarse,
+    "fwidth" => ErasedFunHandle::FWidth,
+    "fwidthFine" => ErasedFunHandle::FWidthFine,
+    "fwidthCoarse" => ErasedFunHandle::FWidthCoarse,
+    "interpolateAtCentroid" => ErasedFunHandle::InterpolateAtCentroid,
+    "interpolateAtSample" => ErasedFunHandle::InterpolateAtSample,
+    "interpolateAtOffset" => ErasedFunHandle::InterpolateAtOffset,
+    "barrier" => ErasedFunHandle::Barrier,
+    "memoryBarrier" => ErasedFunHandle::MemoryBarrier,
+    "memoryBarrierAtomicCounter" => ErasedFunHandle::MemoryBarrierAtomic,
+    "memoryBarrierBuffer" => ErasedFunHandle::MemoryBarrierBuffer,
+    "memoryBarrierShared" => ErasedFunHandle::MemoryBarrierShared,
+    "memoryBarrierImage" => ErasedFunHandle::MemoryBarrierImage,
+    "groupMemoryBarrier" => ErasedFunHandle::GroupMemoryBarrier,
+    "anyInvocation" => ErasedFunHandle::AnyInvocation,
+    "allInvocations" => ErasedFunHandle::AllInvocations,
+    "allInvocationsEqual" => ErasedFunHandle::AllInvocationsEqual,
+    _ => return None,
+  };
+
+  Some(fun)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    validation::{Declaration, Diagnostic},
+    writer::glsl::write_shader_to_str,
+  };
+
+  fn unsupported(source: &str, stage: ShaderStage) -> Vec<(usize, usize, String)> {
+    match import_shader(source, stage) {
+      Err(ImportError::Unsupported(constructs)) => constructs
+        .into_iter()
+        .map(|c| (c.line, c.column, c.construct))
+        .collect(),
+      r => panic!("unexpected result: {:?}", r),
+    }
+  }
+
+  #[test]
+  fn vertex_shader() {
+    let shader = import_shader(
+      r#"#version 330 core
+in vec3 position;
+out vec4 color;
+uniform float t;
+const float speed = 2;
+
+float wave(float x) {
+  return sin(x * speed);
+}
+
+void main() {
+  float w = wave(t);
+  vec3 p = position * w;
+
+  for (int i = 0; i < 4; ++i) {
+    if (w > 0.5) {
+      w -= 1;
+    } else if (w < -0.5) {
+      break;
+    } else {
+      w *= 2.;
+    }
+  }
+
+  color = vec4(p.zyx, 1);
+  gl_Position = vec4(p, w);
+}
+"#,
+      ShaderStage::Vertex,
+    )
+    .unwrap();
+
+    assert_eq!(
+      write_shader_to_str(&shader).unwrap(),
+      "in vec3 position;
+out vec4 color;
+uniform float t;
+const float speed = 2.;
+
+float wave(float x) {
+  return sin((x * speed));
+}
+
+void main() {
+  float w = wave(t);
+  vec3 p = (position * w);
+  for (int var_2_0 = 0; (var_2_0 < 4); var_2_0 = (var_2_0 + 1)) {
+    if ((w > .5)) {
+      w = (w - 1.);
+    }
+    else if ((w < -.5)) {
+      break;
+    }
+    else {
+      w = (w * 2.);
+    }
+  }
+  color = vec4(p.zyx, 1.);
+  gl_Position = vec4(p, w);
+}"
+    );
+  }
+
+  #[test]
+  fn fragment_shader() {
+    let shader = import_shader(
+      r#"
+      in vec2 uv;
+      flat in int layer;
+      layout (location = 1, index = 0) out vec4 frag; // comment
+      uniform float threshold;
+
+      /* block
+         comment */
+      void main() {
+        float d = length(uv - vec2(0.5));
+        vec4 c = vec4(0.);
+        if (d > threshold) {
+          discard;
+        }
+        c.rgb = vec3(1. - d);
+        c.a = 1;
+        frag = c;
+      }
+      "#,
+      ShaderStage::Fragment,
+    )
+    .unwrap();
+
+    let output = write_shader_to_str(&shader).unwrap();
+    assert!(output.contains("flat in int layer;"));
+    assert!(output.contains("layout (location = 1) out vec4 frag;"));
+    assert!(output.contains("float d = length((uv - vec2(.5)));"));
+    assert!(output.contains("vec4 c = vec4(0.);"));
+    assert!(output.contains("discard;"));
+    assert!(output.contains("c.xyz = vec3((1. - d));"));
+    assert!(output.contains("c.w = 1.;"));
+  }
+
+  #[test]
+  fn literals() {
+    let shader = import_shader(
+      r#"
+      void main() {
+        uint a = 0x10u + 3;
+        int b = -017;
+        float c = 1e2 + 2;
+        ivec2 d = ivec2(1, 2);
+        mat2 e = mat2(1);
+        float f[2] = float[](1, 2.5);
+        bool g = !true;
+        uint h = 3u + 0x10u;
+      }
+      "#,
+      ShaderStage::Vertex,
+    )
+    .unwrap();
+
+    let output = write_shader_to_str(&shader).unwrap();
+    assert!(output.contains("uint a = (16u + 3u);"));
+    assert!(output.contains("int b = -15;"));
+    assert!(output.contains("float c = (100. + 2.);"));
+    assert!(output.contains("ivec2 d = ivec2(1, 2);"));
+    assert!(output.contains("mat2 e = mat2(1., 0., 0., 1.);"));
+    assert!(output.contains("float[2] f = float[2](1.,2.5);"));
+    assert!(output.contains("bool g = !true;"));
+    assert!(output.contains("uint h = (3u + 16u);"));
+  }
+
+  #[test]
+  fn array_functions() {
+    let shader = import_shader(
+      r#"
+      float[2] swap(float a[2]) {
+        return float[](a[1], a[0]);
+      }
+
+      float[3] first(vec2[3] m[2]) {
+        return float[3](m[0][0].x, m[0][1].x, m[0][2].x);
+      }
+
+      float first_sum(vec2 m[2][3]) {
+        return first(m)[0] + first(m)[2];
+      }
+
+      void main() {
+        float[2] s = swap(float[2](1, 2));
+      }
+      "#,
+      ShaderStage::Vertex,
+    )
+    .unwrap();
+
+    let output = write_shader_to_str(&shader).unwrap();
+    assert!(output.contains("float[2] swap(float[2] a) {"));
+    assert!(output.contains("float[3] first(vec2[2][3] m) {"));
+    assert!(output.contains("float first_sum(vec2[2][3] m) {"));
+    assert!(output.contains("float[2] s = swap(float[2](1.,2.));"));
+  }
+
+  #[test]
+  fn prototypes() {
+    let shader = import_shader(
+      r#"
+      float twice(float x);
+
+      float quad(float x) {
+        return twice(twice(x));
+      }
+
+      float twice(float x) {
+        return x * 2;
+      }
+
+      void main() {
+        gl_PointSize = quad(1);
+      }
+      "#,
+      ShaderStage::Vertex,
+    )
+    .unwrap();
+
+    let output = write_shader_to_str(&shader).unwrap();
+    assert!(output.find("float twice(float x) {").unwrap() < output.find("float quad").unwrap());
+    assert!(output.contains("return twice(twice(x));"));
+
+    let result = import_shader(
+      r#"
+      float twice(float x);
+
+      void main() {
+        gl_PointSize = twice(1);
+      }
+      "#,
+      ShaderStage::Vertex,
+    );
+    assert_eq!(
+      result,
+      Err(ImportError::InvalidShader(vec![
+        Diagnostic::UndeclaredFunction {
+          declaration: Declaration::Main,
+          handle: 0,
+        }
+      ]))
+    );
+
+    let result = import_shader(
+      r#"
+      float odd(float x);
+
+      float even(float x) {
+        return odd(x - 1);
+      }
+
+      float odd(float x) {
+        return even(x - 1);
+      }
+
+      void main() {
+        gl_PointSize = even(4);
+      }
+      "#,
+      ShaderStage::Vertex,
+    );
+    assert_eq!(
+      result,
+      Err(ImportError::InvalidShader(vec![
+        Diagnostic::Recursion(Declaration::Function(1)),
+        Diagnostic::Recursion(Declaration::Function(0)),
+      ]))
+    );
+  }
+
+  #[test]
+  fn unsupported_constructs() {
+    let source = r#"#version 330 core
+#define PI 3.14
+struct Light { vec3 pos; };
+uniform sampler2D tex;
+flat in vec2 st;
+
+void main() {
+  int x = 0;
+  switch (x) { case 0: break; }
+  float y = x > 0 ? 1. : 2.;
+  x;
+  float u, v = 2.;
+}
+"#;
+
+    assert_eq!(
+      unsupported(source, ShaderStage::Fragment),
+      vec![
+        (2, 1, "preprocessor directive `#define`".to_owned()),
+        (3, 1, "structure".to_owned()),
+        (4, 9, "type `sampler2D`".to_owned()),
+        (5, 14, "qualifier `flat`".to_owned()),
+        (9, 3, "`switch` statement".to_owned()),
+        (10, 13, "conditional operator `?:`".to_owned()),
+        (11, 3, "expression statement".to_owned()),
+        (12, 9, "uninitialized variable `u`".to_owned()),
+      ]
+    );
+  }
+
+  #[test]
+  fn unsupported_before_syntax_errors() {
+    let source = "#define X 1.\nout float o;\nvoid main() {\n  o = X;\n}";
+
+    assert_eq!(
+      unsupported(source, ShaderStage::Fragment),
+      vec![(1, 1, "preprocessor directive `#define`".to_owned())]
+    );
+  }
+
+  #[test]
+  fn postfix_update_in_expression() {
+    let source = "void main() {\n  int i = 0;\n  int j = i++;\n  int k = 2 * i--;\n}";
+
+    assert_eq!(
+      unsupported(source, ShaderStage::Vertex),
+      vec![
+        (3, 12, "postfix increment in expression".to_owned()),
+        (4, 16, "postfix decrement in expression".to_owned()),
+      ]
+    );
+  }
+
+  #[test]
+  fn syntax_errors() {
+    let source = "void main() {\n  float x = y;\n}";
+
+    assert_eq!(
+      import_shader(source, ShaderStage::Vertex),
+      Err(ImportError::Syntax {
+        line: 2,
+        column: 13,
+        message: "undeclared identifier `y`".to_owned()
+      })
+    );
+
+    assert_eq!(
+      import_shader("in vec3 position;", ShaderStage::Vertex),
+      Err(ImportError::Syntax {
+        line: 1,
+        column: 18,
+        message: "missing `main` function".to_owned()
+      })
+    );
+
+    assert!(matches!(
+      import_shader("void main() { float x = 1.; ", ShaderStage::Vertex),
+      Err(ImportError::Syntax { .. })
+    ));
+  }
+}
@@ -0,0 +1,281 @@
+//! Hash-consing of expressions.
+//!
+//! Sub-expressions are reference-counted, so an expression reused several times is shared; equal expressions built
+//! separately are not, though — material graphs, for instance, tend to rebuild the same sub-expressions over and over.
+//! Declarations are interned when added to a shader: their sub-expressions are replaced with the equal ones already
+//! interned, so that equal sub-expressions are allocated, compared, hashed and written once.
+
+use crate::{
+  fingerprint::{self, AddressHasher, Digests},
+  optimizer::fun_exprs_mut,
+  ErasedExpr, ShaderDecl,
+};
+use std::{
+  collections::{hash_map::Entry, HashMap},
+  hash::BuildHasherDefault,
+  sync::{Arc, Weak},
+};
+
+/// Sub-expressions interned by a shader builder, by digest of their content.
+///
+/// Interned sub-expressions are held weakly, so that the table doesn’t keep alive the ones a shader doesn’t use anymore.
+/// Their allocations are kept alive, though, so that their addresses are not reused while they are in the table.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Interner {
+  exprs: Map<u64, Weak<ErasedExpr>>,
+  // digests of the interned sub-expressions, by address
+  digests: Digests,
+  // sub-expressions whose digest is the one of a different interned sub-expression, which are not shared
+  collisions: Vec<Weak<ErasedExpr>>,
+}
+
+// Shared sub-expressions of the declaration being interned which were not interned yet, along with their interned
+// version, by address. The originals are kept alive so that their address is not reused while interning.
+type Replaced = Map<usize, (Arc<ErasedExpr>, Arc<ErasedExpr>)>;
+
+type Map<K, V> = HashMap<K, V, BuildHasherDefault<AddressHasher>>;
+
+impl Interner {
+  /// Replace the sub-expressions of a declaration with the interned ones, interning the new ones.
+  pub(crate) fn intern_decl(&mut self, decl: &mut ShaderDecl) {
+    let mut replaced = Replaced::default();
+
+    match decl {
+      ShaderDecl::Main(fun) | ShaderDecl::FunDef(_, fun) => {
+        for expr in fun_exprs_mut(fun) {
+          self.intern_children(expr, &mut replaced);
+        }
+      }
+
+      ShaderDecl::Const(_, _, expr, _) => {
+        self.intern_children(expr, &mut replaced);
+      }
+
+      ShaderDecl::In(..) | ShaderDecl::Out(..) | ShaderDecl::Uniform(..) => (),
+    }
+  }
+
+  // Intern the direct sub-expressions of an expression, returning whether any was replaced.
+  fn intern_children(&mut self, expr: &mut ErasedExpr, replaced: &mut Replaced) -> bool {
+    let mut changed = false;
+
+    for child in children_mut(expr) {
+      changed |= self.intern(child, replaced);
+    }
+
+    changed
+  }
+
+  // Intern a sub-expression, returning whether it was replaced.
+  fn intern(&mut self, expr: &mut Arc<ErasedExpr>, replaced: &mut Replaced) -> bool {
+    let address = Arc::as_ptr(expr) as usize;
+
+    // a sub-expression only referred to by its parent is not interned yet, and is interned in place; a shared one is
+    // copied if its own sub-expressions are replaced, its other parents being updated as they are interned
+    let original = match Arc::get_mut(expr) {
+      Some(unique) => {
+        self.intern_children(unique, replaced);
+        None
+      }
+
+      None => {
+        if self.digests.contains_key(&address) {
+          return false;
+        }
+
+        if let Some((_, interned)) = replaced.get(&address) {
+          *expr = interned.clone();
+          return true;
+        }
+
+        let mut copy = ErasedExpr::clone(expr);
+        let original = expr.clone();
+
+        if self.intern_children(&mut copy, replaced) {
+          *expr = Arc::new(copy);
+        }
+
+        Some(original)
+      }
+    };
+
+    let digest = fingerprint::digest_with(expr, &mut self.digests);
+    let interned = match self.exprs.entry(digest) {
+      Entry::Occupied(mut entry) => match entry.get().upgrade() {
+        Some(interned) if interned == *expr => Some(interned),
+
+        Some(_) => {
+          self.collisions.push(Arc::downgrade(expr));
+          None
+        }
+
+        // the interned sub-expression is not used anymore
+        None => {
+          self.digests.remove(&(entry.get().as_ptr() as usize));
+          entry.insert(Arc::downgrade(expr));
+          None
+        }
+      },
+
+      Entry::Vacant(entry) => {
+        entry.insert(Arc::downgrade(expr));
+        None
+      }
+    };
+
+    match interned {
+      Some(interned) => *expr = interned,
+      None => {
+        self.digests.insert(Arc::as_ptr(expr) as usize, digest);
+      }
+    }
+
+    if let Some(original) = original {
+      replaced.insert(address, (original, expr.clone()));
+    }
+
+    Arc::as_ptr(expr) as usize != address
+  }
+}
+
+// Direct sub-expressions of an expression.
+fn children_mut(expr: &mut ErasedExpr) -> impl Iterator<Item = &mut Arc<ErasedExpr>> {
+  let (operands, items): ([Option<&mut Arc<ErasedExpr>>; 2], &mut [Arc<ErasedExpr>]) = match expr {
+    ErasedExpr::Array(_, items) | ErasedExpr::FunCall(_, items) => ([None, None], items),
+
+    ErasedExpr::Not(a) | ErasedExpr::Neg(a) | ErasedExpr::Swizzle(a, _) => {
+      ([Some(a), None], &mut [])
+    }
+
+    ErasedExpr::And(a, b)
+    | ErasedExpr::Or(a, b)
+    | ErasedExpr::Xor(a, b)
+    | ErasedExpr::BitOr(a, b)
+    | ErasedExpr::BitAnd(a, b)
+    | ErasedExpr::BitXor(a, b)
+    | ErasedExpr::Add(a, b)
+    | ErasedExpr::Sub(a, b)
+    | ErasedExpr::Mul(a, b)
+    | ErasedExpr::Div(a, b)
+    | ErasedExpr::Rem(a, b)
+    | ErasedExpr::Shl(a, b)
+    | ErasedExpr::Shr(a, b)
+    | ErasedExpr::Eq(a, b)
+    | ErasedExpr::Neq(a, b)
+    | ErasedExpr::Lt(a, b)
+    | ErasedExpr::Lte(a, b)
+    | ErasedExpr::Gt(a, b)
+    | ErasedExpr::Gte(a, b)
+    | ErasedExpr::Field {
+      object: a,
+      field: b,
+    }
+    | ErasedExpr::ArrayLookup {
+      object: a,
+      index: b,
+    } => ([Some(a), Some(b)], &mut []),
+
+    _ => ([None, None], &mut []),
+  };
+
+  IntoIterator::into_iter(operands).flatten().chain(items)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{lit, Expr, Scope, ScopeInstr, Shader, ShaderBuilder, Trigonometry as _};
+
+  // Initial values of the variables of the main function of a shader.
+  fn main_vars(shader: &Shader) -> Vec<&ErasedExpr> {
+    let fun = shader
+      .decls
+      .iter()
+      .find_map(|decl| match decl {
+        ShaderDecl::Main(fun) => Some(fun),
+        _ => None,
+      })
+      .unwrap();
+
+    fun
+      .scope
+      .instructions
+      .iter()
+      .filter_map(|instr| match instr {
+        ScopeInstr::VarDecl { init_value, .. } => Some(init_value),
+        _ => None,
+      })
+      .collect()
+  }
+
+  fn operands(expr: &ErasedExpr) -> (&Arc<ErasedExpr>, &Arc<ErasedExpr>) {
+    expr.operands().unwrap()
+  }
+
+  #[test]
+  fn equal_expressions_shared() {
+    let shader = ShaderBuilder::new_vertex_shader(|s, _| {
+      s.main_fun(|s: &mut Scope<()>| {
+        let _ = s.var((lit!(1.) + 2.).sin() * 3.);
+        let _ = s.var((lit!(1.) + 2.).sin() * 4.);
+      })
+    });
+
+    let vars = main_vars(&shader);
+    assert!(Arc::ptr_eq(operands(vars[0]).0, operands(vars[1]).0));
+    assert!(!Arc::ptr_eq(operands(vars[0]).1, operands(vars[1]).1));
+  }
+
+  #[test]
+  fn equal_expressions_shared_across_declarations() {
+    let shader = ShaderBuilder::new_vertex_shader(|mut s, _| {
+      let c = s.constant(lit!(1.) + 2.);
+      let f = s.fun(|_: &mut Scope<Expr<f32>>, x: Expr<f32>| x * 2.);
+
+      s.main_fun(|s: &mut Scope<()>| {
+        let _ = s.var(f.call(c.clone()) + (lit!(1.) + 2.));
+        let _ = s.var(lit!(1.) * 2.);
+      })
+    });
+
+    let constant = match &shader.decls[0] {
+      ShaderDecl::Const(_, _, expr, _) => expr,
+      _ => panic!("constant expected"),
+    };
+    let vars = main_vars(&shader);
+
+    // the literals of the constant are shared by the addition and the product of main
+    assert!(Arc::ptr_eq(operands(constant).0, operands(vars[1]).0));
+    assert!(Arc::ptr_eq(operands(constant).1, operands(vars[1]).1));
+    assert_eq!(**operands(vars[0]).1, *constant);
+  }
+
+  #[test]
+  fn shared_expressions_replaced_once() {
+    let sum = lit!(1.) + 2.;
+    let x = (sum.clone() * 3.).sin();
+
+    let shader = ShaderBuilder::new_vertex_shader(|s, _| {
+      s.main_fun(|s: &mut Scope<()>| {
+        let _ = s.var(lit!(1.) + 2.);
+        let _ = s.var(x.clone() + x.clone());
+        let _ = s.var(sum.clone() * 3.);
+      })
+    });
+
+    let vars = main_vars(&shader);
+    let (a, b) = operands(vars[1]);
+    assert!(Arc::ptr_eq(a, b));
+
+    let product = match &**a {
+      ErasedExpr::FunCall(_, args) => &args[0],
+      _ => panic!("call expected"),
+    };
+    let sum_in_x = operands(product).0;
+    assert!(Arc::ptr_eq(sum_in_x, operands(vars[2]).0));
+    assert!(Arc::ptr_eq(operands(sum_in_x).0, operands(vars[0]).0));
+
+    // the expressions kept by the caller are left untouched
+    assert!(!Arc::ptr_eq(operands(&sum.erased).0, operands(vars[0]).0));
+  }
+}
@@ -0,0 +1,2227 @@
+//! CPU interpreter for shaders.
+//!
+//! This module allows to run a [`Shader`] on the CPU, which is mostly useful to unit-test the logic of shaders without
+//! a GPU. An [`Invocation`] runs the `main` function of a shader stage once, given values for inputs, uniforms and
+//! built-ins, and returns the values of outputs and built-ins written by the shader as [`Outputs`].
+//!
+//! Values flowing in and out of the interpreter are represented with [`Value`], which can be converted from and to
+//! the Rust types used in the EDSL, such as [`f32`], [`V3<f32>`](crate::V3) or [`M44`](crate::M44).
+//!
+//! # Examples
+//!
+//! ```
+//! use shades::{BuiltIn, Scope, ShaderBuilder, V4, VertexBuiltIn, inputs, lit, uniforms, vec4};
+//! use shades::interpreter::{Invocation, Value};
+//!
+//! let shader = ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+//!   inputs!(s, x: f32);
+//!   uniforms!(s, scale: f32);
+//!
+//!   s.main_fun(|s: &mut Scope<()>| {
+//!     s.set(&vertex.position, vec4!(x * scale, 0., 0., 1.));
+//!   })
+//! });
+//!
+//! let outputs = Invocation::new(&shader)
+//!   .input("x", 2.)
+//!   .uniform("scale", 3.)
+//!   .run()
+//!   .unwrap();
+//!
+//! assert_eq!(
+//!   outputs.builtin(BuiltIn::Vertex(VertexBuiltIn::Position)),
+//!   Some(&Value::from(V4::from([6., 0., 0., 1.])))
+//! );
+//! ```
+
+use crate::{
+  typing::{dim_len, matrix_dim_len},
+  ArgQualifier, BuiltIn, ErasedExpr, ErasedFun, ErasedFunHandle, ErasedReturn, ErasedScope, Matrix,
+  PrimType, ScopeInstr, ScopedHandle, Shader, ShaderDecl, Swizzle, SwizzleSelector, Type, V2, V3,
+  V4,
+};
+use std::{
+  collections::{BTreeMap, BTreeSet, HashMap},
+  convert::TryFrom,
+  fmt,
+  sync::Arc,
+};
+
+/// A value manipulated by the interpreter.
+///
+/// Scalars and vectors are both represented by a list of components: a scalar has one component, and vectors have two
+/// to four components.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+  /// Scalar or vector of signed integers.
+  Int(Vec<i32>),
+
+  /// Scalar or vector of unsigned integers.
+  UInt(Vec<u32>),
+
+  /// Scalar or vector of floating-point numbers.
+  Float(Vec<f32>),
+
+  /// Scalar or vector of booleans.
+  Bool(Vec<bool>),
+
+  /// Floating-point matrix, as a list of columns.
+  Matrix(Vec<Vec<f32>>),
+
+  /// Array of values.
+  Array(Vec<Value>),
+
+  /// Block of built-ins, such as a vertex of [`TessCtrlShaderEnv::input`](crate::TessCtrlShaderEnv::input).
+  Block(BTreeMap<BuiltIn, Value>),
+}
+
+impl Value {
+  /// Zero value of a type; unsized arrays are empty.
+  fn zero(ty: &Type) -> Self {
+    let mut value = match &ty.prim_ty {
+      PrimType::Int(dim) => Value::Int(vec![0; dim_len(dim)]),
+      PrimType::UInt(dim) => Value::UInt(vec![0; dim_len(dim)]),
+      PrimType::Float(dim) => Value::Float(vec![0.; dim_len(dim)]),
+      PrimType::Bool(dim) => Value::Bool(vec![false; dim_len(dim)]),
+      PrimType::Matrix(dim) => {
+        let (cols, rows) = matrix_dim_len(dim);
+        Value::Matrix(vec![vec![0.; rows]; cols])
+      }
+    };
+
+    for &dim in ty.array_dims.iter().rev() {
+      value = Value::Array(vec![value; dim]);
+    }
+
+    value
+  }
+
+  /// Same value, with all components set to zero.
+  fn zeroed(&self) -> Self {
+    match self {
+      Value::Int(a) => Value::Int(vec![0; a.len()]),
+      Value::UInt(a) => Value::UInt(vec![0; a.len()]),
+      Value::Float(a) => Value::Float(vec![0.; a.len()]),
+      Value::Bool(a) => Value::Bool(vec![false; a.len()]),
+      Value::Matrix(m) => Value::Matrix(m.iter().map(|col| vec![0.; col.len()]).collect()),
+      Value::Array(a) => Value::Array(a.iter().map(Value::zeroed).collect()),
+      Value::Block(_) => Value::Block(BTreeMap::new()),
+    }
+  }
+
+  /// Check whether the value has the given type; unsized arrays can have any length.
+  fn has_type(&self, ty: &Type) -> bool {
+    self.has_dims(&ty.prim_ty, &ty.array_dims)
+  }
+
+  fn has_dims(&self, prim_ty: &PrimType, array_dims: &[usize]) -> bool {
+    match (self, array_dims.split_first()) {
+      (Value::Array(values), Some((&dim, dims))) => {
+        (dim == 0 || dim == values.len()) && values.iter().all(|v| v.has_dims(prim_ty, dims))
+      }
+
+      (_, Some(_)) | (Value::Array(_), None) | (Value::Block(_), None) => false,
+
+      (Value::Int(a), None) => matches!(prim_ty, PrimType::Int(dim) if dim_len(dim) == a.len()),
+      (Value::UInt(a), None) => matches!(prim_ty, PrimType::UInt(dim) if dim_len(dim) == a.len()),
+      (Value::Float(a), None) => matches!(prim_ty, PrimType::Float(dim) if dim_len(dim) == a.len()),
+      (Value::Bool(a), None) => matches!(prim_ty, PrimType::Bool(dim) if dim_len(dim) == a.len()),
+      (Value::Matrix(m), None) => match prim_ty {
+        PrimType::Matrix(dim) => {
+          let (cols, rows) = matrix_dim_len(dim);
+          m.len() == cols && m.iter().all(|col| col.len() == rows)
+        }
+        _ => false,
+      },
+    }
+  }
+
+  fn as_bool(&self) -> Result<bool, InterpreterError> {
+    match self {
+      Value::Bool(a) if a.len() == 1 => Ok(a[0]),
+      _ => Err(InterpreterError::type_mismatch("boolean", self)),
+    }
+  }
+
+  // Negative indices are kept so that they are reported along with the length of the indexed array.
+  fn as_index(&self) -> Result<i64, InterpreterError> {
+    match self {
+      Value::Int(a) if a.len() == 1 => Ok(a[0] as i64),
+      Value::UInt(a) if a.len() == 1 => Ok(a[0] as i64),
+      _ => Err(InterpreterError::type_mismatch("integer", self)),
+    }
+  }
+
+  fn as_floats(&self) -> Result<&[f32], InterpreterError> {
+    match self {
+      Value::Float(a) => Ok(a),
+      _ => Err(InterpreterError::type_mismatch("floating-point", self)),
+    }
+  }
+
+  fn kind(&self) -> &'static str {
+    match self {
+      Value::Int(_) => "integer",
+      Value::UInt(_) => "unsigned integer",
+      Value::Float(_) => "floating-point",
+      Value::Bool(_) => "boolean",
+      Value::Matrix(_) => "matrix",
+      Value::Array(_) => "array",
+      Value::Block(_) => "block",
+    }
+  }
+}
+
+macro_rules! impl_Value_conv {
+  ($t:ty, $variant:ident) => {
+    impl From<$t> for Value {
+      fn from(a: $t) -> Self {
+        Value::$variant(vec![a])
+      }
+    }
+
+    impl From<V2<$t>> for Value {
+      fn from(a: V2<$t>) -> Self {
+        Value::$variant(a.0.to_vec())
+      }
+    }
+
+    impl From<V3<$t>> for Value {
+      fn from(a: V3<$t>) -> Self {
+        Value::$variant(a.0.to_vec())
+      }
+    }
+
+    impl From<V4<$t>> for Value {
+      fn from(a: V4<$t>) -> Self {
+        Value::$variant(a.0.to_vec())
+      }
+    }
+
+    impl TryFrom<Value> for $t {
+      type Error = Value;
+
+      fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+          Value::$variant(ref a) if a.len() == 1 => Ok(a[0]),
+          _ => Err(value),
+        }
+      }
+    }
+
+    impl TryFrom<Value> for V2<$t> {
+      type Error = Value;
+
+      fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+          Value::$variant(ref a) if a.len() == 2 => Ok(V2([a[0], a[1]])),
+          _ => Err(value),
+        }
+      }
+    }
+
+    impl TryFrom<Value> for V3<$t> {
+      type Error = Value;
+
+      fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+          Value::$variant(ref a) if a.len() == 3 => Ok(V3([a[0], a[1], a[2]])),
+          _ => Err(value),
+        }
+      }
+    }
+
+    impl TryFrom<Value> for V4<$t> {
+      type Error = Value;
+
+      fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+          Value::$variant(ref a) if a.len() == 4 => Ok(V4([a[0], a[1], a[2], a[3]])),
+          _ => Err(value),
+        }
+      }
+    }
+  };
+}
+
+impl_Value_conv!(i32, Int);
+impl_Value_conv!(u32, UInt);
+impl_Value_conv!(f32, Float);
+impl_Value_conv!(bool, Bool);
+
+impl<const M: usize, const N: usize> From<Matrix<[[f32; N]; M]>> for Value {
+  fn from(m: Matrix<[[f32; N]; M]>) -> Self {
+    Value::Matrix(m.0.iter().map(|col| col.to_vec()).collect())
+  }
+}
+
+impl<T, const N: usize> From<[T; N]> for Value
+where
+  T: Into<Value>,
+{
+  fn from(a: [T; N]) -> Self {
+    Value::Array(IntoIterator::into_iter(a).map(Into::into).collect())
+  }
+}
+
+impl<T> From<Vec<T>> for Value
+where
+  T: Into<Value>,
+{
+  fn from(a: Vec<T>) -> Self {
+    Value::Array(a.into_iter().map(Into::into).collect())
+  }
+}
+
+/// Errors that can occur while interpreting a shader.
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum InterpreterError {
+  /// An input declared by the shader was not given a value.
+  MissingInput(String),
+
+  /// A uniform declared by the shader was not given a value.
+  MissingUniform(String),
+
+  /// A built-in read by the shader was not given a value.
+  MissingBuiltIn(BuiltIn),
+
+  /// A value was given for an input or a uniform the shader doesn’t declare.
+  UndeclaredInterface(String),
+
+  /// The value given for an input or a uniform doesn’t have the declared type.
+  InterfaceTypeMismatch {
+    /// Name of the input or uniform.
+    name: String,
+
+    /// Declared type.
+    expected: Type,
+
+    /// Given value.
+    found: Value,
+  },
+
+  /// An expression evaluated to a value of an unexpected type.
+  TypeMismatch {
+    /// Description of the expected value.
+    expected: &'static str,
+
+    /// Description of the found value.
+    found: &'static str,
+  },
+
+  /// An array was indexed out of its bounds.
+  IndexOutOfBounds {
+    /// Index used to access the array.
+    index: i64,
+
+    /// Length of the array.
+    len: usize,
+  },
+
+  /// An integer division or remainder by zero.
+  DivisionByZero,
+
+  /// An integer division or remainder overflowing, such as `i32::MIN / -1`.
+  Overflow,
+
+  /// The invocation ran more steps than allowed by [`Invocation::max_steps`], typically because of an infinite loop.
+  StepLimitExceeded(u64),
+
+  /// An attempt to write to a read-only variable, such as an input or a uniform.
+  ReadOnly,
+
+  /// The construct cannot be interpreted on the CPU, such as derivative functions.
+  Unsupported(&'static str),
+}
+
+impl InterpreterError {
+  fn type_mismatch(expected: &'static str, found: &Value) -> Self {
+    InterpreterError::TypeMismatch {
+      expected,
+      found: found.kind(),
+    }
+  }
+}
+
+impl fmt::Display for InterpreterError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      InterpreterError::MissingInput(name) => write!(f, "missing value for input `{}`", name),
+      InterpreterError::MissingUniform(name) => write!(f, "missing value for uniform `{}`", name),
+      InterpreterError::MissingBuiltIn(builtin) => {
+        write!(f, "missing value for built-in {:?}", builtin)
+      }
+      InterpreterError::UndeclaredInterface(name) => {
+        write!(f, "`{}` is not declared by the shader", name)
+      }
+      InterpreterError::InterfaceTypeMismatch {
+        name,
+        expected,
+        found,
+      } => write!(
+        f,
+        "value of `{}` doesn’t have the declared type {:?}: {:?}",
+        name, expected, found
+      ),
+      InterpreterError::TypeMismatch { expected, found } => {
+        write!(f, "type mismatch: expected {}, found {}", expected, found)
+      }
+      InterpreterError::IndexOutOfBounds { index, len } => {
+        write!(f, "index {} out of bounds (length is {})", index, len)
+      }
+      InterpreterError::DivisionByZero => f.write_str("integer division by zero"),
+      InterpreterError::Overflow => f.write_str("integer division overflow"),
+      InterpreterError::StepLimitExceeded(max_steps) => {
+        write!(f, "invocation exceeded {} steps", max_steps)
+      }
+      InterpreterError::ReadOnly => f.write_str("cannot write to a read-only variable"),
+      InterpreterError::Unsupported(what) => write!(f, "{} cannot be interpreted", what),
+    }
+  }
+}
+
+impl std::error::Error for InterpreterError {}
+
+/// A single invocation of a [`Shader`], run on the CPU.
+///
+/// Give values to inputs, uniforms and built-ins read by the shader with [`Invocation::input`],
+/// [`Invocation::uniform`] and [`Invocation::builtin`], then run the shader with [`Invocation::run`].
+#[derive(Debug)]
+pub struct Invocation<'a> {
+  shader: &'a Shader,
+  inputs: HashMap<String, Value>,
+  uniforms: HashMap<String, Value>,
+  builtins: HashMap<BuiltIn, Value>,
+  max_steps: u64,
+}
+
+impl<'a> Invocation<'a> {
+  /// Default number of steps an invocation can run; see [`Invocation::max_steps`].
+  pub const DEFAULT_MAX_STEPS: u64 = 1_000_000;
+
+  /// Create a new [`Invocation`] of a shader.
+  pub fn new(shader: &'a Shader) -> Self {
+    Self {
+      shader,
+      inputs: HashMap::new(),
+      uniforms: HashMap::new(),
+      builtins: HashMap::new(),
+      max_steps: Self::DEFAULT_MAX_STEPS,
+    }
+  }
+
+  /// Set the value of an input.
+  pub fn input(mut self, name: impl Into<String>, value: impl Into<Value>) -> Self {
+    self.inputs.insert(name.into(), value.into());
+    self
+  }
+
+  /// Set the value of a uniform.
+  pub fn uniform(mut self, name: impl Into<String>, value: impl Into<Value>) -> Self {
+    self.uniforms.insert(name.into(), value.into());
+    self
+  }
+
+  /// Set the initial value of a built-in.
+  pub fn builtin(mut self, builtin: BuiltIn, value: impl Into<Value>) -> Self {
+    self.builtins.insert(builtin, value.into());
+    self
+  }
+
+  /// Set the number of steps the invocation can run before failing; defaults to [`Invocation::DEFAULT_MAX_STEPS`].
+  ///
+  /// A step is the execution of an instruction or the evaluation of the condition of a loop, so that a shader never
+  /// returning, such as one with an infinite loop, fails instead of hanging.
+  pub fn max_steps(mut self, max_steps: u64) -> Self {
+    self.max_steps = max_steps;
+    self
+  }
+
+  /// Run the `main` function of the shader.
+  ///
+  /// # Errors
+  ///
+  /// Fail with [`InterpreterError`] if inputs and uniforms don’t match the declarations of the shader, if the
+  /// shader reads a value that was not provided, or if it runs more steps than allowed.
+  pub fn run(&self) -> Result<Outputs, InterpreterError> {
+    let decls = &self.shader.decls;
+
+    // check inputs and uniforms against their declarations
+    for decl in decls {
+      let (name, ty, values, missing): (_, _, _, fn(String) -> InterpreterError) = match decl {
+        ShaderDecl::In(name, ty) => (name, ty, &self.inputs, InterpreterError::MissingInput),
+        ShaderDecl::Uniform(name, ty) => {
+          (name, ty, &self.uniforms, InterpreterError::MissingUniform)
+        }
+        _ => continue,
+      };
+
+      match values.get(name) {
+        None => return Err(missing(name.clone())),
+        Some(value) if !value.has_type(ty) => {
+          return Err(InterpreterError::InterfaceTypeMismatch {
+            name: name.clone(),
+            expected: ty.clone(),
+            found: value.clone(),
+          })
+        }
+        _ => (),
+      }
+    }
+
+    for name in self.inputs.keys().chain(self.uniforms.keys()) {
+      let declared = decls.iter().any(|decl| match decl {
+        ShaderDecl::In(n, _) | ShaderDecl::Uniform(n, _) => n == name,
+        _ => false,
+      });
+
+      if !declared {
+        return Err(InterpreterError::UndeclaredInterface(name.clone()));
+      }
+    }
+
+    let mut machine = Machine {
+      funs: HashMap::new(),
+      globals: HashMap::new(),
+      inputs: &self.inputs,
+      uniforms: &self.uniforms,
+      outputs: HashMap::new(),
+      builtins: self.builtins.clone(),
+      written: BTreeSet::new(),
+      steps: 0,
+      max_steps: self.max_steps,
+      calls: Vec::new(),
+    };
+    let mut main = None;
+
+    for decl in decls {
+      match decl {
+        ShaderDecl::Main(fun) => main = Some(fun),
+        ShaderDecl::FunDef(handle, fun) => {
+          machine.funs.insert(*handle, fun);
+        }
+        ShaderDecl::Const(handle, _, expr, _) => {
+          let value = machine
+            .eval(&mut Frame::default(), expr)
+            .map_err(Halt::into_error)?;
+          machine.globals.insert(*handle, value);
+        }
+        ShaderDecl::Out(name, ty, _) => {
+          machine.outputs.insert(name.clone(), Value::zero(ty));
+        }
+        _ => (),
+      }
+    }
+
+    // a shader always has a main function, as it is the only way to build it
+    let discarded = match main.map(|main| machine.exec_scope(&mut Frame::default(), &main.scope)) {
+      Some(Err(Halt::Discard)) => true,
+      Some(Err(Halt::Error(e))) => return Err(e),
+      _ => false,
+    };
+
+    let written = machine.written;
+    let builtins = machine
+      .builtins
+      .into_iter()
+      .filter(|(builtin, _)| written.contains(builtin))
+      .collect();
+
+    Ok(Outputs {
+      outputs: machine.outputs,
+      builtins,
+      discarded,
+    })
+  }
+}
+
+/// Values written by an [`Invocation`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Outputs {
+  outputs: HashMap<String, Value>,
+  builtins: HashMap<BuiltIn, Value>,
+  discarded: bool,
+}
+
+impl Outputs {
+  /// Value of an output.
+  ///
+  /// Outputs never written by the shader are zero.
+  pub fn output(&self, name: &str) -> Option<&Value> {
+    self.outputs.get(name)
+  }
+
+  /// Value of a built-in written by the shader.
+  pub fn builtin(&self, builtin: BuiltIn) -> Option<&Value> {
+    self.builtins.get(&builtin)
+  }
+
+  /// Whether the fragment was discarded.
+  pub fn is_discarded(&self) -> bool {
+    self.discarded
+  }
+}
+
+/// Evaluate an expression that doesn’t read any variable, such as literal arithmetic.
+///
+/// Returns [`None`] if the expression cannot be evaluated, for instance because it reads a variable or divides an
+/// integer by zero.
+pub(crate) fn eval_constant(expr: &ErasedExpr) -> Option<Value> {
+  let inputs = HashMap::new();
+  let uniforms = HashMap::new();
+  let mut machine = Machine {
+    funs: HashMap::new(),
+    globals: HashMap::new(),
+    inputs: &inputs,
+    uniforms: &uniforms,
+    outputs: HashMap::new(),
+    builtins: HashMap::new(),
+    written: BTreeSet::new(),
+    steps: 0,
+    max_steps: u64::MAX,
+    calls: Vec::new(),
+  };
+
+  machine.eval(&mut Frame::default(), expr).ok()
+}
+
+// Reasons for the execution to stop.
+#[derive(Debug)]
+enum Halt {
+  Discard,
+  Error(InterpreterError),
+}
+
+impl Halt {
+  fn into_error(self) -> InterpreterError {
+    match self {
+      Halt::Discard => InterpreterError::Unsupported("discard outside of a function"),
+      Halt::Error(e) => e,
+    }
+  }
+}
+
+impl From<InterpreterError> for Halt {
+  fn from(e: InterpreterError) -> Self {
+    Halt::Error(e)
+  }
+}
+
+// Control flow after an instruction.
+#[derive(Debug)]
+enum Flow {
+  Next,
+  Break,
+  Continue,
+  Return(Option<Value>),
+}
+
+// Arguments and variables of a function call.
+#[derive(Debug, Default)]
+struct Frame {
+  args: Vec<Value>,
+  vars: HashMap<(u16, u16), Value>,
+}
+
+// Path to a part of a variable, used to write to it.
+#[derive(Debug)]
+enum Step {
+  Index(i64),
+  Field(BuiltIn),
+  Swizzle(Swizzle),
+}
+
+struct Machine<'a> {
+  funs: HashMap<u16, &'a ErasedFun>,
+  globals: HashMap<u16, Value>,
+  inputs: &'a HashMap<String, Value>,
+  uniforms: &'a HashMap<String, Value>,
+  outputs: HashMap<String, Value>,
+  builtins: HashMap<BuiltIn, Value>,
+  written: BTreeSet<BuiltIn>,
+  steps: u64,
+  max_steps: u64,
+  // functions being called, innermost last
+  calls: Vec<u16>,
+}
+
+impl<'a> Machine<'a> {
+  // Account for a step, failing once the budget is exhausted.
+  fn step(&mut self) -> Result<(), Halt> {
+    if self.steps == self.max_steps {
+      return Err(InterpreterError::StepLimitExceeded(self.max_steps).into());
+    }
+
+    self.steps += 1;
+    Ok(())
+  }
+
+  // Evaluate the condition of a loop for its next iteration, which is a step on its own.
+  fn loop_condition(&mut self, frame: &mut Frame, condition: &ErasedExpr) -> Result<bool, Halt> {
+    self.step()?;
+    Ok(self.eval(frame, condition)?.as_bool()?)
+  }
+
+  fn exec_scope(&mut self, frame: &mut Frame, scope: &ErasedScope) -> Result<Flow, Halt> {
+    // whether the last if / else if condition was met, so that else if and else know whether to run
+    let mut branch_taken = false;
+
+    for instr in &scope.instructions {
+      self.step()?;
+
+      let flow = match instr {
+        ScopeInstr::VarDecl {
+          handle, init_value, ..
+        } => {
+          let value = self.eval(frame, init_value)?;
+          self.declare(frame, handle, value)?;
+          Flow::Next
+        }
+
+        ScopeInstr::Return(ErasedReturn::Void) => Flow::Return(None),
+
+        ScopeInstr::Return(ErasedReturn::Expr(_, expr)) => {
+          Flow::Return(Some(self.eval(frame, expr)?))
+        }
+
+        ScopeInstr::Continue => Flow::Continue,
+
+        ScopeInstr::Break => Flow::Break,
+
+        ScopeInstr::Discard => return Err(Halt::Discard),
+
+        ScopeInstr::If { condition, scope } => {
+          branch_taken = self.eval(frame, condition)?.as_bool()?;
+
+          if branch_taken {
+            self.exec_scope(frame, scope)?
+          } else {
+            Flow::Next
+          }
+        }
+
+        ScopeInstr::ElseIf { condition, scope } => {
+          if branch_taken {
+            Flow::Next
+          } else {
+            branch_taken = self.eval(frame, condition)?.as_bool()?;
+
+            if branch_taken {
+              self.exec_scope(frame, scope)?
+            } else {
+              Flow::Next
+            }
+          }
+        }
+
+        ScopeInstr::Else { scope } => {
+          if branch_taken {
+            Flow::Next
+          } else {
+            self.exec_scope(frame, scope)?
+          }
+        }
+
+        ScopeInstr::For {
+          init_handle,
+          init_expr,
+          condition,
+          post_expr,
+          scope,
+          ..
+        } => {
+          let init = self.eval(frame, init_expr)?;
+          self.declare(frame, init_handle, init)?;
+
+          let mut flow = Flow::Next;
+          while self.loop_condition(frame, condition)? {
+            match self.exec_scope(frame, scope)? {
+              Flow::Break => break,
+              ret @ Flow::Return(_) => {
+                flow = ret;
+                break;
+              }
+              Flow::Next | Flow::Continue => (),
+            }
+
+            let next = self.eval(frame, post_expr)?;
+            self.declare(frame, init_handle, next)?;
+          }
+
+          flow
+        }
+
+        ScopeInstr::While { condition, scope } => {
+          let mut flow = Flow::Next;
+          while self.loop_condition(frame, condition)? {
+            match self.exec_scope(frame, scope)? {
+              Flow::Break => break,
+              ret @ Flow::Return(_) => {
+                flow = ret;
+                break;
+              }
+              Flow::Next | Flow::Continue => (),
+            }
+          }
+
+          flow
+        }
+
+        ScopeInstr::MutateVar { var, expr } => {
+          let value = self.eval(frame, expr)?;
+          self.assign(frame, var, value)?;
+          Flow::Next
+        }
+      };
+
+      if !matches!(flow, Flow::Next) {
+        return Ok(flow);
+      }
+    }
+
+    Ok(Flow::Next)
+  }
+
+  fn declare(
+    &mut self,
+    frame: &mut Frame,
+    handle: &ScopedHandle,
+    value: Value,
+  ) -> Result<(), Halt> {
+    match handle {
+      ScopedHandle::FunVar { subscope, handle } => {
+        frame.vars.insert((*subscope, *handle), value);
+        Ok(())
+      }
+
+      _ => Err(InterpreterError::ReadOnly.into()),
+    }
+  }
+
+  fn call(
+    &mut self,
+    frame: &mut Frame,
+    handle: u16,
+    args: &[Arc<ErasedExpr>],
+  ) -> Result<Value, Halt> {
+    let fun = *self.funs.get(&handle).ok_or(InterpreterError::Unsupported(
+      "call to an undefined function",
+    ))?;
+
+    // recursive shaders are invalid; don’t overflow the stack on them
+    if self.calls.contains(&handle) {
+      return Err(InterpreterError::Unsupported("recursive call").into());
+    }
+
+    let mut values = Vec::with_capacity(args.len());
+    for ((arg, ty), qualifier) in args.iter().zip(&fun.args).zip(&fun.arg_qualifiers) {
+      // the initial value of out arguments is undefined; start from zero
+      let value = match qualifier {
+        ArgQualifier::Out => Value::zero(ty),
+        ArgQualifier::In | ArgQualifier::InOut => self.eval(frame, arg)?,
+      };
+      values.push(value);
+    }
+
+    let mut callee = Frame {
+      args: values,
+      vars: HashMap::new(),
+    };
+
+    self.calls.push(handle);
+    let ret = self.run_fun(&mut callee, fun);
+    self.calls.pop();
+    let ret = ret?;
+
+    // out and inout arguments are copied back to the variables of the caller once the function returns
+    for ((arg, value), qualifier) in args.iter().zip(callee.args).zip(&fun.arg_qualifiers) {
+      if *qualifier != ArgQualifier::In {
+        self.assign(frame, arg, value)?;
+      }
+    }
+
+    Ok(ret)
+  }
+
+  fn run_fun(&mut self, frame: &mut Frame, fun: &ErasedFun) -> Result<Value, Halt> {
+    let ret = match self.exec_scope(frame, &fun.scope)? {
+      Flow::Return(Some(value)) => value,
+      _ => match &fun.ret {
+        ErasedReturn::Expr(_, expr) => self.eval(frame, expr)?,
+        // void functions don’t return anything; represent it as an empty array, as it cannot be used anyway
+        ErasedReturn::Void => Value::Array(Vec::new()),
+      },
+    };
+
+    Ok(ret)
+  }
+
+  fn read_handle(&self, frame: &Frame, handle: &ScopedHandle) -> Result<Value, InterpreterError> {
+    let value = match handle {
+      ScopedHandle::BuiltIn(builtin) => self
+        .builtins
+        .get(builtin)
+        .ok_or(InterpreterError::MissingBuiltIn(*builtin))?,
+
+      ScopedHandle::Global(handle) => {
+        self
+          .globals
+          .get(handle)
+          .ok_or(InterpreterError::Unsupported(
+            "use of a constant before its declaration",
+          ))?
+      }
+
+      ScopedHandle::LibraryGlobal(..) => {
+        return Err(InterpreterError::Unsupported(
+          "use of a library constant without importing it",
+        ))
+      }
+
+      ScopedHandle::FunArg(arg) => {
+        frame
+          .args
+          .get(*arg as usize)
+          .ok_or(InterpreterError::Unsupported(
+            "use of a function argument outside of its function",
+          ))?
+      }
+
+      ScopedHandle::FunVar { subscope, handle } => {
+        frame
+          .vars
+          .get(&(*subscope, *handle))
+          .ok_or(InterpreterError::Unsupported(
+            "use of a variable outside of its scope",
+          ))?
+      }
+
+      ScopedHandle::Input(name) => self
+        .inputs
+        .get(name)
+        .ok_or_else(|| InterpreterError::MissingInput(name.clone()))?,
+
+      ScopedHandle::Output(name) => self
+        .outputs
+        .get(name)
+        .ok_or_else(|| InterpreterError::UndeclaredInterface(name.clone()))?,
+
+      ScopedHandle::Uniform(name) => self
+        .uniforms
+        .get(name)
+        .ok_or_else(|| InterpreterError::MissingUniform(name.clone()))?,
+    };
+
+    Ok(value.clone())
+  }
+
+  fn assign(&mut self, frame: &mut Frame, var: &ErasedExpr, value: Value) -> Result<(), Halt> {
+    let mut steps = Vec::new();
+    let handle = self.lvalue(frame, var, &mut steps)?;
+
+    let slot = match handle {
+      ScopedHandle::BuiltIn(builtin) => {
+        self.written.insert(*builtin);
+
+        if !self.builtins.contains_key(builtin) {
+          let placeholder = placeholder(&steps, &value)?;
+          self.builtins.insert(*builtin, placeholder);
+        }
+
+        self.builtins.get_mut(builtin).unwrap()
+      }
+
+      ScopedHandle::FunArg(arg) => {
+        frame
+          .args
+          .get_mut(*arg as usize)
+          .ok_or(InterpreterError::Unsupported(
+            "use of a function argument outside of its function",
+          ))?
+      }
+
+      ScopedHandle::FunVar { subscope, handle } => frame
+        .vars
+        .get_mut(&(*subscope, *handle))
+        .ok_or(InterpreterError::Unsupported(
+          "use of a variable outside of its scope",
+        ))?,
+
+      ScopedHandle::Output(name) => self
+        .outputs
+        .get_mut(name)
+        .ok_or_else(|| InterpreterError::UndeclaredInterface(name.clone()))?,
+
+      ScopedHandle::Global(_)
+      | ScopedHandle::LibraryGlobal(..)
+      | ScopedHandle::Input(_)
+      | ScopedHandle::Uniform(_) => return Err(InterpreterError::ReadOnly.into()),
+    };
+
+    write_path(slot, &steps, value)?;
+    Ok(())
+  }
+
+  // Resolve the variable an expression refers to, and the path to the part of it that is referred to.
+  fn lvalue<'e>(
+    &mut self,
+    frame: &mut Frame,
+    expr: &'e ErasedExpr,
+    steps: &mut Vec<Step>,
+  ) -> Result<&'e ScopedHandle, Halt> {
+    match expr {
+      ErasedExpr::Var(handle) => Ok(handle),
+
+      ErasedExpr::ArrayLookup { object, index } => {
+        let handle = self.lvalue(frame, object, steps)?;
+        let index = self.eval(frame, index)?.as_index()?;
+        steps.push(Step::Index(index));
+        Ok(handle)
+      }
+
+      ErasedExpr::Field { object, field } => {
+        let handle = self.lvalue(frame, object, steps)?;
+        steps.push(Step::Field(field_builtin(field)?));
+        Ok(handle)
+      }
+
+      ErasedExpr::Swizzle(object, sw) => {
+        let handle = self.lvalue(frame, object, steps)?;
+        steps.push(Step::Swizzle(*sw));
+        Ok(handle)
+      }
+
+      _ => Err(InterpreterError::ReadOnly.into()),
+    }
+  }
+
+  fn eval(&mut self, frame: &mut Frame, expr: &ErasedExpr) -> Result<Value, Halt> {
+    let value = match expr {
+      ErasedExpr::LitInt(x) => Value::Int(vec![*x]),
+      ErasedExpr::LitUInt(x) => Value::UInt(vec![*x]),
+      ErasedExpr::LitFloat(x) => Value::Float(vec![*x]),
+      ErasedExpr::LitBool(x) => Value::Bool(vec![*x]),
+      ErasedExpr::LitInt2(x) => Value::Int(x.to_vec()),
+      ErasedExpr::LitUInt2(x) => Value::UInt(x.to_vec()),
+      ErasedExpr::LitFloat2(x) => Value::Float(x.to_vec()),
+      ErasedExpr::LitBool2(x) => Value::Bool(x.to_vec()),
+      ErasedExpr::LitInt3(x) => Value::Int(x.to_vec()),
+      ErasedExpr::LitUInt3(x) => Value::UInt(x.to_vec()),
+      ErasedExpr::LitFloat3(x) => Value::Float(x.to_vec()),
+      ErasedExpr::LitBool3(x) => Value::Bool(x.to_vec()),
+      ErasedExpr::LitInt4(x) => Value::Int(x.to_vec()),
+      ErasedExpr::LitUInt4(x) => Value::UInt(x.to_vec()),
+      ErasedExpr::LitFloat4(x) => Value::Float(x.to_vec()),
+      ErasedExpr::LitBool4(x) => Value::Bool(x.to_vec()),
+      ErasedExpr::LitM22(m) => Value::from(m.clone()),
+      ErasedExpr::LitM33(m) => Value::from(m.clone()),
+      ErasedExpr::LitM44(m) => Value::from(m.clone()),
+
+      ErasedExpr::Array(_, items) => Value::Array(self.eval_all(frame, items)?),
+
+      ErasedExpr::Var(handle) => self.read_handle(frame, handle)?,
+
+      ErasedExpr::Not(a) => match self.eval(frame, a)? {
+        Value::Bool(a) => Value::Bool(a.into_iter().map(|a| !a).collect()),
+        a => return Err(InterpreterError::type_mismatch("boolean", &a).into()),
+      },
+
+      ErasedExpr::And(a, b) => {
+        // short-circuit evaluation
+        let a = self.eval(frame, a)?.as_bool()?;
+        Value::Bool(vec![a && self.eval(frame, b)?.as_bool()?])
+      }
+
+      ErasedExpr::Or(a, b) => {
+        let a = self.eval(frame, a)?.as_bool()?;
+        Value::Bool(vec![a || self.eval(frame, b)?.as_bool()?])
+      }
+
+      ErasedExpr::Xor(a, b) => {
+        let a = self.eval(frame, a)?.as_bool()?;
+        Value::Bool(vec![a ^ self.eval(frame, b)?.as_bool()?])
+      }
+
+      ErasedExpr::BitOr(a, b) => self.binop(frame, a, b, BinOp::BitOr)?,
+      ErasedExpr::BitAnd(a, b) => self.binop(frame, a, b, BinOp::BitAnd)?,
+      ErasedExpr::BitXor(a, b) => self.binop(frame, a, b, BinOp::BitXor)?,
+
+      ErasedExpr::Neg(a) => match self.eval(frame, a)? {
+        Value::Int(a) => Value::Int(a.into_iter().map(i32::wrapping_neg).collect()),
+        Value::UInt(a) => Value::UInt(a.into_iter().map(u32::wrapping_neg).collect()),
+        Value::Float(a) => Value::Float(a.into_iter().map(|a| -a).collect()),
+        Value::Matrix(m) => Value::Matrix(
+          m.into_iter()
+            .map(|col| col.into_iter().map(|a| -a).collect())
+            .collect(),
+        ),
+        a => return Err(InterpreterError::type_mismatch("number", &a).into()),
+      },
+
+      ErasedExpr::Add(a, b) => self.binop(frame, a, b, BinOp::Add)?,
+      ErasedExpr::Sub(a, b) => self.binop(frame, a, b, BinOp::Sub)?,
+      ErasedExpr::Mul(a, b) => {
+        let a = self.eval(frame, a)?;
+        let b = self.eval(frame, b)?;
+        mul(a, b)?
+      }
+      ErasedExpr::Div(a, b) => self.binop(frame, a, b, BinOp::Div)?,
+      ErasedExpr::Rem(a, b) => self.binop(frame, a, b, BinOp::Rem)?,
+      ErasedExpr::Shl(a, b) => self.binop(frame, a, b, BinOp::Shl)?,
+      ErasedExpr::Shr(a, b) => self.binop(frame, a, b, BinOp::Shr)?,
+
+      ErasedExpr::Eq(a, b) => {
+        let a = self.eval(frame, a)?;
+        Value::Bool(vec![a == self.eval(frame, b)?])
+      }
+
+      ErasedExpr::Neq(a, b) => {
+        let a = self.eval(frame, a)?;
+        Value::Bool(vec![a != self.eval(frame, b)?])
+      }
+
+      ErasedExpr::Lt(a, b) => self.cmp(frame, a, b, |o| o == std::cmp::Ordering::Less)?,
+      ErasedExpr::Lte(a, b) => self.cmp(frame, a, b, |o| o != std::cmp::Ordering::Greater)?,
+      ErasedExpr::Gt(a, b) => self.cmp(frame, a, b, |o| o == std::cmp::Ordering::Greater)?,
+      ErasedExpr::Gte(a, b) => self.cmp(frame, a, b, |o| o != std::cmp::Ordering::Less)?,
+
+      ErasedExpr::FunCall(ErasedFunHandle::UserDefined(handle), args) => {
+        self.call(frame, *handle, args)?
+      }
+
+      ErasedExpr::FunCall(handle, args) => {
+        let args = self.eval_all(frame, args)?;
+        call_builtin(handle, args)?
+      }
+
+      ErasedExpr::Swizzle(a, sw) => swizzle(self.eval(frame, a)?, sw)?,
+
+      ErasedExpr::Field { object, field } => {
+        let builtin = field_builtin(field)?;
+
+        match self.eval(frame, object)? {
+          Value::Block(mut fields) => fields
+            .remove(&builtin)
+            .ok_or(InterpreterError::MissingBuiltIn(builtin))?,
+          a => return Err(InterpreterError::type_mismatch("block", &a).into()),
+        }
+      }
+
+      ErasedExpr::ArrayLookup { object, index } => {
+        let object = self.eval(frame, object)?;
+        let index = self.eval(frame, index)?.as_index()?;
+
+        match object {
+          Value::Array(mut items) => {
+            let len = items.len();
+
+            match usize::try_from(index) {
+              Ok(i) if i < len => items.swap_remove(i),
+              _ => return Err(InterpreterError::IndexOutOfBounds { index, len }.into()),
+            }
+          }
+
+          a => return Err(InterpreterError::type_mismatch("array", &a).into()),
+        }
+      }
+    };
+
+    Ok(value)
+  }
+
+  fn eval_all(&mut self, frame: &mut Frame, exprs: &[Arc<ErasedExpr>]) -> Result<Vec<Value>, Halt> {
+    exprs.iter().map(|expr| self.eval(frame, expr)).collect()
+  }
+
+  fn binop(
+    &mut self,
+    frame: &mut Frame,
+    a: &ErasedExpr,
+    b: &ErasedExpr,
+    op: BinOp,
+  ) -> Result<Value, Halt> {
+    let a = self.eval(frame, a)?;
+    let b = self.eval(frame, b)?;
+    Ok(binop(a, b, op)?)
+  }
+
+  fn cmp(
+    &mut self,
+    frame: &mut Frame,
+    a: &ErasedExpr,
+    b: &ErasedExpr,
+    f: impl Fn(std::cmp::Ordering) -> bool,
+  ) -> Result<Value, Halt> {
+    let a = self.eval(frame, a)?;
+    let b = self.eval(frame, b)?;
+
+    let ordering = match (&a, &b) {
+      (Value::Int(a), Value::Int(b)) if a.len() == 1 && b.len() == 1 => a[0].partial_cmp(&b[0]),
+      (Value::UInt(a), Value::UInt(b)) if a.len() == 1 && b.len() == 1 => a[0].partial_cmp(&b[0]),
+      (Value::Float(a), Value::Float(b)) if a.len() == 1 && b.len() == 1 => a[0].partial_cmp(&b[0]),
+      _ => return Err(InterpreterError::type_mismatch("scalar", &a).into()),
+    };
+
+    // comparisons with NaN are always false
+    Ok(Value::Bool(vec![matches!(ordering, Some(o) if f(o))]))
+  }
+}
+
+// Built-in of a field expression.
+fn field_builtin(field: &ErasedExpr) -> Result<BuiltIn, InterpreterError> {
+  match field {
+    ErasedExpr::Var(ScopedHandle::BuiltIn(builtin)) => Ok(*builtin),
+    _ => Err(InterpreterError::Unsupported("field of a non-built-in")),
+  }
+}
+
+// Value to create when writing through a path to a variable that doesn’t exist yet.
+fn placeholder(steps: &[Step], value: &Value) -> Result<Value, InterpreterError> {
+  match steps.first() {
+    None => Ok(value.zeroed()),
+    Some(Step::Index(_)) => Ok(Value::Array(Vec::new())),
+    Some(Step::Field(_)) => Ok(Value::Block(BTreeMap::new())),
+    Some(Step::Swizzle(_)) => Err(InterpreterError::Unsupported(
+      "swizzled write to an uninitialized variable",
+    )),
+  }
+}
+
+fn write_path(slot: &mut Value, steps: &[Step], value: Value) -> Result<(), InterpreterError> {
+  let (step, rest) = match steps.split_first() {
+    None => {
+      *slot = value;
+      return Ok(());
+    }
+
+    Some(split) => split,
+  };
+
+  match (step, slot) {
+    (Step::Index(index), Value::Array(items)) => {
+      let i = usize::try_from(*index).map_err(|_| InterpreterError::IndexOutOfBounds {
+        index: *index,
+        len: items.len(),
+      })?;
+
+      // unsized arrays, such as clip distances, grow as they are written to
+      if i >= items.len() {
+        let filler = placeholder(rest, &value)?;
+        items.resize(i + 1, filler);
+      }
+
+      write_path(&mut items[i], rest, value)
+    }
+
+    (Step::Field(builtin), Value::Block(fields)) => {
+      if !fields.contains_key(builtin) {
+        fields.insert(*builtin, placeholder(rest, &value)?);
+      }
+
+      write_path(fields.get_mut(builtin).unwrap(), rest, value)
+    }
+
+    (Step::Swizzle(sw), slot) if rest.is_empty() => {
+      let selectors = swizzle_selectors(sw);
+
+      macro_rules! write_components {
+        ($a:ident, $b:ident) => {{
+          if $b.len() != selectors.len() {
+            return Err(InterpreterError::type_mismatch("vector", &value));
+          }
+
+          for (sel, x) in selectors.iter().zip($b) {
+            let len = $a.len();
+            *$a.get_mut(*sel).ok_or(InterpreterError::IndexOutOfBounds {
+              index: *sel as i64,
+              len,
+            })? = *x;
+          }
+
+          Ok(())
+        }};
+      }
+
+      match (slot, &value) {
+        (Value::Int(a), Value::Int(b)) => write_components!(a, b),
+        (Value::UInt(a), Value::UInt(b)) => write_components!(a, b),
+        (Value::Float(a), Value::Float(b)) => write_components!(a, b),
+        (Value::Bool(a), Value::Bool(b)) => write_components!(a, b),
+        (slot, _) => Err(InterpreterError::type_mismatch("vector", slot)),
+      }
+    }
+
+    (_, slot) => Err(InterpreterError::type_mismatch(
+      "array, block or vector",
+      slot,
+    )),
+  }
+}
+
+fn swizzle_selectors(sw: &Swizzle) -> Vec<usize> {
+  fn index(sel: &SwizzleSelector) -> usize {
+    match sel {
+      SwizzleSelector::X => 0,
+      SwizzleSelector::Y => 1,
+      SwizzleSelector::Z => 2,
+      SwizzleSelector::W => 3,
+    }
+  }
+
+  match sw {
+    Swizzle::D1(a) => vec![index(a)],
+    Swizzle::D2(a, b) => vec![index(a), index(b)],
+    Swizzle::D3(a, b, c) => vec![index(a), index(b), index(c)],
+    Swizzle::D4(a, b, c, d) => vec![index(a), index(b), index(c), index(d)],
+  }
+}
+
+fn swizzle(value: Value, sw: &Swizzle) -> Result<Value, InterpreterError> {
+  let selectors = swizzle_selectors(sw);
+
+  macro_rules! select {
+    ($variant:ident, $a:ident) => {{
+      let components = selectors
+        .iter()
+        .map(|&sel| {
+          $a.get(sel)
+            .copied()
+            .ok_or(InterpreterError::IndexOutOfBounds {
+              index: sel as i64,
+              len: $a.len(),
+            })
+        })
+        .collect::<Result<_, _>>()?;
+      Ok(Value::$variant(components))
+    }};
+  }
+
+  match &value {
+    Value::Int(a) => select!(Int, a),
+    Value::UInt(a) => select!(UInt, a),
+    Value::Float(a) => select!(Float, a),
+    Value::Bool(a) => select!(Bool, a),
+    _ => Err(InterpreterError::type_mismatch("vector", &value)),
+  }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum BinOp {
+  Add,
+  Sub,
+  Div,
+  Rem,
+  BitOr,
+  BitAnd,
+  BitXor,
+  Shl,
+  Shr,
+}
+
+// Apply a function component-wise, broadcasting scalars.
+fn zip_with<A, B, C>(
+  a: &[A],
+  b: &[B],
+  f: impl Fn(A, B) -> Result<C, InterpreterError>,
+) -> Result<Vec<C>, InterpreterError>
+where
+  A: Copy,
+  B: Copy,
+{
+  match (a.len(), b.len()) {
+    (n, m) if n == m => a.iter().zip(b).map(|(&a, &b)| f(a, b)).collect(),
+    (_, 1) => a.iter().map(|&a| f(a, b[0])).collect(),
+    (1, _) => b.iter().map(|&b| f(a[0], b)).collect(),
+    _ => Err(InterpreterError::TypeMismatch {
+      expected: "vectors of the same dimension",
+      found: "vectors of different dimensions",
+    }),
+  }
+}
+
+fn binop(a: Value, b: Value, op: BinOp) -> Result<Value, InterpreterError> {
+  match (a, b) {
+    (Value::Int(a), Value::Int(b)) => Ok(Value::Int(zip_with(&a, &b, |a, b| match op {
+      BinOp::Add => Ok(a.wrapping_add(b)),
+      BinOp::Sub => Ok(a.wrapping_sub(b)),
+      BinOp::Div | BinOp::Rem if b == 0 => Err(InterpreterError::DivisionByZero),
+      BinOp::Div => a.checked_div(b).ok_or(InterpreterError::Overflow),
+      BinOp::Rem => a.checked_rem(b).ok_or(InterpreterError::Overflow),
+      BinOp::BitOr => Ok(a | b),
+      BinOp::BitAnd => Ok(a & b),
+      BinOp::BitXor => Ok(a ^ b),
+      BinOp::Shl => Ok(a.wrapping_shl(b as u32)),
+      BinOp::Shr => Ok(a.wrapping_shr(b as u32)),
+    })?)),
+
+    (Value::UInt(a), Value::UInt(b)) => Ok(Value::UInt(zip_with(&a, &b, |a, b| match op {
+      BinOp::Add => Ok(a.wrapping_add(b)),
+      BinOp::Sub => Ok(a.wrapping_sub(b)),
+      BinOp::Div | BinOp::Rem if b == 0 => Err(InterpreterError::DivisionByZero),
+      BinOp::Div => a.checked_div(b).ok_or(InterpreterError::Overflow),
+      BinOp::Rem => a.checked_rem(b).ok_or(InterpreterError::Overflow),
+      BinOp::BitOr => Ok(a | b),
+      BinOp::BitAnd => Ok(a & b),
+      BinOp::BitXor => Ok(a ^ b),
+      BinOp::Shl => Ok(a.wrapping_shl(b)),
+      BinOp::Shr => Ok(a.wrapping_shr(b)),
+    })?)),
+
+    (Value::Float(a), Value::Float(b)) => Ok(Value::Float(zip_with(&a, &b, |a, b| match op {
+      BinOp::Add => Ok(a + b),
+      BinOp::Sub => Ok(a - b),
+      BinOp::Div => Ok(a / b),
+      // GLSL’s mod
+      BinOp::Rem => Ok(a - b * (a / b).floor()),
+      _ => Err(InterpreterError::TypeMismatch {
+        expected: "integer",
+        found: "floating-point",
+      }),
+    })?)),
+
+    (Value::Bool(a), Value::Bool(b)) => Ok(Value::Bool(zip_with(&a, &b, |a, b| match op {
+      BinOp::BitOr => Ok(a | b),
+      BinOp::BitAnd => Ok(a & b),
+      BinOp::BitXor => Ok(a ^ b),
+      _ => Err(InterpreterError::TypeMismatch {
+        expected: "number",
+        found: "boolean",
+      }),
+    })?)),
+
+    (Value::Matrix(a), Value::Matrix(b)) if a.len() == b.len() => Ok(Value::Matrix(
+      a.iter()
+        .zip(&b)
+        .map(|(a, b)| float_binop(a, b, op))
+        .collect::<Result<_, _>>()?,
+    )),
+
+    (Value::Matrix(a), Value::Float(b)) if b.len() == 1 => Ok(Value::Matrix(
+      a.iter()
+        .map(|a| float_binop(a, &b, op))
+        .collect::<Result<_, _>>()?,
+    )),
+
+    (Value::Float(a), Value::Matrix(b)) if a.len() == 1 => Ok(Value::Matrix(
+      b.iter()
+        .map(|b| float_binop(&a, b, op))
+        .collect::<Result<_, _>>()?,
+    )),
+
+    (a, _) => Err(InterpreterError::type_mismatch(
+      "operands of the same type",
+      &a,
+    )),
+  }
+}
+
+fn float_binop(a: &[f32], b: &[f32], op: BinOp) -> Result<Vec<f32>, InterpreterError> {
+  match binop(Value::Float(a.to_vec()), Value::Float(b.to_vec()), op)? {
+    Value::Float(c) => Ok(c),
+    c => Err(InterpreterError::type_mismatch("floating-point", &c)),
+  }
+}
+
+// Multiplication, which is the linear algebraic product for matrices.
+fn mul(a: Value, b: Value) -> Result<Value, InterpreterError> {
+  match (a, b) {
+    (Value::Matrix(a), Value::Matrix(b)) => {
+      // (a * b)[col][row] = sum_k a[k][row] * b[col][k]
+      let rows = a.first().map_or(0, Vec::len);
+      let product = b
+        .iter()
+        .map(|b_col| (0..rows).map(|row| dot_col(&a, b_col, row)).collect())
+        .collect();
+      Ok(Value::Matrix(product))
+    }
+
+    (Value::Matrix(a), Value::Float(v)) if v.len() > 1 => {
+      let rows = a.first().map_or(0, Vec::len);
+      Ok(Value::Float(
+        (0..rows).map(|row| dot_col(&a, &v, row)).collect(),
+      ))
+    }
+
+    (Value::Float(v), Value::Matrix(b)) if v.len() > 1 => Ok(Value::Float(
+      b.iter()
+        .map(|col| col.iter().zip(&v).map(|(a, b)| a * b).sum())
+        .collect(),
+    )),
+
+    (Value::Int(a), Value::Int(b)) => {
+      Ok(Value::Int(zip_with(&a, &b, |a, b| Ok(a.wrapping_mul(b)))?))
+    }
+    (Value::UInt(a), Value::UInt(b)) => {
+      Ok(Value::UInt(zip_with(&a, &b, |a, b| Ok(a.wrapping_mul(b)))?))
+    }
+    (Value::Float(a), Value::Float(b)) => Ok(Value::Float(zip_with(&a, &b, |a, b| Ok(a * b))?)),
+
+    (Value::Matrix(m), Value::Float(s)) | (Value::Float(s), Value::Matrix(m)) => Ok(Value::Matrix(
+      m.into_iter()
+        .map(|col| col.into_iter().map(|a| a * s[0]).collect())
+        .collect(),
+    )),
+
+    (a, _) => Err(InterpreterError::type_mismatch(
+      "operands of the same type",
+      &a,
+    )),
+  }
+}
+
+// Dot product of a row of a matrix with a vector.
+fn dot_col(m: &[Vec<f32>], v: &[f32], row: usize) -> f32 {
+  m.iter().zip(v).map(|(col, x)| col[row] * x).sum()
+}
+
+fn map_float(value: &Value, f: impl Fn(f32) -> f32) -> Result<Value, InterpreterError> {
+  Ok(Value::Float(
+    value.as_floats()?.iter().map(|&x| f(x)).collect(),
+  ))
+}
+
+fn zip_float(a: &Value, b: &Value, f: impl Fn(f32, f32) -> f32) -> Result<Value, InterpreterError> {
+  Ok(Value::Float(zip_with(
+    a.as_floats()?,
+    b.as_floats()?,
+    |a, b| Ok(f(a, b)),
+  )?))
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+  a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+fn round_even(x: f32) -> f32 {
+  let r = x.round();
+
+  if (x - x.trunc()).abs() == 0.5 && r % 2. != 0. {
+    r - x.signum()
+  } else {
+    r
+  }
+}
+
+fn call_builtin(handle: &ErasedFunHandle, args: Vec<Value>) -> Result<Value, InterpreterError> {
+  let arg = |i: usize| {
+    args
+      .get(i)
+      .ok_or(InterpreterError::Unsupported("call with missing arguments"))
+  };
+
+  match handle {
+    ErasedFunHandle::Vec2 | ErasedFunHandle::Vec3 | ErasedFunHandle::Vec4 => {
+      let mut iter = args.iter();
+      let first = iter
+        .next()
+        .ok_or(InterpreterError::Unsupported("call with missing arguments"))?
+        .clone();
+
+      iter.try_fold(first, |acc, v| match (acc, v) {
+        (Value::Int(mut a), Value::Int(b)) => {
+          a.extend(b);
+          Ok(Value::Int(a))
+        }
+        (Value::UInt(mut a), Value::UInt(b)) => {
+          a.extend(b);
+          Ok(Value::UInt(a))
+        }
+        (Value::Float(mut a), Value::Float(b)) => {
+          a.extend(b);
+          Ok(Value::Float(a))
+        }
+        (Value::Bool(mut a), Value::Bool(b)) => {
+          a.extend(b);
+          Ok(Value::Bool(a))
+        }
+        (a, _) => Err(InterpreterError::type_mismatch(
+          "components of the same type",
+          &a,
+        )),
+      })
+    }
+
+    ErasedFunHandle::Radians => map_float(arg(0)?, f32::to_radians),
+    ErasedFunHandle::Degrees => map_float(arg(0)?, f32::to_degrees),
+    ErasedFunHandle::Sin => map_float(arg(0)?, f32::sin),
+    ErasedFunHandle::Cos => map_float(arg(0)?, f32::cos),
+    ErasedFunHandle::Tan => map_float(arg(0)?, f32::tan),
+    ErasedFunHandle::ASin => map_float(arg(0)?, f32::asin),
+    ErasedFunHandle::ACos => map_float(arg(0)?, f32::acos),
+    ErasedFunHandle::ATan => match args.len() {
+      1 => map_float(arg(0)?, f32::atan),
+      _ => zip_float(arg(0)?, arg(1)?, f32::atan2),
+    },
+    ErasedFunHandle::SinH => map_float(arg(0)?, f32::sinh),
+    ErasedFunHandle::CosH => map_float(arg(0)?, f32::cosh),
+    ErasedFunHandle::TanH => map_float(arg(0)?, f32::tanh),
+    ErasedFunHandle::ASinH => map_float(arg(0)?, f32::asinh),
+    ErasedFunHandle::ACosH => map_float(arg(0)?, f32::acosh),
+    ErasedFunHandle::ATanH => map_float(arg(0)?, f32::atanh),
+
+    ErasedFunHandle::Pow => zip_float(arg(0)?, arg(1)?, f32::powf),
+    ErasedFunHandle::Exp => map_float(arg(0)?, f32::exp),
+    ErasedFunHandle::Exp2 => map_float(arg(0)?, f32::exp2),
+    ErasedFunHandle::Log => map_float(arg(0)?, f32::ln),
+    ErasedFunHandle::Log2 => map_float(arg(0)?, f32::log2),
+    ErasedFunHandle::Sqrt => map_float(arg(0)?, f32::sqrt),
+    ErasedFunHandle::InverseSqrt => map_float(arg(0)?, |x| 1. / x.sqrt()),
+
+    ErasedFunHandle::Abs => match arg(0)? {
+      Value::Int(a) => Ok(Value::Int(a.iter().map(|x| x.wrapping_abs()).collect())),
+      a => map_float(a, f32::abs),
+    },
+    ErasedFunHandle::Sign => match arg(0)? {
+      Value::Int(a) => Ok(Value::Int(a.iter().map(|x| x.signum()).collect())),
+      a => map_float(a, |x| if x == 0. { 0. } else { x.signum() }),
+    },
+    ErasedFunHandle::Floor => map_float(arg(0)?, f32::floor),
+    ErasedFunHandle::Trunc => map_float(arg(0)?, f32::trunc),
+    ErasedFunHandle::Round => map_float(arg(0)?, f32::round),
+    ErasedFunHandle::RoundEven => map_float(arg(0)?, round_even),
+    ErasedFunHandle::Ceil => map_float(arg(0)?, f32::ceil),
+    ErasedFunHandle::Fract => map_float(arg(0)?, |x| x - x.floor()),
+
+    ErasedFunHandle::Min => match (arg(0)?, arg(1)?) {
+      (Value::Int(a), Value::Int(b)) => Ok(Value::Int(zip_with(a, b, |a, b| Ok(a.min(b)))?)),
+      (Value::UInt(a), Value::UInt(b)) => Ok(Value::UInt(zip_with(a, b, |a, b| Ok(a.min(b)))?)),
+      (a, b) => zip_float(a, b, f32::min),
+    },
+    ErasedFunHandle::Max => match (arg(0)?, arg(1)?) {
+      (Value::Int(a), Value::Int(b)) => Ok(Value::Int(zip_with(a, b, |a, b| Ok(a.max(b)))?)),
+      (Value::UInt(a), Value::UInt(b)) => Ok(Value::UInt(zip_with(a, b, |a, b| Ok(a.max(b)))?)),
+      (a, b) => zip_float(a, b, f32::max),
+    },
+    ErasedFunHandle::Clamp => {
+      let lower = call_builtin(
+        &ErasedFunHandle::Max,
+        vec![arg(0)?.clone(), arg(1)?.clone()],
+      )?;
+      call_builtin(&ErasedFunHandle::Min, vec![lower, arg(2)?.clone()])
+    }
+    ErasedFunHandle::Mix => match arg(2)? {
+      Value::Bool(a) => {
+        let x = arg(0)?.as_floats()?;
+        let y = arg(1)?.as_floats()?;
+        let xy = zip_with(x, y, |x, y| Ok((x, y)))?;
+        Ok(Value::Float(zip_with(&xy, a, |(x, y), a| {
+          Ok(if a { y } else { x })
+        })?))
+      }
+      a => {
+        let x = arg(0)?.as_floats()?;
+        let y = arg(1)?.as_floats()?;
+        let xy = zip_with(x, y, |x, y| Ok((x, y)))?;
+        Ok(Value::Float(zip_with(&xy, a.as_floats()?, |(x, y), a| {
+          Ok(x * (1. - a) + y * a)
+        })?))
+      }
+    },
+    ErasedFunHandle::Step => zip_float(arg(0)?, arg(1)?, |edge, x| if x < edge { 0. } else { 1. }),
+    ErasedFunHandle::SmoothStep => {
+      let edges = zip_with(arg(0)?.as_floats()?, arg(1)?.as_floats()?, |a, b| {
+        Ok((a, b))
+      })?;
+      Ok(Value::Float(zip_with(
+        &edges,
+        arg(2)?.as_floats()?,
+        |(a, b), x| {
+          let t = ((x - a) / (b - a)).clamp(0., 1.);
+          Ok(t * t * (3. - 2. * t))
+        },
+      )?))
+    }
+    ErasedFunHandle::IsNan => Ok(Value::Bool(
+      arg(0)?.as_floats()?.iter().map(|x| x.is_nan()).collect(),
+    )),
+    ErasedFunHandle::IsInf => Ok(Value::Bool(
+      arg(0)?
+        .as_floats()?
+        .iter()
+        .map(|x| x.is_infinite())
+        .collect(),
+    )),
+    ErasedFunHandle::FloatBitsToInt => Ok(Value::Int(
+      arg(0)?
+        .as_floats()?
+        .iter()
+        .map(|x| x.to_bits() as i32)
+        .collect(),
+    )),
+    ErasedFunHandle::IntBitsToFloat => match arg(0)? {
+      Value::Int(a) => Ok(Value::Float(
+        a.iter().map(|&x| f32::from_bits(x as u32)).collect(),
+      )),
+      a => Err(InterpreterError::type_mismatch("integer", a)),
+    },
+    ErasedFunHandle::UIntBitsToFloat => match arg(0)? {
+      Value::UInt(a) => Ok(Value::Float(a.iter().map(|&x| f32::from_bits(x)).collect())),
+      a => Err(InterpreterError::type_mismatch("unsigned integer", a)),
+    },
+    ErasedFunHandle::FMA => {
+      let ab = zip_float(arg(0)?, arg(1)?, |a, b| a * b)?;
+      zip_float(&ab, arg(2)?, |ab, c| ab + c)
+    }
+
+    ErasedFunHandle::Length => {
+      let a = arg(0)?.as_floats()?;
+      Ok(Value::Float(vec![dot(a, a).sqrt()]))
+    }
+    ErasedFunHandle::Distance => {
+      let d = zip_float(arg(0)?, arg(1)?, |a, b| a - b)?;
+      call_builtin(&ErasedFunHandle::Length, vec![d])
+    }
+    ErasedFunHandle::Dot => Ok(Value::Float(vec![dot(
+      arg(0)?.as_floats()?,
+      arg(1)?.as_floats()?,
+    )])),
+    ErasedFunHandle::Cross => match (arg(0)?.as_floats()?, arg(1)?.as_floats()?) {
+      (&[ax, ay, az], &[bx, by, bz]) => Ok(Value::Float(vec![
+        ay * bz - az * by,
+        az * bx - ax * bz,
+        ax * by - ay * bx,
+      ])),
+      _ => Err(InterpreterError::type_mismatch("3D vector", arg(0)?)),
+    },
+    ErasedFunHandle::Normalize => {
+      let a = arg(0)?.as_floats()?;
+      let len = dot(a, a).sqrt();
+      Ok(Value::Float(a.iter().map(|x| x / len).collect()))
+    }
+    ErasedFunHandle::FaceForward => {
+      // faceforward(N, I, Nref)
+      let n = arg(0)?.as_floats()?;
+      let i = arg(1)?.as_floats()?;
+      let n_ref = arg(2)?.as_floats()?;
+
+      if dot(n_ref, i) < 0. {
+        Ok(Value::Float(n.to_vec()))
+      } else {
+        Ok(Value::Float(n.iter().map(|x| -x).collect()))
+      }
+    }
+    ErasedFunHandle::Reflect => {
+      // reflect(I, N)
+      let i = arg(0)?.as_floats()?;
+      let n = arg(1)?.as_floats()?;
+      let d = 2. * dot(n, i);
+      Ok(Value::Float(
+        i.iter().zip(n).map(|(i, n)| i - d * n).collect(),
+      ))
+    }
+    ErasedFunHandle::Refract => {
+      // refract(I, N, eta)
+      let i = arg(0)?.as_floats()?;
+      let n = arg(1)?.as_floats()?;
+      let eta = arg(2)?.as_floats()?[0];
+      let d = dot(n, i);
+      let k = 1. - eta * eta * (1. - d * d);
+
+      if k < 0. {
+        Ok(Value::Float(vec![0.; i.len()]))
+      } else {
+        let s = eta * d + k.sqrt();
+        Ok(Value::Float(
+          i.iter().zip(n).map(|(i, n)| eta * i - s * n).collect(),
+        ))
+      }
+    }
+
+    ErasedFunHandle::VLt
+    | ErasedFunHandle::VLte
+    | ErasedFunHandle::VGt
+    | ErasedFunHandle::VGte
+    | ErasedFunHandle::VEq
+    | ErasedFunHandle::VNeq => {
+      use std::cmp::Ordering;
+
+      let test = |o: Option<Ordering>| match handle {
+        ErasedFunHandle::VLt => o == Some(Ordering::Less),
+        ErasedFunHandle::VLte => matches!(o, Some(Ordering::Less) | Some(Ordering::Equal)),
+        ErasedFunHandle::VGt => o == Some(Ordering::Greater),
+        ErasedFunHandle::VGte => matches!(o, Some(Ordering::Greater) | Some(Ordering::Equal)),
+        ErasedFunHandle::VEq => o == Some(Ordering::Equal),
+        _ => o != Some(Ordering::Equal),
+      };
+
+      let result = match (arg(0)?, arg(1)?) {
+        (Value::Int(a), Value::Int(b)) => zip_with(a, b, |a, b| Ok(test(a.partial_cmp(&b))))?,
+        (Value::UInt(a), Value::UInt(b)) => zip_with(a, b, |a, b| Ok(test(a.partial_cmp(&b))))?,
+        (Value::Float(a), Value::Float(b)) => zip_with(a, b, |a, b| Ok(test(a.partial_cmp(&b))))?,
+        (Value::Bool(a), Value::Bool(b)) => zip_with(a, b, |a, b| Ok(test(a.partial_cmp(&b))))?,
+        (a, _) => return Err(InterpreterError::type_mismatch("vector", a)),
+      };
+
+      Ok(Value::Bool(result))
+    }
+    ErasedFunHandle::VAny => match arg(0)? {
+      Value::Bool(a) => Ok(Value::Bool(vec![a.iter().any(|&x| x)])),
+      a => Err(InterpreterError::type_mismatch("boolean", a)),
+    },
+    ErasedFunHandle::VAll => match arg(0)? {
+      Value::Bool(a) => Ok(Value::Bool(vec![a.iter().all(|&x| x)])),
+      a => Err(InterpreterError::type_mismatch("boolean", a)),
+    },
+    ErasedFunHandle::VNot => match arg(0)? {
+      Value::Bool(a) => Ok(Value::Bool(a.iter().map(|&x| !x).collect())),
+      a => Err(InterpreterError::type_mismatch("boolean", a)),
+    },
+
+    ErasedFunHandle::BitfieldReverse => match arg(0)? {
+      Value::Int(a) => Ok(Value::Int(a.iter().map(|x| x.reverse_bits()).collect())),
+      Value::UInt(a) => Ok(Value::UInt(a.iter().map(|x| x.reverse_bits()).collect())),
+      a => Err(InterpreterError::type_mismatch("integer", a)),
+    },
+    ErasedFunHandle::BitCount => match arg(0)? {
+      Value::Int(a) => Ok(Value::Int(
+        a.iter().map(|x| x.count_ones() as i32).collect(),
+      )),
+      Value::UInt(a) => Ok(Value::Int(
+        a.iter().map(|x| x.count_ones() as i32).collect(),
+      )),
+      a => Err(InterpreterError::type_mismatch("integer", a)),
+    },
+    ErasedFunHandle::FindLSB => {
+      let lsb = |x: u32| {
+        if x == 0 {
+          -1
+        } else {
+          x.trailing_zeros() as i32
+        }
+      };
+
+      match arg(0)? {
+        Value::Int(a) => Ok(Value::Int(a.iter().map(|&x| lsb(x as u32)).collect())),
+        Value::UInt(a) => Ok(Value::Int(a.iter().map(|&x| lsb(x)).collect())),
+        a => Err(InterpreterError::type_mismatch("integer", a)),
+      }
+    }
+    ErasedFunHandle::FindMSB => {
+      let msb = |x: u32| {
+        if x == 0 {
+          -1
+        } else {
+          31 - x.leading_zeros() as i32
+        }
+      };
+
+      match arg(0)? {
+        // for negative integers, the most significant bit set to 0 is looked for
+        Value::Int(a) => Ok(Value::Int(
+          a.iter()
+            .map(|&x| msb(if x < 0 { !x as u32 } else { x as u32 }))
+            .collect(),
+        )),
+        Value::UInt(a) => Ok(Value::Int(a.iter().map(|&x| msb(x)).collect())),
+        a => Err(InterpreterError::type_mismatch("integer", a)),
+      }
+    }
+
+    ErasedFunHandle::Frexp | ErasedFunHandle::Ldexp => {
+      Err(InterpreterError::Unsupported("frexp and ldexp"))
+    }
+
+    ErasedFunHandle::PackUnorm2x16
+    | ErasedFunHandle::PackSnorm2x16
+    | ErasedFunHandle::PackUnorm4x8
+    | ErasedFunHandle::PackSnorm4x8
+    | ErasedFunHandle::UnpackUnorm2x16
+    | ErasedFunHandle::UnpackSnorm2x16
+    | ErasedFunHandle::UnpackUnorm4x8
+    | ErasedFunHandle::UnpackSnorm4x8
+    | ErasedFunHandle::PackHalf2x16
+    | ErasedFunHandle::UnpackHalf2x16 => Err(InterpreterError::Unsupported("packing functions")),
+
+    ErasedFunHandle::UAddCarry
+    | ErasedFunHandle::USubBorrow
+    | ErasedFunHandle::UMulExtended
+    | ErasedFunHandle::IMulExtended
+    | ErasedFunHandle::BitfieldExtract
+    | ErasedFunHandle::BitfieldInsert => {
+      Err(InterpreterError::Unsupported("extended integer functions"))
+    }
+
+    ErasedFunHandle::EmitStreamVertex
+    | ErasedFunHandle::EndStreamPrimitive
+    | ErasedFunHandle::EmitVertex
+    | ErasedFunHandle::EndPrimitive => {
+      Err(InterpreterError::Unsupported("geometry shader functions"))
+    }
+
+    ErasedFunHandle::DFDX
+    | ErasedFunHandle::DFDY
+    | ErasedFunHandle::DFDXFine
+    | ErasedFunHandle::DFDYFine
+    | ErasedFunHandle::DFDXCoarse
+    | ErasedFunHandle::DFDYCoarse
+    | ErasedFunHandle::FWidth
+    | ErasedFunHandle::FWidthFine
+    | ErasedFunHandle::FWidthCoarse
+    | ErasedFunHandle::InterpolateAtCentroid
+    | ErasedFunHandle::InterpolateAtSample
+    | ErasedFunHandle::InterpolateAtOffset => Err(InterpreterError::Unsupported(
+      "fragment processing functions",
+    )),
+
+    ErasedFunHandle::Barrier
+    | ErasedFunHandle::MemoryBarrier
+    | ErasedFunHandle::MemoryBarrierAtomic
+    | ErasedFunHandle::MemoryBarrierBuffer
+    | ErasedFunHandle::MemoryBarrierShared
+    | ErasedFunHandle::MemoryBarrierImage
+    | ErasedFunHandle::GroupMemoryBarrier
+    | ErasedFunHandle::AnyInvocation
+    | ErasedFunHandle::AllInvocations
+    | ErasedFunHandle::AllInvocationsEqual => {
+      Err(InterpreterError::Unsupported("invocation functions"))
+    }
+
+    ErasedFunHandle::UserDefined(_) => Err(InterpreterError::Unsupported("user-defined function")),
+
+    ErasedFunHandle::Library(..) => Err(InterpreterError::Unsupported(
+      "call to a library function without importing it",
+    )),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    lit, CanEscape as _, Expr, Floating as _, FragmentBuiltIn, Geometry as _, InOut, Out, Scope,
+    ShaderBuilder, TessCtrlBuiltIn, VertexBuiltIn, M22,
+  };
+
+  #[test]
+  fn vertex_position() {
+    let shader = ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+      let position = s.input::<V3<f32>>("position").unwrap();
+      let color = s.output::<V3<f32>>("color").unwrap();
+      let scale = s.uniform::<f32>("scale").unwrap();
+      let double = s.fun(|_: &mut Scope<Expr<f32>>, a: Expr<f32>| a * 2.);
+
+      s.main_fun(|s: &mut Scope<()>| {
+        let p = s.var(position.clone() * scale.clone());
+        s.set(
+          &vertex.position,
+          crate::vec4!(p.clone(), double.call(crate::lit!(1.))),
+        );
+        s.when(vertex.vertex_id.eq(1), |s| s.set(&color, p.normalize()));
+      })
+    });
+
+    let outputs = Invocation::new(&shader)
+      .input("position", V3::from([1., 0., 0.]))
+      .uniform("scale", 2.)
+      .builtin(BuiltIn::Vertex(VertexBuiltIn::VertexID), 1)
+      .run()
+      .unwrap();
+
+    assert_eq!(
+      outputs.builtin(BuiltIn::Vertex(VertexBuiltIn::Position)),
+      Some(&Value::from(V4::from([2., 0., 0., 2.])))
+    );
+    assert_eq!(
+      outputs.output("color"),
+      Some(&Value::from(V3::from([1., 0., 0.])))
+    );
+    assert_eq!(
+      outputs.builtin(BuiltIn::Vertex(VertexBuiltIn::VertexID)),
+      None
+    );
+  }
+
+  #[test]
+  fn loops_and_conditions() {
+    let shader = ShaderBuilder::new_fragment_shader(|mut s, _| {
+      let n = s.uniform::<i32>("n").unwrap();
+      let out_sum = s.output::<i32>("sum").unwrap();
+      let size = s.output::<i32>("size").unwrap();
+
+      s.main_fun(|s: &mut Scope<()>| {
+        let sum = s.var(0);
+        s.loop_for(
+          0,
+          |i| i.lt(&n),
+          |i| i + 1,
+          |s, i| {
+            s.when(i.eq(3), |s| s.loop_continue());
+            s.set(&sum, sum.clone() + i);
+          },
+        );
+
+        s.set(&out_sum, &sum);
+        s.when(sum.lt(5), |s| s.set(&size, 0))
+          .or_else(sum.lt(10), |s| s.set(&size, 1))
+          .or(|s| s.set(&size, 2));
+      })
+    });
+
+    let outputs = Invocation::new(&shader).uniform("n", 5).run().unwrap();
+
+    // 0 + 1 + 2 + 4
+    assert_eq!(outputs.output("sum"), Some(&Value::from(7)));
+    assert_eq!(outputs.output("size"), Some(&Value::from(1)));
+  }
+
+  #[test]
+  fn arguments_passed_by_reference() {
+    let shader = ShaderBuilder::new_fragment_shader(|mut s, _| {
+      let out_integer = s.output::<f32>("integer").unwrap();
+      let out_fract = s.output::<f32>("fract").unwrap();
+      let total = s.output::<f32>("total").unwrap();
+
+      let split = s.fun(
+        |s: &mut Scope<Expr<f32>>, x: Expr<f32>, integer: Out<f32>, fract: Out<f32>| {
+          s.set(&integer, x.floor());
+          s.set(&fract, x.fract());
+          x
+        },
+      );
+      let accumulate = s.fun(|s: &mut Scope<Expr<f32>>, acc: InOut<f32>, x: Expr<f32>| {
+        s.set(&acc, acc.to_expr() + x);
+        acc.to_expr()
+      });
+
+      s.main_fun(|s: &mut Scope<()>| {
+        let acc = s.var(lit!(1.));
+        let integer = s.var(lit!(10.));
+        let fract = s.var(lit!(0.));
+        let x = s.var(split.call(lit!(2.5), &integer, &fract));
+        s.set(&out_integer, &integer);
+        s.set(&out_fract, &fract);
+        s.set(&total, accumulate.call(&acc, x.to_expr()) + acc.to_expr());
+      })
+    });
+
+    let outputs = Invocation::new(&shader).run().unwrap();
+
+    assert_eq!(outputs.output("integer"), Some(&Value::from(2.)));
+    assert_eq!(outputs.output("fract"), Some(&Value::from(0.5)));
+    // the call returns 3.5 and sets acc to 3.5
+    assert_eq!(outputs.output("total"), Some(&Value::from(7.)));
+  }
+
+  #[test]
+  fn calls_to_later_functions() {
+    let mut shader = ShaderBuilder::new_fragment_shader(|mut s, _| {
+      let result = s.output::<f32>("result").unwrap();
+      let first = s.fun(|_: &mut Scope<Expr<f32>>, x: Expr<f32>| x);
+      s.fun(|_: &mut Scope<Expr<f32>>, x: Expr<f32>| x * 2.);
+
+      s.main_fun(|s: &mut Scope<()>| {
+        s.set(&result, first.call(lit!(3.)));
+      })
+    });
+
+    // calls to functions declared later can only be built from the IR
+    fn set_callee(shader: &mut Shader, handle: u16, callee: u16) {
+      for decl in &mut shader.decls {
+        if let ShaderDecl::FunDef(h, fun) = decl {
+          if *h == handle {
+            let arg = ErasedExpr::Var(ScopedHandle::fun_arg(0));
+            let call =
+              ErasedExpr::FunCall(ErasedFunHandle::UserDefined(callee), vec![Arc::new(arg)]);
+            fun.ret = ErasedReturn::Expr(<f32 as crate::ToType>::ty(), call);
+          }
+        }
+      }
+    }
+
+    set_callee(&mut shader, 0, 1);
+    let outputs = Invocation::new(&shader).run().unwrap();
+    assert_eq!(outputs.output("result"), Some(&Value::from(6.)));
+
+    set_callee(&mut shader, 1, 0);
+    assert_eq!(
+      Invocation::new(&shader).run(),
+      Err(InterpreterError::Unsupported("recursive call"))
+    );
+  }
+
+  #[test]
+  fn discard() {
+    let shader = ShaderBuilder::new_fragment_shader(|mut s, fragment| {
+      let alpha = s.uniform::<f32>("alpha").unwrap();
+
+      s.main_fun(|s: &mut Scope<()>| {
+        s.when(alpha.lt(0.5), |s| fragment.discard(s));
+        s.set(&fragment.frag_depth, 1.);
+      })
+    });
+
+    let outputs = Invocation::new(&shader)
+      .uniform("alpha", 0.2)
+      .run()
+      .unwrap();
+    assert!(outputs.is_discarded());
+
+    let outputs = Invocation::new(&shader)
+      .uniform("alpha", 0.7)
+      .run()
+      .unwrap();
+    assert!(!outputs.is_discarded());
+    assert_eq!(
+      outputs.builtin(BuiltIn::Fragment(FragmentBuiltIn::FragDepth)),
+      Some(&Value::from(1.))
+    );
+  }
+
+  #[test]
+  fn matrices() {
+    let shader = ShaderBuilder::new_vertex_shader(|mut s, _| {
+      let m = s.uniform::<M22>("m").unwrap();
+      let v = s.output::<V2<f32>>("v").unwrap();
+
+      s.main_fun(|s: &mut Scope<()>| {
+        s.set(&v, m * crate::lit!(1., 1.));
+      })
+    });
+
+    // columns (1, 2) and (3, 4)
+    let outputs = Invocation::new(&shader)
+      .uniform("m", M22::from([[1., 2.], [3., 4.]]))
+      .run()
+      .unwrap();
+
+    assert_eq!(outputs.output("v"), Some(&Value::from(V2::from([4., 6.]))));
+  }
+
+  #[test]
+  fn per_vertex_builtins() {
+    let shader = ShaderBuilder::new_tess_ctrl_shader(|s, tess| {
+      s.main_fun(|s: &mut Scope<()>| {
+        s.set(
+          tess.output.at(&tess.invocation_id).position(),
+          tess.input.at(&tess.invocation_id).position() * 2.,
+        );
+      })
+    });
+
+    let mut vertex = BTreeMap::new();
+    vertex.insert(
+      BuiltIn::TessCtrl(TessCtrlBuiltIn::Position),
+      Value::from(V4::from([1., 2., 3., 1.])),
+    );
+
+    let outputs = Invocation::new(&shader)
+      .builtin(BuiltIn::TessCtrl(TessCtrlBuiltIn::InvocationID), 1)
+      .builtin(
+        BuiltIn::TessCtrl(TessCtrlBuiltIn::In),
+        Value::Array(vec![Value::Block(vertex.clone()), Value::Block(vertex)]),
+      )
+      .run()
+      .unwrap();
+
+    let mut out = BTreeMap::new();
+    out.insert(
+      BuiltIn::TessCtrl(TessCtrlBuiltIn::Position),
+      Value::from(V4::from([2., 4., 6., 2.])),
+    );
+
+    assert_eq!(
+      outputs.builtin(BuiltIn::TessCtrl(TessCtrlBuiltIn::Out)),
+      Some(&Value::Array(vec![
+        Value::Block(BTreeMap::new()),
+        Value::Block(out)
+      ]))
+    );
+  }
+
+  #[test]
+  fn interface_errors() {
+    let shader = ShaderBuilder::new_vertex_shader(|mut s, _| {
+      let _ = s.input::<V3<f32>>("position").unwrap();
+      s.main_fun(|_: &mut Scope<()>| {})
+    });
+
+    assert_eq!(
+      Invocation::new(&shader).run(),
+      Err(InterpreterError::MissingInput("position".to_owned()))
+    );
+    assert!(matches!(
+      Invocation::new(&shader).input("position", 1.).run(),
+      Err(InterpreterError::InterfaceTypeMismatch { .. })
+    ));
+    assert_eq!(
+      Invocation::new(&shader)
+        .input("position", V3::from([0., 0., 0.]))
+        .uniform("time", 1.)
+        .run(),
+      Err(InterpreterError::UndeclaredInterface("time".to_owned()))
+    );
+  }
+
+  #[test]
+  fn index_out_of_bounds() {
+    let shader = ShaderBuilder::new_fragment_shader(|mut s, _| {
+      let i = s.uniform::<i32>("i").unwrap();
+      let item = s.output::<i32>("item").unwrap();
+      s.main_fun(|s: &mut Scope<()>| {
+        let items = s.var(lit!([1, 2, 3]));
+        s.set(&item, items.at(&i));
+        s.set(items.at(&i), 0);
+      })
+    });
+    let run = |i: i32| Invocation::new(&shader).uniform("i", i).run().map(|_| ());
+
+    assert_eq!(run(2), Ok(()));
+    assert_eq!(
+      run(3),
+      Err(InterpreterError::IndexOutOfBounds { index: 3, len: 3 })
+    );
+    assert_eq!(
+      run(-1),
+      Err(InterpreterError::IndexOutOfBounds { index: -1, len: 3 })
+    );
+  }
+
+  #[test]
+  fn integer_division_errors() {
+    let shader = ShaderBuilder::new_fragment_shader(|mut s, _| {
+      let a = s.uniform::<i32>("a").unwrap();
+      let b = s.uniform::<i32>("b").unwrap();
+      let quotient = s.output::<i32>("quotient").unwrap();
+      s.main_fun(|s: &mut Scope<()>| s.set(&quotient, a / b))
+    });
+    let run = |a: i32, b: i32| {
+      Invocation::new(&shader)
+        .uniform("a", a)
+        .uniform("b", b)
+        .run()
+        .map(|outputs| outputs.output("quotient").cloned())
+    };
+
+    assert_eq!(run(7, 2), Ok(Some(Value::from(3))));
+    assert_eq!(run(7, 0), Err(InterpreterError::DivisionByZero));
+    assert_eq!(run(i32::MIN, -1), Err(InterpreterError::Overflow));
+  }
+
+  #[test]
+  fn step_limit() {
+    let shader = ShaderBuilder::new_fragment_shader(|s, _| {
+      s.main_fun(|s: &mut Scope<()>| s.loop_while(lit!(true), |_| {}))
+    });
+
+    assert_eq!(
+      Invocation::new(&shader).run(),
+      Err(InterpreterError::StepLimitExceeded(
+        Invocation::DEFAULT_MAX_STEPS
+      ))
+    );
+
+    // the loop instruction and 3 evaluations of the condition
+    let shader = ShaderBuilder::new_fragment_shader(|s, _| {
+      s.main_fun(|s: &mut Scope<()>| {
+        s.loop_for(0, |i| i.lt(2), |i| i + 1, |_, _| {});
+      })
+    });
+
+    assert!(Invocation::new(&shader).max_steps(4).run().is_ok());
+    assert_eq!(
+      Invocation::new(&shader).max_steps(3).run(),
+      Err(InterpreterError::StepLimitExceeded(3))
+    );
+  }
+}
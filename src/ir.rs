@@ -0,0 +1,1783 @@
+//! Read-only view of the intermediate representation of shaders.
+//!
+//! Shaders built with the EDSL are represented as a list of declarations — functions, constants, inputs, outputs and
+//! uniforms — made of scopes of instructions, themselves made of expressions. This module exposes that
+//! representation, so that analyses and backends can live outside of this crate:
+//!
+//! - [`Shader::declarations`] lists the declarations of a shader as [`Decl`]s, which borrow the shader. [`Fun`],
+//!   [`Scope`], [`Instr`] and [`Expr`] give access to the rest of the representation.
+//! - [`Visitor`] walks a shader, calling a method for each declaration, function, scope, instruction and expression;
+//!   override the methods for the nodes you are interested in.
+//! - [`Fold`] rewrites a shader into a new one via [`Shader::fold`], replacing expressions and removing instructions
+//!   and declarations.
+//!
+//! Handles identify functions, constants, arguments and variables the same way the GLSL writer does in the
+//! identifiers it generates, such as `fun_0`, `glob_1` or `var_1_2`.
+//!
+//! # Examples
+//!
+//! Counting the calls to each function:
+//!
+//! ```
+//! use shades::{Exponential as _, Expr, Scope, ShaderBuilder, lit};
+//! use shades::ir::{self, Callee, ExprKind, Visitor};
+//! use std::collections::HashMap;
+//!
+//! let shader = ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+//!   let square = s.fun(|_: &mut Scope<Expr<f32>>, x: Expr<f32>| x.pow(2.));
+//!
+//!   s.main_fun(|s: &mut Scope<()>| {
+//!     let x = s.var(square.call(lit!(3.)));
+//!     s.set(&x, square.call(x.clone().into()).sqrt());
+//!   })
+//! });
+//!
+//! #[derive(Default)]
+//! struct Calls(HashMap<Callee, usize>);
+//!
+//! impl<'a> Visitor<'a> for Calls {
+//!   fn visit_expr(&mut self, expr: ir::Expr<'a>) {
+//!     if let ExprKind::Call(callee, _) = expr.kind() {
+//!       *self.0.entry(callee).or_default() += 1;
+//!     }
+//!
+//!     ir::walk_expr(self, expr);
+//!   }
+//! }
+//!
+//! let mut calls = Calls::default();
+//! ir::walk_shader(&mut calls, &shader);
+//!
+//! assert_eq!(calls.0[&Callee::User(0)], 2);
+//! assert_eq!(calls.0[&Callee::BuiltIn(ir::BuiltInFun::Pow)], 1);
+//! assert_eq!(calls.0[&Callee::BuiltIn(ir::BuiltInFun::Sqrt)], 1);
+//! ```
+
+use crate::{
+  ArgQualifier, BuiltIn, ColorAttachment, ErasedExpr, ErasedFun, ErasedFunHandle, ErasedReturn,
+  ErasedScope, ScopeInstr, ScopedHandle, Shader, ShaderDecl, Swizzle, Type, M22, M33, M44,
+};
+use std::{collections::HashMap, panic::Location, sync::Arc};
+
+/// Top-level declaration of a shader.
+#[derive(Clone, Copy, Debug)]
+#[non_exhaustive]
+pub enum Decl<'a> {
+  /// The `main` function.
+  Main(Fun<'a>),
+
+  /// A function, along with its handle.
+  Fun {
+    /// Handle of the function, as used by [`Callee::User`].
+    handle: u16,
+
+    /// The function.
+    fun: Fun<'a>,
+  },
+
+  /// A constant.
+  Const {
+    /// Handle of the constant, as used by [`Var::Const`].
+    handle: u16,
+
+    /// Type of the constant.
+    ty: &'a Type,
+
+    /// Value of the constant.
+    value: Expr<'a>,
+
+    /// Name given to the constant, if any.
+    name: Option<&'a str>,
+  },
+
+  /// An input.
+  Input {
+    /// Name of the input.
+    name: &'a str,
+
+    /// Type of the input.
+    ty: &'a Type,
+  },
+
+  /// An output.
+  Output {
+    /// Name of the output.
+    name: &'a str,
+
+    /// Type of the output.
+    ty: &'a Type,
+
+    /// Colour attachment the output writes to, if any.
+    attachment: Option<&'a ColorAttachment>,
+  },
+
+  /// A uniform.
+  Uniform {
+    /// Name of the uniform.
+    name: &'a str,
+
+    /// Type of the uniform.
+    ty: &'a Type,
+  },
+}
+
+impl<'a> Decl<'a> {
+  pub(crate) fn new(decl: &'a ShaderDecl) -> Self {
+    match decl {
+      ShaderDecl::Main(fun) => Decl::Main(Fun::new(fun)),
+      ShaderDecl::FunDef(handle, fun) => Decl::Fun {
+        handle: *handle,
+        fun: Fun::new(fun),
+      },
+      ShaderDecl::Const(handle, ty, value, name) => Decl::Const {
+        handle: *handle,
+        ty,
+        value: Expr::new(value),
+        name: name.as_deref(),
+      },
+      ShaderDecl::In(name, ty) => Decl::Input { name, ty },
+      ShaderDecl::Out(name, ty, attachment) => Decl::Output {
+        name,
+        ty,
+        attachment: attachment.as_ref(),
+      },
+      ShaderDecl::Uniform(name, ty) => Decl::Uniform { name, ty },
+    }
+  }
+}
+
+/// A function: either `main` or a function declared with [`ShaderBuilder::fun`](crate::ShaderBuilder::fun).
+#[derive(Clone, Copy, Debug)]
+pub struct Fun<'a> {
+  erased: &'a ErasedFun,
+}
+
+impl<'a> Fun<'a> {
+  fn new(erased: &'a ErasedFun) -> Self {
+    Self { erased }
+  }
+
+  /// Name given to the function, if any.
+  pub fn name(self) -> Option<&'a str> {
+    self.erased.name.as_deref()
+  }
+
+  /// Types of the arguments of the function.
+  pub fn args(self) -> &'a [Type] {
+    &self.erased.args
+  }
+
+  /// How the arguments of the function are passed, in the same order as [`Fun::args`].
+  pub fn arg_qualifiers(self) -> &'a [ArgQualifier] {
+    &self.erased.arg_qualifiers
+  }
+
+  /// Name given to an argument of the function, if any.
+  pub fn arg_name(self, index: usize) -> Option<&'a str> {
+    self.erased.arg_names.get(index).map(String::as_str)
+  }
+
+  /// Type returned by the function, if it returns anything.
+  pub fn ret_ty(self) -> Option<&'a Type> {
+    match &self.erased.ret {
+      ErasedReturn::Void => None,
+      ErasedReturn::Expr(ty, _) => Some(ty),
+    }
+  }
+
+  /// Expression returned by the function once its body has run, if it returns anything.
+  pub fn ret(self) -> Option<Expr<'a>> {
+    match &self.erased.ret {
+      ErasedReturn::Void => None,
+      ErasedReturn::Expr(_, expr) => Some(Expr::new(expr)),
+    }
+  }
+
+  /// Body of the function.
+  pub fn body(self) -> Scope<'a> {
+    Scope::new(&self.erased.scope)
+  }
+
+  /// Location of the Rust code declaring the function, if known.
+  pub fn location(self) -> Option<&'static Location<'static>> {
+    self.erased.location
+  }
+}
+
+/// A scope: the body of a function, conditional or loop.
+#[derive(Clone, Copy, Debug)]
+pub struct Scope<'a> {
+  erased: &'a ErasedScope,
+}
+
+impl<'a> Scope<'a> {
+  fn new(erased: &'a ErasedScope) -> Self {
+    Self { erased }
+  }
+
+  /// Identifier of the scope, as used by [`Var::Local`].
+  ///
+  /// Every scope of a shader built with the EDSL has its own identifier, even across functions.
+  pub fn id(self) -> u16 {
+    self.erased.id
+  }
+
+  /// Instructions of the scope, in order.
+  pub fn instrs(self) -> impl ExactSizeIterator<Item = Instr<'a>> + 'a {
+    self.erased.instructions.iter().map(Instr::new)
+  }
+
+  /// Name given to a variable declared in this scope, if any.
+  pub fn var_name(self, handle: u16) -> Option<&'a str> {
+    self.erased.names.get(&handle).map(String::as_str)
+  }
+
+  /// Location of the Rust code that built the instruction at the given index, if known.
+  pub fn location(self, index: usize) -> Option<&'static Location<'static>> {
+    self.erased.location(index)
+  }
+}
+
+/// An instruction of a [`Scope`].
+#[derive(Clone, Copy, Debug)]
+#[non_exhaustive]
+pub enum Instr<'a> {
+  /// Declare a variable.
+  Declare {
+    /// Type of the variable.
+    ty: &'a Type,
+
+    /// The variable, always a [`Var::Local`].
+    var: Var<'a>,
+
+    /// Initial value of the variable.
+    value: Expr<'a>,
+  },
+
+  /// Return from the function, with a value if the function returns one.
+  Return(Option<Expr<'a>>),
+
+  /// Continue to the next iteration of the innermost loop.
+  Continue,
+
+  /// Break the innermost loop.
+  Break,
+
+  /// Run a scope if a condition holds.
+  If {
+    /// The condition.
+    condition: Expr<'a>,
+
+    /// Scope run if the condition holds.
+    body: Scope<'a>,
+  },
+
+  /// Run a scope if a condition holds and the conditions of the previous [`Instr::If`] and [`Instr::ElseIf`] don’t.
+  ElseIf {
+    /// The condition.
+    condition: Expr<'a>,
+
+    /// Scope run if the condition holds.
+    body: Scope<'a>,
+  },
+
+  /// Run a scope if the conditions of the previous [`Instr::If`] and [`Instr::ElseIf`] don’t hold.
+  Else {
+    /// Scope run if no previous condition holds.
+    body: Scope<'a>,
+  },
+
+  /// Loop over a variable.
+  For {
+    /// Type of the variable.
+    ty: &'a Type,
+
+    /// The variable, declared in the scope of the body.
+    var: Var<'a>,
+
+    /// Initial value of the variable.
+    init: Expr<'a>,
+
+    /// Condition checked before each iteration.
+    condition: Expr<'a>,
+
+    /// Value of the variable for the next iteration.
+    next: Expr<'a>,
+
+    /// Body of the loop.
+    body: Scope<'a>,
+  },
+
+  /// Loop while a condition holds.
+  While {
+    /// Condition checked before each iteration.
+    condition: Expr<'a>,
+
+    /// Body of the loop.
+    body: Scope<'a>,
+  },
+
+  /// Assign a value to a variable, or to a part of a variable.
+  Assign {
+    /// Assigned expression: a variable, or a swizzle, field or index of a variable.
+    target: Expr<'a>,
+
+    /// Assigned value.
+    value: Expr<'a>,
+  },
+
+  /// Discard the current fragment.
+  Discard,
+}
+
+impl<'a> Instr<'a> {
+  fn new(instr: &'a ScopeInstr) -> Self {
+    match instr {
+      ScopeInstr::VarDecl {
+        ty,
+        handle,
+        init_value,
+      } => Instr::Declare {
+        ty,
+        var: Var::new(handle),
+        value: Expr::new(init_value),
+      },
+
+      ScopeInstr::Return(ErasedReturn::Void) => Instr::Return(None),
+      ScopeInstr::Return(ErasedReturn::Expr(_, expr)) => Instr::Return(Some(Expr::new(expr))),
+      ScopeInstr::Continue => Instr::Continue,
+      ScopeInstr::Break => Instr::Break,
+
+      ScopeInstr::If { condition, scope } => Instr::If {
+        condition: Expr::new(condition),
+        body: Scope::new(scope),
+      },
+
+      ScopeInstr::ElseIf { condition, scope } => Instr::ElseIf {
+        condition: Expr::new(condition),
+        body: Scope::new(scope),
+      },
+
+      ScopeInstr::Else { scope } => Instr::Else {
+        body: Scope::new(scope),
+      },
+
+      ScopeInstr::For {
+        init_ty,
+        init_handle,
+        init_expr,
+        condition,
+        post_expr,
+        scope,
+      } => Instr::For {
+        ty: init_ty,
+        var: Var::new(init_handle),
+        init: Expr::new(init_expr),
+        condition: Expr::new(condition),
+        next: Expr::new(post_expr),
+        body: Scope::new(scope),
+      },
+
+      ScopeInstr::While { condition, scope } => Instr::While {
+        condition: Expr::new(condition),
+        body: Scope::new(scope),
+      },
+
+      ScopeInstr::MutateVar { var, expr } => Instr::Assign {
+        target: Expr::new(var),
+        value: Expr::new(expr),
+      },
+
+      ScopeInstr::Discard => Instr::Discard,
+    }
+  }
+}
+
+/// A variable, as read or written by expressions.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum Var<'a> {
+  /// A built-in.
+  BuiltIn(BuiltIn),
+
+  /// A constant, by handle.
+  Const(u16),
+
+  /// A constant of a [`Library`](crate::library::Library) used without being imported.
+  LibraryConst {
+    /// Identifier of the library.
+    library: usize,
+
+    /// Handle of the constant in the library.
+    handle: u16,
+  },
+
+  /// An argument of the current function, by index.
+  Arg(u16),
+
+  /// A variable local to the current function.
+  Local {
+    /// Identifier of the scope declaring the variable.
+    scope: u16,
+
+    /// Handle of the variable in that scope.
+    handle: u16,
+  },
+
+  /// An input, by name.
+  Input(&'a str),
+
+  /// An output, by name.
+  Output(&'a str),
+
+  /// A uniform, by name.
+  Uniform(&'a str),
+}
+
+impl<'a> Var<'a> {
+  fn new(handle: &'a ScopedHandle) -> Self {
+    match handle {
+      ScopedHandle::BuiltIn(builtin) => Var::BuiltIn(*builtin),
+      ScopedHandle::Global(handle) => Var::Const(*handle),
+      ScopedHandle::LibraryGlobal(library, handle) => Var::LibraryConst {
+        library: *library,
+        handle: *handle,
+      },
+      ScopedHandle::FunArg(handle) => Var::Arg(*handle),
+      ScopedHandle::FunVar { subscope, handle } => Var::Local {
+        scope: *subscope,
+        handle: *handle,
+      },
+      ScopedHandle::Input(name) => Var::Input(name),
+      ScopedHandle::Output(name) => Var::Output(name),
+      ScopedHandle::Uniform(name) => Var::Uniform(name),
+    }
+  }
+
+  fn erased(self) -> ScopedHandle {
+    match self {
+      Var::BuiltIn(builtin) => ScopedHandle::BuiltIn(builtin),
+      Var::Const(handle) => ScopedHandle::Global(handle),
+      Var::LibraryConst { library, handle } => ScopedHandle::LibraryGlobal(library, handle),
+      Var::Arg(handle) => ScopedHandle::FunArg(handle),
+      Var::Local { scope, handle } => ScopedHandle::FunVar {
+        subscope: scope,
+        handle,
+      },
+      Var::Input(name) => ScopedHandle::Input(name.to_owned()),
+      Var::Output(name) => ScopedHandle::Output(name.to_owned()),
+      Var::Uniform(name) => ScopedHandle::Uniform(name.to_owned()),
+    }
+  }
+}
+
+/// An expression.
+///
+/// Expressions are shared when the EDSL reuses them, so a single expression can appear several times in a shader.
+/// Expressions compare structurally.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Expr<'a> {
+  erased: &'a ErasedExpr,
+}
+
+impl<'a> Expr<'a> {
+  fn new(erased: &'a ErasedExpr) -> Self {
+    Self { erased }
+  }
+
+  /// What the expression is made of.
+  pub fn kind(self) -> ExprKind<'a> {
+    let binary = |op, a, b| ExprKind::Binary(op, Expr::new(a), Expr::new(b));
+
+    match self.erased {
+      ErasedExpr::LitInt(x) => ExprKind::Lit(Literal::Int(*x)),
+      ErasedExpr::LitUInt(x) => ExprKind::Lit(Literal::UInt(*x)),
+      ErasedExpr::LitFloat(x) => ExprKind::Lit(Literal::Float(*x)),
+      ErasedExpr::LitBool(x) => ExprKind::Lit(Literal::Bool(*x)),
+      ErasedExpr::LitInt2(x) => ExprKind::Lit(Literal::Int2(*x)),
+      ErasedExpr::LitUInt2(x) => ExprKind::Lit(Literal::UInt2(*x)),
+      ErasedExpr::LitFloat2(x) => ExprKind::Lit(Literal::Float2(*x)),
+      ErasedExpr::LitBool2(x) => ExprKind::Lit(Literal::Bool2(*x)),
+      ErasedExpr::LitInt3(x) => ExprKind::Lit(Literal::Int3(*x)),
+      ErasedExpr::LitUInt3(x) => ExprKind::Lit(Literal::UInt3(*x)),
+      ErasedExpr::LitFloat3(x) => ExprKind::Lit(Literal::Float3(*x)),
+      ErasedExpr::LitBool3(x) => ExprKind::Lit(Literal::Bool3(*x)),
+      ErasedExpr::LitInt4(x) => ExprKind::Lit(Literal::Int4(*x)),
+      ErasedExpr::LitUInt4(x) => ExprKind::Lit(Literal::UInt4(*x)),
+      ErasedExpr::LitFloat4(x) => ExprKind::Lit(Literal::Float4(*x)),
+      ErasedExpr::LitBool4(x) => ExprKind::Lit(Literal::Bool4(*x)),
+      ErasedExpr::LitM22(m) => ExprKind::Lit(Literal::M22(m.clone())),
+      ErasedExpr::LitM33(m) => ExprKind::Lit(Literal::M33(m.clone())),
+      ErasedExpr::LitM44(m) => ExprKind::Lit(Literal::M44(m.clone())),
+
+      ErasedExpr::Array(ty, items) => ExprKind::Array {
+        ty,
+        items: items.iter().map(|item| Expr::new(item)).collect(),
+      },
+
+      ErasedExpr::Var(handle) => ExprKind::Var(Var::new(handle)),
+
+      ErasedExpr::Not(a) => ExprKind::Unary(UnaryOp::Not, Expr::new(a)),
+      ErasedExpr::Neg(a) => ExprKind::Unary(UnaryOp::Neg, Expr::new(a)),
+
+      ErasedExpr::And(a, b) => binary(BinaryOp::And, a, b),
+      ErasedExpr::Or(a, b) => binary(BinaryOp::Or, a, b),
+      ErasedExpr::Xor(a, b) => binary(BinaryOp::Xor, a, b),
+      ErasedExpr::BitOr(a, b) => binary(BinaryOp::BitOr, a, b),
+      ErasedExpr::BitAnd(a, b) => binary(BinaryOp::BitAnd, a, b),
+      ErasedExpr::BitXor(a, b) => binary(BinaryOp::BitXor, a, b),
+      ErasedExpr::Add(a, b) => binary(BinaryOp::Add, a, b),
+      ErasedExpr::Sub(a, b) => binary(BinaryOp::Sub, a, b),
+      ErasedExpr::Mul(a, b) => binary(BinaryOp::Mul, a, b),
+      ErasedExpr::Div(a, b) => binary(BinaryOp::Div, a, b),
+      ErasedExpr::Rem(a, b) => binary(BinaryOp::Rem, a, b),
+      ErasedExpr::Shl(a, b) => binary(BinaryOp::Shl, a, b),
+      ErasedExpr::Shr(a, b) => binary(BinaryOp::Shr, a, b),
+      ErasedExpr::Eq(a, b) => binary(BinaryOp::Eq, a, b),
+      ErasedExpr::Neq(a, b) => binary(BinaryOp::Neq, a, b),
+      ErasedExpr::Lt(a, b) => binary(BinaryOp::Lt, a, b),
+      ErasedExpr::Lte(a, b) => binary(BinaryOp::Lte, a, b),
+      ErasedExpr::Gt(a, b) => binary(BinaryOp::Gt, a, b),
+      ErasedExpr::Gte(a, b) => binary(BinaryOp::Gte, a, b),
+
+      ErasedExpr::FunCall(fun, args) => {
+        ExprKind::Call(callee(fun), args.iter().map(|arg| Expr::new(arg)).collect())
+      }
+
+      ErasedExpr::Swizzle(a, swizzle) => ExprKind::Swizzle(Expr::new(a), *swizzle),
+
+      ErasedExpr::Field { object, field } => ExprKind::Field {
+        object: Expr::new(object),
+        field: Expr::new(field),
+      },
+
+      ErasedExpr::ArrayLookup { object, index } => ExprKind::Index {
+        object: Expr::new(object),
+        index: Expr::new(index),
+      },
+    }
+  }
+}
+
+/// What an [`Expr`] is made of.
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum ExprKind<'a> {
+  /// A literal.
+  Lit(Literal),
+
+  /// An array.
+  Array {
+    /// Type of the array.
+    ty: &'a Type,
+
+    /// Items of the array.
+    items: Vec<Expr<'a>>,
+  },
+
+  /// A variable.
+  Var(Var<'a>),
+
+  /// A unary operator applied to an expression.
+  Unary(UnaryOp, Expr<'a>),
+
+  /// A binary operator applied to two expressions.
+  Binary(BinaryOp, Expr<'a>, Expr<'a>),
+
+  /// A function call.
+  Call(Callee, Vec<Expr<'a>>),
+
+  /// A swizzle of a vector.
+  Swizzle(Expr<'a>, Swizzle),
+
+  /// A field of an object, such as the position of a vertex of a geometry shader’s input.
+  Field {
+    /// The object.
+    object: Expr<'a>,
+
+    /// The field, which is a built-in naming the field rather than a value.
+    field: Expr<'a>,
+  },
+
+  /// An item of an array.
+  Index {
+    /// The array.
+    object: Expr<'a>,
+
+    /// Index of the item.
+    index: Expr<'a>,
+  },
+}
+
+/// A literal value.
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum Literal {
+  /// A signed integer.
+  Int(i32),
+
+  /// An unsigned integer.
+  UInt(u32),
+
+  /// A floating-point number.
+  Float(f32),
+
+  /// A boolean.
+  Bool(bool),
+
+  /// A vector of two signed integers.
+  Int2([i32; 2]),
+
+  /// A vector of two unsigned integers.
+  UInt2([u32; 2]),
+
+  /// A vector of two floating-point numbers.
+  Float2([f32; 2]),
+
+  /// A vector of two booleans.
+  Bool2([bool; 2]),
+
+  /// A vector of three signed integers.
+  Int3([i32; 3]),
+
+  /// A vector of three unsigned integers.
+  UInt3([u32; 3]),
+
+  /// A vector of three floating-point numbers.
+  Float3([f32; 3]),
+
+  /// A vector of three booleans.
+  Bool3([bool; 3]),
+
+  /// A vector of four signed integers.
+  Int4([i32; 4]),
+
+  /// A vector of four unsigned integers.
+  UInt4([u32; 4]),
+
+  /// A vector of four floating-point numbers.
+  Float4([f32; 4]),
+
+  /// A vector of four booleans.
+  Bool4([bool; 4]),
+
+  /// A 2×2 matrix.
+  M22(M22),
+
+  /// A 3×3 matrix.
+  M33(M33),
+
+  /// A 4×4 matrix.
+  M44(M44),
+}
+
+impl Literal {
+  fn erased(self) -> ErasedExpr {
+    match self {
+      Literal::Int(x) => ErasedExpr::LitInt(x),
+      Literal::UInt(x) => ErasedExpr::LitUInt(x),
+      Literal::Float(x) => ErasedExpr::LitFloat(x),
+      Literal::Bool(x) => ErasedExpr::LitBool(x),
+      Literal::Int2(x) => ErasedExpr::LitInt2(x),
+      Literal::UInt2(x) => ErasedExpr::LitUInt2(x),
+      Literal::Float2(x) => ErasedExpr::LitFloat2(x),
+      Literal::Bool2(x) => ErasedExpr::LitBool2(x),
+      Literal::Int3(x) => ErasedExpr::LitInt3(x),
+      Literal::UInt3(x) => ErasedExpr::LitUInt3(x),
+      Literal::Float3(x) => ErasedExpr::LitFloat3(x),
+      Literal::Bool3(x) => ErasedExpr::LitBool3(x),
+      Literal::Int4(x) => ErasedExpr::LitInt4(x),
+      Literal::UInt4(x) => ErasedExpr::LitUInt4(x),
+      Literal::Float4(x) => ErasedExpr::LitFloat4(x),
+      Literal::Bool4(x) => ErasedExpr::LitBool4(x),
+      Literal::M22(m) => ErasedExpr::LitM22(m),
+      Literal::M33(m) => ErasedExpr::LitM33(m),
+      Literal::M44(m) => ErasedExpr::LitM44(m),
+    }
+  }
+}
+
+/// Unary operators.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum UnaryOp {
+  /// Logical negation, `!a`.
+  Not,
+
+  /// Arithmetic negation, `-a`.
+  Neg,
+}
+
+/// Binary operators.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum BinaryOp {
+  /// Logical and, `a && b`.
+  And,
+
+  /// Logical or, `a || b`.
+  Or,
+
+  /// Logical exclusive or, `a ^^ b`.
+  Xor,
+
+  /// Bitwise or, `a | b`.
+  BitOr,
+
+  /// Bitwise and, `a & b`.
+  BitAnd,
+
+  /// Bitwise exclusive or, `a ^ b`.
+  BitXor,
+
+  /// Addition, `a + b`.
+  Add,
+
+  /// Subtraction, `a - b`.
+  Sub,
+
+  /// Multiplication, `a * b`.
+  Mul,
+
+  /// Division, `a / b`.
+  Div,
+
+  /// Remainder, `a % b`.
+  Rem,
+
+  /// Left shift, `a << b`.
+  Shl,
+
+  /// Right shift, `a >> b`.
+  Shr,
+
+  /// Equality, `a == b`.
+  Eq,
+
+  /// Inequality, `a != b`.
+  Neq,
+
+  /// Less than, `a < b`.
+  Lt,
+
+  /// Less than or equal, `a <= b`.
+  Lte,
+
+  /// Greater than, `a > b`.
+  Gt,
+
+  /// Greater than or equal, `a >= b`.
+  Gte,
+}
+
+impl BinaryOp {
+  fn erased(self, a: ErasedExpr, b: ErasedExpr) -> ErasedExpr {
+    let (a, b) = (Arc::new(a), Arc::new(b));
+
+    match self {
+      BinaryOp::And => ErasedExpr::And(a, b),
+      BinaryOp::Or => ErasedExpr::Or(a, b),
+      BinaryOp::Xor => ErasedExpr::Xor(a, b),
+      BinaryOp::BitOr => ErasedExpr::BitOr(a, b),
+      BinaryOp::BitAnd => ErasedExpr::BitAnd(a, b),
+      BinaryOp::BitXor => ErasedExpr::BitXor(a, b),
+      BinaryOp::Add => ErasedExpr::Add(a, b),
+      BinaryOp::Sub => ErasedExpr::Sub(a, b),
+      BinaryOp::Mul => ErasedExpr::Mul(a, b),
+      BinaryOp::Div => ErasedExpr::Div(a, b),
+      BinaryOp::Rem => ErasedExpr::Rem(a, b),
+      BinaryOp::Shl => ErasedExpr::Shl(a, b),
+      BinaryOp::Shr => ErasedExpr::Shr(a, b),
+      BinaryOp::Eq => ErasedExpr::Eq(a, b),
+      BinaryOp::Neq => ErasedExpr::Neq(a, b),
+      BinaryOp::Lt => ErasedExpr::Lt(a, b),
+      BinaryOp::Lte => ErasedExpr::Lte(a, b),
+      BinaryOp::Gt => ErasedExpr::Gt(a, b),
+      BinaryOp::Gte => ErasedExpr::Gte(a, b),
+    }
+  }
+}
+
+/// Function called by an expression.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum Callee {
+  /// A built-in function.
+  BuiltIn(BuiltInFun),
+
+  /// A function declared in the shader, by handle.
+  User(u16),
+
+  /// A function of a [`Library`](crate::library::Library) called without being imported.
+  Library {
+    /// Identifier of the library.
+    library: usize,
+
+    /// Handle of the function in the library.
+    handle: u16,
+  },
+}
+
+impl Callee {
+  fn erased(self) -> ErasedFunHandle {
+    match self {
+      Callee::BuiltIn(fun) => fun.erased(),
+      Callee::User(handle) => ErasedFunHandle::UserDefined(handle),
+      Callee::Library { library, handle } => ErasedFunHandle::Library(library, handle),
+    }
+  }
+}
+
+macro_rules! builtin_funs {
+  ($($(#[$meta:meta])* $fun:ident,)*) => {
+    /// Built-in functions.
+    ///
+    /// Built-in functions mirror the ones of GLSL, whose name is given for each of them.
+    #[allow(clippy::upper_case_acronyms)]
+    #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+    #[non_exhaustive]
+    pub enum BuiltInFun {
+      $($(#[$meta])* $fun,)*
+    }
+
+    impl BuiltInFun {
+      fn erased(self) -> ErasedFunHandle {
+        match self {
+          $(BuiltInFun::$fun => ErasedFunHandle::$fun,)*
+        }
+      }
+    }
+
+    fn callee(fun: &ErasedFunHandle) -> Callee {
+      match fun {
+        $(ErasedFunHandle::$fun => Callee::BuiltIn(BuiltInFun::$fun),)*
+        ErasedFunHandle::UserDefined(handle) => Callee::User(*handle),
+        ErasedFunHandle::Library(library, handle) => Callee::Library {
+          library: *library,
+          handle: *handle,
+        },
+      }
+    }
+  };
+}
+
+builtin_funs! {
+    // cast operators
+    /// `vec2`.
+    Vec2,
+    /// `vec3`.
+    Vec3,
+    /// `vec4`.
+    Vec4,
+    // trigonometry
+    /// `radians`.
+    Radians,
+    /// `degrees`.
+    Degrees,
+    /// `sin`.
+    Sin,
+    /// `cos`.
+    Cos,
+    /// `tan`.
+    Tan,
+    /// `asin`.
+    ASin,
+    /// `acos`.
+    ACos,
+    /// `atan`.
+    ATan,
+    /// `sinh`.
+    SinH,
+    /// `cosh`.
+    CosH,
+    /// `tanh`.
+    TanH,
+    /// `asinh`.
+    ASinH,
+    /// `acosh`.
+    ACosH,
+    /// `atanh`.
+    ATanH,
+    // exponential
+    /// `pow`.
+    Pow,
+    /// `exp`.
+    Exp,
+    /// `exp2`.
+    Exp2,
+    /// `log`.
+    Log,
+    /// `log2`.
+    Log2,
+    /// `sqrt`.
+    Sqrt,
+    /// `inversesqrt`.
+    InverseSqrt,
+    // common
+    /// `abs`.
+    Abs,
+    /// `sign`.
+    Sign,
+    /// `floor`.
+    Floor,
+    /// `trunc`.
+    Trunc,
+    /// `round`.
+    Round,
+    /// `roundEven`.
+    RoundEven,
+    /// `ceil`.
+    Ceil,
+    /// `fract`.
+    Fract,
+    /// `min`.
+    Min,
+    /// `max`.
+    Max,
+    /// `clamp`.
+    Clamp,
+    /// `mix`.
+    Mix,
+    /// `step`.
+    Step,
+    /// `smoothstep`.
+    SmoothStep,
+    /// `isnan`.
+    IsNan,
+    /// `isinf`.
+    IsInf,
+    /// `floatBitsToInt`.
+    FloatBitsToInt,
+    /// `intBitsToFloat`.
+    IntBitsToFloat,
+    /// `uintBitsToFloat`.
+    UIntBitsToFloat,
+    /// `fma`.
+    FMA,
+    /// `frexp`.
+    Frexp,
+    /// `ldexp`.
+    Ldexp,
+    // floating-point pack and unpack functions
+    /// `packUnorm2x16`.
+    PackUnorm2x16,
+    /// `packSnorm2x16`.
+    PackSnorm2x16,
+    /// `packUnorm4x8`.
+    PackUnorm4x8,
+    /// `packSnorm4x8`.
+    PackSnorm4x8,
+    /// `unpackUnorm2x16`.
+    UnpackUnorm2x16,
+    /// `unpackSnorm2x16`.
+    UnpackSnorm2x16,
+    /// `unpackUnorm4x8`.
+    UnpackUnorm4x8,
+    /// `unpackSnorm4x8`.
+    UnpackSnorm4x8,
+    /// `packHalf2x16`.
+    PackHalf2x16,
+    /// `unpackHalf2x16`.
+    UnpackHalf2x16,
+    // geometry functions
+    /// `length`.
+    Length,
+    /// `distance`.
+    Distance,
+    /// `dot`.
+    Dot,
+    /// `cross`.
+    Cross,
+    /// `normalize`.
+    Normalize,
+    /// `faceforward`.
+    FaceForward,
+    /// `reflect`.
+    Reflect,
+    /// `refract`.
+    Refract,
+    // vector relational functions
+    /// `lessThan`.
+    VLt,
+    /// `lessThanEqual`.
+    VLte,
+    /// `greaterThan`.
+    VGt,
+    /// `greaterThanEqual`.
+    VGte,
+    /// `equal`.
+    VEq,
+    /// `notEqual`.
+    VNeq,
+    /// `any`.
+    VAny,
+    /// `all`.
+    VAll,
+    /// `not`.
+    VNot,
+    // integer functions
+    /// `uaddCarry`.
+    UAddCarry,
+    /// `usubBorrow`.
+    USubBorrow,
+    /// `umulExtended`.
+    UMulExtended,
+    /// `imulExtended`.
+    IMulExtended,
+    /// `bitfieldExtract`.
+    BitfieldExtract,
+    /// `bitfieldInsert`.
+    BitfieldInsert,
+    /// `bitfieldReverse`.
+    BitfieldReverse,
+    /// `bitCount`.
+    BitCount,
+    /// `findLSB`.
+    FindLSB,
+    /// `findMSB`.
+    FindMSB,
+    // geometry shader functions
+    /// `EmitStreamVertex`.
+    EmitStreamVertex,
+    /// `EndStreamPrimitive`.
+    EndStreamPrimitive,
+    /// `EmitVertex`.
+    EmitVertex,
+    /// `EndPrimitive`.
+    EndPrimitive,
+    // fragment processing functions
+    /// `dFdx`.
+    DFDX,
+    /// `dFdy`.
+    DFDY,
+    /// `dFdxFine`.
+    DFDXFine,
+    /// `dFdyFine`.
+    DFDYFine,
+    /// `dFdxCoarse`.
+    DFDXCoarse,
+    /// `dFdyCoarse`.
+    DFDYCoarse,
+    /// `fwidth`.
+    FWidth,
+    /// `fwidthFine`.
+    FWidthFine,
+    /// `fwidthCoarse`.
+    FWidthCoarse,
+    /// `interpolateAtCentroid`.
+    InterpolateAtCentroid,
+    /// `interpolateAtSample`.
+    InterpolateAtSample,
+    /// `interpolateAtOffset`.
+    InterpolateAtOffset,
+    // shader invocation control functions
+    /// `barrier`.
+    Barrier,
+    /// `memoryBarrier`.
+    MemoryBarrier,
+    /// `memoryBarrierAtomic`.
+    MemoryBarrierAtomic,
+    /// `memoryBarrierBuffer`.
+    MemoryBarrierBuffer,
+    /// `memoryBarrierShared`.
+    MemoryBarrierShared,
+    /// `memoryBarrierImage`.
+    MemoryBarrierImage,
+    /// `groupMemoryBarrier`.
+    GroupMemoryBarrier,
+    // shader invocation group functions
+    /// `anyInvocation`.
+    AnyInvocation,
+    /// `allInvocations`.
+    AllInvocations,
+    /// `allInvocationsEqual`.
+    AllInvocationsEqual,
+}
+
+/// An expression owned rather than borrowed from a shader, used to build expressions when folding a shader.
+///
+/// Expressions built with the EDSL can be turned into [`ExprBuf`] with [`From`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExprBuf {
+  erased: ErasedExpr,
+}
+
+impl ExprBuf {
+  fn new(erased: ErasedExpr) -> Self {
+    Self { erased }
+  }
+
+  /// Borrow the expression.
+  pub fn as_expr(&self) -> Expr<'_> {
+    Expr::new(&self.erased)
+  }
+
+  /// A literal.
+  pub fn lit(lit: Literal) -> Self {
+    Self::new(lit.erased())
+  }
+
+  /// A variable.
+  pub fn var(var: Var<'_>) -> Self {
+    Self::new(ErasedExpr::Var(var.erased()))
+  }
+
+  /// A unary operator applied to an expression.
+  pub fn unary(op: UnaryOp, a: ExprBuf) -> Self {
+    let a = Arc::new(a.erased);
+
+    match op {
+      UnaryOp::Not => Self::new(ErasedExpr::Not(a)),
+      UnaryOp::Neg => Self::new(ErasedExpr::Neg(a)),
+    }
+  }
+
+  /// A binary operator applied to two expressions.
+  pub fn binary(op: BinaryOp, a: ExprBuf, b: ExprBuf) -> Self {
+    Self::new(op.erased(a.erased, b.erased))
+  }
+
+  /// A function call.
+  pub fn call(callee: Callee, args: Vec<ExprBuf>) -> Self {
+    let args = args.into_iter().map(|arg| Arc::new(arg.erased)).collect();
+    Self::new(ErasedExpr::FunCall(callee.erased(), args))
+  }
+
+  /// A swizzle of a vector.
+  pub fn swizzle(a: ExprBuf, swizzle: Swizzle) -> Self {
+    Self::new(ErasedExpr::Swizzle(Arc::new(a.erased), swizzle))
+  }
+
+  /// A field of an object.
+  pub fn field(object: ExprBuf, field: ExprBuf) -> Self {
+    Self::new(ErasedExpr::Field {
+      object: Arc::new(object.erased),
+      field: Arc::new(field.erased),
+    })
+  }
+
+  /// An item of an array.
+  pub fn index(object: ExprBuf, index: ExprBuf) -> Self {
+    Self::new(ErasedExpr::ArrayLookup {
+      object: Arc::new(object.erased),
+      index: Arc::new(index.erased),
+    })
+  }
+
+  /// An array.
+  pub fn array(ty: Type, items: Vec<ExprBuf>) -> Self {
+    let items = items
+      .into_iter()
+      .map(|item| Arc::new(item.erased))
+      .collect();
+    Self::new(ErasedExpr::Array(ty, items))
+  }
+}
+
+impl From<Expr<'_>> for ExprBuf {
+  fn from(expr: Expr) -> Self {
+    Self::new(expr.erased.clone())
+  }
+}
+
+impl<T> From<crate::Expr<T>> for ExprBuf
+where
+  T: ?Sized,
+{
+  fn from(expr: crate::Expr<T>) -> Self {
+    Self::new(expr.erased)
+  }
+}
+
+/// Walk a shader.
+///
+/// Each method is called on a node of the shader and, by default, walks the children of the node by calling the
+/// corresponding `walk_*` function, such as [`walk_expr`]. Override the methods for the nodes you are interested in,
+/// and call the `walk_*` function to keep walking their children.
+///
+/// Expressions shared in the shader are walked each time they appear.
+pub trait Visitor<'a> {
+  /// Visit a declaration.
+  fn visit_decl(&mut self, decl: Decl<'a>) {
+    walk_decl(self, decl)
+  }
+
+  /// Visit a function.
+  fn visit_fun(&mut self, fun: Fun<'a>) {
+    walk_fun(self, fun)
+  }
+
+  /// Visit a scope.
+  fn visit_scope(&mut self, scope: Scope<'a>) {
+    walk_scope(self, scope)
+  }
+
+  /// Visit an instruction.
+  fn visit_instr(&mut self, instr: Instr<'a>) {
+    walk_instr(self, instr)
+  }
+
+  /// Visit an expression.
+  fn visit_expr(&mut self, expr: Expr<'a>) {
+    walk_expr(self, expr)
+  }
+}
+
+/// Visit all the declarations of a shader.
+pub fn walk_shader<'a, V>(visitor: &mut V, shader: &'a Shader)
+where
+  V: Visitor<'a> + ?Sized,
+{
+  for decl in shader.declarations() {
+    visitor.visit_decl(decl);
+  }
+}
+
+/// Visit the functions and expressions of a declaration.
+pub fn walk_decl<'a, V>(visitor: &mut V, decl: Decl<'a>)
+where
+  V: Visitor<'a> + ?Sized,
+{
+  match decl {
+    Decl::Main(fun) | Decl::Fun { fun, .. } => visitor.visit_fun(fun),
+    Decl::Const { value, .. } => visitor.visit_expr(value),
+    Decl::Input { .. } | Decl::Output { .. } | Decl::Uniform { .. } => (),
+  }
+}
+
+/// Visit the body of a function, then its returned expression.
+pub fn walk_fun<'a, V>(visitor: &mut V, fun: Fun<'a>)
+where
+  V: Visitor<'a> + ?Sized,
+{
+  visitor.visit_scope(fun.body());
+
+  if let Some(ret) = fun.ret() {
+    visitor.visit_expr(ret);
+  }
+}
+
+/// Visit the instructions of a scope.
+pub fn walk_scope<'a, V>(visitor: &mut V, scope: Scope<'a>)
+where
+  V: Visitor<'a> + ?Sized,
+{
+  for instr in scope.instrs() {
+    visitor.visit_instr(instr);
+  }
+}
+
+/// Visit the expressions and scopes of an instruction, in evaluation order.
+pub fn walk_instr<'a, V>(visitor: &mut V, instr: Instr<'a>)
+where
+  V: Visitor<'a> + ?Sized,
+{
+  match instr {
+    Instr::Declare { value, .. } | Instr::Return(Some(value)) => visitor.visit_expr(value),
+
+    Instr::Return(None) | Instr::Continue | Instr::Break | Instr::Discard => (),
+
+    Instr::If { condition, body }
+    | Instr::ElseIf { condition, body }
+    | Instr::While { condition, body } => {
+      visitor.visit_expr(condition);
+      visitor.visit_scope(body);
+    }
+
+    Instr::Else { body } => visitor.visit_scope(body),
+
+    Instr::For {
+      init,
+      condition,
+      next,
+      body,
+      ..
+    } => {
+      visitor.visit_expr(init);
+      visitor.visit_expr(condition);
+      visitor.visit_scope(body);
+      visitor.visit_expr(next);
+    }
+
+    Instr::Assign { target, value } => {
+      visitor.visit_expr(value);
+      visitor.visit_expr(target);
+    }
+  }
+}
+
+/// Visit the subexpressions of an expression.
+///
+/// The field of [`ExprKind::Field`] is not visited, as it names a field rather than being a value.
+pub fn walk_expr<'a, V>(visitor: &mut V, expr: Expr<'a>)
+where
+  V: Visitor<'a> + ?Sized,
+{
+  match expr.kind() {
+    ExprKind::Lit(_) | ExprKind::Var(_) => (),
+
+    ExprKind::Array { items, .. } | ExprKind::Call(_, items) => {
+      for item in items {
+        visitor.visit_expr(item);
+      }
+    }
+
+    ExprKind::Unary(_, a) | ExprKind::Swizzle(a, _) | ExprKind::Field { object: a, .. } => {
+      visitor.visit_expr(a)
+    }
+
+    ExprKind::Binary(_, a, b)
+    | ExprKind::Index {
+      object: a,
+      index: b,
+    } => {
+      visitor.visit_expr(a);
+      visitor.visit_expr(b);
+    }
+  }
+}
+
+/// Rewrite a shader into a new one; see [`Shader::fold`].
+///
+/// By default, the shader is left unchanged. The folder is responsible for keeping the shader well-typed: a
+/// floating-point expression must be replaced with a floating-point expression, for instance. Writers validate the
+/// shaders they write, but cannot catch all mistakes.
+///
+/// # Examples
+///
+/// Replacing squares with multiplications:
+///
+/// ```
+/// use shades::{Exponential as _, Expr, Scope, ShaderBuilder, lit};
+/// use shades::ir::{BinaryOp, BuiltInFun, Callee, ExprBuf, ExprKind, Fold, Literal};
+/// use shades::writer::glsl;
+///
+/// let shader = ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+///   s.main_fun(|s: &mut Scope<()>| {
+///     let x = s.var(lit!(3.));
+///     s.set(&x, x.clone().pow(2.));
+///   })
+/// });
+///
+/// struct Squares;
+///
+/// impl Fold for Squares {
+///   fn fold_expr(&mut self, expr: ExprBuf) -> ExprBuf {
+///     if let ExprKind::Call(Callee::BuiltIn(BuiltInFun::Pow), args) = expr.as_expr().kind() {
+///       if args[1].kind() == ExprKind::Lit(Literal::Float(2.)) {
+///         return ExprBuf::binary(BinaryOp::Mul, args[0].into(), args[0].into());
+///       }
+///     }
+///
+///     expr
+///   }
+/// }
+///
+/// let folded = shader.fold(&mut Squares);
+/// let output = glsl::write_shader_to_str(&folded).unwrap();
+/// assert!(output.contains("var_0_0 = (var_0_0 * var_0_0);"));
+/// ```
+pub trait Fold {
+  /// Rewrite an expression, whose subexpressions are already folded.
+  fn fold_expr(&mut self, expr: ExprBuf) -> ExprBuf {
+    expr
+  }
+
+  /// Whether to keep an instruction, whose expressions and scopes are already folded.
+  fn keep_instr(&mut self, instr: Instr<'_>) -> bool {
+    let _ = instr;
+    true
+  }
+
+  /// Whether to keep a declaration, before folding it.
+  ///
+  /// Removing a declaration still used by the rest of the shader makes the shader invalid.
+  fn keep_decl(&mut self, decl: Decl<'_>) -> bool {
+    let _ = decl;
+    true
+  }
+}
+
+// Fold a shader.
+//
+// Expressions shared in the shader are folded once and stay shared; they are identified by address, which is stable
+// as the shader is borrowed for the whole fold.
+pub(crate) fn fold(shader: &Shader, folder: &mut (impl Fold + ?Sized)) -> Shader {
+  let mut state = Folding {
+    folder,
+    shared: HashMap::new(),
+  };
+
+  let decls = shader
+    .decls
+    .iter()
+    .filter_map(|decl| {
+      if state.folder.keep_decl(Decl::new(decl)) {
+        Some(state.fold_decl(decl))
+      } else {
+        None
+      }
+    })
+    .collect();
+
+  Shader {
+    stage: shader.stage,
+    decls,
+    next_fun_handle: shader.next_fun_handle,
+    next_global_handle: shader.next_global_handle,
+  }
+}
+
+struct Folding<'f, F: ?Sized> {
+  folder: &'f mut F,
+  shared: HashMap<*const ErasedExpr, Arc<ErasedExpr>>,
+}
+
+impl<F> Folding<'_, F>
+where
+  F: Fold + ?Sized,
+{
+  fn fold_decl(&mut self, decl: &ShaderDecl) -> ShaderDecl {
+    match decl {
+      ShaderDecl::Main(fun) => ShaderDecl::Main(self.fold_fun(fun)),
+      ShaderDecl::FunDef(handle, fun) => ShaderDecl::FunDef(*handle, self.fold_fun(fun)),
+      ShaderDecl::Const(handle, ty, value, name) => {
+        ShaderDecl::Const(*handle, ty.clone(), self.fold_expr(value), name.clone())
+      }
+      _ => decl.clone(),
+    }
+  }
+
+  fn fold_fun(&mut self, fun: &ErasedFun) -> ErasedFun {
+    ErasedFun {
+      args: fun.args.clone(),
+      arg_qualifiers: fun.arg_qualifiers.clone(),
+      scope: self.fold_scope(&fun.scope),
+      ret: self.fold_ret(&fun.ret),
+      name: fun.name.clone(),
+      arg_names: fun.arg_names.clone(),
+      location: fun.location,
+    }
+  }
+
+  fn fold_ret(&mut self, ret: &ErasedReturn) -> ErasedReturn {
+    match ret {
+      ErasedReturn::Void => ErasedReturn::Void,
+      ErasedReturn::Expr(ty, expr) => ErasedReturn::Expr(ty.clone(), self.fold_expr(expr)),
+    }
+  }
+
+  fn fold_scope(&mut self, scope: &ErasedScope) -> ErasedScope {
+    let mut folded = ErasedScope {
+      id: scope.id,
+      instructions: scope
+        .instructions
+        .iter()
+        .map(|instr| self.fold_instr(instr))
+        .collect(),
+      next_var: scope.next_var,
+      names: scope.names.clone(),
+      locations: scope.locations.clone(),
+    };
+
+    folded.retain(|instr| self.folder.keep_instr(Instr::new(instr)));
+    folded
+  }
+
+  fn fold_instr(&mut self, instr: &ScopeInstr) -> ScopeInstr {
+    match instr {
+      ScopeInstr::VarDecl {
+        ty,
+        handle,
+        init_value,
+      } => ScopeInstr::VarDecl {
+        ty: ty.clone(),
+        handle: handle.clone(),
+        init_value: self.fold_expr(init_value),
+      },
+
+      ScopeInstr::Return(ret) => ScopeInstr::Return(self.fold_ret(ret)),
+      ScopeInstr::Continue => ScopeInstr::Continue,
+      ScopeInstr::Break => ScopeInstr::Break,
+
+      ScopeInstr::If { condition, scope } => ScopeInstr::If {
+        condition: self.fold_expr(condition),
+        scope: self.fold_scope(scope),
+      },
+
+      ScopeInstr::ElseIf { condition, scope } => ScopeInstr::ElseIf {
+        condition: self.fold_expr(condition),
+        scope: self.fold_scope(scope),
+      },
+
+      ScopeInstr::Else { scope } => ScopeInstr::Else {
+        scope: self.fold_scope(scope),
+      },
+
+      ScopeInstr::For {
+        init_ty,
+        init_handle,
+        init_expr,
+        condition,
+        post_expr,
+        scope,
+      } => ScopeInstr::For {
+        init_ty: init_ty.clone(),
+        init_handle: init_handle.clone(),
+        init_expr: self.fold_expr(init_expr),
+        condition: self.fold_expr(condition),
+        post_expr: self.fold_expr(post_expr),
+        scope: self.fold_scope(scope),
+      },
+
+      ScopeInstr::While { condition, scope } => ScopeInstr::While {
+        condition: self.fold_expr(condition),
+        scope: self.fold_scope(scope),
+      },
+
+      ScopeInstr::MutateVar { var, expr } => ScopeInstr::MutateVar {
+        var: self.fold_expr(var),
+        expr: self.fold_expr(expr),
+      },
+
+      ScopeInstr::Discard => ScopeInstr::Discard,
+    }
+  }
+
+  fn fold_expr(&mut self, expr: &ErasedExpr) -> ErasedExpr {
+    let mut folded = expr.clone();
+
+    match &mut folded {
+      ErasedExpr::Array(_, items) | ErasedExpr::FunCall(_, items) => {
+        for item in items {
+          *item = self.fold_shared(item);
+        }
+      }
+
+      ErasedExpr::Not(a)
+      | ErasedExpr::Neg(a)
+      | ErasedExpr::Swizzle(a, _)
+      | ErasedExpr::Field { object: a, .. } => *a = self.fold_shared(a),
+
+      ErasedExpr::And(a, b)
+      | ErasedExpr::Or(a, b)
+      | ErasedExpr::Xor(a, b)
+      | ErasedExpr::BitOr(a, b)
+      | ErasedExpr::BitAnd(a, b)
+      | ErasedExpr::BitXor(a, b)
+      | ErasedExpr::Add(a, b)
+      | ErasedExpr::Sub(a, b)
+      | ErasedExpr::Mul(a, b)
+      | ErasedExpr::Div(a, b)
+      | ErasedExpr::Rem(a, b)
+      | ErasedExpr::Shl(a, b)
+      | ErasedExpr::Shr(a, b)
+      | ErasedExpr::Eq(a, b)
+      | ErasedExpr::Neq(a, b)
+      | ErasedExpr::Lt(a, b)
+      | ErasedExpr::Lte(a, b)
+      | ErasedExpr::Gt(a, b)
+      | ErasedExpr::Gte(a, b)
+      | ErasedExpr::ArrayLookup {
+        object: a,
+        index: b,
+      } => {
+        *a = self.fold_shared(a);
+        *b = self.fold_shared(b);
+      }
+
+      _ => (),
+    }
+
+    self.folder.fold_expr(ExprBuf::new(folded)).erased
+  }
+
+  fn fold_shared(&mut self, expr: &Arc<ErasedExpr>) -> Arc<ErasedExpr> {
+    if let Some(folded) = self.shared.get(&Arc::as_ptr(expr)) {
+      return folded.clone();
+    }
+
+    let folded = Arc::new(self.fold_expr(expr));
+    self.shared.insert(Arc::as_ptr(expr), folded.clone());
+    folded
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    lit, CanEscape as _, Expr as EdslExpr, Scope as EdslScope, ShaderBuilder, Trigonometry as _,
+  };
+
+  #[derive(Default)]
+  struct Collect<'a> {
+    vars: Vec<Var<'a>>,
+    instrs: usize,
+  }
+
+  impl<'a> Visitor<'a> for Collect<'a> {
+    fn visit_instr(&mut self, instr: Instr<'a>) {
+      self.instrs += 1;
+      walk_instr(self, instr);
+    }
+
+    fn visit_expr(&mut self, expr: Expr<'a>) {
+      if let ExprKind::Var(var) = expr.kind() {
+        self.vars.push(var);
+      }
+
+      walk_expr(self, expr);
+    }
+  }
+
+  #[test]
+  fn visit_shader() {
+    let shader = ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+      let k = s.constant(lit!(2.));
+      let double = s.fun(|_: &mut EdslScope<EdslExpr<f32>>, x: EdslExpr<f32>| x * k.clone());
+
+      s.main_fun(|s: &mut EdslScope<()>| {
+        let x = s.var(double.call(lit!(1.)));
+
+        s.loop_for(
+          0.,
+          |i| i.lt(lit!(3.)),
+          |i| i + 1.,
+          |s, i| {
+            s.when(i.eq(lit!(1.)), |s| s.loop_break())
+              .or(|s| s.set(&x, x.clone() + i.clone()));
+          },
+        );
+
+        let position = vertex.position;
+        s.set(&position, position.to_expr() * x.clone().sin());
+      })
+    });
+
+    let decls: Vec<_> = shader.declarations().collect();
+    assert!(matches!(
+      decls[0],
+      Decl::Const {
+        handle: 0,
+        name: None,
+        ..
+      }
+    ));
+    assert!(matches!(decls[1], Decl::Fun { handle: 0, fun } if fun.args().len() == 1));
+    assert!(matches!(decls[2], Decl::Main(fun) if fun.ret().is_none()));
+
+    let mut collect = Collect::default();
+    walk_shader(&mut collect, &shader);
+
+    // declaration, loop, if, break, else, assignment and assignment of the position
+    assert_eq!(collect.instrs, 7);
+
+    // the function declared first has the scope 0
+    let i = Var::Local {
+      scope: 2,
+      handle: 0,
+    };
+    let x = Var::Local {
+      scope: 1,
+      handle: 0,
+    };
+    let position = Var::BuiltIn(BuiltIn::Vertex(crate::VertexBuiltIn::Position));
+    assert_eq!(
+      collect.vars,
+      [
+        Var::Arg(0),
+        Var::Const(0),
+        i,
+        i,
+        x,
+        i,
+        x,
+        i,
+        position,
+        x,
+        position
+      ]
+    );
+  }
+
+  struct Rewrite {
+    folded: usize,
+  }
+
+  impl Fold for Rewrite {
+    fn fold_expr(&mut self, expr: ExprBuf) -> ExprBuf {
+      self.folded += 1;
+
+      match expr.as_expr().kind() {
+        ExprKind::Lit(Literal::Float(x)) => ExprBuf::lit(Literal::Float(x * 10.)),
+        _ => expr,
+      }
+    }
+
+    fn keep_instr(&mut self, instr: Instr<'_>) -> bool {
+      !matches!(instr, Instr::Discard)
+    }
+  }
+
+  #[test]
+  fn fold_shader() {
+    let shader = ShaderBuilder::new_fragment_shader(|s, fragment| {
+      s.main_fun(|s: &mut EdslScope<()>| {
+        let shared = lit!(1.) + 2.;
+        fragment.discard(s);
+        let _ = s.var(shared.clone() * shared);
+      })
+    });
+
+    let mut rewrite = Rewrite { folded: 0 };
+    let folded = shader.fold(&mut rewrite);
+
+    // both operands copy the addition, but the copies are equal and share a single node, folded once
+    assert_eq!(rewrite.folded, 4);
+
+    let main = match folded.declarations().next() {
+      Some(Decl::Main(fun)) => fun,
+      _ => panic!("main expected"),
+    };
+    let instrs: Vec<_> = main.body().instrs().collect();
+    assert_eq!(instrs.len(), 1);
+    assert!(main.body().location(0).is_some());
+
+    let shared = ExprBuf::binary(
+      BinaryOp::Add,
+      ExprBuf::lit(Literal::Float(10.)),
+      ExprBuf::lit(Literal::Float(20.)),
+    );
+    let expected = ExprBuf::binary(BinaryOp::Mul, shared.clone(), shared);
+    assert!(matches!(instrs[0], Instr::Declare { value, .. } if value == expected.as_expr()));
+
+    // the original shader is left untouched
+    assert_eq!(shader.declarations().count(), 1);
+    assert!(matches!(
+      shader.declarations().next(),
+      Some(Decl::Main(fun)) if fun.body().instrs().len() == 2
+    ));
+  }
+
+  struct DropConsts;
+
+  impl Fold for DropConsts {
+    fn keep_decl(&mut self, decl: Decl<'_>) -> bool {
+      !matches!(decl, Decl::Const { .. })
+    }
+  }
+
+  #[test]
+  fn fold_declarations() {
+    let shader = ShaderBuilder::new_vertex_shader(|mut s, _| {
+      let _ = s.constant(lit!(1.));
+      s.main_fun(|_: &mut EdslScope<()>| {})
+    });
+
+    let folded = shader.fold(&mut DropConsts);
+    let decls: Vec<_> = folded.declarations().collect();
+    assert_eq!(decls.len(), 1);
+    assert!(matches!(decls[0], Decl::Main(_)));
+  }
+}
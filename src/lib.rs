@@ -107,18 +107,52 @@
 
 #![cfg_attr(feature = "fun-call", feature(unboxed_closures), feature(fn_traits))]
 
+mod call_graph;
+mod fingerprint;
+pub mod generic;
+#[cfg(feature = "glsl-import")]
+pub mod importer;
+mod interner;
+pub mod interpreter;
+pub mod ir;
+pub mod library;
+mod optimizer;
+#[cfg(feature = "serde")]
+mod serialization;
+mod typing;
+pub mod validation;
 pub mod writer;
 
 use std::{
+  cell::Cell,
+  collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+  fmt,
+  hash::{Hash, Hasher},
   iter::once,
   marker::PhantomData,
+  mem,
   ops::{self, Deref, DerefMut},
+  panic::Location,
+  rc::Rc,
+  sync::{Arc, Mutex},
 };
 
 /// A fully built shader stage as represented in Rust, obtained by adding the `main` function to a [`ShaderBuilder`].
-#[derive(Debug)]
+///
+/// With the `serde` feature, shaders implement `Serialize` and `Deserialize`, using a versioned format so that they can
+/// be cached or built by another process. Expressions shared in the shader are serialized once and stay shared once
+/// deserialized, and the locations of the Rust code that built the shader are not serialized. Deserialized shaders are
+/// validated by writers like any other shader.
+///
+/// Shaders compare and hash by content: two shaders built the same way are equal, even if they were built by different
+/// Rust code. Floating-point literals compare by bits, so `0.` and `-0.` differ while NaN literals are equal to
+/// themselves. See [`Shader::fingerprint`] for a hash that is stable across runs.
+#[derive(Clone, Debug)]
 pub struct Shader {
-  pub(crate) builder: ShaderBuilder,
+  pub(crate) stage: ShaderStage,
+  pub(crate) decls: Vec<ShaderDecl>,
+  pub(crate) next_fun_handle: u16,
+  pub(crate) next_global_handle: u16,
 }
 
 impl AsRef<Shader> for Shader {
@@ -127,16 +161,414 @@ impl AsRef<Shader> for Shader {
   }
 }
 
+impl PartialEq for Shader {
+  fn eq(&self, other: &Self) -> bool {
+    self.stage == other.stage && self.decls == other.decls
+  }
+}
+
+impl Eq for Shader {}
+
+impl Hash for Shader {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    self.stage.hash(state);
+    self.decls.hash(state);
+  }
+}
+
+impl Shader {
+  /// Fingerprint of the content of the shader, to use as a cache key.
+  ///
+  /// Shaders equal as per [`PartialEq`] have the same fingerprint. Unlike [`Hash`], which depends on the [`Hasher`],
+  /// the fingerprint is the same across runs and platforms, so it can be stored. It can change between versions of this
+  /// crate, though.
+  ///
+  /// The locations of the Rust code that built the shader are not part of its content.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use shades::{Scope, Shader, ShaderBuilder, lit};
+  ///
+  /// fn build() -> Shader {
+  ///   ShaderBuilder::new_vertex_shader(|s, vertex| {
+  ///     s.main_fun(|s: &mut Scope<()>| s.set(vertex.position, lit!(0., 0., 0., 1.)))
+  ///   })
+  /// }
+  ///
+  /// assert_eq!(build(), build());
+  /// assert_eq!(build().fingerprint(), build().fingerprint());
+  /// ```
+  pub fn fingerprint(&self) -> u64 {
+    fingerprint::fingerprint(self)
+  }
+
+  /// Inputs, outputs and uniforms declared by the shader, in declaration order.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use shades::{InterfaceQualifier, Scope, ShaderBuilder, ToType as _, V3, inputs, uniforms};
+  ///
+  /// let shader = ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+  ///   inputs!(s, position: V3<f32>);
+  ///   uniforms!(s, time: f32);
+  ///
+  ///   s.main_fun(|s: &mut Scope<()>| {})
+  /// });
+  ///
+  /// let decls: Vec<_> = shader.interface().collect();
+  /// assert_eq!(decls.len(), 2);
+  /// assert_eq!(decls[0].qualifier(), InterfaceQualifier::In);
+  /// assert_eq!(decls[0].name(), "position");
+  /// assert_eq!(decls[0].ty(), &V3::<f32>::ty());
+  /// assert_eq!(decls[1].qualifier(), InterfaceQualifier::Uniform);
+  /// assert_eq!(decls[1].name(), "time");
+  /// ```
+  pub fn interface(&self) -> impl Iterator<Item = InterfaceDecl<'_>> {
+    self.decls.iter().filter_map(|decl| match decl {
+      ShaderDecl::In(name, ty) => Some(InterfaceDecl {
+        qualifier: InterfaceQualifier::In,
+        name,
+        ty,
+        color_attachment: None,
+      }),
+
+      ShaderDecl::Out(name, ty, color_attachment) => Some(InterfaceDecl {
+        qualifier: InterfaceQualifier::Out,
+        name,
+        ty,
+        color_attachment: *color_attachment,
+      }),
+
+      ShaderDecl::Uniform(name, ty) => Some(InterfaceDecl {
+        qualifier: InterfaceQualifier::Uniform,
+        name,
+        ty,
+        color_attachment: None,
+      }),
+
+      _ => None,
+    })
+  }
+
+  /// Inputs declared by the shader, in declaration order.
+  pub fn inputs(&self) -> impl Iterator<Item = InterfaceDecl<'_>> {
+    self
+      .interface()
+      .filter(|decl| decl.qualifier == InterfaceQualifier::In)
+  }
+
+  /// Outputs declared by the shader, in declaration order.
+  pub fn outputs(&self) -> impl Iterator<Item = InterfaceDecl<'_>> {
+    self
+      .interface()
+      .filter(|decl| decl.qualifier == InterfaceQualifier::Out)
+  }
+
+  /// Uniforms declared by the shader, in declaration order.
+  pub fn uniforms(&self) -> impl Iterator<Item = InterfaceDecl<'_>> {
+    self
+      .interface()
+      .filter(|decl| decl.qualifier == InterfaceQualifier::Uniform)
+  }
+
+  /// Built-ins read by the shader.
+  ///
+  /// Built-ins used only as the target of an assignment are not part of this set; indices used to access them are,
+  /// though.
+  pub fn builtins_read(&self) -> BTreeSet<BuiltIn> {
+    self.builtin_usage().read
+  }
+
+  /// Built-ins written by the shader.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use shades::{BuiltIn, Scope, ShaderBuilder, VertexBuiltIn, lit};
+  ///
+  /// let shader = ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+  ///   s.main_fun(|s: &mut Scope<()>| {
+  ///     s.set(&vertex.position, lit!(0., 0., 0., 1.));
+  ///   })
+  /// });
+  ///
+  /// assert!(shader.builtins_written().contains(&BuiltIn::Vertex(VertexBuiltIn::Position)));
+  /// assert!(shader.builtins_read().is_empty());
+  /// ```
+  pub fn builtins_written(&self) -> BTreeSet<BuiltIn> {
+    self.builtin_usage().written
+  }
+
+  /// Declarations of the shader, in declaration order; see [`ir`] to analyze them.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use shades::{Scope, ShaderBuilder, inputs};
+  /// use shades::ir::Decl;
+  ///
+  /// let shader = ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+  ///   inputs!(s, position: f32);
+  ///
+  ///   s.main_fun(|s: &mut Scope<()>| {})
+  /// });
+  ///
+  /// let decls: Vec<_> = shader.declarations().collect();
+  /// assert!(matches!(decls[0], Decl::Input { name: "position", .. }));
+  /// assert!(matches!(decls[1], Decl::Main(_)));
+  /// ```
+  pub fn declarations(&self) -> impl ExactSizeIterator<Item = ir::Decl<'_>> {
+    self.decls.iter().map(ir::Decl::new)
+  }
+
+  /// Rewrite the shader into a new one with a [`Fold`](ir::Fold).
+  ///
+  /// Expressions are folded bottom-up, and expressions shared in the shader are folded once and stay shared.
+  pub fn fold(&self, folder: &mut (impl ir::Fold + ?Sized)) -> Shader {
+    ir::fold(self, folder)
+  }
+
+  /// Check the shader for mistakes the EDSL cannot prevent, such as variables used outside of their scope.
+  ///
+  /// Writers validate the shaders they write, so calling this method is only needed to get the details of what is
+  /// wrong with a shader. See the [`validation`] module for further details.
+  ///
+  /// # Return
+  ///
+  /// The list of [`Diagnostic`](validation::Diagnostic)s found in the shader, which is empty if the shader is valid.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use shades::{Scope, ShaderBuilder};
+  ///
+  /// let shader = ShaderBuilder::new_vertex_shader(|s, _| s.main_fun(|s: &mut Scope<()>| {}));
+  /// assert!(shader.validate().is_empty());
+  /// ```
+  pub fn validate(&self) -> Vec<validation::Diagnostic> {
+    validation::validate(self)
+  }
+
+  fn builtin_usage(&self) -> BuiltInUsage {
+    let mut usage = BuiltInUsage::default();
+
+    for decl in &self.decls {
+      match decl {
+        ShaderDecl::Main(fun) | ShaderDecl::FunDef(_, fun) => {
+          usage.visit_scope(&fun.scope);
+
+          if let ErasedReturn::Expr(_, expr) = &fun.ret {
+            usage.visit_expr(expr);
+          }
+        }
+
+        ShaderDecl::Const(_, _, expr, _) => usage.visit_expr(expr),
+
+        _ => (),
+      }
+    }
+
+    usage
+  }
+}
+
+/// Storage qualifier of an [`InterfaceDecl`].
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum InterfaceQualifier {
+  /// Input of the shader stage.
+  In,
+
+  /// Output of the shader stage.
+  Out,
+
+  /// Uniform.
+  Uniform,
+}
+
+/// Input, output or uniform declared by a [`Shader`].
+///
+/// See [`Shader::interface`] for further details.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct InterfaceDecl<'a> {
+  qualifier: InterfaceQualifier,
+  name: &'a str,
+  ty: &'a Type,
+  color_attachment: Option<ColorAttachment>,
+}
+
+impl<'a> InterfaceDecl<'a> {
+  /// Storage qualifier of the declaration.
+  pub fn qualifier(&self) -> InterfaceQualifier {
+    self.qualifier
+  }
+
+  /// Name of the declaration.
+  pub fn name(&self) -> &'a str {
+    self.name
+  }
+
+  /// Type of the declaration.
+  pub fn ty(&self) -> &'a Type {
+    self.ty
+  }
+
+  /// Colour attachment the output writes to, if any.
+  pub fn color_attachment(&self) -> Option<ColorAttachment> {
+    self.color_attachment
+  }
+
+  /// Explicit location of the declaration, if any.
+  pub fn location(&self) -> Option<u32> {
+    self
+      .color_attachment
+      .map(|attachment| attachment.location())
+  }
+}
+
+// Built-ins read and written by a shader.
+#[derive(Debug, Default)]
+struct BuiltInUsage {
+  read: BTreeSet<BuiltIn>,
+  written: BTreeSet<BuiltIn>,
+}
+
+impl BuiltInUsage {
+  fn visit_scope(&mut self, scope: &ErasedScope) {
+    for instr in &scope.instructions {
+      match instr {
+        ScopeInstr::VarDecl { init_value, .. } => self.visit_expr(init_value),
+
+        ScopeInstr::Return(ErasedReturn::Expr(_, expr)) => self.visit_expr(expr),
+
+        ScopeInstr::Return(ErasedReturn::Void)
+        | ScopeInstr::Continue
+        | ScopeInstr::Break
+        | ScopeInstr::Discard => (),
+
+        ScopeInstr::If { condition, scope }
+        | ScopeInstr::ElseIf { condition, scope }
+        | ScopeInstr::While { condition, scope } => {
+          self.visit_expr(condition);
+          self.visit_scope(scope);
+        }
+
+        ScopeInstr::Else { scope } => self.visit_scope(scope),
+
+        ScopeInstr::For {
+          init_expr,
+          condition,
+          post_expr,
+          scope,
+          ..
+        } => {
+          self.visit_expr(init_expr);
+          self.visit_expr(condition);
+          self.visit_expr(post_expr);
+          self.visit_scope(scope);
+        }
+
+        ScopeInstr::MutateVar { var, expr } => {
+          self.visit_assigned(var);
+          self.visit_expr(expr);
+        }
+      }
+    }
+  }
+
+  // Visit the left-hand side of an assignment.
+  fn visit_assigned(&mut self, expr: &ErasedExpr) {
+    match expr {
+      ErasedExpr::Var(ScopedHandle::BuiltIn(builtin)) => {
+        self.written.insert(*builtin);
+      }
+
+      ErasedExpr::Swizzle(object, _) => self.visit_assigned(object),
+
+      ErasedExpr::Field { object, field } => {
+        self.visit_assigned(object);
+        self.visit_assigned(field);
+      }
+
+      ErasedExpr::ArrayLookup { object, index } => {
+        self.visit_assigned(object);
+        self.visit_expr(index);
+      }
+
+      _ => self.visit_expr(expr),
+    }
+  }
+
+  fn visit_expr(&mut self, expr: &ErasedExpr) {
+    match expr {
+      ErasedExpr::Var(ScopedHandle::BuiltIn(builtin)) => {
+        self.read.insert(*builtin);
+      }
+
+      ErasedExpr::Array(_, exprs) | ErasedExpr::FunCall(_, exprs) => {
+        for expr in exprs {
+          self.visit_expr(expr);
+        }
+      }
+
+      ErasedExpr::Not(a) | ErasedExpr::Neg(a) | ErasedExpr::Swizzle(a, _) => self.visit_expr(a),
+
+      ErasedExpr::And(a, b)
+      | ErasedExpr::Or(a, b)
+      | ErasedExpr::Xor(a, b)
+      | ErasedExpr::BitOr(a, b)
+      | ErasedExpr::BitAnd(a, b)
+      | ErasedExpr::BitXor(a, b)
+      | ErasedExpr::Add(a, b)
+      | ErasedExpr::Sub(a, b)
+      | ErasedExpr::Mul(a, b)
+      | ErasedExpr::Div(a, b)
+      | ErasedExpr::Rem(a, b)
+      | ErasedExpr::Shl(a, b)
+      | ErasedExpr::Shr(a, b)
+      | ErasedExpr::Eq(a, b)
+      | ErasedExpr::Neq(a, b)
+      | ErasedExpr::Lt(a, b)
+      | ErasedExpr::Lte(a, b)
+      | ErasedExpr::Gt(a, b)
+      | ErasedExpr::Gte(a, b)
+      | ErasedExpr::Field {
+        object: a,
+        field: b,
+      }
+      | ErasedExpr::ArrayLookup {
+        object: a,
+        index: b,
+      } => {
+        self.visit_expr(a);
+        self.visit_expr(b);
+      }
+
+      _ => (),
+    }
+  }
+}
+
 /// A shader builder.
 ///
 /// This opaque type is the representation of a shader stage in Rust. It contains constants, uniforms, inputs, outputs and
 /// functions declarations. Such a type is used to build a shader stage and is fully built when the `main` function is
 /// present in its code. See [`ShaderBuilder::main_fun`] for further details.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct ShaderBuilder {
+  pub(crate) stage: ShaderStage,
   pub(crate) decls: Vec<ShaderDecl>,
   next_fun_handle: u16,
   next_global_handle: u16,
+  // identifier of the next scope, so that variables of different scopes never share a handle
+  pub(crate) next_scope: u16,
+  // sub-expressions of the declarations, shared by the equal sub-expressions of the declarations added afterwards
+  interner: interner::Interner,
+  // handles of the declarations imported from libraries
+  imports: HashMap<library::Imported, u16>,
+  // instances of the generic functions, created when they are called
+  instances: Option<Arc<Mutex<generic::Instances>>>,
 }
 
 impl ShaderBuilder {
@@ -169,7 +601,7 @@ impl ShaderBuilder {
   /// });
   /// ```
   pub fn new_vertex_shader(f: impl FnOnce(Self, VertexShaderEnv) -> Shader) -> Shader {
-    f(Self::new(), VertexShaderEnv::new())
+    f(Self::new(ShaderStage::Vertex), VertexShaderEnv::new())
   }
 
   /// Create a new _tessellation control shader_.
@@ -200,7 +632,7 @@ impl ShaderBuilder {
   /// });
   /// ```
   pub fn new_tess_ctrl_shader(f: impl FnOnce(Self, TessCtrlShaderEnv) -> Shader) -> Shader {
-    f(Self::new(), TessCtrlShaderEnv::new())
+    f(Self::new(ShaderStage::TessCtrl), TessCtrlShaderEnv::new())
   }
 
   /// Create a new _tessellation evaluation shader_.
@@ -233,7 +665,7 @@ impl ShaderBuilder {
   /// });
   /// ```
   pub fn new_tess_eval_shader(f: impl FnOnce(Self, TessEvalShaderEnv) -> Shader) -> Shader {
-    f(Self::new(), TessEvalShaderEnv::new())
+    f(Self::new(ShaderStage::TessEval), TessEvalShaderEnv::new())
   }
 
   /// Create a new _geometry shader_.
@@ -265,7 +697,7 @@ impl ShaderBuilder {
   /// });
   /// ```
   pub fn new_geometry_shader(f: impl FnOnce(Self, GeometryShaderEnv) -> Shader) -> Shader {
-    f(Self::new(), GeometryShaderEnv::new())
+    f(Self::new(ShaderStage::Geometry), GeometryShaderEnv::new())
   }
 
   /// Create a new _fragment shader_.
@@ -297,18 +729,114 @@ impl ShaderBuilder {
   /// });
   /// ```
   pub fn new_fragment_shader(f: impl FnOnce(Self, FragmentShaderEnv) -> Shader) -> Shader {
-    f(Self::new(), FragmentShaderEnv::new())
+    f(Self::new(ShaderStage::Fragment), FragmentShaderEnv::new())
   }
 
-  /// Create a new empty shader.
-  fn new() -> Self {
+  /// Create a new empty shader of the given stage.
+  pub(crate) fn new(stage: ShaderStage) -> Self {
     Self {
+      stage,
       decls: Vec::new(),
       next_fun_handle: 0,
       next_global_handle: 0,
+      next_scope: 0,
+      interner: interner::Interner::default(),
+      imports: HashMap::new(),
+      instances: None,
+    }
+  }
+
+  /// The shader built so far, without the state only needed while building it.
+  pub(crate) fn into_shader(self) -> Shader {
+    Shader {
+      stage: self.stage,
+      decls: self.decls,
+      next_fun_handle: self.next_fun_handle,
+      next_global_handle: self.next_global_handle,
+    }
+  }
+
+  /// Build the definition of a function, with scope identifiers distinct from the ones of the other functions.
+  fn build_fn<F, R, A>(&mut self, f: F) -> FunDef<R, A>
+  where
+    F: ToFun<R, A>,
+  {
+    let next_scope = Rc::new(Cell::new(self.next_scope));
+    let fundef = f.build_fn_with_scopes(&next_scope);
+    self.next_scope = next_scope.get();
+
+    fundef
+  }
+
+  /// Give fresh identifiers to the scopes of a function built without the builder, such as an imported function or
+  /// an instance of a generic function.
+  pub(crate) fn renumber_scopes(&mut self, fun: &mut ErasedFun) {
+    fn renumber(scope: &mut ErasedScope, next_scope: &mut u16, ids: &mut HashMap<u16, u16>) {
+      ids.insert(scope.id, *next_scope);
+      scope.id = *next_scope;
+      *next_scope = next_scope.wrapping_add(1);
+
+      for instr in &mut scope.instructions {
+        match instr {
+          ScopeInstr::If { scope, .. }
+          | ScopeInstr::ElseIf { scope, .. }
+          | ScopeInstr::Else { scope }
+          | ScopeInstr::For { scope, .. }
+          | ScopeInstr::While { scope, .. } => renumber(scope, next_scope, ids),
+          _ => (),
+        }
+      }
+    }
+
+    fn rename(handle: &mut ScopedHandle, ids: &HashMap<u16, u16>) {
+      if let ScopedHandle::FunVar { subscope, .. } = handle {
+        if let Some(&id) = ids.get(subscope) {
+          *subscope = id;
+        }
+      }
+    }
+
+    fn rename_decls(scope: &mut ErasedScope, ids: &HashMap<u16, u16>) {
+      for instr in &mut scope.instructions {
+        match instr {
+          ScopeInstr::VarDecl { handle, .. } => rename(handle, ids),
+
+          ScopeInstr::For {
+            init_handle, scope, ..
+          } => {
+            rename(init_handle, ids);
+            rename_decls(scope, ids);
+          }
+
+          ScopeInstr::If { scope, .. }
+          | ScopeInstr::ElseIf { scope, .. }
+          | ScopeInstr::Else { scope }
+          | ScopeInstr::While { scope, .. } => rename_decls(scope, ids),
+
+          _ => (),
+        }
+      }
+    }
+
+    let mut ids = HashMap::new();
+    renumber(&mut fun.scope, &mut self.next_scope, &mut ids);
+    rename_decls(&mut fun.scope, &ids);
+
+    for expr in optimizer::fun_exprs_mut(fun) {
+      optimizer::walk_expr_mut(expr, &mut |expr| {
+        if let ErasedExpr::Var(handle) = expr {
+          rename(handle, &ids);
+        }
+      });
     }
   }
 
+  /// Add a declaration to the shader, sharing its sub-expressions with the equal ones already declared.
+  pub(crate) fn push_decl(&mut self, mut decl: ShaderDecl) {
+    self.interner.intern_decl(&mut decl);
+    self.decls.push(decl);
+  }
+
   /// Create a new function in the shader and get its handle for future use.
   ///
   /// This method requires to pass a closure encoding the argument(s) and return type of the function to create. The
@@ -351,6 +879,22 @@ impl ShaderBuilder {
   ///
   /// Please refer to the [`Scope`] documentation for a complete list of the instructions you can record.
   ///
+  /// Arguments and return values can be arrays as long as their size is known, such as [`Expr<[f32; 2]>`](Expr) or
+  /// [`Expr<[[f32; 2]; 3]>`](Expr). Unsized arrays, such as [`Expr<[f32]>`](Expr), can be neither passed to nor
+  /// returned from functions:
+  ///
+  /// ```
+  /// # use shades::ShaderBuilder;
+  /// # ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+  /// use shades::{Expr, Scope};
+  ///
+  /// let swap = s.fun(|s: &mut Scope<Expr<[f32; 2]>>, a: Expr<[f32; 2]>| {
+  ///   Expr::from([a.at(1), a.at(0)])
+  /// });
+  /// # s.main_fun(|s: &mut Scope<()>| {})
+  /// # });
+  /// ```
+  ///
   /// # Caveats
   ///
   /// On a last note, you can still use the `return` keyword from Rust, but it is highly discouraged, as returning with
@@ -428,126 +972,1180 @@ impl ShaderBuilder {
   ///   })
   /// });
   /// ```
+  #[track_caller]
   pub fn fun<F, R, A>(&mut self, f: F) -> FunHandle<R, A>
   where
-    F: ToFun<R, A>,
+    F: ToFun<R, A>,
+  {
+    let mut fundef = self.build_fn(f);
+    let handle = self.next_fun_handle();
+
+    fundef.erased.location = Some(Location::caller());
+    self.push_decl(ShaderDecl::FunDef(handle, fundef.erased));
+
+    FunHandle {
+      erased: ErasedFunHandle::UserDefined(handle as _),
+      _phantom: PhantomData,
+    }
+  }
+
+  /// Declare a new function with a name, and names for its arguments.
+  ///
+  /// This method is similar to [`ShaderBuilder::fun`], but the name of the function and the names of its arguments
+  /// are used by writers to generate the identifiers of the function and its arguments, instead of generated ones.
+  /// Names are sanitized to be valid identifiers and suffixed if they clash with other names or reserved keywords.
+  /// `arg_names` can name fewer arguments than the function has; the remaining arguments keep generated identifiers.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use shades::{Exponential as _, Expr, Scope, ShaderBuilder, lit, writer::glsl};
+  ///
+  /// let shader = ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+  ///   let square = s.fun_named("square", &["x"], |s: &mut Scope<Expr<f32>>, x: Expr<f32>| {
+  ///     x.pow(2.)
+  ///   });
+  ///
+  ///   s.main_fun(|s: &mut Scope<()>| {
+  ///     let nine = s.var(square.call(lit!(3.)));
+  ///   })
+  /// });
+  ///
+  /// let output = glsl::write_shader_to_str(&shader).unwrap();
+  /// assert!(output.contains("float square(float x) {"));
+  /// ```
+  #[track_caller]
+  pub fn fun_named<F, R, A>(
+    &mut self,
+    name: impl Into<String>,
+    arg_names: &[&str],
+    f: F,
+  ) -> FunHandle<R, A>
+  where
+    F: ToFun<R, A>,
+  {
+    let handle = self.fun(f);
+
+    if let Some(ShaderDecl::FunDef(_, fun)) = self.decls.last_mut() {
+      fun.name = Some(name.into());
+      fun.arg_names = arg_names.iter().map(|&name| name.to_owned()).collect();
+    }
+
+    handle
+  }
+
+  /// Declare the `main` function of the shader stage.
+  ///
+  /// This method is very similar to [`ShaderBuilder::fun`] in the sense it declares a function. However, it declares the special
+  /// `main` entry-point of a shader stage, which doesn’t have have argument and returns nothing, and is the only
+  /// way to finalize the building of a [`Shader`].
+  ///
+  /// The input closure must take a single argument: a mutable reference on a `Scope<()>`, as the `main` function
+  /// cannot return anything.
+  ///
+  /// # Return
+  ///
+  /// The fully built [`Shader`], which cannot be altered anymore.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use shades::{Scope, ShaderBuilder};
+  ///
+  /// let shader = ShaderBuilder::new_vertex_shader(|s, vertex| {
+  ///   s.main_fun(|s: &mut Scope<()>| {
+  ///     // …
+  ///   })
+  /// });
+  /// ```
+  #[track_caller]
+  pub fn main_fun<F, R>(mut self, f: F) -> Shader
+  where
+    F: ToFun<R, ()>,
+  {
+    let mut fundef = self.build_fn(f);
+
+    fundef.erased.location = Some(Location::caller());
+    self.add_instances();
+    self.push_decl(ShaderDecl::Main(fundef.erased));
+    self.remove_unused_imports();
+
+    self.into_shader()
+  }
+
+  /// Declare a new constant, shared between all functions and constants that come next.
+  ///
+  /// The input argument is any object that can be transformed [`Into`] an [`Expr<T>`]. At this level in the
+  /// shader, pretty much nothing but literals and other constants are accepted here.
+  ///
+  /// # Return
+  ///
+  /// An [`Expr<T>`] representing the constant passed as input.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// # use shades::{EscapeScope, Expr, Scope, ShaderBuilder, lit};
+  /// # ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+  /// // don’t do this.
+  /// let illum_coefficient: Expr<f32> = s.constant(10.);
+  /// # s.main_fun(|s: &mut Scope<()>| {})
+  /// # });
+  /// ```
+  pub fn constant<T>(&mut self, expr: impl Into<Expr<T>>) -> Expr<T>
+  where
+    T: ToType,
+  {
+    let handle = self.next_global_handle;
+    self.next_global_handle = self.next_global_handle.wrapping_add(1);
+
+    self.push_decl(ShaderDecl::Const(handle, T::ty(), expr.into().erased, None));
+
+    Expr::new(ErasedExpr::Var(ScopedHandle::global(handle)))
+  }
+
+  /// Declare a new named constant.
+  ///
+  /// This method is similar to [`ShaderBuilder::constant`], but the name is used by writers to generate the
+  /// identifier of the constant.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// # use shades::{Expr, Scope, ShaderBuilder, writer::glsl};
+  /// let shader = ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+  ///   let illum_coefficient: Expr<f32> = s.constant_named("illum_coefficient", 10.);
+  ///   s.main_fun(|s: &mut Scope<()>| {})
+  /// });
+  ///
+  /// let output = glsl::write_shader_to_str(&shader).unwrap();
+  /// assert!(output.contains("const float illum_coefficient = 10.;"));
+  /// ```
+  pub fn constant_named<T>(&mut self, name: impl Into<String>, expr: impl Into<Expr<T>>) -> Expr<T>
+  where
+    T: ToType,
+  {
+    let constant = self.constant(expr);
+
+    if let Some(ShaderDecl::Const(_, _, _, const_name)) = self.decls.last_mut() {
+      *const_name = Some(name.into());
+    }
+
+    constant
+  }
+
+  /// Declare a new input, shared between all functions and constants that come next.
+  ///
+  /// The name of the input is checked before being declared; see [`InterfaceError`] for the list of errors that can
+  /// occur. You are advised to use the [`inputs!`](inputs) macro instead.
+  ///
+  /// # Return
+  ///
+  /// An [`Expr<T>`] representing the input. Inputs are read-only: they cannot be passed to [`Scope::set`].
+  ///
+  /// # Errors
+  ///
+  /// Fail with [`InterfaceError`] if the name is not a valid identifier, is reserved or is already declared.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// # use shades::{InterfaceError, Scope, ShaderBuilder, V3};
+  /// # ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+  /// let position = s.input::<V3<f32>>("position").unwrap();
+  ///
+  /// assert_eq!(
+  ///   s.input::<V3<f32>>("position").unwrap_err(),
+  ///   InterfaceError::DuplicateName("position".to_owned())
+  /// );
+  /// # s.main_fun(|s: &mut Scope<()>| {})
+  /// # });
+  /// ```
+  pub fn input<T>(&mut self, name: &str) -> Result<Expr<T>, InterfaceError>
+  where
+    T: ?Sized + ToType,
+  {
+    self.check_interface_name(name)?;
+    Ok(unsafe { self.input_unchecked(name) })
+  }
+
+  /// Declare a new input without checking its name.
+  ///
+  /// # Safety
+  ///
+  /// The name of the input is written verbatim in the generated code. It must be a valid identifier in the target
+  /// shading language and must not collide with any other declaration.
+  pub unsafe fn input_unchecked<T>(&mut self, name: &str) -> Expr<T>
+  where
+    T: ?Sized + ToType,
+  {
+    let name = name.to_owned();
+    self.decls.push(ShaderDecl::In(name.clone(), T::ty()));
+    Expr::new(ErasedExpr::Var(ScopedHandle::Input(name)))
+  }
+
+  /// Declare a new output, shared between all functions and constants that come next.
+  ///
+  /// The name of the output is checked before being declared; see [`InterfaceError`] for the list of errors that can
+  /// occur. You are advised to use the [`outputs!`](outputs) macro instead.
+  ///
+  /// # Return
+  ///
+  /// An [`Output<T>`] representing the output. Outputs are write-only: they can be passed to [`Scope::set`] but cannot
+  /// be read.
+  ///
+  /// # Errors
+  ///
+  /// Fail with [`InterfaceError`] if the name is not a valid identifier, is reserved or is already declared.
+  pub fn output<T>(&mut self, name: &str) -> Result<Output<T>, InterfaceError>
+  where
+    T: ?Sized + ToType,
+  {
+    self.check_interface_name(name)?;
+    Ok(unsafe { self.output_unchecked(name) })
+  }
+
+  /// Declare a new output without checking its name.
+  ///
+  /// # Safety
+  ///
+  /// The name of the output is written verbatim in the generated code. It must be a valid identifier in the target
+  /// shading language and must not collide with any other declaration.
+  pub unsafe fn output_unchecked<T>(&mut self, name: &str) -> Output<T>
+  where
+    T: ?Sized + ToType,
+  {
+    let name = name.to_owned();
+    self
+      .decls
+      .push(ShaderDecl::Out(name.clone(), T::ty(), None));
+    Output(Var::new(ScopedHandle::Output(name)))
+  }
+
+  /// Declare a new fragment output bound to a colour attachment, shared between all functions and constants that come
+  /// next.
+  ///
+  /// The [`ColorAttachment`] gives the location of the attachment the output writes to and its blend index — only
+  /// meaningful for dual-source blending. Only types implementing [`ColorType`] can be written to a colour attachment.
+  ///
+  /// You are advised to use the [`color_attachments!`](color_attachments) macro instead.
+  ///
+  /// # Return
+  ///
+  /// An [`Output<T>`] representing the output.
+  ///
+  /// # Errors
+  ///
+  /// Fail with [`InterfaceError`] if the name is not a valid identifier, is reserved or is already declared, if the
+  /// shader is not a fragment shader, or if another output already writes to the same colour attachment.
+  pub fn color_attachment<T>(
+    &mut self,
+    name: &str,
+    attachment: ColorAttachment,
+  ) -> Result<Output<T>, InterfaceError>
+  where
+    T: ColorType,
+  {
+    self.check_interface_name(name)?;
+
+    if self.stage != ShaderStage::Fragment {
+      return Err(InterfaceError::ColorAttachmentOutsideFragmentShader {
+        name: name.to_owned(),
+        stage: self.stage,
+      });
+    }
+
+    let used = self.decls.iter().any(|decl| match decl {
+      ShaderDecl::Out(_, _, Some(a)) => *a == attachment,
+      _ => false,
+    });
+
+    if used {
+      return Err(InterfaceError::DuplicateColorAttachment {
+        name: name.to_owned(),
+        attachment,
+      });
+    }
+
+    Ok(unsafe { self.color_attachment_unchecked(name, attachment) })
+  }
+
+  /// Declare a new fragment output bound to a colour attachment without checking its name nor its attachment.
+  ///
+  /// # Safety
+  ///
+  /// The name of the output is written verbatim in the generated code. It must be a valid identifier in the target
+  /// shading language and must not collide with any other declaration. Two outputs must not share the same
+  /// location and blend index, and the shader must be a fragment shader.
+  pub unsafe fn color_attachment_unchecked<T>(
+    &mut self,
+    name: &str,
+    attachment: ColorAttachment,
+  ) -> Output<T>
+  where
+    T: ColorType,
+  {
+    let name = name.to_owned();
+    self
+      .decls
+      .push(ShaderDecl::Out(name.clone(), T::ty(), Some(attachment)));
+    Output(Var::new(ScopedHandle::Output(name)))
+  }
+
+  /// Declare a new uniform, shared between all functions and constants that come next.
+  ///
+  /// The name of the uniform is checked before being declared; see [`InterfaceError`] for the list of errors that can
+  /// occur. You are advised to use the [`uniforms!`](uniforms) macro instead.
+  ///
+  /// # Return
+  ///
+  /// An [`Expr<T>`] representing the uniform. Uniforms are read-only: they cannot be passed to [`Scope::set`].
+  ///
+  /// # Errors
+  ///
+  /// Fail with [`InterfaceError`] if the name is not a valid identifier, is reserved or is already declared.
+  pub fn uniform<T>(&mut self, name: &str) -> Result<Expr<T>, InterfaceError>
+  where
+    T: ToType,
+  {
+    self.check_interface_name(name)?;
+    Ok(unsafe { self.uniform_unchecked(name) })
+  }
+
+  /// Declare a new uniform without checking its name.
+  ///
+  /// # Safety
+  ///
+  /// The name of the uniform is written verbatim in the generated code. It must be a valid identifier in the target
+  /// shading language and must not collide with any other declaration.
+  pub unsafe fn uniform_unchecked<T>(&mut self, name: &str) -> Expr<T>
+  where
+    T: ToType,
+  {
+    let name = name.to_owned();
+    self.decls.push(ShaderDecl::Uniform(name.clone(), T::ty()));
+    Expr::new(ErasedExpr::Var(ScopedHandle::uniform(name)))
+  }
+
+  /// Check that a name can be used to declare an input, output or uniform.
+  fn check_interface_name(&self, name: &str) -> Result<(), InterfaceError> {
+    let mut chars = name.chars();
+    let valid = matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+      && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+
+    if !valid {
+      return Err(InterfaceError::InvalidIdentifier(name.to_owned()));
+    }
+
+    if writer::glsl::is_reserved_identifier(name) {
+      return Err(InterfaceError::ReservedIdentifier(name.to_owned()));
+    }
+
+    if writer::glsl::is_generated_identifier(name) {
+      return Err(InterfaceError::GeneratedIdentifier(name.to_owned()));
+    }
+
+    let declared = self.decls.iter().any(|decl| match decl {
+      ShaderDecl::In(n, _) | ShaderDecl::Out(n, _, _) | ShaderDecl::Uniform(n, _) => n == name,
+      _ => false,
+    });
+
+    if declared {
+      return Err(InterfaceError::DuplicateName(name.to_owned()));
+    }
+
+    Ok(())
+  }
+}
+
+/// Errors that can occur when declaring inputs, outputs and uniforms.
+///
+/// Names of inputs, outputs and uniforms are written verbatim in the generated GLSL, so they are checked against GLSL
+/// only. Other writers, such as the [Rust writer](writer::rust), bind them to names of their own when they are not
+/// valid in their language.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum InterfaceError {
+  /// The name is not a valid identifier.
+  ///
+  /// Identifiers must start with an ASCII letter or an underscore, followed by ASCII letters, digits or underscores.
+  InvalidIdentifier(String),
+
+  /// The name is reserved by GLSL.
+  ///
+  /// This includes keywords, words reserved for future use and `main`, along with identifiers starting with `gl_` or
+  /// containing two consecutive underscores.
+  ReservedIdentifier(String),
+
+  /// The name clashes with an identifier generated by the writers, such as `var_0_0`, `arg_1`, `fun_3` or `glob_2`.
+  GeneratedIdentifier(String),
+
+  /// The name is already used by another input, output or uniform.
+  DuplicateName(String),
+
+  /// The colour attachment is already used by another output.
+  DuplicateColorAttachment {
+    /// Name of the output being declared.
+    name: String,
+
+    /// Colour attachment already in use.
+    attachment: ColorAttachment,
+  },
+
+  /// A colour attachment is declared in a shader of another stage than the fragment one.
+  ColorAttachmentOutsideFragmentShader {
+    /// Name of the output being declared.
+    name: String,
+
+    /// Stage of the shader.
+    stage: ShaderStage,
+  },
+}
+
+impl fmt::Display for InterfaceError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      InterfaceError::InvalidIdentifier(name) => write!(f, "`{}` is not a valid identifier", name),
+      InterfaceError::ReservedIdentifier(name) => write!(f, "`{}` is a reserved identifier", name),
+      InterfaceError::GeneratedIdentifier(name) => {
+        write!(f, "`{}` clashes with a generated identifier", name)
+      }
+      InterfaceError::DuplicateName(name) => write!(f, "`{}` is already declared", name),
+      InterfaceError::DuplicateColorAttachment { name, attachment } => write!(
+        f,
+        "cannot declare `{}`: colour attachment (location = {}, index = {}) is already in use",
+        name,
+        attachment.location(),
+        attachment.index()
+      ),
+      InterfaceError::ColorAttachmentOutsideFragmentShader { name, stage } => write!(
+        f,
+        "cannot declare `{}`: colour attachments are only available in fragment shaders, not in {} shaders",
+        name, stage
+      ),
+    }
+  }
+}
+
+impl std::error::Error for InterfaceError {}
+
+/// Shader stages, in pipeline order.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ShaderStage {
+  /// Vertex shader stage.
+  Vertex,
+
+  /// Tessellation control shader stage.
+  TessCtrl,
+
+  /// Tessellation evaluation shader stage.
+  TessEval,
+
+  /// Geometry shader stage.
+  Geometry,
+
+  /// Fragment shader stage.
+  Fragment,
+}
+
+impl fmt::Display for ShaderStage {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ShaderStage::Vertex => f.write_str("vertex"),
+      ShaderStage::TessCtrl => f.write_str("tessellation control"),
+      ShaderStage::TessEval => f.write_str("tessellation evaluation"),
+      ShaderStage::Geometry => f.write_str("geometry"),
+      ShaderStage::Fragment => f.write_str("fragment"),
+    }
+  }
+}
+
+/// Errors that can occur when building a [`Program`].
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum ProgramError {
+  /// A varying returned by a stage is not one of its outputs.
+  ///
+  /// `index` is the position of the varying in the varyings returned by the stage, flattened.
+  NotAnOutput {
+    /// Stage returning the varying.
+    stage: ShaderStage,
+    /// Position of the varying in the flattened varyings of the stage.
+    index: usize,
+  },
+
+  /// The same output is returned more than once by a stage.
+  DuplicateVarying {
+    /// Stage returning the output.
+    stage: ShaderStage,
+    /// Name of the output.
+    name: String,
+  },
+
+  /// An interface declaration failed in a stage closure.
+  Interface(InterfaceError),
+}
+
+impl fmt::Display for ProgramError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ProgramError::NotAnOutput { stage, index } => {
+        write!(
+          f,
+          "varying #{} of the {} stage is not an output",
+          index, stage
+        )
+      }
+      ProgramError::DuplicateVarying { stage, name } => {
+        write!(
+          f,
+          "`{}` is passed more than once by the {} stage",
+          name, stage
+        )
+      }
+      ProgramError::Interface(e) => write!(f, "interface error: {}", e),
+    }
+  }
+}
+
+impl std::error::Error for ProgramError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      ProgramError::Interface(e) => Some(e),
+      _ => None,
+    }
+  }
+}
+
+impl From<InterfaceError> for ProgramError {
+  fn from(e: InterfaceError) -> Self {
+    ProgramError::Interface(e)
+  }
+}
+
+/// Varyings passed from a shader stage to the next one.
+///
+/// Varyings are the outputs of a stage, returned alongside the [`Shader`] by the stage closure given to the
+/// [`Program`] builder. The next stage then receives them as read-only inputs, with the same names and types.
+///
+/// This trait is implemented for:
+///
+/// - `()`, when a stage doesn’t pass anything to the next one.
+/// - [`Output<T>`], where `T` is a [`VaryingType`], which is seen as an [`Expr<T>`] by the next stage, or as an
+///   [`Expr<[T]>`](Expr) — one item per vertex — if the next stage is a tessellation or a geometry stage.
+/// - [`Output<[T]>`](Output), the per-vertex outputs of tessellation control shaders, seen as an
+///   [`Expr<[T]>`](Expr) by the next stage.
+/// - Tuples of varyings.
+pub trait Varyings {
+  /// Varyings as seen by the next stage.
+  type Input;
+
+  /// Varyings as seen by the next stage, if it reads arrays of per-vertex inputs.
+  type ArrayedInput;
+
+  /// Collect the names of the varyings, or [`None`] for those not being outputs.
+  fn collect_names(&self, names: &mut Vec<Option<String>>);
+
+  /// Declare the varyings as inputs of the next stage.
+  fn declare_input(
+    builder: &mut ShaderBuilder,
+    names: &mut impl Iterator<Item = String>,
+  ) -> Self::Input;
+
+  /// Declare the varyings as arrays of per-vertex inputs of the next stage.
+  fn declare_arrayed_input(
+    builder: &mut ShaderBuilder,
+    names: &mut impl Iterator<Item = String>,
+  ) -> Self::ArrayedInput;
+}
+
+/// Varyings written per-vertex, as required by tessellation control shaders.
+pub trait PerVertexVaryings: Varyings {}
+
+impl Varyings for () {
+  type Input = ();
+
+  type ArrayedInput = ();
+
+  fn collect_names(&self, _: &mut Vec<Option<String>>) {}
+
+  fn declare_input(_: &mut ShaderBuilder, _: &mut impl Iterator<Item = String>) -> Self::Input {}
+
+  fn declare_arrayed_input(
+    _: &mut ShaderBuilder,
+    _: &mut impl Iterator<Item = String>,
+  ) -> Self::ArrayedInput {
+  }
+}
+
+impl PerVertexVaryings for () {}
+
+impl<T> Varyings for Output<T>
+where
+  T: VaryingType,
+{
+  type Input = Expr<T>;
+
+  type ArrayedInput = Expr<[T]>;
+
+  fn collect_names(&self, names: &mut Vec<Option<String>>) {
+    names.push(self.0.output_name());
+  }
+
+  fn declare_input(
+    builder: &mut ShaderBuilder,
+    names: &mut impl Iterator<Item = String>,
+  ) -> Self::Input {
+    // names come from the outputs of the previous stage, which were already checked
+    let name = names.next().unwrap();
+    unsafe { builder.input_unchecked(&name) }
+  }
+
+  fn declare_arrayed_input(
+    builder: &mut ShaderBuilder,
+    names: &mut impl Iterator<Item = String>,
+  ) -> Self::ArrayedInput {
+    let name = names.next().unwrap();
+    unsafe { builder.input_unchecked(&name) }
+  }
+}
+
+impl<T> Varyings for Output<[T]>
+where
+  T: VaryingType,
+{
+  type Input = Expr<[T]>;
+
+  type ArrayedInput = Expr<[T]>;
+
+  fn collect_names(&self, names: &mut Vec<Option<String>>) {
+    names.push(self.0.output_name());
+  }
+
+  fn declare_input(
+    builder: &mut ShaderBuilder,
+    names: &mut impl Iterator<Item = String>,
+  ) -> Self::Input {
+    Self::declare_arrayed_input(builder, names)
+  }
+
+  fn declare_arrayed_input(
+    builder: &mut ShaderBuilder,
+    names: &mut impl Iterator<Item = String>,
+  ) -> Self::ArrayedInput {
+    let name = names.next().unwrap();
+    unsafe { builder.input_unchecked(&name) }
+  }
+}
+
+impl<T> PerVertexVaryings for Output<[T]> where T: VaryingType {}
+
+macro_rules! impl_Varyings_tuple {
+  ($($t:ident),+) => {
+    impl<$($t),+> Varyings for ($($t),+)
+    where
+      $($t: Varyings),+
+    {
+      type Input = ($($t::Input),+);
+
+      type ArrayedInput = ($($t::ArrayedInput),+);
+
+      #[allow(non_snake_case)]
+      fn collect_names(&self, names: &mut Vec<Option<String>>) {
+        let ($($t),+) = self;
+        $( $t.collect_names(names); )+
+      }
+
+      fn declare_input(
+        builder: &mut ShaderBuilder,
+        names: &mut impl Iterator<Item = String>,
+      ) -> Self::Input {
+        ($($t::declare_input(builder, names)),+)
+      }
+
+      fn declare_arrayed_input(
+        builder: &mut ShaderBuilder,
+        names: &mut impl Iterator<Item = String>,
+      ) -> Self::ArrayedInput {
+        ($($t::declare_arrayed_input(builder, names)),+)
+      }
+    }
+
+    impl<$($t),+> PerVertexVaryings for ($($t),+) where $($t: PerVertexVaryings),+ {}
+  };
+}
+
+impl_Varyings_tuple!(A, B);
+impl_Varyings_tuple!(A, B, C);
+impl_Varyings_tuple!(A, B, C, D);
+impl_Varyings_tuple!(A, B, C, D, E);
+impl_Varyings_tuple!(A, B, C, D, E, F);
+impl_Varyings_tuple!(A, B, C, D, E, F, G);
+impl_Varyings_tuple!(A, B, C, D, E, F, G, H);
+
+/// A program, made of shader stages linked together.
+///
+/// A [`Program`] is built stage by stage, in pipeline order, starting with [`Program::new_vertex_shader`]. Each stage
+/// closure returns its [`Shader`] along with the [`Varyings`] it passes to the next stage, and the next stage closure
+/// receives them as read-only inputs. Outputs of a stage and inputs of the next one then always agree on their names
+/// and types.
+///
+/// The order of stages is checked at compile-time: a tessellation control stage must be followed by a tessellation
+/// evaluation stage, a geometry stage can only be followed by the fragment stage, and the fragment stage ends the
+/// program.
+///
+/// # Examples
+///
+/// ```
+/// use shades::{Program, Scope, ShaderBuilder, V3, V4, color_attachments, inputs, lit, vec4};
+///
+/// let program = Program::new_vertex_shader(|mut s, vertex| {
+///   inputs!(s, position: V3<f32>);
+///   let color = s.output::<V3<f32>>("color")?;
+///
+///   let shader = s.main_fun(|s: &mut Scope<()>| {
+///     s.set(&color, lit!(1., 0., 0.));
+///     s.set(&vertex.position, vec4!(position, 1.));
+///   });
+///
+///   Ok((shader, color))
+/// })?
+/// .fragment_shader(|mut s, fragment, color| {
+///   color_attachments!(s, frag: V4<f32> = 0);
+///
+///   Ok(s.main_fun(|s: &mut Scope<()>| {
+///     s.set(&frag, vec4!(color, 1.));
+///   }))
+/// })?;
+/// # Ok::<_, shades::ProgramError>(())
+/// ```
+///
+/// A tessellation control stage cannot be directly followed by the fragment stage:
+///
+/// ```compile_fail
+/// use shades::{Program, Scope};
+///
+/// let program = Program::new_vertex_shader(|s, _| Ok((s.main_fun(|_: &mut Scope<()>| {}), ())))?
+///   .tess_ctrl_shader(|s, _, ()| Ok((s.main_fun(|_: &mut Scope<()>| {}), ())))?
+///   .fragment_shader(|s, _, ()| Ok(s.main_fun(|_: &mut Scope<()>| {})))?;
+/// # Ok::<_, shades::ProgramError>(())
+/// ```
+#[derive(Debug)]
+pub struct Program {
+  vertex: Shader,
+  tess_ctrl: Option<Shader>,
+  tess_eval: Option<Shader>,
+  geometry: Option<Shader>,
+  fragment: Shader,
+}
+
+impl Program {
+  /// Start building a new [`Program`] with its _vertex shader_.
+  ///
+  /// The closure is akin to the one passed to [`ShaderBuilder::new_vertex_shader`], but returns the varyings to pass
+  /// to the next stage along with the [`Shader`]. Errors returned by the closure are forwarded; [`InterfaceError`]s
+  /// can be propagated with `?` inside the closure.
+  ///
+  /// # Errors
+  ///
+  /// Fail with [`ProgramError`] if the varyings are not outputs of the stage, and with any error returned by the
+  /// closure.
+  pub fn new_vertex_shader<V>(
+    f: impl FnOnce(ShaderBuilder, VertexShaderEnv) -> Result<(Shader, V), ProgramError>,
+  ) -> Result<ProgramBuilder<VertexStage, V>, ProgramError>
+  where
+    V: Varyings,
+  {
+    let (vertex, varyings) = f(
+      ShaderBuilder::new(ShaderStage::Vertex),
+      VertexShaderEnv::new(),
+    )?;
+    let varyings = varying_names(&vertex, &varyings)?;
+
+    Ok(ProgramBuilder {
+      vertex,
+      tess_ctrl: None,
+      tess_eval: None,
+      geometry: None,
+      varyings,
+      _phantom: PhantomData,
+    })
+  }
+
+  /// Vertex shader of the program.
+  pub fn vertex(&self) -> &Shader {
+    &self.vertex
+  }
+
+  /// Tessellation control shader of the program, if any.
+  pub fn tess_ctrl(&self) -> Option<&Shader> {
+    self.tess_ctrl.as_ref()
+  }
+
+  /// Tessellation evaluation shader of the program, if any.
+  pub fn tess_eval(&self) -> Option<&Shader> {
+    self.tess_eval.as_ref()
+  }
+
+  /// Geometry shader of the program, if any.
+  pub fn geometry(&self) -> Option<&Shader> {
+    self.geometry.as_ref()
+  }
+
+  /// Fragment shader of the program.
+  pub fn fragment(&self) -> &Shader {
+    &self.fragment
+  }
+}
+
+/// Typestate marker of a [`ProgramBuilder`] whose last stage is the vertex stage.
+#[derive(Debug)]
+pub enum VertexStage {}
+
+/// Typestate marker of a [`ProgramBuilder`] whose last stage is the tessellation control stage.
+#[derive(Debug)]
+pub enum TessCtrlStage {}
+
+/// Typestate marker of a [`ProgramBuilder`] whose last stage is the tessellation evaluation stage.
+#[derive(Debug)]
+pub enum TessEvalStage {}
+
+/// Typestate marker of a [`ProgramBuilder`] whose last stage is the geometry stage.
+#[derive(Debug)]
+pub enum GeometryStage {}
+
+/// A [`Program`] being built.
+///
+/// `S` is the last stage added to the program — one of [`VertexStage`], [`TessCtrlStage`], [`TessEvalStage`] and
+/// [`GeometryStage`] — and restricts which stages can come next. `V` is the type of the varyings passed by the last
+/// stage to the next one.
+#[derive(Debug)]
+pub struct ProgramBuilder<S, V> {
+  vertex: Shader,
+  tess_ctrl: Option<Shader>,
+  tess_eval: Option<Shader>,
+  geometry: Option<Shader>,
+  varyings: Vec<String>,
+  _phantom: PhantomData<(S, V)>,
+}
+
+// Get the names of varyings, checking they are all distinct outputs declared by the shader of the stage.
+fn varying_names(shader: &Shader, varyings: &impl Varyings) -> Result<Vec<String>, ProgramError> {
+  let stage = shader.stage;
+  let mut names = Vec::new();
+  varyings.collect_names(&mut names);
+
+  let mut checked: Vec<String> = Vec::with_capacity(names.len());
+  for (index, name) in names.into_iter().enumerate() {
+    let name = name
+      .filter(|name| {
+        shader
+          .decls
+          .iter()
+          .any(|decl| matches!(decl, ShaderDecl::Out(out, _, _) if out == name))
+      })
+      .ok_or(ProgramError::NotAnOutput { stage, index })?;
+
+    if checked.contains(&name) {
+      return Err(ProgramError::DuplicateVarying { stage, name });
+    }
+
+    checked.push(name);
+  }
+
+  Ok(checked)
+}
+
+impl<S, V> ProgramBuilder<S, V>
+where
+  V: Varyings,
+{
+  // Run the closure of the next stage, with the varyings of the current stage as inputs.
+  fn run_stage<Env, I, W>(
+    &mut self,
+    stage: ShaderStage,
+    env: Env,
+    declare_input: impl FnOnce(&mut ShaderBuilder, &mut std::vec::IntoIter<String>) -> I,
+    f: impl FnOnce(ShaderBuilder, Env, I) -> Result<(Shader, W), ProgramError>,
+  ) -> Result<(Shader, Vec<String>), ProgramError>
+  where
+    W: Varyings,
   {
-    let fundef = f.build_fn();
-    let handle = self.next_fun_handle;
-    self.next_fun_handle += 1;
+    let mut builder = ShaderBuilder::new(stage);
+    let input = declare_input(
+      &mut builder,
+      &mut std::mem::take(&mut self.varyings).into_iter(),
+    );
+    let (shader, varyings) = f(builder, env, input)?;
+    let varyings = varying_names(&shader, &varyings)?;
 
-    self.decls.push(ShaderDecl::FunDef(handle, fundef.erased));
+    Ok((shader, varyings))
+  }
 
-    FunHandle {
-      erased: ErasedFunHandle::UserDefined(handle as _),
+  // Change the stage and varyings of the builder.
+  fn with_varyings<S2, W>(self, varyings: Vec<String>) -> ProgramBuilder<S2, W> {
+    ProgramBuilder {
+      vertex: self.vertex,
+      tess_ctrl: self.tess_ctrl,
+      tess_eval: self.tess_eval,
+      geometry: self.geometry,
+      varyings,
       _phantom: PhantomData,
     }
   }
 
-  /// Declare the `main` function of the shader stage.
-  ///
-  /// This method is very similar to [`ShaderBuilder::fun`] in the sense it declares a function. However, it declares the special
-  /// `main` entry-point of a shader stage, which doesn’t have have argument and returns nothing, and is the only
-  /// way to finalize the building of a [`Shader`].
-  ///
-  /// The input closure must take a single argument: a mutable reference on a `Scope<()>`, as the `main` function
-  /// cannot return anything.
-  ///
-  /// # Return
-  ///
-  /// The fully built [`Shader`], which cannot be altered anymore.
+  fn add_tess_eval<W>(
+    mut self,
+    f: impl FnOnce(
+      ShaderBuilder,
+      TessEvalShaderEnv,
+      V::ArrayedInput,
+    ) -> Result<(Shader, W), ProgramError>,
+  ) -> Result<ProgramBuilder<TessEvalStage, W>, ProgramError>
+  where
+    W: Varyings,
+  {
+    let (shader, varyings) = self.run_stage(
+      ShaderStage::TessEval,
+      TessEvalShaderEnv::new(),
+      V::declare_arrayed_input,
+      f,
+    )?;
+    self.tess_eval = Some(shader);
+
+    Ok(self.with_varyings(varyings))
+  }
+
+  fn add_geometry<W>(
+    mut self,
+    f: impl FnOnce(
+      ShaderBuilder,
+      GeometryShaderEnv,
+      V::ArrayedInput,
+    ) -> Result<(Shader, W), ProgramError>,
+  ) -> Result<ProgramBuilder<GeometryStage, W>, ProgramError>
+  where
+    W: Varyings,
+  {
+    let (shader, varyings) = self.run_stage(
+      ShaderStage::Geometry,
+      GeometryShaderEnv::new(),
+      V::declare_arrayed_input,
+      f,
+    )?;
+    self.geometry = Some(shader);
+
+    Ok(self.with_varyings(varyings))
+  }
+
+  fn add_fragment(
+    mut self,
+    f: impl FnOnce(ShaderBuilder, FragmentShaderEnv, V::Input) -> Result<Shader, ProgramError>,
+  ) -> Result<Program, ProgramError> {
+    let (fragment, _) = self.run_stage(
+      ShaderStage::Fragment,
+      FragmentShaderEnv::new(),
+      V::declare_input,
+      |s, env, input| f(s, env, input).map(|shader| (shader, ())),
+    )?;
+
+    Ok(Program {
+      vertex: self.vertex,
+      tess_ctrl: self.tess_ctrl,
+      tess_eval: self.tess_eval,
+      geometry: self.geometry,
+      fragment,
+    })
+  }
+}
+
+impl<V> ProgramBuilder<VertexStage, V>
+where
+  V: Varyings,
+{
+  /// Add a _tessellation control shader_ to the program.
   ///
-  /// # Examples
+  /// The closure receives the varyings of the vertex stage as arrays of per-vertex inputs, and must return per-vertex
+  /// varyings. A tessellation control stage must be followed by a tessellation evaluation stage.
   ///
-  /// ```
-  /// use shades::{Scope, ShaderBuilder};
+  /// # Errors
   ///
-  /// let shader = ShaderBuilder::new_vertex_shader(|s, vertex| {
-  ///   s.main_fun(|s: &mut Scope<()>| {
-  ///     // …
-  ///   })
-  /// });
-  /// ```
-  pub fn main_fun<F, R>(mut self, f: F) -> Shader
+  /// Fail with [`ProgramError`] if the varyings are not outputs of the stage, and with any error returned by the
+  /// closure.
+  pub fn tess_ctrl_shader<W>(
+    mut self,
+    f: impl FnOnce(
+      ShaderBuilder,
+      TessCtrlShaderEnv,
+      V::ArrayedInput,
+    ) -> Result<(Shader, W), ProgramError>,
+  ) -> Result<ProgramBuilder<TessCtrlStage, W>, ProgramError>
   where
-    F: ToFun<R, ()>,
+    W: PerVertexVaryings,
   {
-    let fundef = f.build_fn();
-
-    self.decls.push(ShaderDecl::Main(fundef.erased));
+    let (shader, varyings) = self.run_stage(
+      ShaderStage::TessCtrl,
+      TessCtrlShaderEnv::new(),
+      V::declare_arrayed_input,
+      f,
+    )?;
+    self.tess_ctrl = Some(shader);
 
-    Shader { builder: self }
+    Ok(self.with_varyings(varyings))
   }
 
-  /// Declare a new constant, shared between all functions and constants that come next.
+  /// Add a _tessellation evaluation shader_ to the program, without tessellation control shader.
   ///
-  /// The input argument is any object that can be transformed [`Into`] an [`Expr<T>`]. At this level in the
-  /// shader, pretty much nothing but literals and other constants are accepted here.
+  /// The closure receives the varyings of the vertex stage as arrays of per-vertex inputs.
   ///
-  /// # Return
+  /// # Errors
   ///
-  /// An [`Expr<T>`] representing the constant passed as input.
+  /// Fail with [`ProgramError`] if the varyings are not outputs of the stage, and with any error returned by the
+  /// closure.
+  pub fn tess_eval_shader<W>(
+    self,
+    f: impl FnOnce(
+      ShaderBuilder,
+      TessEvalShaderEnv,
+      V::ArrayedInput,
+    ) -> Result<(Shader, W), ProgramError>,
+  ) -> Result<ProgramBuilder<TessEvalStage, W>, ProgramError>
+  where
+    W: Varyings,
+  {
+    self.add_tess_eval(f)
+  }
+
+  /// Add a _geometry shader_ to the program.
   ///
-  /// # Examples
+  /// The closure receives the varyings of the vertex stage as arrays of per-vertex inputs.
   ///
-  /// ```
-  /// # use shades::{EscapeScope, Expr, Scope, ShaderBuilder, lit};
-  /// # ShaderBuilder::new_vertex_shader(|mut s, vertex| {
-  /// // don’t do this.
-  /// let illum_coefficient: Expr<f32> = s.constant(10.);
-  /// # s.main_fun(|s: &mut Scope<()>| {})
-  /// # });
-  /// ```
-  pub fn constant<T>(&mut self, expr: impl Into<Expr<T>>) -> Expr<T>
+  /// # Errors
+  ///
+  /// Fail with [`ProgramError`] if the varyings are not outputs of the stage, and with any error returned by the
+  /// closure.
+  pub fn geometry_shader<W>(
+    self,
+    f: impl FnOnce(
+      ShaderBuilder,
+      GeometryShaderEnv,
+      V::ArrayedInput,
+    ) -> Result<(Shader, W), ProgramError>,
+  ) -> Result<ProgramBuilder<GeometryStage, W>, ProgramError>
   where
-    T: ToType,
+    W: Varyings,
   {
-    let handle = self.next_global_handle;
-    self.next_global_handle += 1;
-
-    self
-      .decls
-      .push(ShaderDecl::Const(handle, T::ty(), expr.into().erased));
+    self.add_geometry(f)
+  }
 
-    Expr::new(ErasedExpr::Var(ScopedHandle::global(handle)))
+  /// Add the _fragment shader_ to the program, finalizing it.
+  ///
+  /// The closure receives the varyings of the vertex stage as inputs.
+  ///
+  /// # Errors
+  ///
+  /// Fail with any error returned by the closure.
+  pub fn fragment_shader(
+    self,
+    f: impl FnOnce(ShaderBuilder, FragmentShaderEnv, V::Input) -> Result<Shader, ProgramError>,
+  ) -> Result<Program, ProgramError> {
+    self.add_fragment(f)
   }
+}
 
-  /// Declare a new input, shared between all functions and constants that come next.
+impl<V> ProgramBuilder<TessCtrlStage, V>
+where
+  V: Varyings,
+{
+  /// Add a _tessellation evaluation shader_ to the program.
+  ///
+  /// The closure receives the per-vertex varyings of the tessellation control stage.
   ///
-  /// TODO
-  pub unsafe fn input<T>(&mut self, name: &str) -> Var<T>
+  /// # Errors
+  ///
+  /// Fail with [`ProgramError`] if the varyings are not outputs of the stage, and with any error returned by the
+  /// closure.
+  pub fn tess_eval_shader<W>(
+    self,
+    f: impl FnOnce(
+      ShaderBuilder,
+      TessEvalShaderEnv,
+      V::ArrayedInput,
+    ) -> Result<(Shader, W), ProgramError>,
+  ) -> Result<ProgramBuilder<TessEvalStage, W>, ProgramError>
   where
-    T: ToType,
+    W: Varyings,
   {
-    let name = name.to_owned();
-    self.decls.push(ShaderDecl::In(name.clone(), T::ty()));
-    Var::new(ScopedHandle::Input(name))
+    self.add_tess_eval(f)
   }
+}
 
-  /// TODO
-  pub unsafe fn output<T>(&mut self, name: &str) -> Var<T>
+impl<V> ProgramBuilder<TessEvalStage, V>
+where
+  V: Varyings,
+{
+  /// Add a _geometry shader_ to the program.
+  ///
+  /// The closure receives the varyings of the tessellation evaluation stage as arrays of per-vertex inputs.
+  ///
+  /// # Errors
+  ///
+  /// Fail with [`ProgramError`] if the varyings are not outputs of the stage, and with any error returned by the
+  /// closure.
+  pub fn geometry_shader<W>(
+    self,
+    f: impl FnOnce(
+      ShaderBuilder,
+      GeometryShaderEnv,
+      V::ArrayedInput,
+    ) -> Result<(Shader, W), ProgramError>,
+  ) -> Result<ProgramBuilder<GeometryStage, W>, ProgramError>
   where
-    T: ToType,
+    W: Varyings,
   {
-    let name = name.to_owned();
-    self.decls.push(ShaderDecl::Out(name.clone(), T::ty()));
-    Var::new(ScopedHandle::Output(name))
+    self.add_geometry(f)
   }
 
-  pub unsafe fn uniform<T>(&mut self, name: &str) -> Var<T>
-  where
-    T: ToType,
-  {
-    let name = name.to_owned();
-    self.decls.push(ShaderDecl::Uniform(name.clone(), T::ty()));
-    Var::new(ScopedHandle::uniform(name))
+  /// Add the _fragment shader_ to the program, finalizing it.
+  ///
+  /// The closure receives the varyings of the tessellation evaluation stage as inputs.
+  ///
+  /// # Errors
+  ///
+  /// Fail with any error returned by the closure.
+  pub fn fragment_shader(
+    self,
+    f: impl FnOnce(ShaderBuilder, FragmentShaderEnv, V::Input) -> Result<Shader, ProgramError>,
+  ) -> Result<Program, ProgramError> {
+    self.add_fragment(f)
+  }
+}
+
+impl<V> ProgramBuilder<GeometryStage, V>
+where
+  V: Varyings,
+{
+  /// Add the _fragment shader_ to the program, finalizing it.
+  ///
+  /// The closure receives the varyings of the geometry stage as inputs.
+  ///
+  /// # Errors
+  ///
+  /// Fail with any error returned by the closure.
+  pub fn fragment_shader(
+    self,
+    f: impl FnOnce(ShaderBuilder, FragmentShaderEnv, V::Input) -> Result<Shader, ProgramError>,
+  ) -> Result<Program, ProgramError> {
+    self.add_fragment(f)
   }
 }
 
 /// Shader declaration.
 ///
 /// This contain everything that can be declared at top-level of a shader.
-#[derive(Debug)]
+#[derive(Clone, Debug, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) enum ShaderDecl {
   /// The `main` function declaration. The [`ErasedFun`] is a function that returns nothing and has no argument.
   Main(ErasedFun),
@@ -561,8 +2159,9 @@ pub(crate) enum ShaderDecl {
   /// A constant definition.
   ///
   /// The [`u16`] represents the _handle_ of the constant, and is unique for each shader stage. The [`Type`] is the
-  /// the type of the constant expression. [`ErasedExpr`] is the representation of the constant.
-  Const(u16, Type, ErasedExpr),
+  /// the type of the constant expression. [`ErasedExpr`] is the representation of the constant. The [`String`], if
+  /// any, is the name given to the constant.
+  Const(u16, Type, ErasedExpr, Option<String>),
 
   /// An input definition.
   ///
@@ -573,13 +2172,62 @@ pub(crate) enum ShaderDecl {
   /// An output definition.
   ///
   /// The [`u16`] represents the _handle_ of the output, and is unique for each shader stage. The [`Type`] is the
-  /// the type of the output.
-  Out(String, Type),
+  /// the type of the output. The [`ColorAttachment`], if any, is the colour attachment a fragment output writes to.
+  Out(String, Type, Option<ColorAttachment>),
 
   /// A uniform definition.
   Uniform(String, Type),
 }
 
+/// Colour attachment a fragment output writes to.
+///
+/// A colour attachment is identified by its _location_, which is the index of the draw buffer the output is written
+/// to, and its _blend index_, used to select the source of dual-source blending. Most of the time, you will only want
+/// to set the location and leave the blend index to `0`.
+///
+/// [`ColorAttachment`] can be created from a single [`u32`], representing the location, or from a `(u32, u32)`,
+/// representing the location and the blend index.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ColorAttachment {
+  location: u32,
+  index: u32,
+}
+
+impl ColorAttachment {
+  /// Create a new [`ColorAttachment`] at the given location, with blend index `0`.
+  pub const fn new(location: u32) -> Self {
+    Self { location, index: 0 }
+  }
+
+  /// Change the blend index of the [`ColorAttachment`].
+  pub const fn with_index(self, index: u32) -> Self {
+    Self { index, ..self }
+  }
+
+  /// Location of the attachment.
+  pub const fn location(&self) -> u32 {
+    self.location
+  }
+
+  /// Blend index of the attachment.
+  pub const fn index(&self) -> u32 {
+    self.index
+  }
+}
+
+impl From<u32> for ColorAttachment {
+  fn from(location: u32) -> Self {
+    Self::new(location)
+  }
+}
+
+impl From<(u32, u32)> for ColorAttachment {
+  fn from((location, index): (u32, u32)) -> Self {
+    Self::new(location).with_index(index)
+  }
+}
+
 macro_rules! make_vn {
   ($t:ident, $dim:expr) => {
     /// Scalar vectors.
@@ -616,7 +2264,11 @@ make_vn!(V3, 3);
 make_vn!(V4, 4);
 
 /// Representation of an expression.
-#[derive(Clone, Debug, PartialEq)]
+///
+/// Subexpressions are reference-counted, so that cloning an expression — which the EDSL does a lot — is cheap and an
+/// expression used several times is shared instead of copied: expressions form a DAG rather than a tree. Equal
+/// subexpressions built separately are shared as well once declared in a [`ShaderBuilder`], which interns them.
+#[derive(Clone, Debug)]
 enum ErasedExpr {
   // scalars
   LitInt(i32),
@@ -647,44 +2299,189 @@ enum ErasedExpr {
   // LitM43(M43),
   LitM44(M44),
   // arrays
-  Array(Type, Vec<ErasedExpr>),
+  Array(Type, Vec<Arc<ErasedExpr>>),
   // var
   Var(ScopedHandle),
   // built-in functions and operators
-  Not(Box<Self>),
-  And(Box<Self>, Box<Self>),
-  Or(Box<Self>, Box<Self>),
-  Xor(Box<Self>, Box<Self>),
-  BitOr(Box<Self>, Box<Self>),
-  BitAnd(Box<Self>, Box<Self>),
-  BitXor(Box<Self>, Box<Self>),
-  Neg(Box<Self>),
-  Add(Box<Self>, Box<Self>),
-  Sub(Box<Self>, Box<Self>),
-  Mul(Box<Self>, Box<Self>),
-  Div(Box<Self>, Box<Self>),
-  Rem(Box<Self>, Box<Self>),
-  Shl(Box<Self>, Box<Self>),
-  Shr(Box<Self>, Box<Self>),
-  Eq(Box<Self>, Box<Self>),
-  Neq(Box<Self>, Box<Self>),
-  Lt(Box<Self>, Box<Self>),
-  Lte(Box<Self>, Box<Self>),
-  Gt(Box<Self>, Box<Self>),
-  Gte(Box<Self>, Box<Self>),
+  Not(Arc<Self>),
+  And(Arc<Self>, Arc<Self>),
+  Or(Arc<Self>, Arc<Self>),
+  Xor(Arc<Self>, Arc<Self>),
+  BitOr(Arc<Self>, Arc<Self>),
+  BitAnd(Arc<Self>, Arc<Self>),
+  BitXor(Arc<Self>, Arc<Self>),
+  Neg(Arc<Self>),
+  Add(Arc<Self>, Arc<Self>),
+  Sub(Arc<Self>, Arc<Self>),
+  Mul(Arc<Self>, Arc<Self>),
+  Div(Arc<Self>, Arc<Self>),
+  Rem(Arc<Self>, Arc<Self>),
+  Shl(Arc<Self>, Arc<Self>),
+  Shr(Arc<Self>, Arc<Self>),
+  Eq(Arc<Self>, Arc<Self>),
+  Neq(Arc<Self>, Arc<Self>),
+  Lt(Arc<Self>, Arc<Self>),
+  Lte(Arc<Self>, Arc<Self>),
+  Gt(Arc<Self>, Arc<Self>),
+  Gte(Arc<Self>, Arc<Self>),
   // function call
-  FunCall(ErasedFunHandle, Vec<Self>),
+  FunCall(ErasedFunHandle, Vec<Arc<Self>>),
   // swizzle
-  Swizzle(Box<Self>, Swizzle),
+  Swizzle(Arc<Self>, Swizzle),
   // field expression, as in a struct Foo { float x; }, foo.x is an Expr representing the x field on object foo
-  Field { object: Box<Self>, field: Box<Self> },
-  ArrayLookup { object: Box<Self>, index: Box<Self> },
+  Field { object: Arc<Self>, field: Arc<Self> },
+  ArrayLookup { object: Arc<Self>, index: Arc<Self> },
+}
+
+// floating-point literals compare by bits, so that equality is reflexive, even for NaN, and 0. and -0., which don’t
+// compute the same results, differ; sub-expressions shared by both expressions are compared by address, and shared
+// sub-expressions found equal are not compared again: comparing expressions as trees would take exponential time
+impl PartialEq for ErasedExpr {
+  fn eq(&self, other: &Self) -> bool {
+    self.eq_shared(other, &mut HashSet::new())
+  }
+}
+
+impl Eq for ErasedExpr {}
+
+// expressions hash as a digest of their content, computed once per shared sub-expression
+impl Hash for ErasedExpr {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    state.write_u64(fingerprint::digest(self));
+  }
 }
 
 impl ErasedExpr {
   const fn new_builtin(builtin: BuiltIn) -> Self {
     ErasedExpr::Var(ScopedHandle::builtin(builtin))
   }
+
+  // Operands of binary expressions.
+  fn operands(&self) -> Option<(&Arc<Self>, &Arc<Self>)> {
+    match self {
+      ErasedExpr::And(a, b)
+      | ErasedExpr::Or(a, b)
+      | ErasedExpr::Xor(a, b)
+      | ErasedExpr::BitOr(a, b)
+      | ErasedExpr::BitAnd(a, b)
+      | ErasedExpr::BitXor(a, b)
+      | ErasedExpr::Add(a, b)
+      | ErasedExpr::Sub(a, b)
+      | ErasedExpr::Mul(a, b)
+      | ErasedExpr::Div(a, b)
+      | ErasedExpr::Rem(a, b)
+      | ErasedExpr::Shl(a, b)
+      | ErasedExpr::Shr(a, b)
+      | ErasedExpr::Eq(a, b)
+      | ErasedExpr::Neq(a, b)
+      | ErasedExpr::Lt(a, b)
+      | ErasedExpr::Lte(a, b)
+      | ErasedExpr::Gt(a, b)
+      | ErasedExpr::Gte(a, b)
+      | ErasedExpr::Field {
+        object: a,
+        field: b,
+      }
+      | ErasedExpr::ArrayLookup {
+        object: a,
+        index: b,
+      } => Some((a, b)),
+
+      _ => None,
+    }
+  }
+
+  // Compare two expressions, remembering the pairs of shared sub-expressions found equal.
+  fn eq_shared(&self, other: &Self, equal: &mut HashSet<(*const Self, *const Self)>) -> bool {
+    fn eq_arc(
+      a: &Arc<ErasedExpr>,
+      b: &Arc<ErasedExpr>,
+      equal: &mut HashSet<(*const ErasedExpr, *const ErasedExpr)>,
+    ) -> bool {
+      if Arc::ptr_eq(a, b) {
+        return true;
+      }
+
+      let shared = Arc::strong_count(a) > 1 && Arc::strong_count(b) > 1;
+      let pair = (Arc::as_ptr(a), Arc::as_ptr(b));
+      if shared && equal.contains(&pair) {
+        return true;
+      }
+
+      let eq = a.eq_shared(b, equal);
+      if eq && shared {
+        equal.insert(pair);
+      }
+
+      eq
+    }
+
+    fn same_bits(a: &[f32], b: &[f32]) -> bool {
+      a.iter().zip(b).all(|(a, b)| a.to_bits() == b.to_bits())
+    }
+
+    fn eq_all(
+      a: &[Arc<ErasedExpr>],
+      b: &[Arc<ErasedExpr>],
+      equal: &mut HashSet<(*const ErasedExpr, *const ErasedExpr)>,
+    ) -> bool {
+      a.len() == b.len() && a.iter().zip(b).all(|(a, b)| eq_arc(a, b, equal))
+    }
+
+    if mem::discriminant(self) != mem::discriminant(other) {
+      return false;
+    }
+
+    if let (Some((a, b)), Some((c, d))) = (self.operands(), other.operands()) {
+      return eq_arc(a, c, equal) && eq_arc(b, d, equal);
+    }
+
+    match (self, other) {
+      (ErasedExpr::LitInt(a), ErasedExpr::LitInt(b)) => a == b,
+      (ErasedExpr::LitUInt(a), ErasedExpr::LitUInt(b)) => a == b,
+      (ErasedExpr::LitFloat(a), ErasedExpr::LitFloat(b)) => same_bits(&[*a], &[*b]),
+      (ErasedExpr::LitBool(a), ErasedExpr::LitBool(b)) => a == b,
+      (ErasedExpr::LitInt2(a), ErasedExpr::LitInt2(b)) => a == b,
+      (ErasedExpr::LitUInt2(a), ErasedExpr::LitUInt2(b)) => a == b,
+      (ErasedExpr::LitFloat2(a), ErasedExpr::LitFloat2(b)) => same_bits(a, b),
+      (ErasedExpr::LitBool2(a), ErasedExpr::LitBool2(b)) => a == b,
+      (ErasedExpr::LitInt3(a), ErasedExpr::LitInt3(b)) => a == b,
+      (ErasedExpr::LitUInt3(a), ErasedExpr::LitUInt3(b)) => a == b,
+      (ErasedExpr::LitFloat3(a), ErasedExpr::LitFloat3(b)) => same_bits(a, b),
+      (ErasedExpr::LitBool3(a), ErasedExpr::LitBool3(b)) => a == b,
+      (ErasedExpr::LitInt4(a), ErasedExpr::LitInt4(b)) => a == b,
+      (ErasedExpr::LitUInt4(a), ErasedExpr::LitUInt4(b)) => a == b,
+      (ErasedExpr::LitFloat4(a), ErasedExpr::LitFloat4(b)) => same_bits(a, b),
+      (ErasedExpr::LitBool4(a), ErasedExpr::LitBool4(b)) => a == b,
+      (ErasedExpr::LitM22(a), ErasedExpr::LitM22(b)) => {
+        a.0.iter().zip(&b.0).all(|(a, b)| same_bits(a, b))
+      }
+      (ErasedExpr::LitM33(a), ErasedExpr::LitM33(b)) => {
+        a.0.iter().zip(&b.0).all(|(a, b)| same_bits(a, b))
+      }
+      (ErasedExpr::LitM44(a), ErasedExpr::LitM44(b)) => {
+        a.0.iter().zip(&b.0).all(|(a, b)| same_bits(a, b))
+      }
+
+      (ErasedExpr::Array(a_ty, a), ErasedExpr::Array(b_ty, b)) => {
+        a_ty == b_ty && eq_all(a, b, equal)
+      }
+
+      (ErasedExpr::Var(a), ErasedExpr::Var(b)) => a == b,
+
+      (ErasedExpr::Not(a), ErasedExpr::Not(b)) | (ErasedExpr::Neg(a), ErasedExpr::Neg(b)) => {
+        eq_arc(a, b, equal)
+      }
+
+      (ErasedExpr::FunCall(f, a), ErasedExpr::FunCall(g, b)) => f == g && eq_all(a, b, equal),
+
+      (ErasedExpr::Swizzle(a, a_sw), ErasedExpr::Swizzle(b, b_sw)) => {
+        a_sw == b_sw && eq_arc(a, b, equal)
+      }
+
+      _ => false,
+    }
+  }
 }
 
 /// Expression representation.
@@ -795,8 +2592,8 @@ where
   /// ```
   pub fn eq(&self, rhs: impl Into<Expr<T>>) -> Expr<bool> {
     Expr::new(ErasedExpr::Eq(
-      Box::new(self.erased.clone()),
-      Box::new(rhs.into().erased),
+      Arc::new(self.erased.clone()),
+      Arc::new(rhs.into().erased),
     ))
   }
 
@@ -822,8 +2619,8 @@ where
   /// ```
   pub fn neq(&self, rhs: impl Into<Expr<T>>) -> Expr<bool> {
     Expr::new(ErasedExpr::Neq(
-      Box::new(self.erased.clone()),
-      Box::new(rhs.into().erased),
+      Arc::new(self.erased.clone()),
+      Arc::new(rhs.into().erased),
     ))
   }
 }
@@ -846,7 +2643,7 @@ impl<T> Vec2<(Expr<T>, Expr<T>)> for Expr<V2<T>> {
     let (x, y) = args;
     Expr::new(ErasedExpr::FunCall(
       ErasedFunHandle::Vec2,
-      vec![x.erased, y.erased],
+      vec![Arc::new(x.erased), Arc::new(y.erased)],
     ))
   }
 }
@@ -870,7 +2667,7 @@ impl<T> Vec3<(Expr<V2<T>>, Expr<T>)> for Expr<V3<T>> {
     let (xy, z) = args;
     Expr::new(ErasedExpr::FunCall(
       ErasedFunHandle::Vec3,
-      vec![xy.erased, z.erased],
+      vec![Arc::new(xy.erased), Arc::new(z.erased)],
     ))
   }
 }
@@ -880,7 +2677,7 @@ impl<T> Vec3<(Expr<T>, Expr<T>, Expr<T>)> for Expr<V3<T>> {
     let (x, y, z) = args;
     Expr::new(ErasedExpr::FunCall(
       ErasedFunHandle::Vec3,
-      vec![x.erased, y.erased, z.erased],
+      vec![Arc::new(x.erased), Arc::new(y.erased), Arc::new(z.erased)],
     ))
   }
 }
@@ -904,7 +2701,7 @@ impl<T> Vec4<(Expr<V3<T>>, Expr<T>)> for Expr<V4<T>> {
     let (xyz, w) = args;
     Expr::new(ErasedExpr::FunCall(
       ErasedFunHandle::Vec4,
-      vec![xyz.erased, w.erased],
+      vec![Arc::new(xyz.erased), Arc::new(w.erased)],
     ))
   }
 }
@@ -914,27 +2711,34 @@ impl<T> Vec4<(Expr<V2<T>>, Expr<V2<T>>)> for Expr<V4<T>> {
     let (xy, zw) = args;
     Expr::new(ErasedExpr::FunCall(
       ErasedFunHandle::Vec4,
-      vec![xy.erased, zw.erased],
+      vec![Arc::new(xy.erased), Arc::new(zw.erased)],
     ))
   }
 }
 
+#[allow(clippy::extra_unused_lifetimes)]
 impl<'a, T> Vec4<(Expr<V2<T>>, Expr<T>, Expr<T>)> for Expr<V4<T>> {
   fn vec4(args: (Expr<V2<T>>, Expr<T>, Expr<T>)) -> Self {
     let (xy, z, w) = args;
     Expr::new(ErasedExpr::FunCall(
       ErasedFunHandle::Vec4,
-      vec![xy.erased, z.erased, w.erased],
+      vec![Arc::new(xy.erased), Arc::new(z.erased), Arc::new(w.erased)],
     ))
   }
 }
 
+#[allow(clippy::extra_unused_lifetimes)]
 impl<'a, T> Vec4<(Expr<T>, Expr<T>, Expr<T>, Expr<T>)> for Expr<V4<T>> {
   fn vec4(args: (Expr<T>, Expr<T>, Expr<T>, Expr<T>)) -> Self {
     let (x, y, z, w) = args;
     Expr::new(ErasedExpr::FunCall(
       ErasedFunHandle::Vec4,
-      vec![x.erased, y.erased, z.erased, w.erased],
+      vec![
+        Arc::new(x.erased),
+        Arc::new(y.erased),
+        Arc::new(z.erased),
+        Arc::new(w.erased),
+      ],
     ))
   }
 }
@@ -964,8 +2768,8 @@ where
   /// ```
   pub fn lt(&self, rhs: impl Into<Expr<T>>) -> Expr<bool> {
     Expr::new(ErasedExpr::Lt(
-      Box::new(self.erased.clone()),
-      Box::new(rhs.into().erased),
+      Arc::new(self.erased.clone()),
+      Arc::new(rhs.into().erased),
     ))
   }
 
@@ -990,8 +2794,8 @@ where
   /// ```
   pub fn lte(&self, rhs: impl Into<Expr<T>>) -> Expr<bool> {
     Expr::new(ErasedExpr::Lte(
-      Box::new(self.erased.clone()),
-      Box::new(rhs.into().erased),
+      Arc::new(self.erased.clone()),
+      Arc::new(rhs.into().erased),
     ))
   }
 
@@ -1016,8 +2820,8 @@ where
   /// ```
   pub fn gt(&self, rhs: impl Into<Expr<T>>) -> Expr<bool> {
     Expr::new(ErasedExpr::Gt(
-      Box::new(self.erased.clone()),
-      Box::new(rhs.into().erased),
+      Arc::new(self.erased.clone()),
+      Arc::new(rhs.into().erased),
     ))
   }
 
@@ -1042,8 +2846,8 @@ where
   /// ```
   pub fn gte(&self, rhs: impl Into<Expr<T>>) -> Expr<bool> {
     Expr::new(ErasedExpr::Gte(
-      Box::new(self.erased.clone()),
-      Box::new(rhs.into().erased),
+      Arc::new(self.erased.clone()),
+      Arc::new(rhs.into().erased),
     ))
   }
 }
@@ -1070,8 +2874,8 @@ impl Expr<bool> {
   /// ```
   pub fn and(&self, rhs: impl Into<Expr<bool>>) -> Expr<bool> {
     Expr::new(ErasedExpr::And(
-      Box::new(self.erased.clone()),
-      Box::new(rhs.into().erased),
+      Arc::new(self.erased.clone()),
+      Arc::new(rhs.into().erased),
     ))
   }
 
@@ -1096,8 +2900,8 @@ impl Expr<bool> {
   /// ```
   pub fn or(&self, rhs: impl Into<Expr<bool>>) -> Expr<bool> {
     Expr::new(ErasedExpr::Or(
-      Box::new(self.erased.clone()),
-      Box::new(rhs.into().erased),
+      Arc::new(self.erased.clone()),
+      Arc::new(rhs.into().erased),
     ))
   }
 
@@ -1122,8 +2926,8 @@ impl Expr<bool> {
   /// ```
   pub fn xor(&self, rhs: impl Into<Expr<bool>>) -> Expr<bool> {
     Expr::new(ErasedExpr::Xor(
-      Box::new(self.erased.clone()),
-      Box::new(rhs.into().erased),
+      Arc::new(self.erased.clone()),
+      Arc::new(rhs.into().erased),
     ))
   }
 }
@@ -1151,8 +2955,8 @@ impl<T> Expr<[T]> {
   /// ```
   pub fn at(&self, index: impl Into<Expr<i32>>) -> Expr<T> {
     Expr::new(ErasedExpr::ArrayLookup {
-      object: Box::new(self.erased.clone()),
-      index: Box::new(index.into().erased),
+      object: Arc::new(self.erased.clone()),
+      index: Arc::new(index.into().erased),
     })
   }
 }
@@ -1180,8 +2984,8 @@ impl<T, const N: usize> Expr<[T; N]> {
   /// ```
   pub fn at(&self, index: impl Into<Expr<i32>>) -> Expr<T> {
     Expr::new(ErasedExpr::ArrayLookup {
-      object: Box::new(self.erased.clone()),
-      index: Box::new(index.into().erased),
+      object: Arc::new(self.erased.clone()),
+      index: Arc::new(index.into().erased),
     })
   }
 }
@@ -1193,7 +2997,7 @@ macro_rules! impl_Not_Expr {
       type Output = Self;
 
       fn not(self) -> Self::Output {
-        Expr::new(ErasedExpr::Not(Box::new(self.erased)))
+        Expr::new(ErasedExpr::Not(Arc::new(self.erased)))
       }
     }
 
@@ -1201,7 +3005,7 @@ macro_rules! impl_Not_Expr {
       type Output = Expr<$t>;
 
       fn not(self) -> Self::Output {
-        Expr::new(ErasedExpr::Not(Box::new(self.erased.clone())))
+        Expr::new(ErasedExpr::Not(Arc::new(self.erased.clone())))
       }
     }
 
@@ -1209,7 +3013,7 @@ macro_rules! impl_Not_Expr {
       type Output = Expr<$t>;
 
       fn not(self) -> Self::Output {
-        Expr::new(ErasedExpr::Not(Box::new(self.0.erased)))
+        Expr::new(ErasedExpr::Not(Arc::new(self.0.erased)))
       }
     }
 
@@ -1217,7 +3021,7 @@ macro_rules! impl_Not_Expr {
       type Output = Expr<$t>;
 
       fn not(self) -> Self::Output {
-        Expr::new(ErasedExpr::Not(Box::new(self.0.erased.clone())))
+        Expr::new(ErasedExpr::Not(Arc::new(self.0.erased.clone())))
       }
     }
   };
@@ -1235,7 +3039,7 @@ macro_rules! impl_Neg {
       type Output = Self;
 
       fn neg(self) -> Self::Output {
-        Expr::new(ErasedExpr::Neg(Box::new(self.erased)))
+        Expr::new(ErasedExpr::Neg(Arc::new(self.erased)))
       }
     }
 
@@ -1243,7 +3047,7 @@ macro_rules! impl_Neg {
       type Output = Expr<$t>;
 
       fn neg(self) -> Self::Output {
-        Expr::new(ErasedExpr::Neg(Box::new(self.erased.clone())))
+        Expr::new(ErasedExpr::Neg(Arc::new(self.erased.clone())))
       }
     }
 
@@ -1251,7 +3055,7 @@ macro_rules! impl_Neg {
       type Output = Expr<$t>;
 
       fn neg(self) -> Self::Output {
-        Expr::new(ErasedExpr::Neg(Box::new(self.0.erased)))
+        Expr::new(ErasedExpr::Neg(Arc::new(self.0.erased)))
       }
     }
 
@@ -1259,7 +3063,7 @@ macro_rules! impl_Neg {
       type Output = Expr<$t>;
 
       fn neg(self) -> Self::Output {
-        Expr::new(ErasedExpr::Neg(Box::new(self.0.erased.clone())))
+        Expr::new(ErasedExpr::Neg(Arc::new(self.0.erased.clone())))
       }
     }
   };
@@ -1293,7 +3097,7 @@ macro_rules! impl_binop_Expr {
       type Output = Expr<$r>;
 
       fn $meth_name(self, rhs: Expr<$b>) -> Self::Output {
-        Expr::new(ErasedExpr::$op(Box::new(self.erased), Box::new(rhs.erased)))
+        Expr::new(ErasedExpr::$op(Arc::new(self.erased), Arc::new(rhs.erased)))
       }
     }
 
@@ -1303,8 +3107,8 @@ macro_rules! impl_binop_Expr {
 
       fn $meth_name(self, rhs: Expr<$b>) -> Self::Output {
         Expr::new(ErasedExpr::$op(
-          Box::new(self.0.erased),
-          Box::new(rhs.erased),
+          Arc::new(self.0.erased),
+          Arc::new(rhs.erased),
         ))
       }
     }
@@ -1315,8 +3119,8 @@ macro_rules! impl_binop_Expr {
 
       fn $meth_name(self, rhs: Var<$b>) -> Self::Output {
         Expr::new(ErasedExpr::$op(
-          Box::new(self.erased),
-          Box::new(rhs.0.erased),
+          Arc::new(self.erased),
+          Arc::new(rhs.0.erased),
         ))
       }
     }
@@ -1327,8 +3131,8 @@ macro_rules! impl_binop_Expr {
 
       fn $meth_name(self, rhs: Var<$b>) -> Self::Output {
         Expr::new(ErasedExpr::$op(
-          Box::new(self.0.erased),
-          Box::new(rhs.0.erased),
+          Arc::new(self.0.erased),
+          Arc::new(rhs.0.erased),
         ))
       }
     }
@@ -1339,8 +3143,8 @@ macro_rules! impl_binop_Expr {
 
       fn $meth_name(self, rhs: &'a Expr<$b>) -> Self::Output {
         Expr::new(ErasedExpr::$op(
-          Box::new(self.erased),
-          Box::new(rhs.erased.clone()),
+          Arc::new(self.erased),
+          Arc::new(rhs.erased.clone()),
         ))
       }
     }
@@ -1351,8 +3155,8 @@ macro_rules! impl_binop_Expr {
 
       fn $meth_name(self, rhs: &'a Expr<$b>) -> Self::Output {
         Expr::new(ErasedExpr::$op(
-          Box::new(self.0.erased),
-          Box::new(rhs.erased.clone()),
+          Arc::new(self.0.erased),
+          Arc::new(rhs.erased.clone()),
         ))
       }
     }
@@ -1363,8 +3167,8 @@ macro_rules! impl_binop_Expr {
 
       fn $meth_name(self, rhs: &'a Var<$b>) -> Self::Output {
         Expr::new(ErasedExpr::$op(
-          Box::new(self.erased),
-          Box::new(rhs.0.erased.clone()),
+          Arc::new(self.erased),
+          Arc::new(rhs.0.erased.clone()),
         ))
       }
     }
@@ -1375,8 +3179,8 @@ macro_rules! impl_binop_Expr {
 
       fn $meth_name(self, rhs: &'a Var<$b>) -> Self::Output {
         Expr::new(ErasedExpr::$op(
-          Box::new(self.0.erased),
-          Box::new(rhs.0.erased.clone()),
+          Arc::new(self.0.erased),
+          Arc::new(rhs.0.erased.clone()),
         ))
       }
     }
@@ -1387,8 +3191,8 @@ macro_rules! impl_binop_Expr {
 
       fn $meth_name(self, rhs: Expr<$b>) -> Self::Output {
         Expr::new(ErasedExpr::$op(
-          Box::new(self.erased.clone()),
-          Box::new(rhs.erased),
+          Arc::new(self.erased.clone()),
+          Arc::new(rhs.erased),
         ))
       }
     }
@@ -1399,8 +3203,8 @@ macro_rules! impl_binop_Expr {
 
       fn $meth_name(self, rhs: Expr<$b>) -> Self::Output {
         Expr::new(ErasedExpr::$op(
-          Box::new(self.0.erased.clone()),
-          Box::new(rhs.erased),
+          Arc::new(self.0.erased.clone()),
+          Arc::new(rhs.erased),
         ))
       }
     }
@@ -1411,8 +3215,8 @@ macro_rules! impl_binop_Expr {
 
       fn $meth_name(self, rhs: Var<$b>) -> Self::Output {
         Expr::new(ErasedExpr::$op(
-          Box::new(self.erased.clone()),
-          Box::new(rhs.0.erased),
+          Arc::new(self.erased.clone()),
+          Arc::new(rhs.0.erased),
         ))
       }
     }
@@ -1423,8 +3227,8 @@ macro_rules! impl_binop_Expr {
 
       fn $meth_name(self, rhs: Var<$b>) -> Self::Output {
         Expr::new(ErasedExpr::$op(
-          Box::new(self.0.erased.clone()),
-          Box::new(rhs.0.erased),
+          Arc::new(self.0.erased.clone()),
+          Arc::new(rhs.0.erased),
         ))
       }
     }
@@ -1435,8 +3239,8 @@ macro_rules! impl_binop_Expr {
 
       fn $meth_name(self, rhs: &'a Expr<$b>) -> Self::Output {
         Expr::new(ErasedExpr::$op(
-          Box::new(self.erased.clone()),
-          Box::new(rhs.erased.clone()),
+          Arc::new(self.erased.clone()),
+          Arc::new(rhs.erased.clone()),
         ))
       }
     }
@@ -1447,8 +3251,8 @@ macro_rules! impl_binop_Expr {
 
       fn $meth_name(self, rhs: &'a Expr<$b>) -> Self::Output {
         Expr::new(ErasedExpr::$op(
-          Box::new(self.0.erased.clone()),
-          Box::new(rhs.erased.clone()),
+          Arc::new(self.0.erased.clone()),
+          Arc::new(rhs.erased.clone()),
         ))
       }
     }
@@ -1459,8 +3263,8 @@ macro_rules! impl_binop_Expr {
 
       fn $meth_name(self, rhs: &'a Var<$b>) -> Self::Output {
         Expr::new(ErasedExpr::$op(
-          Box::new(self.erased.clone()),
-          Box::new(rhs.0.erased.clone()),
+          Arc::new(self.erased.clone()),
+          Arc::new(rhs.0.erased.clone()),
         ))
       }
     }
@@ -1471,8 +3275,8 @@ macro_rules! impl_binop_Expr {
 
       fn $meth_name(self, rhs: &'a Var<$b>) -> Self::Output {
         Expr::new(ErasedExpr::$op(
-          Box::new(self.0.erased.clone()),
-          Box::new(rhs.0.erased.clone()),
+          Arc::new(self.0.erased.clone()),
+          Arc::new(rhs.0.erased.clone()),
         ))
       }
     }
@@ -1483,7 +3287,7 @@ macro_rules! impl_binop_Expr {
 
       fn $meth_name(self, rhs: $b) -> Self::Output {
         let rhs = Expr::from(rhs);
-        Expr::new(ErasedExpr::$op(Box::new(self.erased), Box::new(rhs.erased)))
+        Expr::new(ErasedExpr::$op(Arc::new(self.erased), Arc::new(rhs.erased)))
       }
     }
 
@@ -1494,8 +3298,8 @@ macro_rules! impl_binop_Expr {
       fn $meth_name(self, rhs: $b) -> Self::Output {
         let rhs = Expr::from(rhs);
         Expr::new(ErasedExpr::$op(
-          Box::new(self.0.erased),
-          Box::new(rhs.erased),
+          Arc::new(self.0.erased),
+          Arc::new(rhs.erased),
         ))
       }
     }
@@ -1507,8 +3311,8 @@ macro_rules! impl_binop_Expr {
       fn $meth_name(self, rhs: $b) -> Self::Output {
         let rhs: Expr<$b> = rhs.into();
         Expr::new(ErasedExpr::$op(
-          Box::new(self.erased.clone()),
-          Box::new(rhs.erased),
+          Arc::new(self.erased.clone()),
+          Arc::new(rhs.erased),
         ))
       }
     }
@@ -1520,8 +3324,8 @@ macro_rules! impl_binop_Expr {
       fn $meth_name(self, rhs: $b) -> Self::Output {
         let rhs: Expr<$b> = rhs.into();
         Expr::new(ErasedExpr::$op(
-          Box::new(self.0.erased.clone()),
-          Box::new(rhs.erased),
+          Arc::new(self.0.erased.clone()),
+          Arc::new(rhs.erased),
         ))
       }
     }
@@ -1616,7 +3420,7 @@ macro_rules! impl_binshift_Expr {
       type Output = Expr<$ty>;
 
       fn $meth_name(self, rhs: Expr<u32>) -> Self::Output {
-        Expr::new(ErasedExpr::$op(Box::new(self.erased), Box::new(rhs.erased)))
+        Expr::new(ErasedExpr::$op(Arc::new(self.erased), Arc::new(rhs.erased)))
       }
     }
 
@@ -1625,8 +3429,8 @@ macro_rules! impl_binshift_Expr {
 
       fn $meth_name(self, rhs: Expr<u32>) -> Self::Output {
         Expr::new(ErasedExpr::$op(
-          Box::new(self.erased.clone()),
-          Box::new(rhs.erased),
+          Arc::new(self.erased.clone()),
+          Arc::new(rhs.erased),
         ))
       }
     }
@@ -1636,8 +3440,8 @@ macro_rules! impl_binshift_Expr {
 
       fn $meth_name(self, rhs: &'a Expr<u32>) -> Self::Output {
         Expr::new(ErasedExpr::$op(
-          Box::new(self.erased),
-          Box::new(rhs.erased.clone()),
+          Arc::new(self.erased),
+          Arc::new(rhs.erased.clone()),
         ))
       }
     }
@@ -1647,8 +3451,8 @@ macro_rules! impl_binshift_Expr {
 
       fn $meth_name(self, rhs: &'a Expr<u32>) -> Self::Output {
         Expr::new(ErasedExpr::$op(
-          Box::new(self.erased.clone()),
-          Box::new(rhs.erased.clone()),
+          Arc::new(self.erased.clone()),
+          Arc::new(rhs.erased.clone()),
         ))
       }
     }
@@ -1659,7 +3463,7 @@ macro_rules! impl_binshift_Expr {
 
       fn $meth_name(self, rhs: u32) -> Self::Output {
         let rhs = Expr::from(rhs);
-        Expr::new(ErasedExpr::$op(Box::new(self.erased), Box::new(rhs.erased)))
+        Expr::new(ErasedExpr::$op(Arc::new(self.erased), Arc::new(rhs.erased)))
       }
     }
 
@@ -1669,8 +3473,8 @@ macro_rules! impl_binshift_Expr {
       fn $meth_name(self, rhs: u32) -> Self::Output {
         let rhs = Expr::from(rhs);
         Expr::new(ErasedExpr::$op(
-          Box::new(self.erased.clone()),
-          Box::new(rhs.erased),
+          Arc::new(self.erased.clone()),
+          Arc::new(rhs.erased),
         ))
       }
     }
@@ -1759,7 +3563,7 @@ where
     let array = array
       .iter()
       .cloned()
-      .map(|t| Expr::from(t).erased)
+      .map(|t| Arc::new(Expr::from(t).erased))
       .collect();
     Self::new(ErasedExpr::Array(<[T; N] as ToType>::ty(), array))
   }
@@ -1774,7 +3578,7 @@ where
     let array = array
       .iter()
       .cloned()
-      .map(|t| Expr::from(t).erased)
+      .map(|t| Arc::new(Expr::from(t).erased))
       .collect();
     Self::new(ErasedExpr::Array(<[T; N] as ToType>::ty(), array))
   }
@@ -1786,7 +3590,7 @@ where
   T: ToType,
 {
   fn from(array: [Expr<T>; N]) -> Self {
-    let array = array.iter().cloned().map(|e| e.erased).collect();
+    let array = array.iter().cloned().map(|e| Arc::new(e.erased)).collect();
     Self::new(ErasedExpr::Array(<[T; N] as ToType>::ty(), array))
   }
 }
@@ -1797,7 +3601,7 @@ where
   T: ToType,
 {
   fn from(array: &'a [Expr<T>; N]) -> Self {
-    let array = array.iter().cloned().map(|e| e.erased).collect();
+    let array = array.iter().cloned().map(|e| Arc::new(e.erased)).collect();
     Self::new(ErasedExpr::Array(<[T; N] as ToType>::ty(), array))
   }
 }
@@ -1987,7 +3791,8 @@ pub struct Return {
 /// Erased return.
 ///
 /// Either `Void` (i.e. `void`) or an expression. The type of the expression is also present for convenience.
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 enum ErasedReturn {
   Void,
   Expr(Type, ErasedExpr),
@@ -2001,34 +3806,172 @@ impl From<()> for Return {
   }
 }
 
-impl<T> From<Expr<T>> for Return
+impl<T> From<Expr<T>> for Return
+where
+  T: ToType,
+{
+  fn from(expr: Expr<T>) -> Self {
+    Return {
+      erased: ErasedReturn::Expr(T::ty(), expr.erased),
+    }
+  }
+}
+
+/// Function definition injection.
+///
+/// This trait represents _function definition injection_, i.e. types that can provide a function definition — see
+/// [`FunDef`]. Ideally, only a very small number of types can do this: polymorphic types implementing the [`FnOnce`]
+/// trait with different number of arguments. Namely, closures / lambdas with various numbers of arguments.
+///
+/// You are not supposed to implement this type by yourself. Instead, when creating functions in the EDSL, you just have
+/// to pass lambdas to automatically get the proper function definition lifted into the EDSL.
+///
+/// See the [`ShaderBuilder::fun`] for further information.
+///
+/// # Caveats
+///
+/// This way of doing currently comes with a price: type inference is bad. You will — most of the time — have to
+/// annotate the closure’s arguments. This is currently working on but progress on that matter is slow.
+pub trait ToFun<R, A>: Sized {
+  /// Build the definition of the function.
+  ///
+  /// Scopes of the function are identified from `0`. Functions declared with a [`ShaderBuilder`] are built with
+  /// identifiers distinct from the ones of the other functions of the shader instead.
+  fn build_fn(self) -> FunDef<R, A> {
+    self.build_fn_with_scopes(&Rc::new(Cell::new(0)))
+  }
+
+  /// Build the definition of the function, taking the identifiers of its scopes from a counter.
+  #[doc(hidden)]
+  fn build_fn_with_scopes(self, next_scope: &Rc<Cell<u16>>) -> FunDef<R, A>;
+}
+
+/// How an argument is passed to a function.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ArgQualifier {
+  /// The argument is copied into the function; see [`Expr<T>`].
+  In,
+
+  /// The function writes the argument back to a variable of the caller; see [`Out<T>`].
+  Out,
+
+  /// The argument is copied into the function, which writes it back to a variable of the caller; see [`InOut<T>`].
+  InOut,
+}
+
+/// Argument of a function created with [`ShaderBuilder::fun`].
+///
+/// Arguments are either passed by value as [`Expr<T>`], or passed by reference as [`Out<T>`] or [`InOut<T>`], so
+/// that the function can write to a variable of the caller. Such functions can return several values.
+///
+/// This trait is sealed: it cannot be implemented outside of this crate.
+pub trait FunArg: sealed::Arg {
+  /// Type of the argument in the shader.
+  type Value: ToType;
+
+  /// What is passed for the argument when calling the function: an [`Expr<T>`] for an argument passed by value, and a
+  /// [`Var<T>`] for an argument passed by reference.
+  type Param<'a>
+  where
+    Self: 'a;
+
+  /// How the argument is passed.
+  fn qualifier() -> ArgQualifier;
+
+  /// Argument at a given position in the arguments of the function.
+  #[doc(hidden)]
+  fn from_rank(rank: u16) -> Self;
+
+  /// Expression passed for the argument at a call site.
+  #[doc(hidden)]
+  fn param_expr<'a>(param: Self::Param<'a>) -> Expr<Self::Value>
+  where
+    Self: 'a;
+}
+
+impl<T> sealed::Arg for Expr<T> {}
+
+impl<T> FunArg for Expr<T>
+where
+  T: ToType,
+{
+  type Value = T;
+  type Param<'a>
+    = Expr<T>
+  where
+    Self: 'a;
+
+  fn qualifier() -> ArgQualifier {
+    ArgQualifier::In
+  }
+
+  fn from_rank(rank: u16) -> Self {
+    Expr::new(ErasedExpr::Var(ScopedHandle::fun_arg(rank)))
+  }
+
+  fn param_expr<'a>(param: Self::Param<'a>) -> Expr<Self::Value>
+  where
+    Self: 'a,
+  {
+    param
+  }
+}
+
+impl<T> sealed::Arg for Out<T> {}
+
+impl<T> FunArg for Out<T>
+where
+  T: ToType,
+{
+  type Value = T;
+  type Param<'a>
+    = &'a Var<T>
+  where
+    Self: 'a;
+
+  fn qualifier() -> ArgQualifier {
+    ArgQualifier::Out
+  }
+
+  fn from_rank(rank: u16) -> Self {
+    Out(Var::new(ScopedHandle::fun_arg(rank)))
+  }
+
+  fn param_expr<'a>(param: Self::Param<'a>) -> Expr<Self::Value>
+  where
+    Self: 'a,
+  {
+    param.to_expr()
+  }
+}
+
+impl<T> sealed::Arg for InOut<T> {}
+
+impl<T> FunArg for InOut<T>
 where
   T: ToType,
 {
-  fn from(expr: Expr<T>) -> Self {
-    Return {
-      erased: ErasedReturn::Expr(T::ty(), expr.erased),
-    }
+  type Value = T;
+  type Param<'a>
+    = &'a Var<T>
+  where
+    Self: 'a;
+
+  fn qualifier() -> ArgQualifier {
+    ArgQualifier::InOut
   }
-}
 
-/// Function definition injection.
-///
-/// This trait represents _function definition injection_, i.e. types that can provide a function definition — see
-/// [`FunDef`]. Ideally, only a very small number of types can do this: polymorphic types implementing the [`FnOnce`]
-/// trait with different number of arguments. Namely, closures / lambdas with various numbers of arguments.
-///
-/// You are not supposed to implement this type by yourself. Instead, when creating functions in the EDSL, you just have
-/// to pass lambdas to automatically get the proper function definition lifted into the EDSL.
-///
-/// See the [`ShaderBuilder::fun`] for further information.
-///
-/// # Caveats
-///
-/// This way of doing currently comes with a price: type inference is bad. You will — most of the time — have to
-/// annotate the closure’s arguments. This is currently working on but progress on that matter is slow.
-pub trait ToFun<R, A> {
-  fn build_fn(self) -> FunDef<R, A>;
+  fn from_rank(rank: u16) -> Self {
+    InOut(Var::new(ScopedHandle::fun_arg(rank)))
+  }
+
+  fn param_expr<'a>(param: Self::Param<'a>) -> Expr<Self::Value>
+  where
+    Self: 'a,
+  {
+    param.to_expr()
+  }
 }
 
 impl<F, R> ToFun<R, ()> for F
@@ -2036,8 +3979,8 @@ where
   Self: FnOnce(&mut Scope<R>) -> R,
   Return: From<R>,
 {
-  fn build_fn(self) -> FunDef<R, ()> {
-    let mut scope = Scope::new(0);
+  fn build_fn_with_scopes(self, next_scope: &Rc<Cell<u16>>) -> FunDef<R, ()> {
+    let mut scope = Scope::with_ids(next_scope.clone());
     let ret = self(&mut scope);
 
     let erased = ErasedFun::new(Vec::new(), scope.erased, Return::from(ret).erased);
@@ -2048,20 +3991,21 @@ where
 
 macro_rules! impl_ToFun_args {
   ($($arg:ident , $arg_ident:ident , $arg_rank:expr),*) => {
-    impl<F, R, $($arg),*> ToFun<R, ($(Expr<$arg>),*)> for F
+    impl<F, R, $($arg),*> ToFun<R, ($($arg),*)> for F
     where
-      Self: FnOnce(&mut Scope<R>, $(Expr<$arg>),*) -> R,
+      Self: FnOnce(&mut Scope<R>, $($arg),*) -> R,
       Return: From<R>,
-      $($arg: ToType),*
+      $($arg: FunArg),*
     {
-      fn build_fn(self) -> FunDef<R, ($(Expr<$arg>),*)> {
-        $( let $arg_ident = Expr::new(ErasedExpr::Var(ScopedHandle::fun_arg($arg_rank))); )*
-          let args = vec![$( $arg::ty() ),*];
+      fn build_fn_with_scopes(self, next_scope: &Rc<Cell<u16>>) -> FunDef<R, ($($arg),*)> {
+        $( let $arg_ident = $arg::from_rank($arg_rank); )*
+          let args = vec![$( $arg::Value::ty() ),*];
 
-        let mut scope = Scope::new(0);
+        let mut scope = Scope::with_ids(next_scope.clone());
         let ret = self(&mut scope, $($arg_ident),*);
 
-        let erased = ErasedFun::new(args, scope.erased, Return::from(ret).erased);
+        let mut erased = ErasedFun::new(args, scope.erased, Return::from(ret).erased);
+        erased.arg_qualifiers = vec![$( $arg::qualifier() ),*];
 
         FunDef::new(erased)
       }
@@ -2069,19 +4013,20 @@ macro_rules! impl_ToFun_args {
   }
 }
 
-impl<F, R, A> ToFun<R, Expr<A>> for F
+impl<F, R, A> ToFun<R, A> for F
 where
-  Self: FnOnce(&mut Scope<R>, Expr<A>) -> R,
+  Self: FnOnce(&mut Scope<R>, A) -> R,
   Return: From<R>,
-  A: ToType,
+  A: FunArg,
 {
-  fn build_fn(self) -> FunDef<R, Expr<A>> {
-    let arg = Expr::new(ErasedExpr::Var(ScopedHandle::fun_arg(0)));
+  fn build_fn_with_scopes(self, next_scope: &Rc<Cell<u16>>) -> FunDef<R, A> {
+    let arg = A::from_rank(0);
 
-    let mut scope = Scope::new(0);
+    let mut scope = Scope::with_ids(next_scope.clone());
     let ret = self(&mut scope, arg);
 
-    let erased = ErasedFun::new(vec![A::ty()], scope.erased, Return::from(ret).erased);
+    let mut erased = ErasedFun::new(vec![A::Value::ty()], scope.erased, Return::from(ret).erased);
+    erased.arg_qualifiers = vec![A::qualifier()];
 
     FunDef::new(erased)
   }
@@ -2249,17 +4194,29 @@ impl<R> Fn<()> for FunHandle<Expr<R>, ()> {
   }
 }
 
-impl<R, A> FunHandle<Expr<R>, Expr<A>> {
+impl<R, A> FunHandle<Expr<R>, A>
+where
+  A: FunArg,
+{
   /// Create an expression representing a function call to this function.
   ///
   /// See the documentation of [`FunHandle`] for examples.
-  pub fn call(&self, a: Expr<A>) -> Expr<R> {
-    Expr::new(ErasedExpr::FunCall(self.erased.clone(), vec![a.erased]))
+  pub fn call<'a>(&self, a: A::Param<'a>) -> Expr<R>
+  where
+    A: 'a,
+  {
+    Expr::new(ErasedExpr::FunCall(
+      self.erased.clone(),
+      vec![Arc::new(A::param_expr(a).erased)],
+    ))
   }
 }
 
 #[cfg(feature = "fun-call")]
-impl<R, A> FnOnce<(Expr<A>,)> for FunHandle<Expr<R>, Expr<A>> {
+impl<R, A> FnOnce<(Expr<A>,)> for FunHandle<Expr<R>, Expr<A>>
+where
+  A: ToType,
+{
   type Output = Expr<R>;
 
   extern "rust-call" fn call_once(self, a: (Expr<A>,)) -> Self::Output {
@@ -2268,14 +4225,20 @@ impl<R, A> FnOnce<(Expr<A>,)> for FunHandle<Expr<R>, Expr<A>> {
 }
 
 #[cfg(feature = "fun-call")]
-impl<R, A> FnMut<(Expr<A>,)> for FunHandle<Expr<R>, Expr<A>> {
+impl<R, A> FnMut<(Expr<A>,)> for FunHandle<Expr<R>, Expr<A>>
+where
+  A: ToType,
+{
   extern "rust-call" fn call_mut(&mut self, a: (Expr<A>,)) -> Self::Output {
     self.call(a.0)
   }
 }
 
 #[cfg(feature = "fun-call")]
-impl<R, A> Fn<(Expr<A>,)> for FunHandle<Expr<R>, Expr<A>> {
+impl<R, A> Fn<(Expr<A>,)> for FunHandle<Expr<R>, Expr<A>>
+where
+  A: ToType,
+{
   extern "rust-call" fn call(&self, a: (Expr<A>,)) -> Self::Output {
     self.call(a.0)
   }
@@ -2284,18 +4247,29 @@ impl<R, A> Fn<(Expr<A>,)> for FunHandle<Expr<R>, Expr<A>> {
 // the first stage must be named S0
 macro_rules! impl_FunCall {
   ( $( ( $arg_name:ident, $arg_ty:ident ) ),*) => {
-    impl<R, $($arg_ty),*> FunHandle<Expr<R>, ($(Expr<$arg_ty>),*)>
+    impl<R, $($arg_ty),*> FunHandle<Expr<R>, ($($arg_ty),*)>
+    where
+      $($arg_ty: FunArg),*
     {
       /// Create an expression representing a function call to this function.
       ///
       /// See the documentation of [`FunHandle`] for examples.
-      pub fn call(&self, $($arg_name : Expr<$arg_ty>),*) -> Expr<R> {
-        Expr::new(ErasedExpr::FunCall(self.erased.clone(), vec![$($arg_name.erased),*]))
+      #[allow(clippy::too_many_arguments)]
+      pub fn call<'a>(&self, $($arg_name : $arg_ty::Param<'a>),*) -> Expr<R>
+      where
+        $($arg_ty: 'a),*
+      {
+        Expr::new(ErasedExpr::FunCall(
+          self.erased.clone(),
+          vec![$(Arc::new($arg_ty::param_expr($arg_name).erased)),*],
+        ))
       }
     }
 
     #[cfg(feature = "fun-call")]
     impl<R, $($arg_ty),*> FnOnce<($(Expr<$arg_ty>),*)> for FunHandle<Expr<R>, ($(Expr<$arg_ty>),*)>
+    where
+      $($arg_ty: ToType),*
     {
       type Output = Expr<R>;
 
@@ -2306,6 +4280,8 @@ macro_rules! impl_FunCall {
 
     #[cfg(feature = "fun-call")]
     impl<R, $($arg_ty),*> FnMut<($(Expr<$arg_ty>),*)> for FunHandle<Expr<R>, ($(Expr<$arg_ty>),*)>
+    where
+      $($arg_ty: ToType),*
     {
       extern "rust-call" fn call_mut(&mut self, ($($arg_name),*): ($(Expr<$arg_ty>),*)) -> Self::Output {
         self.call($($arg_name),*)
@@ -2314,6 +4290,8 @@ macro_rules! impl_FunCall {
 
     #[cfg(feature = "fun-call")]
     impl<R, $($arg_ty),*> Fn<($(Expr<$arg_ty>),*)> for FunHandle<Expr<R>, ($(Expr<$arg_ty>),*)>
+    where
+      $($arg_ty: ToType),*
     {
       extern "rust-call" fn call(&self, ($($arg_name),*): ($(Expr<$arg_ty>),*)) -> Self::Output {
         self.call($($arg_name),*)
@@ -2353,7 +4331,9 @@ impl_FunCall_rec!(
 );
 
 /// Erased function handle.
-#[derive(Clone, Debug, PartialEq)]
+#[allow(clippy::upper_case_acronyms, dead_code)]
+#[derive(Clone, Debug, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 enum ErasedFunHandle {
   // cast operators
   Vec2,
@@ -2481,6 +4461,8 @@ enum ErasedFunHandle {
   AllInvocations,
   AllInvocationsEqual,
   UserDefined(u16),
+  // function of a library, by library and handle in the library; replaced when the function is imported
+  Library(usize, u16),
 }
 
 /// A function definition.
@@ -2503,16 +4485,57 @@ impl<R, A> FunDef<R, A> {
 }
 
 /// Erased function definition.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct ErasedFun {
   args: Vec<Type>,
+  // how each argument is passed
+  arg_qualifiers: Vec<ArgQualifier>,
   scope: ErasedScope,
   ret: ErasedReturn,
+  // name given to the function, if any
+  name: Option<String>,
+  // names given to the first arguments
+  arg_names: Vec<String>,
+  // location of the EDSL call declaring the function
+  #[cfg_attr(feature = "serde", serde(skip))]
+  location: Option<&'static Location<'static>>,
 }
 
 impl ErasedFun {
   fn new(args: Vec<Type>, scope: ErasedScope, ret: ErasedReturn) -> Self {
-    Self { args, scope, ret }
+    Self {
+      arg_qualifiers: vec![ArgQualifier::In; args.len()],
+      args,
+      scope,
+      ret,
+      name: None,
+      arg_names: Vec::new(),
+      location: None,
+    }
+  }
+}
+
+// the location is debug information and doesn’t change the meaning of a function
+impl PartialEq for ErasedFun {
+  fn eq(&self, other: &Self) -> bool {
+    self.args == other.args
+      && self.arg_qualifiers == other.arg_qualifiers
+      && self.scope == other.scope
+      && self.ret == other.ret
+      && self.name == other.name
+      && self.arg_names == other.arg_names
+  }
+}
+
+impl Hash for ErasedFun {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    self.args.hash(state);
+    self.arg_qualifiers.hash(state);
+    self.scope.hash(state);
+    self.ret.hash(state);
+    self.name.hash(state);
+    self.arg_names.hash(state);
   }
 }
 
@@ -2537,6 +4560,8 @@ impl ErasedFun {
 #[derive(Debug)]
 pub struct Scope<R> {
   erased: ErasedScope,
+  // identifier of the next scope, shared by all the scopes of a function
+  next_scope: Rc<Cell<u16>>,
   _phantom: PhantomData<R>,
 }
 
@@ -2546,21 +4571,31 @@ where
 {
   /// Create a new [`Scope<R>`] for which the ID is explicitly passed.
   ///
-  /// The ID is unique in the scope hierarchy, but is not necessarily unique in the parent scope. What it means is that
-  /// creating a scope `s` in a (parent) scope of ID `p` will give `s` the ID `p + 1`. So any scope created directly
-  /// under the scope of ID `p` will get the `p + 1` ID. The reason for this is that variables go out of scope at the
-  /// end of the scope they were created in, so it’s safe to reuse the same ID for sibling scopes, as they can’t share
-  /// variables.
+  /// Scopes created under it get the next IDs.
+  #[cfg(test)]
   fn new(id: u16) -> Self {
+    Scope::with_ids(Rc::new(Cell::new(id)))
+  }
+
+  /// Create a new [`Scope<R>`], taking its ID from a counter shared with the scopes created under it.
+  ///
+  /// Every scope of a shader gets its own ID, so that variables of a scope, which are identified by the ID of the scope
+  /// and their declaration order in it, cannot be mistaken for variables of another scope — even when a [`Var<T>`]
+  /// leaks out of the closure of a function into another one.
+  fn with_ids(next_scope: Rc<Cell<u16>>) -> Self {
+    let id = next_scope.get();
+    next_scope.set(id.wrapping_add(1));
+
     Self {
       erased: ErasedScope::new(id),
+      next_scope,
       _phantom: PhantomData,
     }
   }
 
   /// Create a new fresh scope under the current scope.
   fn deeper(&self) -> Self {
-    Scope::new(self.erased.id + 1)
+    Scope::with_ids(self.next_scope.clone())
   }
 
   /// Bind an expression to a variable in the current scope.
@@ -2584,6 +4619,7 @@ where
   /// #   })
   /// # });
   /// ```
+  #[track_caller]
   pub fn var<T>(&mut self, init_value: impl Into<Expr<T>>) -> Var<T>
   where
     T: ToType,
@@ -2591,9 +4627,9 @@ where
     let n = self.erased.next_var;
     let handle = ScopedHandle::fun_var(self.erased.id, n);
 
-    self.erased.next_var += 1;
+    self.erased.next_var = self.erased.next_var.wrapping_add(1);
 
-    self.erased.instructions.push(ScopeInstr::VarDecl {
+    self.erased.push(ScopeInstr::VarDecl {
       ty: T::ty(),
       handle: handle.clone(),
       init_value: init_value.into().erased,
@@ -2602,6 +4638,35 @@ where
     Var::new(handle)
   }
 
+  /// Bind an expression to a named variable in the current scope.
+  ///
+  /// This method is similar to [`Scope::var`], but the name is used by writers to generate the identifier of the
+  /// variable.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// # use shades::{Scope, ShaderBuilder, writer::glsl};
+  /// let shader = ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+  ///   s.main_fun(|s: &mut Scope<()>| {
+  ///     let albedo = s.var_named("albedo", 3.1415);
+  ///   })
+  /// });
+  ///
+  /// let output = glsl::write_shader_to_str(&shader).unwrap();
+  /// assert!(output.contains("float albedo = 3.1415;"));
+  /// ```
+  #[track_caller]
+  pub fn var_named<T>(&mut self, name: impl Into<String>, init_value: impl Into<Expr<T>>) -> Var<T>
+  where
+    T: ToType,
+  {
+    let handle = self.erased.next_var;
+    let var = self.var(init_value);
+    self.erased.names.insert(handle, name.into());
+    var
+  }
+
   /// For looping statement — `for`.
   ///
   /// `s.loop_for(i, |i| /* cond */, |i| /* fold */, |i| /* body */ )` inserts a looping statement into the EDSL
@@ -2640,6 +4705,7 @@ where
   ///   })
   /// });
   /// ```
+  #[track_caller]
   pub fn loop_for<T>(
     &mut self,
     init_value: impl Into<Expr<T>>,
@@ -2651,8 +4717,11 @@ where
   {
     let mut scope = LoopScope::new(self.deeper());
 
-    // bind the init value so that it’s available in all closures
-    let init_var = scope.var(init_value);
+    // bind the init value so that it’s available in all closures; it is declared by the loop itself, so it must not be
+    // declared in the loop scope
+    let init_handle = ScopedHandle::fun_var(scope.erased.id, scope.erased.next_var);
+    scope.erased.next_var = scope.erased.next_var.wrapping_add(1);
+    let init_var: Var<T> = Var::new(init_handle.clone());
 
     let condition = condition(&init_var);
 
@@ -2663,10 +4732,10 @@ where
     body(&mut scope, &init_var);
 
     let scope = Scope::from(scope);
-    self.erased.instructions.push(ScopeInstr::For {
+    self.erased.push(ScopeInstr::For {
       init_ty: T::ty(),
-      init_handle: ScopedHandle::fun_var(scope.erased.id, 0),
-      init_expr: init_var.to_expr().erased,
+      init_handle,
+      init_expr: init_value.into().erased,
       condition: condition.erased,
       post_expr: post_expr.erased,
       scope: scope.erased,
@@ -2698,6 +4767,7 @@ where
   ///   })
   /// });
   /// ```
+  #[track_caller]
   pub fn loop_while(
     &mut self,
     condition: impl Into<Expr<bool>>,
@@ -2706,7 +4776,7 @@ where
     let mut scope = LoopScope::new(self.deeper());
     body(&mut scope);
 
-    self.erased.instructions.push(ScopeInstr::While {
+    self.erased.push(ScopeInstr::While {
       condition: condition.into().erased,
       scope: Scope::from(scope).erased,
     });
@@ -2725,9 +4795,10 @@ where
   /// #   })
   /// # });
   /// ```
-  pub fn set<T>(&mut self, var: impl Into<Var<T>>, value: impl Into<Expr<T>>) {
-    self.erased.instructions.push(ScopeInstr::MutateVar {
-      var: var.into().to_expr().erased,
+  #[track_caller]
+  pub fn set<T>(&mut self, var: impl Assignable<T>, value: impl Into<Expr<T>>) {
+    self.erased.push(ScopeInstr::MutateVar {
+      var: sealed::Place::place(var).to_expr().erased,
       expr: value.into().erased,
     });
   }
@@ -2787,10 +4858,10 @@ where
   /// #   })
   /// # });
   /// ```
+  #[track_caller]
   pub fn leave(&mut self, ret: impl Into<R>) {
     self
       .erased
-      .instructions
       .push(ScopeInstr::Return(Return::from(ret.into()).erased));
   }
 }
@@ -2818,11 +4889,9 @@ impl EscapeScope<()> {
   /// #   })
   /// # });
   /// ```
+  #[track_caller]
   pub fn abort(&mut self) {
-    self
-      .erased
-      .instructions
-      .push(ScopeInstr::Return(ErasedReturn::Void));
+    self.erased.push(ScopeInstr::Return(ErasedReturn::Void));
   }
 }
 
@@ -2874,8 +4943,9 @@ where
   /// #   })
   /// # });
   /// ```
+  #[track_caller]
   pub fn loop_continue(&mut self) {
-    self.erased.instructions.push(ScopeInstr::Continue);
+    self.erased.push(ScopeInstr::Continue);
   }
 
   /// Break the nearest loop.
@@ -2894,16 +4964,43 @@ where
   /// #   })
   /// # });
   /// ```
+  #[track_caller]
   pub fn loop_break(&mut self) {
-    self.erased.instructions.push(ScopeInstr::Break);
+    self.erased.push(ScopeInstr::Break);
   }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct ErasedScope {
   id: u16,
   instructions: Vec<ScopeInstr>,
   next_var: u16,
+  // names given to the variables declared in this scope, by handle
+  names: BTreeMap<u16, String>,
+  // locations of the EDSL calls that created the instructions; instructions created otherwise have none, and can be
+  // missing at the end
+  #[cfg_attr(feature = "serde", serde(skip))]
+  locations: Vec<Option<&'static Location<'static>>>,
+}
+
+// locations are debug information and don’t change the meaning of a scope
+impl PartialEq for ErasedScope {
+  fn eq(&self, other: &Self) -> bool {
+    self.id == other.id
+      && self.instructions == other.instructions
+      && self.next_var == other.next_var
+      && self.names == other.names
+  }
+}
+
+impl Hash for ErasedScope {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    self.id.hash(state);
+    self.instructions.hash(state);
+    self.next_var.hash(state);
+    self.names.hash(state);
+  }
 }
 
 impl ErasedScope {
@@ -2912,8 +5009,48 @@ impl ErasedScope {
       id,
       instructions: Vec::new(),
       next_var: 0,
+      names: BTreeMap::new(),
+      locations: Vec::new(),
+    }
+  }
+
+  /// Push an instruction created by the caller.
+  #[track_caller]
+  fn push(&mut self, instr: ScopeInstr) {
+    self.insert(self.instructions.len(), instr, Some(Location::caller()));
+  }
+
+  /// Insert an instruction at the given index.
+  fn insert(
+    &mut self,
+    index: usize,
+    instr: ScopeInstr,
+    location: Option<&'static Location<'static>>,
+  ) {
+    self.locations.resize(self.instructions.len(), None);
+    self.instructions.insert(index, instr);
+    self.locations.insert(index, location);
+  }
+
+  /// Keep only the instructions satisfying the predicate, along with their locations.
+  fn retain(&mut self, mut f: impl FnMut(&ScopeInstr) -> bool) {
+    self.locations.resize(self.instructions.len(), None);
+
+    let instructions = std::mem::take(&mut self.instructions);
+    let locations = std::mem::take(&mut self.locations);
+
+    for (instr, location) in instructions.into_iter().zip(locations) {
+      if f(&instr) {
+        self.instructions.push(instr);
+        self.locations.push(location);
+      }
     }
   }
+
+  /// Location of the EDSL call that created the instruction at the given index, if any.
+  fn location(&self, index: usize) -> Option<&'static Location<'static>> {
+    self.locations.get(index).copied().flatten()
+  }
 }
 
 /// Scopes allowing to enter conditional scopes.
@@ -2993,6 +5130,7 @@ where
   ///
   /// This method does the same thing as [`Scope::when`] but applies the [`Not::not`](std::ops::Not::not) operator on
   /// the condition first.
+  #[track_caller]
   fn unless<'a>(
     &'a mut self,
     condition: impl Into<Expr<bool>>,
@@ -3008,6 +5146,7 @@ where
 {
   type InnerScope = EscapeScope<R>;
 
+  #[track_caller]
   fn when<'a>(
     &'a mut self,
     condition: impl Into<Expr<bool>>,
@@ -3016,7 +5155,7 @@ where
     let mut scope = EscapeScope::new(self.deeper());
     body(&mut scope);
 
-    self.erased.instructions.push(ScopeInstr::If {
+    self.erased.push(ScopeInstr::If {
       condition: condition.into().erased,
       scope: Scope::from(scope).erased,
     });
@@ -3031,6 +5170,7 @@ where
 {
   type InnerScope = LoopScope<R>;
 
+  #[track_caller]
   fn when<'a>(
     &'a mut self,
     condition: impl Into<Expr<bool>>,
@@ -3039,7 +5179,7 @@ where
     let mut scope = LoopScope::new(self.deeper());
     body(&mut scope);
 
-    self.erased.instructions.push(ScopeInstr::If {
+    self.erased.push(ScopeInstr::If {
       condition: condition.into().erased,
       scope: Scope::from(scope).erased,
     });
@@ -3093,6 +5233,7 @@ where
   /// #   })
   /// # });
   /// ```
+  #[track_caller]
   pub fn or_else(
     self,
     condition: impl Into<Expr<bool>>,
@@ -3101,14 +5242,10 @@ where
     let mut scope = EscapeScope::new(self.parent_scope.deeper());
     body(&mut scope);
 
-    self
-      .parent_scope
-      .erased
-      .instructions
-      .push(ScopeInstr::ElseIf {
-        condition: condition.into().erased,
-        scope: Scope::from(scope).erased,
-      });
+    self.parent_scope.erased.push(ScopeInstr::ElseIf {
+      condition: condition.into().erased,
+      scope: Scope::from(scope).erased,
+    });
 
     self
   }
@@ -3161,17 +5298,14 @@ where
   /// #   })
   /// # });
   /// ```
+  #[track_caller]
   pub fn or(self, body: impl FnOnce(&mut EscapeScope<R>)) {
     let mut scope = EscapeScope::new(self.parent_scope.deeper());
     body(&mut scope);
 
-    self
-      .parent_scope
-      .erased
-      .instructions
-      .push(ScopeInstr::Else {
-        scope: Scope::from(scope).erased,
-      });
+    self.parent_scope.erased.push(ScopeInstr::Else {
+      scope: Scope::from(scope).erased,
+    });
   }
 }
 
@@ -3223,6 +5357,14 @@ where
     Self(Expr::new(ErasedExpr::Var(handle)))
   }
 
+  /// Name of the output this variable represents, if any.
+  fn output_name(&self) -> Option<String> {
+    match &self.0.erased {
+      ErasedExpr::Var(ScopedHandle::Output(name)) => Some(name.clone()),
+      _ => None,
+    }
+  }
+
   /// Coerce [`Var<T>`] into [`Expr<T>`].
   ///
   /// Remember that doing so will move the [`Var<T>`]. `clone` it if you want to preserve the source variable.
@@ -3274,6 +5416,235 @@ where
   }
 }
 
+/// Places that can be written to with [`Scope::set`]: variables, outputs and function arguments passed by reference.
+///
+/// This trait is sealed: it cannot be implemented outside of this crate.
+pub trait Assignable<T>: sealed::Place<T> {}
+
+mod sealed {
+  use crate::Var;
+
+  pub trait Place<T> {
+    fn place(self) -> Var<T>;
+  }
+
+  pub trait Arg {}
+}
+
+impl<T> Assignable<T> for Var<T> {}
+
+impl<T> sealed::Place<T> for Var<T> {
+  fn place(self) -> Var<T> {
+    self
+  }
+}
+
+impl<T> Assignable<T> for &Var<T> {}
+
+impl<T> sealed::Place<T> for &Var<T> {
+  fn place(self) -> Var<T> {
+    Var::from(self)
+  }
+}
+
+impl<T> Assignable<T> for Output<T> {}
+
+impl<T> sealed::Place<T> for Output<T> {
+  fn place(self) -> Var<T> {
+    self.0
+  }
+}
+
+impl<T> Assignable<T> for &Output<T> {}
+
+impl<T> sealed::Place<T> for &Output<T> {
+  fn place(self) -> Var<T> {
+    Var::from(&self.0)
+  }
+}
+
+impl<T> Assignable<T> for Out<T> {}
+
+impl<T> sealed::Place<T> for Out<T> {
+  fn place(self) -> Var<T> {
+    self.0
+  }
+}
+
+impl<T> Assignable<T> for &Out<T> {}
+
+impl<T> sealed::Place<T> for &Out<T> {
+  fn place(self) -> Var<T> {
+    Var::from(&self.0)
+  }
+}
+
+impl<T> Assignable<T> for &InOut<T> {}
+
+impl<T> sealed::Place<T> for &InOut<T> {
+  fn place(self) -> Var<T> {
+    Var::from(&self.0)
+  }
+}
+
+/// Output of a shader stage.
+///
+/// An [`Output<T>`] is write-only: it can be passed to [`Scope::set`], but it cannot be used as an [`Expr<T>`], since
+/// the value of an output is undefined until written. Compute the value in a [`Var<T>`] first if you need to read it
+/// back.
+///
+/// # Examples
+///
+/// ```
+/// use shades::{Scope, ShaderBuilder, V4, lit, outputs};
+///
+/// ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+///   outputs!(s, color: V4<f32>);
+///
+///   s.main_fun(|s: &mut Scope<()>| {
+///     s.set(&color, lit!(1., 0., 0., 1.));
+///   })
+/// });
+/// ```
+///
+/// Outputs cannot be read:
+///
+/// ```compile_fail
+/// use shades::{Scope, ShaderBuilder, V4, lit, outputs};
+///
+/// ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+///   outputs!(s, color: V4<f32>);
+///
+///   s.main_fun(|s: &mut Scope<()>| {
+///     let c = s.var(color.to_expr());
+///   })
+/// });
+/// ```
+///
+/// Nor turned into a [`Var<T>`]:
+///
+/// ```compile_fail
+/// use shades::{Scope, ShaderBuilder, V4, Var, lit, outputs};
+///
+/// ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+///   outputs!(s, color: V4<f32>);
+///
+///   s.main_fun(|s: &mut Scope<()>| {
+///     let c = s.var(Var::from(&color).to_expr());
+///   })
+/// });
+/// ```
+#[derive(Debug)]
+pub struct Output<T>(Var<T>)
+where
+  T: ?Sized;
+
+impl<T> Output<[T]> {
+  /// Output at the given index of an array output.
+  pub fn at(&self, index: impl Into<Expr<i32>>) -> Output<T> {
+    Output(self.0.at(index))
+  }
+}
+
+impl<T, const N: usize> Output<[T; N]> {
+  /// Output at the given index of an array output.
+  pub fn at(&self, index: impl Into<Expr<i32>>) -> Output<T> {
+    Output(self.0.at(index))
+  }
+}
+
+/// Argument of a function that the function writes to a variable of the caller.
+///
+/// Use it as an argument of the closure passed to [`ShaderBuilder::fun`] to create a function with an `out` argument,
+/// typically to return several values. The function is called with a reference to a [`Var<T>`], which is assigned the
+/// value the function wrote to the argument; the value of the variable before the call is not passed to the function.
+/// In the body of the function, the argument is write-only: it can be passed to [`Scope::set`], but it cannot be read,
+/// since its value is undefined until written. Use [`InOut<T>`] to read the value of the variable.
+///
+/// # Examples
+///
+/// ```
+/// # use shades::ShaderBuilder;
+/// # ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+/// use shades::{Expr, Floating as _, Out, Scope, lit};
+///
+/// // split a number into its integer and fractional parts
+/// let split = s.fun(|s: &mut Scope<Expr<f32>>, x: Expr<f32>, integer: Out<f32>| {
+///   s.set(&integer, x.floor());
+///   x.fract()
+/// });
+///
+/// s.main_fun(|s: &mut Scope<()>| {
+///   let integer = s.var(lit!(0.));
+///   let fract = s.var(split.call(lit!(1.5), &integer));
+/// })
+/// # });
+/// ```
+///
+/// `out` arguments cannot be read:
+///
+/// ```compile_fail
+/// # use shades::ShaderBuilder;
+/// # ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+/// use shades::{Expr, Out, Scope, lit};
+///
+/// let increment = s.fun(|s: &mut Scope<()>, x: Out<f32>| {
+///   s.set(&x, x.to_expr() + 1.);
+/// });
+/// # s.main_fun(|s: &mut Scope<()>| {})
+/// # });
+/// ```
+#[derive(Debug)]
+pub struct Out<T>(Var<T>);
+
+impl<T, const N: usize> Out<[T; N]> {
+  /// Argument at the given index of an array argument.
+  pub fn at(&self, index: impl Into<Expr<i32>>) -> Out<T> {
+    Out(self.0.at(index))
+  }
+}
+
+/// Argument of a function that the function reads from and writes to a variable of the caller.
+///
+/// This is similar to [`Out<T>`], but the value of the variable is passed to the function, which can read it.
+///
+/// # Examples
+///
+/// ```
+/// # use shades::ShaderBuilder;
+/// # ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+/// use shades::{Expr, InOut, Scope, lit};
+///
+/// // add a value to an accumulator and return the previous value
+/// let accumulate = s.fun(|s: &mut Scope<Expr<f32>>, acc: InOut<f32>, x: Expr<f32>| {
+///   let previous = s.var(acc.to_expr());
+///   s.set(&acc, acc.clone() + x);
+///   previous.to_expr()
+/// });
+///
+/// s.main_fun(|s: &mut Scope<()>| {
+///   let acc = s.var(lit!(1.));
+///   let previous = s.var(accumulate.call(&acc, lit!(2.)));
+/// })
+/// # });
+/// ```
+#[derive(Debug)]
+pub struct InOut<T>(Var<T>);
+
+impl<'a, T> From<&'a InOut<T>> for Var<T> {
+  fn from(arg: &'a InOut<T>) -> Self {
+    Var::from(&arg.0)
+  }
+}
+
+impl<T> ops::Deref for InOut<T> {
+  type Target = Var<T>;
+
+  fn deref(&self) -> &Self::Target {
+    &self.0
+  }
+}
+
 /// Hierarchical and namespaced handle.
 ///
 /// Handles live in different namespaces:
@@ -3285,12 +5656,16 @@ where
 /// - The _output_ namespace gathers outputs.
 /// - The _function argument_ namespace gives handles to function arguments, which exist only in a function body.
 /// - The _function variable_ namespace gives handles to variables defined in function bodies. This namespace is
-/// hierarchical: for each scope, a new namespace is created. The depth at which a namespace is located is referred to
-/// as its _subscope_.
+/// hierarchical: for each scope, a new namespace is created. The identifier of the scope, unique in the shader, is
+/// referred to as its _subscope_.
+#[allow(clippy::doc_lazy_continuation)]
 #[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 enum ScopedHandle {
   BuiltIn(BuiltIn),
   Global(u16),
+  // constant of a library, by library and handle in the library; replaced when the constant is imported
+  LibraryGlobal(usize, u16),
   FunArg(u16),
   FunVar { subscope: u16, handle: u16 },
   Input(String),
@@ -3320,7 +5695,9 @@ impl ScopedHandle {
   }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[allow(clippy::large_enum_variant)]
 enum ScopeInstr {
   VarDecl {
     ty: Type,
@@ -3366,6 +5743,8 @@ enum ScopeInstr {
     var: ErasedExpr,
     expr: ErasedExpr,
   },
+
+  Discard,
 }
 
 /// Dimension of a primitive type.
@@ -3377,6 +5756,7 @@ enum ScopeInstr {
 /// - [`Dim::D3`]: designates a 3D vector.
 /// - [`Dim::D4`]: designates a 4D vector.
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Dim {
   /// Scalar value.
   Scalar,
@@ -3395,6 +5775,7 @@ pub enum Dim {
 ///
 /// This type represents a matrix of a given dimension, deduced from the wrapped type.
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Matrix<T>(T);
 
 impl<T, const M: usize, const N: usize> From<[[T; N]; M]> for Matrix<[[T; N]; M]> {
@@ -3436,6 +5817,7 @@ make_mat_ty!(M44, LitM44, 4, 4, D44);
 ///
 /// > Note: matrices are expressed in column-major.
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum MatrixDim {
   /// Squared 2 dimension.
   D22,
@@ -3459,14 +5841,30 @@ pub enum MatrixDim {
 
 /// Type representation — akin to [`PrimType`] glued with array dimensions, if any.
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Type {
   /// Primitive type, representing a type without array dimensions.
   prim_ty: PrimType,
 
   /// Array dimensions, if any.
   ///
-  /// Dimensions are sorted from outer to inner; i.e. `[[i32; N]; M]`’s dimensions is encoded as `vec![M, N]`.
-  array_dims: Vec<usize>,
+  /// Dimensions are sorted from outer to inner; i.e. `[[i32; N]; M]`’s dimensions is encoded as `vec![M, N]`. A
+  /// dimension of `0` represents an unsized array, such as `[i32]`.
+  array_dims: Vec<usize>,
+}
+
+impl Type {
+  /// Primitive type, representing the type without its array dimensions.
+  pub fn prim_ty(&self) -> &PrimType {
+    &self.prim_ty
+  }
+
+  /// Array dimensions, if any.
+  ///
+  /// Dimensions are sorted from outer to inner. A dimension of `0` represents an unsized array.
+  pub fn array_dims(&self) -> &[usize] {
+    &self.array_dims
+  }
 }
 
 /// Primitive supported types.
@@ -3475,6 +5873,7 @@ pub struct Type {
 /// [`PrimType`].
 #[non_exhaustive]
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PrimType {
   /// An integral type.
   ///
@@ -3573,8 +5972,80 @@ where
   }
 }
 
+impl<T> ToType for [T]
+where
+  T: ToType,
+{
+  fn ty() -> Type {
+    let Type {
+      prim_ty,
+      array_dims,
+    } = T::ty();
+    let array_dims = once(0).chain(array_dims).collect();
+
+    Type {
+      prim_ty,
+      array_dims,
+    }
+  }
+}
+
+/// Types that can be written to a colour attachment.
+///
+/// Only scalars and scalar vectors of signed integers, unsigned integers and floating-point numbers can be written to
+/// colour attachments. See [`ShaderBuilder::color_attachment`] for further details.
+pub trait ColorType: ToType {}
+
+impl ColorType for i32 {}
+impl ColorType for u32 {}
+impl ColorType for f32 {}
+impl ColorType for V2<i32> {}
+impl ColorType for V2<u32> {}
+impl ColorType for V2<f32> {}
+impl ColorType for V3<i32> {}
+impl ColorType for V3<u32> {}
+impl ColorType for V3<f32> {}
+impl ColorType for V4<i32> {}
+impl ColorType for V4<u32> {}
+impl ColorType for V4<f32> {}
+
+/// Types that can be passed from a shader stage to the next one.
+///
+/// Booleans cannot be passed between stages. Integers are not interpolated: they are written `flat` by the stages
+/// passing them to the fragment stage, and by the fragment stage. See [`Varyings`] for further details.
+///
+/// ```compile_fail
+/// use shades::{Program, Scope};
+///
+/// let program = Program::new_vertex_shader(|mut s, _| {
+///   let culled = s.output::<bool>("culled")?;
+///   Ok((s.main_fun(|_: &mut Scope<()>| {}), culled))
+/// })?
+/// .fragment_shader(|s, _, _| Ok(s.main_fun(|_: &mut Scope<()>| {})))?;
+/// # Ok::<_, shades::ProgramError>(())
+/// ```
+pub trait VaryingType: ToType {}
+
+impl VaryingType for i32 {}
+impl VaryingType for u32 {}
+impl VaryingType for f32 {}
+impl VaryingType for V2<i32> {}
+impl VaryingType for V2<u32> {}
+impl VaryingType for V2<f32> {}
+impl VaryingType for V3<i32> {}
+impl VaryingType for V3<u32> {}
+impl VaryingType for V3<f32> {}
+impl VaryingType for V4<i32> {}
+impl VaryingType for V4<u32> {}
+impl VaryingType for V4<f32> {}
+impl VaryingType for M22 {}
+impl VaryingType for M33 {}
+impl VaryingType for M44 {}
+impl<T, const N: usize> VaryingType for [T; N] where T: VaryingType {}
+
 /// Select a channel to extract from into a swizzled expession.
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum SwizzleSelector {
   /// Select the `.x` (or `.r`) channel.
   X,
@@ -3593,7 +6064,8 @@ pub enum SwizzleSelector {
 ///
 /// This type gives the dimension of the target expression (output) and dimension of the source expression (input). The
 /// [`SwizzleSelector`] also to select a specific channel in the input expression.
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Swizzle {
   /// Create a one-channel expression.
   D1(SwizzleSelector),
@@ -3633,7 +6105,7 @@ impl<T> Swizzlable<SwizzleSelector> for Expr<V2<T>> {
 
   fn swizzle(&self, x: SwizzleSelector) -> Self::Output {
     Expr::new(ErasedExpr::Swizzle(
-      Box::new(self.erased.clone()),
+      Arc::new(self.erased.clone()),
       Swizzle::D1(x),
     ))
   }
@@ -3644,7 +6116,7 @@ impl<T> Swizzlable<[SwizzleSelector; 2]> for Expr<V2<T>> {
 
   fn swizzle(&self, [x, y]: [SwizzleSelector; 2]) -> Self::Output {
     Expr::new(ErasedExpr::Swizzle(
-      Box::new(self.erased.clone()),
+      Arc::new(self.erased.clone()),
       Swizzle::D2(x, y),
     ))
   }
@@ -3656,7 +6128,7 @@ impl<T> Swizzlable<SwizzleSelector> for Expr<V3<T>> {
 
   fn swizzle(&self, x: SwizzleSelector) -> Self::Output {
     Expr::new(ErasedExpr::Swizzle(
-      Box::new(self.erased.clone()),
+      Arc::new(self.erased.clone()),
       Swizzle::D1(x),
     ))
   }
@@ -3667,7 +6139,7 @@ impl<T> Swizzlable<[SwizzleSelector; 2]> for Expr<V3<T>> {
 
   fn swizzle(&self, [x, y]: [SwizzleSelector; 2]) -> Self::Output {
     Expr::new(ErasedExpr::Swizzle(
-      Box::new(self.erased.clone()),
+      Arc::new(self.erased.clone()),
       Swizzle::D2(x, y),
     ))
   }
@@ -3678,7 +6150,7 @@ impl<T> Swizzlable<[SwizzleSelector; 3]> for Expr<V3<T>> {
 
   fn swizzle(&self, [x, y, z]: [SwizzleSelector; 3]) -> Self::Output {
     Expr::new(ErasedExpr::Swizzle(
-      Box::new(self.erased.clone()),
+      Arc::new(self.erased.clone()),
       Swizzle::D3(x, y, z),
     ))
   }
@@ -3690,7 +6162,7 @@ impl<T> Swizzlable<SwizzleSelector> for Expr<V4<T>> {
 
   fn swizzle(&self, x: SwizzleSelector) -> Self::Output {
     Expr::new(ErasedExpr::Swizzle(
-      Box::new(self.erased.clone()),
+      Arc::new(self.erased.clone()),
       Swizzle::D1(x),
     ))
   }
@@ -3701,7 +6173,7 @@ impl<T> Swizzlable<[SwizzleSelector; 2]> for Expr<V4<T>> {
 
   fn swizzle(&self, [x, y]: [SwizzleSelector; 2]) -> Self::Output {
     Expr::new(ErasedExpr::Swizzle(
-      Box::new(self.erased.clone()),
+      Arc::new(self.erased.clone()),
       Swizzle::D2(x, y),
     ))
   }
@@ -3712,7 +6184,7 @@ impl<T> Swizzlable<[SwizzleSelector; 3]> for Expr<V4<T>> {
 
   fn swizzle(&self, [x, y, z]: [SwizzleSelector; 3]) -> Self::Output {
     Expr::new(ErasedExpr::Swizzle(
-      Box::new(self.erased.clone()),
+      Arc::new(self.erased.clone()),
       Swizzle::D3(x, y, z),
     ))
   }
@@ -3723,7 +6195,7 @@ impl<T> Swizzlable<[SwizzleSelector; 4]> for Expr<V4<T>> {
 
   fn swizzle(&self, [x, y, z, w]: [SwizzleSelector; 4]) -> Self::Output {
     Expr::new(ErasedExpr::Swizzle(
-      Box::new(self.erased.clone()),
+      Arc::new(self.erased.clone()),
       Swizzle::D4(x, y, z, w),
     ))
   }
@@ -3876,6 +6348,12 @@ macro_rules! sw_extract {
 
 /// Input declaration.
 ///
+/// Each input is bound to an [`Expr<T>`], so it can be read but not written to.
+///
+/// # Panics
+///
+/// Panic if an input cannot be declared. See [`ShaderBuilder::input`] for further details.
+///
 /// # Examples
 ///
 /// ```
@@ -3892,17 +6370,37 @@ macro_rules! sw_extract {
 ///   })
 /// });
 /// ```
+///
+/// Inputs cannot be written to:
+///
+/// ```compile_fail
+/// use shades::{Scope, ShaderBuilder, V3, inputs, lit};
+///
+/// ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+///   inputs!(s, position: V3<f32>);
+///
+///   s.main_fun(|s: &mut Scope<()>| {
+///     s.set(&position, lit!(0., 0., 0.));
+///   })
+/// });
+/// ```
 #[macro_export]
 macro_rules! inputs {
   ($s:ident, $( $name:ident : $t:ty ),+) => {
     $(
-      let $name = unsafe { $s.input::<$t>(stringify!($name)) };
+      let $name = $s.input::<$t>(stringify!($name)).unwrap_or_else(|e| panic!("{}", e));
     )+
   }
 }
 
 /// Output declaration.
 ///
+/// Each output is bound to an [`Output<T>`], so it can be written to but not read.
+///
+/// # Panics
+///
+/// Panic if an output cannot be declared. See [`ShaderBuilder::output`] for further details.
+///
 /// # Examples
 ///
 /// ```
@@ -3922,13 +6420,58 @@ macro_rules! inputs {
 macro_rules! outputs {
   ($s:ident, $( $name:ident : $t:ty ),+) => {
     $(
-      let $name = unsafe { $s.output::<$t>(stringify!($name)) };
+      let $name = $s.output::<$t>(stringify!($name)).unwrap_or_else(|e| panic!("{}", e));
+    )+
+  }
+}
+
+/// Colour attachment declaration.
+///
+/// Each output is declared with its type and the [`ColorAttachment`] it writes to. The attachment can be given as a
+/// single location or as a `(location, blend_index)` pair, for dual-source blending.
+///
+/// # Panics
+///
+/// Panic if an output cannot be declared. See [`ShaderBuilder::color_attachment`] for further details.
+///
+/// # Examples
+///
+/// ```
+/// use shades::{Scope, ShaderBuilder, V4, color_attachments, lit};
+///
+/// ShaderBuilder::new_fragment_shader(|mut s, fragment| {
+///   color_attachments!(s,
+///     albedo: V4<f32> = 0,
+///     normal: V4<f32> = 1,
+///     blend_factor: V4<f32> = (0, 1)
+///   );
+///
+///   s.main_fun(|s: &mut Scope<()>| {
+///     s.set(&albedo, lit!(1., 0., 0., 1.));
+///     s.set(&normal, lit!(0., 0., 1., 0.));
+///     s.set(&blend_factor, lit!(0.5, 0.5, 0.5, 0.5));
+///   })
+/// });
+/// ```
+#[macro_export]
+macro_rules! color_attachments {
+  ($s:ident, $( $name:ident : $t:ty = $attachment:expr ),+) => {
+    $(
+      let $name = $s
+        .color_attachment::<$t>(stringify!($name), $crate::ColorAttachment::from($attachment))
+        .unwrap_or_else(|e| panic!("{}", e));
     )+
   }
 }
 
 /// Uniform declaration.
 ///
+/// Each uniform is bound to an [`Expr<T>`], so it can be read but not written to.
+///
+/// # Panics
+///
+/// Panic if a uniform cannot be declared. See [`ShaderBuilder::uniform`] for further details.
+///
 /// # Examples
 ///
 /// ```
@@ -3944,97 +6487,196 @@ macro_rules! outputs {
 ///   })
 /// });
 /// ```
+///
+/// Uniforms cannot be written to:
+///
+/// ```compile_fail
+/// use shades::{Scope, ShaderBuilder, uniforms};
+///
+/// ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+///   uniforms!(s, time: f32);
+///
+///   s.main_fun(|s: &mut Scope<()>| {
+///     s.set(&time, 0.);
+///   })
+/// });
+/// ```
 #[macro_export]
 macro_rules! uniforms {
   ($s:ident, $( $name:ident : $t:ty ),+) => {
     $(
-      let $name = unsafe { $s.uniform::<$t>(stringify!($name)) };
+      let $name = $s.uniform::<$t>(stringify!($name)).unwrap_or_else(|e| panic!("{}", e));
     )+
   }
 }
 
+/// Built-in variables, per shader stage.
+///
+/// Built-ins are accessed through the shader stage environments, such as [`VertexShaderEnv`]. This type is used to
+/// report which built-ins a [`Shader`] uses; see [`Shader::builtins_read`] and [`Shader::builtins_written`].
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
-enum BuiltIn {
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BuiltIn {
+  /// Vertex shader built-in.
   Vertex(VertexBuiltIn),
+
+  /// Tessellation control shader built-in.
   TessCtrl(TessCtrlBuiltIn),
+
+  /// Tessellation evaluation shader built-in.
   TessEval(TessEvalBuiltIn),
+
+  /// Geometry shader built-in.
   Geometry(GeometryBuiltIn),
+
+  /// Fragment shader built-in.
   Fragment(FragmentBuiltIn),
 }
 
+/// Vertex shader built-ins. See [`VertexShaderEnv`] for their meaning.
+#[non_exhaustive]
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
-enum VertexBuiltIn {
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum VertexBuiltIn {
+  /// [`VertexShaderEnv::vertex_id`].
   VertexID,
+  /// [`VertexShaderEnv::instance_id`].
   InstanceID,
+  /// [`VertexShaderEnv::base_vertex`].
   BaseVertex,
+  /// [`VertexShaderEnv::base_instance`].
   BaseInstance,
+  /// [`VertexShaderEnv::position`].
   Position,
+  /// [`VertexShaderEnv::point_size`].
   PointSize,
+  /// [`VertexShaderEnv::clip_distance`].
   ClipDistance,
 }
 
+/// Tessellation control shader built-ins. See [`TessCtrlShaderEnv`] for their meaning.
+#[non_exhaustive]
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
-enum TessCtrlBuiltIn {
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TessCtrlBuiltIn {
+  /// [`TessCtrlShaderEnv::max_patch_vertices_in`].
   MaxPatchVerticesIn,
+  /// [`TessCtrlShaderEnv::patch_vertices_in`].
   PatchVerticesIn,
+  /// [`TessCtrlShaderEnv::primitive_id`].
   PrimitiveID,
+  /// [`TessCtrlShaderEnv::invocation_id`].
   InvocationID,
+  /// [`TessCtrlShaderEnv::tess_level_outer`].
   TessellationLevelOuter,
+  /// [`TessCtrlShaderEnv::tess_level_inner`].
   TessellationLevelInner,
+  /// [`TessCtrlShaderEnv::input`].
   In,
+  /// [`TessCtrlShaderEnv::output`].
   Out,
+  /// Position of a vertex in [`TessCtrlBuiltIn::In`] or [`TessCtrlBuiltIn::Out`].
   Position,
+  /// Point size of a vertex in [`TessCtrlBuiltIn::In`] or [`TessCtrlBuiltIn::Out`].
   PointSize,
+  /// Clip distances of a vertex in [`TessCtrlBuiltIn::In`] or [`TessCtrlBuiltIn::Out`].
   ClipDistance,
+  /// Cull distances of a vertex in [`TessCtrlBuiltIn::In`] or [`TessCtrlBuiltIn::Out`].
   CullDistance,
 }
 
+/// Tessellation evaluation shader built-ins. See [`TessEvalShaderEnv`] for their meaning.
+#[non_exhaustive]
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
-enum TessEvalBuiltIn {
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TessEvalBuiltIn {
+  /// [`TessEvalShaderEnv::tess_coord`].
   TessCoord,
+  /// Maximum number of vertices per patch.
   MaxPatchVerticesIn,
+  /// [`TessEvalShaderEnv::patch_vertices_in`].
   PatchVerticesIn,
+  /// [`TessEvalShaderEnv::primitive_id`].
   PrimitiveID,
+  /// [`TessEvalShaderEnv::tess_level_outer`].
   TessellationLevelOuter,
+  /// [`TessEvalShaderEnv::tess_level_inner`].
   TessellationLevelInner,
+  /// [`TessEvalShaderEnv::input`].
   In,
+  /// Output vertex.
   Out,
+  /// Position of a vertex.
   Position,
+  /// Point size of a vertex.
   PointSize,
+  /// Clip distances of a vertex.
   ClipDistance,
+  /// Cull distances of a vertex.
   CullDistance,
 }
 
+/// Geometry shader built-ins. See [`GeometryShaderEnv`] for their meaning.
+#[non_exhaustive]
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
-enum GeometryBuiltIn {
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum GeometryBuiltIn {
+  /// [`GeometryShaderEnv::input`].
   In,
+  /// Output vertex.
   Out,
+  /// Position of a vertex.
   Position,
+  /// Point size of a vertex.
   PointSize,
+  /// Clip distances of a vertex.
   ClipDistance,
+  /// Cull distances of a vertex.
   CullDistance,
+  /// [`GeometryShaderEnv::primitive_id`].
   PrimitiveID,
+  /// [`GeometryShaderEnv::primitive_id_in`].
   PrimitiveIDIn,
+  /// [`GeometryShaderEnv::invocation_id`].
   InvocationID,
+  /// [`GeometryShaderEnv::layer`].
   Layer,
+  /// [`GeometryShaderEnv::viewport_index`].
   ViewportIndex,
 }
 
+/// Fragment shader built-ins. See [`FragmentShaderEnv`] for their meaning.
+#[non_exhaustive]
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
-enum FragmentBuiltIn {
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FragmentBuiltIn {
+  /// [`FragmentShaderEnv::frag_coord`].
   FragCoord,
+  /// [`FragmentShaderEnv::front_facing`].
   FrontFacing,
+  /// [`FragmentShaderEnv::point_coord`].
   PointCoord,
+  /// [`FragmentShaderEnv::sample_id`].
   SampleID,
+  /// [`FragmentShaderEnv::sample_position`].
   SamplePosition,
+  /// [`FragmentShaderEnv::sample_mask_in`].
   SampleMaskIn,
+  /// [`FragmentShaderEnv::clip_distance`].
   ClipDistance,
+  /// [`FragmentShaderEnv::cull_distance`].
   CullDistance,
+  /// [`FragmentShaderEnv::primitive_id`].
   PrimitiveID,
+  /// [`FragmentShaderEnv::layer`].
   Layer,
+  /// [`FragmentShaderEnv::viewport_index`].
   ViewportIndex,
+  /// [`FragmentShaderEnv::frag_depth`].
   FragDepth,
+  /// [`FragmentShaderEnv::sample_mask`].
   SampleMask,
+  /// [`FragmentShaderEnv::helper_invocation`].
   HelperInvocation,
 }
 
@@ -4176,11 +6818,11 @@ impl TessCtrlShaderEnv {
 pub struct TessControlPerVertexIn;
 
 impl Expr<TessControlPerVertexIn> {
-  ///
+  /// 4D position of the vertex.
   pub fn position(&self) -> Expr<V4<f32>> {
     let erased = ErasedExpr::Field {
-      object: Box::new(self.erased.clone()),
-      field: Box::new(ErasedExpr::new_builtin(BuiltIn::TessCtrl(
+      object: Arc::new(self.erased.clone()),
+      field: Arc::new(ErasedExpr::new_builtin(BuiltIn::TessCtrl(
         TessCtrlBuiltIn::Position,
       ))),
     };
@@ -4190,8 +6832,8 @@ impl Expr<TessControlPerVertexIn> {
 
   pub fn point_size(&self) -> Expr<f32> {
     let erased = ErasedExpr::Field {
-      object: Box::new(self.erased.clone()),
-      field: Box::new(ErasedExpr::new_builtin(BuiltIn::TessCtrl(
+      object: Arc::new(self.erased.clone()),
+      field: Arc::new(ErasedExpr::new_builtin(BuiltIn::TessCtrl(
         TessCtrlBuiltIn::PointSize,
       ))),
     };
@@ -4201,8 +6843,8 @@ impl Expr<TessControlPerVertexIn> {
 
   pub fn clip_distance(&self) -> Expr<[f32]> {
     let erased = ErasedExpr::Field {
-      object: Box::new(self.erased.clone()),
-      field: Box::new(ErasedExpr::new_builtin(BuiltIn::TessCtrl(
+      object: Arc::new(self.erased.clone()),
+      field: Arc::new(ErasedExpr::new_builtin(BuiltIn::TessCtrl(
         TessCtrlBuiltIn::ClipDistance,
       ))),
     };
@@ -4212,8 +6854,8 @@ impl Expr<TessControlPerVertexIn> {
 
   pub fn cull_distance(&self) -> Expr<[f32]> {
     let erased = ErasedExpr::Field {
-      object: Box::new(self.erased.clone()),
-      field: Box::new(ErasedExpr::new_builtin(BuiltIn::TessCtrl(
+      object: Arc::new(self.erased.clone()),
+      field: Arc::new(ErasedExpr::new_builtin(BuiltIn::TessCtrl(
         TessCtrlBuiltIn::CullDistance,
       ))),
     };
@@ -4230,8 +6872,8 @@ impl Expr<TessControlPerVertexOut> {
   /// 4D position of the verte.
   pub fn position(&self) -> Var<V4<f32>> {
     let expr = ErasedExpr::Field {
-      object: Box::new(self.erased.clone()),
-      field: Box::new(ErasedExpr::new_builtin(BuiltIn::TessCtrl(
+      object: Arc::new(self.erased.clone()),
+      field: Arc::new(ErasedExpr::new_builtin(BuiltIn::TessCtrl(
         TessCtrlBuiltIn::Position,
       ))),
     };
@@ -4242,8 +6884,8 @@ impl Expr<TessControlPerVertexOut> {
   /// Point size of the vertex.
   pub fn point_size(&self) -> Var<f32> {
     let expr = ErasedExpr::Field {
-      object: Box::new(self.erased.clone()),
-      field: Box::new(ErasedExpr::new_builtin(BuiltIn::TessCtrl(
+      object: Arc::new(self.erased.clone()),
+      field: Arc::new(ErasedExpr::new_builtin(BuiltIn::TessCtrl(
         TessCtrlBuiltIn::PointSize,
       ))),
     };
@@ -4254,8 +6896,8 @@ impl Expr<TessControlPerVertexOut> {
   /// Clip distances to user-defined planes.
   pub fn clip_distance(&self) -> Var<[f32]> {
     let expr = ErasedExpr::Field {
-      object: Box::new(self.erased.clone()),
-      field: Box::new(ErasedExpr::new_builtin(BuiltIn::TessCtrl(
+      object: Arc::new(self.erased.clone()),
+      field: Arc::new(ErasedExpr::new_builtin(BuiltIn::TessCtrl(
         TessCtrlBuiltIn::ClipDistance,
       ))),
     };
@@ -4266,8 +6908,8 @@ impl Expr<TessControlPerVertexOut> {
   /// Cull distances to user-defined planes.
   pub fn cull_distance(&self) -> Var<[f32]> {
     let expr = ErasedExpr::Field {
-      object: Box::new(self.erased.clone()),
-      field: Box::new(ErasedExpr::new_builtin(BuiltIn::TessCtrl(
+      object: Arc::new(self.erased.clone()),
+      field: Arc::new(ErasedExpr::new_builtin(BuiltIn::TessCtrl(
         TessCtrlBuiltIn::CullDistance,
       ))),
     };
@@ -4369,8 +7011,8 @@ impl Expr<TessEvaluationPerVertexIn> {
   /// 4D position of the vertex.
   pub fn position(&self) -> Expr<V4<f32>> {
     let erased = ErasedExpr::Field {
-      object: Box::new(self.erased.clone()),
-      field: Box::new(ErasedExpr::new_builtin(BuiltIn::TessEval(
+      object: Arc::new(self.erased.clone()),
+      field: Arc::new(ErasedExpr::new_builtin(BuiltIn::TessEval(
         TessEvalBuiltIn::Position,
       ))),
     };
@@ -4381,8 +7023,8 @@ impl Expr<TessEvaluationPerVertexIn> {
   /// Point size of the vertex.
   pub fn point_size(&self) -> Expr<f32> {
     let erased = ErasedExpr::Field {
-      object: Box::new(self.erased.clone()),
-      field: Box::new(ErasedExpr::new_builtin(BuiltIn::TessEval(
+      object: Arc::new(self.erased.clone()),
+      field: Arc::new(ErasedExpr::new_builtin(BuiltIn::TessEval(
         TessEvalBuiltIn::PointSize,
       ))),
     };
@@ -4393,8 +7035,8 @@ impl Expr<TessEvaluationPerVertexIn> {
   /// Clip distances to user-defined planes.
   pub fn clip_distance(&self) -> Expr<[f32]> {
     let erased = ErasedExpr::Field {
-      object: Box::new(self.erased.clone()),
-      field: Box::new(ErasedExpr::new_builtin(BuiltIn::TessEval(
+      object: Arc::new(self.erased.clone()),
+      field: Arc::new(ErasedExpr::new_builtin(BuiltIn::TessEval(
         TessEvalBuiltIn::ClipDistance,
       ))),
     };
@@ -4405,8 +7047,8 @@ impl Expr<TessEvaluationPerVertexIn> {
   /// Cull distances to user-defined planes.
   pub fn cull_distance(&self) -> Expr<[f32]> {
     let erased = ErasedExpr::Field {
-      object: Box::new(self.erased.clone()),
-      field: Box::new(ErasedExpr::new_builtin(BuiltIn::TessEval(
+      object: Arc::new(self.erased.clone()),
+      field: Arc::new(ErasedExpr::new_builtin(BuiltIn::TessEval(
         TessEvalBuiltIn::CullDistance,
       ))),
     };
@@ -4508,8 +7150,8 @@ impl Expr<GeometryPerVertexIn> {
   /// Provides 4D the position of the vertex.
   pub fn position(&self) -> Expr<V4<f32>> {
     let erased = ErasedExpr::Field {
-      object: Box::new(self.erased.clone()),
-      field: Box::new(ErasedExpr::new_builtin(BuiltIn::Geometry(
+      object: Arc::new(self.erased.clone()),
+      field: Arc::new(ErasedExpr::new_builtin(BuiltIn::Geometry(
         GeometryBuiltIn::Position,
       ))),
     };
@@ -4520,8 +7162,8 @@ impl Expr<GeometryPerVertexIn> {
   /// Provides the size point of the vertex if it’s currently being rendered in point mode.
   pub fn point_size(&self) -> Expr<f32> {
     let erased = ErasedExpr::Field {
-      object: Box::new(self.erased.clone()),
-      field: Box::new(ErasedExpr::new_builtin(BuiltIn::Geometry(
+      object: Arc::new(self.erased.clone()),
+      field: Arc::new(ErasedExpr::new_builtin(BuiltIn::Geometry(
         GeometryBuiltIn::PointSize,
       ))),
     };
@@ -4532,8 +7174,8 @@ impl Expr<GeometryPerVertexIn> {
   /// Clip distances to user planes of the vertex.
   pub fn clip_distance(&self) -> Expr<[f32]> {
     let erased = ErasedExpr::Field {
-      object: Box::new(self.erased.clone()),
-      field: Box::new(ErasedExpr::new_builtin(BuiltIn::Geometry(
+      object: Arc::new(self.erased.clone()),
+      field: Arc::new(ErasedExpr::new_builtin(BuiltIn::Geometry(
         GeometryBuiltIn::ClipDistance,
       ))),
     };
@@ -4544,8 +7186,8 @@ impl Expr<GeometryPerVertexIn> {
   /// Cull distances to user planes of the vertex.
   pub fn cull_distance(&self) -> Expr<[f32]> {
     let erased = ErasedExpr::Field {
-      object: Box::new(self.erased.clone()),
-      field: Box::new(ErasedExpr::new_builtin(BuiltIn::Geometry(
+      object: Arc::new(self.erased.clone()),
+      field: Arc::new(ErasedExpr::new_builtin(BuiltIn::Geometry(
         GeometryBuiltIn::CullDistance,
       ))),
     };
@@ -4671,6 +7313,35 @@ impl FragmentShaderEnv {
       sample_mask,
     }
   }
+
+  /// Discard the current fragment.
+  ///
+  /// The fragment is not written to any attachment and the invocation stops. This is only available in fragment
+  /// shaders, and can be used in any scope, including functions.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use shades::{CanEscape as _, HasW as _, Scope, ShaderBuilder, V4, color_attachments, uniforms};
+  ///
+  /// ShaderBuilder::new_fragment_shader(|mut s, fragment| {
+  ///   uniforms!(s, diffuse: V4<f32>);
+  ///   color_attachments!(s, color: V4<f32> = 0);
+  ///
+  ///   s.main_fun(|s: &mut Scope<()>| {
+  ///     // alpha-testing
+  ///     s.when(diffuse.w().lt(0.5), |s| {
+  ///       fragment.discard(s);
+  ///     });
+  ///
+  ///     s.set(&color, &diffuse);
+  ///   })
+  /// });
+  /// ```
+  #[track_caller]
+  pub fn discard<R>(&self, scope: &mut Scope<R>) {
+    scope.erased.push(ScopeInstr::Discard);
+  }
 }
 
 // standard library
@@ -4711,98 +7382,98 @@ macro_rules! impl_Trigonometry {
       fn radians(&self) -> Self {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::Radians,
-          vec![self.erased.clone()],
+          vec![Arc::new(self.erased.clone())],
         ))
       }
 
       fn degrees(&self) -> Self {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::Degrees,
-          vec![self.erased.clone()],
+          vec![Arc::new(self.erased.clone())],
         ))
       }
 
       fn sin(&self) -> Self {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::Sin,
-          vec![self.erased.clone()],
+          vec![Arc::new(self.erased.clone())],
         ))
       }
 
       fn cos(&self) -> Self {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::Cos,
-          vec![self.erased.clone()],
+          vec![Arc::new(self.erased.clone())],
         ))
       }
 
       fn tan(&self) -> Self {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::Tan,
-          vec![self.erased.clone()],
+          vec![Arc::new(self.erased.clone())],
         ))
       }
 
       fn asin(&self) -> Self {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::ASin,
-          vec![self.erased.clone()],
+          vec![Arc::new(self.erased.clone())],
         ))
       }
 
       fn acos(&self) -> Self {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::ACos,
-          vec![self.erased.clone()],
+          vec![Arc::new(self.erased.clone())],
         ))
       }
 
       fn atan(&self) -> Self {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::ATan,
-          vec![self.erased.clone()],
+          vec![Arc::new(self.erased.clone())],
         ))
       }
 
       fn sinh(&self) -> Self {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::SinH,
-          vec![self.erased.clone()],
+          vec![Arc::new(self.erased.clone())],
         ))
       }
 
       fn cosh(&self) -> Self {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::CosH,
-          vec![self.erased.clone()],
+          vec![Arc::new(self.erased.clone())],
         ))
       }
 
       fn tanh(&self) -> Self {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::TanH,
-          vec![self.erased.clone()],
+          vec![Arc::new(self.erased.clone())],
         ))
       }
 
       fn asinh(&self) -> Self {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::ASinH,
-          vec![self.erased.clone()],
+          vec![Arc::new(self.erased.clone())],
         ))
       }
 
       fn acosh(&self) -> Self {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::ACosH,
-          vec![self.erased.clone()],
+          vec![Arc::new(self.erased.clone())],
         ))
       }
 
       fn atanh(&self) -> Self {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::ATanH,
-          vec![self.erased.clone()],
+          vec![Arc::new(self.erased.clone())],
         ))
       }
     }
@@ -4836,49 +7507,49 @@ macro_rules! impl_Exponential {
       fn pow(&self, p: impl Into<Self>) -> Self {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::Pow,
-          vec![self.erased.clone(), p.into().erased],
+          vec![Arc::new(self.erased.clone()), Arc::new(p.into().erased)],
         ))
       }
 
       fn exp(&self) -> Self {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::Exp,
-          vec![self.erased.clone()],
+          vec![Arc::new(self.erased.clone())],
         ))
       }
 
       fn exp2(&self) -> Self {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::Exp2,
-          vec![self.erased.clone()],
+          vec![Arc::new(self.erased.clone())],
         ))
       }
 
       fn log(&self) -> Self {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::Log,
-          vec![self.erased.clone()],
+          vec![Arc::new(self.erased.clone())],
         ))
       }
 
       fn log2(&self) -> Self {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::Log2,
-          vec![self.erased.clone()],
+          vec![Arc::new(self.erased.clone())],
         ))
       }
 
       fn sqrt(&self) -> Self {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::Sqrt,
-          vec![self.erased.clone()],
+          vec![Arc::new(self.erased.clone())],
         ))
       }
 
       fn isqrt(&self) -> Self {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::InverseSqrt,
-          vec![self.erased.clone()],
+          vec![Arc::new(self.erased.clone())],
         ))
       }
     }
@@ -4902,14 +7573,14 @@ macro_rules! impl_Relative {
       fn abs(&self) -> Self {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::Abs,
-          vec![self.erased.clone()],
+          vec![Arc::new(self.erased.clone())],
         ))
       }
 
       fn sign(&self) -> Self {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::Sign,
-          vec![self.erased.clone()],
+          vec![Arc::new(self.erased.clone())],
         ))
       }
     }
@@ -4943,35 +7614,35 @@ macro_rules! impl_Floating {
       fn floor(&self) -> Self {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::Floor,
-          vec![self.erased.clone()],
+          vec![Arc::new(self.erased.clone())],
         ))
       }
 
       fn trunc(&self) -> Self {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::Trunc,
-          vec![self.erased.clone()],
+          vec![Arc::new(self.erased.clone())],
         ))
       }
 
       fn round(&self) -> Self {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::Round,
-          vec![self.erased.clone()],
+          vec![Arc::new(self.erased.clone())],
         ))
       }
 
       fn ceil(&self) -> Self {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::Ceil,
-          vec![self.erased.clone()],
+          vec![Arc::new(self.erased.clone())],
         ))
       }
 
       fn fract(&self) -> Self {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::Fract,
-          vec![self.erased.clone()],
+          vec![Arc::new(self.erased.clone())],
         ))
       }
     }
@@ -4997,14 +7668,14 @@ macro_rules! impl_Bounded {
       fn min(&self, rhs: impl Into<Self>) -> Self {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::Min,
-          vec![self.erased.clone(), rhs.into().erased],
+          vec![Arc::new(self.erased.clone()), Arc::new(rhs.into().erased)],
         ))
       }
 
       fn max(&self, rhs: impl Into<Self>) -> Self {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::Max,
-          vec![self.erased.clone(), rhs.into().erased],
+          vec![Arc::new(self.erased.clone()), Arc::new(rhs.into().erased)],
         ))
       }
 
@@ -5012,9 +7683,9 @@ macro_rules! impl_Bounded {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::Clamp,
           vec![
-            self.erased.clone(),
-            min_value.into().erased,
-            max_value.into().erased,
+            Arc::new(self.erased.clone()),
+            Arc::new(min_value.into().erased),
+            Arc::new(max_value.into().erased),
           ],
         ))
       }
@@ -5056,21 +7727,29 @@ macro_rules! impl_Mix {
       fn mix(&self, y: impl Into<Self>, a: Expr<$q>) -> Self {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::Mix,
-          vec![self.erased.clone(), y.into().erased, a.erased],
+          vec![
+            Arc::new(self.erased.clone()),
+            Arc::new(y.into().erased),
+            Arc::new(a.erased),
+          ],
         ))
       }
 
       fn step(&self, edge: Expr<$q>) -> Self {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::Step,
-          vec![self.erased.clone(), edge.erased],
+          vec![Arc::new(self.erased.clone()), Arc::new(edge.erased)],
         ))
       }
 
       fn smooth_step(&self, edge_a: Expr<$q>, edge_b: Expr<$q>) -> Self {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::SmoothStep,
-          vec![self.erased.clone(), edge_a.erased, edge_b.erased],
+          vec![
+            Arc::new(self.erased.clone()),
+            Arc::new(edge_a.erased),
+            Arc::new(edge_b.erased),
+          ],
         ))
       }
     }
@@ -5103,14 +7782,14 @@ macro_rules! impl_FloatingExt {
       fn is_nan(&self) -> Self::BoolExpr {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::IsNan,
-          vec![self.erased.clone()],
+          vec![Arc::new(self.erased.clone())],
         ))
       }
 
       fn is_inf(&self) -> Self::BoolExpr {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::IsInf,
-          vec![self.erased.clone()],
+          vec![Arc::new(self.erased.clone())],
         ))
       }
     }
@@ -5150,35 +7829,35 @@ macro_rules! impl_Geometry {
       fn length(&self) -> Self::LengthExpr {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::Length,
-          vec![self.erased.clone()],
+          vec![Arc::new(self.erased.clone())],
         ))
       }
 
       fn distance(&self, other: impl Into<Self>) -> Self::LengthExpr {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::Distance,
-          vec![self.erased.clone(), other.into().erased],
+          vec![Arc::new(self.erased.clone()), Arc::new(other.into().erased)],
         ))
       }
 
       fn dot(&self, other: impl Into<Self>) -> Self::LengthExpr {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::Dot,
-          vec![self.erased.clone(), other.into().erased],
+          vec![Arc::new(self.erased.clone()), Arc::new(other.into().erased)],
         ))
       }
 
       fn cross(&self, other: impl Into<Self>) -> Self {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::Cross,
-          vec![self.erased.clone(), other.into().erased],
+          vec![Arc::new(self.erased.clone()), Arc::new(other.into().erased)],
         ))
       }
 
       fn normalize(&self) -> Self {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::Normalize,
-          vec![self.erased.clone()],
+          vec![Arc::new(self.erased.clone())],
         ))
       }
 
@@ -5187,9 +7866,9 @@ macro_rules! impl_Geometry {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::FaceForward,
           vec![
-            normal.into().erased,
-            self.erased.clone(),
-            reference.into().erased,
+            Arc::new(normal.into().erased),
+            Arc::new(self.erased.clone()),
+            Arc::new(reference.into().erased),
           ],
         ))
       }
@@ -5197,14 +7876,21 @@ macro_rules! impl_Geometry {
       fn reflect(&self, normal: impl Into<Self>) -> Self {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::Reflect,
-          vec![self.erased.clone(), normal.into().erased],
+          vec![
+            Arc::new(self.erased.clone()),
+            Arc::new(normal.into().erased),
+          ],
         ))
       }
 
       fn refract(&self, normal: impl Into<Self>, eta: impl Into<Expr<f32>>) -> Self {
         Expr::new(ErasedExpr::FunCall(
           ErasedFunHandle::Refract,
-          vec![self.erased.clone(), normal.into().erased, eta.into().erased],
+          vec![
+            Arc::new(self.erased.clone()),
+            Arc::new(normal.into().erased),
+            Arc::new(eta.into().erased),
+          ],
         ))
       }
     }
@@ -5235,9 +7921,9 @@ mod tests {
 
     assert_eq!(
       a.erased,
-      ErasedExpr::Not(Box::new(ErasedExpr::LitBool(true)))
+      ErasedExpr::Not(Arc::new(ErasedExpr::LitBool(true)))
     );
-    assert_eq!(b.erased, ErasedExpr::Neg(Box::new(ErasedExpr::LitInt(3))));
+    assert_eq!(b.erased, ErasedExpr::Neg(Arc::new(ErasedExpr::LitInt(3))));
     assert_eq!(c.erased, ErasedExpr::Var(ScopedHandle::fun_var(0, 0)));
   }
 
@@ -5250,15 +7936,15 @@ mod tests {
     assert_eq!(
       a.erased,
       ErasedExpr::Add(
-        Box::new(ErasedExpr::LitInt(1)),
-        Box::new(ErasedExpr::LitInt(2)),
+        Arc::new(ErasedExpr::LitInt(1)),
+        Arc::new(ErasedExpr::LitInt(2)),
       )
     );
     assert_eq!(
       b.erased,
       ErasedExpr::Add(
-        Box::new(ErasedExpr::LitInt(1)),
-        Box::new(ErasedExpr::LitInt(2)),
+        Arc::new(ErasedExpr::LitInt(1)),
+        Arc::new(ErasedExpr::LitInt(2)),
       )
     );
 
@@ -5269,15 +7955,15 @@ mod tests {
     assert_eq!(
       a.erased,
       ErasedExpr::Sub(
-        Box::new(ErasedExpr::LitInt(1)),
-        Box::new(ErasedExpr::LitInt(2)),
+        Arc::new(ErasedExpr::LitInt(1)),
+        Arc::new(ErasedExpr::LitInt(2)),
       )
     );
     assert_eq!(
       b.erased,
       ErasedExpr::Sub(
-        Box::new(ErasedExpr::LitInt(1)),
-        Box::new(ErasedExpr::LitInt(2)),
+        Arc::new(ErasedExpr::LitInt(1)),
+        Arc::new(ErasedExpr::LitInt(2)),
       )
     );
 
@@ -5288,15 +7974,15 @@ mod tests {
     assert_eq!(
       a.erased,
       ErasedExpr::Mul(
-        Box::new(ErasedExpr::LitInt(1)),
-        Box::new(ErasedExpr::LitInt(2)),
+        Arc::new(ErasedExpr::LitInt(1)),
+        Arc::new(ErasedExpr::LitInt(2)),
       )
     );
     assert_eq!(
       b.erased,
       ErasedExpr::Mul(
-        Box::new(ErasedExpr::LitInt(1)),
-        Box::new(ErasedExpr::LitInt(2)),
+        Arc::new(ErasedExpr::LitInt(1)),
+        Arc::new(ErasedExpr::LitInt(2)),
       )
     );
 
@@ -5307,15 +7993,15 @@ mod tests {
     assert_eq!(
       a.erased,
       ErasedExpr::Div(
-        Box::new(ErasedExpr::LitInt(1)),
-        Box::new(ErasedExpr::LitInt(2)),
+        Arc::new(ErasedExpr::LitInt(1)),
+        Arc::new(ErasedExpr::LitInt(2)),
       )
     );
     assert_eq!(
       b.erased,
       ErasedExpr::Div(
-        Box::new(ErasedExpr::LitInt(1)),
-        Box::new(ErasedExpr::LitInt(2)),
+        Arc::new(ErasedExpr::LitInt(1)),
+        Arc::new(ErasedExpr::LitInt(2)),
       )
     );
   }
@@ -5330,6 +8016,25 @@ mod tests {
   }
 
   #[test]
+  fn expr_sharing() {
+    let a = lit!(1i32) + 2;
+    let b = a.clone() * a;
+
+    // both operands share the subexpressions of a
+    match b.erased {
+      ErasedExpr::Mul(x, y) => match (&*x, &*y) {
+        (ErasedExpr::Add(x1, x2), ErasedExpr::Add(y1, y2)) => {
+          assert!(Arc::ptr_eq(x1, y1));
+          assert!(Arc::ptr_eq(x2, y2));
+        }
+        _ => panic!("additions expected"),
+      },
+      _ => panic!("multiplication expected"),
+    }
+  }
+
+  #[test]
+  #[allow(clippy::useless_conversion)]
   fn expr_var() {
     let mut scope = Scope::<()>::new(0);
 
@@ -5389,7 +8094,10 @@ mod tests {
       a.min(&b).erased,
       ErasedExpr::FunCall(
         ErasedFunHandle::Min,
-        vec![ErasedExpr::LitInt(1), ErasedExpr::LitInt(2)],
+        vec![
+          Arc::new(ErasedExpr::LitInt(1)),
+          Arc::new(ErasedExpr::LitInt(2))
+        ],
       )
     );
 
@@ -5397,7 +8105,10 @@ mod tests {
       a.max(&b).erased,
       ErasedExpr::FunCall(
         ErasedFunHandle::Max,
-        vec![ErasedExpr::LitInt(1), ErasedExpr::LitInt(2)],
+        vec![
+          Arc::new(ErasedExpr::LitInt(1)),
+          Arc::new(ErasedExpr::LitInt(2))
+        ],
       )
     );
 
@@ -5406,9 +8117,9 @@ mod tests {
       ErasedExpr::FunCall(
         ErasedFunHandle::Clamp,
         vec![
-          ErasedExpr::LitInt(1),
-          ErasedExpr::LitInt(2),
-          ErasedExpr::LitInt(3)
+          Arc::new(ErasedExpr::LitInt(1)),
+          Arc::new(ErasedExpr::LitInt(2)),
+          Arc::new(ErasedExpr::LitInt(3))
         ],
       )
     );
@@ -5416,7 +8127,7 @@ mod tests {
 
   #[test]
   fn fun0() {
-    let mut shader = ShaderBuilder::new();
+    let mut shader = ShaderBuilder::new(ShaderStage::Vertex);
     let fun = shader.fun(|s: &mut Scope<()>| {
       let _x = s.var(3);
     });
@@ -5446,7 +8157,7 @@ mod tests {
 
   #[test]
   fn fun1() {
-    let mut shader = ShaderBuilder::new();
+    let mut shader = ShaderBuilder::new(ShaderStage::Vertex);
     let fun = shader.fun(|f: &mut Scope<Expr<i32>>, _arg: Expr<i32>| {
       let x = f.var(lit!(3i32));
       x.into()
@@ -5494,7 +8205,7 @@ mod tests {
     assert_eq!(
       foo_xy.erased,
       ErasedExpr::Swizzle(
-        Box::new(ErasedExpr::Var(ScopedHandle::fun_var(0, 0))),
+        Arc::new(ErasedExpr::Var(ScopedHandle::fun_var(0, 0))),
         Swizzle::D2(SwizzleSelector::X, SwizzleSelector::Y),
       )
     );
@@ -5502,7 +8213,7 @@ mod tests {
     assert_eq!(
       foo_xx.erased,
       ErasedExpr::Swizzle(
-        Box::new(ErasedExpr::Var(ScopedHandle::fun_var(0, 0))),
+        Arc::new(ErasedExpr::Var(ScopedHandle::fun_var(0, 0))),
         Swizzle::D2(SwizzleSelector::X, SwizzleSelector::X),
       )
     );
@@ -5556,15 +8267,15 @@ mod tests {
       s.erased.instructions[1],
       ScopeInstr::If {
         condition: ErasedExpr::Eq(
-          Box::new(ErasedExpr::Var(ScopedHandle::fun_var(0, 0))),
-          Box::new(ErasedExpr::LitInt(2)),
+          Arc::new(ErasedExpr::Var(ScopedHandle::fun_var(0, 0))),
+          Arc::new(ErasedExpr::LitInt(2)),
         ),
         scope,
       }
     );
 
-    // else if
-    let mut scope = ErasedScope::new(1);
+    // else if, in a scope of its own
+    let mut scope = ErasedScope::new(2);
     scope
       .instructions
       .push(ScopeInstr::Return(ErasedReturn::Expr(
@@ -5576,8 +8287,8 @@ mod tests {
       s.erased.instructions[2],
       ScopeInstr::ElseIf {
         condition: ErasedExpr::Eq(
-          Box::new(ErasedExpr::Var(ScopedHandle::fun_var(0, 0))),
-          Box::new(ErasedExpr::LitInt(0)),
+          Arc::new(ErasedExpr::Var(ScopedHandle::fun_var(0, 0))),
+          Arc::new(ErasedExpr::LitInt(0)),
         ),
         scope,
       }
@@ -5587,7 +8298,7 @@ mod tests {
     assert_eq!(
       s.erased.instructions[3],
       ScopeInstr::Else {
-        scope: ErasedScope::new(1)
+        scope: ErasedScope::new(3)
       }
     );
   }
@@ -5609,14 +8320,6 @@ mod tests {
 
     let mut loop_scope = ErasedScope::new(1);
     loop_scope.next_var = 1;
-    loop_scope.instructions.push(ScopeInstr::VarDecl {
-      ty: Type {
-        prim_ty: PrimType::Int(Dim::Scalar),
-        array_dims: Vec::new(),
-      },
-      handle: ScopedHandle::fun_var(1, 0),
-      init_value: ErasedExpr::LitInt(0),
-    });
     loop_scope
       .instructions
       .push(ScopeInstr::Return(ErasedReturn::Expr(
@@ -5629,14 +8332,14 @@ mod tests {
       ScopeInstr::For {
         init_ty: i32::ty(),
         init_handle: ScopedHandle::fun_var(1, 0),
-        init_expr: ErasedExpr::Var(ScopedHandle::fun_var(1, 0)),
+        init_expr: ErasedExpr::LitInt(0),
         condition: ErasedExpr::Lt(
-          Box::new(ErasedExpr::Var(ScopedHandle::fun_var(1, 0))),
-          Box::new(ErasedExpr::LitInt(10)),
+          Arc::new(ErasedExpr::Var(ScopedHandle::fun_var(1, 0))),
+          Arc::new(ErasedExpr::LitInt(10)),
         ),
         post_expr: ErasedExpr::Add(
-          Box::new(ErasedExpr::Var(ScopedHandle::fun_var(1, 0))),
-          Box::new(ErasedExpr::LitInt(1)),
+          Arc::new(ErasedExpr::Var(ScopedHandle::fun_var(1, 0))),
+          Arc::new(ErasedExpr::LitInt(1)),
         ),
         scope: loop_scope,
       }
@@ -5657,8 +8360,8 @@ mod tests {
       scope.erased.instructions[0],
       ScopeInstr::While {
         condition: ErasedExpr::Lt(
-          Box::new(ErasedExpr::LitInt(1)),
-          Box::new(ErasedExpr::LitInt(2)),
+          Arc::new(ErasedExpr::LitInt(1)),
+          Arc::new(ErasedExpr::LitInt(2)),
         ),
         scope: loop_scope,
       }
@@ -5682,8 +8385,8 @@ mod tests {
     assert_eq!(
       clip_dist_expr.erased,
       ErasedExpr::ArrayLookup {
-        object: Box::new(vertex.clip_distance.erased.clone()),
-        index: Box::new(ErasedExpr::LitInt(1)),
+        object: Arc::new(vertex.clip_distance.erased.clone()),
+        index: Arc::new(ErasedExpr::LitInt(1)),
       }
     );
   }
@@ -5699,14 +8402,20 @@ mod tests {
       ErasedExpr::Array(
         <[[i32; 2]; 2] as ToType>::ty(),
         vec![
-          ErasedExpr::Array(
+          Arc::new(ErasedExpr::Array(
             <[i32; 2] as ToType>::ty(),
-            vec![ErasedExpr::LitInt(1), ErasedExpr::LitInt(2)]
-          ),
-          ErasedExpr::Array(
+            vec![
+              Arc::new(ErasedExpr::LitInt(1)),
+              Arc::new(ErasedExpr::LitInt(2))
+            ]
+          )),
+          Arc::new(ErasedExpr::Array(
             <[i32; 2] as ToType>::ty(),
-            vec![ErasedExpr::LitInt(3), ErasedExpr::LitInt(4)]
-          )
+            vec![
+              Arc::new(ErasedExpr::LitInt(3)),
+              Arc::new(ErasedExpr::LitInt(4))
+            ]
+          ))
         ]
       )
     );
@@ -5720,14 +8429,21 @@ mod tests {
 
     assert_eq!(
       xyz2.erased,
-      ErasedExpr::FunCall(ErasedFunHandle::Vec3, vec![xy.erased, lit!(3.).erased])
+      ErasedExpr::FunCall(
+        ErasedFunHandle::Vec3,
+        vec![Arc::new(xy.erased), Arc::new(lit!(3.).erased)]
+      )
     );
 
     assert_eq!(
       xyz3.erased,
       ErasedExpr::FunCall(
         ErasedFunHandle::Vec3,
-        vec![lit!(1.).erased, lit!(2.).erased, lit!(3.).erased]
+        vec![
+          Arc::new(lit!(1.).erased),
+          Arc::new(lit!(2.).erased),
+          Arc::new(lit!(3.).erased)
+        ]
       )
     );
   }
@@ -5744,7 +8460,7 @@ mod tests {
       xyzw22.erased,
       ErasedExpr::FunCall(
         ErasedFunHandle::Vec4,
-        vec![xy.clone().erased, xy.clone().erased]
+        vec![Arc::new(xy.clone().erased), Arc::new(xy.clone().erased)]
       )
     );
 
@@ -5752,7 +8468,11 @@ mod tests {
       xyzw211.erased,
       ErasedExpr::FunCall(
         ErasedFunHandle::Vec4,
-        vec![xy.clone().erased, lit!(3.).erased, lit!(4.).erased]
+        vec![
+          Arc::new(xy.clone().erased),
+          Arc::new(lit!(3.).erased),
+          Arc::new(lit!(4.).erased)
+        ]
       )
     );
 
@@ -5760,7 +8480,10 @@ mod tests {
       xyzw31.erased,
       ErasedExpr::FunCall(
         ErasedFunHandle::Vec4,
-        vec![vec3!(1., 2., 3.).erased, lit!(4.).erased]
+        vec![
+          Arc::new(vec3!(1., 2., 3.).erased),
+          Arc::new(lit!(4.).erased)
+        ]
       )
     );
 
@@ -5769,10 +8492,10 @@ mod tests {
       ErasedExpr::FunCall(
         ErasedFunHandle::Vec4,
         vec![
-          lit!(1.).erased,
-          lit!(2.).erased,
-          lit!(3.).erased,
-          lit!(4.).erased
+          Arc::new(lit!(1.).erased),
+          Arc::new(lit!(2.).erased),
+          Arc::new(lit!(3.).erased),
+          Arc::new(lit!(4.).erased)
         ]
       )
     );
@@ -5791,4 +8514,346 @@ mod tests {
     assert_eq!(xyzw.z().erased, z.erased);
     assert_eq!(xyzw.w().erased, w.erased);
   }
+
+  #[test]
+  fn discard() {
+    let fragment = FragmentShaderEnv::new();
+    let mut scope: Scope<()> = Scope::new(0);
+
+    scope.when(lit!(true), |s| fragment.discard(s));
+
+    let mut when_scope = ErasedScope::new(1);
+    when_scope.instructions.push(ScopeInstr::Discard);
+
+    assert_eq!(scope.erased.instructions.len(), 1);
+    assert_eq!(
+      scope.erased.instructions[0],
+      ScopeInstr::If {
+        condition: ErasedExpr::LitBool(true),
+        scope: when_scope,
+      }
+    );
+  }
+
+  #[test]
+  fn interface_names() {
+    let mut s = ShaderBuilder::new(ShaderStage::Vertex);
+
+    assert!(s.input::<f32>("position").is_ok());
+    assert!(s.output::<f32>("color").is_ok());
+    assert!(s.uniform::<f32>("time").is_ok());
+
+    assert_eq!(
+      s.uniform::<f32>("position").unwrap_err(),
+      InterfaceError::DuplicateName("position".to_owned())
+    );
+    assert_eq!(
+      s.input::<f32>("color").unwrap_err(),
+      InterfaceError::DuplicateName("color".to_owned())
+    );
+    assert_eq!(
+      s.output::<f32>("0color").unwrap_err(),
+      InterfaceError::InvalidIdentifier("0color".to_owned())
+    );
+    assert_eq!(
+      s.output::<f32>("").unwrap_err(),
+      InterfaceError::InvalidIdentifier("".to_owned())
+    );
+    assert_eq!(
+      s.input::<f32>("gl_Foo").unwrap_err(),
+      InterfaceError::ReservedIdentifier("gl_Foo".to_owned())
+    );
+    assert_eq!(
+      s.input::<f32>("a__b").unwrap_err(),
+      InterfaceError::ReservedIdentifier("a__b".to_owned())
+    );
+    assert_eq!(
+      s.uniform::<f32>("sampler2D").unwrap_err(),
+      InterfaceError::ReservedIdentifier("sampler2D".to_owned())
+    );
+    assert_eq!(
+      s.input::<f32>("var_0_1").unwrap_err(),
+      InterfaceError::GeneratedIdentifier("var_0_1".to_owned())
+    );
+    assert_eq!(
+      s.input::<f32>("fun_3").unwrap_err(),
+      InterfaceError::GeneratedIdentifier("fun_3".to_owned())
+    );
+    assert!(s.input::<f32>("fun_x").is_ok());
+    assert!(s.input::<f32>("var_0").is_ok());
+  }
+
+  #[test]
+  fn interface_access() {
+    let mut s = ShaderBuilder::new(ShaderStage::Vertex);
+    let position = s.input::<V3<f32>>("position").unwrap();
+    let time = s.uniform::<f32>("time").unwrap();
+    let color = s.output::<V3<f32>>("color").unwrap();
+    let weights = s.output::<[f32; 4]>("weights").unwrap();
+
+    // inputs and uniforms are expressions
+    assert_eq!(
+      position.erased,
+      ErasedExpr::Var(ScopedHandle::Input("position".to_owned()))
+    );
+    assert_eq!(
+      time.erased,
+      ErasedExpr::Var(ScopedHandle::Uniform("time".to_owned()))
+    );
+
+    // outputs can only be written to
+    let mut scope: Scope<()> = Scope::new(0);
+    scope.set(&color, position * time);
+    scope.set(weights.at(1), lit!(0.5));
+
+    assert_eq!(
+      scope.erased.instructions,
+      vec![
+        ScopeInstr::MutateVar {
+          var: ErasedExpr::Var(ScopedHandle::Output("color".to_owned())),
+          expr: ErasedExpr::Mul(
+            Arc::new(ErasedExpr::Var(ScopedHandle::Input("position".to_owned()))),
+            Arc::new(ErasedExpr::Var(ScopedHandle::Uniform("time".to_owned()))),
+          ),
+        },
+        ScopeInstr::MutateVar {
+          var: ErasedExpr::ArrayLookup {
+            object: Arc::new(ErasedExpr::Var(ScopedHandle::Output("weights".to_owned()))),
+            index: Arc::new(ErasedExpr::LitInt(1)),
+          },
+          expr: ErasedExpr::LitFloat(0.5),
+        },
+      ]
+    );
+  }
+
+  #[test]
+  fn color_attachment_collision() {
+    let mut s = ShaderBuilder::new(ShaderStage::Fragment);
+
+    assert!(s
+      .color_attachment::<V4<f32>>("albedo", ColorAttachment::new(0))
+      .is_ok());
+    assert!(s
+      .color_attachment::<V4<f32>>("blend", ColorAttachment::new(0).with_index(1))
+      .is_ok());
+    assert_eq!(
+      s.color_attachment::<V4<f32>>("normal", ColorAttachment::new(0))
+        .unwrap_err(),
+      InterfaceError::DuplicateColorAttachment {
+        name: "normal".to_owned(),
+        attachment: ColorAttachment::new(0)
+      }
+    );
+
+    let mut s = ShaderBuilder::new(ShaderStage::Vertex);
+    assert_eq!(
+      s.color_attachment::<V4<f32>>("albedo", ColorAttachment::new(0))
+        .unwrap_err(),
+      InterfaceError::ColorAttachmentOutsideFragmentShader {
+        name: "albedo".to_owned(),
+        stage: ShaderStage::Vertex,
+      }
+    );
+  }
+
+  #[test]
+  fn program_varyings() {
+    let program = Program::new_vertex_shader(|mut s, _| {
+      let normal = s.output::<V3<f32>>("normal")?;
+      Ok((s.main_fun(|_: &mut Scope<()>| {}), normal))
+    })
+    .unwrap()
+    .tess_ctrl_shader(|mut s, tess, normal| {
+      let out_normal = s.output::<[V3<f32>]>("out_normal")?;
+      let shader = s.main_fun(|s: &mut Scope<()>| {
+        s.set(
+          out_normal.at(&tess.invocation_id),
+          normal.at(&tess.invocation_id),
+        );
+      });
+
+      Ok((shader, out_normal))
+    })
+    .unwrap()
+    .tess_eval_shader(|mut s, _, out_normal| {
+      let normal = s.output::<V3<f32>>("normal")?;
+      let shader = s.main_fun(|s: &mut Scope<()>| {
+        s.set(&normal, out_normal.at(0));
+      });
+
+      Ok((shader, normal))
+    })
+    .unwrap()
+    .fragment_shader(|s, _, _: Expr<V3<f32>>| Ok(s.main_fun(|_: &mut Scope<()>| {})))
+    .unwrap();
+
+    assert!(program.geometry().is_none());
+    assert!(matches!(
+      program.tess_ctrl().unwrap().decls[0],
+      ShaderDecl::In(ref name, ref ty) if name == "normal" && *ty == <[V3<f32>]>::ty()
+    ));
+    assert!(matches!(
+      program.tess_eval().unwrap().decls[0],
+      ShaderDecl::In(ref name, ref ty) if name == "out_normal" && *ty == <[V3<f32>]>::ty()
+    ));
+    assert!(matches!(
+      program.fragment().decls[0],
+      ShaderDecl::In(ref name, ref ty) if name == "normal" && *ty == V3::<f32>::ty()
+    ));
+  }
+
+  #[test]
+  fn program_not_an_output() {
+    let err = Program::new_vertex_shader(|mut s, _| {
+      let normal = s.output::<V3<f32>>("normal")?;
+      let weights = s.output::<[f32; 2]>("weights")?;
+      let shader = s.main_fun(|_: &mut Scope<()>| {});
+      Ok((shader, (normal, weights.at(0))))
+    })
+    .unwrap_err();
+
+    assert_eq!(
+      err,
+      ProgramError::NotAnOutput {
+        stage: ShaderStage::Vertex,
+        index: 1
+      }
+    );
+
+    let err = Program::new_vertex_shader(|mut s, _| {
+      let normal = s.output::<V3<f32>>("normal")?;
+      let shader = s.main_fun(|_: &mut Scope<()>| {});
+      Ok((shader, (Output(Var::from(&normal.0)), normal)))
+    })
+    .unwrap_err();
+
+    assert_eq!(
+      err,
+      ProgramError::DuplicateVarying {
+        stage: ShaderStage::Vertex,
+        name: "normal".to_owned()
+      }
+    );
+
+    // an output of another shader is not an output of this stage
+    let err = Program::new_vertex_shader(|s, _| {
+      let mut other = ShaderBuilder::new(ShaderStage::Vertex);
+      let normal = other.output::<V3<f32>>("normal")?;
+      let shader = s.main_fun(|_: &mut Scope<()>| {});
+      Ok((shader, normal))
+    })
+    .unwrap_err();
+
+    assert_eq!(
+      err,
+      ProgramError::NotAnOutput {
+        stage: ShaderStage::Vertex,
+        index: 0
+      }
+    );
+  }
+
+  #[test]
+  fn reflection() {
+    let shader = ShaderBuilder::new_tess_ctrl_shader(|mut s, tess| {
+      s.input::<[V3<f32>]>("normal").unwrap();
+      let scale = s.uniform::<f32>("scale").unwrap();
+
+      s.main_fun(|s: &mut Scope<()>| {
+        s.set(
+          tess.output.at(&tess.invocation_id).position(),
+          tess.input.at(&tess.invocation_id).position() * &scale,
+        );
+        s.set(tess.tess_level_outer.at(0), 1.);
+      })
+    });
+
+    let inputs: Vec<_> = shader.inputs().collect();
+    assert_eq!(inputs.len(), 1);
+    assert_eq!(inputs[0].name(), "normal");
+    assert_eq!(inputs[0].ty().array_dims(), &[0]);
+    assert_eq!(inputs[0].ty().prim_ty(), &PrimType::Float(Dim::D3));
+    assert_eq!(inputs[0].location(), None);
+    assert_eq!(shader.outputs().count(), 0);
+    assert_eq!(shader.uniforms().next().unwrap().name(), "scale");
+
+    let read: Vec<_> = shader.builtins_read().into_iter().collect();
+    assert_eq!(
+      read,
+      vec![
+        BuiltIn::TessCtrl(TessCtrlBuiltIn::InvocationID),
+        BuiltIn::TessCtrl(TessCtrlBuiltIn::In),
+        BuiltIn::TessCtrl(TessCtrlBuiltIn::Position),
+      ]
+    );
+
+    let written: Vec<_> = shader.builtins_written().into_iter().collect();
+    assert_eq!(
+      written,
+      vec![
+        BuiltIn::TessCtrl(TessCtrlBuiltIn::TessellationLevelOuter),
+        BuiltIn::TessCtrl(TessCtrlBuiltIn::Out),
+        BuiltIn::TessCtrl(TessCtrlBuiltIn::Position),
+      ]
+    );
+  }
+
+  #[test]
+  fn reflection_color_attachments() {
+    let shader = ShaderBuilder::new_fragment_shader(|mut s, _| {
+      s.color_attachment::<V4<f32>>("albedo", 0.into()).unwrap();
+      s.color_attachment::<V4<f32>>("blend", (0, 1).into())
+        .unwrap();
+      s.main_fun(|_: &mut Scope<()>| {})
+    });
+
+    let outputs: Vec<_> = shader.outputs().collect();
+    assert_eq!(outputs[0].location(), Some(0));
+    assert_eq!(
+      outputs[1].color_attachment(),
+      Some(ColorAttachment::new(0).with_index(1))
+    );
+    assert_eq!(outputs[1].qualifier(), InterfaceQualifier::Out);
+  }
+
+  #[test]
+  fn shader_equality() {
+    fn build(x: f32, name: &str) -> Shader {
+      ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+        let c = s.constant_named(name, lit!(x));
+        s.main_fun(|s: &mut Scope<()>| {
+          s.set(vertex.position, vec4!(c.clone(), 0., 0., 1.));
+        })
+      })
+    }
+
+    let shader = build(1., "c");
+    assert_eq!(shader, build(1., "c"));
+    assert_eq!(shader.fingerprint(), build(1., "c").fingerprint());
+    assert_ne!(shader, build(2., "c"));
+    assert_ne!(shader.fingerprint(), build(2., "c").fingerprint());
+    assert_ne!(shader, build(1., "d"));
+    assert_ne!(shader.fingerprint(), build(1., "d").fingerprint());
+
+    // floating-point literals compare by bits
+    assert_ne!(build(0., "c"), build(-0., "c"));
+    assert_ne!(build(0., "c").fingerprint(), build(-0., "c").fingerprint());
+    assert_eq!(build(f32::NAN, "c"), build(f32::NAN, "c").clone());
+    assert_eq!(
+      build(f32::NAN, "c").fingerprint(),
+      build(f32::NAN, "c").fingerprint()
+    );
+
+    // the same shader built from other Rust code
+    let other = ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+      let c = s.constant_named("c", lit!(1.));
+      s.main_fun(|s: &mut Scope<()>| s.set(vertex.position, vec4!(c.clone(), 0., 0., 1.)))
+    });
+    assert_eq!(shader, other);
+    assert_eq!(shader.fingerprint(), other.fingerprint());
+
+    let set: std::collections::HashSet<_> = vec![shader, other].into_iter().collect();
+    assert_eq!(set.len(), 1);
+  }
 }
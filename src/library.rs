@@ -0,0 +1,522 @@
+//! Function libraries shared between shaders.
+//!
+//! Functions declared with [`ShaderBuilder::fun`] belong to the shader they are declared in: a helper used by several
+//! stages or shaders has to be declared again in each of them. A [`Library`] declares functions and constants once,
+//! outside of any shader, and [`ShaderBuilder::import`] brings a function of a library into a shader, along with the
+//! functions and constants it depends on.
+//!
+//! Imported declarations are added to the shader in dependency order, and the handles they use are remapped to the
+//! handles of the shader. A declaration imported several times, directly or as a dependency of other functions, is
+//! only added once. Imported declarations the shader doesn’t end up using are removed when its `main` function is
+//! declared, so writers only output the functions of a library a shader actually needs.
+//!
+//! # Examples
+//!
+//! ```
+//! use shades::{
+//!   inputs, lit, outputs, vec4, library::Library, Bounded as _, Expr, Geometry as _, Scope, ShaderBuilder, V3, V4,
+//!   writer::glsl,
+//! };
+//!
+//! let mut lighting = Library::new();
+//! let ambient = lighting.constant_named("ambient", 0.1);
+//! let lambert = lighting.fun_named(
+//!   "lambert",
+//!   &["n", "l"],
+//!   |_: &mut Scope<Expr<f32>>, n: Expr<V3<f32>>, l: Expr<V3<f32>>| n.dot(l).max(&ambient),
+//! );
+//! let _unused = lighting.fun_named("unused", &[], |_: &mut Scope<Expr<f32>>| lit!(1.));
+//!
+//! let fragment_shader = ShaderBuilder::new_fragment_shader(|mut s, _| {
+//!   inputs!(s, normal: V3<f32>);
+//!   outputs!(s, color: V4<f32>);
+//!
+//!   let lambert = s.import(&lambert);
+//!
+//!   s.main_fun(|s: &mut Scope<()>| {
+//!     let light = s.var(lambert.call(normal.clone(), lit!(0., 1., 0.)));
+//!     s.set(&color, vec4!(light, light, light, 1.));
+//!   })
+//! });
+//!
+//! let output = glsl::write_shader_to_str(&fragment_shader).unwrap();
+//! assert!(output.contains("const float ambient = .1;"));
+//! assert!(output.contains("float lambert(vec3 n, vec3 l) {"));
+//! assert!(!output.contains("unused"));
+//! ```
+
+use crate::{
+  optimizer::{self, Reachable},
+  ErasedExpr, ErasedFunHandle, Expr, FunHandle, ScopedHandle, ShaderBuilder, ShaderDecl, ToFun,
+  ToType,
+};
+use std::{
+  collections::{HashMap, HashSet},
+  marker::PhantomData,
+  panic::Location,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+  },
+};
+
+// Identifier of the next library, telling apart the declarations of different libraries.
+static NEXT_LIBRARY_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A declaration of a library, imported into a shader.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub(crate) enum Imported {
+  /// Function, by library and handle in the library.
+  Fun(usize, u16),
+
+  /// Constant, by library and handle in the library.
+  Global(usize, u16),
+}
+
+/// A library of functions and constants, shared between shaders.
+///
+/// Functions and constants are declared the same way they are in a [`ShaderBuilder`]. Functions of the library can call
+/// the functions and use the constants declared before them in the same library. Handles of the library are only valid
+/// in the functions of the library: import functions into a shader with [`ShaderBuilder::import`] to use them there.
+/// Shaders using the handles of a library without importing them are reported by
+/// [`Shader::validate`](crate::Shader::validate).
+///
+/// See the [module documentation](crate::library) for an example.
+#[derive(Debug)]
+pub struct Library {
+  id: usize,
+  decls: Vec<ShaderDecl>,
+  next_fun_handle: u16,
+  next_global_handle: u16,
+}
+
+impl Default for Library {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Library {
+  /// Create a new empty library.
+  pub fn new() -> Self {
+    Self {
+      id: NEXT_LIBRARY_ID.fetch_add(1, Ordering::Relaxed),
+      decls: Vec::new(),
+      next_fun_handle: 0,
+      next_global_handle: 0,
+    }
+  }
+
+  /// Declare a new function in the library.
+  ///
+  /// This method is similar to [`ShaderBuilder::fun`]. The returned [`LibFun`] can be called by the functions declared
+  /// next in the library, and imported into shaders with [`ShaderBuilder::import`].
+  #[track_caller]
+  pub fn fun<F, R, A>(&mut self, f: F) -> LibFun<R, A>
+  where
+    F: ToFun<R, A>,
+  {
+    let mut fundef = f.build_fn();
+    let handle = self.next_fun_handle;
+    self.next_fun_handle = self.next_fun_handle.wrapping_add(1);
+
+    for expr in optimizer::fun_exprs_mut(&mut fundef.erased) {
+      self.localize(expr);
+    }
+
+    fundef.erased.location = Some(Location::caller());
+    self.decls.push(ShaderDecl::FunDef(handle, fundef.erased));
+
+    self.lib_fun(handle)
+  }
+
+  /// Declare a new function in the library with a name, and names for its arguments.
+  ///
+  /// This method is similar to [`ShaderBuilder::fun_named`].
+  #[track_caller]
+  pub fn fun_named<F, R, A>(
+    &mut self,
+    name: impl Into<String>,
+    arg_names: &[&str],
+    f: F,
+  ) -> LibFun<R, A>
+  where
+    F: ToFun<R, A>,
+  {
+    let handle = self.next_fun_handle;
+    self.fun::<F, R, A>(f);
+
+    if let Some(ShaderDecl::FunDef(_, fun)) = self.decls.last_mut() {
+      fun.name = Some(name.into());
+      fun.arg_names = arg_names.iter().map(|&name| name.to_owned()).collect();
+    }
+
+    self.lib_fun(handle)
+  }
+
+  /// Declare a new constant in the library.
+  ///
+  /// This method is similar to [`ShaderBuilder::constant`]. The returned [`Expr<T>`] can only be used by the functions
+  /// declared next in the library; constants are imported into shaders along with the functions using them.
+  pub fn constant<T>(&mut self, expr: impl Into<Expr<T>>) -> Expr<T>
+  where
+    T: ToType,
+  {
+    let handle = self.next_global_handle;
+    self.next_global_handle = self.next_global_handle.wrapping_add(1);
+
+    let mut expr = expr.into().erased;
+    self.localize(&mut expr);
+    self
+      .decls
+      .push(ShaderDecl::Const(handle, T::ty(), expr, None));
+
+    Expr::new(ErasedExpr::Var(ScopedHandle::LibraryGlobal(
+      self.id, handle,
+    )))
+  }
+
+  /// Declare a new named constant in the library.
+  ///
+  /// This method is similar to [`ShaderBuilder::constant_named`].
+  pub fn constant_named<T>(&mut self, name: impl Into<String>, expr: impl Into<Expr<T>>) -> Expr<T>
+  where
+    T: ToType,
+  {
+    let constant = self.constant(expr);
+
+    if let Some(ShaderDecl::Const(_, _, _, const_name)) = self.decls.last_mut() {
+      *const_name = Some(name.into());
+    }
+
+    constant
+  }
+
+  /// Replace the handles of the library with the handles of its declarations.
+  ///
+  /// Handles of other libraries are kept, so that validation reports them once imported.
+  fn localize(&self, expr: &mut ErasedExpr) {
+    let id = self.id;
+
+    optimizer::walk_expr_mut(expr, &mut |expr| match expr {
+      ErasedExpr::FunCall(handle, _) => match *handle {
+        ErasedFunHandle::Library(library, h) if library == id => {
+          *handle = ErasedFunHandle::UserDefined(h)
+        }
+        _ => (),
+      },
+
+      ErasedExpr::Var(handle) => match *handle {
+        ScopedHandle::LibraryGlobal(library, h) if library == id => {
+          *handle = ScopedHandle::global(h)
+        }
+        _ => (),
+      },
+
+      _ => (),
+    });
+  }
+
+  /// Handle of a function of the library, along with the declarations it depends on.
+  fn lib_fun<R, A>(&self, handle: u16) -> LibFun<R, A> {
+    let roots = self
+      .decls
+      .iter()
+      .flat_map(|decl| match decl {
+        ShaderDecl::FunDef(h, fun) if *h == handle => optimizer::fun_exprs(fun),
+        _ => Vec::new(),
+      })
+      .collect();
+    let reachable = Reachable::from_exprs(&self.decls, roots);
+
+    let decls = self
+      .decls
+      .iter()
+      .filter(|decl| match decl {
+        ShaderDecl::FunDef(h, _) => *h == handle || reachable.funs.contains(h),
+        ShaderDecl::Const(h, ..) => reachable.globals.contains(h),
+        _ => false,
+      })
+      .cloned()
+      .collect();
+
+    LibFun {
+      library: self.id,
+      handle: FunHandle {
+        erased: ErasedFunHandle::Library(self.id, handle),
+        _phantom: PhantomData,
+      },
+      decls,
+    }
+  }
+}
+
+/// A function of a [`Library`].
+///
+/// The functions declared next in the library call it through [`LibFun::handle`]. To call it from a shader, import it
+/// first with [`ShaderBuilder::import`].
+#[derive(Clone, Debug)]
+pub struct LibFun<R, A> {
+  library: usize,
+  handle: FunHandle<R, A>,
+  // the function and the declarations it depends on, in declaration order
+  decls: Arc<[ShaderDecl]>,
+}
+
+impl<R, A> LibFun<R, A> {
+  /// Handle of the function in its library.
+  ///
+  /// The handle is only valid in the functions declared next in the library: shaders calling it are reported by
+  /// [`Shader::validate`](crate::Shader::validate) as long as the function is not imported.
+  pub fn handle(&self) -> &FunHandle<R, A> {
+    &self.handle
+  }
+}
+
+impl ShaderBuilder {
+  /// Import a function of a [`Library`] into the shader, and get its handle in the shader.
+  ///
+  /// The functions and constants the function depends on are imported with it, before it. Importing a declaration
+  /// that was already imported, directly or as a dependency, returns the handle it was imported with. Imported
+  /// declarations that are not used by the shader are removed when [`ShaderBuilder::main_fun`] is called.
+  ///
+  /// See the [`library`](crate::library) module for an example.
+  pub fn import<R, A>(&mut self, fun: &LibFun<R, A>) -> FunHandle<R, A> {
+    for decl in fun.decls.iter() {
+      match decl {
+        ShaderDecl::FunDef(handle, def) => {
+          let imported = Imported::Fun(fun.library, *handle);
+
+          if !self.imports.contains_key(&imported) {
+            let mut def = def.clone();
+
+            for expr in optimizer::fun_exprs_mut(&mut def) {
+              self.remap(fun.library, expr);
+            }
+            self.renumber_scopes(&mut def);
+
+            let handle = self.next_fun_handle();
+            self.push_decl(ShaderDecl::FunDef(handle, def));
+            self.imports.insert(imported, handle);
+          }
+        }
+
+        ShaderDecl::Const(handle, ty, expr, name) => {
+          let imported = Imported::Global(fun.library, *handle);
+
+          if !self.imports.contains_key(&imported) {
+            let mut expr = expr.clone();
+            self.remap(fun.library, &mut expr);
+
+            let handle = self.next_global_handle;
+            self.next_global_handle = self.next_global_handle.wrapping_add(1);
+            self.push_decl(ShaderDecl::Const(handle, ty.clone(), expr, name.clone()));
+            self.imports.insert(imported, handle);
+          }
+        }
+
+        _ => (),
+      }
+    }
+
+    let handle = match fun.handle.erased {
+      ErasedFunHandle::Library(_, handle) => self.imports[&Imported::Fun(fun.library, handle)],
+      _ => unreachable!("library functions have library handles"),
+    };
+
+    FunHandle {
+      erased: ErasedFunHandle::UserDefined(handle),
+      _phantom: PhantomData,
+    }
+  }
+
+  /// Replace the handles of a library with the ones they were imported with.
+  fn remap(&self, library: usize, expr: &mut ErasedExpr) {
+    optimizer::walk_expr_mut(expr, &mut |expr| match expr {
+      ErasedExpr::FunCall(ErasedFunHandle::UserDefined(handle), _) => {
+        if let Some(&imported) = self.imports.get(&Imported::Fun(library, *handle)) {
+          *handle = imported;
+        }
+      }
+
+      ErasedExpr::Var(ScopedHandle::Global(handle)) => {
+        if let Some(&imported) = self.imports.get(&Imported::Global(library, *handle)) {
+          *handle = imported;
+        }
+      }
+
+      _ => (),
+    });
+  }
+
+  /// Remove the imported declarations that are not reachable from the other declarations.
+  pub(crate) fn remove_unused_imports(&mut self) {
+    if self.imports.is_empty() {
+      return;
+    }
+
+    let mut funs = HashSet::new();
+    let mut globals = HashSet::new();
+
+    for (imported, &handle) in &self.imports {
+      match imported {
+        Imported::Fun(..) => funs.insert(handle),
+        Imported::Global(..) => globals.insert(handle),
+      };
+    }
+
+    let roots = self
+      .decls
+      .iter()
+      .flat_map(|decl| match decl {
+        ShaderDecl::Main(fun) => optimizer::fun_exprs(fun),
+        ShaderDecl::FunDef(handle, fun) if !funs.contains(handle) => optimizer::fun_exprs(fun),
+        ShaderDecl::Const(handle, _, expr, _) if !globals.contains(handle) => vec![expr],
+        _ => Vec::new(),
+      })
+      .collect();
+    let reachable = Reachable::from_exprs(&self.decls, roots);
+
+    self.decls.retain(|decl| match decl {
+      ShaderDecl::FunDef(handle, _) => !funs.contains(handle) || reachable.funs.contains(handle),
+      ShaderDecl::Const(handle, ..) => {
+        !globals.contains(handle) || reachable.globals.contains(handle)
+      }
+      _ => true,
+    });
+    self.imports = HashMap::new();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    lit,
+    validation::{Declaration, Diagnostic},
+    Exponential as _, Scope, Shader, V4,
+  };
+
+  #[test]
+  fn import() {
+    let mut lib = Library::new();
+    let two = lib.constant(lit!(2.));
+    let unused = lib.fun(|_: &mut Scope<Expr<f32>>| lit!(0.));
+    let double = lib.fun(|_: &mut Scope<Expr<f32>>, x: Expr<f32>| x * &two);
+    let quadruple = lib
+      .fun(|_: &mut Scope<Expr<f32>>, x: Expr<f32>| double.handle().call(double.handle().call(x)));
+
+    let imported = ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+      let one = s.constant(lit!(1.));
+      let square = s.fun(|_: &mut Scope<Expr<f32>>, x: Expr<f32>| x.pow(2.));
+      let _ = s.import(&unused);
+      let quadruple = s.import(&quadruple);
+      let double = s.import(&double);
+
+      s.main_fun(|s: &mut Scope<()>| {
+        let x = quadruple.call(double.call(square.call(one.clone())));
+        s.set(&vertex.position, lit!(0., 0., 0., 1.) * x);
+      })
+    });
+
+    let expected = ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+      let one = s.constant(lit!(1.));
+      let square = s.fun(|_: &mut Scope<Expr<f32>>, x: Expr<f32>| x.pow(2.));
+      let _ = s.fun(|_: &mut Scope<Expr<f32>>| lit!(0.));
+      let two = s.constant(lit!(2.));
+      let double = s.fun(|_: &mut Scope<Expr<f32>>, x: Expr<f32>| x * &two);
+      let quadruple = s.fun(|_: &mut Scope<Expr<f32>>, x: Expr<f32>| double.call(double.call(x)));
+
+      s.main_fun(|s: &mut Scope<()>| {
+        let x = quadruple.call(double.call(square.call(one.clone())));
+        s.set(&vertex.position, lit!(0., 0., 0., 1.) * x);
+      })
+    });
+
+    // the unused import is removed, but handles are allocated in import order
+    let expected_decls: Vec<_> = expected
+      .decls
+      .iter()
+      .filter(|decl| !matches!(decl, ShaderDecl::FunDef(1, _)))
+      .cloned()
+      .collect();
+    assert_eq!(imported.decls, expected_decls);
+    assert!(imported.validate().is_empty());
+  }
+
+  #[test]
+  fn shared_between_shaders() {
+    let mut lib = Library::new();
+    let red = lib.fun_named("red", &[], |_: &mut Scope<Expr<V4<f32>>>| {
+      lit!(1., 0., 0., 1.)
+    });
+
+    let build = || -> Shader {
+      ShaderBuilder::new_fragment_shader(|mut s, _| {
+        let color = s.output::<V4<f32>>("color").unwrap();
+        let red = s.import(&red);
+
+        s.main_fun(|s: &mut Scope<()>| {
+          s.set(&color, red.call());
+        })
+      })
+    };
+
+    let first = build();
+    let second = build();
+    assert_eq!(first, second);
+    assert_eq!(first.decls[1], lib.decls[0]);
+  }
+
+  #[test]
+  fn not_imported() {
+    let mut lib = Library::new();
+    let two = lib.constant(lit!(2.));
+    let double = lib.fun(|_: &mut Scope<Expr<f32>>, x: Expr<f32>| x * &two);
+
+    // the shader declares a function and a constant with the same handles as the ones of the library
+    let shader = ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+      let _ = s.constant(lit!(1.));
+      let _ = s.fun(|_: &mut Scope<Expr<f32>>, x: Expr<f32>| x);
+
+      s.main_fun(|s: &mut Scope<()>| {
+        s.set(&vertex.point_size, double.handle().call(two.clone()));
+      })
+    });
+
+    assert_eq!(
+      shader.validate(),
+      vec![
+        Diagnostic::UnimportedFunction {
+          declaration: Declaration::Main,
+          handle: 0
+        },
+        Diagnostic::UnimportedConstant {
+          declaration: Declaration::Main,
+          handle: 0
+        },
+      ]
+    );
+
+    // functions of another library are not imported along with the functions calling them
+    let mut other = Library::new();
+    let quadruple = other
+      .fun(|_: &mut Scope<Expr<f32>>, x: Expr<f32>| double.handle().call(double.handle().call(x)));
+
+    let shader = ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+      let quadruple = s.import(&quadruple);
+
+      s.main_fun(|s: &mut Scope<()>| {
+        s.set(&vertex.point_size, quadruple.call(lit!(1.)));
+      })
+    });
+
+    assert_eq!(
+      shader.validate(),
+      vec![Diagnostic::UnimportedFunction {
+        declaration: Declaration::Function(0),
+        handle: 0
+      }]
+    );
+  }
+}
@@ -0,0 +1,1629 @@
+//! Optimization passes over the shader representation.
+//!
+//! Passes never change what a shader computes; they are applied by writers on demand, via their options.
+
+use crate::{
+  call_graph::CallGraph,
+  interpreter::{self, Value},
+  typing::TypeEnv,
+  ArgQualifier, ErasedExpr, ErasedFun, ErasedFunHandle, ErasedReturn, ErasedScope, Matrix,
+  ScopeInstr, ScopedHandle, Shader, ShaderDecl,
+};
+use std::{
+  collections::{HashMap, HashSet},
+  mem,
+  sync::Arc,
+};
+
+/// Fold constant expressions and simplify algebraic identities.
+///
+/// Only operations that GLSL defines exactly are folded, so that the folded shader computes the same values as the
+/// original one:
+///
+/// - integer arithmetic, except divisions and shifts whose result is undefined in GLSL;
+/// - floating-point additions, subtractions and component-wise multiplications, which GLSL requires to be correctly
+///   rounded; divisions and matrix products are left to the driver;
+/// - boolean logic, comparisons and swizzles;
+/// - built-in functions that don’t round, such as [`ErasedFunHandle::Floor`] or [`ErasedFunHandle::Max`];
+///   transcendental functions are left to the driver, as their precision is implementation-defined.
+///
+/// Identities are only applied when they are exact, so `x + 0.` is kept (it turns `-0.` into `0.`) while `x - 0.` is
+/// folded into `x`. Constants evaluating to a literal are propagated to their uses.
+pub(crate) fn fold_constants(shader: &mut Shader) {
+  let mut folder = ConstantFolder::default();
+  let mut rewrites = Rewrites::default();
+
+  for decl in &mut shader.decls {
+    folder.fold_decl(decl, &mut rewrites);
+  }
+}
+
+#[derive(Debug, Default)]
+struct ConstantFolder {
+  // literal values of constants, propagated to their uses
+  constants: HashMap<u16, ErasedExpr>,
+}
+
+impl ConstantFolder {
+  fn fold_decl(&mut self, decl: &mut ShaderDecl, rewrites: &mut Rewrites) {
+    match decl {
+      ShaderDecl::Main(fun) | ShaderDecl::FunDef(_, fun) => self.fold_fun(fun, rewrites),
+
+      ShaderDecl::Const(handle, _, expr, _) => {
+        self.fold_expr(expr, rewrites);
+
+        if is_literal(expr) {
+          self.constants.insert(*handle, expr.clone());
+        }
+      }
+
+      ShaderDecl::In(..) | ShaderDecl::Out(..) | ShaderDecl::Uniform(..) => (),
+    }
+  }
+
+  fn fold_fun(&self, fun: &mut ErasedFun, rewrites: &mut Rewrites) {
+    self.fold_scope(&mut fun.scope, rewrites);
+
+    if let ErasedReturn::Expr(_, expr) = &mut fun.ret {
+      self.fold_expr(expr, rewrites);
+    }
+  }
+
+  fn fold_scope(&self, scope: &mut ErasedScope, rewrites: &mut Rewrites) {
+    for instr in &mut scope.instructions {
+      match instr {
+        ScopeInstr::VarDecl { init_value, .. } => self.fold_expr(init_value, rewrites),
+
+        ScopeInstr::Return(ErasedReturn::Expr(_, expr)) => self.fold_expr(expr, rewrites),
+
+        ScopeInstr::Return(ErasedReturn::Void)
+        | ScopeInstr::Continue
+        | ScopeInstr::Break
+        | ScopeInstr::Discard => (),
+
+        ScopeInstr::If { condition, scope }
+        | ScopeInstr::ElseIf { condition, scope }
+        | ScopeInstr::While { condition, scope } => {
+          self.fold_expr(condition, rewrites);
+          self.fold_scope(scope, rewrites);
+        }
+
+        ScopeInstr::Else { scope } => self.fold_scope(scope, rewrites),
+
+        ScopeInstr::For {
+          init_expr,
+          condition,
+          post_expr,
+          scope,
+          ..
+        } => {
+          self.fold_expr(init_expr, rewrites);
+          self.fold_expr(condition, rewrites);
+          self.fold_expr(post_expr, rewrites);
+          self.fold_scope(scope, rewrites);
+        }
+
+        ScopeInstr::MutateVar { var, expr } => {
+          self.fold_lvalue(var, rewrites);
+          self.fold_expr(expr, rewrites);
+        }
+      }
+    }
+  }
+
+  // Fold the indices of an assigned expression, which must stay assignable.
+  fn fold_lvalue(&self, expr: &mut ErasedExpr, rewrites: &mut Rewrites) {
+    match expr {
+      ErasedExpr::ArrayLookup { object, index } => {
+        self.fold_lvalue(Arc::make_mut(object), rewrites);
+        rewrites.rewrite(index, &mut |index, rewrites| {
+          self.fold_expr(index, rewrites)
+        });
+      }
+
+      ErasedExpr::Field { object, .. } | ErasedExpr::Swizzle(object, _) => {
+        self.fold_lvalue(Arc::make_mut(object), rewrites)
+      }
+
+      _ => (),
+    }
+  }
+
+  fn fold_expr(&self, expr: &mut ErasedExpr, rewrites: &mut Rewrites) {
+    rewrites.children(expr, &mut |child, rewrites| self.fold_expr(child, rewrites));
+
+    if let ErasedExpr::Var(ScopedHandle::Global(handle)) = expr {
+      if let Some(value) = self.constants.get(handle) {
+        *expr = value.clone();
+      }
+    } else if let Some(folded) = fold(expr) {
+      *expr = folded;
+    } else {
+      simplify(expr);
+    }
+  }
+}
+
+/// Remove declarations and local variables that don’t contribute to the shader.
+///
+/// Functions and constants are removed if they are not reachable from `main`. Inputs and uniforms never read are also
+/// removed if `interface` is `true`, which changes the interface of the shader; outputs are always kept. Local
+/// variables are removed if they are never used and their initial value has no side effects.
+pub(crate) fn eliminate_dead_code(shader: &mut Shader, interface: bool) {
+  let purity = Purity::new(&shader.decls);
+
+  // remove unused variables first, so that they don’t keep functions and constants alive
+  for decl in &mut shader.decls {
+    if let ShaderDecl::Main(fun) | ShaderDecl::FunDef(_, fun) = decl {
+      while remove_unused_vars(fun, &purity) {}
+    }
+  }
+
+  let reachable = Reachable::from_main(&shader.decls);
+
+  shader.decls.retain(|decl| match decl {
+    ShaderDecl::Main(_) | ShaderDecl::Out(..) => true,
+    ShaderDecl::FunDef(handle, _) => reachable.funs.contains(handle),
+    ShaderDecl::Const(handle, ..) => reachable.globals.contains(handle),
+    ShaderDecl::In(name, _) | ShaderDecl::Uniform(name, _) => {
+      !interface || reachable.interface.contains(name)
+    }
+  });
+}
+
+// User-defined functions that can be called without side effects.
+#[derive(Debug, Default)]
+struct Purity {
+  pure_funs: HashSet<u16>,
+}
+
+impl Purity {
+  fn new(decls: &[ShaderDecl]) -> Self {
+    let mut purity = Purity::default();
+    let funs: HashMap<_, _> = decls
+      .iter()
+      .filter_map(|decl| match decl {
+        ShaderDecl::FunDef(handle, fun) => Some((*handle, fun)),
+        _ => None,
+      })
+      .collect();
+
+    // callees come first, so that callers know whether they are pure; recursive functions are never pure
+    for handle in CallGraph::new(decls).callees_first() {
+      if purity.is_pure_fun(funs[&handle]) {
+        purity.pure_funs.insert(handle);
+      }
+    }
+
+    purity
+  }
+
+  fn is_pure(&self, expr: &ErasedExpr) -> bool {
+    let mut pure = true;
+
+    walk_expr(expr, &mut |expr| {
+      if let ErasedExpr::FunCall(handle, _) = expr {
+        pure &= match handle {
+          ErasedFunHandle::UserDefined(handle) => self.pure_funs.contains(handle),
+          handle => !has_side_effects(handle),
+        };
+      }
+    });
+
+    pure
+  }
+
+  // A function is pure if it only assigns its own arguments and variables, and doesn’t write back to the caller’s.
+  fn is_pure_fun(&self, fun: &ErasedFun) -> bool {
+    fn is_pure_scope(scope: &ErasedScope) -> bool {
+      scope.instructions.iter().all(|instr| match instr {
+        ScopeInstr::Discard => false,
+
+        ScopeInstr::MutateVar { var, .. } => matches!(
+          lvalue_root(var),
+          Some(ScopedHandle::FunArg(_)) | Some(ScopedHandle::FunVar { .. })
+        ),
+
+        ScopeInstr::If { scope, .. }
+        | ScopeInstr::ElseIf { scope, .. }
+        | ScopeInstr::Else { scope }
+        | ScopeInstr::For { scope, .. }
+        | ScopeInstr::While { scope, .. } => is_pure_scope(scope),
+
+        _ => true,
+      })
+    }
+
+    fun
+      .arg_qualifiers
+      .iter()
+      .all(|qualifier| *qualifier == ArgQualifier::In)
+      && is_pure_scope(&fun.scope)
+      && fun_exprs(fun).into_iter().all(|expr| self.is_pure(expr))
+  }
+}
+
+// Built-in functions that have an effect besides returning a value.
+fn has_side_effects(handle: &ErasedFunHandle) -> bool {
+  matches!(
+    handle,
+    ErasedFunHandle::EmitStreamVertex
+      | ErasedFunHandle::EndStreamPrimitive
+      | ErasedFunHandle::EmitVertex
+      | ErasedFunHandle::EndPrimitive
+      | ErasedFunHandle::Barrier
+      | ErasedFunHandle::MemoryBarrier
+      | ErasedFunHandle::MemoryBarrierAtomic
+      | ErasedFunHandle::MemoryBarrierBuffer
+      | ErasedFunHandle::MemoryBarrierShared
+      | ErasedFunHandle::MemoryBarrierImage
+      | ErasedFunHandle::GroupMemoryBarrier
+  )
+}
+
+// Remove the unused variables of a function; return whether any variable was removed, as removing a variable can
+// make others unused.
+fn remove_unused_vars(fun: &mut ErasedFun, purity: &Purity) -> bool {
+  fn remove(scope: &mut ErasedScope, used: &HashSet<(u16, u16)>, purity: &Purity) -> bool {
+    let len = scope.instructions.len();
+
+    scope.retain(|instr| match instr {
+      ScopeInstr::VarDecl {
+        handle: ScopedHandle::FunVar { subscope, handle },
+        init_value,
+        ..
+      } => used.contains(&(*subscope, *handle)) || !purity.is_pure(init_value),
+      _ => true,
+    });
+
+    let mut removed = scope.instructions.len() != len;
+
+    for instr in &mut scope.instructions {
+      match instr {
+        ScopeInstr::If { scope, .. }
+        | ScopeInstr::ElseIf { scope, .. }
+        | ScopeInstr::Else { scope }
+        | ScopeInstr::For { scope, .. }
+        | ScopeInstr::While { scope, .. } => removed |= remove(scope, used, purity),
+        _ => (),
+      }
+    }
+
+    removed
+  }
+
+  // sibling scopes share their identifiers, so a variable might be kept because of a homonym; that’s fine
+  let mut used = HashSet::new();
+  for expr in fun_exprs(fun) {
+    walk_expr(expr, &mut |expr| {
+      if let ErasedExpr::Var(ScopedHandle::FunVar { subscope, handle }) = expr {
+        used.insert((*subscope, *handle));
+      }
+    });
+  }
+
+  remove(&mut fun.scope, &used, purity)
+}
+
+// Declarations reachable from the main function, or from any expressions.
+#[derive(Debug, Default)]
+pub(crate) struct Reachable {
+  pub(crate) funs: HashSet<u16>,
+  pub(crate) globals: HashSet<u16>,
+  pub(crate) interface: HashSet<String>,
+}
+
+impl Reachable {
+  fn from_main(decls: &[ShaderDecl]) -> Self {
+    let roots = decls
+      .iter()
+      .flat_map(|decl| match decl {
+        ShaderDecl::Main(fun) => fun_exprs(fun),
+        _ => Vec::new(),
+      })
+      .collect();
+
+    Self::from_exprs(decls, roots)
+  }
+
+  // Declarations reachable from some expressions.
+  pub(crate) fn from_exprs<'a>(decls: &'a [ShaderDecl], mut pending: Vec<&'a ErasedExpr>) -> Self {
+    let mut funs = HashMap::new();
+    let mut globals = HashMap::new();
+
+    for decl in decls {
+      match decl {
+        ShaderDecl::FunDef(handle, fun) => {
+          funs.insert(*handle, fun);
+        }
+        ShaderDecl::Const(handle, _, expr, _) => {
+          globals.insert(*handle, expr);
+        }
+        _ => (),
+      }
+    }
+
+    let mut reachable = Reachable::default();
+
+    while let Some(expr) = pending.pop() {
+      walk_expr(expr, &mut |expr| match expr {
+        ErasedExpr::FunCall(ErasedFunHandle::UserDefined(handle), _)
+          if reachable.funs.insert(*handle) =>
+        {
+          pending.extend(
+            funs
+              .get(handle)
+              .map(|fun| fun_exprs(fun))
+              .unwrap_or_default(),
+          );
+        }
+
+        ErasedExpr::Var(ScopedHandle::Global(handle)) if reachable.globals.insert(*handle) => {
+          pending.extend(globals.get(handle).copied());
+        }
+
+        ErasedExpr::Var(ScopedHandle::Input(name))
+        | ErasedExpr::Var(ScopedHandle::Uniform(name)) => {
+          reachable.interface.insert(name.clone());
+        }
+
+        _ => (),
+      });
+    }
+
+    reachable
+  }
+}
+
+/// Hoist repeated subexpressions into local variables.
+///
+/// Within a run of instructions that doesn’t assign any variable, pure subexpressions evaluated more than once are
+/// computed once into a new variable, declared right before the first instruction using it. Expressions only
+/// evaluated conditionally, such as loop conditions and the right operand of `&&`, are never hoisted.
+pub(crate) fn eliminate_common_subexpressions(shader: &mut Shader) {
+  let env = TypeEnv::new(&shader.decls);
+  let purity = Purity::new(&shader.decls);
+
+  for decl in &mut shader.decls {
+    if let ShaderDecl::Main(fun) | ShaderDecl::FunDef(_, fun) = decl {
+      let env = env.with_args(&fun.args);
+
+      // the returned expression is evaluated after the scope, so it can share the last run of instructions
+      let ret = match mem::replace(&mut fun.ret, ErasedReturn::Void) {
+        ErasedReturn::Void => None,
+        ErasedReturn::Expr(ty, expr) => {
+          fun
+            .scope
+            .instructions
+            .push(ScopeInstr::Return(ErasedReturn::Expr(ty, expr)));
+          Some(())
+        }
+      };
+
+      hoist_scope(&mut fun.scope, &env, &purity);
+
+      if ret.is_some() {
+        if let Some(ScopeInstr::Return(ret)) = fun.scope.instructions.pop() {
+          fun.ret = ret;
+        }
+
+        let len = fun.scope.instructions.len();
+        fun.scope.locations.truncate(len);
+      }
+    }
+  }
+}
+
+fn hoist_scope(scope: &mut ErasedScope, env: &TypeEnv, purity: &Purity) {
+  // handles are unique within a scope, so all the variables of the scope can be declared upfront
+  let mut env = env.clone();
+  for instr in &scope.instructions {
+    match instr {
+      ScopeInstr::VarDecl { ty, handle, .. } => env.declare(handle, ty),
+      ScopeInstr::For {
+        init_ty,
+        init_handle,
+        ..
+      } => env.declare(init_handle, init_ty),
+      _ => (),
+    }
+  }
+
+  for instr in &mut scope.instructions {
+    match instr {
+      ScopeInstr::If { scope, .. }
+      | ScopeInstr::ElseIf { scope, .. }
+      | ScopeInstr::Else { scope }
+      | ScopeInstr::For { scope, .. }
+      | ScopeInstr::While { scope, .. } => hoist_scope(scope, &env, purity),
+      _ => (),
+    }
+  }
+
+  let mut start = 0;
+  while start < scope.instructions.len() {
+    // instructions with side effects are left alone, as they might change what other expressions evaluate to
+    if !instr_slots(&scope.instructions[start])
+      .into_iter()
+      .all(|expr| purity.is_pure(expr))
+    {
+      start += 1;
+      continue;
+    }
+
+    let mut end = start;
+    while !ends_run(&scope.instructions[end])
+      && end + 1 < scope.instructions.len()
+      && instr_slots(&scope.instructions[end + 1])
+        .into_iter()
+        .all(|expr| purity.is_pure(expr))
+    {
+      end += 1;
+    }
+
+    end = hoist_run(scope, start, end, &mut env, purity);
+    start = end + 1;
+  }
+}
+
+// Whether an instruction ends a run of instructions sharing subexpressions: either it assigns a variable, or what
+// comes next is not always evaluated right after it.
+fn ends_run(instr: &ScopeInstr) -> bool {
+  !matches!(instr, ScopeInstr::VarDecl { .. })
+}
+
+// Hoist the repeated subexpressions of instructions start..=end; return the new index of the last instruction.
+fn hoist_run(
+  scope: &mut ErasedScope,
+  start: usize,
+  mut end: usize,
+  env: &mut TypeEnv,
+  purity: &Purity,
+) -> usize {
+  loop {
+    let counts = count_subexprs(
+      scope.instructions[start..=end]
+        .iter()
+        .flat_map(instr_slots)
+        .collect(),
+    );
+
+    // hoist the biggest expressions first, so that their subexpressions are not hoisted needlessly
+    let candidate = counts
+      .into_iter()
+      .filter(|(expr, count)| *count > 1 && is_hoistable(expr, env, purity))
+      .max_by_key(|(expr, _)| expr_size(expr))
+      .map(|(expr, _)| expr.clone());
+
+    let expr = match candidate {
+      Some(expr) => expr,
+      None => return end,
+    };
+
+    // is_hoistable checked the expression is typed
+    let ty = match env.type_of(&expr) {
+      Some(ty) => ty,
+      None => return end,
+    };
+    let handle = ScopedHandle::fun_var(scope.id, scope.next_var);
+    scope.next_var += 1;
+
+    // instructions are rewritten in order, so the first replacement happens in the first instruction using the
+    // expression, even if it is shared with later ones
+    let mut first = None;
+    let mut rewrites = Rewrites::default();
+    for index in start..=end {
+      let mut replaced = false;
+
+      for slot in instr_slots_mut(&mut scope.instructions[index]) {
+        replace_subexpr(slot, &expr, &handle, &mut replaced, &mut rewrites);
+      }
+
+      if replaced && first.is_none() {
+        first = Some(index);
+      }
+    }
+
+    env.declare(&handle, &ty);
+    // the variable is attributed to the instruction it was first hoisted from
+    let index = first.unwrap_or(start);
+    let location = scope.location(index);
+    scope.insert(
+      index,
+      ScopeInstr::VarDecl {
+        ty,
+        handle,
+        init_value: expr,
+      },
+      location,
+    );
+    end += 1;
+  }
+}
+
+// Expressions of an instruction evaluated unconditionally, once, when the instruction runs.
+fn instr_slots(instr: &ScopeInstr) -> Vec<&ErasedExpr> {
+  match instr {
+    ScopeInstr::VarDecl { init_value, .. } => vec![init_value],
+    ScopeInstr::Return(ErasedReturn::Expr(_, expr)) => vec![expr],
+    ScopeInstr::If { condition, .. } => vec![condition],
+    ScopeInstr::For { init_expr, .. } => vec![init_expr],
+    ScopeInstr::MutateVar { var, expr } => {
+      let mut slots = vec![expr];
+      lvalue_indices(var, &mut slots);
+      slots
+    }
+    _ => Vec::new(),
+  }
+}
+
+fn instr_slots_mut(instr: &mut ScopeInstr) -> Vec<&mut ErasedExpr> {
+  fn lvalue_indices_mut<'a>(expr: &'a mut ErasedExpr, indices: &mut Vec<&'a mut ErasedExpr>) {
+    match expr {
+      ErasedExpr::ArrayLookup { object, index } => {
+        lvalue_indices_mut(Arc::make_mut(object), indices);
+        indices.push(Arc::make_mut(index));
+      }
+      ErasedExpr::Field { object, .. } | ErasedExpr::Swizzle(object, _) => {
+        lvalue_indices_mut(Arc::make_mut(object), indices)
+      }
+      _ => (),
+    }
+  }
+
+  match instr {
+    ScopeInstr::VarDecl { init_value, .. } => vec![init_value],
+    ScopeInstr::Return(ErasedReturn::Expr(_, expr)) => vec![expr],
+    ScopeInstr::If { condition, .. } => vec![condition],
+    ScopeInstr::For { init_expr, .. } => vec![init_expr],
+    ScopeInstr::MutateVar { var, expr } => {
+      let mut slots = vec![expr];
+      lvalue_indices_mut(var, &mut slots);
+      slots
+    }
+    _ => Vec::new(),
+  }
+}
+
+// Index expressions of an assigned expression.
+fn lvalue_indices<'a>(expr: &'a ErasedExpr, indices: &mut Vec<&'a ErasedExpr>) {
+  match expr {
+    ErasedExpr::ArrayLookup { object, index } => {
+      lvalue_indices(object, indices);
+      indices.push(index);
+    }
+    ErasedExpr::Field { object, .. } | ErasedExpr::Swizzle(object, _) => {
+      lvalue_indices(object, indices)
+    }
+    _ => (),
+  }
+}
+
+// Count how many times the subexpressions always evaluated when some expressions are get evaluated, equal
+// subexpressions being counted together.
+fn count_subexprs(exprs: Vec<&ErasedExpr>) -> Vec<(&ErasedExpr, usize)> {
+  // the right operand of && and || is only evaluated depending on the left one
+  fn evaluated(expr: &ErasedExpr) -> Vec<&ErasedExpr> {
+    match expr {
+      ErasedExpr::And(a, _) | ErasedExpr::Or(a, _) => vec![a],
+      _ => children(expr),
+    }
+  }
+
+  // shared subexpressions come once, after all the expressions using them
+  fn sort<'a>(
+    expr: &'a ErasedExpr,
+    visited: &mut HashSet<*const ErasedExpr>,
+    sorted: &mut Vec<&'a ErasedExpr>,
+  ) {
+    if visited.insert(expr) {
+      for child in evaluated(expr) {
+        sort(child, visited, sorted);
+      }
+
+      sorted.push(expr);
+    }
+  }
+
+  let mut sorted = Vec::new();
+  let mut visited = HashSet::new();
+  for expr in &exprs {
+    sort(expr, &mut visited, &mut sorted);
+  }
+
+  // a subexpression is evaluated once per path leading to it
+  let mut paths: HashMap<*const ErasedExpr, usize> = HashMap::new();
+  for expr in &exprs {
+    paths.insert(*expr, 1);
+  }
+
+  let mut counts: Vec<(&ErasedExpr, usize)> = Vec::new();
+  for expr in sorted.into_iter().rev() {
+    let count = paths[&(expr as *const _)];
+
+    for child in evaluated(expr) {
+      let paths = paths.entry(child).or_default();
+      *paths = paths.saturating_add(count);
+    }
+
+    match counts.iter_mut().find(|(e, _)| *e == expr) {
+      Some((_, total)) => *total = total.saturating_add(count),
+      None => counts.push((expr, count)),
+    }
+  }
+
+  counts
+}
+
+// Replace the occurrences of a subexpression by a variable, recording whether any was replaced.
+fn replace_subexpr(
+  expr: &mut ErasedExpr,
+  subexpr: &ErasedExpr,
+  handle: &ScopedHandle,
+  replaced: &mut bool,
+  rewrites: &mut Rewrites,
+) {
+  if expr == subexpr {
+    *expr = ErasedExpr::Var(handle.clone());
+    *replaced = true;
+    return;
+  }
+
+  rewrites.children(expr, &mut |child, rewrites| {
+    replace_subexpr(child, subexpr, handle, replaced, rewrites)
+  });
+}
+
+fn expr_size(expr: &ErasedExpr) -> usize {
+  let mut size = 0;
+  walk_expr(expr, &mut |_| size += 1);
+  size
+}
+
+// Whether computing an expression once into a variable is worth it.
+fn is_hoistable(expr: &ErasedExpr, env: &TypeEnv, purity: &Purity) -> bool {
+  // accessing variables is as cheap as accessing a temporary
+  fn is_access(expr: &ErasedExpr) -> bool {
+    match expr {
+      ErasedExpr::Var(_) => true,
+      ErasedExpr::Swizzle(object, _) | ErasedExpr::Field { object, .. } => is_access(object),
+      ErasedExpr::ArrayLookup { object, index } => {
+        is_access(object) && (is_literal(index) || matches!(**index, ErasedExpr::Var(_)))
+      }
+      _ => false,
+    }
+  }
+
+  let is_constructor = matches!(
+    expr,
+    ErasedExpr::FunCall(ErasedFunHandle::Vec2, _)
+      | ErasedExpr::FunCall(ErasedFunHandle::Vec3, _)
+      | ErasedExpr::FunCall(ErasedFunHandle::Vec4, _)
+      | ErasedExpr::Array(..)
+  );
+
+  !is_literal(expr)
+    && !is_access(expr)
+    && !is_constructor
+    && purity.is_pure(expr)
+    && matches!(env.type_of(expr), Some(ty) if ty.array_dims.is_empty())
+}
+
+// Variable an assigned expression refers to.
+fn lvalue_root(expr: &ErasedExpr) -> Option<&ScopedHandle> {
+  match expr {
+    ErasedExpr::Var(handle) => Some(handle),
+    ErasedExpr::ArrayLookup { object, .. }
+    | ErasedExpr::Field { object, .. }
+    | ErasedExpr::Swizzle(object, _) => lvalue_root(object),
+    _ => None,
+  }
+}
+
+// All the expressions of a function, including assigned ones and the returned one.
+pub(crate) fn fun_exprs(fun: &ErasedFun) -> Vec<&ErasedExpr> {
+  let mut exprs = instrs_exprs(&fun.scope.instructions);
+
+  if let ErasedReturn::Expr(_, expr) = &fun.ret {
+    exprs.push(expr);
+  }
+
+  exprs
+}
+
+// Mutable version of fun_exprs.
+pub(crate) fn fun_exprs_mut(fun: &mut ErasedFun) -> Vec<&mut ErasedExpr> {
+  fn scope_exprs_mut<'a>(instructions: &'a mut [ScopeInstr], exprs: &mut Vec<&'a mut ErasedExpr>) {
+    for instr in instructions {
+      match instr {
+        ScopeInstr::VarDecl { init_value, .. } => exprs.push(init_value),
+
+        ScopeInstr::Return(ErasedReturn::Expr(_, expr)) => exprs.push(expr),
+
+        ScopeInstr::Return(ErasedReturn::Void)
+        | ScopeInstr::Continue
+        | ScopeInstr::Break
+        | ScopeInstr::Discard => (),
+
+        ScopeInstr::If { condition, scope }
+        | ScopeInstr::ElseIf { condition, scope }
+        | ScopeInstr::While { condition, scope } => {
+          exprs.push(condition);
+          scope_exprs_mut(&mut scope.instructions, exprs);
+        }
+
+        ScopeInstr::Else { scope } => scope_exprs_mut(&mut scope.instructions, exprs),
+
+        ScopeInstr::For {
+          init_expr,
+          condition,
+          post_expr,
+          scope,
+          ..
+        } => {
+          exprs.push(init_expr);
+          exprs.push(condition);
+          exprs.push(post_expr);
+          scope_exprs_mut(&mut scope.instructions, exprs);
+        }
+
+        ScopeInstr::MutateVar { var, expr } => {
+          exprs.push(var);
+          exprs.push(expr);
+        }
+      }
+    }
+  }
+
+  let mut exprs = Vec::new();
+  scope_exprs_mut(&mut fun.scope.instructions, &mut exprs);
+
+  if let ErasedReturn::Expr(_, expr) = &mut fun.ret {
+    exprs.push(expr);
+  }
+
+  exprs
+}
+
+// All the expressions of instructions, including the ones of their nested scopes.
+pub(crate) fn instrs_exprs(instructions: &[ScopeInstr]) -> Vec<&ErasedExpr> {
+  fn scope_exprs<'a>(instructions: &'a [ScopeInstr], exprs: &mut Vec<&'a ErasedExpr>) {
+    for instr in instructions {
+      match instr {
+        ScopeInstr::VarDecl { init_value, .. } => exprs.push(init_value),
+
+        ScopeInstr::Return(ErasedReturn::Expr(_, expr)) => exprs.push(expr),
+
+        ScopeInstr::Return(ErasedReturn::Void)
+        | ScopeInstr::Continue
+        | ScopeInstr::Break
+        | ScopeInstr::Discard => (),
+
+        ScopeInstr::If { condition, scope }
+        | ScopeInstr::ElseIf { condition, scope }
+        | ScopeInstr::While { condition, scope } => {
+          exprs.push(condition);
+          scope_exprs(&scope.instructions, exprs);
+        }
+
+        ScopeInstr::Else { scope } => scope_exprs(&scope.instructions, exprs),
+
+        ScopeInstr::For {
+          init_expr,
+          condition,
+          post_expr,
+          scope,
+          ..
+        } => {
+          exprs.extend([init_expr, condition, post_expr].iter().copied());
+          scope_exprs(&scope.instructions, exprs);
+        }
+
+        ScopeInstr::MutateVar { var, expr } => {
+          exprs.push(var);
+          exprs.push(expr);
+        }
+      }
+    }
+  }
+
+  let mut exprs = Vec::new();
+  scope_exprs(instructions, &mut exprs);
+  exprs
+}
+
+// Visit an expression and all its sub-expressions; sub-expressions shared by several parents are visited once.
+pub(crate) fn walk_expr<'a>(expr: &'a ErasedExpr, f: &mut impl FnMut(&'a ErasedExpr)) {
+  fn walk<'a>(
+    expr: &'a ErasedExpr,
+    f: &mut impl FnMut(&'a ErasedExpr),
+    visited: &mut HashSet<*const ErasedExpr>,
+  ) {
+    if visited.insert(expr) {
+      f(expr);
+
+      for child in children(expr) {
+        walk(child, f, visited);
+      }
+    }
+  }
+
+  walk(expr, f, &mut HashSet::new());
+}
+
+// Mutable version of walk_expr.
+pub(crate) fn walk_expr_mut(expr: &mut ErasedExpr, f: &mut impl FnMut(&mut ErasedExpr)) {
+  fn walk(expr: &mut ErasedExpr, f: &mut impl FnMut(&mut ErasedExpr), rewrites: &mut Rewrites) {
+    f(expr);
+    rewrites.children(expr, &mut |child, rewrites| walk(child, f, rewrites));
+  }
+
+  walk(expr, f, &mut Rewrites::default());
+}
+
+// Shared sub-expressions already rewritten, by address of the original ones.
+//
+// Expressions are graphs: a sub-expression can be shared by several parents, and rewriting it once per parent would
+// take exponential time. The originals are kept alive so that their address is not reused while rewriting.
+#[derive(Debug, Default)]
+struct Rewrites(HashMap<*const ErasedExpr, (Arc<ErasedExpr>, Arc<ErasedExpr>)>);
+
+impl Rewrites {
+  // Rewrite the direct sub-expressions of an expression.
+  fn children(&mut self, expr: &mut ErasedExpr, f: &mut dyn FnMut(&mut ErasedExpr, &mut Self)) {
+    for child in child_slots(expr) {
+      self.rewrite(child, f);
+    }
+  }
+
+  // Rewrite a sub-expression that might be shared, reusing its previous rewrite if any.
+  fn rewrite(&mut self, expr: &mut Arc<ErasedExpr>, f: &mut dyn FnMut(&mut ErasedExpr, &mut Self)) {
+    if let Some(expr) = Arc::get_mut(expr) {
+      return f(expr, self);
+    }
+
+    let original = Arc::as_ptr(expr);
+    if let Some((_, rewritten)) = self.0.get(&original) {
+      *expr = rewritten.clone();
+      return;
+    }
+
+    let original = expr.clone();
+    f(Arc::make_mut(expr), self);
+    self
+      .0
+      .insert(Arc::as_ptr(&original), (original, expr.clone()));
+  }
+}
+
+// Direct sub-expressions of an expression that are evaluated as values.
+fn children(expr: &ErasedExpr) -> Vec<&ErasedExpr> {
+  match expr {
+    ErasedExpr::Array(_, items) | ErasedExpr::FunCall(_, items) => {
+      items.iter().map(|item| &**item).collect()
+    }
+
+    ErasedExpr::Not(a) | ErasedExpr::Neg(a) | ErasedExpr::Swizzle(a, _) => vec![a],
+
+    ErasedExpr::And(a, b)
+    | ErasedExpr::Or(a, b)
+    | ErasedExpr::Xor(a, b)
+    | ErasedExpr::BitOr(a, b)
+    | ErasedExpr::BitAnd(a, b)
+    | ErasedExpr::BitXor(a, b)
+    | ErasedExpr::Add(a, b)
+    | ErasedExpr::Sub(a, b)
+    | ErasedExpr::Mul(a, b)
+    | ErasedExpr::Div(a, b)
+    | ErasedExpr::Rem(a, b)
+    | ErasedExpr::Shl(a, b)
+    | ErasedExpr::Shr(a, b)
+    | ErasedExpr::Eq(a, b)
+    | ErasedExpr::Neq(a, b)
+    | ErasedExpr::Lt(a, b)
+    | ErasedExpr::Lte(a, b)
+    | ErasedExpr::Gt(a, b)
+    | ErasedExpr::Gte(a, b) => vec![a, b],
+
+    // the field is a built-in name, not a value
+    ErasedExpr::Field { object, .. } => vec![object],
+
+    ErasedExpr::ArrayLookup { object, index } => vec![object, index],
+
+    _ => Vec::new(),
+  }
+}
+
+// Mutable version of children.
+fn child_slots(expr: &mut ErasedExpr) -> Vec<&mut Arc<ErasedExpr>> {
+  match expr {
+    ErasedExpr::Array(_, items) | ErasedExpr::FunCall(_, items) => items.iter_mut().collect(),
+
+    ErasedExpr::Not(a) | ErasedExpr::Neg(a) | ErasedExpr::Swizzle(a, _) => vec![a],
+
+    ErasedExpr::And(a, b)
+    | ErasedExpr::Or(a, b)
+    | ErasedExpr::Xor(a, b)
+    | ErasedExpr::BitOr(a, b)
+    | ErasedExpr::BitAnd(a, b)
+    | ErasedExpr::BitXor(a, b)
+    | ErasedExpr::Add(a, b)
+    | ErasedExpr::Sub(a, b)
+    | ErasedExpr::Mul(a, b)
+    | ErasedExpr::Div(a, b)
+    | ErasedExpr::Rem(a, b)
+    | ErasedExpr::Shl(a, b)
+    | ErasedExpr::Shr(a, b)
+    | ErasedExpr::Eq(a, b)
+    | ErasedExpr::Neq(a, b)
+    | ErasedExpr::Lt(a, b)
+    | ErasedExpr::Lte(a, b)
+    | ErasedExpr::Gt(a, b)
+    | ErasedExpr::Gte(a, b) => vec![a, b],
+
+    ErasedExpr::Field { object, .. } => vec![object],
+
+    ErasedExpr::ArrayLookup { object, index } => vec![object, index],
+
+    _ => Vec::new(),
+  }
+}
+
+fn is_literal(expr: &ErasedExpr) -> bool {
+  matches!(
+    expr,
+    ErasedExpr::LitInt(_)
+      | ErasedExpr::LitUInt(_)
+      | ErasedExpr::LitFloat(_)
+      | ErasedExpr::LitBool(_)
+      | ErasedExpr::LitInt2(_)
+      | ErasedExpr::LitUInt2(_)
+      | ErasedExpr::LitFloat2(_)
+      | ErasedExpr::LitBool2(_)
+      | ErasedExpr::LitInt3(_)
+      | ErasedExpr::LitUInt3(_)
+      | ErasedExpr::LitFloat3(_)
+      | ErasedExpr::LitBool3(_)
+      | ErasedExpr::LitInt4(_)
+      | ErasedExpr::LitUInt4(_)
+      | ErasedExpr::LitFloat4(_)
+      | ErasedExpr::LitBool4(_)
+      | ErasedExpr::LitM22(_)
+      | ErasedExpr::LitM33(_)
+      | ErasedExpr::LitM44(_)
+  )
+}
+
+fn is_float_literal(expr: &ErasedExpr) -> bool {
+  matches!(
+    expr,
+    ErasedExpr::LitFloat(_)
+      | ErasedExpr::LitFloat2(_)
+      | ErasedExpr::LitFloat3(_)
+      | ErasedExpr::LitFloat4(_)
+      | ErasedExpr::LitM22(_)
+      | ErasedExpr::LitM33(_)
+      | ErasedExpr::LitM44(_)
+  )
+}
+
+fn is_matrix_literal(expr: &ErasedExpr) -> bool {
+  matches!(
+    expr,
+    ErasedExpr::LitM22(_) | ErasedExpr::LitM33(_) | ErasedExpr::LitM44(_)
+  )
+}
+
+fn has_negative_int(expr: &ErasedExpr) -> bool {
+  match expr {
+    ErasedExpr::LitInt(a) => *a < 0,
+    ErasedExpr::LitInt2(a) => a.iter().any(|a| *a < 0),
+    ErasedExpr::LitInt3(a) => a.iter().any(|a| *a < 0),
+    ErasedExpr::LitInt4(a) => a.iter().any(|a| *a < 0),
+    _ => false,
+  }
+}
+
+fn has_nan(expr: &ErasedExpr) -> bool {
+  match expr {
+    ErasedExpr::LitFloat(a) => a.is_nan(),
+    ErasedExpr::LitFloat2(a) => a.iter().any(|a| a.is_nan()),
+    ErasedExpr::LitFloat3(a) => a.iter().any(|a| a.is_nan()),
+    ErasedExpr::LitFloat4(a) => a.iter().any(|a| a.is_nan()),
+    _ => false,
+  }
+}
+
+// Shifting by a negative amount or by at least the bit width is undefined in GLSL.
+fn is_shift_amount(expr: &ErasedExpr) -> bool {
+  match expr {
+    ErasedExpr::LitInt(a) => (0..32).contains(a),
+    ErasedExpr::LitUInt(a) => *a < 32,
+    _ => false,
+  }
+}
+
+// Built-in functions computing an exact result.
+fn is_exact_builtin(handle: &ErasedFunHandle) -> bool {
+  matches!(
+    handle,
+    ErasedFunHandle::Vec2
+      | ErasedFunHandle::Vec3
+      | ErasedFunHandle::Vec4
+      | ErasedFunHandle::Abs
+      | ErasedFunHandle::Sign
+      | ErasedFunHandle::Floor
+      | ErasedFunHandle::Trunc
+      | ErasedFunHandle::RoundEven
+      | ErasedFunHandle::Ceil
+      | ErasedFunHandle::Fract
+      | ErasedFunHandle::Min
+      | ErasedFunHandle::Max
+      | ErasedFunHandle::Clamp
+      | ErasedFunHandle::Step
+      | ErasedFunHandle::FloatBitsToInt
+      | ErasedFunHandle::IntBitsToFloat
+      | ErasedFunHandle::UIntBitsToFloat
+      | ErasedFunHandle::VLt
+      | ErasedFunHandle::VLte
+      | ErasedFunHandle::VGt
+      | ErasedFunHandle::VGte
+      | ErasedFunHandle::VEq
+      | ErasedFunHandle::VNeq
+      | ErasedFunHandle::VAny
+      | ErasedFunHandle::VAll
+      | ErasedFunHandle::VNot
+      | ErasedFunHandle::BitfieldReverse
+      | ErasedFunHandle::BitCount
+      | ErasedFunHandle::FindLSB
+      | ErasedFunHandle::FindMSB
+  )
+}
+
+// Evaluate an expression whose operands are all literals, if its result is exactly defined.
+fn fold(expr: &ErasedExpr) -> Option<ErasedExpr> {
+  let foldable = match expr {
+    ErasedExpr::Not(a) | ErasedExpr::Neg(a) | ErasedExpr::Swizzle(a, _) => is_literal(a),
+
+    ErasedExpr::Div(a, b) | ErasedExpr::Rem(a, b) => {
+      is_literal(a)
+        && is_literal(b)
+        && !is_float_literal(a)
+        && !has_negative_int(a)
+        && !has_negative_int(b)
+    }
+
+    ErasedExpr::Mul(a, b) => {
+      is_literal(a) && is_literal(b) && !is_matrix_literal(a) && !is_matrix_literal(b)
+    }
+
+    ErasedExpr::Shl(a, b) | ErasedExpr::Shr(a, b) => is_literal(a) && is_shift_amount(b),
+
+    ErasedExpr::And(a, b)
+    | ErasedExpr::Or(a, b)
+    | ErasedExpr::Xor(a, b)
+    | ErasedExpr::BitOr(a, b)
+    | ErasedExpr::BitAnd(a, b)
+    | ErasedExpr::BitXor(a, b)
+    | ErasedExpr::Add(a, b)
+    | ErasedExpr::Sub(a, b)
+    | ErasedExpr::Eq(a, b)
+    | ErasedExpr::Neq(a, b)
+    | ErasedExpr::Lt(a, b)
+    | ErasedExpr::Lte(a, b)
+    | ErasedExpr::Gt(a, b)
+    | ErasedExpr::Gte(a, b) => is_literal(a) && is_literal(b),
+
+    // NaN handling is implementation-defined for most built-ins
+    ErasedExpr::FunCall(handle, args) => {
+      is_exact_builtin(handle) && args.iter().all(|arg| is_literal(arg) && !has_nan(arg))
+    }
+
+    _ => false,
+  };
+
+  if foldable {
+    interpreter::eval_constant(expr).and_then(literal)
+  } else {
+    None
+  }
+}
+
+// Literal expression of a value; non-finite floats have no literal representation.
+fn literal(value: Value) -> Option<ErasedExpr> {
+  let expr = match value {
+    Value::Int(a) => match a[..] {
+      [x] => ErasedExpr::LitInt(x),
+      [x, y] => ErasedExpr::LitInt2([x, y]),
+      [x, y, z] => ErasedExpr::LitInt3([x, y, z]),
+      [x, y, z, w] => ErasedExpr::LitInt4([x, y, z, w]),
+      _ => return None,
+    },
+
+    Value::UInt(a) => match a[..] {
+      [x] => ErasedExpr::LitUInt(x),
+      [x, y] => ErasedExpr::LitUInt2([x, y]),
+      [x, y, z] => ErasedExpr::LitUInt3([x, y, z]),
+      [x, y, z, w] => ErasedExpr::LitUInt4([x, y, z, w]),
+      _ => return None,
+    },
+
+    Value::Float(a) if a.iter().all(|x| x.is_finite()) => match a[..] {
+      [x] => ErasedExpr::LitFloat(x),
+      [x, y] => ErasedExpr::LitFloat2([x, y]),
+      [x, y, z] => ErasedExpr::LitFloat3([x, y, z]),
+      [x, y, z, w] => ErasedExpr::LitFloat4([x, y, z, w]),
+      _ => return None,
+    },
+
+    Value::Bool(a) => match a[..] {
+      [x] => ErasedExpr::LitBool(x),
+      [x, y] => ErasedExpr::LitBool2([x, y]),
+      [x, y, z] => ErasedExpr::LitBool3([x, y, z]),
+      [x, y, z, w] => ErasedExpr::LitBool4([x, y, z, w]),
+      _ => return None,
+    },
+
+    Value::Matrix(m) => match m.len() {
+      2 => ErasedExpr::LitM22(Matrix(columns(&m)?)),
+      3 => ErasedExpr::LitM33(Matrix(columns(&m)?)),
+      4 => ErasedExpr::LitM44(Matrix(columns(&m)?)),
+      _ => return None,
+    },
+
+    _ => return None,
+  };
+
+  Some(expr)
+}
+
+fn columns<const N: usize>(m: &[Vec<f32>]) -> Option<[[f32; N]; N]> {
+  let mut columns = [[0.; N]; N];
+
+  for (column, col) in columns.iter_mut().zip(m) {
+    if col.len() != N || !col.iter().all(|x| x.is_finite()) {
+      return None;
+    }
+
+    column.copy_from_slice(col);
+  }
+
+  Some(columns)
+}
+
+fn is_zero(expr: &ErasedExpr) -> bool {
+  matches!(expr, ErasedExpr::LitInt(0) | ErasedExpr::LitUInt(0))
+}
+
+fn is_one(expr: &ErasedExpr) -> bool {
+  match expr {
+    ErasedExpr::LitInt(1) | ErasedExpr::LitUInt(1) => true,
+    ErasedExpr::LitFloat(a) => *a == 1.,
+    _ => false,
+  }
+}
+
+// Check a float literal bit-wise, to tell 0. and -0. apart.
+fn is_float_bits(expr: &ErasedExpr, x: f32) -> bool {
+  matches!(expr, ErasedExpr::LitFloat(a) if a.to_bits() == x.to_bits())
+}
+
+// Apply exact algebraic identities. Only scalar literals are simplified away, so that the type of the expression is
+// kept.
+fn simplify(expr: &mut ErasedExpr) {
+  let kept = match expr {
+    // x + -0. is x, even for x = -0.
+    ErasedExpr::Add(a, b) if is_zero(b) || is_float_bits(b, -0.) => a,
+    ErasedExpr::Add(a, b) if is_zero(a) || is_float_bits(a, -0.) => b,
+    ErasedExpr::Sub(a, b) if is_zero(b) || is_float_bits(b, 0.) => a,
+    ErasedExpr::Mul(a, b) if is_one(b) => a,
+    ErasedExpr::Mul(a, b) if is_one(a) => b,
+    ErasedExpr::Div(a, b) if is_one(b) => a,
+    ErasedExpr::And(a, b) if matches!(**b, ErasedExpr::LitBool(true)) => a,
+    ErasedExpr::And(a, b) if matches!(**a, ErasedExpr::LitBool(true)) => b,
+    // the right operand is not evaluated
+    ErasedExpr::And(a, _) if matches!(**a, ErasedExpr::LitBool(false)) => a,
+    ErasedExpr::Or(a, b) if matches!(**b, ErasedExpr::LitBool(false)) => a,
+    ErasedExpr::Or(a, b) if matches!(**a, ErasedExpr::LitBool(false)) => b,
+    ErasedExpr::Or(a, _) if matches!(**a, ErasedExpr::LitBool(true)) => a,
+    ErasedExpr::Not(a) => match Arc::make_mut(a) {
+      ErasedExpr::Not(b) => b,
+      _ => return,
+    },
+    _ => return,
+  };
+
+  let kept = mem::replace(kept, Arc::new(ErasedExpr::LitBool(false)));
+  *expr = Arc::try_unwrap(kept).unwrap_or_else(|kept| (*kept).clone());
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    interpreter::Invocation, lit, Bounded as _, CanEscape as _, Exponential as _, Expr,
+    Floating as _, Relative as _, Scope, ShaderBuilder, Swizzlable as _, Trigonometry as _, V2, V3,
+  };
+
+  fn constant_folded(shader: &Shader) -> Shader {
+    let mut shader = shader.clone();
+    fold_constants(&mut shader);
+    shader
+  }
+
+  fn main_scope(shader: &Shader) -> &ErasedScope {
+    shader
+      .decls
+      .iter()
+      .find_map(|decl| match decl {
+        ShaderDecl::Main(fun) => Some(&fun.scope),
+        _ => None,
+      })
+      .unwrap()
+  }
+
+  fn init_values(shader: &Shader) -> Vec<&ErasedExpr> {
+    main_scope(shader)
+      .instructions
+      .iter()
+      .filter_map(|instr| match instr {
+        ScopeInstr::VarDecl { init_value, .. } => Some(init_value),
+        _ => None,
+      })
+      .collect()
+  }
+
+  #[test]
+  fn fold_literals() {
+    let shader = ShaderBuilder::new_vertex_shader(|mut s, _| {
+      let c = s.constant(lit!(2.) * 3. + 0.);
+
+      s.main_fun(|s: &mut Scope<()>| {
+        let _ = s.var(c * 2.);
+        let zz: Expr<V2<i32>> = crate::sw!(lit!(1, 2, 3), .z.z);
+        let _ = s.var(zz.eq(lit!(3, 3)));
+        let _ = s.var(lit!(-1.5, 2.5).floor());
+        let _ = s.var(lit!(7) / lit!(2) - 1);
+      })
+    });
+    let folded = constant_folded(&shader);
+
+    assert!(matches!(
+      folded.decls[0],
+      ShaderDecl::Const(_, _, ErasedExpr::LitFloat(x), _) if x == 6.
+    ));
+    assert_eq!(
+      init_values(&folded),
+      vec![
+        &ErasedExpr::LitFloat(12.),
+        &ErasedExpr::LitBool(true),
+        &ErasedExpr::LitFloat2([-2., 2.]),
+        &ErasedExpr::LitInt(2),
+      ]
+    );
+  }
+
+  #[test]
+  fn identities() {
+    let shader = ShaderBuilder::new_vertex_shader(|mut s, _| {
+      let x = s.input::<V3<f32>>("x").unwrap();
+      let i = s.input::<i32>("i").unwrap();
+      let b = s.input::<bool>("b").unwrap();
+
+      s.main_fun(|s: &mut Scope<()>| {
+        let _ = s.var(x.clone() * 1. - 0.);
+        let _ = s.var(lit!(0) + i.clone() * 1);
+        let _ = s.var((!!b.clone()).and(true));
+        // kept, as -0. + 0. is 0.
+        let _ = s.var(x.clone() + 0.);
+      })
+    });
+    let folded = constant_folded(&shader);
+    let x = ErasedExpr::Var(ScopedHandle::Input("x".to_owned()));
+
+    assert_eq!(
+      init_values(&folded),
+      vec![
+        &x,
+        &ErasedExpr::Var(ScopedHandle::Input("i".to_owned())),
+        &ErasedExpr::Var(ScopedHandle::Input("b".to_owned())),
+        &ErasedExpr::Add(Arc::new(x.clone()), Arc::new(ErasedExpr::LitFloat(0.))),
+      ]
+    );
+  }
+
+  #[test]
+  fn inexact_operations_are_kept() {
+    let shader = ShaderBuilder::new_vertex_shader(|s, _| {
+      s.main_fun(|s: &mut Scope<()>| {
+        let _ = s.var(lit!(1.).sin());
+        let _ = s.var(lit!(1.) / 3.);
+        let _ = s.var(lit!(1) / lit!(0));
+        let _ = s.var(lit!(-7) / lit!(2));
+        let _ = s.var(lit!(1) << lit!(32u32));
+      })
+    });
+    let folded = constant_folded(&shader);
+
+    assert_eq!(init_values(&folded), init_values(&shader));
+  }
+
+  #[test]
+  fn same_semantics() {
+    let shader = ShaderBuilder::new_fragment_shader(|mut s, _| {
+      let x = s.uniform::<V2<f32>>("x").unwrap();
+      let color = s.output::<V2<f32>>("color").unwrap();
+      let c = s.constant(lit!(1.5, -2.).abs() * 2. - 0.);
+
+      s.main_fun(|s: &mut Scope<()>| {
+        let y = s.var(x.clone() * 1. + c.clone().max(Expr::from(V2::from([4., 0.]))));
+        s.set(&color, y.clone() - lit!(-0.5, 0.5).fract());
+      })
+    });
+    let folded = constant_folded(&shader);
+
+    for x in [[0., 1.], [-0., 2.5], [1e20, -3.]].iter() {
+      let run = |shader| {
+        Invocation::new(shader)
+          .uniform("x", V2::from(*x))
+          .run()
+          .unwrap()
+      };
+
+      assert_eq!(run(&shader), run(&folded));
+    }
+  }
+  fn declarations(shader: &Shader) -> Vec<String> {
+    shader
+      .decls
+      .iter()
+      .map(|decl| match decl {
+        ShaderDecl::Main(_) => "main".to_owned(),
+        ShaderDecl::FunDef(handle, _) => format!("fun_{}", handle),
+        ShaderDecl::Const(handle, ..) => format!("glob_{}", handle),
+        ShaderDecl::In(name, _) | ShaderDecl::Out(name, ..) | ShaderDecl::Uniform(name, _) => {
+          name.clone()
+        }
+      })
+      .collect()
+  }
+
+  #[test]
+  fn unreachable_declarations() {
+    let shader = ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+      let used_input = s.input::<f32>("used_input").unwrap();
+      let _ = s.input::<f32>("unused_input").unwrap();
+      let used_uniform = s.uniform::<f32>("used_uniform").unwrap();
+      let _ = s.uniform::<f32>("unused_uniform").unwrap();
+      let _ = s.output::<f32>("unused_output").unwrap();
+      let used_const = s.constant(lit!(1.));
+      let _ = s.constant(lit!(2.));
+      let used_fun = s.fun(move |_: &mut Scope<Expr<f32>>, a: Expr<f32>| a * used_const.clone());
+      let _ = s.fun(|_: &mut Scope<Expr<f32>>, a: Expr<f32>| a);
+      let caller = s.fun(move |_: &mut Scope<Expr<f32>>, a: Expr<f32>| used_fun.call(a));
+
+      s.main_fun(|s: &mut Scope<()>| {
+        let x = caller.call(used_input * used_uniform);
+        s.set(&vertex.position, crate::vec4!(x, 0., 0., 1.));
+      })
+    });
+
+    let mut eliminated = shader.clone();
+    eliminate_dead_code(&mut eliminated, false);
+    assert_eq!(
+      declarations(&eliminated),
+      vec![
+        "used_input",
+        "unused_input",
+        "used_uniform",
+        "unused_uniform",
+        "unused_output",
+        "glob_0",
+        "fun_0",
+        "fun_2",
+        "main"
+      ]
+    );
+
+    let mut eliminated = shader;
+    eliminate_dead_code(&mut eliminated, true);
+    assert_eq!(
+      declarations(&eliminated),
+      vec![
+        "used_input",
+        "used_uniform",
+        "unused_output",
+        "glob_0",
+        "fun_0",
+        "fun_2",
+        "main"
+      ]
+    );
+  }
+
+  #[test]
+  fn unused_variables() {
+    let shader = ShaderBuilder::new_fragment_shader(|mut s, fragment| {
+      let color = s.output::<f32>("color").unwrap();
+      let pure = s.fun(|_: &mut Scope<Expr<f32>>, a: Expr<f32>| a * 2.);
+      let impure = s.fun(move |s: &mut Scope<Expr<f32>>, a: Expr<f32>| {
+        s.set(&color, a.clone());
+        a
+      });
+
+      s.main_fun(|s: &mut Scope<()>| {
+        // only used by an unused variable
+        let a = s.var(lit!(1.));
+        let _ = s.var(pure.call(a.into()));
+        // kept for its side effect
+        let _ = s.var(impure.call(lit!(2.)));
+        let used = s.var(lit!(3.));
+        s.set(&fragment.frag_depth, used);
+      })
+    });
+
+    let mut eliminated = shader;
+    eliminate_dead_code(&mut eliminated, true);
+
+    assert_eq!(
+      main_scope(&eliminated)
+        .instructions
+        .iter()
+        .filter_map(|instr| match instr {
+          ScopeInstr::VarDecl { handle, .. } => Some(handle.clone()),
+          _ => None,
+        })
+        .collect::<Vec<_>>(),
+      vec![ScopedHandle::fun_var(2, 2), ScopedHandle::fun_var(2, 3)]
+    );
+    // the pure function is not called anymore
+    assert_eq!(declarations(&eliminated), vec!["color", "fun_1", "main"]);
+  }
+
+  #[test]
+  fn hoist_repeated_subexpressions() {
+    let shader = ShaderBuilder::new_fragment_shader(|mut s, fragment| {
+      let x = s.input::<f32>("x").unwrap();
+      let expensive = s.fun(|_: &mut Scope<Expr<f32>>, a: Expr<f32>| a.sin() * a.cos());
+
+      s.main_fun(|s: &mut Scope<()>| {
+        let a = s.var(expensive.call(x.clone()) * 2.);
+        let b = s.var(expensive.call(x.clone()) + 1.);
+        s.set(&fragment.frag_depth, a + b);
+      })
+    });
+
+    let mut hoisted = shader.clone();
+    eliminate_common_subexpressions(&mut hoisted);
+
+    let call = ErasedExpr::FunCall(
+      ErasedFunHandle::UserDefined(0),
+      vec![Arc::new(ErasedExpr::Var(ScopedHandle::Input(
+        "x".to_owned(),
+      )))],
+    );
+    let temp = ErasedExpr::Var(ScopedHandle::fun_var(1, 2));
+
+    assert_eq!(
+      init_values(&hoisted),
+      vec![
+        &call,
+        &ErasedExpr::Mul(Arc::new(temp.clone()), Arc::new(ErasedExpr::LitFloat(2.))),
+        &ErasedExpr::Add(Arc::new(temp), Arc::new(ErasedExpr::LitFloat(1.))),
+      ]
+    );
+
+    // the function body only evaluates its argument, which is not worth a variable
+    match &hoisted.decls[1] {
+      ShaderDecl::FunDef(_, fun) => assert!(fun.scope.instructions.is_empty()),
+      _ => panic!("function expected"),
+    }
+
+    for x in [0., 1., -2.5].iter() {
+      let run = |shader| Invocation::new(shader).input("x", *x).run().unwrap();
+      assert_eq!(run(&shader), run(&hoisted));
+    }
+  }
+
+  #[test]
+  fn hoist_in_returned_expression() {
+    let shader = ShaderBuilder::new_vertex_shader(|mut s, _| {
+      let _ = s.fun(|_: &mut Scope<Expr<f32>>, a: Expr<f32>| a.sin() * a.sin());
+      s.main_fun(|_: &mut Scope<()>| {})
+    });
+
+    let mut hoisted = shader;
+    eliminate_common_subexpressions(&mut hoisted);
+
+    match &hoisted.decls[0] {
+      ShaderDecl::FunDef(_, fun) => {
+        let temp = ScopedHandle::fun_var(0, 0);
+
+        assert_eq!(
+          fun.scope.instructions,
+          vec![ScopeInstr::VarDecl {
+            ty: <f32 as crate::ToType>::ty(),
+            handle: temp.clone(),
+            init_value: ErasedExpr::FunCall(
+              ErasedFunHandle::Sin,
+              vec![Arc::new(ErasedExpr::Var(ScopedHandle::FunArg(0)))]
+            ),
+          }]
+        );
+        assert_eq!(
+          fun.ret,
+          ErasedReturn::Expr(
+            <f32 as crate::ToType>::ty(),
+            ErasedExpr::Mul(
+              Arc::new(ErasedExpr::Var(temp.clone())),
+              Arc::new(ErasedExpr::Var(temp))
+            )
+          )
+        );
+      }
+      _ => panic!("function expected"),
+    }
+  }
+
+  #[test]
+  fn assignments_split_runs() {
+    let shader = ShaderBuilder::new_fragment_shader(|mut s, fragment| {
+      let x = s.input::<f32>("x").unwrap();
+
+      s.main_fun(|s: &mut Scope<()>| {
+        let y = s.var(x.clone());
+        let a = s.var(y.clone().sqrt() * 2.);
+        s.set(&y, y.clone() + 1.);
+        let b = s.var(y.clone().sqrt() * 2.);
+        // the right operand of && is not always evaluated
+        let c = s.var(x.clone().lt(1.).and(y.clone().sqrt().lt(2.)));
+        s.when(c, |s| s.set(&fragment.frag_depth, a + b));
+      })
+    });
+
+    let mut hoisted = shader.clone();
+    eliminate_common_subexpressions(&mut hoisted);
+
+    assert_eq!(init_values(&hoisted), init_values(&shader));
+
+    for x in [0., 3., 0.5].iter() {
+      let run = |shader| Invocation::new(shader).input("x", *x).run().unwrap();
+      assert_eq!(run(&shader), run(&hoisted));
+    }
+  }
+
+  #[test]
+  fn shared_subexpressions() {
+    // each level uses the previous one twice: walked as a tree, the expression would have about 2^64 nodes
+    let shader = ShaderBuilder::new_fragment_shader(|mut s, _| {
+      let x = s.input::<f32>("x").unwrap();
+      let y = s.output::<f32>("y").unwrap();
+
+      s.main_fun(|s: &mut Scope<()>| {
+        let mut e = x.clone();
+        for _ in 0..64 {
+          e = e.clone() * e + 0.5;
+        }
+
+        s.set(&y, e);
+      })
+    });
+
+    assert!(crate::validation::validate(&shader).is_empty());
+    assert_eq!(shader, shader.clone());
+    assert_eq!(shader.fingerprint(), shader.clone().fingerprint());
+
+    let mut optimized = shader.clone();
+    fold_constants(&mut optimized);
+    eliminate_dead_code(&mut optimized, true);
+    assert_eq!(optimized, shader);
+
+    // every level but the input is computed once into a variable
+    eliminate_common_subexpressions(&mut optimized);
+    assert_eq!(init_values(&optimized).len(), 63);
+
+    let expected = (0..64).fold(0.25f32, |e, _| e * e + 0.5);
+    let outputs = Invocation::new(&optimized).input("x", 0.25).run().unwrap();
+    assert_eq!(outputs.output("y"), Some(&Value::Float(vec![expected])));
+  }
+}
@@ -0,0 +1,605 @@
+//! Serialization of shaders, available with the `serde` feature.
+//!
+//! A [`Shader`] is serialized as its stage and declarations along with the version of the format. Bump
+//! [`FORMAT_VERSION`] whenever the serialized representation changes: deserializing another version fails rather than
+//! producing a different shader.
+//!
+//! Expressions are graphs: a sub-expression can be shared by several parents. The expressions of a shader are
+//! serialized as a flat table of nodes, before the declarations, with one node per sub-expression. Nodes refer to their
+//! children by index and come after them, and declarations refer to their expressions by index as well. Shared
+//! sub-expressions are then serialized once, and the nesting of the serialized shader doesn’t depend on the depth of its
+//! expressions, which deserializers limiting nesting — such as serde_json — would otherwise reject.
+
+use crate::{
+  optimizer, ErasedExpr, ErasedFunHandle, ScopedHandle, Shader, ShaderDecl, ShaderStage, Swizzle,
+  Type, M22, M33, M44,
+};
+use serde::{de, ser, Deserialize, Deserializer, Serialize, Serializer};
+use std::{cell::RefCell, collections::HashMap, sync::Arc};
+
+/// Version of the serialization format.
+const FORMAT_VERSION: u32 = 1;
+
+/// Version of the serialization format, rejecting any other version when deserialized.
+#[derive(Serialize)]
+struct Version(u32);
+
+impl<'de> Deserialize<'de> for Version {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+  where
+    D: Deserializer<'de>,
+  {
+    let version = u32::deserialize(deserializer)?;
+
+    if version == FORMAT_VERSION {
+      Ok(Version(version))
+    } else {
+      Err(de::Error::invalid_value(
+        de::Unexpected::Unsigned(version.into()),
+        &format!("shader format version {}", FORMAT_VERSION).as_str(),
+      ))
+    }
+  }
+}
+
+#[derive(Serialize)]
+struct SerializedShaderRef<'a> {
+  version: Version,
+  stage: ShaderStage,
+  exprs: Vec<SerializedExpr>,
+  decls: &'a [ShaderDecl],
+  next_fun_handle: u16,
+  next_global_handle: u16,
+}
+
+#[derive(Deserialize)]
+struct SerializedShader {
+  #[allow(dead_code)]
+  version: Version,
+  stage: ShaderStage,
+  #[allow(dead_code)]
+  exprs: DeserializedExprs,
+  decls: Vec<ShaderDecl>,
+  next_fun_handle: u16,
+  next_global_handle: u16,
+}
+
+impl Serialize for Shader {
+  fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+  where
+    S: Serializer,
+  {
+    let _tracking = Tracking::start();
+
+    let mut nodes = Nodes::default();
+    for expr in self.decls.iter().flat_map(decl_exprs) {
+      nodes.insert(expr);
+    }
+    let Nodes { indices, nodes } = nodes;
+    SERIALIZED.with(|serialized| *serialized.borrow_mut() = indices);
+
+    SerializedShaderRef {
+      version: Version(FORMAT_VERSION),
+      stage: self.stage,
+      exprs: nodes,
+      decls: &self.decls,
+      next_fun_handle: self.next_fun_handle,
+      next_global_handle: self.next_global_handle,
+    }
+    .serialize(serializer)
+  }
+}
+
+impl<'de> Deserialize<'de> for Shader {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+  where
+    D: Deserializer<'de>,
+  {
+    let _tracking = Tracking::start();
+    let shader = SerializedShader::deserialize(deserializer)?;
+
+    Ok(Shader {
+      stage: shader.stage,
+      decls: shader.decls,
+      next_fun_handle: shader.next_fun_handle,
+      next_global_handle: shader.next_global_handle,
+    })
+  }
+}
+
+// Expressions of a declaration, without their sub-expressions.
+fn decl_exprs(decl: &ShaderDecl) -> Vec<&ErasedExpr> {
+  match decl {
+    ShaderDecl::Main(fun) | ShaderDecl::FunDef(_, fun) => optimizer::fun_exprs(fun),
+    ShaderDecl::Const(_, _, expr, _) => vec![expr],
+    ShaderDecl::In(..) | ShaderDecl::Out(..) | ShaderDecl::Uniform(..) => Vec::new(),
+  }
+}
+
+// Indices of the nodes of the expressions of a shader being serialized, by address, and expressions of the nodes of a
+// shader being deserialized.
+thread_local! {
+  static SERIALIZED: RefCell<HashMap<*const ErasedExpr, u32>> = RefCell::new(HashMap::new());
+  static DESERIALIZED: RefCell<Vec<Arc<ErasedExpr>>> = const { RefCell::new(Vec::new()) };
+}
+
+// Nodes tracked while serializing or deserializing a shader, forgotten afterwards.
+struct Tracking;
+
+impl Tracking {
+  fn start() -> Self {
+    Tracking.clear();
+    Tracking
+  }
+
+  fn clear(&self) {
+    SERIALIZED.with(|serialized| serialized.borrow_mut().clear());
+    DESERIALIZED.with(|deserialized| deserialized.borrow_mut().clear());
+  }
+}
+
+impl Drop for Tracking {
+  fn drop(&mut self) {
+    self.clear();
+  }
+}
+
+/// Table of the nodes of the expressions of a shader, children first.
+#[derive(Default)]
+struct Nodes {
+  indices: HashMap<*const ErasedExpr, u32>,
+  nodes: Vec<SerializedExpr>,
+}
+
+impl Nodes {
+  /// Add the nodes of an expression and of its sub-expressions not in the table yet.
+  ///
+  /// Expressions are walked with an explicit stack rather than recursively, so that deep expressions don’t overflow
+  /// the stack.
+  fn insert(&mut self, expr: &ErasedExpr) {
+    let mut pending = vec![expr];
+
+    while let Some(&expr) = pending.last() {
+      let address = expr as *const ErasedExpr;
+
+      if self.indices.contains_key(&address) {
+        pending.pop();
+        continue;
+      }
+
+      let mut missing = Vec::new();
+      let node = SerializedExpr::new(expr, |child| {
+        self
+          .indices
+          .get(&Arc::as_ptr(child))
+          .copied()
+          .unwrap_or_else(|| {
+            missing.push(&**child);
+            0
+          })
+      });
+
+      if missing.is_empty() {
+        pending.pop();
+        self.indices.insert(address, self.nodes.len() as u32);
+        self.nodes.push(node);
+      } else {
+        pending.extend(missing);
+      }
+    }
+  }
+}
+
+/// Nodes of a shader being deserialized, whose expressions are kept in [`DESERIALIZED`] for the declarations referring
+/// to them.
+struct DeserializedExprs;
+
+impl<'de> Deserialize<'de> for DeserializedExprs {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+  where
+    D: Deserializer<'de>,
+  {
+    let nodes = Vec::<SerializedExpr>::deserialize(deserializer)?;
+
+    DESERIALIZED.with(|deserialized| {
+      let mut deserialized = deserialized.borrow_mut();
+
+      for node in nodes {
+        let expr = node.into_expr(&deserialized).map_err(invalid_index)?;
+        deserialized.push(Arc::new(expr));
+      }
+
+      Ok(DeserializedExprs)
+    })
+  }
+}
+
+fn invalid_index<E>(index: u32) -> E
+where
+  E: de::Error,
+{
+  E::invalid_value(
+    de::Unexpected::Unsigned(index.into()),
+    &"the index of a previous expression",
+  )
+}
+
+// Expressions of declarations are serialized as the index of their node.
+impl Serialize for ErasedExpr {
+  fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+  where
+    S: Serializer,
+  {
+    SERIALIZED
+      .with(|serialized| {
+        serialized
+          .borrow()
+          .get(&(self as *const ErasedExpr))
+          .copied()
+      })
+      .ok_or_else(|| ser::Error::custom("expression serialized outside of its shader"))?
+      .serialize(serializer)
+  }
+}
+
+impl<'de> Deserialize<'de> for ErasedExpr {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+  where
+    D: Deserializer<'de>,
+  {
+    let index = u32::deserialize(deserializer)?;
+
+    DESERIALIZED
+      .with(|deserialized| deserialized.borrow().get(index as usize).cloned())
+      .map(|expr| ErasedExpr::clone(&expr))
+      .ok_or_else(|| invalid_index(index))
+  }
+}
+
+/// Expression as serialized, its sub-expressions being [`u32`]ren.
+#[derive(Deserialize, Serialize)]
+enum SerializedExpr {
+  LitInt(i32),
+  LitUInt(u32),
+  LitFloat(f32),
+  LitBool(bool),
+  LitInt2([i32; 2]),
+  LitUInt2([u32; 2]),
+  LitFloat2([f32; 2]),
+  LitBool2([bool; 2]),
+  LitInt3([i32; 3]),
+  LitUInt3([u32; 3]),
+  LitFloat3([f32; 3]),
+  LitBool3([bool; 3]),
+  LitInt4([i32; 4]),
+  LitUInt4([u32; 4]),
+  LitFloat4([f32; 4]),
+  LitBool4([bool; 4]),
+  LitM22(M22),
+  LitM33(M33),
+  LitM44(M44),
+  Array(Type, Vec<u32>),
+  Var(ScopedHandle),
+  Not(u32),
+  Neg(u32),
+  And(u32, u32),
+  Or(u32, u32),
+  Xor(u32, u32),
+  BitOr(u32, u32),
+  BitAnd(u32, u32),
+  BitXor(u32, u32),
+  Add(u32, u32),
+  Sub(u32, u32),
+  Mul(u32, u32),
+  Div(u32, u32),
+  Rem(u32, u32),
+  Shl(u32, u32),
+  Shr(u32, u32),
+  Eq(u32, u32),
+  Neq(u32, u32),
+  Lt(u32, u32),
+  Lte(u32, u32),
+  Gt(u32, u32),
+  Gte(u32, u32),
+  FunCall(ErasedFunHandle, Vec<u32>),
+  Swizzle(u32, Swizzle),
+  Field { object: u32, field: u32 },
+  ArrayLookup { object: u32, index: u32 },
+}
+
+impl SerializedExpr {
+  /// Node of an expression, given the indices of the nodes of its children.
+  fn new<'a>(expr: &'a ErasedExpr, mut node: impl FnMut(&'a Arc<ErasedExpr>) -> u32) -> Self {
+    match expr {
+      ErasedExpr::LitInt(x) => SerializedExpr::LitInt(*x),
+      ErasedExpr::LitUInt(x) => SerializedExpr::LitUInt(*x),
+      ErasedExpr::LitFloat(x) => SerializedExpr::LitFloat(*x),
+      ErasedExpr::LitBool(x) => SerializedExpr::LitBool(*x),
+      ErasedExpr::LitInt2(x) => SerializedExpr::LitInt2(*x),
+      ErasedExpr::LitUInt2(x) => SerializedExpr::LitUInt2(*x),
+      ErasedExpr::LitFloat2(x) => SerializedExpr::LitFloat2(*x),
+      ErasedExpr::LitBool2(x) => SerializedExpr::LitBool2(*x),
+      ErasedExpr::LitInt3(x) => SerializedExpr::LitInt3(*x),
+      ErasedExpr::LitUInt3(x) => SerializedExpr::LitUInt3(*x),
+      ErasedExpr::LitFloat3(x) => SerializedExpr::LitFloat3(*x),
+      ErasedExpr::LitBool3(x) => SerializedExpr::LitBool3(*x),
+      ErasedExpr::LitInt4(x) => SerializedExpr::LitInt4(*x),
+      ErasedExpr::LitUInt4(x) => SerializedExpr::LitUInt4(*x),
+      ErasedExpr::LitFloat4(x) => SerializedExpr::LitFloat4(*x),
+      ErasedExpr::LitBool4(x) => SerializedExpr::LitBool4(*x),
+      ErasedExpr::LitM22(x) => SerializedExpr::LitM22(x.clone()),
+      ErasedExpr::LitM33(x) => SerializedExpr::LitM33(x.clone()),
+      ErasedExpr::LitM44(x) => SerializedExpr::LitM44(x.clone()),
+      ErasedExpr::Array(ty, items) => {
+        SerializedExpr::Array(ty.clone(), items.iter().map(&mut node).collect())
+      }
+      ErasedExpr::Var(handle) => SerializedExpr::Var(handle.clone()),
+      ErasedExpr::Not(a) => SerializedExpr::Not(node(a)),
+      ErasedExpr::Neg(a) => SerializedExpr::Neg(node(a)),
+      ErasedExpr::And(a, b) => SerializedExpr::And(node(a), node(b)),
+      ErasedExpr::Or(a, b) => SerializedExpr::Or(node(a), node(b)),
+      ErasedExpr::Xor(a, b) => SerializedExpr::Xor(node(a), node(b)),
+      ErasedExpr::BitOr(a, b) => SerializedExpr::BitOr(node(a), node(b)),
+      ErasedExpr::BitAnd(a, b) => SerializedExpr::BitAnd(node(a), node(b)),
+      ErasedExpr::BitXor(a, b) => SerializedExpr::BitXor(node(a), node(b)),
+      ErasedExpr::Add(a, b) => SerializedExpr::Add(node(a), node(b)),
+      ErasedExpr::Sub(a, b) => SerializedExpr::Sub(node(a), node(b)),
+      ErasedExpr::Mul(a, b) => SerializedExpr::Mul(node(a), node(b)),
+      ErasedExpr::Div(a, b) => SerializedExpr::Div(node(a), node(b)),
+      ErasedExpr::Rem(a, b) => SerializedExpr::Rem(node(a), node(b)),
+      ErasedExpr::Shl(a, b) => SerializedExpr::Shl(node(a), node(b)),
+      ErasedExpr::Shr(a, b) => SerializedExpr::Shr(node(a), node(b)),
+      ErasedExpr::Eq(a, b) => SerializedExpr::Eq(node(a), node(b)),
+      ErasedExpr::Neq(a, b) => SerializedExpr::Neq(node(a), node(b)),
+      ErasedExpr::Lt(a, b) => SerializedExpr::Lt(node(a), node(b)),
+      ErasedExpr::Lte(a, b) => SerializedExpr::Lte(node(a), node(b)),
+      ErasedExpr::Gt(a, b) => SerializedExpr::Gt(node(a), node(b)),
+      ErasedExpr::Gte(a, b) => SerializedExpr::Gte(node(a), node(b)),
+      ErasedExpr::FunCall(fun, args) => {
+        SerializedExpr::FunCall(fun.clone(), args.iter().map(&mut node).collect())
+      }
+      ErasedExpr::Swizzle(a, swizzle) => SerializedExpr::Swizzle(node(a), *swizzle),
+      ErasedExpr::Field { object, field } => SerializedExpr::Field {
+        object: node(object),
+        field: node(field),
+      },
+      ErasedExpr::ArrayLookup { object, index } => SerializedExpr::ArrayLookup {
+        object: node(object),
+        index: node(index),
+      },
+    }
+  }
+
+  /// Expression of a node, given the expressions of the previous nodes, or the first index not referring to one of
+  /// them.
+  fn into_expr(self, nodes: &[Arc<ErasedExpr>]) -> Result<ErasedExpr, u32> {
+    let expr = |index: u32| nodes.get(index as usize).cloned().ok_or(index);
+    let exprs = |indices: Vec<u32>| indices.into_iter().map(expr).collect::<Result<_, _>>();
+
+    let expr = match self {
+      SerializedExpr::LitInt(x) => ErasedExpr::LitInt(x),
+      SerializedExpr::LitUInt(x) => ErasedExpr::LitUInt(x),
+      SerializedExpr::LitFloat(x) => ErasedExpr::LitFloat(x),
+      SerializedExpr::LitBool(x) => ErasedExpr::LitBool(x),
+      SerializedExpr::LitInt2(x) => ErasedExpr::LitInt2(x),
+      SerializedExpr::LitUInt2(x) => ErasedExpr::LitUInt2(x),
+      SerializedExpr::LitFloat2(x) => ErasedExpr::LitFloat2(x),
+      SerializedExpr::LitBool2(x) => ErasedExpr::LitBool2(x),
+      SerializedExpr::LitInt3(x) => ErasedExpr::LitInt3(x),
+      SerializedExpr::LitUInt3(x) => ErasedExpr::LitUInt3(x),
+      SerializedExpr::LitFloat3(x) => ErasedExpr::LitFloat3(x),
+      SerializedExpr::LitBool3(x) => ErasedExpr::LitBool3(x),
+      SerializedExpr::LitInt4(x) => ErasedExpr::LitInt4(x),
+      SerializedExpr::LitUInt4(x) => ErasedExpr::LitUInt4(x),
+      SerializedExpr::LitFloat4(x) => ErasedExpr::LitFloat4(x),
+      SerializedExpr::LitBool4(x) => ErasedExpr::LitBool4(x),
+      SerializedExpr::LitM22(x) => ErasedExpr::LitM22(x),
+      SerializedExpr::LitM33(x) => ErasedExpr::LitM33(x),
+      SerializedExpr::LitM44(x) => ErasedExpr::LitM44(x),
+      SerializedExpr::Array(ty, items) => ErasedExpr::Array(ty, exprs(items)?),
+      SerializedExpr::Var(handle) => ErasedExpr::Var(handle),
+      SerializedExpr::Not(a) => ErasedExpr::Not(expr(a)?),
+      SerializedExpr::Neg(a) => ErasedExpr::Neg(expr(a)?),
+      SerializedExpr::And(a, b) => ErasedExpr::And(expr(a)?, expr(b)?),
+      SerializedExpr::Or(a, b) => ErasedExpr::Or(expr(a)?, expr(b)?),
+      SerializedExpr::Xor(a, b) => ErasedExpr::Xor(expr(a)?, expr(b)?),
+      SerializedExpr::BitOr(a, b) => ErasedExpr::BitOr(expr(a)?, expr(b)?),
+      SerializedExpr::BitAnd(a, b) => ErasedExpr::BitAnd(expr(a)?, expr(b)?),
+      SerializedExpr::BitXor(a, b) => ErasedExpr::BitXor(expr(a)?, expr(b)?),
+      SerializedExpr::Add(a, b) => ErasedExpr::Add(expr(a)?, expr(b)?),
+      SerializedExpr::Sub(a, b) => ErasedExpr::Sub(expr(a)?, expr(b)?),
+      SerializedExpr::Mul(a, b) => ErasedExpr::Mul(expr(a)?, expr(b)?),
+      SerializedExpr::Div(a, b) => ErasedExpr::Div(expr(a)?, expr(b)?),
+      SerializedExpr::Rem(a, b) => ErasedExpr::Rem(expr(a)?, expr(b)?),
+      SerializedExpr::Shl(a, b) => ErasedExpr::Shl(expr(a)?, expr(b)?),
+      SerializedExpr::Shr(a, b) => ErasedExpr::Shr(expr(a)?, expr(b)?),
+      SerializedExpr::Eq(a, b) => ErasedExpr::Eq(expr(a)?, expr(b)?),
+      SerializedExpr::Neq(a, b) => ErasedExpr::Neq(expr(a)?, expr(b)?),
+      SerializedExpr::Lt(a, b) => ErasedExpr::Lt(expr(a)?, expr(b)?),
+      SerializedExpr::Lte(a, b) => ErasedExpr::Lte(expr(a)?, expr(b)?),
+      SerializedExpr::Gt(a, b) => ErasedExpr::Gt(expr(a)?, expr(b)?),
+      SerializedExpr::Gte(a, b) => ErasedExpr::Gte(expr(a)?, expr(b)?),
+      SerializedExpr::FunCall(fun, args) => ErasedExpr::FunCall(fun, exprs(args)?),
+      SerializedExpr::Swizzle(a, swizzle) => ErasedExpr::Swizzle(expr(a)?, swizzle),
+      SerializedExpr::Field { object, field } => ErasedExpr::Field {
+        object: expr(object)?,
+        field: expr(field)?,
+      },
+      SerializedExpr::ArrayLookup { object, index } => ErasedExpr::ArrayLookup {
+        object: expr(object)?,
+        index: expr(index)?,
+      },
+    };
+
+    Ok(expr)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    inputs, lit, outputs, sw, uniforms, vec4, writer::glsl, CanEscape as _, Expr, Geometry as _,
+    Scope, ShaderBuilder, Swizzlable as _, V2, V3, V4,
+  };
+
+  fn round_trip(shader: &Shader) -> Shader {
+    let json = serde_json::to_string(shader).unwrap();
+    serde_json::from_str(&json).unwrap()
+  }
+
+  #[test]
+  fn round_trip_vertex_shader() {
+    let shader = ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+      inputs!(s, position: V2<f32>, color: V3<f32>);
+      outputs!(s, v_color: V3<f32>);
+      uniforms!(s, scale: f32);
+
+      let weights = s.constant_named("weights", lit!([1., 0.5, 0.25]));
+      let weight = s.fun_named(
+        "weight",
+        &["i"],
+        |_: &mut Scope<Expr<f32>>, i: Expr<i32>| weights.at(i),
+      );
+
+      s.main_fun(|s: &mut Scope<()>| {
+        let total = s.var_named("total", lit!(0.));
+
+        s.loop_for(
+          0,
+          |i| i.lt(lit!(3)),
+          |i| i + 1,
+          |s, i| {
+            s.when(i.eq(lit!(2)), |s| s.loop_break());
+            s.set(&total, total.clone() + weight.call(i.clone()));
+          },
+        );
+
+        s.set(&v_color, color.normalize() * total.clone());
+        s.set(vertex.position, vec4!(position * scale.clone(), 0., 1.));
+      })
+    });
+
+    let deserialized = round_trip(&shader);
+    assert_eq!(deserialized, shader);
+    assert_eq!(
+      glsl::write_shader_to_str(&deserialized).unwrap(),
+      glsl::write_shader_to_str(&shader).unwrap()
+    );
+    assert_eq!(
+      deserialized.interface().collect::<Vec<_>>(),
+      shader.interface().collect::<Vec<_>>()
+    );
+  }
+
+  #[test]
+  fn round_trip_fragment_shader() {
+    let shader = ShaderBuilder::new_fragment_shader(|mut s, fragment| {
+      outputs!(s, frag: V4<f32>);
+      let m = s.constant(lit!(crate::M22::from([[1., 0.], [0., 1.]])));
+
+      s.main_fun(|s: &mut Scope<()>| {
+        let uv = s.var(m.clone() * sw!(fragment.frag_coord.clone(), .x.y));
+
+        s.when(sw!(uv.clone(), .x).lt(lit!(0.)), |s| fragment.discard(s))
+          .or_else(sw!(uv.clone(), .y).gt(lit!(1.)), |s| s.leave(()))
+          .or(|s| s.set(&frag, vec4!(uv.clone(), 0., 1.)));
+      })
+    });
+
+    let deserialized = round_trip(&shader);
+    assert_eq!(deserialized, shader);
+    assert_eq!(
+      glsl::write_shader_to_str(&deserialized).unwrap(),
+      glsl::write_shader_to_str(&shader).unwrap()
+    );
+  }
+
+  #[test]
+  fn locations_are_dropped() {
+    let shader = ShaderBuilder::new_vertex_shader(|s, vertex| {
+      s.main_fun(|s: &mut Scope<()>| s.set(vertex.position, lit!(0., 0., 0., 1.)))
+    });
+    let options = glsl::WriteOptions::new().with_source_comments(true);
+
+    let (_, source_map) = glsl::write_shader_to_str_with_source_map(&shader, &options).unwrap();
+    assert_ne!(source_map.iter().count(), 0);
+
+    let (_, source_map) =
+      glsl::write_shader_to_str_with_source_map(round_trip(&shader), &options).unwrap();
+    assert_eq!(source_map.iter().count(), 0);
+  }
+
+  #[test]
+  fn shared_subexpressions() {
+    // each level uses the previous one twice: serialized as a tree, the expression would have about 2^16 nodes; deeper
+    // expressions exceed the nesting limit of serde_json
+    let shader = ShaderBuilder::new_vertex_shader(|mut s, _| {
+      uniforms!(s, x: f32);
+      outputs!(s, y: f32);
+
+      s.main_fun(|s: &mut Scope<()>| {
+        let mut e = x.clone();
+        for _ in 0..16 {
+          e = e.clone() * e + 0.5;
+        }
+
+        s.set(&y, e);
+      })
+    });
+
+    let json = serde_json::to_string(&shader).unwrap();
+    assert!(json.len() < 20_000);
+
+    let deserialized: Shader = serde_json::from_str(&json).unwrap();
+    assert_eq!(deserialized, shader);
+    // shared sub-expressions are still shared
+    assert_eq!(serde_json::to_string(&deserialized).unwrap(), json);
+
+    // nodes can only refer to the nodes before them
+    let mut json = serde_json::to_value(&shader).unwrap();
+    let root = json["exprs"].as_array().unwrap().len() - 1;
+    json["exprs"][root]["Add"][0] = root.into();
+    assert!(serde_json::from_value::<Shader>(json).is_err());
+  }
+
+  #[test]
+  fn deep_expressions() {
+    // a chain of additions and an expression reusing its previous level twice, both deeper than the nesting limit of
+    // serde_json if expressions were nested
+    let shader = ShaderBuilder::new_vertex_shader(|mut s, _| {
+      uniforms!(s, x: f32);
+      outputs!(s, y: f32, z: f32);
+
+      s.main_fun(|s: &mut Scope<()>| {
+        let mut chain = x.clone();
+        for i in 0..40 {
+          chain = chain + i as f32;
+        }
+
+        let mut reused = x.clone();
+        for _ in 0..20 {
+          reused = reused.clone() + reused;
+        }
+
+        s.set(&y, chain);
+        s.set(&z, reused);
+      })
+    });
+
+    assert_eq!(round_trip(&shader), shader);
+  }
+
+  #[test]
+  fn version() {
+    let shader = ShaderBuilder::new_vertex_shader(|s, _| s.main_fun(|_: &mut Scope<()>| {}));
+    let mut json = serde_json::to_value(&shader).unwrap();
+    assert_eq!(json["version"], FORMAT_VERSION);
+
+    json["version"] = (FORMAT_VERSION + 1).into();
+    let err = serde_json::from_value::<Shader>(json).unwrap_err();
+    assert!(err
+      .to_string()
+      .contains(&format!("shader format version {}", FORMAT_VERSION)));
+  }
+}
@@ -0,0 +1,486 @@
+//! Type inference over the erased representation of shaders.
+//!
+//! Expressions lose their Rust type once erased; this module recovers it, which is required to declare variables
+//! holding arbitrary expressions.
+
+use crate::{
+  BuiltIn, Dim, ErasedExpr, ErasedFunHandle, ErasedReturn, FragmentBuiltIn, GeometryBuiltIn,
+  MatrixDim, PrimType, ScopedHandle, ShaderDecl, TessCtrlBuiltIn, TessEvalBuiltIn, ToType, Type,
+  VertexBuiltIn, V2, V3, V4,
+};
+use std::{collections::HashMap, sync::Arc};
+
+/// Number of components of a [`Dim`].
+pub(crate) fn dim_len(dim: &Dim) -> usize {
+  match dim {
+    Dim::Scalar => 1,
+    Dim::D2 => 2,
+    Dim::D3 => 3,
+    Dim::D4 => 4,
+  }
+}
+
+/// Number of columns and rows of a [`MatrixDim`].
+pub(crate) fn matrix_dim_len(dim: &MatrixDim) -> (usize, usize) {
+  match dim {
+    MatrixDim::D22 => (2, 2),
+    MatrixDim::D23 => (2, 3),
+    MatrixDim::D24 => (2, 4),
+    MatrixDim::D32 => (3, 2),
+    MatrixDim::D33 => (3, 3),
+    MatrixDim::D34 => (3, 4),
+    MatrixDim::D42 => (4, 2),
+    MatrixDim::D43 => (4, 3),
+    MatrixDim::D44 => (4, 4),
+  }
+}
+
+fn dim(len: usize) -> Option<Dim> {
+  match len {
+    1 => Some(Dim::Scalar),
+    2 => Some(Dim::D2),
+    3 => Some(Dim::D3),
+    4 => Some(Dim::D4),
+    _ => None,
+  }
+}
+
+fn matrix_dim(cols: usize, rows: usize) -> Option<MatrixDim> {
+  match (cols, rows) {
+    (2, 2) => Some(MatrixDim::D22),
+    (2, 3) => Some(MatrixDim::D23),
+    (2, 4) => Some(MatrixDim::D24),
+    (3, 2) => Some(MatrixDim::D32),
+    (3, 3) => Some(MatrixDim::D33),
+    (3, 4) => Some(MatrixDim::D34),
+    (4, 2) => Some(MatrixDim::D42),
+    (4, 3) => Some(MatrixDim::D43),
+    (4, 4) => Some(MatrixDim::D44),
+    _ => None,
+  }
+}
+
+fn ty<T>() -> Option<Type>
+where
+  T: ?Sized + ToType,
+{
+  Some(T::ty())
+}
+
+fn prim(prim_ty: PrimType) -> Type {
+  Type {
+    prim_ty,
+    array_dims: Vec::new(),
+  }
+}
+
+/// Type of a built-in; per-vertex blocks, such as `gl_in`, have no [`Type`].
+pub(crate) fn builtin_type(builtin: &BuiltIn) -> Option<Type> {
+  match builtin {
+    BuiltIn::Vertex(builtin) => match builtin {
+      VertexBuiltIn::VertexID
+      | VertexBuiltIn::InstanceID
+      | VertexBuiltIn::BaseVertex
+      | VertexBuiltIn::BaseInstance => ty::<i32>(),
+      VertexBuiltIn::Position => ty::<V4<f32>>(),
+      VertexBuiltIn::PointSize => ty::<f32>(),
+      VertexBuiltIn::ClipDistance => ty::<[f32]>(),
+    },
+
+    BuiltIn::TessCtrl(builtin) => match builtin {
+      TessCtrlBuiltIn::MaxPatchVerticesIn
+      | TessCtrlBuiltIn::PatchVerticesIn
+      | TessCtrlBuiltIn::PrimitiveID
+      | TessCtrlBuiltIn::InvocationID => ty::<i32>(),
+      TessCtrlBuiltIn::TessellationLevelOuter => ty::<[f32; 4]>(),
+      TessCtrlBuiltIn::TessellationLevelInner => ty::<[f32; 2]>(),
+      TessCtrlBuiltIn::In | TessCtrlBuiltIn::Out => None,
+      TessCtrlBuiltIn::Position => ty::<V4<f32>>(),
+      TessCtrlBuiltIn::PointSize => ty::<f32>(),
+      TessCtrlBuiltIn::ClipDistance | TessCtrlBuiltIn::CullDistance => ty::<[f32]>(),
+    },
+
+    BuiltIn::TessEval(builtin) => match builtin {
+      TessEvalBuiltIn::TessCoord => ty::<V3<f32>>(),
+      TessEvalBuiltIn::MaxPatchVerticesIn
+      | TessEvalBuiltIn::PatchVerticesIn
+      | TessEvalBuiltIn::PrimitiveID => ty::<i32>(),
+      TessEvalBuiltIn::TessellationLevelOuter => ty::<[f32; 4]>(),
+      TessEvalBuiltIn::TessellationLevelInner => ty::<[f32; 2]>(),
+      TessEvalBuiltIn::In | TessEvalBuiltIn::Out => None,
+      TessEvalBuiltIn::Position => ty::<V4<f32>>(),
+      TessEvalBuiltIn::PointSize => ty::<f32>(),
+      TessEvalBuiltIn::ClipDistance | TessEvalBuiltIn::CullDistance => ty::<[f32]>(),
+    },
+
+    BuiltIn::Geometry(builtin) => match builtin {
+      GeometryBuiltIn::In | GeometryBuiltIn::Out => None,
+      GeometryBuiltIn::Position => ty::<V4<f32>>(),
+      GeometryBuiltIn::PointSize => ty::<f32>(),
+      GeometryBuiltIn::ClipDistance | GeometryBuiltIn::CullDistance => ty::<[f32]>(),
+      GeometryBuiltIn::PrimitiveID
+      | GeometryBuiltIn::PrimitiveIDIn
+      | GeometryBuiltIn::InvocationID
+      | GeometryBuiltIn::Layer
+      | GeometryBuiltIn::ViewportIndex => ty::<i32>(),
+    },
+
+    BuiltIn::Fragment(builtin) => match builtin {
+      FragmentBuiltIn::FragCoord => ty::<V4<f32>>(),
+      FragmentBuiltIn::FrontFacing | FragmentBuiltIn::HelperInvocation => ty::<bool>(),
+      FragmentBuiltIn::PointCoord | FragmentBuiltIn::SamplePosition => ty::<V2<f32>>(),
+      FragmentBuiltIn::SampleID
+      | FragmentBuiltIn::SampleMaskIn
+      | FragmentBuiltIn::PrimitiveID
+      | FragmentBuiltIn::Layer
+      | FragmentBuiltIn::ViewportIndex => ty::<i32>(),
+      FragmentBuiltIn::ClipDistance | FragmentBuiltIn::CullDistance => ty::<[f32]>(),
+      FragmentBuiltIn::FragDepth => ty::<f32>(),
+      FragmentBuiltIn::SampleMask => ty::<[i32]>(),
+    },
+  }
+}
+
+// Memoized types of the sub-expressions of an expression, by address.
+type Types = HashMap<*const ErasedExpr, Option<Type>>;
+
+/// Types of everything an expression can refer to.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TypeEnv {
+  globals: HashMap<u16, Type>,
+  interface: HashMap<String, Type>,
+  fun_rets: HashMap<u16, Type>,
+  args: Vec<Type>,
+  vars: HashMap<(u16, u16), Type>,
+}
+
+impl TypeEnv {
+  /// Environment of the top-level declarations of a shader.
+  pub(crate) fn new(decls: &[ShaderDecl]) -> Self {
+    let mut env = TypeEnv::default();
+
+    for decl in decls {
+      match decl {
+        ShaderDecl::Main(_) => (),
+
+        ShaderDecl::FunDef(handle, fun) => {
+          if let ErasedReturn::Expr(ty, _) = &fun.ret {
+            env.fun_rets.insert(*handle, ty.clone());
+          }
+        }
+
+        ShaderDecl::Const(handle, ty, ..) => {
+          env.globals.insert(*handle, ty.clone());
+        }
+
+        ShaderDecl::In(name, ty) | ShaderDecl::Out(name, ty, _) | ShaderDecl::Uniform(name, ty) => {
+          env.interface.insert(name.clone(), ty.clone());
+        }
+      }
+    }
+
+    env
+  }
+
+  /// Environment of the body of a function taking the given arguments.
+  pub(crate) fn with_args(&self, args: &[Type]) -> Self {
+    TypeEnv {
+      args: args.to_vec(),
+      vars: HashMap::new(),
+      ..self.clone()
+    }
+  }
+
+  /// Declare a function variable.
+  pub(crate) fn declare(&mut self, handle: &ScopedHandle, ty: &Type) {
+    if let ScopedHandle::FunVar { subscope, handle } = handle {
+      self.vars.insert((*subscope, *handle), ty.clone());
+    }
+  }
+
+  fn handle_type(&self, handle: &ScopedHandle) -> Option<Type> {
+    match handle {
+      ScopedHandle::BuiltIn(builtin) => builtin_type(builtin),
+      ScopedHandle::Global(handle) => self.globals.get(handle).cloned(),
+      ScopedHandle::LibraryGlobal(..) => None,
+      ScopedHandle::FunArg(arg) => self.args.get(*arg as usize).cloned(),
+      ScopedHandle::FunVar { subscope, handle } => self.vars.get(&(*subscope, *handle)).cloned(),
+      ScopedHandle::Input(name) | ScopedHandle::Output(name) | ScopedHandle::Uniform(name) => {
+        self.interface.get(name).cloned()
+      }
+    }
+  }
+
+  /// Type of an expression, if it can be inferred.
+  pub(crate) fn type_of(&self, expr: &ErasedExpr) -> Option<Type> {
+    self.infer(expr, &mut HashMap::new())
+  }
+
+  // Sub-expressions can be shared by several parents; their type is memoized by address so that each of them is
+  // inferred once.
+  fn infer(&self, expr: &ErasedExpr, types: &mut Types) -> Option<Type> {
+    if let Some(ty) = types.get(&(expr as *const _)) {
+      return ty.clone();
+    }
+
+    let ty = self.infer_node(expr, types);
+    types.insert(expr, ty.clone());
+    ty
+  }
+
+  fn infer_node(&self, expr: &ErasedExpr, types: &mut Types) -> Option<Type> {
+    match expr {
+      ErasedExpr::LitInt(_) => ty::<i32>(),
+      ErasedExpr::LitUInt(_) => ty::<u32>(),
+      ErasedExpr::LitFloat(_) => ty::<f32>(),
+      ErasedExpr::LitBool(_) => ty::<bool>(),
+      ErasedExpr::LitInt2(_) => ty::<V2<i32>>(),
+      ErasedExpr::LitUInt2(_) => ty::<V2<u32>>(),
+      ErasedExpr::LitFloat2(_) => ty::<V2<f32>>(),
+      ErasedExpr::LitBool2(_) => ty::<V2<bool>>(),
+      ErasedExpr::LitInt3(_) => ty::<V3<i32>>(),
+      ErasedExpr::LitUInt3(_) => ty::<V3<u32>>(),
+      ErasedExpr::LitFloat3(_) => ty::<V3<f32>>(),
+      ErasedExpr::LitBool3(_) => ty::<V3<bool>>(),
+      ErasedExpr::LitInt4(_) => ty::<V4<i32>>(),
+      ErasedExpr::LitUInt4(_) => ty::<V4<u32>>(),
+      ErasedExpr::LitFloat4(_) => ty::<V4<f32>>(),
+      ErasedExpr::LitBool4(_) => ty::<V4<bool>>(),
+      ErasedExpr::LitM22(_) => Some(prim(PrimType::Matrix(MatrixDim::D22))),
+      ErasedExpr::LitM33(_) => Some(prim(PrimType::Matrix(MatrixDim::D33))),
+      ErasedExpr::LitM44(_) => Some(prim(PrimType::Matrix(MatrixDim::D44))),
+
+      ErasedExpr::Array(ty, _) => Some(ty.clone()),
+
+      ErasedExpr::Var(handle) => self.handle_type(handle),
+
+      ErasedExpr::Not(a) | ErasedExpr::Neg(a) => self.infer(a, types),
+
+      ErasedExpr::And(..)
+      | ErasedExpr::Or(..)
+      | ErasedExpr::Xor(..)
+      | ErasedExpr::Eq(..)
+      | ErasedExpr::Neq(..)
+      | ErasedExpr::Lt(..)
+      | ErasedExpr::Lte(..)
+      | ErasedExpr::Gt(..)
+      | ErasedExpr::Gte(..) => ty::<bool>(),
+
+      ErasedExpr::BitOr(a, b)
+      | ErasedExpr::BitAnd(a, b)
+      | ErasedExpr::BitXor(a, b)
+      | ErasedExpr::Add(a, b)
+      | ErasedExpr::Sub(a, b)
+      | ErasedExpr::Div(a, b)
+      | ErasedExpr::Rem(a, b)
+      | ErasedExpr::Shl(a, b)
+      | ErasedExpr::Shr(a, b) => widest(self.infer(a, types)?, self.infer(b, types)?),
+
+      ErasedExpr::Mul(a, b) => mul_type(self.infer(a, types)?, self.infer(b, types)?),
+
+      ErasedExpr::FunCall(ErasedFunHandle::UserDefined(handle), _) => {
+        self.fun_rets.get(handle).cloned()
+      }
+
+      ErasedExpr::FunCall(handle, args) => self.builtin_call_type(handle, args, types),
+
+      ErasedExpr::Swizzle(a, sw) => {
+        let len = match sw {
+          crate::Swizzle::D1(..) => 1,
+          crate::Swizzle::D2(..) => 2,
+          crate::Swizzle::D3(..) => 3,
+          crate::Swizzle::D4(..) => 4,
+        };
+
+        with_dim(&self.infer(a, types)?.prim_ty, dim(len)?).map(prim)
+      }
+
+      ErasedExpr::Field { field, .. } => self.infer(field, types),
+
+      ErasedExpr::ArrayLookup { object, .. } => {
+        let Type {
+          prim_ty,
+          array_dims,
+        } = self.infer(object, types)?;
+
+        if let Some((_, dims)) = array_dims.split_first() {
+          Some(Type {
+            prim_ty,
+            array_dims: dims.to_vec(),
+          })
+        } else if let PrimType::Matrix(dim) = prim_ty {
+          // columns of a matrix
+          let (_, rows) = matrix_dim_len(&dim);
+          Some(prim(PrimType::Float(self::dim(rows)?)))
+        } else {
+          with_dim(&prim_ty, Dim::Scalar).map(prim)
+        }
+      }
+    }
+  }
+
+  fn builtin_call_type(
+    &self,
+    handle: &ErasedFunHandle,
+    args: &[Arc<ErasedExpr>],
+    types: &mut Types,
+  ) -> Option<Type> {
+    let mut arg = |i: usize| args.get(i).and_then(|arg| self.infer(arg, types));
+
+    match handle {
+      ErasedFunHandle::Vec2 | ErasedFunHandle::Vec3 | ErasedFunHandle::Vec4 => {
+        let len = match handle {
+          ErasedFunHandle::Vec2 => 2,
+          ErasedFunHandle::Vec3 => 3,
+          _ => 4,
+        };
+
+        with_dim(&arg(0)?.prim_ty, dim(len)?).map(prim)
+      }
+
+      ErasedFunHandle::Length | ErasedFunHandle::Distance | ErasedFunHandle::Dot => ty::<f32>(),
+
+      ErasedFunHandle::Cross => ty::<V3<f32>>(),
+
+      ErasedFunHandle::Step | ErasedFunHandle::SmoothStep => args
+        .iter()
+        .try_fold(None, |acc: Option<Type>, arg| {
+          let ty = self.infer(arg, types)?;
+          Some(Some(match acc {
+            Some(acc) => widest(acc, ty)?,
+            None => ty,
+          }))
+        })
+        .flatten(),
+
+      ErasedFunHandle::IsNan | ErasedFunHandle::IsInf => {
+        with_kind(&arg(0)?.prim_ty, PrimType::Bool).map(prim)
+      }
+
+      ErasedFunHandle::FloatBitsToInt
+      | ErasedFunHandle::BitCount
+      | ErasedFunHandle::FindLSB
+      | ErasedFunHandle::FindMSB => with_kind(&arg(0)?.prim_ty, PrimType::Int).map(prim),
+
+      ErasedFunHandle::IntBitsToFloat | ErasedFunHandle::UIntBitsToFloat => {
+        with_kind(&arg(0)?.prim_ty, PrimType::Float).map(prim)
+      }
+
+      ErasedFunHandle::VLt
+      | ErasedFunHandle::VLte
+      | ErasedFunHandle::VGt
+      | ErasedFunHandle::VGte
+      | ErasedFunHandle::VEq
+      | ErasedFunHandle::VNeq => with_kind(&arg(0)?.prim_ty, PrimType::Bool).map(prim),
+
+      ErasedFunHandle::VAny | ErasedFunHandle::VAll => ty::<bool>(),
+
+      ErasedFunHandle::Radians
+      | ErasedFunHandle::Degrees
+      | ErasedFunHandle::Sin
+      | ErasedFunHandle::Cos
+      | ErasedFunHandle::Tan
+      | ErasedFunHandle::ASin
+      | ErasedFunHandle::ACos
+      | ErasedFunHandle::ATan
+      | ErasedFunHandle::SinH
+      | ErasedFunHandle::CosH
+      | ErasedFunHandle::TanH
+      | ErasedFunHandle::ASinH
+      | ErasedFunHandle::ACosH
+      | ErasedFunHandle::ATanH
+      | ErasedFunHandle::Pow
+      | ErasedFunHandle::Exp
+      | ErasedFunHandle::Exp2
+      | ErasedFunHandle::Log
+      | ErasedFunHandle::Log2
+      | ErasedFunHandle::Sqrt
+      | ErasedFunHandle::InverseSqrt
+      | ErasedFunHandle::Abs
+      | ErasedFunHandle::Sign
+      | ErasedFunHandle::Floor
+      | ErasedFunHandle::Trunc
+      | ErasedFunHandle::Round
+      | ErasedFunHandle::RoundEven
+      | ErasedFunHandle::Ceil
+      | ErasedFunHandle::Fract
+      | ErasedFunHandle::Min
+      | ErasedFunHandle::Max
+      | ErasedFunHandle::Clamp
+      | ErasedFunHandle::Mix
+      | ErasedFunHandle::FMA
+      | ErasedFunHandle::Normalize
+      | ErasedFunHandle::FaceForward
+      | ErasedFunHandle::Reflect
+      | ErasedFunHandle::Refract
+      | ErasedFunHandle::VNot
+      | ErasedFunHandle::BitfieldReverse => arg(0),
+
+      _ => None,
+    }
+  }
+}
+
+// Same kind of primitive type, with another dimension.
+fn with_dim(prim_ty: &PrimType, dim: Dim) -> Option<PrimType> {
+  match prim_ty {
+    PrimType::Int(_) => Some(PrimType::Int(dim)),
+    PrimType::UInt(_) => Some(PrimType::UInt(dim)),
+    PrimType::Float(_) => Some(PrimType::Float(dim)),
+    PrimType::Bool(_) => Some(PrimType::Bool(dim)),
+    PrimType::Matrix(_) => None,
+  }
+}
+
+// Same dimension, with another kind of primitive type.
+fn with_kind(prim_ty: &PrimType, kind: fn(Dim) -> PrimType) -> Option<PrimType> {
+  match prim_ty {
+    PrimType::Int(dim) | PrimType::UInt(dim) | PrimType::Float(dim) | PrimType::Bool(dim) => {
+      Some(kind(dim.clone()))
+    }
+    PrimType::Matrix(_) => None,
+  }
+}
+
+fn is_scalar(ty: &Type) -> bool {
+  ty.array_dims.is_empty()
+    && matches!(
+      ty.prim_ty,
+      PrimType::Int(Dim::Scalar)
+        | PrimType::UInt(Dim::Scalar)
+        | PrimType::Float(Dim::Scalar)
+        | PrimType::Bool(Dim::Scalar)
+    )
+}
+
+// Type of a component-wise operation, where scalars are broadcast.
+fn widest(a: Type, b: Type) -> Option<Type> {
+  if is_scalar(&a) {
+    Some(b)
+  } else {
+    Some(a)
+  }
+}
+
+// Type of a multiplication, which is the linear algebraic product for matrices.
+fn mul_type(a: Type, b: Type) -> Option<Type> {
+  match (&a.prim_ty, &b.prim_ty) {
+    (PrimType::Matrix(ma), PrimType::Matrix(mb)) => {
+      let (_, rows) = matrix_dim_len(ma);
+      let (cols, _) = matrix_dim_len(mb);
+      Some(prim(PrimType::Matrix(matrix_dim(cols, rows)?)))
+    }
+
+    (PrimType::Matrix(m), PrimType::Float(d)) if *d != Dim::Scalar => {
+      let (_, rows) = matrix_dim_len(m);
+      Some(prim(PrimType::Float(dim(rows)?)))
+    }
+
+    (PrimType::Float(d), PrimType::Matrix(m)) if *d != Dim::Scalar => {
+      let (cols, _) = matrix_dim_len(m);
+      Some(prim(PrimType::Float(dim(cols)?)))
+    }
+
+    _ => widest(a, b),
+  }
+}
@@ -0,0 +1,978 @@
+//! Validation of shaders.
+//!
+//! The EDSL prevents most invalid shaders from being built, but a few mistakes can only be detected once the whole
+//! shader is known: a [`Var`](crate::Var) leaking out of the closure that declared it, for instance, or more functions
+//! declared than handles can identify. [`Shader::validate`] checks a shader for such mistakes and reports them as
+//! [`Diagnostic`]s. Writers validate the shaders they write and refuse to write invalid ones.
+//!
+//! # Examples
+//!
+//! ```
+//! use shades::{Scope, ShaderBuilder, Var};
+//! use shades::validation::{Declaration, Diagnostic};
+//!
+//! let mut leaked: Option<Var<i32>> = None;
+//!
+//! let shader = ShaderBuilder::new_vertex_shader(|mut s, _| {
+//!   s.fun(|s: &mut Scope<()>, _: shades::Expr<i32>| {
+//!     leaked = Some(s.var(1));
+//!   });
+//!
+//!   s.main_fun(|s: &mut Scope<()>| {
+//!     // the variable is only valid in the function that declared it
+//!     s.var(leaked.unwrap());
+//!   })
+//! });
+//!
+//! assert_eq!(
+//!   shader.validate(),
+//!   vec![Diagnostic::UndeclaredVariable {
+//!     declaration: Declaration::Main,
+//!     scope: 0,
+//!     handle: 0,
+//!   }]
+//! );
+//! ```
+
+use crate::{
+  call_graph::CallGraph, optimizer::walk_expr, ErasedExpr, ErasedFun, ErasedFunHandle,
+  ErasedReturn, ErasedScope, ScopeInstr, ScopedHandle, Shader, ShaderDecl, ShaderStage, Type,
+};
+use std::{collections::HashSet, fmt};
+
+/// Declaration of a shader in which a [`Diagnostic`] or a [`WriteError`](crate::writer::WriteError) was found.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Declaration {
+  /// The `main` function.
+  Main,
+
+  /// A function declared with [`ShaderBuilder::fun`](crate::ShaderBuilder::fun).
+  ///
+  /// Functions are numbered in declaration order, starting from `0`.
+  Function(u16),
+
+  /// A constant declared with [`ShaderBuilder::constant`](crate::ShaderBuilder::constant).
+  ///
+  /// Constants are numbered in declaration order, starting from `0`.
+  Constant(u16),
+
+  /// An input, output or uniform, identified by its name.
+  Interface(String),
+}
+
+impl fmt::Display for Declaration {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Declaration::Main => f.write_str("main"),
+      Declaration::Function(handle) => write!(f, "function {}", handle),
+      Declaration::Constant(handle) => write!(f, "constant {}", handle),
+      Declaration::Interface(name) => write!(f, "`{}`", name),
+    }
+  }
+}
+
+/// Mistakes found in a shader by [`Shader::validate`].
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum Diagnostic {
+  /// A variable is used outside of the scope that declared it.
+  ///
+  /// This happens when a [`Var`](crate::Var) leaks out of the closure it was declared in, such as the body of a
+  /// function or of a conditional statement. Variables are identified by their scope and their declaration order in
+  /// that scope. Every scope of a shader has its own identifier, so leaked variables are detected unless
+  /// [`Diagnostic::TooManyScopes`] is reported as well.
+  UndeclaredVariable {
+    /// Declaration using the variable.
+    declaration: Declaration,
+
+    /// Identifier of the scope that declared the variable.
+    scope: u16,
+
+    /// Declaration order of the variable in its scope.
+    handle: u16,
+  },
+
+  /// A function argument is used outside of the function it belongs to.
+  UndeclaredArgument {
+    /// Declaration using the argument.
+    declaration: Declaration,
+
+    /// Position of the argument.
+    index: u16,
+  },
+
+  /// A constant is used before being declared.
+  UndeclaredConstant {
+    /// Declaration using the constant.
+    declaration: Declaration,
+
+    /// Handle of the constant.
+    handle: u16,
+  },
+
+  /// A function is called without being declared by the shader.
+  UndeclaredFunction {
+    /// Declaration calling the function.
+    declaration: Declaration,
+
+    /// Handle of the function.
+    handle: u16,
+  },
+
+  /// A constant of a [`Library`](crate::library::Library) is used without being imported.
+  ///
+  /// Constants of a library can only be used by the functions of the library; they are imported into shaders along
+  /// with the functions using them.
+  UnimportedConstant {
+    /// Declaration using the constant.
+    declaration: Declaration,
+
+    /// Handle of the constant in its library.
+    handle: u16,
+  },
+
+  /// A function of a [`Library`](crate::library::Library) is called without being imported.
+  ///
+  /// Functions of a library can only be called by the functions of the library, or by shaders after importing them
+  /// with [`ShaderBuilder::import`](crate::ShaderBuilder::import).
+  UnimportedFunction {
+    /// Declaration calling the function.
+    declaration: Declaration,
+
+    /// Handle of the function in its library.
+    handle: u16,
+  },
+
+  /// An input, output or uniform is used without being declared by the shader, as when using an input of a shader
+  /// stage in another one.
+  UndeclaredInterface {
+    /// Declaration using the input, output or uniform.
+    declaration: Declaration,
+
+    /// Name of the input, output or uniform.
+    name: String,
+  },
+
+  /// A `break` statement is not in a loop.
+  BreakOutsideLoop(Declaration),
+
+  /// A `continue` statement is not in a loop.
+  ContinueOutsideLoop(Declaration),
+
+  /// A `discard` statement is in a shader of another stage than the fragment one.
+  ///
+  /// This happens when a [`FragmentShaderEnv`](crate::FragmentShaderEnv) leaks out of the closure building a fragment
+  /// shader, or when the statement is added with the [`ir`](crate::ir) module.
+  DiscardOutsideFragmentShader(Declaration),
+
+  /// A function calls itself, directly or through other functions.
+  ///
+  /// GLSL forbids recursion. Functions can only call functions declared before them in the EDSL, but shaders built
+  /// from the IR can form call cycles.
+  Recursion(Declaration),
+
+  /// A function returning a value returns without one.
+  MissingReturnValue(Declaration),
+
+  /// A function returning nothing, such as `main`, returns a value.
+  UnexpectedReturnValue(Declaration),
+
+  /// A function returns values of different types.
+  ReturnTypeMismatch {
+    /// Function returning the values.
+    declaration: Declaration,
+
+    /// Type returned at the end of the function.
+    expected: Type,
+
+    /// Type of the mismatching returned value.
+    found: Type,
+  },
+
+  /// A function takes or returns an unsized array.
+  ///
+  /// Arrays passed to and returned from functions must have a known size, such as [`Expr<[T; N]>`](crate::Expr).
+  UnsizedArray(Declaration),
+
+  /// Several functions or constants have the same handle.
+  ///
+  /// Handles wrap around when more than 65536 functions or constants are declared. Shaders built from the IR can also
+  /// reuse handles. The declaration is the function or constant whose handle is already used.
+  DuplicateHandle(Declaration),
+
+  /// More than 65536 variables are declared in a single scope.
+  TooManyVariables(Declaration),
+
+  /// Several scopes have the same identifier.
+  ///
+  /// Identifiers wrap around when more than 65536 scopes are declared. Shaders built from the IR can also reuse
+  /// identifiers. The declaration is the one containing a scope whose identifier is already used.
+  TooManyScopes(Declaration),
+}
+
+impl fmt::Display for Diagnostic {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Diagnostic::UndeclaredVariable {
+        declaration,
+        scope,
+        handle,
+      } => write!(
+        f,
+        "{}: variable {} of scope {} is used outside of its scope",
+        declaration, handle, scope
+      ),
+      Diagnostic::UndeclaredArgument { declaration, index } => write!(
+        f,
+        "{}: argument {} is used outside of its function",
+        declaration, index
+      ),
+      Diagnostic::UndeclaredConstant {
+        declaration,
+        handle,
+      } => write!(
+        f,
+        "{}: constant {} is used before being declared",
+        declaration, handle
+      ),
+      Diagnostic::UndeclaredFunction {
+        declaration,
+        handle,
+      } => write!(
+        f,
+        "{}: function {} is not declared by the shader",
+        declaration, handle
+      ),
+      Diagnostic::UnimportedConstant {
+        declaration,
+        handle,
+      } => write!(
+        f,
+        "{}: constant {} of a library is used without being imported",
+        declaration, handle
+      ),
+      Diagnostic::UnimportedFunction {
+        declaration,
+        handle,
+      } => write!(
+        f,
+        "{}: function {} of a library is called without being imported",
+        declaration, handle
+      ),
+      Diagnostic::UndeclaredInterface { declaration, name } => {
+        write!(
+          f,
+          "{}: `{}` is not declared by the shader",
+          declaration, name
+        )
+      }
+      Diagnostic::BreakOutsideLoop(declaration) => {
+        write!(f, "{}: `break` outside of a loop", declaration)
+      }
+      Diagnostic::ContinueOutsideLoop(declaration) => {
+        write!(f, "{}: `continue` outside of a loop", declaration)
+      }
+      Diagnostic::DiscardOutsideFragmentShader(declaration) => {
+        write!(f, "{}: `discard` outside of a fragment shader", declaration)
+      }
+      Diagnostic::Recursion(declaration) => {
+        write!(f, "{}: recursive function", declaration)
+      }
+      Diagnostic::MissingReturnValue(declaration) => {
+        write!(f, "{}: missing return value", declaration)
+      }
+      Diagnostic::UnexpectedReturnValue(declaration) => {
+        write!(f, "{}: unexpected return value", declaration)
+      }
+      Diagnostic::ReturnTypeMismatch {
+        declaration,
+        expected,
+        found,
+      } => write!(
+        f,
+        "{}: returned value has type {:?} instead of {:?}",
+        declaration, found, expected
+      ),
+      Diagnostic::UnsizedArray(declaration) => {
+        write!(f, "{}: unsized array argument or return value", declaration)
+      }
+      Diagnostic::DuplicateHandle(declaration) => {
+        write!(f, "{}: handle already used", declaration)
+      }
+      Diagnostic::TooManyVariables(declaration) => {
+        write!(f, "{}: too many variables in a single scope", declaration)
+      }
+      Diagnostic::TooManyScopes(declaration) => {
+        write!(f, "{}: too many scopes", declaration)
+      }
+    }
+  }
+}
+
+impl std::error::Error for Diagnostic {}
+
+pub(crate) fn validate(shader: &Shader) -> Vec<Diagnostic> {
+  let mut validator = Validator::new(shader.stage);
+
+  // functions can be called before their declaration, as writers declare them before their callers
+  for decl in &shader.decls {
+    if let ShaderDecl::FunDef(handle, _) = decl {
+      if !validator.funs.insert(*handle) {
+        validator.report_once(Diagnostic::DuplicateHandle(Declaration::Function(*handle)));
+      }
+    }
+  }
+
+  for decl in &shader.decls {
+    match decl {
+      ShaderDecl::Main(fun) => validator.validate_fun(Declaration::Main, fun),
+
+      ShaderDecl::FunDef(handle, fun) => {
+        validator.validate_fun(Declaration::Function(*handle), fun)
+      }
+
+      ShaderDecl::Const(handle, _, expr, _) => {
+        validator.declaration = Declaration::Constant(*handle);
+        validator.args = 0;
+        validator.validate_expr(expr);
+
+        if !validator.globals.insert(*handle) {
+          validator.report_once(Diagnostic::DuplicateHandle(Declaration::Constant(*handle)));
+        }
+      }
+
+      ShaderDecl::In(name, _) | ShaderDecl::Out(name, _, _) | ShaderDecl::Uniform(name, _) => {
+        validator.interface.insert(name);
+      }
+    }
+  }
+
+  for handle in CallGraph::new(&shader.decls).recursive_funs() {
+    validator.report(Diagnostic::Recursion(Declaration::Function(handle)));
+  }
+
+  validator.diagnostics
+}
+
+/// Declarations visible at a given point of a shader.
+struct Validator<'a> {
+  stage: ShaderStage,
+  diagnostics: Vec<Diagnostic>,
+  declaration: Declaration,
+  // all the functions of the shader, wherever they are declared
+  funs: HashSet<u16>,
+  globals: HashSet<u16>,
+  interface: HashSet<&'a str>,
+  // number of arguments of the current function
+  args: usize,
+  // variables declared by each enclosing scope, innermost last
+  scopes: Vec<HashSet<ScopedHandle>>,
+  // identifiers of all the scopes met so far, wherever they are declared
+  scope_ids: HashSet<u16>,
+  loops: usize,
+}
+
+impl<'a> Validator<'a> {
+  fn new(stage: ShaderStage) -> Self {
+    Self {
+      stage,
+      diagnostics: Vec::new(),
+      declaration: Declaration::Main,
+      funs: HashSet::new(),
+      globals: HashSet::new(),
+      interface: HashSet::new(),
+      args: 0,
+      scopes: Vec::new(),
+      scope_ids: HashSet::new(),
+      loops: 0,
+    }
+  }
+
+  fn report(&mut self, diagnostic: Diagnostic) {
+    self.diagnostics.push(diagnostic);
+  }
+
+  // Report a diagnostic that would otherwise be reported for every offending declaration.
+  fn report_once(&mut self, diagnostic: Diagnostic) {
+    if !self.diagnostics.contains(&diagnostic) {
+      self.report(diagnostic);
+    }
+  }
+
+  fn validate_fun(&mut self, declaration: Declaration, fun: &ErasedFun) {
+    self.declaration = declaration.clone();
+    self.args = fun.args.len();
+
+    let ret_ty = match &fun.ret {
+      ErasedReturn::Expr(ty, _) => Some(ty),
+      ErasedReturn::Void => None,
+    };
+    if fun
+      .args
+      .iter()
+      .chain(ret_ty)
+      .any(|ty| ty.array_dims.contains(&0))
+    {
+      self.report(Diagnostic::UnsizedArray(declaration.clone()));
+    }
+
+    self.validate_scope(&fun.scope, None);
+
+    // the returned expression is evaluated in the top-level scope of the function
+    self.scopes.push(fun_scope_vars(&fun.scope));
+
+    match (&declaration, &fun.ret) {
+      (Declaration::Main, ErasedReturn::Expr(..)) => {
+        self.report(Diagnostic::UnexpectedReturnValue(declaration.clone()))
+      }
+      (_, ErasedReturn::Expr(_, expr)) => self.validate_expr(expr),
+      _ => (),
+    }
+
+    self.scopes.pop();
+
+    let expected = match &fun.ret {
+      ErasedReturn::Expr(ty, _) if declaration != Declaration::Main => Some(ty),
+      _ => None,
+    };
+    self.validate_returns(&fun.scope, expected);
+  }
+
+  fn validate_scope(&mut self, scope: &ErasedScope, loop_var: Option<&ScopedHandle>) {
+    if !self.scope_ids.insert(scope.id) {
+      self.report_once(Diagnostic::TooManyScopes(self.declaration.clone()));
+    }
+
+    let mut vars = HashSet::new();
+    vars.extend(loop_var.cloned());
+    self.scopes.push(vars);
+
+    for instr in &scope.instructions {
+      match instr {
+        ScopeInstr::VarDecl {
+          handle, init_value, ..
+        } => {
+          self.validate_expr(init_value);
+          self.declare(handle);
+        }
+
+        ScopeInstr::Return(ErasedReturn::Expr(_, expr)) => self.validate_expr(expr),
+
+        ScopeInstr::Discard if self.stage != ShaderStage::Fragment => self.report(
+          Diagnostic::DiscardOutsideFragmentShader(self.declaration.clone()),
+        ),
+
+        ScopeInstr::Return(ErasedReturn::Void) | ScopeInstr::Discard => (),
+
+        ScopeInstr::Continue if self.loops == 0 => {
+          self.report(Diagnostic::ContinueOutsideLoop(self.declaration.clone()))
+        }
+
+        ScopeInstr::Break if self.loops == 0 => {
+          self.report(Diagnostic::BreakOutsideLoop(self.declaration.clone()))
+        }
+
+        ScopeInstr::Continue | ScopeInstr::Break => (),
+
+        ScopeInstr::If { condition, scope } | ScopeInstr::ElseIf { condition, scope } => {
+          self.validate_expr(condition);
+          self.validate_scope(scope, None);
+        }
+
+        ScopeInstr::Else { scope } => self.validate_scope(scope, None),
+
+        ScopeInstr::For {
+          init_handle,
+          init_expr,
+          condition,
+          post_expr,
+          scope,
+          ..
+        } => {
+          self.validate_expr(init_expr);
+
+          // the loop variable belongs to the scope of the loop
+          self
+            .scopes
+            .push(Some(init_handle.clone()).into_iter().collect());
+          self.validate_expr(condition);
+          self.validate_expr(post_expr);
+          self.scopes.pop();
+
+          self.loops += 1;
+          self.validate_scope(scope, Some(init_handle));
+          self.loops -= 1;
+        }
+
+        ScopeInstr::While { condition, scope } => {
+          self.validate_expr(condition);
+
+          self.loops += 1;
+          self.validate_scope(scope, None);
+          self.loops -= 1;
+        }
+
+        ScopeInstr::MutateVar { var, expr } => {
+          self.validate_expr(var);
+          self.validate_expr(expr);
+        }
+      }
+    }
+
+    self.scopes.pop();
+  }
+
+  fn declare(&mut self, handle: &ScopedHandle) {
+    let declaration = self.declaration.clone();
+
+    if let Some(vars) = self.scopes.last_mut() {
+      // handles wrap around when too many variables are declared
+      if !vars.insert(handle.clone()) {
+        self.report_once(Diagnostic::TooManyVariables(declaration));
+      }
+    }
+  }
+
+  fn validate_expr(&mut self, expr: &ErasedExpr) {
+    let mut diagnostics = Vec::new();
+
+    walk_expr(expr, &mut |expr| {
+      if let Some(diagnostic) = self.check_expr(expr) {
+        diagnostics.push(diagnostic);
+      }
+    });
+
+    for diagnostic in diagnostics {
+      self.report_once(diagnostic);
+    }
+  }
+
+  fn check_expr(&self, expr: &ErasedExpr) -> Option<Diagnostic> {
+    let declaration = self.declaration.clone();
+
+    match expr {
+      ErasedExpr::Var(ScopedHandle::FunVar { subscope, handle })
+        if !self.scopes.iter().any(|vars| {
+          vars.contains(&ScopedHandle::FunVar {
+            subscope: *subscope,
+            handle: *handle,
+          })
+        }) =>
+      {
+        Some(Diagnostic::UndeclaredVariable {
+          declaration,
+          scope: *subscope,
+          handle: *handle,
+        })
+      }
+
+      ErasedExpr::Var(ScopedHandle::FunArg(index)) if *index as usize >= self.args => {
+        Some(Diagnostic::UndeclaredArgument {
+          declaration,
+          index: *index,
+        })
+      }
+
+      ErasedExpr::Var(ScopedHandle::Global(handle)) if !self.globals.contains(handle) => {
+        Some(Diagnostic::UndeclaredConstant {
+          declaration,
+          handle: *handle,
+        })
+      }
+
+      ErasedExpr::Var(ScopedHandle::LibraryGlobal(_, handle)) => {
+        Some(Diagnostic::UnimportedConstant {
+          declaration,
+          handle: *handle,
+        })
+      }
+
+      ErasedExpr::Var(ScopedHandle::Input(name))
+      | ErasedExpr::Var(ScopedHandle::Output(name))
+      | ErasedExpr::Var(ScopedHandle::Uniform(name))
+        if !self.interface.contains(name.as_str()) =>
+      {
+        Some(Diagnostic::UndeclaredInterface {
+          declaration,
+          name: name.clone(),
+        })
+      }
+
+      ErasedExpr::FunCall(ErasedFunHandle::UserDefined(handle), _)
+        if !self.funs.contains(handle) =>
+      {
+        Some(Diagnostic::UndeclaredFunction {
+          declaration,
+          handle: *handle,
+        })
+      }
+
+      ErasedExpr::FunCall(ErasedFunHandle::Library(_, handle), _) => {
+        Some(Diagnostic::UnimportedFunction {
+          declaration,
+          handle: *handle,
+        })
+      }
+
+      _ => None,
+    }
+  }
+
+  fn validate_returns(&mut self, scope: &ErasedScope, expected: Option<&Type>) {
+    for instr in &scope.instructions {
+      match instr {
+        ScopeInstr::Return(ret) => match (expected, ret) {
+          (Some(_), ErasedReturn::Void) => {
+            self.report(Diagnostic::MissingReturnValue(self.declaration.clone()))
+          }
+
+          (None, ErasedReturn::Expr(..)) => {
+            self.report(Diagnostic::UnexpectedReturnValue(self.declaration.clone()))
+          }
+
+          (Some(expected), ErasedReturn::Expr(found, _)) if expected != found => {
+            self.report(Diagnostic::ReturnTypeMismatch {
+              declaration: self.declaration.clone(),
+              expected: expected.clone(),
+              found: found.clone(),
+            })
+          }
+
+          _ => (),
+        },
+
+        ScopeInstr::If { scope, .. }
+        | ScopeInstr::ElseIf { scope, .. }
+        | ScopeInstr::Else { scope }
+        | ScopeInstr::For { scope, .. }
+        | ScopeInstr::While { scope, .. } => self.validate_returns(scope, expected),
+
+        _ => (),
+      }
+    }
+  }
+}
+
+// Variables declared in the top-level scope of a function.
+fn fun_scope_vars(scope: &ErasedScope) -> HashSet<ScopedHandle> {
+  scope
+    .instructions
+    .iter()
+    .filter_map(|instr| match instr {
+      ScopeInstr::VarDecl { handle, .. } => Some(handle.clone()),
+      _ => None,
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    lit,
+    writer::{glsl::write_shader_to_str, WriteError},
+    CanEscape as _, EscapeScope, Expr, Scope, ShaderBuilder, Var,
+  };
+  use std::sync::Arc;
+
+  #[test]
+  fn valid_shader() {
+    let shader = ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+      let x = s.input::<f32>("x").unwrap();
+      let k = s.constant(2.);
+      let double = s.fun(|_: &mut Scope<Expr<f32>>, a: Expr<f32>| a * 2.);
+
+      s.main_fun(|s: &mut Scope<()>| {
+        let y = s.var(double.call(x.clone()) * k.clone());
+
+        s.loop_for(
+          0,
+          |i| i.lt(10),
+          |i| i + 1,
+          |s, _| {
+            s.when(y.lt(0.), |s| s.loop_break());
+          },
+        );
+
+        s.set(&vertex.point_size, y);
+      })
+    });
+
+    assert_eq!(shader.validate(), Vec::new());
+  }
+
+  #[test]
+  fn variable_out_of_scope() {
+    let mut leaked: Option<Var<i32>> = None;
+
+    let shader = ShaderBuilder::new_vertex_shader(|s, _| {
+      s.main_fun(|s: &mut Scope<()>| {
+        s.when(lit!(true), |s: &mut EscapeScope<()>| {
+          leaked = Some(s.var(1));
+        });
+
+        s.var(leaked.unwrap() + 1);
+      })
+    });
+
+    assert_eq!(
+      shader.validate(),
+      vec![Diagnostic::UndeclaredVariable {
+        declaration: Declaration::Main,
+        scope: 1,
+        handle: 0,
+      }]
+    );
+    assert!(matches!(
+      write_shader_to_str(&shader),
+      Err(WriteError::InvalidShader(_))
+    ));
+  }
+
+  #[test]
+  fn variable_of_another_function() {
+    let mut leaked: Option<Var<i32>> = None;
+
+    let shader = ShaderBuilder::new_vertex_shader(|mut s, _| {
+      s.fun(|s: &mut Scope<()>, _: Expr<i32>| {
+        leaked = Some(s.var(1));
+      });
+
+      // the variable has the same declaration order as the one of the function using it
+      s.fun(|s: &mut Scope<()>, _: Expr<i32>| {
+        let x = s.var(2);
+        s.set(&x, leaked.unwrap());
+      });
+
+      s.main_fun(|_: &mut Scope<()>| {})
+    });
+
+    assert_eq!(
+      shader.validate(),
+      vec![Diagnostic::UndeclaredVariable {
+        declaration: Declaration::Function(1),
+        scope: 0,
+        handle: 0,
+      }]
+    );
+  }
+
+  #[test]
+  fn duplicate_scopes() {
+    let mut shader = ShaderBuilder::new_vertex_shader(|mut s, _| {
+      s.fun(|_: &mut Scope<()>, _: Expr<i32>| {});
+      s.fun(|_: &mut Scope<()>, _: Expr<i32>| {});
+      s.main_fun(|_: &mut Scope<()>| {})
+    });
+
+    assert_eq!(shader.validate(), Vec::new());
+
+    // identifiers wrap around after 65536 scopes, so that scopes of different functions can share one
+    if let ShaderDecl::FunDef(_, fun) = &mut shader.decls[1] {
+      fun.scope.id = 0;
+    }
+
+    assert_eq!(
+      shader.validate(),
+      vec![Diagnostic::TooManyScopes(Declaration::Function(1))]
+    );
+  }
+
+  #[test]
+  fn interface_of_another_stage() {
+    let mut x = None;
+
+    ShaderBuilder::new_vertex_shader(|mut s, _| {
+      x = Some(s.input::<f32>("x").unwrap());
+      s.main_fun(|_: &mut Scope<()>| {})
+    });
+
+    let shader = ShaderBuilder::new_fragment_shader(|s, _| {
+      s.main_fun(|s: &mut Scope<()>| {
+        s.var(x.unwrap());
+      })
+    });
+
+    assert_eq!(
+      shader.validate(),
+      vec![Diagnostic::UndeclaredInterface {
+        declaration: Declaration::Main,
+        name: "x".to_owned(),
+      }]
+    );
+  }
+
+  #[test]
+  fn discard_outside_fragment_shader() {
+    let mut vertex_shader = None;
+
+    let fragment_shader = ShaderBuilder::new_fragment_shader(|s, fragment| {
+      vertex_shader = Some(ShaderBuilder::new_vertex_shader(|s, _| {
+        s.main_fun(|s: &mut Scope<()>| fragment.discard(s))
+      }));
+
+      s.main_fun(|s: &mut Scope<()>| fragment.discard(s))
+    });
+
+    assert!(fragment_shader.validate().is_empty());
+    assert_eq!(
+      vertex_shader.unwrap().validate(),
+      vec![Diagnostic::DiscardOutsideFragmentShader(Declaration::Main)]
+    );
+  }
+
+  #[test]
+  fn break_outside_loop() {
+    let mut shader = ShaderBuilder::new_vertex_shader(|s, _| {
+      s.main_fun(|s: &mut Scope<()>| {
+        s.loop_while(true, |s| s.loop_break());
+      })
+    });
+
+    if let ShaderDecl::Main(fun) = &mut shader.decls[0] {
+      fun.scope.instructions.push(ScopeInstr::Continue);
+    }
+
+    assert_eq!(
+      shader.validate(),
+      vec![Diagnostic::ContinueOutsideLoop(Declaration::Main)]
+    );
+  }
+
+  #[test]
+  fn returns() {
+    let shader =
+      ShaderBuilder::new_vertex_shader(|s, _| s.main_fun(|_: &mut Scope<Expr<i32>>| lit!(1)));
+
+    assert_eq!(
+      shader.validate(),
+      vec![Diagnostic::UnexpectedReturnValue(Declaration::Main)]
+    );
+
+    let mut shader = ShaderBuilder::new_vertex_shader(|mut s, _| {
+      s.fun(|s: &mut Scope<Expr<i32>>, a: Expr<i32>| {
+        s.when(a.lt(0), |s| s.leave(0));
+        a
+      });
+
+      s.main_fun(|_: &mut Scope<()>| {})
+    });
+
+    if let ShaderDecl::FunDef(_, fun) = &mut shader.decls[0] {
+      fun
+        .scope
+        .instructions
+        .push(ScopeInstr::Return(ErasedReturn::Void));
+      fun
+        .scope
+        .instructions
+        .push(ScopeInstr::Return(ErasedReturn::Expr(
+          <f32 as crate::ToType>::ty(),
+          ErasedExpr::LitFloat(0.),
+        )));
+    }
+
+    assert_eq!(
+      shader.validate(),
+      vec![
+        Diagnostic::MissingReturnValue(Declaration::Function(0)),
+        Diagnostic::ReturnTypeMismatch {
+          declaration: Declaration::Function(0),
+          expected: <i32 as crate::ToType>::ty(),
+          found: <f32 as crate::ToType>::ty(),
+        },
+      ]
+    );
+  }
+
+  #[test]
+  fn calls() {
+    let mut shader = ShaderBuilder::new_vertex_shader(|mut s, _| {
+      let f = s.fun(|_: &mut Scope<Expr<i32>>, a: Expr<i32>| a);
+      s.fun(|_: &mut Scope<Expr<i32>>, a: Expr<i32>| f.call(a));
+      s.fun(|_: &mut Scope<Expr<i32>>, a: Expr<i32>| a);
+      s.main_fun(|_: &mut Scope<()>| {})
+    });
+
+    // calls to other functions can only be changed from the IR
+    fn set_callee(shader: &mut Shader, decl: usize, callee: u16) {
+      if let ShaderDecl::FunDef(_, fun) = &mut shader.decls[decl] {
+        let arg = ErasedExpr::Var(ScopedHandle::fun_arg(0));
+        let call = ErasedExpr::FunCall(ErasedFunHandle::UserDefined(callee), vec![Arc::new(arg)]);
+        fun.ret = ErasedReturn::Expr(<i32 as crate::ToType>::ty(), call);
+      }
+    }
+
+    // functions declared later can be called
+    set_callee(&mut shader, 0, 2);
+    assert_eq!(shader.validate(), Vec::new());
+
+    set_callee(&mut shader, 0, 1);
+    set_callee(&mut shader, 2, 7);
+
+    assert_eq!(
+      shader.validate(),
+      vec![
+        Diagnostic::UndeclaredFunction {
+          declaration: Declaration::Function(2),
+          handle: 7,
+        },
+        Diagnostic::Recursion(Declaration::Function(0)),
+        Diagnostic::Recursion(Declaration::Function(1)),
+      ]
+    );
+  }
+
+  #[test]
+  fn unsized_arrays() {
+    let mut shader = ShaderBuilder::new_vertex_shader(|mut s, _| {
+      s.fun(|_: &mut Scope<Expr<[[f32; 2]; 3]>>, a: Expr<[[f32; 2]; 3]>| a);
+      s.main_fun(|_: &mut Scope<()>| {})
+    });
+
+    assert_eq!(shader.validate(), Vec::new());
+
+    // only shaders built from the IR, such as imported ones, can take unsized arrays
+    if let ShaderDecl::FunDef(_, fun) = &mut shader.decls[0] {
+      fun.args[0] = <[[f32; 2]] as crate::ToType>::ty();
+    }
+
+    assert_eq!(
+      shader.validate(),
+      vec![Diagnostic::UnsizedArray(Declaration::Function(0))]
+    );
+  }
+
+  #[test]
+  fn duplicate_handles() {
+    let shader = ShaderBuilder::new_vertex_shader(|mut s, _| {
+      for _ in 0..(1 << 16) + 1 {
+        s.fun(|_: &mut Scope<()>, _: Expr<i32>| {});
+      }
+
+      s.main_fun(|_: &mut Scope<()>| {})
+    });
+
+    // scope identifiers wrap around as well
+    assert_eq!(
+      shader.validate(),
+      vec![
+        Diagnostic::DuplicateHandle(Declaration::Function(0)),
+        Diagnostic::TooManyScopes(Declaration::Function(0)),
+        Diagnostic::TooManyScopes(Declaration::Main),
+      ]
+    );
+
+    let mut shader = ShaderBuilder::new_vertex_shader(|mut s, _| {
+      s.constant(1);
+      s.main_fun(|_: &mut Scope<()>| {})
+    });
+    let constant = shader.decls[0].clone();
+    shader.decls.insert(0, constant);
+
+    assert_eq!(
+      shader.validate(),
+      vec![Diagnostic::DuplicateHandle(Declaration::Constant(0))]
+    );
+  }
+}
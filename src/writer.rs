@@ -1,2 +1,264 @@
 //! All available _shades -> lang_ writers.
 pub mod glsl;
+pub mod rust;
+
+use crate::{
+  validation::{Declaration, Diagnostic},
+  BuiltIn, Type,
+};
+use std::{collections::BTreeMap, fmt, panic::Location};
+
+/// Errors that can occur when writing a shader.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum WriteError {
+  /// Formatting the output failed, as reported by the [`fmt::Write`] the shader is written to.
+  Fmt(fmt::Error),
+
+  /// The shader is invalid.
+  ///
+  /// Those are the diagnostics reported by [`Shader::validate`](crate::Shader::validate).
+  InvalidShader(Vec<Diagnostic>),
+
+  /// The shader uses a construct the target language doesn’t support.
+  Unsupported {
+    /// Declaration using the construct.
+    declaration: Declaration,
+
+    /// The unsupported construct.
+    construct: Construct,
+  },
+}
+
+impl fmt::Display for WriteError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      WriteError::Fmt(_) => f.write_str("cannot format the output"),
+      WriteError::InvalidShader(diagnostics) => {
+        f.write_str("invalid shader")?;
+
+        for diagnostic in diagnostics {
+          write!(f, "\n  {}", diagnostic)?;
+        }
+
+        Ok(())
+      }
+      WriteError::Unsupported {
+        declaration,
+        construct,
+      } => write!(
+        f,
+        "{}: {} is not supported by the target",
+        declaration, construct
+      ),
+    }
+  }
+}
+
+impl std::error::Error for WriteError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      WriteError::Fmt(e) => Some(e),
+      _ => None,
+    }
+  }
+}
+
+impl From<fmt::Error> for WriteError {
+  fn from(e: fmt::Error) -> Self {
+    WriteError::Fmt(e)
+  }
+}
+
+/// Constructs that a target language might not support.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum Construct {
+  /// A built-in, such as the built-ins of a shader stage the target doesn’t have.
+  BuiltIn(BuiltIn),
+
+  /// A built-in function, by its name in the target language.
+  Function(String),
+
+  /// A type, such as arrays of arrays.
+  Type(Type),
+
+  /// The index of a [`ColorAttachment`](crate::ColorAttachment), used for dual-source blending.
+  ColorAttachmentIndex,
+
+  /// A statement or a declaration, such as an assignment to a swizzle.
+  Statement(String),
+
+  /// An expression, such as an operator applied to operands it isn’t defined for.
+  Expression(String),
+}
+
+impl fmt::Display for Construct {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Construct::BuiltIn(builtin) => write!(f, "built-in {:?}", builtin),
+      Construct::Function(name) => write!(f, "function `{}`", name),
+      Construct::Type(ty) => write!(f, "type {:?}", ty),
+      Construct::ColorAttachmentIndex => f.write_str("colour attachment index"),
+      Construct::Statement(statement) => f.write_str(statement),
+      Construct::Expression(expr) => f.write_str(expr),
+    }
+  }
+}
+
+/// Mapping from the lines of generated code back to the Rust code that built them.
+///
+/// The EDSL records the location of each statement — variable declarations, assignments, conditionals, loops, etc. —
+/// and of each function declaration. When writing a shader, the lines where those statements start are mapped to
+/// their locations. This is useful to make sense of the errors a driver reports when compiling a shader: see
+/// [`SourceMap::rewrite_log`].
+///
+/// Lines are numbered from 1.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SourceMap {
+  // lines where statements start, with their location, or none for lines starting something built without one
+  lines: BTreeMap<usize, Option<&'static Location<'static>>>,
+}
+
+impl SourceMap {
+  pub(crate) fn insert(&mut self, line: usize, location: Option<&'static Location<'static>>) {
+    self.lines.insert(line, location);
+  }
+
+  /// Location of the statement the given line belongs to, if any.
+  ///
+  /// A line belongs to the closest statement starting at or before it.
+  pub fn location(&self, line: usize) -> Option<&'static Location<'static>> {
+    self
+      .lines
+      .range(..=line)
+      .next_back()
+      .and_then(|(_, location)| *location)
+  }
+
+  /// Iterate over the lines where statements start, along with their locations.
+  pub fn iter(&self) -> impl Iterator<Item = (usize, &'static Location<'static>)> + '_ {
+    self
+      .lines
+      .iter()
+      .filter_map(|(line, location)| location.map(|location| (*line, location)))
+  }
+
+  /// Shift the mapped lines by the given number of lines.
+  ///
+  /// Use this if the generated code is prefixed before being passed to the driver, with a `#version` directive for
+  /// instance.
+  pub fn with_line_offset(self, offset: usize) -> Self {
+    let lines = self
+      .lines
+      .into_iter()
+      .map(|(line, location)| (line + offset, location))
+      .collect();
+
+    Self { lines }
+  }
+
+  /// Rewrite the line references of a driver’s info log into Rust locations.
+  ///
+  /// The usual formats of line references are recognized: `0:12` (as in `ERROR: 0:12: …`), `0:12(5)` (Mesa, with a
+  /// column) and `0(12)` (NVIDIA). The first reference of each line of the log is replaced with the `file.rs:line`
+  /// location of the statement it points to, if any; everything else is left untouched.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use shades::{Scope, ShaderBuilder};
+  /// use shades::writer::glsl::{WriteOptions, write_shader_to_str_with_source_map};
+  ///
+  /// let shader = ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+  ///   s.main_fun(|s: &mut Scope<()>| {
+  ///     let x = s.var(1.);
+  ///   })
+  /// });
+  ///
+  /// let (output, source_map) =
+  ///   write_shader_to_str_with_source_map(&shader, &WriteOptions::new()).unwrap();
+  /// assert_eq!(output, "\nvoid main() {\n  float var_0_0 = 1.;\n}");
+  ///
+  /// // the variable declaration is on the third line
+  /// let location = source_map.location(3).unwrap();
+  /// assert_eq!(location.file(), file!());
+  ///
+  /// let log = source_map.rewrite_log("0:3(9): error: something went wrong");
+  /// assert_eq!(log, format!("{}:{}: error: something went wrong", file!(), location.line()));
+  /// ```
+  pub fn rewrite_log(&self, log: &str) -> String {
+    let mut rewritten = String::with_capacity(log.len());
+
+    for (i, log_line) in log.split('\n').enumerate() {
+      if i != 0 {
+        rewritten.push('\n');
+      }
+
+      match find_line_ref(log_line).and_then(|(range, line)| Some((range, self.location(line)?))) {
+        Some(((start, end), location)) => {
+          rewritten.push_str(&log_line[..start]);
+          rewritten.push_str(&format!("{}:{}", location.file(), location.line()));
+          rewritten.push_str(&log_line[end..]);
+        }
+
+        None => rewritten.push_str(log_line),
+      }
+    }
+
+    rewritten
+  }
+}
+
+// Find the first line reference of a line of log — `0:12`, `0:12(5)` or `0(12)` — and return its byte range and line.
+fn find_line_ref(log_line: &str) -> Option<((usize, usize), usize)> {
+  let bytes = log_line.as_bytes();
+  let digits = |from: usize| {
+    bytes[from..]
+      .iter()
+      .take_while(|b| b.is_ascii_digit())
+      .count()
+  };
+
+  for start in 0..bytes.len() {
+    if !bytes[start].is_ascii_digit() || (start > 0 && bytes[start - 1].is_ascii_alphanumeric()) {
+      continue;
+    }
+
+    let source_end = start + digits(start);
+    let (line_start, closing) = match bytes.get(source_end) {
+      Some(b':') => (source_end + 1, None),
+      Some(b'(') => (source_end + 1, Some(b')')),
+      _ => continue,
+    };
+
+    let line_end = line_start + digits(line_start);
+    if line_end == line_start {
+      continue;
+    }
+
+    let end = match closing {
+      Some(closing) if bytes.get(line_end) == Some(&closing) => line_end + 1,
+      Some(_) => continue,
+
+      // skip the column, if any
+      None if bytes.get(line_end) == Some(&b'(') => {
+        let column_end = line_end + 1 + digits(line_end + 1);
+
+        if column_end > line_end + 1 && bytes.get(column_end) == Some(&b')') {
+          column_end + 1
+        } else {
+          line_end
+        }
+      }
+
+      None => line_end,
+    };
+
+    if let Ok(line) = log_line[line_start..line_end].parse() {
+      return Some(((start, end), line));
+    }
+  }
+
+  None
+}
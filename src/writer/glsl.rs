@@ -1,96 +1,1202 @@
 //! GLSL writers.
+//!
+//! Shaders are validated before being written: writing a shader for which [`Shader::validate`] reports diagnostics
+//! fails with [`WriteError::InvalidShader`]. Shaders using constructs the [`Target`] doesn’t support fail with
+//! [`WriteError::Unsupported`].
 
 use crate::{
-  BuiltIn, Dim, ErasedExpr, ErasedFun, ErasedFunHandle, ErasedReturn, ErasedScope, FragmentBuiltIn,
-  GeometryBuiltIn, MatrixDim, PrimType, ScopeInstr, ScopedHandle, Shader, ShaderDecl, Swizzle,
-  SwizzleSelector, TessCtrlBuiltIn, TessEvalBuiltIn, Type, VertexBuiltIn,
+  call_graph::{CallGraph, OrderedDecl},
+  optimizer,
+  validation::Declaration,
+  writer::{Construct, SourceMap, WriteError},
+  ArgQualifier, BuiltIn, ColorAttachment, Dim, ErasedExpr, ErasedFun, ErasedFunHandle,
+  ErasedReturn, ErasedScope, FragmentBuiltIn, GeometryBuiltIn, MatrixDim, PrimType, Program,
+  ScopeInstr, ScopedHandle, Shader, ShaderDecl, ShaderStage, Swizzle, SwizzleSelector,
+  TessCtrlBuiltIn, TessEvalBuiltIn, Type, VertexBuiltIn,
 };
-use std::fmt;
+use std::{
+  collections::{BTreeMap, HashMap, HashSet},
+  fmt::{self, Write as _},
+  panic::Location,
+};
+
+// Keywords and reserved words of GLSL, which cannot be used as identifiers.
+#[rustfmt::skip]
+const RESERVED_KEYWORDS: &[&str] = &[
+  // keywords
+  "attribute", "const", "uniform", "buffer", "shared", "varying", "coherent", "volatile",
+  "restrict", "readonly", "writeonly", "atomic_uint", "layout", "centroid", "flat", "smooth",
+  "noperspective", "patch", "sample", "invariant", "precise", "break", "continue", "do", "for",
+  "while", "switch", "case", "default", "if", "else", "subroutine", "in", "out", "inout", "int",
+  "void", "bool", "true", "false", "float", "double", "discard", "return", "vec2", "vec3", "vec4",
+  "ivec2", "ivec3", "ivec4", "bvec2", "bvec3", "bvec4", "uint", "uvec2", "uvec3", "uvec4", "dvec2",
+  "dvec3", "dvec4", "mat2", "mat3", "mat4", "mat2x2", "mat2x3", "mat2x4", "mat3x2", "mat3x3",
+  "mat3x4", "mat4x2", "mat4x3", "mat4x4", "dmat2", "dmat3", "dmat4", "dmat2x2", "dmat2x3",
+  "dmat2x4", "dmat3x2", "dmat3x3", "dmat3x4", "dmat4x2", "dmat4x3", "dmat4x4", "lowp", "mediump",
+  "highp", "precision", "sampler1D", "sampler1DShadow", "sampler1DArray", "sampler1DArrayShadow",
+  "isampler1D", "isampler1DArray", "usampler1D", "usampler1DArray", "sampler2D", "sampler2DShadow",
+  "sampler2DArray", "sampler2DArrayShadow", "isampler2D", "isampler2DArray", "usampler2D",
+  "usampler2DArray", "sampler2DRect", "sampler2DRectShadow", "isampler2DRect", "usampler2DRect",
+  "sampler2DMS", "isampler2DMS", "usampler2DMS", "sampler2DMSArray", "isampler2DMSArray",
+  "usampler2DMSArray", "sampler3D", "isampler3D", "usampler3D", "samplerCube", "samplerCubeShadow",
+  "isamplerCube", "usamplerCube", "samplerCubeArray", "samplerCubeArrayShadow",
+  "isamplerCubeArray", "usamplerCubeArray", "samplerBuffer", "isamplerBuffer", "usamplerBuffer",
+  "image1D", "iimage1D", "uimage1D", "image1DArray", "iimage1DArray", "uimage1DArray", "image2D",
+  "iimage2D", "uimage2D", "image2DArray", "iimage2DArray", "uimage2DArray", "image2DRect",
+  "iimage2DRect", "uimage2DRect", "image2DMS", "iimage2DMS", "uimage2DMS", "image2DMSArray",
+  "iimage2DMSArray", "uimage2DMSArray", "image3D", "iimage3D", "uimage3D", "imageCube",
+  "iimageCube", "uimageCube", "imageCubeArray", "iimageCubeArray", "uimageCubeArray",
+  "imageBuffer", "iimageBuffer", "uimageBuffer", "struct",
+  // reserved for future use
+  "common", "partition", "active", "asm", "class", "union", "enum", "typedef", "template", "this",
+  "resource", "goto", "inline", "noinline", "public", "static", "extern", "external", "interface",
+  "long", "short", "half", "fixed", "unsigned", "superp", "input", "output", "hvec2", "hvec3",
+  "hvec4", "fvec2", "fvec3", "fvec4", "filter", "sizeof", "cast", "namespace", "using",
+  "sampler3DRect",
+  // entry point
+  "main",
+];
+
+// Built-in functions written by write_fun_handle. Declaring an identifier with one of those names would hide the
+// built-in function, so generated identifiers avoid them.
+#[rustfmt::skip]
+const BUILT_IN_FUNCTIONS: &[&str] = &[
+  "abs", "acos", "acosh", "all", "allInvocations", "allInvocationsEqual", "any", "anyInvocation",
+  "asin", "asinh", "atan", "atanh", "barrier", "bitCount", "bitfieldExtract", "bitfieldInsert",
+  "bitfieldReverse", "ceil", "clamp", "cos", "cosh", "cross", "degrees", "dfdx", "dfdxCoarse",
+  "dfdxFine", "dfdy", "dfdyCoarse", "dfdyFine", "distance", "dot", "EmitStreamVertex",
+  "EmitVertex", "EndPrimitive", "EndStreamPrimitive", "equal", "exp", "exp2", "faceforward",
+  "findLSB", "findMSB", "floatBitsToInt", "floor", "fma", "fract", "frexp", "fwidth",
+  "fwidthCoarse", "fwidthFine", "greaterThan", "greaterThanEqual", "groupMemoryBarrier",
+  "imulExtended", "intBitsToFloat", "interpolateAtCentroid", "interpolateAtOffset",
+  "interpolateAtSample", "inversesqrt", "isinf", "isnan", "ldexp", "length", "lessThan",
+  "lessThanEqual", "log", "log2", "max", "memoryBarrier", "memoryBarrierAtomic",
+  "memoryBarrierBuffer", "memoryBarrierImage", "memoryBarrierShared", "min", "mix", "normalize",
+  "not", "notEqual", "packHalf2x16", "packSnorm2x16", "packSnorm4x8", "packUnorm2x16",
+  "packUnorm4x8", "pow", "radians", "reflect", "refract", "round", "roundEven", "sign", "sin",
+  "smoothstep", "sqrt", "step", "tan", "tanh", "trunc", "uaddCarry", "uIntBitsToFloat",
+  "umulExtended", "unpackHalf2x16", "unpackSnorm2x16", "unpackSnorm4x8", "unpackUnorm2x16",
+  "unpackUnorm4x8", "usubBorrow", "vec2", "vec3", "vec4",
+];
+
+/// Check whether an identifier is reserved in GLSL.
+///
+/// Reserved identifiers are keywords, words reserved for future use, identifiers starting with `gl_` and identifiers
+/// containing two consecutive underscores.
+pub(crate) fn is_reserved_identifier(name: &str) -> bool {
+  name.starts_with("gl_") || name.contains("__") || RESERVED_KEYWORDS.contains(&name)
+}
+
+/// Check whether an identifier has the same shape as the identifiers generated by the writer.
+///
+/// Generated identifiers are `fun_N` for functions, `arg_N` for function arguments, `var_N_N` for variables and
+/// `glob_N` for constants.
+pub(crate) fn is_generated_identifier(name: &str) -> bool {
+  fn is_handle(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit())
+  }
+
+  if let Some(rest) = name.strip_prefix("var_") {
+    let mut parts = rest.splitn(2, '_');
+    let subscope = parts.next().unwrap_or_default();
+    let handle = parts.next().unwrap_or_default();
+    is_handle(subscope) && is_handle(handle)
+  } else {
+    ["fun_", "arg_", "glob_"]
+      .iter()
+      .filter_map(|prefix| name.strip_prefix(prefix))
+      .any(is_handle)
+  }
+}
+
+/// Options controlling how shaders are written.
+///
+/// The default options write shaders verbatim.
+///
+/// # Examples
+///
+/// ```
+/// use shades::{Scope, ShaderBuilder, lit};
+/// use shades::writer::glsl::{WriteOptions, write_shader_to_str_with_options};
+///
+/// let shader = ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+///   let scale = s.constant(lit!(2.) * 3. - 0.);
+///
+///   s.main_fun(|s: &mut Scope<()>| {
+///     s.set(&vertex.position, lit!(1., 1., 0., 1.) * scale);
+///   })
+/// });
+///
+/// let options = WriteOptions::new().with_constant_folding(true);
+/// let output = write_shader_to_str_with_options(&shader, &options).unwrap();
+///
+/// assert_eq!(
+///   output,
+///   "const float glob_0 = 6.;\n\nvoid main() {\n  gl_Position = vec4(6., 6., 0., 6.);\n}"
+/// );
+/// ```
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct WriteOptions {
+  target: Target,
+  constant_folding: bool,
+  common_subexpression_elimination: bool,
+  dead_code_elimination: bool,
+  unused_interface_elimination: bool,
+  source_comments: bool,
+  indent: Indent,
+  brace_placement: BracePlacement,
+  blank_lines: BlankLines,
+  minification: bool,
+}
+
+impl WriteOptions {
+  /// Default options.
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Flavour of GLSL to write shaders for.
+  ///
+  /// Writing a shader using a construct the target doesn’t support fails with [`WriteError::Unsupported`].
+  pub fn with_target(mut self, target: Target) -> Self {
+    self.target = target;
+    self
+  }
+
+  /// Flavour of GLSL to write shaders for.
+  pub fn target(&self) -> Target {
+    self.target
+  }
+
+  /// Fold constant expressions and simplify algebraic identities before writing.
+  ///
+  /// Only operations whose result is exactly defined by GLSL are folded, so that the written shader computes the same
+  /// values as the unoptimized one: transcendental functions and floating-point divisions, for instance, are left to
+  /// the driver.
+  pub fn with_constant_folding(mut self, enabled: bool) -> Self {
+    self.constant_folding = enabled;
+    self
+  }
+
+  /// Whether constant folding is enabled.
+  pub fn constant_folding(&self) -> bool {
+    self.constant_folding
+  }
+
+  /// Compute repeated subexpressions once into generated variables before writing.
+  ///
+  /// Expressions are freely cloned in the EDSL, which duplicates them in the written code; with this option, an
+  /// expression evaluated several times in a row without any variable being assigned in between — such as a call to a
+  /// user-defined function — is computed only once.
+  pub fn with_common_subexpression_elimination(mut self, enabled: bool) -> Self {
+    self.common_subexpression_elimination = enabled;
+    self
+  }
+
+  /// Whether common subexpression elimination is enabled.
+  pub fn common_subexpression_elimination(&self) -> bool {
+    self.common_subexpression_elimination
+  }
+
+  /// Remove functions and constants not reachable from `main`, and local variables never used, before writing.
+  ///
+  /// Local variables are only removed if their initial value has no side effects, such as calling a function that
+  /// writes to an output. Inputs and uniforms are kept, unless [`WriteOptions::with_unused_interface_elimination`]
+  /// is enabled too.
+  pub fn with_dead_code_elimination(mut self, enabled: bool) -> Self {
+    self.dead_code_elimination = enabled;
+    self
+  }
+
+  /// Whether dead code elimination is enabled.
+  pub fn dead_code_elimination(&self) -> bool {
+    self.dead_code_elimination
+  }
+
+  /// Also remove inputs and uniforms never read when eliminating dead code.
+  ///
+  /// This changes the interface of the shader, so it is disabled by default: setting a uniform removed this way fails
+  /// at runtime, for instance. It has no effect unless [`WriteOptions::with_dead_code_elimination`] is enabled.
+  pub fn with_unused_interface_elimination(mut self, enabled: bool) -> Self {
+    self.unused_interface_elimination = enabled;
+    self
+  }
+
+  /// Whether unused inputs and uniforms are removed.
+  pub fn unused_interface_elimination(&self) -> bool {
+    self.unused_interface_elimination
+  }
+
+  /// Precede statements and functions with a `// file.rs:42` comment giving the Rust code that built them.
+  ///
+  /// See [`SourceMap`] to map lines of the generated code back to Rust code without changing the output.
+  pub fn with_source_comments(mut self, enabled: bool) -> Self {
+    self.source_comments = enabled;
+    self
+  }
+
+  /// Whether statements and functions are preceded with a comment giving their location.
+  pub fn source_comments(&self) -> bool {
+    self.source_comments
+  }
+
+  /// Indentation of nested code.
+  pub fn with_indent(mut self, indent: Indent) -> Self {
+    self.indent = indent;
+    self
+  }
+
+  /// Indentation of nested code.
+  pub fn indent(&self) -> Indent {
+    self.indent
+  }
+
+  /// Placement of the opening braces of functions, conditionals and loops.
+  pub fn with_brace_placement(mut self, brace_placement: BracePlacement) -> Self {
+    self.brace_placement = brace_placement;
+    self
+  }
+
+  /// Placement of the opening braces of functions, conditionals and loops.
+  pub fn brace_placement(&self) -> BracePlacement {
+    self.brace_placement
+  }
+
+  /// Blank lines written between top-level declarations.
+  pub fn with_blank_lines(mut self, blank_lines: BlankLines) -> Self {
+    self.blank_lines = blank_lines;
+    self
+  }
+
+  /// Blank lines written between top-level declarations.
+  pub fn blank_lines(&self) -> BlankLines {
+    self.blank_lines
+  }
+
+  /// Write the shortest code possible, to ship compact shaders.
+  ///
+  /// Whitespace is stripped, so that the whole shader fits on a single line, and functions, constants, arguments and
+  /// variables get the shortest identifiers available, whether they are named or not. Inputs, outputs and uniforms
+  /// keep their names, as they are part of the interface of the shader. Layout options and source comments are
+  /// ignored.
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use shades::{Expr, Scope, ShaderBuilder, lit};
+  /// use shades::writer::glsl::{WriteOptions, write_shader_to_str_with_options};
+  ///
+  /// let shader = ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+  ///   let scale = s.fun_named("scale", &["x"], |_: &mut Scope<Expr<f32>>, x: Expr<f32>| x * 2.);
+  ///
+  ///   s.main_fun(|s: &mut Scope<()>| {
+  ///     let size = s.var_named("size", scale.call(lit!(1.)));
+  ///     s.set(&vertex.position, lit!(0., 0., 0., 1.) * size);
+  ///   })
+  /// });
+  ///
+  /// let options = WriteOptions::new().with_minification(true);
+  /// let output = write_shader_to_str_with_options(&shader, &options).unwrap();
+  ///
+  /// assert_eq!(
+  ///   output,
+  ///   "float a(float b){return(b*2.);}void main(){float b=a(1.);gl_Position=(vec4(0.,0.,0.,1.)*b);}"
+  /// );
+  /// ```
+  pub fn with_minification(mut self, enabled: bool) -> Self {
+    self.minification = enabled;
+    self
+  }
+
+  /// Whether the shortest code possible is written.
+  pub fn minification(&self) -> bool {
+    self.minification
+  }
+}
+
+/// Indentation of nested code.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum Indent {
+  /// Indent with the given number of spaces per level.
+  Spaces(usize),
+
+  /// Indent with a tab per level.
+  Tabs,
+}
+
+impl Default for Indent {
+  fn default() -> Self {
+    Indent::Spaces(2)
+  }
+}
+
+/// Placement of opening braces.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum BracePlacement {
+  /// At the end of the line opening the block, as in `if (x) {`.
+  #[default]
+  SameLine,
+
+  /// On their own line, at the indentation of the line opening the block.
+  NextLine,
+}
 
-// Number of space an indent level represents.
-const INDENT_SPACES: usize = 2;
+/// Blank lines written between top-level declarations.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum BlankLines {
+  /// No blank lines at all.
+  Never,
+
+  /// A blank line before each function.
+  #[default]
+  BeforeFunctions,
+
+  /// A blank line before each declaration: functions, but also constants, inputs, outputs and uniforms.
+  BeforeDeclarations,
+}
+
+/// Flavours of GLSL shaders can be written for.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum Target {
+  /// Desktop GLSL.
+  #[default]
+  Glsl,
+
+  /// GLSL ES 3.00, as used by OpenGL ES 3.0 and WebGL 2.
+  ///
+  /// Tessellation and geometry shaders, arrays of arrays, dual-source blending and a few built-ins and built-in
+  /// functions are not supported.
+  GlslEs300,
+}
 
 /// Write a [`Shader`] to a [`String`].
-pub fn write_shader_to_str(shader: impl AsRef<Shader>) -> Result<String, fmt::Error> {
+pub fn write_shader_to_str(shader: impl AsRef<Shader>) -> Result<String, WriteError> {
+  write_shader_to_str_with_options(shader, &WriteOptions::default())
+}
+
+/// Write a [`Shader`] to a [`String`] with the given [`WriteOptions`].
+pub fn write_shader_to_str_with_options(
+  shader: impl AsRef<Shader>,
+  options: &WriteOptions,
+) -> Result<String, WriteError> {
   let mut output = String::new();
-  write_shader(&mut output, shader)?;
+  write_shader_with_options(&mut output, shader, options)?;
   Ok(output)
 }
 
+/// Write a [`Shader`] to a [`String`] with the given [`WriteOptions`], along with its [`SourceMap`].
+pub fn write_shader_to_str_with_source_map(
+  shader: impl AsRef<Shader>,
+  options: &WriteOptions,
+) -> Result<(String, SourceMap), WriteError> {
+  let mut output = String::new();
+  let source_map = write_shader_with_source_map(&mut output, shader, options)?;
+  Ok((output, source_map))
+}
+
+/// Sources of all the stages of a [`Program`].
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct ProgramSources {
+  /// Source of the vertex shader.
+  pub vertex: String,
+
+  /// Source of the tessellation control shader, if any.
+  pub tess_ctrl: Option<String>,
+
+  /// Source of the tessellation evaluation shader, if any.
+  pub tess_eval: Option<String>,
+
+  /// Source of the geometry shader, if any.
+  pub geometry: Option<String>,
+
+  /// Source of the fragment shader.
+  pub fragment: String,
+}
+
+/// Write all the stages of a [`Program`] to [`String`]s.
+pub fn write_program_to_str(program: &Program) -> Result<ProgramSources, WriteError> {
+  write_program_to_str_with_options(program, &WriteOptions::default())
+}
+
+/// Write all the stages of a [`Program`] to [`String`]s with the given [`WriteOptions`].
+pub fn write_program_to_str_with_options(
+  program: &Program,
+  options: &WriteOptions,
+) -> Result<ProgramSources, WriteError> {
+  let write = |shader| write_shader_to_str_with_options(shader, options);
+
+  Ok(ProgramSources {
+    vertex: write(program.vertex())?,
+    tess_ctrl: program.tess_ctrl().map(write).transpose()?,
+    tess_eval: program.tess_eval().map(write).transpose()?,
+    geometry: program.geometry().map(write).transpose()?,
+    fragment: write(program.fragment())?,
+  })
+}
+
 /// Write a [`Shader`] to a [`fmt::Write`](std::fmt::Write).
-pub fn write_shader(f: &mut impl fmt::Write, shader: impl AsRef<Shader>) -> Result<(), fmt::Error> {
-  for decl in &shader.as_ref().builder.decls {
+pub fn write_shader(f: &mut impl fmt::Write, shader: impl AsRef<Shader>) -> Result<(), WriteError> {
+  write_shader_with_options(f, shader, &WriteOptions::default())
+}
+
+/// Write a [`Shader`] to a [`fmt::Write`] with the given [`WriteOptions`].
+pub fn write_shader_with_options(
+  f: &mut impl fmt::Write,
+  shader: impl AsRef<Shader>,
+  options: &WriteOptions,
+) -> Result<(), WriteError> {
+  write_shader_with_source_map(f, shader, options).map(|_| ())
+}
+
+/// Write a [`Shader`] to a [`fmt::Write`] with the given [`WriteOptions`], and return its [`SourceMap`].
+///
+/// The lines of the source map are the lines written to `f`.
+pub fn write_shader_with_source_map(
+  f: &mut impl fmt::Write,
+  shader: impl AsRef<Shader>,
+  options: &WriteOptions,
+) -> Result<SourceMap, WriteError> {
+  let diagnostics = shader.as_ref().validate();
+
+  if !diagnostics.is_empty() {
+    return Err(WriteError::InvalidShader(diagnostics));
+  }
+
+  let optimized = optimize(shader.as_ref(), options);
+  let shader = optimized.as_ref().unwrap_or_else(|| shader.as_ref());
+
+  check_target(shader, options.target)?;
+
+  let mut names = Names::new(shader, options.minification);
+  let f = &mut Output::new(f, options);
+
+  for decl in CallGraph::new(&shader.decls).ordered_decls() {
+    let is_fun = matches!(
+      decl,
+      OrderedDecl::Prototype(..)
+        | OrderedDecl::Decl(ShaderDecl::Main(_))
+        | OrderedDecl::Decl(ShaderDecl::FunDef(..))
+    );
+    if let (BlankLines::BeforeDeclarations, _) | (BlankLines::BeforeFunctions, true) =
+      (options.blank_lines, is_fun)
+    {
+      f.write_str("\n")?;
+    }
+
+    let decl = match decl {
+      OrderedDecl::Decl(decl) => decl,
+      OrderedDecl::Prototype(handle, fun) => {
+        f.locate(None, 0)?;
+        write_fun_prototype(f, &mut names, handle, fun)?;
+        continue;
+      }
+    };
+
     match decl {
-      ShaderDecl::Main(fun) => write_main_fun(f, fun)?,
-      ShaderDecl::FunDef(handle, fun) => write_fun_def(f, *handle, fun)?,
-      ShaderDecl::Const(handle, ty, ref constant) => write_constant(f, *handle, ty, constant)?,
-      ShaderDecl::In(name, ty) => write_input(f, name, ty)?,
-      ShaderDecl::Out(name, ty) => write_output(f, name, ty)?,
-      ShaderDecl::Uniform(name, ty) => write_uniform(f, name, ty)?,
+      ShaderDecl::Main(fun) => write_main_fun(f, &mut names, fun)?,
+      ShaderDecl::FunDef(handle, fun) => write_fun_def(f, &mut names, *handle, fun)?,
+      ShaderDecl::Const(handle, ty, constant, _) => {
+        f.locate(None, 0)?;
+        write_constant(f, &names, *handle, ty, constant)?
+      }
+      ShaderDecl::In(name, ty) => {
+        f.locate(None, 0)?;
+        write_input(f, shader.stage, name, ty)?
+      }
+      ShaderDecl::Out(name, ty, attachment) => {
+        f.locate(None, 0)?;
+        write_output(f, shader.stage, name, ty, attachment.as_ref())?
+      }
+      ShaderDecl::Uniform(name, ty) => {
+        f.locate(None, 0)?;
+        write_uniform(f, name, ty)?
+      }
     }
   }
 
-  Ok(())
+  Ok(f.source_map.clone())
 }
 
-fn write_main_fun(f: &mut impl fmt::Write, fun: &ErasedFun) -> Result<(), fmt::Error> {
-  f.write_str("\nvoid main() {\n")?;
-  write_scope(f, &fun.scope, 1)?;
-  f.write_str("}")
+// Output of the writer, laying out the code and keeping track of the line being written to map it back to the EDSL.
+struct Output<'a, W> {
+  f: &'a mut W,
+  options: WriteOptions,
+  // line being written, starting at 1
+  line: usize,
+  source_map: SourceMap,
+  // when minifying, the last character written and whether whitespace was stripped after it
+  last: Option<char>,
+  stripped_whitespace: bool,
 }
 
-fn write_fun_def(f: &mut impl fmt::Write, handle: u16, fun: &ErasedFun) -> Result<(), fmt::Error> {
-  // just for aesthetics :')
-  f.write_str("\n")?;
+impl<'a, W> Output<'a, W>
+where
+  W: fmt::Write,
+{
+  fn new(f: &'a mut W, options: &WriteOptions) -> Self {
+    Self {
+      f,
+      options: options.clone(),
+      line: 1,
+      source_map: SourceMap::default(),
+      last: None,
+      stripped_whitespace: false,
+    }
+  }
 
-  let ret_expr = match &fun.ret {
-    ErasedReturn::Void => {
-      f.write_str("void")?;
-      None
+  // Map the next line to be written to a location, preceding it with a comment if enabled.
+  fn locate(
+    &mut self,
+    location: Option<&'static Location<'static>>,
+    indent_lvl: usize,
+  ) -> Result<(), fmt::Error> {
+    if let Some(location) = location {
+      // comments run to the end of the line, so they cannot be minified
+      if self.options.source_comments && !self.options.minification {
+        self.indent(indent_lvl)?;
+        writeln!(self, "// {}:{}", location.file(), location.line())?;
+      }
     }
 
-    ErasedReturn::Expr(ref ty, expr) => {
-      write_type(f, ty)?;
-      Some(expr)
+    self.source_map.insert(self.line, location);
+    Ok(())
+  }
+
+  fn indent(&mut self, indent_lvl: usize) -> Result<(), fmt::Error> {
+    for _ in 0..indent_lvl {
+      match self.options.indent {
+        Indent::Spaces(n) => write!(self, "{:1$}", "", n)?,
+        Indent::Tabs => self.write_str("\t")?,
+      }
+    }
+
+    Ok(())
+  }
+
+  // Open a block, after the line opening it.
+  fn open_brace(&mut self, indent_lvl: usize) -> Result<(), fmt::Error> {
+    match self.options.brace_placement {
+      BracePlacement::SameLine => self.write_str(" {\n"),
+      BracePlacement::NextLine => {
+        self.write_str("\n")?;
+        self.indent(indent_lvl)?;
+        self.write_str("{\n")
+      }
+    }
+  }
+
+  // Close a block, on its own line.
+  fn close_brace(&mut self, indent_lvl: usize) -> Result<(), fmt::Error> {
+    self.indent(indent_lvl)?;
+    self.write_str("}")
+  }
+
+  // Strip whitespace, except where it separates two tokens that would otherwise merge into one.
+  fn minify(&mut self, s: &str) -> String {
+    let mut minified = String::with_capacity(s.len());
+
+    for c in s.chars() {
+      if c.is_whitespace() {
+        self.stripped_whitespace = self.last.is_some();
+        continue;
+      }
+
+      if let (true, Some(last)) = (self.stripped_whitespace, self.last) {
+        let is_word = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.';
+
+        if (is_word(last) && is_word(c)) || (last == c && matches!(c, '+' | '-')) {
+          minified.push(' ');
+        }
+      }
+
+      minified.push(c);
+      self.last = Some(c);
+      self.stripped_whitespace = false;
     }
+
+    minified
+  }
+}
+
+impl<W> fmt::Write for Output<'_, W>
+where
+  W: fmt::Write,
+{
+  fn write_str(&mut self, s: &str) -> fmt::Result {
+    if self.options.minification {
+      let minified = self.minify(s);
+      return self.f.write_str(&minified);
+    }
+
+    self.line += s.matches('\n').count();
+    self.f.write_str(s)
+  }
+}
+
+// Apply the optimization passes enabled in the options, if any.
+fn optimize(shader: &Shader, options: &WriteOptions) -> Option<Shader> {
+  if !options.constant_folding
+    && !options.common_subexpression_elimination
+    && !options.dead_code_elimination
+  {
+    return None;
+  }
+
+  let mut shader = shader.clone();
+
+  if options.constant_folding {
+    optimizer::fold_constants(&mut shader);
+  }
+
+  if options.dead_code_elimination {
+    optimizer::eliminate_dead_code(&mut shader, options.unused_interface_elimination);
+  }
+
+  if options.common_subexpression_elimination {
+    optimizer::eliminate_common_subexpressions(&mut shader);
+  }
+
+  Some(shader)
+}
+
+// Check that the shader only uses constructs supported by the target.
+fn check_target(shader: &Shader, target: Target) -> Result<(), WriteError> {
+  if target == Target::Glsl {
+    return Ok(());
+  }
+
+  let unsupported = |declaration: &Declaration, construct| {
+    Err(WriteError::Unsupported {
+      declaration: declaration.clone(),
+      construct,
+    })
   };
 
+  for decl in &shader.decls {
+    let (declaration, types, exprs) = match decl {
+      ShaderDecl::Main(fun) => (Declaration::Main, fun_types(fun), optimizer::fun_exprs(fun)),
+
+      ShaderDecl::FunDef(handle, fun) => (
+        Declaration::Function(*handle),
+        fun_types(fun),
+        optimizer::fun_exprs(fun),
+      ),
+
+      ShaderDecl::Const(handle, ty, expr, _) => {
+        (Declaration::Constant(*handle), vec![ty], vec![expr])
+      }
+
+      ShaderDecl::Out(name, _, Some(attachment)) if attachment.index() != 0 => {
+        return unsupported(
+          &Declaration::Interface(name.clone()),
+          Construct::ColorAttachmentIndex,
+        );
+      }
+
+      ShaderDecl::In(name, ty) | ShaderDecl::Out(name, ty, _) | ShaderDecl::Uniform(name, ty) => {
+        (Declaration::Interface(name.clone()), vec![ty], Vec::new())
+      }
+    };
+
+    // arrays of arrays
+    if let Some(ty) = types.into_iter().find(|ty| ty.array_dims.len() > 1) {
+      return unsupported(&declaration, Construct::Type(ty.clone()));
+    }
+
+    let mut construct = None;
+
+    for expr in exprs {
+      optimizer::walk_expr(expr, &mut |expr| {
+        if construct.is_none() {
+          construct = unsupported_es300_construct(expr);
+        }
+      });
+    }
+
+    if let Some(construct) = construct {
+      return unsupported(&declaration, construct);
+    }
+  }
+
+  Ok(())
+}
+
+// Construct of an expression node not available in GLSL ES 3.00, if any.
+fn unsupported_es300_construct(expr: &ErasedExpr) -> Option<Construct> {
+  match expr {
+    ErasedExpr::Var(ScopedHandle::BuiltIn(builtin)) if !is_es300_builtin(builtin) => {
+      Some(Construct::BuiltIn(*builtin))
+    }
+
+    // arrays of arrays
+    ErasedExpr::Array(ty, _) if ty.array_dims.len() > 1 => Some(Construct::Type(ty.clone())),
+
+    ErasedExpr::FunCall(handle, _) if !is_es300_fun(handle) => {
+      Some(Construct::Function(fun_name(handle)))
+    }
+
+    _ => None,
+  }
+}
+
+// Types of the arguments, variables and returned value of a function.
+fn fun_types(fun: &ErasedFun) -> Vec<&Type> {
+  fn scope_types<'a>(scope: &'a ErasedScope, types: &mut Vec<&'a Type>) {
+    for instr in &scope.instructions {
+      match instr {
+        ScopeInstr::VarDecl { ty, .. } => types.push(ty),
+
+        ScopeInstr::For { init_ty, scope, .. } => {
+          types.push(init_ty);
+          scope_types(scope, types);
+        }
+
+        ScopeInstr::If { scope, .. }
+        | ScopeInstr::ElseIf { scope, .. }
+        | ScopeInstr::Else { scope }
+        | ScopeInstr::While { scope, .. } => scope_types(scope, types),
+
+        _ => (),
+      }
+    }
+  }
+
+  let mut types: Vec<_> = fun.args.iter().collect();
+  scope_types(&fun.scope, &mut types);
+
+  if let ErasedReturn::Expr(ty, _) = &fun.ret {
+    types.push(ty);
+  }
+
+  types
+}
+
+// Whether a built-in is available in GLSL ES 3.00.
+fn is_es300_builtin(builtin: &BuiltIn) -> bool {
+  matches!(
+    builtin,
+    BuiltIn::Vertex(VertexBuiltIn::VertexID)
+      | BuiltIn::Vertex(VertexBuiltIn::InstanceID)
+      | BuiltIn::Vertex(VertexBuiltIn::Position)
+      | BuiltIn::Vertex(VertexBuiltIn::PointSize)
+      | BuiltIn::Fragment(FragmentBuiltIn::FragCoord)
+      | BuiltIn::Fragment(FragmentBuiltIn::FrontFacing)
+      | BuiltIn::Fragment(FragmentBuiltIn::PointCoord)
+      | BuiltIn::Fragment(FragmentBuiltIn::FragDepth)
+  )
+}
+
+// Whether a function is available in GLSL ES 3.00.
+fn is_es300_fun(handle: &ErasedFunHandle) -> bool {
+  !matches!(
+    handle,
+    ErasedFunHandle::FMA
+      | ErasedFunHandle::Frexp
+      | ErasedFunHandle::Ldexp
+      | ErasedFunHandle::PackUnorm4x8
+      | ErasedFunHandle::PackSnorm4x8
+      | ErasedFunHandle::UnpackUnorm4x8
+      | ErasedFunHandle::UnpackSnorm4x8
+      | ErasedFunHandle::UAddCarry
+      | ErasedFunHandle::USubBorrow
+      | ErasedFunHandle::UMulExtended
+      | ErasedFunHandle::IMulExtended
+      | ErasedFunHandle::BitfieldExtract
+      | ErasedFunHandle::BitfieldInsert
+      | ErasedFunHandle::BitfieldReverse
+      | ErasedFunHandle::BitCount
+      | ErasedFunHandle::FindLSB
+      | ErasedFunHandle::FindMSB
+      | ErasedFunHandle::EmitStreamVertex
+      | ErasedFunHandle::EndStreamPrimitive
+      | ErasedFunHandle::EmitVertex
+      | ErasedFunHandle::EndPrimitive
+      | ErasedFunHandle::DFDXFine
+      | ErasedFunHandle::DFDYFine
+      | ErasedFunHandle::DFDXCoarse
+      | ErasedFunHandle::DFDYCoarse
+      | ErasedFunHandle::FWidthFine
+      | ErasedFunHandle::FWidthCoarse
+      | ErasedFunHandle::InterpolateAtCentroid
+      | ErasedFunHandle::InterpolateAtSample
+      | ErasedFunHandle::InterpolateAtOffset
+      | ErasedFunHandle::Barrier
+      | ErasedFunHandle::MemoryBarrier
+      | ErasedFunHandle::MemoryBarrierAtomic
+      | ErasedFunHandle::MemoryBarrierBuffer
+      | ErasedFunHandle::MemoryBarrierShared
+      | ErasedFunHandle::MemoryBarrierImage
+      | ErasedFunHandle::GroupMemoryBarrier
+      | ErasedFunHandle::AnyInvocation
+      | ErasedFunHandle::AllInvocations
+      | ErasedFunHandle::AllInvocationsEqual
+  )
+}
+
+// Identifiers of the functions, constants, arguments and variables of a shader.
+//
+// Declarations named by the user get an identifier based on their name, sanitized so that it is a valid GLSL
+// identifier, and suffixed if it clashes with another identifier. Other declarations get a generated identifier,
+// such as var_0_1; user names never have the shape of generated identifiers. When minifying, all declarations get the
+// shortest identifiers available instead.
+#[derive(Debug, Default)]
+struct Names {
+  minify: bool,
+  // identifiers visible everywhere in the shader
+  globals: HashSet<String>,
+  funs: HashMap<u16, String>,
+  consts: HashMap<u16, String>,
+  // identifiers declared in the function being written
+  locals: HashSet<String>,
+  args: Vec<String>,
+  // identifiers of the variables named in the scopes being written, innermost last
+  scopes: Vec<(u16, BTreeMap<u16, String>)>,
+  // indices of the next short identifiers to try
+  next_global: usize,
+  next_local: usize,
+}
+
+impl Names {
+  fn new(shader: &Shader, minify: bool) -> Self {
+    let mut names = Names {
+      minify,
+      ..Names::default()
+    };
+
+    // interface names are written verbatim, so they must be reserved before allocating anything else
+    for decl in &shader.decls {
+      if let ShaderDecl::In(name, _) | ShaderDecl::Out(name, _, _) | ShaderDecl::Uniform(name, _) =
+        decl
+      {
+        names.globals.insert(name.clone());
+      }
+    }
+
+    // functions sharing a name are written as overloads of the same identifier, unless their arguments clash
+    type Overloads<'a> = Vec<(String, HashSet<&'a [Type]>)>;
+    let mut overloads: HashMap<&str, Overloads> = HashMap::new();
+
+    for decl in &shader.decls {
+      match decl {
+        ShaderDecl::FunDef(handle, fun) => {
+          let ident = match fun.name.as_deref() {
+            Some(name) if !minify => {
+              let idents = overloads.entry(name).or_default();
+
+              let overload = idents
+                .iter_mut()
+                .find_map(|(ident, args)| args.insert(fun.args.as_slice()).then(|| ident.clone()));
+
+              overload.or_else(|| {
+                let ident = names.allocate(name, false);
+                idents.push((
+                  ident.clone(),
+                  std::iter::once(fun.args.as_slice()).collect(),
+                ));
+                Some(ident)
+              })
+            }
+
+            name => names.identify(name, false),
+          };
+
+          if let Some(ident) = ident {
+            names.funs.insert(*handle, ident);
+          }
+        }
+
+        ShaderDecl::Const(handle, _, _, name) => {
+          if let Some(ident) = names.identify(name.as_deref(), false) {
+            names.consts.insert(*handle, ident);
+          }
+        }
+
+        _ => (),
+      }
+    }
+
+    names
+  }
+
+  // Identifier of a declaration, either global or local to the current function, if it doesn’t use a generated one.
+  fn identify(&mut self, name: Option<&str>, local: bool) -> Option<String> {
+    if self.minify {
+      Some(self.shorten(local))
+    } else {
+      name.map(|name| self.allocate(name, local))
+    }
+  }
+
+  // Allocate a unique identifier for a user name.
+  fn allocate(&mut self, name: &str, local: bool) -> String {
+    let base = sanitize_identifier(name);
+    let mut ident = base.clone();
+    let mut suffix = 0;
+
+    while !self.is_available(&ident) || is_generated_identifier(&ident) {
+      suffix += 1;
+      ident = format!("{}_{}", base.trim_end_matches('_'), suffix);
+    }
+
+    self.claim(ident, local)
+  }
+
+  // Allocate the shortest unique identifier available.
+  fn shorten(&mut self, local: bool) -> String {
+    loop {
+      let next = if local {
+        &mut self.next_local
+      } else {
+        &mut self.next_global
+      };
+      let ident = short_identifier(*next);
+      *next += 1;
+
+      if self.is_available(&ident) {
+        return self.claim(ident, local);
+      }
+    }
+  }
+
+  fn is_available(&self, ident: &str) -> bool {
+    ident != "main"
+      && !is_reserved_identifier(ident)
+      && !BUILT_IN_FUNCTIONS.contains(&ident)
+      && !self.globals.contains(ident)
+      && !self.locals.contains(ident)
+  }
+
+  fn claim(&mut self, ident: String, local: bool) -> String {
+    if local {
+      self.locals.insert(ident.clone());
+    } else {
+      self.globals.insert(ident.clone());
+    }
+
+    ident
+  }
+
+  fn enter_fun(&mut self, fun: &ErasedFun) {
+    self.locals.clear();
+    self.next_local = 0;
+    self.args = (0..fun.args.len().max(fun.arg_names.len()))
+      .map_while(|i| {
+        let name = fun.arg_names.get(i).map(String::as_str);
+        self.identify(name, true)
+      })
+      .collect();
+  }
+
+  fn enter_scope(&mut self, scope: &ErasedScope) {
+    let vars = if self.minify {
+      scope
+        .instructions
+        .iter()
+        .filter_map(|instr| match instr {
+          ScopeInstr::VarDecl {
+            handle: ScopedHandle::FunVar { handle, .. },
+            ..
+          } => Some((*handle, self.shorten(true))),
+          _ => None,
+        })
+        .collect()
+    } else {
+      scope
+        .names
+        .iter()
+        .map(|(handle, name)| (*handle, self.allocate(name, true)))
+        .collect()
+    };
+
+    self.scopes.push((scope.id, vars));
+  }
+
+  // Enter the scope of a for loop, which declares its variable before its body.
+  fn enter_loop(&mut self, init_handle: &ScopedHandle) {
+    if let ScopedHandle::FunVar { subscope, handle } = init_handle {
+      let mut vars = BTreeMap::new();
+
+      if self.minify {
+        vars.insert(*handle, self.shorten(true));
+      }
+
+      self.scopes.push((*subscope, vars));
+    }
+  }
+
+  fn leave_scope(&mut self) {
+    self.scopes.pop();
+  }
+
+  fn var(&self, subscope: u16, handle: u16) -> Option<&str> {
+    self
+      .scopes
+      .iter()
+      .rev()
+      .filter(|(id, _)| *id == subscope)
+      .find_map(|(_, vars)| vars.get(&handle))
+      .map(String::as_str)
+  }
+}
+
+// The n-th shortest identifier: a, b, …, Z, aa, ab, …
+fn short_identifier(mut n: usize) -> String {
+  const LETTERS: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+  let mut ident = Vec::new();
+  loop {
+    ident.push(LETTERS[n % LETTERS.len()]);
+    n /= LETTERS.len();
+
+    if n == 0 {
+      break;
+    }
+
+    n -= 1;
+  }
+
+  ident.into_iter().rev().map(char::from).collect()
+}
+
+// Turn a user name into a valid GLSL identifier that is not reserved.
+fn sanitize_identifier(name: &str) -> String {
+  let mut ident = String::with_capacity(name.len() + 1);
+
+  if name.starts_with(|c: char| c.is_ascii_digit()) || name.starts_with("gl_") || name.is_empty() {
+    ident.push('_');
+  }
+
+  for c in name.chars() {
+    let c = if c.is_ascii_alphanumeric() { c } else { '_' };
+
+    // two consecutive underscores are reserved
+    if c != '_' || !ident.ends_with('_') {
+      ident.push(c);
+    }
+  }
+
+  ident
+}
+
+fn write_main_fun(
+  f: &mut Output<impl fmt::Write>,
+  names: &mut Names,
+  fun: &ErasedFun,
+) -> Result<(), fmt::Error> {
+  names.enter_fun(fun);
+
+  f.locate(fun.location, 0)?;
+  f.write_str("void main()")?;
+  f.open_brace(0)?;
+  write_scope(f, names, &fun.scope, 1)?;
+  f.write_str("}")
+}
+
+// Declare a function called before its definition.
+fn write_fun_prototype(
+  f: &mut Output<impl fmt::Write>,
+  names: &mut Names,
+  handle: u16,
+  fun: &ErasedFun,
+) -> Result<(), fmt::Error> {
+  write_fun_signature(f, names, handle, fun)?;
+  f.write_str(";\n")
+}
+
+fn write_fun_signature(
+  f: &mut Output<impl fmt::Write>,
+  names: &mut Names,
+  handle: u16,
+  fun: &ErasedFun,
+) -> Result<(), fmt::Error> {
+  names.enter_fun(fun);
+
+  match &fun.ret {
+    ErasedReturn::Void => f.write_str("void")?,
+    ErasedReturn::Expr(ty, _) => write_type(f, ty)?,
+  }
+
   f.write_str(" ")?;
-  write_user_fun_handle(f, handle)?;
+  write_user_fun_handle(f, names, handle)?;
 
   f.write_str("(")?;
-  if !fun.args.is_empty() {
-    write_type(f, &fun.args[0])?;
-    f.write_str("arg_0")?;
-
-    for (i, arg) in fun.args.iter().enumerate().skip(1) {
+  for (i, (arg, qualifier)) in fun.args.iter().zip(&fun.arg_qualifiers).enumerate() {
+    if i != 0 {
       f.write_str(", ")?;
+    }
 
-      write_type(f, arg)?;
-      write!(f, " arg_{}", i)?;
+    match qualifier {
+      ArgQualifier::In => (),
+      ArgQualifier::Out => f.write_str("out ")?,
+      ArgQualifier::InOut => f.write_str("inout ")?,
     }
+
+    write_type(f, arg)?;
+    f.write_str(" ")?;
+    write_scoped_handle(f, names, &ScopedHandle::fun_arg(i as u16))?;
   }
-  f.write_str(") {\n")?;
+  f.write_str(")")
+}
+
+fn write_fun_def(
+  f: &mut Output<impl fmt::Write>,
+  names: &mut Names,
+  handle: u16,
+  fun: &ErasedFun,
+) -> Result<(), fmt::Error> {
+  f.locate(fun.location, 0)?;
+  write_fun_signature(f, names, handle, fun)?;
+  f.open_brace(0)?;
+
+  let ret_expr = match &fun.ret {
+    ErasedReturn::Void => None,
+    ErasedReturn::Expr(_, expr) => Some(expr),
+  };
 
-  write_scope(f, &fun.scope, 1)?;
+  // the returned expression is evaluated in the top-level scope of the function
+  names.enter_scope(&fun.scope);
+  write_instructions(f, names, &fun.scope, 1)?;
 
-  if let Some(ref expr) = ret_expr {
-    write_indent(f, 1)?;
+  if let Some(expr) = ret_expr {
+    // the returned expression is built by the closure defining the function
+    f.locate(fun.location, 1)?;
+    f.indent(1)?;
     f.write_str("return ")?;
-    write_expr(f, expr)?;
-    f.write_str(";")?;
+    write_expr(f, names, expr)?;
+    f.write_str(";\n")?;
   }
 
-  f.write_str("\n}\n")
+  names.leave_scope();
+
+  f.write_str("}\n")
 }
 
 fn write_scope(
-  f: &mut impl fmt::Write,
+  f: &mut Output<impl fmt::Write>,
+  names: &mut Names,
   scope: &ErasedScope,
   indent_lvl: usize,
 ) -> Result<(), fmt::Error> {
-  for instr in &scope.instructions {
-    write_indent(f, indent_lvl)?;
+  names.enter_scope(scope);
+  write_instructions(f, names, scope, indent_lvl)?;
+  names.leave_scope();
+
+  Ok(())
+}
+
+fn write_instructions(
+  f: &mut Output<impl fmt::Write>,
+  names: &mut Names,
+  scope: &ErasedScope,
+  indent_lvl: usize,
+) -> Result<(), fmt::Error> {
+  for (index, instr) in scope.instructions.iter().enumerate() {
+    f.locate(scope.location(index), indent_lvl)?;
+    f.indent(indent_lvl)?;
 
     match instr {
       ScopeInstr::VarDecl {
@@ -100,9 +1206,9 @@ fn write_scope(
       } => {
         write_type(f, ty)?;
         f.write_str(" ")?;
-        write_scoped_handle(f, handle)?;
+        write_scoped_handle(f, names, handle)?;
         f.write_str(" = ")?;
-        write_expr(f, init_value)?;
+        write_expr(f, names, init_value)?;
         f.write_str(";")?;
       }
 
@@ -113,7 +1219,7 @@ fn write_scope(
 
         ErasedReturn::Expr(_, expr) => {
           f.write_str("return ")?;
-          write_expr(f, expr)?;
+          write_expr(f, names, expr)?;
           f.write_str(";")?;
         }
       },
@@ -128,24 +1234,27 @@ fn write_scope(
 
       ScopeInstr::If { condition, scope } => {
         f.write_str("if (")?;
-        write_expr(f, condition)?;
-        f.write_str(") {\n")?;
-        write_scope(f, scope, indent_lvl + 1)?;
-        write_indented(f, indent_lvl, "}")?;
+        write_expr(f, names, condition)?;
+        f.write_str(")")?;
+        f.open_brace(indent_lvl)?;
+        write_scope(f, names, scope, indent_lvl + 1)?;
+        f.close_brace(indent_lvl)?;
       }
 
       ScopeInstr::ElseIf { condition, scope } => {
-        f.write_str(" else if (")?;
-        write_expr(f, condition)?;
-        f.write_str(") {\n")?;
-        write_scope(f, scope, indent_lvl + 1)?;
-        write_indented(f, indent_lvl, "}")?;
+        f.write_str("else if (")?;
+        write_expr(f, names, condition)?;
+        f.write_str(")")?;
+        f.open_brace(indent_lvl)?;
+        write_scope(f, names, scope, indent_lvl + 1)?;
+        f.close_brace(indent_lvl)?;
       }
 
       ScopeInstr::Else { scope } => {
-        f.write_str("else {\n")?;
-        write_scope(f, scope, indent_lvl + 1)?;
-        write_indented(f, indent_lvl, "}")?;
+        f.write_str("else")?;
+        f.open_brace(indent_lvl)?;
+        write_scope(f, names, scope, indent_lvl + 1)?;
+        f.close_brace(indent_lvl)?;
       }
 
       ScopeInstr::For {
@@ -156,46 +1265,57 @@ fn write_scope(
         post_expr,
         scope,
       } => {
+        // the loop variable is declared in the scope of the loop
+        names.enter_loop(init_handle);
+
         f.write_str("for (")?;
 
         // initialization
         write_type(f, init_ty)?;
         f.write_str(" ")?;
-        write_scoped_handle(f, init_handle)?;
+        write_scoped_handle(f, names, init_handle)?;
         f.write_str(" = ")?;
-        write_expr(f, init_expr)?;
+        write_expr(f, names, init_expr)?;
         f.write_str("; ")?;
 
         // condition
-        write_expr(f, condition)?;
+        write_expr(f, names, condition)?;
         f.write_str("; ")?;
 
         // iteration; we basically write <init-expr> = <next-expr> in a fold-like way, so we need to re-use the
         // init_handle
-        write_scoped_handle(f, init_handle)?;
+        write_scoped_handle(f, names, init_handle)?;
         f.write_str(" = ")?;
-        write_expr(f, post_expr)?;
-        f.write_str(") {\n")?;
+        write_expr(f, names, post_expr)?;
+        f.write_str(")")?;
+        f.open_brace(indent_lvl)?;
 
         // scope
-        write_scope(f, scope, indent_lvl + 1)?;
-        f.write_str("}")?;
+        write_scope(f, names, scope, indent_lvl + 1)?;
+        f.close_brace(indent_lvl)?;
+
+        names.leave_scope();
       }
 
       ScopeInstr::While { condition, scope } => {
         f.write_str("while (")?;
-        write_expr(f, condition)?;
-        f.write_str(") {\n")?;
-        write_scope(f, scope, indent_lvl + 1)?;
-        write_indented(f, indent_lvl, "}")?;
+        write_expr(f, names, condition)?;
+        f.write_str(")")?;
+        f.open_brace(indent_lvl)?;
+        write_scope(f, names, scope, indent_lvl + 1)?;
+        f.close_brace(indent_lvl)?;
       }
 
       ScopeInstr::MutateVar { var, expr } => {
-        write_expr(f, var)?;
+        write_expr(f, names, var)?;
         f.write_str(" = ")?;
-        write_expr(f, expr)?;
+        write_expr(f, names, expr)?;
         f.write_str(";")?;
       }
+
+      ScopeInstr::Discard => {
+        f.write_str("discard;")?;
+      }
     }
 
     f.write_str("\n")?;
@@ -206,6 +1326,7 @@ fn write_scope(
 
 fn write_constant(
   f: &mut impl fmt::Write,
+  names: &Names,
   handle: u16,
   ty: &Type,
   constant: &ErasedExpr,
@@ -213,44 +1334,89 @@ fn write_constant(
   f.write_str("const ")?;
   write_type(f, ty)?;
   f.write_str(" ")?;
-  write_scoped_handle(f, &ScopedHandle::global(handle))?;
+  write_scoped_handle(f, names, &ScopedHandle::global(handle))?;
   f.write_str(" = ")?;
-  write_expr(f, constant)?;
+  write_expr(f, names, constant)?;
   f.write_str(";\n")
 }
 
-fn write_input(f: &mut impl fmt::Write, name: &str, ty: &Type) -> Result<(), fmt::Error> {
+// Integers are not interpolated: integer inputs of fragment shaders must be flat, and so must the outputs of the
+// stages feeding them, as GLSL ES and older GLSL versions require interpolation qualifiers to match across stages.
+fn is_flat(stage: ShaderStage, ty: &Type, is_input: bool) -> bool {
+  let is_integer = matches!(ty.prim_ty, PrimType::Int(_) | PrimType::UInt(_));
+  let is_interpolated = match stage {
+    ShaderStage::Fragment => is_input,
+    ShaderStage::Vertex | ShaderStage::TessEval | ShaderStage::Geometry => !is_input,
+    ShaderStage::TessCtrl => false,
+  };
+
+  is_integer && is_interpolated
+}
+
+fn write_input(
+  f: &mut impl fmt::Write,
+  stage: ShaderStage,
+  name: &str,
+  ty: &Type,
+) -> Result<(), fmt::Error> {
+  if is_flat(stage, ty, true) {
+    f.write_str("flat ")?;
+  }
+
   f.write_str("in ")?;
-  write_type(f, ty)?;
-  write!(f, " {};\n", name)
+  write_interface_type(f, name, ty)
 }
 
-fn write_output(f: &mut impl fmt::Write, name: &str, ty: &Type) -> Result<(), fmt::Error> {
+fn write_output(
+  f: &mut impl fmt::Write,
+  stage: ShaderStage,
+  name: &str,
+  ty: &Type,
+  attachment: Option<&ColorAttachment>,
+) -> Result<(), fmt::Error> {
+  if let Some(attachment) = attachment {
+    write!(f, "layout (location = {}", attachment.location())?;
+
+    if attachment.index() != 0 {
+      write!(f, ", index = {}", attachment.index())?;
+    }
+
+    f.write_str(") ")?;
+  }
+
+  if is_flat(stage, ty, false) {
+    f.write_str("flat ")?;
+  }
+
   f.write_str("out ")?;
-  write_type(f, ty)?;
-  write!(f, " {};\n", name)
+  write_interface_type(f, name, ty)
 }
 
 fn write_uniform(f: &mut impl fmt::Write, name: &str, ty: &Type) -> Result<(), fmt::Error> {
   f.write_str("uniform ")?;
-  write_type(f, ty)?;
-  write!(f, " {};\n", name)
+  write_interface_type(f, name, ty)
 }
 
-fn write_expr(f: &mut impl fmt::Write, expr: &ErasedExpr) -> Result<(), fmt::Error> {
+fn write_expr(f: &mut impl fmt::Write, names: &Names, expr: &ErasedExpr) -> Result<(), fmt::Error> {
   match expr {
-    ErasedExpr::LitInt(x) => write!(f, "{}", x),
-    ErasedExpr::LitUInt(x) => write!(f, "{}", x),
+    ErasedExpr::LitInt(x) => write!(f, "{}", write_i32(*x)),
+    ErasedExpr::LitUInt(x) => write!(f, "{}u", x),
     ErasedExpr::LitFloat(x) => write!(f, "{}", write_f32(*x)),
     ErasedExpr::LitBool(x) => write!(f, "{}", x),
 
-    ErasedExpr::LitInt2([x, y]) => write!(f, "ivec2({}, {})", x, y),
+    ErasedExpr::LitInt2([x, y]) => write!(f, "ivec2({}, {})", write_i32(*x), write_i32(*y)),
     ErasedExpr::LitUInt2([x, y]) => write!(f, "uvec2({}, {})", x, y),
     ErasedExpr::LitFloat2([x, y]) => write!(f, "vec2({}, {})", write_f32(*x), write_f32(*y)),
 
     ErasedExpr::LitBool2([x, y]) => write!(f, "bvec2({}, {})", x, y),
 
-    ErasedExpr::LitInt3([x, y, z]) => write!(f, "ivec3({}, {}, {})", x, y, z),
+    ErasedExpr::LitInt3([x, y, z]) => write!(
+      f,
+      "ivec3({}, {}, {})",
+      write_i32(*x),
+      write_i32(*y),
+      write_i32(*z)
+    ),
     ErasedExpr::LitUInt3([x, y, z]) => write!(f, "uvec3({}, {}, {})", x, y, z),
     ErasedExpr::LitFloat3([x, y, z]) => write!(
       f,
@@ -261,7 +1427,14 @@ fn write_expr(f: &mut impl fmt::Write, expr: &ErasedExpr) -> Result<(), fmt::Err
     ),
     ErasedExpr::LitBool3([x, y, z]) => write!(f, "bvec3({}, {}, {})", x, y, z),
 
-    ErasedExpr::LitInt4([x, y, z, w]) => write!(f, "ivec4({}, {}, {}, {})", x, y, z, w),
+    ErasedExpr::LitInt4([x, y, z, w]) => write!(
+      f,
+      "ivec4({}, {}, {}, {})",
+      write_i32(*x),
+      write_i32(*y),
+      write_i32(*z),
+      write_i32(*w)
+    ),
     ErasedExpr::LitUInt4([x, y, z, w]) => write!(f, "uvec4({}, {}, {}, {})", x, y, z, w),
     ErasedExpr::LitFloat4([x, y, z, w]) => write!(
       f,
@@ -282,226 +1455,236 @@ fn write_expr(f: &mut impl fmt::Write, expr: &ErasedExpr) -> Result<(), fmt::Err
       write_type(f, ty)?;
       f.write_str("(")?;
 
-      write_expr(f, &items[0])?;
+      write_expr(f, names, &items[0])?;
       for item in &items[1..] {
         f.write_str(",")?;
-        write_expr(f, item)?;
+        write_expr(f, names, item)?;
       }
 
       f.write_str(")")
     }
 
-    ErasedExpr::Var(handle) => write_var(f, handle),
+    ErasedExpr::Var(handle) => write_var(f, names, handle),
 
     ErasedExpr::Not(e) => {
       f.write_str("!")?;
-      write_expr(f, e)
+      write_expr(f, names, e)
     }
 
     ErasedExpr::And(a, b) => {
-      f.write_str("()")?;
-      write_expr(f, a)?;
-      f.write_str("&&")?;
-      write_expr(f, b)?;
+      f.write_str("(")?;
+      write_expr(f, names, a)?;
+      f.write_str(" && ")?;
+      write_expr(f, names, b)?;
       f.write_str(")")
     }
 
     ErasedExpr::Or(a, b) => {
       f.write_str("(")?;
-      write_expr(f, a)?;
+      write_expr(f, names, a)?;
       f.write_str(" || ")?;
-      write_expr(f, b)?;
+      write_expr(f, names, b)?;
       f.write_str(")")
     }
 
     ErasedExpr::Xor(a, b) => {
       f.write_str("(")?;
-      write_expr(f, a)?;
+      write_expr(f, names, a)?;
       f.write_str(" ^^ ")?;
-      write_expr(f, b)?;
+      write_expr(f, names, b)?;
       f.write_str(")")
     }
 
     ErasedExpr::BitAnd(a, b) => {
       f.write_str("(")?;
-      write_expr(f, a)?;
+      write_expr(f, names, a)?;
       f.write_str(" & ")?;
-      write_expr(f, b)?;
+      write_expr(f, names, b)?;
       f.write_str(")")
     }
 
     ErasedExpr::BitOr(a, b) => {
       f.write_str("(")?;
-      write_expr(f, a)?;
+      write_expr(f, names, a)?;
       f.write_str(" | ")?;
-      write_expr(f, b)?;
+      write_expr(f, names, b)?;
       f.write_str(")")
     }
 
     ErasedExpr::BitXor(a, b) => {
       f.write_str("(")?;
-      write_expr(f, a)?;
+      write_expr(f, names, a)?;
       f.write_str(" ^ ")?;
-      write_expr(f, b)?;
+      write_expr(f, names, b)?;
       f.write_str(")")
     }
 
     ErasedExpr::Neg(e) => {
       f.write_str("-(")?;
-      write_expr(f, e)?;
+      write_expr(f, names, e)?;
       f.write_str(")")
     }
 
     ErasedExpr::Add(a, b) => {
       f.write_str("(")?;
-      write_expr(f, a)?;
+      write_expr(f, names, a)?;
       f.write_str(" + ")?;
-      write_expr(f, b)?;
+      write_expr(f, names, b)?;
       f.write_str(")")
     }
 
     ErasedExpr::Sub(a, b) => {
       f.write_str("(")?;
-      write_expr(f, a)?;
+      write_expr(f, names, a)?;
       f.write_str(" - ")?;
-      write_expr(f, b)?;
+      write_expr(f, names, b)?;
       f.write_str(")")
     }
 
     ErasedExpr::Mul(a, b) => {
       f.write_str("(")?;
-      write_expr(f, a)?;
+      write_expr(f, names, a)?;
       f.write_str(" * ")?;
-      write_expr(f, b)?;
+      write_expr(f, names, b)?;
       f.write_str(")")
     }
 
     ErasedExpr::Div(a, b) => {
       f.write_str("(")?;
-      write_expr(f, a)?;
+      write_expr(f, names, a)?;
       f.write_str(" / ")?;
-      write_expr(f, b)?;
+      write_expr(f, names, b)?;
       f.write_str(")")
     }
 
     ErasedExpr::Rem(a, b) => {
       f.write_str("mod(")?;
-      write_expr(f, a)?;
+      write_expr(f, names, a)?;
       f.write_str(", ")?;
-      write_expr(f, b)?;
+      write_expr(f, names, b)?;
       f.write_str(")")
     }
 
     ErasedExpr::Shl(a, b) => {
       f.write_str("(")?;
-      write_expr(f, a)?;
+      write_expr(f, names, a)?;
       f.write_str(" << ")?;
-      write_expr(f, b)?;
+      write_expr(f, names, b)?;
       f.write_str(")")
     }
 
     ErasedExpr::Shr(a, b) => {
       f.write_str("(")?;
-      write_expr(f, a)?;
+      write_expr(f, names, a)?;
       f.write_str(" >> ")?;
-      write_expr(f, b)?;
+      write_expr(f, names, b)?;
       f.write_str(")")
     }
 
     ErasedExpr::Eq(a, b) => {
       f.write_str("(")?;
-      write_expr(f, a)?;
+      write_expr(f, names, a)?;
       f.write_str(" == ")?;
-      write_expr(f, b)?;
+      write_expr(f, names, b)?;
       f.write_str(")")
     }
 
     ErasedExpr::Neq(a, b) => {
       f.write_str("(")?;
-      write_expr(f, a)?;
+      write_expr(f, names, a)?;
       f.write_str(" != ")?;
-      write_expr(f, b)?;
+      write_expr(f, names, b)?;
       f.write_str(")")
     }
 
     ErasedExpr::Lt(a, b) => {
       f.write_str("(")?;
-      write_expr(f, a)?;
+      write_expr(f, names, a)?;
       f.write_str(" < ")?;
-      write_expr(f, b)?;
+      write_expr(f, names, b)?;
       f.write_str(")")
     }
 
     ErasedExpr::Lte(a, b) => {
       f.write_str("(")?;
-      write_expr(f, a)?;
+      write_expr(f, names, a)?;
       f.write_str(" <= ")?;
-      write_expr(f, b)?;
+      write_expr(f, names, b)?;
       f.write_str(")")
     }
 
     ErasedExpr::Gt(a, b) => {
       f.write_str("(")?;
-      write_expr(f, a)?;
+      write_expr(f, names, a)?;
       f.write_str(" > ")?;
-      write_expr(f, b)?;
+      write_expr(f, names, b)?;
       f.write_str(")")
     }
 
     ErasedExpr::Gte(a, b) => {
       f.write_str("(")?;
-      write_expr(f, a)?;
+      write_expr(f, names, a)?;
       f.write_str(" >= ")?;
-      write_expr(f, b)?;
+      write_expr(f, names, b)?;
       f.write_str(")")
     }
 
     ErasedExpr::FunCall(fun, args) => {
-      write_fun_handle(f, fun)?;
+      write_fun_handle(f, names, fun)?;
       f.write_str("(")?;
 
-      if !args.is_empty() {
-        write_expr(f, &args[0])?;
-      }
+      for (i, arg) in args.iter().enumerate() {
+        if i != 0 {
+          f.write_str(", ")?;
+        }
 
-      for arg in &args[1..] {
-        f.write_str(", ")?;
-        write_expr(f, arg)?;
+        write_expr(f, names, arg)?;
       }
 
       f.write_str(")")
     }
 
     ErasedExpr::Swizzle(e, s) => {
-      write_expr(f, e)?;
+      write_expr(f, names, e)?;
       f.write_str(".")?;
       write_swizzle(f, s)
     }
 
     ErasedExpr::Field { object, field } => {
-      write_expr(f, object)?;
+      write_expr(f, names, object)?;
       f.write_str(".")?;
-      write_expr(f, field)
+      write_expr(f, names, field)
     }
 
     ErasedExpr::ArrayLookup { object, index } => {
-      write_expr(f, object)?;
+      write_expr(f, names, object)?;
       f.write_str("[")?;
-      write_expr(f, index)?;
+      write_expr(f, names, index)?;
       f.write_str("]")
     }
   }
 }
 
+fn write_i32(i: i32) -> String {
+  // 2147483648 is out of the range of int literals, so that -2147483648 is not a valid literal
+  if i == i32::MIN {
+    "(-2147483647 - 1)".to_owned()
+  } else {
+    i.to_string()
+  }
+}
+
 fn write_f32(f: f32) -> String {
+  // -0. is kept, as it changes the result of divisions and some built-ins
   if f == 0. {
-    return "0.".to_owned();
+    return if f.is_sign_negative() { "-0." } else { "0." }.to_owned();
   }
 
   let mut s = f.to_string();
 
   if f.trunc() == 0. {
-    s[1..].to_string()
+    // drop the leading zero, keeping the sign
+    s.replacen("0.", ".", 1)
   } else if f.fract() == 0. {
     s += ".";
     s
@@ -543,7 +1726,18 @@ fn write_swizzle_sel(f: &mut impl fmt::Write, d: &SwizzleSelector) -> Result<(),
   }
 }
 
-fn write_fun_handle(f: &mut impl fmt::Write, fun: &ErasedFunHandle) -> Result<(), fmt::Error> {
+/// GLSL name of a built-in function.
+pub(crate) fn fun_name(handle: &ErasedFunHandle) -> String {
+  let mut name = String::new();
+  let _ = write_fun_handle(&mut name, &Names::default(), handle);
+  name
+}
+
+fn write_fun_handle(
+  f: &mut impl fmt::Write,
+  names: &Names,
+  fun: &ErasedFunHandle,
+) -> Result<(), fmt::Error> {
   match fun {
     ErasedFunHandle::Vec2 => f.write_str("vec2"),
     ErasedFunHandle::Vec3 => f.write_str("vec3"),
@@ -654,33 +1848,54 @@ fn write_fun_handle(f: &mut impl fmt::Write, fun: &ErasedFunHandle) -> Result<()
     ErasedFunHandle::AnyInvocation => f.write_str("anyInvocation"),
     ErasedFunHandle::AllInvocations => f.write_str("allInvocations"),
     ErasedFunHandle::AllInvocationsEqual => f.write_str("allInvocationsEqual"),
-    ErasedFunHandle::UserDefined(handle) => write_user_fun_handle(f, *handle),
+    ErasedFunHandle::UserDefined(handle) => write_user_fun_handle(f, names, *handle),
+    ErasedFunHandle::Library(library, handle) => write!(f, "lib_{}_fun_{}", library, handle),
   }
 }
 
-fn write_user_fun_handle(f: &mut impl fmt::Write, handle: u16) -> Result<(), fmt::Error> {
-  write!(f, "fun_{}", handle)
+fn write_user_fun_handle(
+  f: &mut impl fmt::Write,
+  names: &Names,
+  handle: u16,
+) -> Result<(), fmt::Error> {
+  match names.funs.get(&handle) {
+    Some(ident) => f.write_str(ident),
+    None => write!(f, "fun_{}", handle),
+  }
 }
 
-fn write_var(f: &mut impl fmt::Write, handle: &ScopedHandle) -> Result<(), fmt::Error> {
-  write_scoped_handle(f, handle)
+fn write_var(
+  f: &mut impl fmt::Write,
+  names: &Names,
+  handle: &ScopedHandle,
+) -> Result<(), fmt::Error> {
+  write_scoped_handle(f, names, handle)
 }
 
-fn write_scoped_handle(f: &mut impl fmt::Write, handle: &ScopedHandle) -> Result<(), fmt::Error> {
+fn write_scoped_handle(
+  f: &mut impl fmt::Write,
+  names: &Names,
+  handle: &ScopedHandle,
+) -> Result<(), fmt::Error> {
   match handle {
     ScopedHandle::BuiltIn(builtin) => write_builtin(f, builtin),
 
-    ScopedHandle::Global(handle) => {
-      write!(f, "glob_{}", handle)
-    }
+    ScopedHandle::Global(handle) => match names.consts.get(handle) {
+      Some(ident) => f.write_str(ident),
+      None => write!(f, "glob_{}", handle),
+    },
 
-    ScopedHandle::FunArg(handle) => {
-      write!(f, "arg_{}", handle)
-    }
+    ScopedHandle::LibraryGlobal(library, handle) => write!(f, "lib_{}_glob_{}", library, handle),
 
-    ScopedHandle::FunVar { subscope, handle } => {
-      write!(f, "var_{}_{}", subscope, handle)
-    }
+    ScopedHandle::FunArg(handle) => match names.args.get(*handle as usize) {
+      Some(ident) => f.write_str(ident),
+      None => write!(f, "arg_{}", handle),
+    },
+
+    ScopedHandle::FunVar { subscope, handle } => match names.var(*subscope, *handle) {
+      Some(ident) => f.write_str(ident),
+      None => write!(f, "var_{}_{}", subscope, handle),
+    },
 
     ScopedHandle::Input(name) => f.write_str(name),
 
@@ -833,36 +2048,32 @@ fn write_prim_type(f: &mut impl fmt::Write, prim_ty: &PrimType) -> Result<(), fm
 
 fn write_type(f: &mut impl fmt::Write, ty: &Type) -> Result<(), fmt::Error> {
   write_prim_type(f, &ty.prim_ty)?;
+  write_array_dims(f, &ty.array_dims)
+}
 
-  // array notation
-  if !ty.array_dims.is_empty() {
-    f.write_str("[")?;
+// Write the type and name of an interface declaration; array dimensions are written after the name, as unsized arrays
+// are not allowed in type position.
+fn write_interface_type(f: &mut impl fmt::Write, name: &str, ty: &Type) -> Result<(), fmt::Error> {
+  write_prim_type(f, &ty.prim_ty)?;
+  write!(f, " {}", name)?;
+  write_array_dims(f, &ty.array_dims)?;
+  f.write_str(";\n")
+}
 
-    write!(f, "{}", &ty.array_dims[0])?;
-    for dim in &ty.array_dims[1..] {
-      write!(f, "][{}", dim)?;
+fn write_array_dims(f: &mut impl fmt::Write, dims: &[usize]) -> Result<(), fmt::Error> {
+  for &dim in dims {
+    // 0 represents unsized arrays
+    if dim == 0 {
+      f.write_str("[]")?;
+    } else {
+      write!(f, "[{}]", dim)?;
     }
-
-    f.write_str("]")
-  } else {
-    Ok(())
   }
-}
-
-fn write_indented(f: &mut impl fmt::Write, indent_lvl: usize, t: &str) -> Result<(), fmt::Error> {
-  write_indent(f, indent_lvl)?;
-  f.write_str(t)
-}
 
-fn write_indent(f: &mut impl fmt::Write, indent_lvl: usize) -> Result<(), fmt::Error> {
-  write!(
-    f,
-    "{indent:<width$}",
-    indent = " ",
-    width = INDENT_SPACES * indent_lvl
-  )
+  Ok(())
 }
 
+#[allow(clippy::needless_range_loop)]
 fn write_matrix<const M: usize, const N: usize>(
   f: &mut impl fmt::Write,
   ctor_name: &str,
@@ -889,6 +2100,7 @@ fn write_matrix<const M: usize, const N: usize>(
 #[cfg(test)]
 mod tests {
   use super::*;
+  use std::sync::Arc;
 
   #[test]
   fn matrices() {
@@ -923,4 +2135,669 @@ mod tests {
       "mat4(1., 2., 3., 4., 5., 6., 7., 8., 9., 10., 11., 12., 13., 14., 15., 16.)"
     );
   }
+
+  #[test]
+  fn floats() {
+    assert_eq!(write_f32(0.), "0.");
+    assert_eq!(write_f32(0.5), ".5");
+    assert_eq!(write_f32(-0.5), "-.5");
+    assert_eq!(write_f32(-2.), "-2.");
+    assert_eq!(write_f32(1.25), "1.25");
+  }
+
+  #[test]
+  fn ints() {
+    assert_eq!(write_i32(-3), "-3");
+    assert_eq!(write_i32(i32::MAX), "2147483647");
+    assert_eq!(write_i32(i32::MIN), "(-2147483647 - 1)");
+
+    let mut output = String::new();
+    let expr = ErasedExpr::LitInt2([i32::MIN, 1]);
+    write_expr(&mut output, &Names::default(), &expr).unwrap();
+    assert_eq!(output, "ivec2((-2147483647 - 1), 1)");
+  }
+
+  #[test]
+  fn uint_literals() {
+    let mut output = String::new();
+    write_expr(&mut output, &Names::default(), &ErasedExpr::LitUInt(3)).unwrap();
+    assert_eq!(output, "3u");
+  }
+
+  #[test]
+  fn negative_zero() {
+    use crate::{lit, Scope, ShaderBuilder};
+
+    assert_eq!(write_f32(-0.), "-0.");
+
+    // folding -1. * 0. must not turn the division into 1. / 0.
+    let shader = ShaderBuilder::new_fragment_shader(|mut s, _| {
+      let color = s.output::<f32>("color").unwrap();
+      s.main_fun(|s: &mut Scope<()>| s.set(&color, lit!(1.) / (lit!(-1.) * lit!(0.))))
+    });
+    let options = WriteOptions::new().with_constant_folding(true);
+    let output = write_shader_to_str_with_options(&shader, &options).unwrap();
+
+    assert!(output.contains("color = (1. / -0.);"), "{}", output);
+  }
+
+  #[test]
+  fn color_attachments() {
+    let mut output = String::new();
+    let ty = <crate::V4<f32> as crate::ToType>::ty();
+
+    write_output(&mut output, ShaderStage::Fragment, "color", &ty, None).unwrap();
+    assert_eq!(output, "out vec4 color;\n");
+
+    output.clear();
+    write_output(
+      &mut output,
+      ShaderStage::Fragment,
+      "color",
+      &ty,
+      Some(&ColorAttachment::new(2)),
+    )
+    .unwrap();
+    assert_eq!(output, "layout (location = 2) out vec4 color;\n");
+
+    output.clear();
+    write_output(
+      &mut output,
+      ShaderStage::Fragment,
+      "color",
+      &ty,
+      Some(&ColorAttachment::new(0).with_index(1)),
+    )
+    .unwrap();
+    assert_eq!(output, "layout (location = 0, index = 1) out vec4 color;\n");
+  }
+
+  #[test]
+  fn program() {
+    use crate::{Scope, V3};
+
+    let sources = Program::new_vertex_shader(|mut s, _| {
+      let color = s.output::<V3<f32>>("color")?;
+      Ok((s.main_fun(|_: &mut Scope<()>| {}), color))
+    })
+    .unwrap()
+    .geometry_shader(|mut s, _, _| {
+      let color = s.output::<V3<f32>>("geo_color")?;
+      Ok((s.main_fun(|_: &mut Scope<()>| {}), color))
+    })
+    .unwrap()
+    .fragment_shader(|s, _, _| Ok(s.main_fun(|_: &mut Scope<()>| {})))
+    .map(|program| write_program_to_str(&program))
+    .unwrap()
+    .unwrap();
+
+    assert!(sources.vertex.starts_with("out vec3 color;\n"));
+    assert_eq!(sources.tess_ctrl, None);
+    assert_eq!(sources.tess_eval, None);
+    assert!(sources
+      .geometry
+      .unwrap()
+      .starts_with("in vec3 color[];\nout vec3 geo_color;\n"));
+    assert!(sources.fragment.starts_with("in vec3 geo_color;\n"));
+  }
+
+  #[test]
+  fn flat_varyings() {
+    use crate::{Scope, V2};
+
+    let sources = Program::new_vertex_shader(|mut s, _| {
+      let id = s.output::<u32>("id")?;
+      let cell = s.output::<[V2<i32>; 2]>("cell")?;
+      let depth = s.output::<f32>("depth")?;
+      Ok((s.main_fun(|_: &mut Scope<()>| {}), (id, cell, depth)))
+    })
+    .unwrap()
+    .fragment_shader(|s, _, _| Ok(s.main_fun(|_: &mut Scope<()>| {})))
+    .map(|program| write_program_to_str(&program))
+    .unwrap()
+    .unwrap();
+
+    assert!(sources
+      .vertex
+      .starts_with("flat out uint id;\nflat out ivec2 cell[2];\nout float depth;\n"));
+    assert!(sources
+      .fragment
+      .starts_with("flat in uint id;\nflat in ivec2 cell[2];\nin float depth;\n"));
+  }
+
+  #[test]
+  fn targets() {
+    use crate::{sw, Expr, Scope, ShaderBuilder, Swizzlable as _, V4};
+
+    let es = WriteOptions::new().with_target(Target::GlslEs300);
+
+    let shader = ShaderBuilder::new_fragment_shader(|mut s, fragment| {
+      let color = s.output::<V4<f32>>("color").unwrap();
+
+      s.main_fun(|s: &mut Scope<()>| {
+        s.set(&color, Expr::from(V4::from([1., 0., 0., 1.])));
+        s.set(&fragment.frag_depth, sw!(fragment.frag_coord.clone(), .z));
+      })
+    });
+    assert!(write_shader_to_str_with_options(&shader, &es).is_ok());
+
+    let shader = ShaderBuilder::new_fragment_shader(|s, fragment| {
+      s.main_fun(|s: &mut Scope<()>| {
+        s.var(fragment.sample_id.clone());
+      })
+    });
+    assert!(write_shader_to_str(&shader).is_ok());
+    assert_eq!(
+      write_shader_to_str_with_options(&shader, &es),
+      Err(WriteError::Unsupported {
+        declaration: Declaration::Main,
+        construct: Construct::BuiltIn(BuiltIn::Fragment(FragmentBuiltIn::SampleID)),
+      })
+    );
+
+    let shader = ShaderBuilder::new_fragment_shader(|mut s, _| {
+      let _ = s
+        .color_attachment::<V4<f32>>("color", ColorAttachment::new(0).with_index(1))
+        .unwrap();
+      s.main_fun(|_: &mut Scope<()>| {})
+    });
+    assert_eq!(
+      write_shader_to_str_with_options(&shader, &es),
+      Err(WriteError::Unsupported {
+        declaration: Declaration::Interface("color".to_owned()),
+        construct: Construct::ColorAttachmentIndex,
+      })
+    );
+
+    // fma has no EDSL function yet
+    let mut shader = ShaderBuilder::new_vertex_shader(|mut s, _| {
+      let _ = s.fun(|_: &mut Scope<Expr<f32>>, a: Expr<f32>| a);
+      s.main_fun(|_: &mut Scope<()>| {})
+    });
+    if let ShaderDecl::FunDef(_, fun) = &mut shader.decls[0] {
+      let arg = ErasedExpr::Var(ScopedHandle::FunArg(0));
+      fun.ret = ErasedReturn::Expr(
+        <f32 as crate::ToType>::ty(),
+        ErasedExpr::FunCall(
+          ErasedFunHandle::FMA,
+          vec![Arc::new(arg.clone()), Arc::new(arg.clone()), Arc::new(arg)],
+        ),
+      );
+    }
+    assert_eq!(
+      write_shader_to_str_with_options(&shader, &es),
+      Err(WriteError::Unsupported {
+        declaration: Declaration::Function(0),
+        construct: Construct::Function("fma".to_owned()),
+      })
+    );
+  }
+
+  #[test]
+  fn identifiers() {
+    assert_eq!(sanitize_identifier("albedo"), "albedo");
+    assert_eq!(sanitize_identifier("base color"), "base_color");
+    assert_eq!(sanitize_identifier("a__b"), "a_b");
+    assert_eq!(sanitize_identifier("2d"), "_2d");
+    assert_eq!(sanitize_identifier("gl_Position"), "_gl_Position");
+    assert_eq!(sanitize_identifier(""), "_");
+
+    let mut names = Names::default();
+    assert_eq!(names.allocate("albedo", false), "albedo");
+    assert_eq!(names.allocate("albedo", false), "albedo_1");
+    assert_eq!(names.allocate("float", false), "float_1");
+    assert_eq!(names.allocate("main", false), "main_1");
+    assert_eq!(names.allocate("var_0_1", false), "var_0_1_1");
+    assert_eq!(names.allocate("max", false), "max_1");
+  }
+
+  #[test]
+  fn named_declarations() {
+    use crate::{lit, Expr, Scope, ShaderBuilder, V4};
+
+    let shader = ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+      let color = s.output::<V4<f32>>("color").unwrap();
+      let scale = s.constant_named("scale", 2.);
+      let unnamed = s.constant(3.);
+
+      let tint = s.fun_named(
+        "color",
+        &["in"],
+        |s: &mut Scope<Expr<f32>>, x: Expr<f32>, y: Expr<f32>| {
+          let scaled = s.var_named("scaled", x * scale.clone());
+          let other = s.var_named("scaled", y);
+          scaled + other
+        },
+      );
+
+      s.main_fun(|s: &mut Scope<()>| {
+        let t = s.var_named("t", tint.call(unnamed.clone(), lit!(1.)));
+        s.set(&color, Expr::from(V4::from([1., 0., 0., 1.])) * t.clone());
+        s.set(&vertex.position, V4::from([0., 0., 0., 1.]));
+      })
+    });
+
+    let output = write_shader_to_str(&shader).unwrap();
+    assert!(output.contains("const float scale = 2.;"));
+    assert!(output.contains("const float glob_1 = 3.;"));
+    assert!(output.contains("float color_1(float in_1, float arg_1) {"));
+    assert!(output.contains("float scaled = (in_1 * scale);"));
+    assert!(output.contains("float scaled_1 = arg_1;"));
+    assert!(output.contains("return (scaled + scaled_1);"));
+    assert!(output.contains("float t = color_1(glob_1, 1.);"));
+  }
+
+  #[test]
+  fn built_in_function_names() {
+    use crate::{lit, Bounded as _, Scope, ShaderBuilder};
+
+    let shader = ShaderBuilder::new_fragment_shader(|mut s, _| {
+      let color = s.output::<f32>("color").unwrap();
+
+      s.main_fun(|s: &mut Scope<()>| {
+        let a = s.var_named("max", lit!(1.));
+        s.set(&color, a.max(lit!(2.)));
+      })
+    });
+
+    let output = write_shader_to_str(&shader).unwrap();
+    assert!(output.contains("float max_1 = 1.;"), "{}", output);
+    assert!(output.contains("color = max(max_1, 2.);"), "{}", output);
+  }
+
+  #[test]
+  fn arguments_passed_by_reference() {
+    use crate::{lit, Expr, InOut, Out, Scope, ShaderBuilder, V4};
+
+    let shader = ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+      let scale = s.fun_named(
+        "scale",
+        &["factor", "position", "scaled"],
+        |s: &mut Scope<Expr<bool>>,
+         factor: Expr<f32>,
+         position: InOut<V4<f32>>,
+         scaled: Out<bool>| {
+          s.set(&position, position.to_expr() * factor);
+          s.set(&scaled, lit!(true));
+          lit!(true)
+        },
+      );
+
+      s.main_fun(|s: &mut Scope<()>| {
+        let scaled = s.var_named("scaled", lit!(false));
+        let _ = s.var(scale.call(lit!(2.), &vertex.position, &scaled));
+      })
+    });
+
+    let output = write_shader_to_str(&shader).unwrap();
+    assert!(output.contains("bool scale(float factor, inout vec4 position, out bool scaled) {"));
+    assert!(output.contains("position = (position * factor);"));
+    assert!(output.contains("scale(2., gl_Position, scaled);"));
+  }
+
+  #[test]
+  fn array_functions() {
+    use crate::{lit, vec4, Expr, Out, Scope, ShaderBuilder};
+
+    let shader = ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+      let swap = s.fun_named(
+        "swap",
+        &["a"],
+        |_: &mut Scope<Expr<[f32; 2]>>, a: Expr<[f32; 2]>| Expr::from([a.at(1), a.at(0)]),
+      );
+      let copy = s.fun_named(
+        "copy",
+        &["a", "b"],
+        |s: &mut Scope<Expr<[[f32; 2]; 3]>>, a: Expr<[[f32; 2]; 3]>, b: Out<[[f32; 2]; 3]>| {
+          s.set(&b, &a);
+          a
+        },
+      );
+
+      s.main_fun(|s: &mut Scope<()>| {
+        let x = s.var_named("x", swap.call(lit!([1., 2.])));
+        let m = s.var_named("m", lit!([[1., 2.], [3., 4.], [5., 6.]]));
+        let y = s.var_named("y", copy.call(m.to_expr(), &m));
+        s.set(&vertex.position, vec4!(x.at(0), y.at(1).at(0), 0., 1.));
+      })
+    });
+
+    let output = write_shader_to_str(&shader).unwrap();
+    assert!(output.contains("float[2] swap(float[2] a) {"));
+    assert!(output.contains("return float[2](a[1],a[0]);"));
+    assert!(output.contains("float[3][2] copy(float[3][2] a, out float[3][2] b) {"));
+    assert!(output.contains("float[3][2] y = copy(m, m);"));
+    assert!(output.contains("gl_Position = vec4(x[0], y[1][0], 0., 1.);"));
+  }
+
+  #[test]
+  fn function_order() {
+    use crate::{lit, ErasedReturn, Expr, Scope, ShaderBuilder, ToType as _};
+
+    let mut shader = ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+      let first = s.fun_named("first", &[], |_: &mut Scope<Expr<f32>>| lit!(0.));
+      let k = s.constant_named("k", lit!(2.));
+      let _ = s.fun_named(
+        "second",
+        &["x"],
+        |_: &mut Scope<Expr<f32>>, x: Expr<f32>| x * k,
+      );
+      let _ = s.fun_named("third", &[], |_: &mut Scope<Expr<f32>>| lit!(1.));
+
+      s.main_fun(|s: &mut Scope<()>| {
+        s.set(&vertex.point_size, first.call());
+      })
+    });
+
+    // functions can only call functions declared after them when built from the IR
+    if let ShaderDecl::FunDef(_, fun) = &mut shader.decls[0] {
+      let third = ErasedExpr::FunCall(ErasedFunHandle::UserDefined(2), Vec::new());
+      let second = ErasedExpr::FunCall(ErasedFunHandle::UserDefined(1), vec![Arc::new(third)]);
+      fun.ret = ErasedReturn::Expr(f32::ty(), second);
+    }
+
+    // third can be moved before first, but second uses a constant declared after first
+    assert_eq!(
+      write_shader_to_str(&shader).unwrap(),
+      "
+float second(float x);
+
+float third() {
+  return 1.;
+}
+
+float first() {
+  return second(third());
+}
+const float k = 2.;
+
+float second(float x) {
+  return (x * k);
+}
+
+void main() {
+  gl_PointSize = first();
+}"
+    );
+  }
+
+  #[test]
+  fn overloaded_functions() {
+    use crate::{lit, Expr, Scope, ShaderBuilder, V4};
+
+    let shader = ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+      let scalar = s.fun_named("shade", &[], |_: &mut Scope<Expr<f32>>, x: Expr<f32>| x);
+      let vector = s.fun_named(
+        "shade",
+        &[],
+        |_: &mut Scope<Expr<V4<f32>>>, v: Expr<V4<f32>>| v,
+      );
+      let clash = s.fun_named("shade", &[], |_: &mut Scope<Expr<f32>>, x: Expr<f32>| -x);
+
+      s.main_fun(|s: &mut Scope<()>| {
+        let x = s.var(scalar.call(lit!(1.)) + clash.call(lit!(2.)));
+        s.set(
+          &vertex.position,
+          vector.call(lit!(0., 0., 0., 1.)) * x.clone(),
+        );
+      })
+    });
+
+    let output = write_shader_to_str(&shader).unwrap();
+    assert!(output.contains("float shade(float arg_0) {"));
+    assert!(output.contains("vec4 shade(vec4 arg_0) {"));
+    assert!(output.contains("float shade_1(float arg_0) {"));
+    assert!(output.contains("(shade(1.) + shade_1(2.))"));
+
+    let minified =
+      write_shader_to_str_with_options(&shader, &WriteOptions::new().with_minification(true));
+    assert_eq!(minified.unwrap().matches("shade").count(), 0);
+  }
+
+  #[test]
+  fn source_locations() {
+    use crate::{CanEscape as _, Expr, Scope, ShaderBuilder};
+
+    let mut lines = Vec::new();
+    let shader = ShaderBuilder::new_vertex_shader(|mut s, _| {
+      let (f, fun_line) = (s.fun(|_: &mut Scope<Expr<f32>>, a: Expr<f32>| a), line!());
+      lines.push(fun_line);
+
+      lines.push(line!() + 1);
+      s.main_fun(|s: &mut Scope<()>| {
+        let (x, var_line) = (s.var(f.call(1.0.into())), line!());
+        lines.push(var_line);
+
+        let (_, when_line) = (s.when(x.lt(2.), |s| s.set(&x, 2.)), line!());
+        lines.push(when_line);
+      })
+    });
+
+    let options = WriteOptions::new().with_source_comments(true);
+    let (output, source_map) = write_shader_to_str_with_source_map(&shader, &options).unwrap();
+    let file = file!();
+
+    assert_eq!(
+      output,
+      format!(
+        "
+// {file}:{f}
+float fun_0(float arg_0) {{
+  // {file}:{f}
+  return arg_0;
+}}
+
+// {file}:{m}
+void main() {{
+  // {file}:{v}
+  float var_1_0 = fun_0(1.);
+  // {file}:{w}
+  if ((var_1_0 < 2.)) {{
+    // {file}:{w}
+    var_1_0 = 2.;
+  }}
+}}",
+        file = file,
+        f = lines[0],
+        m = lines[1],
+        v = lines[2],
+        w = lines[3],
+      )
+    );
+
+    let located: Vec<_> = source_map
+      .iter()
+      .map(|(line, location)| (line, location.line()))
+      .collect();
+    assert_eq!(
+      located,
+      vec![
+        (3, lines[0]),
+        (5, lines[0]),
+        (9, lines[1]),
+        (11, lines[2]),
+        (13, lines[3]),
+        (15, lines[3]),
+      ]
+    );
+
+    // comments don’t change the generated code
+    let (output, source_map) =
+      write_shader_to_str_with_source_map(&shader, &WriteOptions::new()).unwrap();
+    assert_eq!(output.lines().nth(5), Some("void main() {"));
+    assert_eq!(source_map.location(6).map(Location::line), Some(lines[1]));
+    assert_eq!(source_map.location(7).map(Location::line), Some(lines[2]));
+    assert_eq!(source_map.location(1), None);
+
+    let log = "ERROR: 0:7: 'x' : undeclared identifier\n0:8(12): error: oops\n0(9) : error C0000: oops\nnothing";
+    assert_eq!(
+      source_map.rewrite_log(log),
+      format!(
+        "ERROR: {file}:{v}: 'x' : undeclared identifier\n{file}:{w}: error: oops\n{file}:{w} : error C0000: oops\nnothing",
+        file = file,
+        v = lines[2],
+        w = lines[3],
+      )
+    );
+
+    let shifted = source_map.with_line_offset(1);
+    assert_eq!(shifted.location(8).map(Location::line), Some(lines[2]));
+  }
+
+  #[test]
+  fn layout() {
+    use crate::{lit, CanEscape as _, Expr, Scope, ShaderBuilder};
+
+    let shader = ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+      let scale = s.uniform::<f32>("scale").unwrap();
+      let k = s.constant(lit!(2.));
+      let both = s.fun(|s: &mut Scope<Expr<bool>>, a: Expr<bool>, b: Expr<bool>| {
+        s.when(a.and(b), |s| s.leave(true));
+        a
+      });
+
+      s.main_fun(|s: &mut Scope<()>| {
+        let x = s.var(-scale.clone() - -k.clone());
+        s.var(both.call(x.lt(1.), lit!(true)));
+
+        s.when(x.lt(0.), |s| s.set(&x, 0.))
+          .or_else(x.lt(1.), |s| s.set(&x, 1.))
+          .or(|s| s.set(&x, 2.));
+
+        s.loop_for(
+          0.,
+          |i| i.lt(10.),
+          |i| i + 1.,
+          |s, i| {
+            s.set(&x, x.clone() + i);
+          },
+        );
+
+        s.set(&vertex.position, lit!(0., 0., 0., 1.) * x);
+      })
+    });
+
+    let output = write_shader_to_str(&shader).unwrap();
+    assert_eq!(
+      output,
+      "uniform float scale;
+const float glob_0 = 2.;
+
+bool fun_0(bool arg_0, bool arg_1) {
+  if ((arg_0 && arg_1)) {
+    return true;
+  }
+  return arg_0;
+}
+
+void main() {
+  float var_2_0 = (-(scale) - -(glob_0));
+  bool var_2_1 = fun_0((var_2_0 < 1.), true);
+  if ((var_2_0 < 0.)) {
+    var_2_0 = 0.;
+  }
+  else if ((var_2_0 < 1.)) {
+    var_2_0 = 1.;
+  }
+  else {
+    var_2_0 = 2.;
+  }
+  for (float var_6_0 = 0.; (var_6_0 < 10.); var_6_0 = (var_6_0 + 1.)) {
+    var_2_0 = (var_2_0 + var_6_0);
+  }
+  gl_Position = (vec4(0., 0., 0., 1.) * var_2_0);
+}"
+    );
+
+    let options = WriteOptions::new()
+      .with_indent(Indent::Tabs)
+      .with_brace_placement(BracePlacement::NextLine)
+      .with_blank_lines(BlankLines::BeforeDeclarations);
+    let output = write_shader_to_str_with_options(&shader, &options).unwrap();
+    assert_eq!(
+      output,
+      "
+uniform float scale;
+
+const float glob_0 = 2.;
+
+bool fun_0(bool arg_0, bool arg_1)
+{
+\tif ((arg_0 && arg_1))
+\t{
+\t\treturn true;
+\t}
+\treturn arg_0;
+}
+
+void main()
+{
+\tfloat var_2_0 = (-(scale) - -(glob_0));
+\tbool var_2_1 = fun_0((var_2_0 < 1.), true);
+\tif ((var_2_0 < 0.))
+\t{
+\t\tvar_2_0 = 0.;
+\t}
+\telse if ((var_2_0 < 1.))
+\t{
+\t\tvar_2_0 = 1.;
+\t}
+\telse
+\t{
+\t\tvar_2_0 = 2.;
+\t}
+\tfor (float var_6_0 = 0.; (var_6_0 < 10.); var_6_0 = (var_6_0 + 1.))
+\t{
+\t\tvar_2_0 = (var_2_0 + var_6_0);
+\t}
+\tgl_Position = (vec4(0., 0., 0., 1.) * var_2_0);
+}"
+    );
+
+    let options = WriteOptions::new()
+      .with_indent(Indent::Spaces(4))
+      .with_blank_lines(BlankLines::Never);
+    let output = write_shader_to_str_with_options(&shader, &options).unwrap();
+    assert!(output.starts_with("uniform float scale;\nconst float glob_0 = 2.;\nbool fun_0("));
+    assert!(output.contains("}\nvoid main() {\n    float var_2_0"));
+    assert!(output.contains("    for (float var_6_0 = 0.;"));
+    assert!(output.contains("        var_2_0 = (var_2_0 + var_6_0);\n    }\n"));
+
+    // unary minuses must not merge into a decrement; interface names are kept
+    let options = WriteOptions::new()
+      .with_minification(true)
+      .with_source_comments(true);
+    let output = write_shader_to_str_with_options(&shader, &options).unwrap();
+    assert_eq!(
+      output,
+      "uniform float scale;const float a=2.;bool b(bool c,bool d){if((c&&d)){return true;}return c;}\
+       void main(){float c=(-(scale)- -(a));bool d=b((c<1.),true);if((c<0.)){c=0.;}else if((c<1.)){c=1.;}\
+       else{c=2.;}for(float e=0.;(e<10.);e=(e+1.)){c=(c+e);}gl_Position=(vec4(0.,0.,0.,1.)*c);}"
+    );
+  }
+
+  #[test]
+  fn short_identifiers() {
+    assert_eq!(short_identifier(0), "a");
+    assert_eq!(short_identifier(25), "z");
+    assert_eq!(short_identifier(26), "A");
+    assert_eq!(short_identifier(51), "Z");
+    assert_eq!(short_identifier(52), "aa");
+    assert_eq!(short_identifier(53), "ab");
+    assert_eq!(short_identifier(52 + 52 * 52), "aaa");
+
+    // reserved words are skipped
+    let mut names = Names {
+      minify: true,
+      next_local: 52 * 9 + 13,
+      ..Names::default()
+    };
+    assert_eq!(short_identifier(52 * 9 + 13), "in");
+    assert_eq!(names.shorten(true), "io");
+
+    // so are built-in functions
+    names.next_global = 52 + 52 * 52 + 12 * 52 * 52 + 23;
+    assert_eq!(short_identifier(names.next_global), "max");
+    assert_eq!(names.shorten(false), "may");
+  }
 }
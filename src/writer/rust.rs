@@ -0,0 +1,2749 @@
+//! Rust writers.
+//!
+//! Shaders are written as Rust source code building them again with the EDSL: a function returning the [`Shader`],
+//! built with [`ShaderBuilder`](crate::ShaderBuilder), [`lit!`](crate::lit), [`sw!`](crate::sw) and friends. This is
+//! useful to turn shaders imported from another language into maintainable Rust code, and to compare shaders after a
+//! round-trip through the EDSL in tests.
+//!
+//! Shaders are validated before being written: writing a shader for which [`Shader::validate`] reports diagnostics
+//! fails with [`WriteError::InvalidShader`]. Shaders using constructs the EDSL cannot express, such as an assignment to
+//! a swizzle or an operator the EDSL doesn’t define for its operands, fail with [`WriteError::Unsupported`].
+//!
+//! # Examples
+//!
+//! ```
+//! use shades::{Scope, ShaderBuilder, V3, inputs, vec4, writer::rust};
+//!
+//! let shader = ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+//!   inputs!(s, position: V3<f32>);
+//!
+//!   s.main_fun(|s: &mut Scope<()>| {
+//!     s.set(&vertex.position, vec4!(position, 1.));
+//!   })
+//! });
+//!
+//! let output = rust::write_shader_to_str(&shader).unwrap();
+//!
+//! assert_eq!(
+//!   output,
+//!   r#"use shades::{inputs, vec4, Scope, Shader, ShaderBuilder, V3};
+//!
+//! pub fn vertex_shader() -> Shader {
+//!   ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+//!     inputs!(s, position: V3<f32>);
+//!
+//!     s.main_fun(|s: &mut Scope<()>| {
+//!       s.set(&vertex.position, vec4!(position, 1.0));
+//!     })
+//!   })
+//! }
+//! "#
+//! );
+//! ```
+
+use crate::{
+  call_graph::{CallGraph, OrderedDecl},
+  optimizer,
+  typing::TypeEnv,
+  validation::Declaration,
+  writer::{glsl, Construct, WriteError},
+  ArgQualifier, BuiltIn, Dim, ErasedExpr, ErasedFun, ErasedFunHandle, ErasedReturn, ErasedScope,
+  FragmentBuiltIn, GeometryBuiltIn, MatrixDim, PrimType, ScopeInstr, ScopedHandle, Shader,
+  ShaderDecl, ShaderStage, Swizzle, SwizzleSelector, TessCtrlBuiltIn, TessEvalBuiltIn, Type,
+  VertexBuiltIn,
+};
+use std::{
+  collections::{BTreeSet, HashMap, HashSet},
+  fmt::{self, Write as _},
+  sync::Arc,
+};
+
+// Keywords of Rust, which cannot be used as identifiers.
+#[rustfmt::skip]
+const KEYWORDS: &[&str] = &[
+  "as", "break", "const", "continue", "crate", "else", "enum", "extern", "false", "fn", "for", "if", "impl", "in",
+  "let", "loop", "match", "mod", "move", "mut", "pub", "ref", "return", "self", "Self", "static", "struct", "super",
+  "trait", "true", "type", "unsafe", "use", "where", "while", "async", "await", "dyn", "abstract", "become", "box",
+  "do", "final", "macro", "override", "priv", "typeof", "unsized", "virtual", "yield", "try", "gen",
+];
+
+// Names the written code can import, which identifiers must not shadow.
+#[rustfmt::skip]
+const IMPORTABLE: &[&str] = &[
+  "Bounded", "CanEscape", "Exponential", "Expr", "Floating", "FloatingExt", "Geometry", "M22", "M33", "M44", "Mix",
+  "Relative", "Scope", "Shader", "ShaderBuilder", "Swizzlable", "Trigonometry", "V2", "V3", "V4",
+];
+
+// Precedence of binary operators.
+const PREC_MUL: u8 = 13;
+const PREC_ADD: u8 = 12;
+const PREC_SHIFT: u8 = 11;
+const PREC_BIT_AND: u8 = 10;
+const PREC_BIT_XOR: u8 = 9;
+const PREC_BIT_OR: u8 = 8;
+
+/// Write a [`Shader`] to a [`String`].
+pub fn write_shader_to_str(shader: impl AsRef<Shader>) -> Result<String, WriteError> {
+  let mut output = String::new();
+  write_shader(&mut output, shader)?;
+  Ok(output)
+}
+
+/// Write a [`Shader`] to a [`fmt::Write`].
+///
+/// The output is a Rust module importing what it needs from `shades` and defining a `pub fn` named after the stage of
+/// the shader, such as `vertex_shader`, that returns the shader.
+pub fn write_shader(f: &mut impl fmt::Write, shader: impl AsRef<Shader>) -> Result<(), WriteError> {
+  let shader = shader.as_ref();
+  let stage = shader.stage;
+  let diagnostics = shader.validate();
+
+  if !diagnostics.is_empty() {
+    return Err(WriteError::InvalidShader(diagnostics));
+  }
+
+  let mut writer = Writer::new(&shader.decls, stage);
+  let body = writer.write_decls()?;
+
+  let imports = writer.imports().join(", ");
+  let line = format!("use shades::{{{}}};", imports);
+
+  if line.len() <= 100 {
+    writeln!(f, "{}\n", line)?;
+  } else {
+    // fill lines with as many imports as they can hold
+    f.write_str("use shades::{\n ")?;
+    let mut width = 1;
+
+    for import in writer.imports() {
+      if width + import.len() + 2 > 100 {
+        f.write_str("\n ")?;
+        width = 1;
+      }
+
+      write!(f, " {},", import)?;
+      width += import.len() + 2;
+    }
+
+    f.write_str("\n};\n\n")?;
+  }
+
+  let env = if writer.env_used {
+    env_ident(stage)
+  } else {
+    "_"
+  };
+  let builder = if shader.decls.len() > 1 { "mut s" } else { "s" };
+
+  writeln!(f, "pub fn {}_shader() -> Shader {{", env_ident(stage))?;
+  writeln!(
+    f,
+    "  ShaderBuilder::new_{}_shader(|{}, {}| {{",
+    env_ident(stage),
+    builder,
+    env
+  )?;
+  f.write_str(&body)?;
+  f.write_str("  })\n}\n")?;
+
+  Ok(())
+}
+
+/// Identifier of the environment of a stage, also used to name its constructor.
+fn env_ident(stage: ShaderStage) -> &'static str {
+  match stage {
+    ShaderStage::Vertex => "vertex",
+    ShaderStage::TessCtrl => "tess_ctrl",
+    ShaderStage::TessEval => "tess_eval",
+    ShaderStage::Geometry => "geometry",
+    ShaderStage::Fragment => "fragment",
+  }
+}
+
+/// Field of the environment of a stage holding a built-in, and whether it can be written to.
+fn env_field(stage: ShaderStage, builtin: &BuiltIn) -> Option<(&'static str, bool)> {
+  let field = match (stage, builtin) {
+    (ShaderStage::Vertex, BuiltIn::Vertex(builtin)) => match builtin {
+      VertexBuiltIn::VertexID => ("vertex_id", false),
+      VertexBuiltIn::InstanceID => ("instance_id", false),
+      VertexBuiltIn::BaseVertex => ("base_vertex", false),
+      VertexBuiltIn::BaseInstance => ("base_instance", false),
+      VertexBuiltIn::Position => ("position", true),
+      VertexBuiltIn::PointSize => ("point_size", true),
+      VertexBuiltIn::ClipDistance => ("clip_distance", true),
+    },
+
+    (ShaderStage::TessCtrl, BuiltIn::TessCtrl(builtin)) => match builtin {
+      TessCtrlBuiltIn::MaxPatchVerticesIn => ("max_patch_vertices_in", false),
+      TessCtrlBuiltIn::PatchVerticesIn => ("patch_vertices_in", false),
+      TessCtrlBuiltIn::PrimitiveID => ("primitive_id", false),
+      TessCtrlBuiltIn::InvocationID => ("invocation_id", false),
+      TessCtrlBuiltIn::TessellationLevelOuter => ("tess_level_outer", true),
+      TessCtrlBuiltIn::TessellationLevelInner => ("tess_level_inner", true),
+      TessCtrlBuiltIn::In => ("input", false),
+      TessCtrlBuiltIn::Out => ("output", true),
+      _ => return None,
+    },
+
+    (ShaderStage::TessEval, BuiltIn::TessEval(builtin)) => match builtin {
+      TessEvalBuiltIn::TessCoord => ("tess_coord", false),
+      TessEvalBuiltIn::PatchVerticesIn => ("patch_vertices_in", false),
+      TessEvalBuiltIn::PrimitiveID => ("primitive_id", false),
+      TessEvalBuiltIn::TessellationLevelOuter => ("tess_level_outer", false),
+      TessEvalBuiltIn::TessellationLevelInner => ("tess_level_inner", false),
+      TessEvalBuiltIn::In => ("input", false),
+      TessEvalBuiltIn::Position => ("position", true),
+      TessEvalBuiltIn::PointSize => ("point_size", true),
+      TessEvalBuiltIn::ClipDistance => ("clip_distance", true),
+      _ => return None,
+    },
+
+    (ShaderStage::Geometry, BuiltIn::Geometry(builtin)) => match builtin {
+      GeometryBuiltIn::PrimitiveIDIn => ("primitive_id_in", false),
+      GeometryBuiltIn::InvocationID => ("invocation_id", false),
+      GeometryBuiltIn::In => ("input", false),
+      GeometryBuiltIn::Position => ("position", true),
+      GeometryBuiltIn::PointSize => ("point_size", true),
+      GeometryBuiltIn::ClipDistance => ("clip_distance", true),
+      GeometryBuiltIn::CullDistance => ("cull_distance", true),
+      GeometryBuiltIn::PrimitiveID => ("primitive_id", true),
+      GeometryBuiltIn::Layer => ("layer", true),
+      GeometryBuiltIn::ViewportIndex => ("viewport_index", true),
+      _ => return None,
+    },
+
+    (ShaderStage::Fragment, BuiltIn::Fragment(builtin)) => match builtin {
+      FragmentBuiltIn::FragCoord => ("frag_coord", false),
+      FragmentBuiltIn::FrontFacing => ("front_facing", false),
+      FragmentBuiltIn::PointCoord => ("point_coord", false),
+      FragmentBuiltIn::SampleID => ("sample_id", false),
+      FragmentBuiltIn::SamplePosition => ("sample_position", false),
+      FragmentBuiltIn::SampleMaskIn => ("sample_mask_in", false),
+      FragmentBuiltIn::ClipDistance => ("clip_distance", false),
+      FragmentBuiltIn::CullDistance => ("cull_distance", false),
+      FragmentBuiltIn::PrimitiveID => ("primitive_id", false),
+      FragmentBuiltIn::Layer => ("layer", false),
+      FragmentBuiltIn::ViewportIndex => ("viewport_index", false),
+      FragmentBuiltIn::HelperInvocation => ("helper_invocation", false),
+      FragmentBuiltIn::FragDepth => ("frag_depth", true),
+      FragmentBuiltIn::SampleMask => ("sample_mask", true),
+    },
+
+    _ => return None,
+  };
+
+  Some(field)
+}
+
+/// Method reading a built-in from a vertex of the per-vertex arrays of a stage.
+fn per_vertex_method(stage: ShaderStage, builtin: &BuiltIn) -> Option<&'static str> {
+  match (stage, builtin) {
+    (ShaderStage::TessCtrl, BuiltIn::TessCtrl(builtin)) => match builtin {
+      TessCtrlBuiltIn::Position => Some("position"),
+      TessCtrlBuiltIn::PointSize => Some("point_size"),
+      TessCtrlBuiltIn::ClipDistance => Some("clip_distance"),
+      TessCtrlBuiltIn::CullDistance => Some("cull_distance"),
+      _ => None,
+    },
+
+    (ShaderStage::TessEval, BuiltIn::TessEval(builtin)) => match builtin {
+      TessEvalBuiltIn::Position => Some("position"),
+      TessEvalBuiltIn::PointSize => Some("point_size"),
+      TessEvalBuiltIn::ClipDistance => Some("clip_distance"),
+      TessEvalBuiltIn::CullDistance => Some("cull_distance"),
+      _ => None,
+    },
+
+    (ShaderStage::Geometry, BuiltIn::Geometry(builtin)) => match builtin {
+      GeometryBuiltIn::Position => Some("position"),
+      GeometryBuiltIn::PointSize => Some("point_size"),
+      GeometryBuiltIn::ClipDistance => Some("clip_distance"),
+      GeometryBuiltIn::CullDistance => Some("cull_distance"),
+      _ => None,
+    },
+
+    _ => None,
+  }
+}
+
+/// Check whether a name can be used as a Rust identifier as-is.
+fn is_valid_identifier(name: &str) -> bool {
+  let mut chars = name.chars();
+  let starts_well = matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_');
+
+  starts_well
+    && name != "_"
+    && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+    && !KEYWORDS.contains(&name)
+}
+
+/// Convert a GLSL name to snake case, as Rust bindings are, so that `baseColor` becomes `base_color` and `K` becomes
+/// `k`.
+fn snake_case(name: &str) -> String {
+  let chars: Vec<char> = name.chars().collect();
+  let mut snake = String::with_capacity(name.len());
+
+  for (i, &c) in chars.iter().enumerate() {
+    if c.is_ascii_uppercase() && i > 0 {
+      let prev = chars[i - 1];
+      let next_is_lower = chars.get(i + 1).is_some_and(char::is_ascii_lowercase);
+
+      if prev.is_ascii_lowercase()
+        || prev.is_ascii_digit()
+        || (prev.is_ascii_uppercase() && next_is_lower)
+      {
+        snake.push('_');
+      }
+    }
+
+    snake.push(c.to_ascii_lowercase());
+  }
+
+  snake
+}
+
+/// Rust type of a [`Type`], if the EDSL has one.
+fn rust_type(ty: &Type) -> Option<String> {
+  fn scalar(prim_ty: &PrimType) -> Option<(&'static str, &Dim)> {
+    match prim_ty {
+      PrimType::Int(dim) => Some(("i32", dim)),
+      PrimType::UInt(dim) => Some(("u32", dim)),
+      PrimType::Float(dim) => Some(("f32", dim)),
+      PrimType::Bool(dim) => Some(("bool", dim)),
+      _ => None,
+    }
+  }
+
+  let mut name = match &ty.prim_ty {
+    PrimType::Matrix(MatrixDim::D22) => "M22".to_owned(),
+    PrimType::Matrix(MatrixDim::D33) => "M33".to_owned(),
+    PrimType::Matrix(MatrixDim::D44) => "M44".to_owned(),
+    prim_ty => match scalar(prim_ty)? {
+      (scalar, Dim::Scalar) => scalar.to_owned(),
+      (scalar, Dim::D2) => format!("V2<{}>", scalar),
+      (scalar, Dim::D3) => format!("V3<{}>", scalar),
+      (scalar, Dim::D4) => format!("V4<{}>", scalar),
+    },
+  };
+
+  for (i, &dim) in ty.array_dims.iter().enumerate().rev() {
+    name = match dim {
+      0 if i == 0 => format!("[{}]", name),
+      0 => return None,
+      _ => format!("[{}; {}]", name, dim),
+    };
+  }
+
+  Some(name)
+}
+
+/// Name of a function suffixed with the types of its arguments, such as `largest_v3_f32`.
+fn mangle(name: &str, args: &[Type]) -> String {
+  let mut mangled = name.to_owned();
+
+  for arg in args {
+    let ty = rust_type(arg).unwrap_or_default().to_lowercase();
+    for part in ty
+      .split(|c: char| !c.is_ascii_alphanumeric())
+      .filter(|part| !part.is_empty())
+    {
+      mangled.push('_');
+      mangled.push_str(part);
+    }
+  }
+
+  mangled
+}
+
+/// Describe a type in error messages.
+fn describe(ty: &Option<Type>) -> String {
+  match ty {
+    Some(ty) => rust_type(ty).unwrap_or_else(|| format!("{:?}", ty)),
+    None => "_".to_owned(),
+  }
+}
+
+/// Scalar type and dimension of a type without array dimensions.
+fn components(ty: &Type) -> Option<(Scalar, usize)> {
+  if !ty.array_dims.is_empty() {
+    return None;
+  }
+
+  let (scalar, dim) = match &ty.prim_ty {
+    PrimType::Int(dim) => (Scalar::Int, dim),
+    PrimType::UInt(dim) => (Scalar::UInt, dim),
+    PrimType::Float(dim) => (Scalar::Float, dim),
+    PrimType::Bool(dim) => (Scalar::Bool, dim),
+    _ => return None,
+  };
+
+  let len = match dim {
+    Dim::Scalar => 1,
+    Dim::D2 => 2,
+    Dim::D3 => 3,
+    Dim::D4 => 4,
+  };
+
+  Some((scalar, len))
+}
+
+/// Size of a square matrix type.
+fn matrix_size(ty: &Type) -> Option<usize> {
+  match (&ty.prim_ty, ty.array_dims.is_empty()) {
+    (PrimType::Matrix(MatrixDim::D22), true) => Some(2),
+    (PrimType::Matrix(MatrixDim::D33), true) => Some(3),
+    (PrimType::Matrix(MatrixDim::D44), true) => Some(4),
+    _ => None,
+  }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Scalar {
+  Int,
+  UInt,
+  Float,
+  Bool,
+}
+
+/// Scalar types a method or an operator is defined for.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Family {
+  /// `f32` and its vectors.
+  Float,
+
+  /// `i32`, `f32` and their vectors.
+  Signed,
+
+  /// `i32`, `u32`, `f32` and their vectors.
+  Numeric,
+
+  /// Any scalar type and its vectors.
+  Any,
+
+  /// `bool` and its vectors.
+  Bool,
+
+  /// Vectors of `f32`.
+  FloatVector,
+}
+
+impl Family {
+  fn contains(self, ty: &Type) -> bool {
+    let (scalar, len) = match components(ty) {
+      Some(components) => components,
+      None => return false,
+    };
+
+    match self {
+      Family::Float => scalar == Scalar::Float,
+      Family::Signed => matches!(scalar, Scalar::Int | Scalar::Float),
+      Family::Numeric => scalar != Scalar::Bool,
+      Family::Any => true,
+      Family::Bool => scalar == Scalar::Bool,
+      Family::FloatVector => scalar == Scalar::Float && len > 1,
+    }
+  }
+}
+
+/// Role of an argument of a built-in method.
+#[derive(Clone, Copy, Debug)]
+enum Param {
+  /// Same type as the receiver, taken as `impl Into<Self>`.
+  Same,
+
+  /// `f32` or the type of the receiver, taken as an owned [`Expr`](crate::Expr).
+  Edge,
+
+  /// `f32`, taken as `impl Into<Expr<f32>>`.
+  Float,
+}
+
+/// Built-in function written as a method: trait, method, scalar types, receiver argument and other arguments.
+type Method = (
+  &'static str,
+  &'static str,
+  Family,
+  usize,
+  &'static [(usize, Param)],
+);
+
+fn method(handle: &ErasedFunHandle) -> Option<Method> {
+  use ErasedFunHandle as H;
+
+  let (trait_name, name, family) = match handle {
+    H::Radians => ("Trigonometry", "radians", Family::Float),
+    H::Degrees => ("Trigonometry", "degrees", Family::Float),
+    H::Sin => ("Trigonometry", "sin", Family::Float),
+    H::Cos => ("Trigonometry", "cos", Family::Float),
+    H::Tan => ("Trigonometry", "tan", Family::Float),
+    H::ASin => ("Trigonometry", "asin", Family::Float),
+    H::ACos => ("Trigonometry", "acos", Family::Float),
+    H::ATan => ("Trigonometry", "atan", Family::Float),
+    H::SinH => ("Trigonometry", "sinh", Family::Float),
+    H::CosH => ("Trigonometry", "cosh", Family::Float),
+    H::TanH => ("Trigonometry", "tanh", Family::Float),
+    H::ASinH => ("Trigonometry", "asinh", Family::Float),
+    H::ACosH => ("Trigonometry", "acosh", Family::Float),
+    H::ATanH => ("Trigonometry", "atanh", Family::Float),
+    H::Pow => ("Exponential", "pow", Family::Float),
+    H::Exp => ("Exponential", "exp", Family::Float),
+    H::Exp2 => ("Exponential", "exp2", Family::Float),
+    H::Log => ("Exponential", "log", Family::Float),
+    H::Log2 => ("Exponential", "log2", Family::Float),
+    H::Sqrt => ("Exponential", "sqrt", Family::Float),
+    H::InverseSqrt => ("Exponential", "isqrt", Family::Float),
+    H::Abs => ("Relative", "abs", Family::Signed),
+    H::Sign => ("Relative", "sign", Family::Signed),
+    H::Floor => ("Floating", "floor", Family::Float),
+    H::Trunc => ("Floating", "trunc", Family::Float),
+    H::Round => ("Floating", "round", Family::Float),
+    H::Ceil => ("Floating", "ceil", Family::Float),
+    H::Fract => ("Floating", "fract", Family::Float),
+    H::Min => ("Bounded", "min", Family::Any),
+    H::Max => ("Bounded", "max", Family::Any),
+    H::Clamp => ("Bounded", "clamp", Family::Any),
+    H::Mix => ("Mix", "mix", Family::Float),
+    H::Step => ("Mix", "step", Family::Float),
+    H::SmoothStep => ("Mix", "smooth_step", Family::Float),
+    H::IsNan => ("FloatingExt", "is_nan", Family::Float),
+    H::IsInf => ("FloatingExt", "is_inf", Family::Float),
+    H::Length => ("Geometry", "length", Family::FloatVector),
+    H::Distance => ("Geometry", "distance", Family::FloatVector),
+    H::Dot => ("Geometry", "dot", Family::FloatVector),
+    H::Cross => ("Geometry", "cross", Family::FloatVector),
+    H::Normalize => ("Geometry", "normalize", Family::FloatVector),
+    H::FaceForward => ("Geometry", "face_forward", Family::FloatVector),
+    H::Reflect => ("Geometry", "reflect", Family::FloatVector),
+    H::Refract => ("Geometry", "refract", Family::FloatVector),
+    _ => return None,
+  };
+
+  let (receiver, params): (usize, &'static [(usize, Param)]) = match handle {
+    H::Pow | H::Min | H::Max | H::Distance | H::Dot | H::Cross | H::Reflect => {
+      (0, &[(1, Param::Same)])
+    }
+    H::Clamp => (0, &[(1, Param::Same), (2, Param::Same)]),
+    H::Mix => (0, &[(1, Param::Same), (2, Param::Edge)]),
+    H::Step => (0, &[(1, Param::Edge)]),
+    H::SmoothStep => (0, &[(1, Param::Edge), (2, Param::Edge)]),
+    H::Refract => (0, &[(1, Param::Same), (2, Param::Float)]),
+    // the normal comes first in the arguments of the built-in, but is the first argument of the method
+    H::FaceForward => (1, &[(0, Param::Same), (2, Param::Same)]),
+    _ => (0, &[]),
+  };
+
+  Some((trait_name, name, family, receiver, params))
+}
+
+/// Rust expression for a float.
+fn float(x: f32) -> String {
+  if x.is_nan() {
+    "f32::NAN".to_owned()
+  } else if x == f32::INFINITY {
+    "f32::INFINITY".to_owned()
+  } else if x == f32::NEG_INFINITY {
+    "f32::NEG_INFINITY".to_owned()
+  } else {
+    format!("{:?}", x)
+  }
+}
+
+/// How a piece of code can be used where an expression is expected.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Kind {
+  /// An identifier or a field bound to an [`Expr`](crate::Expr) or a [`Var`](crate::Var).
+  Binding,
+
+  /// An identifier bound to a `&Expr`, such as the variable of a loop.
+  Borrowed,
+
+  /// A bare scalar literal.
+  Literal,
+
+  /// A prefix operator.
+  Prefix,
+
+  /// A binary operator, by precedence.
+  Binary(u8),
+
+  /// A method call, a macro invocation or anything binding as tightly.
+  Postfix,
+}
+
+/// A piece of Rust code evaluating to an expression.
+#[derive(Clone, Debug)]
+struct Code {
+  text: String,
+  kind: Kind,
+}
+
+impl Code {
+  fn new(text: impl Into<String>, kind: Kind) -> Self {
+    Code {
+      text: text.into(),
+      kind,
+    }
+  }
+}
+
+/// Kind of scope instructions are written in, which restricts the statements available.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum ScopeKind {
+  /// The top-level scope of a function, a [`Scope`](crate::Scope).
+  Fun,
+
+  /// An [`EscapeScope`](crate::EscapeScope).
+  Escape,
+
+  /// A [`LoopScope`](crate::LoopScope).
+  Loop,
+}
+
+struct Writer<'a> {
+  stage: ShaderStage,
+  decls: &'a [ShaderDecl],
+  // top-level types
+  types: TypeEnv,
+  imports: BTreeSet<&'static str>,
+  // whether the environment of the stage is used
+  env_used: bool,
+  // identifiers of the top-level declarations
+  top_idents: HashSet<String>,
+  funs: HashMap<u16, String>,
+  constants: HashMap<u16, String>,
+  interface: HashMap<String, String>,
+  // top-level declarations referred to, and functions called
+  used: HashSet<ScopedHandle>,
+  called: HashSet<u16>,
+  // state of the declaration being written
+  declaration: Declaration,
+  fun_types: TypeEnv,
+  args: Vec<String>,
+  arg_qualifiers: Vec<ArgQualifier>,
+  vars: HashMap<(u16, u16), Code>,
+  idents: HashSet<String>,
+  // returned expression of the function being written, which can refer to its top-level variables
+  ret: Option<&'a ErasedExpr>,
+}
+
+impl<'a> Writer<'a> {
+  fn new(decls: &'a [ShaderDecl], stage: ShaderStage) -> Self {
+    let types = TypeEnv::new(decls);
+    let mut used = HashSet::new();
+    let mut called = HashSet::new();
+
+    for decl in decls {
+      let exprs = match decl {
+        ShaderDecl::Main(fun) | ShaderDecl::FunDef(_, fun) => optimizer::fun_exprs(fun),
+        ShaderDecl::Const(_, _, expr, _) => vec![expr],
+        _ => Vec::new(),
+      };
+
+      for expr in exprs {
+        optimizer::walk_expr(expr, &mut |e| match e {
+          ErasedExpr::Var(handle) => {
+            used.insert(handle.clone());
+          }
+
+          ErasedExpr::FunCall(ErasedFunHandle::UserDefined(handle), _) => {
+            called.insert(*handle);
+          }
+
+          _ => (),
+        });
+      }
+    }
+
+    let mut top_idents: HashSet<String> = IMPORTABLE.iter().map(|&name| name.to_owned()).collect();
+    top_idents.insert("s".to_owned());
+    top_idents.insert(env_ident(stage).to_owned());
+
+    Writer {
+      stage,
+      decls,
+      fun_types: types.clone(),
+      types,
+      imports: BTreeSet::new(),
+      env_used: false,
+      top_idents,
+      funs: HashMap::new(),
+      constants: HashMap::new(),
+      interface: HashMap::new(),
+      used,
+      called,
+      declaration: Declaration::Main,
+      args: Vec::new(),
+      arg_qualifiers: Vec::new(),
+      vars: HashMap::new(),
+      idents: HashSet::new(),
+      ret: None,
+    }
+  }
+
+  /// Imports, macros first, as rustfmt sorts them.
+  fn imports(&self) -> Vec<&'static str> {
+    let mut imports: Vec<_> = self.imports.iter().copied().collect();
+    imports.sort_by_key(|import| (import.starts_with(char::is_uppercase), *import));
+    imports
+  }
+
+  fn import(&mut self, name: &'static str) {
+    self.imports.insert(name);
+  }
+
+  fn unsupported(&self, construct: Construct) -> WriteError {
+    WriteError::Unsupported {
+      declaration: self.declaration.clone(),
+      construct,
+    }
+  }
+
+  fn unsupported_expr(&self, expr: impl Into<String>) -> WriteError {
+    self.unsupported(Construct::Expression(expr.into()))
+  }
+
+  fn unsupported_statement(&self, statement: impl Into<String>) -> WriteError {
+    self.unsupported(Construct::Statement(statement.into()))
+  }
+
+  /// Pick a fresh identifier among `idents`, from the snake case of a name if it is a valid identifier or from a
+  /// generated one.
+  fn fresh(idents: &mut HashSet<String>, name: Option<&str>, generated: String) -> String {
+    let mut ident = match name.map(snake_case) {
+      Some(name) if is_valid_identifier(&name) => name,
+      _ => generated,
+    };
+
+    while idents.contains(&ident) {
+      ident.push('_');
+    }
+
+    idents.insert(ident.clone());
+    ident
+  }
+
+  /// Rust type of a [`Type`], importing what it needs.
+  fn type_name(&mut self, ty: &Type) -> Result<String, WriteError> {
+    let name = rust_type(ty).ok_or_else(|| self.unsupported(Construct::Type(ty.clone())))?;
+
+    for import in ["V2", "V3", "V4", "M22", "M33", "M44"] {
+      if name.contains(import) {
+        self.import(import);
+      }
+    }
+
+    Ok(name)
+  }
+
+  fn type_of(&self, expr: &ErasedExpr) -> Option<Type> {
+    self.fun_types.type_of(expr)
+  }
+
+  fn write_decls(&mut self) -> Result<String, WriteError> {
+    let decls = self.decls;
+    self.import("Scope");
+    self.import("Shader");
+    self.import("ShaderBuilder");
+
+    let (main, decls) = match decls.split_last() {
+      Some((ShaderDecl::Main(main), decls))
+        if !decls.iter().any(|decl| matches!(decl, ShaderDecl::Main(_))) =>
+      {
+        (main, decls)
+      }
+      _ => {
+        return Err(self.unsupported_statement("declaration after `main`"));
+      }
+    };
+
+    self.name_decls(decls);
+
+    // closures can only call the functions defined before them, which have no prototypes in the EDSL
+    let mut ordered = Vec::with_capacity(decls.len());
+    for decl in CallGraph::new(decls).ordered_decls() {
+      match decl {
+        OrderedDecl::Decl(decl) => ordered.push(decl),
+        OrderedDecl::Prototype(handle, _) => {
+          self.declaration = Declaration::Function(handle);
+          return Err(self.unsupported_statement("call before the definition"));
+        }
+      }
+    }
+    let decls = ordered.as_slice();
+
+    let mut items = Vec::new();
+    let mut i = 0;
+
+    while i < decls.len() {
+      let mut item = String::new();
+
+      match decls[i] {
+        ShaderDecl::Const(..) => {
+          while let Some(ShaderDecl::Const(handle, ty, expr, name)) = decls.get(i).copied() {
+            self.write_constant(&mut item, *handle, ty, expr, name.as_deref())?;
+            i += 1;
+          }
+        }
+
+        ShaderDecl::FunDef(handle, fun) => {
+          self.write_fun_def(&mut item, *handle, fun)?;
+          i += 1;
+        }
+
+        _ => {
+          let len = decls[i..]
+            .iter()
+            .take_while(|decl| interface_kind(decl) == interface_kind(decls[i]))
+            .count();
+          self.write_interface(&mut item, &decls[i..i + len])?;
+          i += len;
+        }
+      }
+
+      items.push(item);
+    }
+
+    let mut item = String::new();
+    self.write_main(&mut item, main)?;
+    items.push(item);
+
+    Ok(items.join("\n"))
+  }
+
+  /// Pick the identifiers of all the top-level declarations.
+  fn name_decls(&mut self, decls: &'a [ShaderDecl]) {
+    // functions sharing a name, such as the instances of a generic function, are told apart by their argument types
+    let mut fun_names = HashMap::new();
+    for decl in decls {
+      if let ShaderDecl::FunDef(
+        _,
+        ErasedFun {
+          name: Some(name), ..
+        },
+      ) = decl
+      {
+        *fun_names.entry(name.as_str()).or_insert(0) += 1;
+      }
+    }
+
+    for decl in decls {
+      match decl {
+        ShaderDecl::FunDef(handle, fun) => {
+          let name = match fun.name.as_deref() {
+            Some(name) if fun_names[name] > 1 => Some(mangle(name, &fun.args)),
+            name => name.map(str::to_owned),
+          };
+          let ident = Self::fresh(
+            &mut self.top_idents,
+            name.as_deref(),
+            format!("fun_{}", handle),
+          );
+          self.funs.insert(*handle, ident);
+        }
+
+        ShaderDecl::Const(handle, _, _, name) => {
+          let ident = Self::fresh(
+            &mut self.top_idents,
+            name.as_deref(),
+            format!("glob_{}", handle),
+          );
+          self.constants.insert(*handle, ident);
+        }
+
+        ShaderDecl::In(name, _) | ShaderDecl::Out(name, _, _) | ShaderDecl::Uniform(name, _) => {
+          let ident = Self::fresh(&mut self.top_idents, Some(name), format!("{}_", name));
+          self.interface.insert(name.clone(), ident);
+        }
+
+        ShaderDecl::Main(_) => (),
+      }
+    }
+  }
+
+  fn write_interface(&mut self, f: &mut String, decls: &[&ShaderDecl]) -> Result<(), WriteError> {
+    // entries of the macro call being written
+    let mut entries = Vec::new();
+    let mut mac = "";
+
+    for decl in decls {
+      let (name, ty, attachment, handle) = match decl {
+        ShaderDecl::In(name, ty) => (name, ty, None, ScopedHandle::Input(name.clone())),
+        ShaderDecl::Out(name, ty, attachment) => {
+          (name, ty, *attachment, ScopedHandle::Output(name.clone()))
+        }
+        ShaderDecl::Uniform(name, ty) => (name, ty, None, ScopedHandle::Uniform(name.clone())),
+        _ => continue,
+      };
+
+      self.declaration = Declaration::Interface(name.clone());
+      let ty = self.type_name(ty)?;
+      let ident = self.interface[name].clone();
+      let attachment = attachment.map(|attachment| {
+        if attachment.index() == 0 {
+          attachment.location().to_string()
+        } else {
+          format!("({}, {})", attachment.location(), attachment.index())
+        }
+      });
+
+      let (method, macro_name) = match (decl, &attachment) {
+        (ShaderDecl::In(..), _) => ("input", "inputs"),
+        (ShaderDecl::Uniform(..), _) => ("uniform", "uniforms"),
+        (_, None) => ("output", "outputs"),
+        (_, Some(_)) => ("color_attachment", "color_attachments"),
+      };
+
+      if self.used.contains(&handle) && ident == *name {
+        mac = macro_name;
+        entries.push(match &attachment {
+          Some(attachment) => format!("{}: {} = {}", ident, ty, attachment),
+          None => format!("{}: {}", ident, ty),
+        });
+        continue;
+      }
+
+      self.write_macro(f, mac, &entries);
+      entries.clear();
+
+      let ident = if self.used.contains(&handle) {
+        ident.as_str()
+      } else {
+        "_"
+      };
+      let attachment = attachment
+        .map(|attachment| format!(", {}.into()", attachment))
+        .unwrap_or_default();
+
+      let _ = writeln!(
+        f,
+        "    let {} = s.{}::<{}>({:?}{}).unwrap();",
+        ident, method, ty, name, attachment
+      );
+    }
+
+    self.write_macro(f, mac, &entries);
+
+    Ok(())
+  }
+
+  /// Write a call to an interface macro, on a single line if it fits.
+  fn write_macro(&mut self, f: &mut String, mac: &'static str, entries: &[String]) {
+    if entries.is_empty() {
+      return;
+    }
+
+    self.import(mac);
+
+    let line = format!("    {}!(s, {});", mac, entries.join(", "));
+
+    if line.len() <= 100 {
+      f.push_str(&line);
+      f.push('\n');
+    } else {
+      let _ = writeln!(f, "    {}!(s,", mac);
+
+      for (i, entry) in entries.iter().enumerate() {
+        let sep = if i + 1 < entries.len() { "," } else { "" };
+        let _ = writeln!(f, "      {}{}", entry, sep);
+      }
+
+      f.push_str("    );\n");
+    }
+  }
+
+  fn write_constant(
+    &mut self,
+    f: &mut String,
+    handle: u16,
+    ty: &Type,
+    expr: &ErasedExpr,
+    name: Option<&str>,
+  ) -> Result<(), WriteError> {
+    self.declaration = Declaration::Constant(handle);
+    self.fun_types = self.types.clone();
+    self.args.clear();
+    self.arg_qualifiers.clear();
+    self.vars.clear();
+
+    let code = self.expr(expr)?;
+    self.check_type(ty, expr)?;
+    let value = self.arg(&code);
+
+    let ident = if self.used.contains(&ScopedHandle::Global(handle)) {
+      self.constants[&handle].as_str()
+    } else {
+      "_"
+    };
+
+    let _ = match name {
+      Some(name) => writeln!(
+        f,
+        "    let {} = s.constant_named({:?}, {});",
+        ident, name, value
+      ),
+      None => writeln!(f, "    let {} = s.constant({});", ident, value),
+    };
+
+    Ok(())
+  }
+
+  /// Start writing a function: reset the state of the declaration and pick the identifiers of its arguments.
+  fn start_fun(&mut self, declaration: Declaration, fun: &ErasedFun) {
+    self.declaration = declaration;
+    self.fun_types = self.types.with_args(&fun.args);
+    self.arg_qualifiers = fun.arg_qualifiers.clone();
+    self.vars.clear();
+    self.idents = self.top_idents.clone();
+
+    let exprs = optimizer::fun_exprs(fun);
+    self.args = (0..fun.args.len())
+      .map(|i| {
+        let handle = ScopedHandle::FunArg(i as u16);
+
+        if refers_to(&exprs, &handle) {
+          Self::fresh(
+            &mut self.idents,
+            fun.arg_names.get(i).map(String::as_str),
+            format!("arg_{}", i),
+          )
+        } else {
+          "_".to_owned()
+        }
+      })
+      .collect();
+  }
+
+  fn write_fun_def(
+    &mut self,
+    f: &mut String,
+    handle: u16,
+    fun: &'a ErasedFun,
+  ) -> Result<(), WriteError> {
+    self.start_fun(Declaration::Function(handle), fun);
+
+    let ret = match &fun.ret {
+      ErasedReturn::Void => "()".to_owned(),
+      ErasedReturn::Expr(ty, _) => {
+        self.import("Expr");
+        format!("Expr<{}>", self.type_name(ty)?)
+      }
+    };
+
+    let s = if fun.scope.instructions.is_empty() {
+      "_"
+    } else {
+      "s"
+    };
+    let mut params = format!("{}: &mut Scope<{}>", s, ret);
+
+    for ((ident, ty), qualifier) in self
+      .args
+      .clone()
+      .iter()
+      .zip(&fun.args)
+      .zip(&fun.arg_qualifiers)
+    {
+      let kind = match qualifier {
+        ArgQualifier::In => "Expr",
+        ArgQualifier::Out => "Out",
+        ArgQualifier::InOut => "InOut",
+      };
+      self.import(kind);
+      let _ = write!(params, ", {}: {}<{}>", ident, kind, self.type_name(ty)?);
+    }
+
+    let ident = if self.called.contains(&handle) {
+      self.funs[&handle].as_str()
+    } else {
+      "_"
+    };
+
+    let _ = match &fun.name {
+      Some(name) => {
+        let arg_names: Vec<_> = fun
+          .arg_names
+          .iter()
+          .map(|name| format!("{:?}", name))
+          .collect();
+        write!(
+          f,
+          "    let {} = s.fun_named({:?}, &[{}], |{}| ",
+          ident,
+          name,
+          arg_names.join(", "),
+          params
+        )
+      }
+
+      None if !fun.arg_names.is_empty() => {
+        return Err(self.unsupported_statement("argument names of an unnamed function"));
+      }
+
+      None => write!(f, "    let {} = s.fun(|{}| ", ident, params),
+    };
+
+    let ret = match &fun.ret {
+      ErasedReturn::Void => None,
+      ErasedReturn::Expr(_, expr) => Some(expr),
+    };
+
+    if fun.scope.instructions.is_empty() {
+      match ret {
+        Some(expr) => {
+          let code = self.expr(expr)?;
+          let tail = self.owned(&code);
+          f.push_str(&tail);
+        }
+        None => f.push_str("{}"),
+      }
+    } else {
+      f.push_str("{\n");
+      self.ret = ret;
+      self.write_scope(f, 3, &fun.scope, ScopeKind::Fun)?;
+      self.ret = None;
+
+      if let Some(expr) = ret {
+        let code = self.expr(expr)?;
+        let _ = writeln!(f, "      {}", self.owned(&code));
+      }
+
+      f.push_str("    }");
+    }
+
+    f.push_str(");\n");
+    Ok(())
+  }
+
+  fn write_main(&mut self, f: &mut String, fun: &ErasedFun) -> Result<(), WriteError> {
+    self.start_fun(Declaration::Main, fun);
+
+    if fun.scope.instructions.is_empty() {
+      f.push_str("    s.main_fun(|_: &mut Scope<()>| {})\n");
+    } else {
+      f.push_str("    s.main_fun(|s: &mut Scope<()>| {\n");
+      self.write_scope(f, 3, &fun.scope, ScopeKind::Fun)?;
+      f.push_str("    })\n");
+    }
+
+    Ok(())
+  }
+
+  fn write_scope(
+    &mut self,
+    f: &mut String,
+    depth: usize,
+    scope: &ErasedScope,
+    kind: ScopeKind,
+  ) -> Result<(), WriteError> {
+    let instructions = &scope.instructions;
+    let indent = "  ".repeat(depth);
+    let mut i = 0;
+
+    while i < instructions.len() {
+      let rest = &instructions[i + 1..];
+      f.push_str(&indent);
+
+      match &instructions[i] {
+        ScopeInstr::VarDecl {
+          ty,
+          handle,
+          init_value,
+        } => {
+          let code = self.expr(init_value)?;
+          self.check_type(ty, init_value)?;
+          let init = self.arg(&code);
+          self.fun_types.declare(handle, ty);
+
+          let (subscope, var) = match handle {
+            ScopedHandle::FunVar { subscope, handle } => (*subscope, *handle),
+            _ => return Err(self.unsupported_statement("declaration of a non-local variable")),
+          };
+          let name = scope.names.get(&var);
+
+          let mut exprs = optimizer::instrs_exprs(rest);
+          if kind == ScopeKind::Fun {
+            exprs.extend(self.ret);
+          }
+
+          let ident = if refers_to(&exprs, handle) {
+            let ident = Self::fresh(
+              &mut self.idents,
+              name.map(String::as_str),
+              format!("var_{}_{}", subscope, var),
+            );
+            self
+              .vars
+              .insert((subscope, var), Code::new(ident.clone(), Kind::Binding));
+            ident
+          } else {
+            "_".to_owned()
+          };
+
+          let _ = match name {
+            Some(name) => writeln!(f, "let {} = s.var_named({:?}, {});", ident, name, init),
+            None => writeln!(f, "let {} = s.var({});", ident, init),
+          };
+        }
+
+        ScopeInstr::Return(ret) => {
+          if kind == ScopeKind::Fun {
+            return Err(self.unsupported_statement("return at the top level of a function"));
+          }
+
+          match ret {
+            ErasedReturn::Void => f.push_str("s.abort();\n"),
+            ErasedReturn::Expr(_, expr) => {
+              let code = self.expr(expr)?;
+              let _ = writeln!(f, "s.leave({});", self.arg(&code));
+            }
+          }
+        }
+
+        ScopeInstr::Continue | ScopeInstr::Break => {
+          let (statement, call) = if let ScopeInstr::Continue = instructions[i] {
+            ("continue", "loop_continue")
+          } else {
+            ("break", "loop_break")
+          };
+
+          if kind != ScopeKind::Loop {
+            return Err(
+              self.unsupported_statement(format!("`{}` outside of a loop scope", statement)),
+            );
+          }
+
+          let _ = writeln!(f, "s.{}();", call);
+        }
+
+        ScopeInstr::If { condition, scope } => {
+          self.import("CanEscape as _");
+
+          let inner = if kind == ScopeKind::Loop {
+            ScopeKind::Loop
+          } else {
+            ScopeKind::Escape
+          };
+          let condition = self.expr(condition)?;
+          let _ = write!(f, "s.when({}, ", self.arg(&condition));
+          self.write_closure(f, depth, scope, inner)?;
+          f.push(')');
+
+          while let Some(instr) = instructions.get(i + 1) {
+            match instr {
+              ScopeInstr::ElseIf { condition, scope } => {
+                let condition = self.expr(condition)?;
+                let _ = write!(f, "\n{}.or_else({}, ", indent, self.arg(&condition));
+                self.write_closure(f, depth, scope, ScopeKind::Escape)?;
+                f.push(')');
+              }
+
+              ScopeInstr::Else { scope } => {
+                let _ = write!(f, "\n{}.or(", indent);
+                self.write_closure(f, depth, scope, ScopeKind::Escape)?;
+                f.push(')');
+              }
+
+              _ => break,
+            }
+
+            i += 1;
+
+            if let ScopeInstr::Else { .. } = instr {
+              break;
+            }
+          }
+
+          f.push_str(";\n");
+        }
+
+        ScopeInstr::ElseIf { .. } | ScopeInstr::Else { .. } => {
+          return Err(self.unsupported_statement("`else` without `if`"));
+        }
+
+        ScopeInstr::For {
+          init_ty,
+          init_handle,
+          init_expr,
+          condition,
+          post_expr,
+          scope,
+        } => {
+          let init = self.expr(init_expr)?;
+          self.check_type(init_ty, init_expr)?;
+          let init = self.arg(&init);
+
+          let (subscope, var) = match init_handle {
+            ScopedHandle::FunVar { subscope, handle } => (*subscope, *handle),
+            _ => return Err(self.unsupported_statement("loop over a non-local variable")),
+          };
+          let ident = Self::fresh(
+            &mut self.idents,
+            scope.names.get(&var).map(String::as_str),
+            format!("var_{}_{}", subscope, var),
+          );
+          self
+            .vars
+            .insert((subscope, var), Code::new(ident.clone(), Kind::Borrowed));
+          self.fun_types.declare(init_handle, init_ty);
+
+          let param = |exprs: &[&ErasedExpr]| {
+            if refers_to(exprs, init_handle) {
+              ident.as_str()
+            } else {
+              "_"
+            }
+          };
+          let condition_param = param(&[condition]);
+          let post_param = param(&[post_expr]);
+          let body_param = param(&optimizer::instrs_exprs(&scope.instructions));
+
+          let condition = self.expr(condition)?;
+          let post = self.expr(post_expr)?;
+          let s = if scope.instructions.is_empty() {
+            "_"
+          } else {
+            "s"
+          };
+
+          let _ = write!(
+            f,
+            "s.loop_for({}, |{}| {}, |{}| {}, |{}, {}| ",
+            init,
+            condition_param,
+            self.owned(&condition),
+            post_param,
+            self.owned(&post),
+            s,
+            body_param
+          );
+          self.write_block(f, depth, scope, ScopeKind::Loop)?;
+          f.push_str(");\n");
+        }
+
+        ScopeInstr::While { condition, scope } => {
+          let condition = self.expr(condition)?;
+          let _ = write!(f, "s.loop_while({}, ", self.arg(&condition));
+          self.write_closure(f, depth, scope, ScopeKind::Loop)?;
+          f.push_str(");\n");
+        }
+
+        ScopeInstr::MutateVar { var, expr } => {
+          let place = self.place(var)?;
+          let value = self.expr(expr)?;
+
+          if let (Some(var_ty), Some(expr_ty)) = (self.type_of(var), self.type_of(expr)) {
+            if var_ty != expr_ty {
+              return Err(self.unsupported_statement(format!(
+                "assignment of a `{}` to a `{}`",
+                describe(&Some(expr_ty)),
+                describe(&Some(var_ty))
+              )));
+            }
+          }
+
+          let _ = writeln!(f, "s.set({}, {});", place, self.arg(&value));
+        }
+
+        ScopeInstr::Discard => {
+          if self.stage != ShaderStage::Fragment {
+            return Err(self.unsupported_statement("`discard` outside of a fragment shader"));
+          }
+
+          self.env_used = true;
+          let _ = writeln!(f, "{}.discard(s);", env_ident(self.stage));
+        }
+      }
+
+      i += 1;
+    }
+
+    Ok(())
+  }
+
+  /// Write a closure taking a scope, such as `|s| { … }`.
+  fn write_closure(
+    &mut self,
+    f: &mut String,
+    depth: usize,
+    scope: &ErasedScope,
+    kind: ScopeKind,
+  ) -> Result<(), WriteError> {
+    f.push_str(if scope.instructions.is_empty() {
+      "|_| "
+    } else {
+      "|s| "
+    });
+    self.write_block(f, depth, scope, kind)
+  }
+
+  /// Write the block of a closure, ending on the line of its closing brace.
+  fn write_block(
+    &mut self,
+    f: &mut String,
+    depth: usize,
+    scope: &ErasedScope,
+    kind: ScopeKind,
+  ) -> Result<(), WriteError> {
+    if scope.instructions.is_empty() {
+      f.push_str("{}");
+    } else {
+      f.push_str("{\n");
+      self.write_scope(f, depth + 1, scope, kind)?;
+      f.push_str(&"  ".repeat(depth));
+      f.push('}');
+    }
+
+    Ok(())
+  }
+
+  fn check_type(&self, ty: &Type, expr: &ErasedExpr) -> Result<(), WriteError> {
+    match self.type_of(expr) {
+      Some(expr_ty) if expr_ty != *ty => Err(self.unsupported_expr(format!(
+        "`{}` used as `{}`",
+        describe(&Some(expr_ty)),
+        describe(&Some(ty.clone()))
+      ))),
+      _ => Ok(()),
+    }
+  }
+
+  /// Code passed where an owned [`Expr`](crate::Expr) is expected.
+  fn owned(&mut self, code: &Code) -> String {
+    match code.kind {
+      Kind::Binding | Kind::Borrowed => format!("{}.clone()", code.text),
+      Kind::Literal => self.lit(&code.text),
+      _ => code.text.clone(),
+    }
+  }
+
+  /// Code passed as an `impl Into<Expr<T>>` argument.
+  fn arg(&self, code: &Code) -> String {
+    match code.kind {
+      Kind::Binding => format!("&{}", code.text),
+      _ => code.text.clone(),
+    }
+  }
+
+  /// Code a method is called on.
+  fn receiver(&mut self, code: &Code) -> String {
+    match code.kind {
+      Kind::Literal => self.lit(&code.text),
+      Kind::Prefix | Kind::Binary(_) => format!("({})", code.text),
+      _ => code.text.clone(),
+    }
+  }
+
+  /// Code passed to a macro taking a reference to its arguments.
+  fn macro_arg(&mut self, code: &Code) -> String {
+    match code.kind {
+      Kind::Borrowed => format!("{}.clone()", code.text),
+      _ => code.text.clone(),
+    }
+  }
+
+  /// Code used as an operand of an operator of the given precedence.
+  fn operand(&mut self, code: &Code, prec: u8, rhs: bool) -> String {
+    match code.kind {
+      Kind::Binding => format!("&{}", code.text),
+      Kind::Literal if !rhs => self.lit(&code.text),
+      Kind::Binary(p) if p < prec || (rhs && p == prec) => format!("({})", code.text),
+      _ => code.text.clone(),
+    }
+  }
+
+  fn lit(&mut self, text: &str) -> String {
+    self.import("lit");
+    format!("lit!({})", text)
+  }
+
+  fn expr(&mut self, expr: &ErasedExpr) -> Result<Code, WriteError> {
+    let code = match expr {
+      ErasedExpr::LitInt(x) => Code::new(x.to_string(), Kind::Literal),
+      ErasedExpr::LitUInt(x) => Code::new(format!("{}u32", x), Kind::Literal),
+      ErasedExpr::LitFloat(x) => Code::new(float(*x), Kind::Literal),
+      ErasedExpr::LitBool(x) => Code::new(x.to_string(), Kind::Literal),
+      ErasedExpr::LitInt2(v) => self.lit_vector(v.iter().map(|x| x.to_string())),
+      ErasedExpr::LitUInt2(v) => self.lit_vector(v.iter().map(|x| format!("{}u32", x))),
+      ErasedExpr::LitFloat2(v) => self.lit_vector(v.iter().map(|&x| float(x))),
+      ErasedExpr::LitBool2(v) => self.lit_vector(v.iter().map(|x| x.to_string())),
+      ErasedExpr::LitInt3(v) => self.lit_vector(v.iter().map(|x| x.to_string())),
+      ErasedExpr::LitUInt3(v) => self.lit_vector(v.iter().map(|x| format!("{}u32", x))),
+      ErasedExpr::LitFloat3(v) => self.lit_vector(v.iter().map(|&x| float(x))),
+      ErasedExpr::LitBool3(v) => self.lit_vector(v.iter().map(|x| x.to_string())),
+      ErasedExpr::LitInt4(v) => self.lit_vector(v.iter().map(|x| x.to_string())),
+      ErasedExpr::LitUInt4(v) => self.lit_vector(v.iter().map(|x| format!("{}u32", x))),
+      ErasedExpr::LitFloat4(v) => self.lit_vector(v.iter().map(|&x| float(x))),
+      ErasedExpr::LitBool4(v) => self.lit_vector(v.iter().map(|x| x.to_string())),
+      ErasedExpr::LitM22(m) => self.lit_matrix("M22", m.0.iter().map(|col| col.as_slice())),
+      ErasedExpr::LitM33(m) => self.lit_matrix("M33", m.0.iter().map(|col| col.as_slice())),
+      ErasedExpr::LitM44(m) => self.lit_matrix("M44", m.0.iter().map(|col| col.as_slice())),
+
+      ErasedExpr::Array(ty, items) => {
+        if items.is_empty() || ty.array_dims.first() != Some(&items.len()) {
+          return Err(self.unsupported(Construct::Type(ty.clone())));
+        }
+
+        let items = items
+          .iter()
+          .map(|item| self.expr(item))
+          .collect::<Result<Vec<_>, _>>()?;
+
+        let items: Vec<_> = if items.iter().all(|item| item.kind == Kind::Literal) {
+          items.into_iter().map(|item| item.text).collect()
+        } else {
+          items.iter().map(|item| self.owned(item)).collect()
+        };
+
+        Code::new(self.lit(&format!("[{}]", items.join(", "))), Kind::Postfix)
+      }
+
+      ErasedExpr::Var(handle) => self.var(handle)?,
+
+      ErasedExpr::Not(a) => self.unary("!", Family::Bool, a)?,
+      ErasedExpr::Neg(a) => self.unary("-", Family::Numeric, a)?,
+
+      ErasedExpr::And(a, b) => self.logical("and", a, b)?,
+      ErasedExpr::Or(a, b) => self.logical("or", a, b)?,
+      ErasedExpr::Xor(a, b) => self.logical("xor", a, b)?,
+
+      ErasedExpr::BitOr(a, b) => self.binary("|", PREC_BIT_OR, a, b)?,
+      ErasedExpr::BitAnd(a, b) => self.binary("&", PREC_BIT_AND, a, b)?,
+      ErasedExpr::BitXor(a, b) => self.binary("^", PREC_BIT_XOR, a, b)?,
+      ErasedExpr::Add(a, b) => self.binary("+", PREC_ADD, a, b)?,
+      ErasedExpr::Sub(a, b) => self.binary("-", PREC_ADD, a, b)?,
+      ErasedExpr::Mul(a, b) => self.binary("*", PREC_MUL, a, b)?,
+      ErasedExpr::Div(a, b) => self.binary("/", PREC_MUL, a, b)?,
+      ErasedExpr::Rem(a, b) => self.binary("%", PREC_MUL, a, b)?,
+      ErasedExpr::Shl(a, b) => self.shift("<<", a, b)?,
+      ErasedExpr::Shr(a, b) => self.shift(">>", a, b)?,
+
+      ErasedExpr::Eq(a, b) => self.comparison("eq", Family::Any, true, a, b)?,
+      ErasedExpr::Neq(a, b) => self.comparison("neq", Family::Any, true, a, b)?,
+      ErasedExpr::Lt(a, b) => self.comparison("lt", Family::Any, false, a, b)?,
+      ErasedExpr::Lte(a, b) => self.comparison("lte", Family::Any, false, a, b)?,
+      ErasedExpr::Gt(a, b) => self.comparison("gt", Family::Any, false, a, b)?,
+      ErasedExpr::Gte(a, b) => self.comparison("gte", Family::Any, false, a, b)?,
+
+      ErasedExpr::FunCall(ErasedFunHandle::UserDefined(handle), args) => {
+        self.user_call(*handle, args)?
+      }
+
+      ErasedExpr::FunCall(handle, args) => self.builtin_call(handle, args)?,
+
+      ErasedExpr::Swizzle(a, sw) => {
+        let len = match sw {
+          Swizzle::D1(..) => 1,
+          Swizzle::D2(..) => 2,
+          Swizzle::D3(..) => 3,
+          Swizzle::D4(..) => 4,
+        };
+
+        let ty = self.type_of(a);
+        if let Some(ty) = &ty {
+          match components(ty) {
+            Some((_, dim)) if dim > 1 && len <= dim => (),
+            _ => {
+              return Err(
+                self.unsupported_expr(format!("swizzle of a `{}`", describe(&Some(ty.clone())))),
+              )
+            }
+          }
+        }
+
+        let selectors = match sw {
+          Swizzle::D1(a) => vec![a],
+          Swizzle::D2(a, b) => vec![a, b],
+          Swizzle::D3(a, b, c) => vec![a, b, c],
+          Swizzle::D4(a, b, c, d) => vec![a, b, c, d],
+        };
+        let selectors: String = selectors
+          .into_iter()
+          .map(|selector| match selector {
+            SwizzleSelector::X => ".x",
+            SwizzleSelector::Y => ".y",
+            SwizzleSelector::Z => ".z",
+            SwizzleSelector::W => ".w",
+          })
+          .collect();
+
+        self.import("sw");
+        self.import("Swizzlable as _");
+        let a = self.expr(a)?;
+        let a = match a.kind {
+          Kind::Literal => self.lit(&a.text),
+          _ => a.text,
+        };
+
+        Code::new(format!("sw!({}, {})", a, selectors), Kind::Postfix)
+      }
+
+      ErasedExpr::Field { object, field } => {
+        let method = match &**field {
+          ErasedExpr::Var(ScopedHandle::BuiltIn(builtin)) => per_vertex_method(self.stage, builtin),
+          _ => None,
+        }
+        .ok_or_else(|| self.unsupported_expr("field access"))?;
+
+        let object = self.expr(object)?;
+        Code::new(
+          format!("{}.{}()", self.receiver(&object), method),
+          Kind::Postfix,
+        )
+      }
+
+      ErasedExpr::ArrayLookup { object, index } => {
+        if let Some(ty) = self.type_of(object) {
+          if ty.array_dims.is_empty() {
+            return Err(self.unsupported_expr(format!("indexing a `{}`", describe(&Some(ty)))));
+          }
+        }
+
+        self.check_index(index)?;
+
+        let object = self.expr(object)?;
+        let index = self.expr(index)?;
+        Code::new(
+          format!("{}.at({})", self.receiver(&object), self.arg(&index)),
+          Kind::Postfix,
+        )
+      }
+    };
+
+    Ok(code)
+  }
+
+  fn check_index(&self, index: &ErasedExpr) -> Result<(), WriteError> {
+    match self.type_of(index) {
+      Some(ty) if components(&ty) != Some((Scalar::Int, 1)) => {
+        Err(self.unsupported_expr(format!("index of type `{}`", describe(&Some(ty)))))
+      }
+      _ => Ok(()),
+    }
+  }
+
+  fn lit_vector(&mut self, items: impl Iterator<Item = String>) -> Code {
+    let items: Vec<_> = items.collect();
+
+    if items.len() == 2 {
+      // lit! refers to V2 unqualified
+      self.import("V2");
+    }
+
+    Code::new(self.lit(&items.join(", ")), Kind::Postfix)
+  }
+
+  fn lit_matrix<'b>(&mut self, ty: &'static str, cols: impl Iterator<Item = &'b [f32]>) -> Code {
+    let cols: Vec<_> = cols
+      .map(|col| {
+        let col: Vec<_> = col.iter().map(|&x| float(x)).collect();
+        format!("[{}]", col.join(", "))
+      })
+      .collect();
+
+    self.import(ty);
+    Code::new(
+      self.lit(&format!("{}::from([{}])", ty, cols.join(", "))),
+      Kind::Postfix,
+    )
+  }
+
+  fn var(&mut self, handle: &ScopedHandle) -> Result<Code, WriteError> {
+    let code = match handle {
+      ScopedHandle::BuiltIn(builtin) => {
+        let (field, _) = env_field(self.stage, builtin)
+          .ok_or_else(|| self.unsupported(Construct::BuiltIn(*builtin)))?;
+        self.env_used = true;
+        Code::new(
+          format!("{}.{}", env_ident(self.stage), field),
+          Kind::Binding,
+        )
+      }
+
+      ScopedHandle::Global(handle) => self
+        .constants
+        .get(handle)
+        .map(|ident| Code::new(ident.clone(), Kind::Binding))
+        .ok_or_else(|| self.unsupported_expr("undeclared constant"))?,
+
+      ScopedHandle::LibraryGlobal(..) => {
+        return Err(self.unsupported_expr("constant of a library that is not imported"))
+      }
+
+      // arguments passed by reference don’t have the operators of expressions, and out ones are write-only
+      ScopedHandle::FunArg(arg) => match (
+        self.args.get(*arg as usize),
+        self.arg_qualifiers.get(*arg as usize),
+      ) {
+        (Some(ident), Some(ArgQualifier::In)) => Code::new(ident.clone(), Kind::Binding),
+        (Some(ident), Some(ArgQualifier::InOut)) => {
+          Code::new(format!("{}.to_expr()", ident), Kind::Postfix)
+        }
+        (Some(_), _) => return Err(self.unsupported_expr("read of an out argument")),
+        (None, _) => return Err(self.unsupported_expr("undeclared argument")),
+      },
+
+      ScopedHandle::FunVar { subscope, handle } => self
+        .vars
+        .get(&(*subscope, *handle))
+        .cloned()
+        .ok_or_else(|| self.unsupported_expr("undeclared variable"))?,
+
+      ScopedHandle::Input(name) | ScopedHandle::Uniform(name) => {
+        Code::new(self.interface_ident(name)?, Kind::Binding)
+      }
+
+      // outputs are write-only
+      ScopedHandle::Output(_) => return Err(self.unsupported_expr("read of an output")),
+    };
+
+    Ok(code)
+  }
+
+  fn interface_ident(&self, name: &str) -> Result<String, WriteError> {
+    self
+      .interface
+      .get(name)
+      .cloned()
+      .ok_or_else(|| self.unsupported_expr("undeclared interface"))
+  }
+
+  /// Code of a variable passed as the `impl Assignable<T>` of [`Scope::set`](crate::Scope::set).
+  fn place(&mut self, expr: &ErasedExpr) -> Result<String, WriteError> {
+    match expr {
+      ErasedExpr::Var(handle) => {
+        let writable = match handle {
+          ScopedHandle::BuiltIn(builtin) => {
+            env_field(self.stage, builtin)
+              .ok_or_else(|| self.unsupported(Construct::BuiltIn(*builtin)))?
+              .1
+          }
+          ScopedHandle::Output(_) => true,
+          ScopedHandle::FunArg(arg) => {
+            self.arg_qualifiers.get(*arg as usize) != Some(&ArgQualifier::In)
+          }
+          ScopedHandle::FunVar { subscope, handle } => match self.vars.get(&(*subscope, *handle)) {
+            Some(code) if code.kind == Kind::Borrowed => {
+              return Err(self.unsupported_statement("assignment to a loop variable"));
+            }
+            _ => true,
+          },
+          _ => false,
+        };
+
+        if !writable {
+          return Err(self.unsupported_statement("assignment to a read-only value"));
+        }
+
+        let ident = match handle {
+          ScopedHandle::FunArg(arg) => self.args[*arg as usize].clone(),
+          ScopedHandle::Output(name) => self.interface_ident(name)?,
+          _ => self.var(handle)?.text,
+        };
+        Ok(format!("&{}", ident))
+      }
+
+      ErasedExpr::ArrayLookup { object, index } => {
+        self.check_index(index)?;
+
+        let object = self.place(object)?;
+        let index = self.expr(index)?;
+        Ok(format!(
+          "{}.at({})",
+          object.trim_start_matches('&'),
+          self.arg(&index)
+        ))
+      }
+
+      ErasedExpr::Field { object, field } => {
+        let method = match &**field {
+          ErasedExpr::Var(ScopedHandle::BuiltIn(builtin @ BuiltIn::TessCtrl(_))) => {
+            per_vertex_method(self.stage, builtin)
+          }
+          _ => None,
+        }
+        .ok_or_else(|| self.unsupported_statement("assignment to a read-only value"))?;
+
+        let object = self.place(object)?;
+        Ok(format!("{}.{}()", object.trim_start_matches('&'), method))
+      }
+
+      ErasedExpr::Swizzle(..) => Err(self.unsupported_statement("assignment to a swizzle")),
+
+      _ => Err(self.unsupported_statement("assignment to an expression")),
+    }
+  }
+
+  fn unary(&mut self, op: &str, family: Family, a: &ErasedExpr) -> Result<Code, WriteError> {
+    if let Some(ty) = self.type_of(a) {
+      if !family.contains(&ty) {
+        return Err(self.unsupported_expr(format!(
+          "operator `{}` on `{}`",
+          op,
+          describe(&Some(ty))
+        )));
+      }
+    }
+
+    let a = self.expr(a)?;
+    let a = match a.kind {
+      Kind::Binding => format!("&{}", a.text),
+      Kind::Literal => self.lit(&a.text),
+      Kind::Binary(_) => format!("({})", a.text),
+      _ => a.text,
+    };
+
+    Ok(Code::new(format!("{}{}", op, a), Kind::Prefix))
+  }
+
+  fn binary(
+    &mut self,
+    op: &str,
+    prec: u8,
+    a: &ErasedExpr,
+    b: &ErasedExpr,
+  ) -> Result<Code, WriteError> {
+    let (a_ty, b_ty) = (self.type_of(a), self.type_of(b));
+
+    if let (Some(a_ty), Some(b_ty)) = (&a_ty, &b_ty) {
+      let family = match op {
+        "|" | "&" | "^" => Family::Bool,
+        "%" => Family::Float,
+        _ => Family::Numeric,
+      };
+
+      let componentwise = match (components(a_ty), components(b_ty)) {
+        (Some((a_scalar, a_len)), Some((b_scalar, b_len))) => {
+          family.contains(a_ty) && a_scalar == b_scalar && (a_len == b_len || b_len == 1)
+        }
+        _ => false,
+      };
+
+      let matrix = op == "*"
+        && match (matrix_size(a_ty), matrix_size(b_ty)) {
+          (Some(a_size), Some(b_size)) => a_size == b_size,
+          (Some(size), None) => components(b_ty) == Some((Scalar::Float, size)),
+          (None, Some(size)) => components(a_ty) == Some((Scalar::Float, size)),
+          (None, None) => false,
+        };
+
+      if !componentwise && !matrix {
+        return Err(self.unsupported_expr(format!(
+          "operator `{}` on `{}` and `{}`",
+          op,
+          describe(&Some(a_ty.clone())),
+          describe(&Some(b_ty.clone()))
+        )));
+      }
+    }
+
+    let a = self.expr(a)?;
+    let b = self.expr(b)?;
+    let text = format!(
+      "{} {} {}",
+      self.operand(&a, prec, false),
+      op,
+      self.operand(&b, prec, true)
+    );
+
+    Ok(Code::new(text, Kind::Binary(prec)))
+  }
+
+  fn shift(&mut self, op: &str, a: &ErasedExpr, b: &ErasedExpr) -> Result<Code, WriteError> {
+    let (a_ty, b_ty) = (self.type_of(a), self.type_of(b));
+
+    if let (Some(a_ty), Some(b_ty)) = (&a_ty, &b_ty) {
+      if !Family::Numeric.contains(a_ty) || components(b_ty) != Some((Scalar::UInt, 1)) {
+        return Err(self.unsupported_expr(format!(
+          "operator `{}` on `{}` and `{}`",
+          op,
+          describe(&Some(a_ty.clone())),
+          describe(&Some(b_ty.clone()))
+        )));
+      }
+    }
+
+    // shifts are only defined on expressions, not on variables
+    let a = self.expr(a)?;
+    let a = match a.kind {
+      Kind::Binding => format!("{}.clone()", a.text),
+      _ => self.operand(&a, PREC_SHIFT, false),
+    };
+    let b = self.expr(b)?;
+    let b = match b.kind {
+      Kind::Binding => format!("{}.clone()", b.text),
+      _ => self.operand(&b, PREC_SHIFT, true),
+    };
+
+    Ok(Code::new(
+      format!("{} {} {}", a, op, b),
+      Kind::Binary(PREC_SHIFT),
+    ))
+  }
+
+  fn logical(&mut self, method: &str, a: &ErasedExpr, b: &ErasedExpr) -> Result<Code, WriteError> {
+    for ty in [self.type_of(a), self.type_of(b)].iter().flatten() {
+      if components(ty) != Some((Scalar::Bool, 1)) {
+        return Err(self.unsupported_expr(format!(
+          "method `{}` on `{}`",
+          method,
+          describe(&Some(ty.clone()))
+        )));
+      }
+    }
+
+    self.method_call(method, a, &[b])
+  }
+
+  fn comparison(
+    &mut self,
+    method: &str,
+    family: Family,
+    vectors: bool,
+    a: &ErasedExpr,
+    b: &ErasedExpr,
+  ) -> Result<Code, WriteError> {
+    let (a_ty, b_ty) = (self.type_of(a), self.type_of(b));
+
+    if let (Some(a_ty), Some(b_ty)) = (&a_ty, &b_ty) {
+      let ordered = vectors || matches!(components(a_ty), Some((_, 1)) if family.contains(a_ty));
+
+      if a_ty != b_ty || !ordered {
+        return Err(self.unsupported_expr(format!(
+          "method `{}` on `{}` and `{}`",
+          method,
+          describe(&Some(a_ty.clone())),
+          describe(&Some(b_ty.clone()))
+        )));
+      }
+    }
+
+    self.method_call(method, a, &[b])
+  }
+
+  /// Call of a method taking `impl Into<Expr<T>>` arguments.
+  fn method_call(
+    &mut self,
+    method: &str,
+    receiver: &ErasedExpr,
+    args: &[&ErasedExpr],
+  ) -> Result<Code, WriteError> {
+    let receiver = self.expr(receiver)?;
+    let receiver = self.receiver(&receiver);
+    let args = args
+      .iter()
+      .map(|arg| {
+        let code = self.expr(arg)?;
+        Ok(self.arg(&code))
+      })
+      .collect::<Result<Vec<_>, WriteError>>()?;
+
+    Ok(Code::new(
+      format!("{}.{}({})", receiver, method, args.join(", ")),
+      Kind::Postfix,
+    ))
+  }
+
+  fn user_call(&mut self, handle: u16, args: &[Arc<ErasedExpr>]) -> Result<Code, WriteError> {
+    let fun = self.decls.iter().find_map(|decl| match decl {
+      ShaderDecl::FunDef(h, fun) if *h == handle => Some(fun),
+      _ => None,
+    });
+
+    if let Some(fun) = fun {
+      for (param, arg) in fun.args.iter().zip(args) {
+        self.check_type(param, arg)?;
+      }
+    }
+
+    let ident = self
+      .funs
+      .get(&handle)
+      .cloned()
+      .ok_or_else(|| self.unsupported_expr("undeclared function"))?;
+    let qualifiers = fun.map_or(&[][..], |fun| &fun.arg_qualifiers);
+    let args = args
+      .iter()
+      .enumerate()
+      .map(|(i, arg)| match qualifiers.get(i) {
+        // arguments passed by reference are variables, passed as &Var<T>
+        Some(ArgQualifier::Out) | Some(ArgQualifier::InOut) => {
+          let place = self.place(arg)?;
+          if place.starts_with('&') {
+            Ok(place)
+          } else {
+            Ok(format!("&{}", place))
+          }
+        }
+        _ => {
+          let code = self.expr(arg)?;
+          Ok(self.owned(&code))
+        }
+      })
+      .collect::<Result<Vec<_>, WriteError>>()?;
+
+    Ok(Code::new(
+      format!("{}.call({})", ident, args.join(", ")),
+      Kind::Postfix,
+    ))
+  }
+
+  fn builtin_call(
+    &mut self,
+    handle: &ErasedFunHandle,
+    args: &[Arc<ErasedExpr>],
+  ) -> Result<Code, WriteError> {
+    let vector = match handle {
+      ErasedFunHandle::Vec2 => Some(("vec2", 2)),
+      ErasedFunHandle::Vec3 => Some(("vec3", 3)),
+      ErasedFunHandle::Vec4 => Some(("vec4", 4)),
+      _ => None,
+    };
+
+    if let Some((mac, len)) = vector {
+      return self.vector(mac, len, args);
+    }
+
+    let (trait_name, name, family, receiver, params) = method(handle)
+      .filter(|(.., receiver, params)| args.len() == params.len() + 1 && *receiver < args.len())
+      .ok_or_else(|| self.unsupported(Construct::Function(glsl::fun_name(handle))))?;
+
+    let receiver_ty = self.type_of(&args[receiver]);
+    if let Some(ty) = &receiver_ty {
+      let edges: Vec<_> = params
+        .iter()
+        .filter(|(_, param)| matches!(param, Param::Edge))
+        .filter_map(|(i, _)| self.type_of(&args[*i]))
+        .collect();
+
+      let well_typed = family.contains(ty)
+        && params
+          .iter()
+          .all(|(i, param)| match (param, self.type_of(&args[*i])) {
+            (Param::Same, Some(arg_ty)) => arg_ty == *ty,
+            (Param::Edge, Some(arg_ty)) => {
+              (arg_ty == *ty || components(&arg_ty) == Some((Scalar::Float, 1)))
+                && edges.iter().all(|edge| *edge == arg_ty)
+            }
+            (Param::Float, Some(arg_ty)) => components(&arg_ty) == Some((Scalar::Float, 1)),
+            (_, None) => true,
+          });
+
+      if !well_typed {
+        return Err(self.unsupported_expr(format!(
+          "method `{}` on `{}`",
+          name,
+          describe(&receiver_ty)
+        )));
+      }
+    }
+
+    self.import(match trait_name {
+      "Trigonometry" => "Trigonometry as _",
+      "Exponential" => "Exponential as _",
+      "Relative" => "Relative as _",
+      "Floating" => "Floating as _",
+      "Bounded" => "Bounded as _",
+      "Mix" => "Mix as _",
+      "FloatingExt" => "FloatingExt as _",
+      _ => "Geometry as _",
+    });
+
+    let receiver = self.expr(&args[receiver])?;
+    let receiver = self.receiver(&receiver);
+    let mut params_code = Vec::new();
+
+    for (i, param) in params {
+      let code = self.expr(&args[*i])?;
+      params_code.push(match param {
+        Param::Same | Param::Float => self.arg(&code),
+        Param::Edge => self.owned(&code),
+      });
+    }
+
+    Ok(Code::new(
+      format!("{}.{}({})", receiver, name, params_code.join(", ")),
+      Kind::Postfix,
+    ))
+  }
+
+  fn vector(
+    &mut self,
+    mac: &'static str,
+    len: usize,
+    args: &[Arc<ErasedExpr>],
+  ) -> Result<Code, WriteError> {
+    let tys: Option<Vec<_>> = args
+      .iter()
+      .map(|arg| self.type_of(arg).as_ref().and_then(components))
+      .collect();
+
+    if let Some(tys) = tys {
+      let lens: Vec<_> = tys.iter().map(|(_, len)| *len).collect();
+      let shape_supported = match len {
+        2 => lens == [1, 1],
+        3 => lens == [2, 1] || lens == [1, 1, 1],
+        _ => lens == [3, 1] || lens == [2, 2] || lens == [2, 1, 1] || lens == [1, 1, 1, 1],
+      };
+      let same_scalar = tys.windows(2).all(|w| w[0].0 == w[1].0);
+
+      if !shape_supported || !same_scalar {
+        return Err(self.unsupported_expr(format!(
+          "`{}!` with arguments of type {}",
+          mac,
+          args
+            .iter()
+            .map(|arg| format!("`{}`", describe(&self.type_of(arg))))
+            .collect::<Vec<_>>()
+            .join(", ")
+        )));
+      }
+    }
+
+    self.import(mac);
+    let args = args
+      .iter()
+      .map(|arg| {
+        let code = self.expr(arg)?;
+        Ok(self.macro_arg(&code))
+      })
+      .collect::<Result<Vec<_>, WriteError>>()?;
+
+    Ok(Code::new(
+      format!("{}!({})", mac, args.join(", ")),
+      Kind::Postfix,
+    ))
+  }
+}
+
+/// Kind of an interface declaration, grouping declarations written with the same macro.
+fn interface_kind(decl: &ShaderDecl) -> Option<(u8, bool)> {
+  match decl {
+    ShaderDecl::In(..) => Some((0, false)),
+    ShaderDecl::Out(_, _, attachment) => Some((1, attachment.is_some())),
+    ShaderDecl::Uniform(..) => Some((2, false)),
+    _ => None,
+  }
+}
+
+/// Check whether expressions refer to a variable.
+fn refers_to(exprs: &[&ErasedExpr], handle: &ScopedHandle) -> bool {
+  let mut found = false;
+
+  for expr in exprs {
+    optimizer::walk_expr(expr, &mut |e| {
+      if let ErasedExpr::Var(h) = e {
+        found |= h == handle;
+      }
+    });
+  }
+
+  found
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    color_attachments, inputs, lit, sw, uniforms, vec4, Bounded as _, CanEscape as _,
+    Exponential as _, Expr, Geometry as _, InOut, Mix as _, Out, Relative as _, Scope,
+    ShaderBuilder, Swizzlable as _, Trigonometry as _, M22, M44, V2, V3, V4,
+  };
+
+  fn fragment_shader() -> Shader {
+    ShaderBuilder::new_fragment_shader(|mut s, fragment| {
+      inputs!(s, uv: V2<f32>, normal: V3<f32>);
+      uniforms!(s, time: f32, weights: [f32; 4]);
+      color_attachments!(s, color: V4<f32> = 0, glow: V4<f32> = (1, 1));
+
+      let scale = s.constant_named("scale", lit!(2.));
+      let offsets = s.constant(lit!([1, -2, 3]));
+
+      let shade = s.fun_named(
+        "shade",
+        &["n", "t"],
+        |s: &mut Scope<Expr<f32>>, n: Expr<V3<f32>>, t: Expr<f32>| {
+          let k = s.var_named("k", sw!(n, .z) * &scale);
+          s.when(t.lt(0.), |s| {
+            s.leave(-&k);
+          });
+          k.sin().mix(t.exp(), lit!(0.5))
+        },
+      );
+
+      let flip = s.fun_named(
+        "flip",
+        &["m"],
+        |_: &mut Scope<Expr<[[f32; 3]; 2]>>, m: Expr<[[f32; 2]; 3]>| {
+          Expr::from([
+            Expr::from([m.at(0).at(0), m.at(1).at(0), m.at(2).at(0)]),
+            Expr::from([m.at(0).at(1), m.at(1).at(1), m.at(2).at(1)]),
+          ])
+        },
+      );
+
+      s.main_fun(|s: &mut Scope<()>| {
+        let acc = s.var(0.);
+        let flipped = s.var(flip.call(lit!([[1., 2.], [3., 4.], [5., 6.]])));
+        s.set(&acc, flipped.at(1).at(2));
+        s.loop_for(
+          0,
+          |i| i.lt(4),
+          |i| i + 1,
+          |s, i| {
+            s.set(&acc, &acc + weights.at(i));
+          },
+        );
+        s.loop_while(acc.gt(1.), |s| {
+          s.set(&acc, &acc / 2.);
+          s.when(acc.eq(lit!(0.25)), |s| s.loop_break());
+        });
+        let x = s.var(shade.call(normal.clone(), time.clone()));
+        let flag = s.var(false);
+        s.when(fragment.front_facing.clone(), |s| {
+          s.set(&x, &x * 2.);
+        })
+        .or_else(x.gt(1.), |s| {
+          s.set(&flag, offsets.at(1).lt(0).eq(true));
+        })
+        .or(|s| fragment.discard(s));
+        s.set(&color, vec4!(uv, x, 1.));
+        s.set(&glow, vec4!(sw!(uv, .y), 0., acc, 1.));
+      })
+    })
+  }
+
+  fn tess_ctrl_shader() -> Shader {
+    ShaderBuilder::new_tess_ctrl_shader(|mut s, tess_ctrl| {
+      uniforms!(s, transform: M44, level: u32);
+      let _ = s.uniform::<f32>("unused").unwrap();
+
+      let identity = s.constant(lit!(M22::from([[1., 0.], [0., 1.]])));
+      let _ = s.constant(lit!(1, 2));
+
+      let _ = s.fun(|_: &mut Scope<()>| {});
+      let halve = s.fun(|_: &mut Scope<Expr<u32>>, x: Expr<u32>| x >> 1u32);
+      let _ = s.fun(|_: &mut Scope<Expr<bool>>| lit!(true));
+      let swap = s.fun(
+        |s: &mut Scope<Expr<f32>>, a: InOut<f32>, b: InOut<f32>, sum: Out<f32>| {
+          let t = s.var(a.to_expr());
+          s.set(&a, b.to_expr());
+          s.set(&b, &t);
+          s.set(&sum, a.to_expr() + b.to_expr());
+          t.to_expr()
+        },
+      );
+
+      s.main_fun(|s: &mut Scope<()>| {
+        let i = s.var(tess_ctrl.invocation_id.clone());
+        let x = s.var(lit!([1., 2.]));
+        let _ = s.var(swap.call(&x.at(0), &x.at(1), &tess_ctrl.tess_level_inner.at(0)));
+        let p = s.var(&transform * tess_ctrl.input.at(&i).position());
+        s.set(tess_ctrl.output.at(&i).position(), &p * 2. - (-&p + 1.));
+        s.set(tess_ctrl.tess_level_outer.at(0), 1.);
+        let l = s.var(halve.call(level.clone() << halve.call(lit!(2u32))) >> 1u32);
+        let b = s.var(!(l.eq(lit!(0u32))));
+        s.set(&b, (lit!(true) ^ &b) & false | &b);
+        s.when(b.and(&b).or(lit!(false)).xor(true), |s| {
+          s.abort();
+        });
+        let m = s.var(&identity * &identity);
+        s.set(&m, &m * lit!(M22::from([[2., 0.], [0., 2.]])));
+        let v = s.var(sw!(p, .x.y) * (&m * lit!(1., 2.)));
+        s.set(&v, v.normalize().min(&v).clamp(lit!(0., 0.), lit!(1., 1.)));
+        s.set(&v, v.step(lit!(0.5)).smooth_step(lit!(0.), lit!(1.)));
+        s.set(&v, v.face_forward(&v, v.reflect(&v)));
+        s.set(
+          tess_ctrl.tess_level_inner.at(1),
+          sw!(v, .y).pow(v.length()).abs() % 2.,
+        );
+        s.loop_for(0, |_| lit!(false), |_| lit!(0), |_, _| {});
+        s.loop_while(false, |_| {});
+      })
+    })
+  }
+
+  // outputs of write_shader, formatted with rustfmt
+  mod generated_fragment {
+    use crate as shades;
+    use shades::{
+      color_attachments, inputs, lit, sw, uniforms, vec4, CanEscape as _, Exponential as _, Expr,
+      Mix as _, Scope, Shader, ShaderBuilder, Swizzlable as _, Trigonometry as _, V2, V3, V4,
+    };
+
+    pub fn fragment_shader() -> Shader {
+      ShaderBuilder::new_fragment_shader(|mut s, fragment| {
+        inputs!(s, uv: V2<f32>, normal: V3<f32>);
+
+        uniforms!(s, time: f32, weights: [f32; 4]);
+
+        color_attachments!(s, color: V4<f32> = 0, glow: V4<f32> = (1, 1));
+
+        let scale = s.constant_named("scale", 2.0);
+        let glob_1 = s.constant(lit!([1, -2, 3]));
+
+        let shade = s.fun_named(
+          "shade",
+          &["n", "t"],
+          |s: &mut Scope<Expr<f32>>, n: Expr<V3<f32>>, t: Expr<f32>| {
+            let k = s.var_named("k", sw!(n, .z) * &scale);
+            s.when(t.lt(0.0), |s| {
+              s.leave(-&k);
+            });
+            k.sin().mix(t.exp(), lit!(0.5))
+          },
+        );
+
+        let flip = s.fun_named(
+          "flip",
+          &["m"],
+          |_: &mut Scope<Expr<[[f32; 3]; 2]>>, m: Expr<[[f32; 2]; 3]>| {
+            lit!([
+              lit!([m.at(0).at(0), m.at(1).at(0), m.at(2).at(0)]),
+              lit!([m.at(0).at(1), m.at(1).at(1), m.at(2).at(1)])
+            ])
+          },
+        );
+
+        s.main_fun(|s: &mut Scope<()>| {
+          let var_3_0 = s.var(0.0);
+          let var_3_1 =
+            s.var(flip.call(lit!([lit!([1.0, 2.0]), lit!([3.0, 4.0]), lit!([5.0, 6.0])])));
+          s.set(&var_3_0, var_3_1.at(1).at(2));
+          s.loop_for(
+            0,
+            |var_4_0| var_4_0.lt(4),
+            |var_4_0| var_4_0 + 1,
+            |s, var_4_0| {
+              s.set(&var_3_0, &var_3_0 + weights.at(var_4_0));
+            },
+          );
+          s.loop_while(var_3_0.gt(1.0), |s| {
+            s.set(&var_3_0, &var_3_0 / 2.0);
+            s.when(var_3_0.eq(0.25), |s| {
+              s.loop_break();
+            });
+          });
+          let var_3_2 = s.var(shade.call(normal.clone(), time.clone()));
+          let var_3_3 = s.var(false);
+          s.when(&fragment.front_facing, |s| {
+            s.set(&var_3_2, &var_3_2 * 2.0);
+          })
+          .or_else(var_3_2.gt(1.0), |s| {
+            s.set(&var_3_3, glob_1.at(1).lt(0).eq(true));
+          })
+          .or(|s| {
+            fragment.discard(s);
+          });
+          s.set(&color, vec4!(uv, var_3_2, 1.0));
+          s.set(&glow, vec4!(sw!(uv, .y), 0.0, var_3_0, 1.0));
+        })
+      })
+    }
+  }
+
+  mod generated_tess_ctrl {
+    use crate as shades;
+
+    use shades::{
+      lit, sw, uniforms, Bounded as _, CanEscape as _, Exponential as _, Expr, Geometry as _,
+      InOut, Mix as _, Out, Relative as _, Scope, Shader, ShaderBuilder, Swizzlable as _, M22, M44,
+      V2,
+    };
+
+    pub fn tess_ctrl_shader() -> Shader {
+      ShaderBuilder::new_tess_ctrl_shader(|mut s, tess_ctrl| {
+        uniforms!(s, transform: M44, level: u32);
+        let _ = s.uniform::<f32>("unused").unwrap();
+
+        let glob_0 = s.constant(lit!(M22::from([[1.0, 0.0], [0.0, 1.0]])));
+        let _ = s.constant(lit!(1, 2));
+
+        let _ = s.fun(|_: &mut Scope<()>| {});
+
+        let fun_1 = s.fun(|_: &mut Scope<Expr<u32>>, arg_0: Expr<u32>| arg_0.clone() >> 1u32);
+
+        let _ = s.fun(|_: &mut Scope<Expr<bool>>| lit!(true));
+
+        let fun_3 = s.fun(
+          |s: &mut Scope<Expr<f32>>, arg_0: InOut<f32>, arg_1: InOut<f32>, arg_2: Out<f32>| {
+            let var_3_0 = s.var(arg_0.to_expr());
+            s.set(&arg_0, arg_1.to_expr());
+            s.set(&arg_1, &var_3_0);
+            s.set(&arg_2, arg_0.to_expr() + arg_1.to_expr());
+            var_3_0.clone()
+          },
+        );
+
+        s.main_fun(|s: &mut Scope<()>| {
+          let var_4_0 = s.var(&tess_ctrl.invocation_id);
+          let var_4_1 = s.var(lit!([1.0, 2.0]));
+          let _ = s.var(fun_3.call(
+            &var_4_1.at(0),
+            &var_4_1.at(1),
+            &tess_ctrl.tess_level_inner.at(0),
+          ));
+          let var_4_3 = s.var(&transform * tess_ctrl.input.at(&var_4_0).position());
+          s.set(
+            tess_ctrl.output.at(&var_4_0).position(),
+            &var_4_3 * 2.0 - (-&var_4_3 + 1.0),
+          );
+          s.set(tess_ctrl.tess_level_outer.at(0), 1.0);
+          let var_4_4 = s.var(fun_1.call(level.clone() << fun_1.call(lit!(2u32))) >> 1u32);
+          let var_4_5 = s.var(!var_4_4.eq(0u32));
+          s.set(&var_4_5, (lit!(true) ^ &var_4_5) & false | &var_4_5);
+          s.when(var_4_5.and(&var_4_5).or(false).xor(true), |s| {
+            s.abort();
+          });
+          let var_4_6 = s.var(&glob_0 * &glob_0);
+          s.set(
+            &var_4_6,
+            &var_4_6 * lit!(M22::from([[2.0, 0.0], [0.0, 2.0]])),
+          );
+          let var_4_7 = s.var(sw!(var_4_3, .x.y) * (&var_4_6 * lit!(1.0, 2.0)));
+          s.set(
+            &var_4_7,
+            var_4_7
+              .normalize()
+              .min(&var_4_7)
+              .clamp(lit!(0.0, 0.0), lit!(1.0, 1.0)),
+          );
+          s.set(
+            &var_4_7,
+            var_4_7.step(lit!(0.5)).smooth_step(lit!(0.0), lit!(1.0)),
+          );
+          s.set(
+            &var_4_7,
+            var_4_7.face_forward(&var_4_7, var_4_7.reflect(&var_4_7)),
+          );
+          s.set(
+            tess_ctrl.tess_level_inner.at(1),
+            sw!(var_4_7, .y).pow(var_4_7.length()).abs() % 2.0,
+          );
+          s.loop_for(0, |_| lit!(false), |_| lit!(0), |_, _| {});
+          s.loop_while(false, |_| {});
+        })
+      })
+    }
+  }
+
+  #[test]
+  fn round_trip() {
+    // the written code is checked first, then compiled in the modules above
+    assert_eq!(
+      write_shader_to_str(fragment_shader()).unwrap(),
+      r#"use shades::{
+  color_attachments, inputs, lit, sw, uniforms, vec4, CanEscape as _, Exponential as _, Expr,
+  Mix as _, Scope, Shader, ShaderBuilder, Swizzlable as _, Trigonometry as _, V2, V3, V4,
+};
+
+pub fn fragment_shader() -> Shader {
+  ShaderBuilder::new_fragment_shader(|mut s, fragment| {
+    inputs!(s, uv: V2<f32>, normal: V3<f32>);
+
+    uniforms!(s, time: f32, weights: [f32; 4]);
+
+    color_attachments!(s, color: V4<f32> = 0, glow: V4<f32> = (1, 1));
+
+    let scale = s.constant_named("scale", 2.0);
+    let glob_1 = s.constant(lit!([1, -2, 3]));
+
+    let shade = s.fun_named("shade", &["n", "t"], |s: &mut Scope<Expr<f32>>, n: Expr<V3<f32>>, t: Expr<f32>| {
+      let k = s.var_named("k", sw!(n, .z) * &scale);
+      s.when(t.lt(0.0), |s| {
+        s.leave(-&k);
+      });
+      k.sin().mix(t.exp(), lit!(0.5))
+    });
+
+    let flip = s.fun_named("flip", &["m"], |_: &mut Scope<Expr<[[f32; 3]; 2]>>, m: Expr<[[f32; 2]; 3]>| lit!([lit!([m.at(0).at(0), m.at(1).at(0), m.at(2).at(0)]), lit!([m.at(0).at(1), m.at(1).at(1), m.at(2).at(1)])]));
+
+    s.main_fun(|s: &mut Scope<()>| {
+      let var_3_0 = s.var(0.0);
+      let var_3_1 = s.var(flip.call(lit!([lit!([1.0, 2.0]), lit!([3.0, 4.0]), lit!([5.0, 6.0])])));
+      s.set(&var_3_0, var_3_1.at(1).at(2));
+      s.loop_for(0, |var_4_0| var_4_0.lt(4), |var_4_0| var_4_0 + 1, |s, var_4_0| {
+        s.set(&var_3_0, &var_3_0 + weights.at(var_4_0));
+      });
+      s.loop_while(var_3_0.gt(1.0), |s| {
+        s.set(&var_3_0, &var_3_0 / 2.0);
+        s.when(var_3_0.eq(0.25), |s| {
+          s.loop_break();
+        });
+      });
+      let var_3_2 = s.var(shade.call(normal.clone(), time.clone()));
+      let var_3_3 = s.var(false);
+      s.when(&fragment.front_facing, |s| {
+        s.set(&var_3_2, &var_3_2 * 2.0);
+      })
+      .or_else(var_3_2.gt(1.0), |s| {
+        s.set(&var_3_3, glob_1.at(1).lt(0).eq(true));
+      })
+      .or(|s| {
+        fragment.discard(s);
+      });
+      s.set(&color, vec4!(uv, var_3_2, 1.0));
+      s.set(&glow, vec4!(sw!(uv, .y), 0.0, var_3_0, 1.0));
+    })
+  })
+}
+"#
+    );
+    assert_eq!(generated_fragment::fragment_shader(), fragment_shader());
+
+    assert_eq!(
+      write_shader_to_str(tess_ctrl_shader()).unwrap(),
+      r#"use shades::{
+  lit, sw, uniforms, Bounded as _, CanEscape as _, Exponential as _, Expr, Geometry as _, InOut,
+  M22, M44, Mix as _, Out, Relative as _, Scope, Shader, ShaderBuilder, Swizzlable as _, V2,
+};
+
+pub fn tess_ctrl_shader() -> Shader {
+  ShaderBuilder::new_tess_ctrl_shader(|mut s, tess_ctrl| {
+    uniforms!(s, transform: M44, level: u32);
+    let _ = s.uniform::<f32>("unused").unwrap();
+
+    let glob_0 = s.constant(lit!(M22::from([[1.0, 0.0], [0.0, 1.0]])));
+    let _ = s.constant(lit!(1, 2));
+
+    let _ = s.fun(|_: &mut Scope<()>| {});
+
+    let fun_1 = s.fun(|_: &mut Scope<Expr<u32>>, arg_0: Expr<u32>| arg_0.clone() >> 1u32);
+
+    let _ = s.fun(|_: &mut Scope<Expr<bool>>| lit!(true));
+
+    let fun_3 = s.fun(|s: &mut Scope<Expr<f32>>, arg_0: InOut<f32>, arg_1: InOut<f32>, arg_2: Out<f32>| {
+      let var_3_0 = s.var(arg_0.to_expr());
+      s.set(&arg_0, arg_1.to_expr());
+      s.set(&arg_1, &var_3_0);
+      s.set(&arg_2, arg_0.to_expr() + arg_1.to_expr());
+      var_3_0.clone()
+    });
+
+    s.main_fun(|s: &mut Scope<()>| {
+      let var_4_0 = s.var(&tess_ctrl.invocation_id);
+      let var_4_1 = s.var(lit!([1.0, 2.0]));
+      let _ = s.var(fun_3.call(&var_4_1.at(0), &var_4_1.at(1), &tess_ctrl.tess_level_inner.at(0)));
+      let var_4_3 = s.var(&transform * tess_ctrl.input.at(&var_4_0).position());
+      s.set(tess_ctrl.output.at(&var_4_0).position(), &var_4_3 * 2.0 - (-&var_4_3 + 1.0));
+      s.set(tess_ctrl.tess_level_outer.at(0), 1.0);
+      let var_4_4 = s.var(fun_1.call(level.clone() << fun_1.call(lit!(2u32))) >> 1u32);
+      let var_4_5 = s.var(!var_4_4.eq(0u32));
+      s.set(&var_4_5, (lit!(true) ^ &var_4_5) & false | &var_4_5);
+      s.when(var_4_5.and(&var_4_5).or(false).xor(true), |s| {
+        s.abort();
+      });
+      let var_4_6 = s.var(&glob_0 * &glob_0);
+      s.set(&var_4_6, &var_4_6 * lit!(M22::from([[2.0, 0.0], [0.0, 2.0]])));
+      let var_4_7 = s.var(sw!(var_4_3, .x.y) * (&var_4_6 * lit!(1.0, 2.0)));
+      s.set(&var_4_7, var_4_7.normalize().min(&var_4_7).clamp(lit!(0.0, 0.0), lit!(1.0, 1.0)));
+      s.set(&var_4_7, var_4_7.step(lit!(0.5)).smooth_step(lit!(0.0), lit!(1.0)));
+      s.set(&var_4_7, var_4_7.face_forward(&var_4_7, var_4_7.reflect(&var_4_7)));
+      s.set(tess_ctrl.tess_level_inner.at(1), sw!(var_4_7, .y).pow(var_4_7.length()).abs() % 2.0);
+      s.loop_for(0, |_| lit!(false), |_| lit!(0), |_, _| {});
+      s.loop_while(false, |_| {});
+    })
+  })
+}
+"#
+    );
+    assert_eq!(generated_tess_ctrl::tess_ctrl_shader(), tess_ctrl_shader());
+  }
+
+  #[test]
+  fn snake_case_bindings() {
+    let shader = ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+      let k = s.constant_named("K", lit!(0.5));
+      let base_color = s.fun_named(
+        "baseColor",
+        &["HDRScale"],
+        |_: &mut Scope<Expr<f32>>, x: Expr<f32>| x * k,
+      );
+
+      s.main_fun(|s: &mut Scope<()>| {
+        s.set(&vertex.point_size, base_color.call(lit!(1.)));
+      })
+    });
+
+    let output = write_shader_to_str(&shader).unwrap();
+    assert!(output.contains("let k = s.constant_named(\"K\", 0.5);"));
+    assert!(output.contains("let base_color = s.fun_named(\"baseColor\""));
+    assert!(output.contains("hdr_scale"));
+    assert_eq!(snake_case("HDRColor"), "hdr_color");
+  }
+
+  #[test]
+  fn overloaded_functions() {
+    let shader = ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+      let scalar = s.fun_named("shade", &[], |_: &mut Scope<Expr<f32>>, x: Expr<f32>| x);
+      let vector = s.fun_named(
+        "shade",
+        &[],
+        |_: &mut Scope<Expr<V4<f32>>>, v: Expr<V4<f32>>| v,
+      );
+
+      s.main_fun(|s: &mut Scope<()>| {
+        s.set(
+          &vertex.position,
+          vector.call(lit!(0., 0., 0., 1.)) * scalar.call(lit!(1.)),
+        );
+      })
+    });
+
+    let output = write_shader_to_str(&shader).unwrap();
+    assert!(output.contains("let shade_f32 = s.fun_named(\"shade\""));
+    assert!(output.contains("let shade_v4_f32 = s.fun_named(\"shade\""));
+  }
+
+  #[test]
+  fn function_order() {
+    let mut shader = ShaderBuilder::new_vertex_shader(|mut s, vertex| {
+      let first = s.fun_named("first", &[], |_: &mut Scope<Expr<f32>>| lit!(0.));
+      let k = s.constant_named("k", lit!(2.));
+      let _ = s.fun_named(
+        "second",
+        &["x"],
+        |_: &mut Scope<Expr<f32>>, x: Expr<f32>| x * k,
+      );
+      let _ = s.fun_named("third", &[], |_: &mut Scope<Expr<f32>>| lit!(1.));
+
+      s.main_fun(|s: &mut Scope<()>| {
+        s.set(&vertex.point_size, first.call());
+      })
+    });
+
+    // calls to functions declared later can only be built from the IR
+    fn call_from_first(shader: &mut Shader, callee: u16, args: Vec<Arc<ErasedExpr>>) {
+      if let ShaderDecl::FunDef(_, fun) = &mut shader.decls[0] {
+        let call = ErasedExpr::FunCall(ErasedFunHandle::UserDefined(callee), args);
+        fun.ret = ErasedReturn::Expr(<f32 as crate::ToType>::ty(), call);
+      }
+    }
+
+    // third is defined before its caller
+    call_from_first(&mut shader, 2, Vec::new());
+    let output = write_shader_to_str(&shader).unwrap();
+    assert!(output.find("let third").unwrap() < output.find("let first").unwrap());
+
+    // second cannot be defined before k
+    call_from_first(&mut shader, 1, vec![Arc::new(ErasedExpr::LitFloat(1.))]);
+    assert_eq!(
+      write_shader_to_str(&shader),
+      Err(WriteError::Unsupported {
+        declaration: Declaration::Function(1),
+        construct: Construct::Statement("call before the definition".to_owned()),
+      })
+    );
+  }
+
+  #[test]
+  fn unsupported() {
+    let mut shader = ShaderBuilder::new_vertex_shader(|s, vertex| {
+      s.main_fun(|s: &mut Scope<()>| {
+        s.set(&vertex.position, lit!(0., 0., 0., 1.));
+      })
+    });
+    assert!(write_shader_to_str(&shader).is_ok());
+    shader.stage = ShaderStage::Fragment;
+    assert_eq!(
+      write_shader_to_str(&shader),
+      Err(WriteError::Unsupported {
+        declaration: Declaration::Main,
+        construct: Construct::BuiltIn(BuiltIn::Vertex(VertexBuiltIn::Position)),
+      })
+    );
+
+    // assignments to swizzles and early returns can be built from the IR only
+    let mut shader = ShaderBuilder::new_vertex_shader(|mut s, _| {
+      let _ = s.fun(|s: &mut Scope<Expr<f32>>, a: Expr<V2<f32>>| {
+        let _ = s.var(a);
+        lit!(1.)
+      });
+      s.main_fun(|_: &mut Scope<()>| {})
+    });
+    let var = ErasedExpr::Var(ScopedHandle::fun_var(0, 0));
+    if let ShaderDecl::FunDef(_, fun) = &mut shader.decls[0] {
+      fun.scope.instructions.push(ScopeInstr::MutateVar {
+        var: ErasedExpr::Swizzle(var.into(), Swizzle::D1(SwizzleSelector::X)),
+        expr: ErasedExpr::LitFloat(0.),
+      });
+    }
+    assert_eq!(
+      write_shader_to_str(&shader),
+      Err(WriteError::Unsupported {
+        declaration: Declaration::Function(0),
+        construct: Construct::Statement("assignment to a swizzle".to_owned()),
+      })
+    );
+
+    if let ShaderDecl::FunDef(_, fun) = &mut shader.decls[0] {
+      fun.scope.instructions[1] = ScopeInstr::Return(ErasedReturn::Expr(
+        <f32 as crate::ToType>::ty(),
+        ErasedExpr::LitFloat(0.),
+      ));
+    }
+    assert_eq!(
+      write_shader_to_str(&shader),
+      Err(WriteError::Unsupported {
+        declaration: Declaration::Function(0),
+        construct: Construct::Statement("return at the top level of a function".to_owned()),
+      })
+    );
+
+    // GLSL multiplies matrices by scalars, the EDSL doesn’t
+    if let ShaderDecl::FunDef(_, fun) = &mut shader.decls[0] {
+      fun.scope.instructions.pop();
+      fun.ret = ErasedReturn::Expr(
+        <M22 as crate::ToType>::ty(),
+        ErasedExpr::Mul(
+          ErasedExpr::LitM22(M22::from([[1., 0.], [0., 1.]])).into(),
+          ErasedExpr::LitFloat(2.).into(),
+        ),
+      );
+    }
+    assert_eq!(
+      write_shader_to_str(&shader),
+      Err(WriteError::Unsupported {
+        declaration: Declaration::Function(0),
+        construct: Construct::Expression("operator `*` on `M22` and `f32`".to_owned()),
+      })
+    );
+
+    // outputs are write-only
+    let mut shader = ShaderBuilder::new_fragment_shader(|mut s, _| {
+      let color = s.output::<f32>("color").unwrap();
+      s.main_fun(|s: &mut Scope<()>| s.set(&color, lit!(1.)))
+    });
+    if let Some(ShaderDecl::Main(fun)) = shader.decls.last_mut() {
+      fun.scope.instructions[0] = ScopeInstr::MutateVar {
+        var: ErasedExpr::Var(ScopedHandle::Output("color".to_owned())),
+        expr: ErasedExpr::Var(ScopedHandle::Output("color".to_owned())),
+      };
+    }
+    assert_eq!(
+      write_shader_to_str(&shader),
+      Err(WriteError::Unsupported {
+        declaration: Declaration::Main,
+        construct: Construct::Expression("read of an output".to_owned()),
+      })
+    );
+
+    // so are out arguments
+    let mut shader = ShaderBuilder::new_fragment_shader(|mut s, _| {
+      let _ = s.fun(|s: &mut Scope<()>, x: Out<f32>| s.set(&x, lit!(1.)));
+      s.main_fun(|_: &mut Scope<()>| {})
+    });
+    if let ShaderDecl::FunDef(_, fun) = &mut shader.decls[0] {
+      fun.scope.instructions[0] = ScopeInstr::MutateVar {
+        var: ErasedExpr::Var(ScopedHandle::fun_arg(0)),
+        expr: ErasedExpr::Var(ScopedHandle::fun_arg(0)),
+      };
+    }
+    assert_eq!(
+      write_shader_to_str(&shader),
+      Err(WriteError::Unsupported {
+        declaration: Declaration::Function(0),
+        construct: Construct::Expression("read of an out argument".to_owned()),
+      })
+    );
+  }
+}